@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use dexparser::fuzzing::GeneratedDex;
+use dexparser::{ParseOptions, RegionKind};
+
+// Structurally valid files must always parse, and the result must match what was generated
+fuzz_target!(|dex: GeneratedDex| {
+    let bytes = dex.to_bytes();
+    let file = dexparser::parse_with_options(&bytes, ParseOptions::new().verify_checksum(true))
+        .expect("generated file failed to parse");
+
+    assert!(file.regions.iter().all(|region| region.kind != RegionKind::Unmapped));
+    assert!(dexparser::write(&file).unwrap() == bytes, "parsed file was not written back the same");
+    assert_eq!(file.classes.len(), dex.classes.len());
+    for (parsed, generated) in file.classes.iter().zip(&dex.classes) {
+        assert_eq!(*parsed.class_type, generated.name);
+        assert_eq!(parsed.static_values.len(), generated.static_values.len());
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes must never panic, hang or exhaust memory, whether parsed strictly or leniently
+fuzz_target!(|data: &[u8]| {
+    let _ = dexparser::parse(data);
+    let _ = dexparser::parse_with_options(data, dexparser::ParseOptions::new().lenient(true));
+});
@@ -0,0 +1,849 @@
+// Assembling method bodies from instructions with symbolic labels
+//
+// A CodeBuilder takes instructions in order, with branches, switch cases and try blocks naming labels instead of
+// offsets and the strings, types and members they use given as References. Building lays them out: goto becomes
+// goto/16 or goto/32 when its target is too far, const-string becomes const-string/jumbo when its string index doesn't
+// fit in 16 bits, and moves, constants and invokes take the smallest form their operands fit. Switch and array data
+// payloads go after the instructions, aligned to 32 bits.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::instructions::{self, Instruction, Opcode, Payload};
+use crate::result_types::*;
+use crate::rewrite::{CodeReference, Reference};
+use crate::writer::Indexes;
+
+pub struct CodeBuilder {
+    registers_size: u16,
+    ins_size: u16,
+    debug_info: Option<DebugInfo>,
+    items: Vec<Item>,
+    // the position in items each label comes before
+    labels: HashMap<String, usize>,
+    tries: Vec<TryBlock>,
+    // a label given twice, reported by build
+    duplicate: Option<String>
+}
+
+struct Item {
+    opcode: Opcode,
+    registers: Vec<u16>,
+    literal: Option<i64>,
+    // the label a branch goes to
+    target: Option<String>,
+    reference: Option<Reference>,
+    // the second reference of invoke-polymorphic
+    prototype: Option<Rc<Prototype>>,
+    payload: Option<PayloadItem>
+}
+
+enum PayloadItem {
+    PackedSwitch { first_key: i32, targets: Vec<String> },
+    SparseSwitch { keys: Vec<i32>, targets: Vec<String> },
+    FillArrayData { element_width: u16, data: Vec<u8> }
+}
+
+struct TryBlock {
+    start: String,
+    end: String,
+    handlers: Vec<(Rc<String>, String)>,
+    catch_all: Option<String>
+}
+
+impl Item {
+    fn new(opcode: Opcode, registers: &[u16]) -> Self {
+        Item {
+            opcode,
+            registers: registers.to_vec(),
+            literal: None,
+            target: None,
+            reference: None,
+            prototype: None,
+            payload: None
+        }
+    }
+}
+
+impl CodeBuilder {
+    // ins_size is the number of words of arguments, which go in the last registers
+    pub fn new(registers_size: u16, ins_size: u16) -> Self {
+        CodeBuilder {
+            registers_size,
+            ins_size,
+            debug_info: None,
+            items: vec!(),
+            labels: HashMap::new(),
+            tries: vec!(),
+            duplicate: None
+        }
+    }
+
+    pub fn debug_info(&mut self, debug_info: DebugInfo) -> &mut Self {
+        self.debug_info = Some(debug_info);
+        self
+    }
+
+    // Marks the position of the next instruction
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        if self.labels.insert(name.to_string(), self.items.len()).is_some() {
+            self.duplicate.get_or_insert_with(|| name.to_string());
+        }
+        self
+    }
+
+    // Any instruction whose only operands are registers
+    pub fn op(&mut self, opcode: Opcode, registers: &[u16]) -> &mut Self {
+        self.push(Item::new(opcode, registers))
+    }
+
+    // An instruction with a constant, such as const/16 or add-int/lit8
+    pub fn literal(&mut self, opcode: Opcode, registers: &[u16], value: i64) -> &mut Self {
+        let mut item = Item::new(opcode, registers);
+        item.literal = Some(value);
+        self.push(item)
+    }
+
+    // An if-* to the given label
+    pub fn branch(&mut self, opcode: Opcode, registers: &[u16], label: &str) -> &mut Self {
+        let mut item = Item::new(opcode, registers);
+        item.target = Some(label.to_string());
+        self.push(item)
+    }
+
+    // goto, goto/16 or goto/32, whichever reaches the label
+    pub fn goto(&mut self, label: &str) -> &mut Self {
+        self.branch(Opcode::GOTO, &[], label)
+    }
+
+    // move, move-wide or move-object in the form that fits the registers
+    pub fn move_(&mut self, opcode: Opcode, to: u16, from: u16) -> &mut Self {
+        let forms = match opcode {
+            Opcode::MOVE_WIDE => [Opcode::MOVE_WIDE, Opcode::MOVE_WIDE_FROM16, Opcode::MOVE_WIDE_16],
+            Opcode::MOVE_OBJECT => [Opcode::MOVE_OBJECT, Opcode::MOVE_OBJECT_FROM16, Opcode::MOVE_OBJECT_16],
+            _ => [Opcode::MOVE, Opcode::MOVE_FROM16, Opcode::MOVE_16]
+        };
+        let form = if to < 16 && from < 16 {
+            forms[0]
+        } else if to < 256 {
+            forms[1]
+        } else {
+            forms[2]
+        };
+        self.op(form, &[to, from])
+    }
+
+    // The shortest of const/4, const/16, const/high16 and const that holds the value
+    pub fn const_(&mut self, register: u16, value: i32) -> &mut Self {
+        let opcode = if register < 16 && (-8 ..= 7).contains(&value) {
+            Opcode::CONST_4
+        } else if i16::try_from(value).is_ok() {
+            Opcode::CONST_16
+        } else if value & 0xFFFF == 0 {
+            Opcode::CONST_HIGH16
+        } else {
+            Opcode::CONST
+        };
+        self.literal(opcode, &[register], i64::from(value))
+    }
+
+    // The shortest of const-wide/16, const-wide/32, const-wide/high16 and const-wide that holds the value
+    pub fn const_wide(&mut self, register: u16, value: i64) -> &mut Self {
+        let opcode = if i16::try_from(value).is_ok() {
+            Opcode::CONST_WIDE_16
+        } else if i32::try_from(value).is_ok() {
+            Opcode::CONST_WIDE_32
+        } else if value & 0xFFFF_FFFF_FFFF == 0 {
+            Opcode::CONST_WIDE_HIGH16
+        } else {
+            Opcode::CONST_WIDE
+        };
+        self.literal(opcode, &[register], value)
+    }
+
+    // const-string, or const-string/jumbo if the string's index needs it
+    pub fn const_string(&mut self, register: u16, string: Rc<String>) -> &mut Self {
+        self.reference(Opcode::CONST_STRING, &[register], Reference::String(string))
+    }
+
+    // An instruction using a string, type, field, method handle or prototype, such as new-instance or iget
+    pub fn reference(&mut self, opcode: Opcode, registers: &[u16], target: Reference) -> &mut Self {
+        let mut item = Item::new(opcode, registers);
+        item.reference = Some(target);
+        self.push(item)
+    }
+
+    // invoke-*, filled-new-array and invoke-custom, switching to the /range form for more than 5 registers or
+    // registers past v15, which then have to be consecutive
+    pub fn invoke(&mut self, opcode: Opcode, registers: &[u16], target: Reference) -> &mut Self {
+        let opcode = if registers.len() > 5 || registers.iter().any(|r| *r > 15) { range_form(opcode) } else { opcode };
+        self.reference(opcode, registers, target)
+    }
+
+    pub fn invoke_polymorphic(&mut self, registers: &[u16], method: Rc<Method>, prototype: Rc<Prototype>)
+                              -> &mut Self {
+        self.invoke(Opcode::INVOKE_POLYMORPHIC, registers, Reference::Method(method));
+        self.items.last_mut().unwrap().prototype = Some(prototype);
+        self
+    }
+
+    // The targets are the labels of the cases for first_key, first_key + 1 and so on
+    pub fn packed_switch(&mut self, register: u16, first_key: i32, targets: &[&str]) -> &mut Self {
+        let mut item = Item::new(Opcode::PACKED_SWITCH, &[register]);
+        item.payload = Some(PayloadItem::PackedSwitch { first_key, targets: labels(targets) });
+        self.push(item)
+    }
+
+    // The cases are given as (key, label), in any order
+    pub fn sparse_switch(&mut self, register: u16, cases: &[(i32, &str)]) -> &mut Self {
+        let mut cases = cases.to_vec();
+        cases.sort_by_key(|case| case.0);
+        let mut item = Item::new(Opcode::SPARSE_SWITCH, &[register]);
+        item.payload = Some(PayloadItem::SparseSwitch {
+            keys: cases.iter().map(|case| case.0).collect(),
+            targets: cases.iter().map(|case| case.1.to_string()).collect()
+        });
+        self.push(item)
+    }
+
+    // data holds the elements in little-endian order, element_width bytes each
+    pub fn fill_array_data(&mut self, register: u16, element_width: u16, data: Vec<u8>) -> &mut Self {
+        let mut item = Item::new(Opcode::FILL_ARRAY_DATA, &[register]);
+        item.payload = Some(PayloadItem::FillArrayData { element_width, data });
+        self.push(item)
+    }
+
+    // Covers the instructions from the start label up to the end label, which go to the handler of the first
+    // listed type the exception is an instance of, else to catch_all
+    pub fn try_catch(&mut self, start: &str, end: &str, handlers: &[(Rc<String>, &str)], catch_all: Option<&str>)
+                     -> &mut Self {
+        self.tries.push(TryBlock {
+            start: start.to_string(),
+            end: end.to_string(),
+            handlers: handlers.iter().map(|(type_, label)| (type_.clone(), label.to_string())).collect(),
+            catch_all: catch_all.map(str::to_string)
+        });
+        self
+    }
+
+    // The code with its indexes pointing into the given pools, as the writer takes it
+    pub fn build(self, file_data: &DexFileData) -> Result<Code, DexParserError> {
+        let idx = Indexes::new(file_data);
+        self.assemble(|target| index_of(target, &idx, &file_data.call_sites)).map(|(code, _)| code)
+    }
+
+    // The code with every index left at zero and what each refers to, as Rewriter::set_code takes them. No index is
+    // known yet, so const-string stays as it is here and Rewriter::build makes it const-string/jumbo if its string
+    // ends up past index 0xFFFF
+    pub fn build_references(self) -> Result<(Code, Vec<CodeReference>), DexParserError> {
+        self.assemble(|_| Ok(0))
+    }
+
+    // The instructions of the code, with a label at each offset and at the end, and each index taken from the
+    // reference at its offset. Also the position among the items each offset went to
+    fn from_code(code: &Code, references: &[CodeReference]) -> Result<(Self, Vec<(u32, usize)>), DexParserError> {
+        let mut at = HashMap::new();
+        for reference in references {
+            at.entry(reference.offset).or_insert_with(Vec::new).push(&reference.target);
+        }
+
+        let insns = instructions::decode_all(&code.insns)?;
+        let mut builder = CodeBuilder::new(code.registers_size, code.ins_size);
+        let mut positions = vec!();
+        for (i, insn) in insns.iter().enumerate() {
+            positions.push((insn.offset, builder.items.len()));
+            builder.label(&address_label(insn.offset));
+            // payloads are laid out again after the instructions, along with the nop that aligned them
+            if insn.payload.is_some() || insn.opcode == Opcode::NOP
+                && insns.get(i + 1).is_some_and(|next| next.payload.is_some()) {
+                continue;
+            }
+
+            let err = |reason: String| DexParserError::InvalidBytecode { offset: insn.offset, reason };
+            let mut item = Item::new(insn.opcode, &insn.registers);
+            item.literal = insn.literal;
+            if let Some(target) = insn.target() {
+                if insn.opcode.can_switch() || insn.opcode == Opcode::FILL_ARRAY_DATA {
+                    let labels = |targets: &[i32]| targets.iter()
+                        .map(|target| address_label((i64::from(insn.offset) + i64::from(*target)) as u32))
+                        .collect();
+                    item.payload = Some(match (insn.opcode, instructions::decode(&code.insns, target)?.payload) {
+                        (Opcode::PACKED_SWITCH, Some(Payload::PackedSwitch { first_key, targets })) =>
+                            PayloadItem::PackedSwitch { first_key, targets: labels(&targets) },
+                        (Opcode::SPARSE_SWITCH, Some(Payload::SparseSwitch { keys, targets })) =>
+                            PayloadItem::SparseSwitch { keys, targets: labels(&targets) },
+                        (Opcode::FILL_ARRAY_DATA, Some(Payload::FillArrayData { element_width, data })) =>
+                            PayloadItem::FillArrayData { element_width, data },
+                        _ => return Err(err(format!("{} has no payload at {}", insn.opcode.name(), target)))
+                    });
+                } else {
+                    item.target = Some(address_label(target));
+                }
+            }
+
+            let missing = || err(format!("no reference given for the index of {}", insn.opcode.name()));
+            let targets = at.get(&insn.offset).map_or(&[][..], Vec::as_slice);
+            if insn.index.is_some() {
+                let kind = insn.opcode.index_kind();
+                item.reference = Some(targets.iter().find(|target| target.kind() == kind).cloned().cloned()
+                    .ok_or_else(missing)?);
+            }
+            if insn.proto_index.is_some() {
+                item.prototype = Some(targets.iter()
+                    .find_map(|target| match target {
+                        Reference::Prototype(proto) => Some(proto.clone()),
+                        _ => None
+                    })
+                    .ok_or_else(missing)?);
+            }
+            builder.items.push(item);
+        }
+        let end = code.insns.len() as u32;
+        positions.push((end, builder.items.len()));
+        builder.label(&address_label(end));
+
+        for try_ in &code.tries {
+            builder.tries.push(TryBlock {
+                start: address_label(try_.start_addr),
+                end: address_label(try_.start_addr + u32::from(try_.insn_count)),
+                handlers: try_.handler.handlers.iter()
+                    .map(|pair| (pair.type_.clone(), address_label(pair.addr)))
+                    .collect(),
+                catch_all: try_.handler.catch_all_addr.map(address_label)
+            });
+        }
+        Ok((builder, positions))
+    }
+
+    fn push(&mut self, item: Item) -> &mut Self {
+        self.items.push(item);
+        self
+    }
+
+    fn assemble(mut self, resolve: impl Fn(&Reference) -> Result<u32, DexParserError>)
+                -> Result<(Code, Vec<CodeReference>), DexParserError> {
+        if let Some(label) = self.duplicate {
+            return Err(failed(format!("label {} is given more than once", label)));
+        }
+
+        let mut indexes = vec!();
+        for item in &mut self.items {
+            let index = item.reference.as_ref().map(&resolve).transpose()?;
+            if item.opcode == Opcode::CONST_STRING && index.is_some_and(|i| i > 0xFFFF) {
+                item.opcode = Opcode::CONST_STRING_JUMBO;
+            }
+            let proto_index = item.prototype.as_ref().map(|proto| resolve(&Reference::Prototype(proto.clone())))
+                .transpose()?;
+            indexes.push((index, proto_index));
+        }
+
+        // gotos start out short and grow until every one reaches its target, which can only push targets further
+        let mut offsets = self.offsets();
+        loop {
+            let mut grown = false;
+            for (i, item) in self.items.iter_mut().enumerate() {
+                let target = match item.target {
+                    Some(ref target) if matches!(item.opcode, Opcode::GOTO | Opcode::GOTO_16) => target,
+                    _ => continue
+                };
+                let distance = label_offset(&self.labels, &offsets, target)? - offsets[i];
+                let needed = if distance == 0 || i16::try_from(distance).is_err() {
+                    Opcode::GOTO_32
+                } else if i8::try_from(distance).is_err() {
+                    Opcode::GOTO_16
+                } else {
+                    Opcode::GOTO
+                };
+                if needed.length() > item.opcode.length() {
+                    item.opcode = needed;
+                    grown = true;
+                }
+            }
+            if !grown {
+                break;
+            }
+            offsets = self.offsets();
+        }
+
+        // payloads go after the last instruction, each starting on an even code unit
+        let end = *offsets.last().unwrap();
+        let mut payload_offset = end + (end & 1);
+        let mut payload_offsets = vec!();
+        for item in &self.items {
+            match item.payload {
+                Some(ref payload) => {
+                    payload_offsets.push(Some(payload_offset));
+                    payload_offset += payload_length(payload);
+                },
+                None => payload_offsets.push(None)
+            }
+        }
+
+        let mut insns = vec!();
+        let mut payloads = vec!();
+        let mut references = vec!();
+        for (i, item) in self.items.iter().enumerate() {
+            let offset = offsets[i];
+            let relative = |label: &String| label_offset(&self.labels, &offsets, label).map(|target| target - offset);
+            let branch_offset = match (&item.target, payload_offsets[i]) {
+                (Some(label), _) => Some(relative(label)?),
+                (None, Some(payload)) => Some(payload - offset),
+                (None, None) => None
+            };
+            let (index, proto_index) = indexes[i];
+            insns.extend(instructions::encode(&Instruction {
+                offset: offset as u32,
+                opcode: item.opcode,
+                length: item.opcode.length(),
+                registers: item.registers.clone(),
+                literal: item.literal,
+                branch_offset: branch_offset.map(to_i32).transpose()?,
+                index,
+                proto_index,
+                payload: None
+            })?);
+
+            if let Some(ref target) = item.reference {
+                references.push(CodeReference { offset: offset as u32, target: target.clone() });
+            }
+            if let Some(ref proto) = item.prototype {
+                references.push(CodeReference { offset: offset as u32, target: Reference::Prototype(proto.clone()) });
+            }
+
+            // switch targets are relative to the switch instruction
+            let relatives = |targets: &[String]| targets.iter()
+                .map(|label| relative(label).and_then(to_i32))
+                .collect::<Result<Vec<_>, _>>();
+            let (opcode, payload) = match item.payload {
+                Some(PayloadItem::PackedSwitch { first_key, ref targets }) =>
+                    (Opcode::PACKED_SWITCH_PAYLOAD, Payload::PackedSwitch { first_key, targets: relatives(targets)? }),
+                Some(PayloadItem::SparseSwitch { ref keys, ref targets }) =>
+                    (Opcode::SPARSE_SWITCH_PAYLOAD,
+                     Payload::SparseSwitch { keys: keys.clone(), targets: relatives(targets)? }),
+                Some(PayloadItem::FillArrayData { element_width, ref data }) =>
+                    (Opcode::FILL_ARRAY_DATA_PAYLOAD, Payload::FillArrayData { element_width, data: data.clone() }),
+                None => continue
+            };
+            payloads.extend(instructions::encode(&Instruction {
+                offset: payload_offsets[i].unwrap() as u32,
+                opcode,
+                length: 0,
+                registers: vec!(),
+                literal: None,
+                branch_offset: None,
+                index: None,
+                proto_index: None,
+                payload: Some(payload)
+            })?);
+        }
+        if !payloads.is_empty() && insns.len() % 2 == 1 {
+            // nop
+            insns.push(0x0000);
+        }
+        insns.extend(payloads);
+
+        let (tries, handlers) = self.tries(&offsets)?;
+        let outs_size = self.items.iter()
+            .filter(|item| item.opcode.is_invoke())
+            .map(|item| item.registers.len() as u16)
+            .max()
+            .unwrap_or(0);
+        let code = Code {
+            registers_size: self.registers_size,
+            ins_size: self.ins_size,
+            outs_size,
+            debug_info: self.debug_info,
+            insns,
+            tries,
+            handlers
+        };
+        Ok((code, references))
+    }
+
+    // where each item starts, then where the instructions end
+    fn offsets(&self) -> Vec<i64> {
+        let mut offsets = vec!(0);
+        for item in &self.items {
+            offsets.push(offsets.last().unwrap() + i64::from(item.opcode.length()));
+        }
+        offsets
+    }
+
+    // Docs: try_item, which have to be in order of address and can't overlap
+    fn tries(&self, offsets: &[i64]) -> Result<(Vec<TryItem>, Vec<EncodedCatchHandler>), DexParserError> {
+        let address = |label: &String| label_offset(&self.labels, offsets, label).map(|offset| offset as u32);
+        let mut tries = vec!();
+        let mut handlers = vec!();
+        for block in &self.tries {
+            let (start, end) = (address(&block.start)?, address(&block.end)?);
+            if end <= start || end - start > u32::from(u16::MAX) {
+                return Err(failed(format!("try block from {} to {} covers {} code units", block.start, block.end,
+                                          i64::from(end) - i64::from(start))));
+            }
+            let handler = EncodedCatchHandler {
+                handlers: block.handlers.iter()
+                    .map(|(type_, label)| Ok(EncodedTypeAddrPair { type_: type_.clone(), addr: address(label)? }))
+                    .collect::<Result<Vec<_>, DexParserError>>()?,
+                catch_all_addr: block.catch_all.as_ref().map(address).transpose()?
+            };
+            if !handlers.contains(&handler) {
+                handlers.push(handler.clone());
+            }
+            tries.push(TryItem { start_addr: start, insn_count: (end - start) as u16, handler });
+        }
+
+        tries.sort_by_key(|try_| try_.start_addr);
+        for pair in tries.windows(2) {
+            if pair[0].start_addr + u32::from(pair[0].insn_count) > pair[1].start_addr {
+                return Err(failed(format!("try blocks at {} and {} overlap", pair[0].start_addr, pair[1].start_addr)));
+            }
+        }
+        Ok((tries, handlers))
+    }
+}
+
+// The code laid out again with its indexes from resolve, for when one no longer fits the instruction using it.
+// const-string becomes const-string/jumbo, and branches, payloads, try blocks and debug info follow the
+// instructions they point at
+pub fn relayout(code: &Code, references: &[CodeReference],
+              resolve: impl Fn(&Reference) -> Result<u32, DexParserError>) -> Result<Code, DexParserError> {
+    let (builder, positions) = CodeBuilder::from_code(code, references)?;
+    let items = builder.items.len();
+    let (mut relaid, _) = builder.assemble(resolve)?;
+
+    // the instructions come first in the new code, one for each item
+    let insns = instructions::decode_all(&relaid.insns)?;
+    let moved = |position: usize| match insns.get(position) {
+        Some(insn) if position < items => insn.offset,
+        _ => insns[.. items].last().map_or(0, |insn| insn.offset + insn.length)
+    };
+    let address = |old: u32| {
+        let i = match positions.binary_search_by_key(&old, |position| position.0) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1)
+        };
+        moved(positions[i].1)
+    };
+    relaid.outs_size = code.outs_size;
+    relaid.debug_info = code.debug_info.as_ref().map(|info| move_debug_info(info, address));
+    Ok(relaid)
+}
+
+// The index of what a reference refers to in the pools of a file
+pub(crate) fn index_of(target: &Reference, idx: &Indexes, call_sites: &[Rc<CallSiteItem>])
+                       -> Result<u32, DexParserError> {
+    match target {
+        Reference::String(s) => idx.string(s),
+        Reference::Type(type_) => idx.type_(type_),
+        Reference::Field(field) => idx.field(field),
+        Reference::Method(method) => idx.method(method),
+        Reference::Prototype(proto) => idx.prototype(proto),
+        Reference::MethodHandle(handle) => idx.method_handle(handle),
+        Reference::CallSite(call_site) => call_sites.iter().position(|c| c == call_site)
+            .map(|i| i as u32)
+            .ok_or_else(|| failed(format!("{:?} is not in the call site pool", call_site)))
+    }
+}
+
+// Docs: debug_info_item, with every address the state machine reaches moved to where address puts it
+fn move_debug_info(info: &DebugInfo, address: impl Fn(u32) -> u32) -> DebugInfo {
+    let (mut old, mut new) = (0, 0);
+    let mut bytecode = vec!();
+    for item in &info.bytecode {
+        match *item {
+            DebugItemBytecodes::DBG_ADVANCE_PC(delta) => {
+                old += delta;
+                let moved = address(old);
+                bytecode.push(DebugItemBytecodes::DBG_ADVANCE_PC(moved.saturating_sub(new)));
+                new = moved;
+            },
+            DebugItemBytecodes::SPECIAL_OPCODE(opcode) => {
+                let adjusted = opcode.saturating_sub(0x0A);
+                old += u32::from(adjusted / 15);
+                let moved = address(old);
+                let delta = moved.saturating_sub(new);
+                // the line part stays, and the address part goes in a DBG_ADVANCE_PC if it no longer fits
+                let line = u32::from(adjusted % 15);
+                match u8::try_from(0x0A + line + 15 * delta) {
+                    Ok(opcode) => bytecode.push(DebugItemBytecodes::SPECIAL_OPCODE(opcode)),
+                    Err(_) => {
+                        bytecode.push(DebugItemBytecodes::DBG_ADVANCE_PC(delta));
+                        bytecode.push(DebugItemBytecodes::SPECIAL_OPCODE(0x0A + line as u8));
+                    }
+                }
+                new = moved;
+            },
+            ref other => bytecode.push(other.clone())
+        }
+    }
+    DebugInfo { line_start: info.line_start, parameter_names: info.parameter_names.clone(), bytecode }
+}
+
+fn failed(reason: String) -> DexParserError {
+    DexParserError::WritingFailed { reason }
+}
+
+fn address_label(offset: u32) -> String {
+    format!("@{}", offset)
+}
+
+fn labels(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+fn label_offset(labels: &HashMap<String, usize>, offsets: &[i64], label: &str) -> Result<i64, DexParserError> {
+    labels.get(label).map(|position| offsets[*position]).ok_or_else(|| failed(format!("no label {}", label)))
+}
+
+fn to_i32(offset: i64) -> Result<i32, DexParserError> {
+    i32::try_from(offset).map_err(|_| failed(format!("branch of {} code units is too far", offset)))
+}
+
+fn range_form(opcode: Opcode) -> Opcode {
+    match opcode {
+        Opcode::INVOKE_VIRTUAL => Opcode::INVOKE_VIRTUAL_RANGE,
+        Opcode::INVOKE_SUPER => Opcode::INVOKE_SUPER_RANGE,
+        Opcode::INVOKE_DIRECT => Opcode::INVOKE_DIRECT_RANGE,
+        Opcode::INVOKE_STATIC => Opcode::INVOKE_STATIC_RANGE,
+        Opcode::INVOKE_INTERFACE => Opcode::INVOKE_INTERFACE_RANGE,
+        Opcode::INVOKE_POLYMORPHIC => Opcode::INVOKE_POLYMORPHIC_RANGE,
+        Opcode::INVOKE_CUSTOM => Opcode::INVOKE_CUSTOM_RANGE,
+        Opcode::FILLED_NEW_ARRAY => Opcode::FILLED_NEW_ARRAY_RANGE,
+        opcode => opcode
+    }
+}
+
+// in code units
+fn payload_length(payload: &PayloadItem) -> i64 {
+    match payload {
+        PayloadItem::PackedSwitch { targets, .. } => 4 + 2 * targets.len() as i64,
+        PayloadItem::SparseSwitch { keys, .. } => 2 + 4 * keys.len() as i64,
+        PayloadItem::FillArrayData { data, .. } => 4 + (data.len() as i64 + 1) / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+    use crate::instructions::decode_all;
+
+    #[test]
+    fn test_layout() {
+        let exception = Rc::new("Ljava/lang/Exception;".to_string());
+        let mut b = CodeBuilder::new(300, 1);
+        b.label("start")
+            .const_(0, 3)
+            .const_(0, 1000)
+            .const_(0, 0x10000)
+            .const_wide(0, 1 << 50)
+            .move_(Opcode::MOVE, 1, 299)
+            .packed_switch(0, 10, &["far", "start"])
+            .branch(Opcode::IF_EQZ, &[0], "far")
+            .label("try")
+            .invoke(Opcode::INVOKE_STATIC, &[10, 11, 12, 13, 14, 15], Reference::Type(exception.clone()))
+            .label("end")
+            .goto("far")
+            .fill_array_data(0, 2, vec!(1, 0, 2, 0));
+        for _ in 0 .. 200 {
+            b.op(Opcode::NOP, &[]);
+        }
+        b.label("far")
+            .const_string(0, Rc::new("s".to_string()))
+            .op(Opcode::RETURN_VOID, &[])
+            .label("handler")
+            .goto("handler")
+            .try_catch("try", "end", &[(exception.clone(), "handler")], None);
+
+        let (code, references) = b.assemble(|target| Ok(if let Reference::String(_) = target { 0x12345 } else { 7 }))
+            .unwrap();
+        let insns = decode_all(&code.insns).unwrap();
+        let opcodes = insns.iter().map(|insn| insn.opcode).take(10).collect::<Vec<_>>();
+        assert_eq!(opcodes, vec!(Opcode::CONST_4, Opcode::CONST_16, Opcode::CONST_HIGH16, Opcode::CONST_WIDE_HIGH16,
+                                 Opcode::MOVE_FROM16, Opcode::PACKED_SWITCH, Opcode::IF_EQZ,
+                                 Opcode::INVOKE_STATIC_RANGE, Opcode::GOTO_16, Opcode::FILL_ARRAY_DATA));
+
+        let far = insns.iter().find(|insn| insn.opcode == Opcode::CONST_STRING_JUMBO).unwrap();
+        assert_eq!(far.index, Some(0x12345));
+        assert_eq!(insns[6].target(), Some(far.offset));
+        assert_eq!(insns[8].target(), Some(far.offset));
+        let self_loop = insns.iter().find(|insn| insn.opcode == Opcode::GOTO_32).unwrap();
+        assert_eq!(self_loop.target(), Some(self_loop.offset));
+
+        let switch = insns[5].target().unwrap();
+        assert_eq!(switch % 2, 0);
+        let payload = insns.iter().find(|insn| insn.offset == switch).unwrap().payload.clone().unwrap();
+        assert_eq!(payload.switch_cases(), vec!((10, far.offset as i32 - insns[5].offset as i32),
+                                                (11, -(insns[5].offset as i32))));
+        let array = insns.iter().find(|insn| Some(insn.offset) == insns[9].target()).unwrap();
+        assert_eq!(array.payload, Some(Payload::FillArrayData { element_width: 2, data: vec!(1, 0, 2, 0) }));
+
+        assert_eq!(code.outs_size, 6);
+        assert_eq!(code.tries.len(), 1);
+        assert_eq!(code.tries[0].start_addr, insns[7].offset);
+        assert_eq!(code.tries[0].insn_count as u32, insns[7].length);
+        assert_eq!(code.tries[0].handler.handlers[0].addr, self_loop.offset);
+        assert_eq!(references.iter().map(|r| r.offset).collect::<Vec<_>>(), vec!(insns[7].offset, far.offset));
+
+        let mut b = CodeBuilder::new(1, 0);
+        b.label("a").label("a").op(Opcode::RETURN_VOID, &[]);
+        assert!(b.build_references().is_err());
+        let mut b = CodeBuilder::new(1, 0);
+        b.goto("nowhere");
+        assert!(b.build_references().is_err());
+    }
+
+    #[test]
+    fn test_build() {
+        let mut b = DexBuilder::new(35);
+        let hello = b.string("hello");
+        let println = b.method("Ljava/io/PrintStream;", "println", "V", &["Ljava/lang/String;"]);
+        let out = b.field("Ljava/lang/System;", "out", "Ljava/io/PrintStream;");
+        let main = b.method("LMain;", "main", "V", &[]);
+        let class_type = b.type_("LMain;");
+        b.class(ClassDefinition {
+            class_type, access_flags: vec!(AccessFlag::ACC_PUBLIC), superclass: None,
+            interfaces: vec!(), source_file_name: None, annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(), instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod { method: main, access_flags: vec!(AccessFlag::ACC_STATIC),
+                                                     code: None, system_annotations: Default::default() }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(), system_annotations: Default::default()
+        });
+        let mut file = b.build().unwrap();
+
+        let mut code = CodeBuilder::new(2, 0);
+        code.reference(Opcode::SGET_OBJECT, &[0], Reference::Field(out))
+            .const_string(1, hello)
+            .invoke(Opcode::INVOKE_VIRTUAL, &[0, 1], Reference::Method(println))
+            .op(Opcode::RETURN_VOID, &[]);
+        let code = code.build(&file.file_data).unwrap();
+        assert_eq!(code.outs_size, 2);
+        file.classes[0].class_data.as_mut().unwrap().direct_methods[0].code = Some(code);
+
+        let parsed = crate::parse(&crate::write(&file).unwrap()).unwrap();
+        let insns = decode_all(&parsed.classes[0].class_data.as_ref().unwrap().direct_methods[0].code.as_ref()
+            .unwrap().insns).unwrap();
+        let string = insns[1].index.unwrap() as usize;
+        assert_eq!(parsed.file_data.string_data[string].as_str(), "hello");
+        let method = insns[2].index.unwrap() as usize;
+        assert_eq!(parsed.file_data.methods[method].name.as_str(), "println");
+    }
+
+    // Every string lands past index 0xFFFF, so each const-string has to become const-string/jumbo
+    fn widen(code: &Code, references: &[CodeReference]) -> Code {
+        relayout(code, references, |target| Ok(if let Reference::String(_) = target { 0x10000 } else { 3 })).unwrap()
+    }
+
+    #[test]
+    fn test_relayout() {
+        let mut b = CodeBuilder::new(2, 0);
+        b.label("loop")
+            .const_(0, 1)
+            .const_string(1, Rc::new("s".to_string()))
+            .branch(Opcode::IF_EQZ, &[0], "end")
+            .sparse_switch(0, &[(5, "loop"), (100, "end")])
+            .fill_array_data(1, 2, vec!(1, 0, 2, 0))
+            .goto("loop")
+            .label("end")
+            .op(Opcode::RETURN_VOID, &[]);
+        let (code, references) = b.build_references().unwrap();
+        let old = decode_all(&code.insns).unwrap();
+
+        let relaid = widen(&code, &references);
+        assert_eq!((relaid.registers_size, relaid.ins_size, relaid.outs_size), (2, 0, 0));
+        let insns = decode_all(&relaid.insns).unwrap();
+        assert_eq!(insns.iter().map(|insn| insn.opcode).take(7).collect::<Vec<_>>(),
+                   vec!(Opcode::CONST_4, Opcode::CONST_STRING_JUMBO, Opcode::IF_EQZ, Opcode::SPARSE_SWITCH,
+                        Opcode::FILL_ARRAY_DATA, Opcode::GOTO, Opcode::RETURN_VOID));
+        assert_eq!(insns[1].index, Some(0x10000));
+        // everything after the widened instruction is a unit further on
+        for (insn, old) in insns[2 .. 7].iter().zip(&old[2 .. 7]) {
+            assert_eq!(insn.offset, old.offset + 1);
+        }
+        let end = insns[6].offset;
+        assert_eq!((insns[2].target(), insns[5].target()), (Some(end), Some(0)));
+
+        // the payloads move too, still aligned, with the switch targets relative to the switch where it is now
+        let payload = |insn: &Instruction| {
+            let target = insn.target().unwrap();
+            assert_eq!(target % 2, 0);
+            insns.iter().find(|payload| payload.offset == target).unwrap().payload.clone().unwrap()
+        };
+        let switch = insns[3].offset as i32;
+        assert_eq!(payload(&insns[3]).switch_cases(), vec!((5, -switch), (100, end as i32 - switch)));
+        assert_eq!(payload(&insns[4]), Payload::FillArrayData { element_width: 2, data: vec!(1, 0, 2, 0) });
+
+        // an index with nothing said about what it refers to can't be resolved again
+        assert!(relayout(&code, &[], |_| Ok(0)).is_err());
+    }
+
+    #[test]
+    fn test_relayout_tries() {
+        let exception = Rc::new("Ljava/lang/Exception;".to_string());
+        let mut b = CodeBuilder::new(1, 0);
+        b.label("start")
+            .const_(0, 1)
+            .const_string(0, Rc::new("s".to_string()))
+            .const_(0, 2)
+            .label("after")
+            .op(Opcode::RETURN_VOID, &[])
+            .label("handler")
+            .op(Opcode::RETURN_VOID, &[])
+            .label("end")
+            // one try block around the widened instruction and one wholly after it
+            .try_catch("start", "after", &[(exception.clone(), "handler")], None)
+            .try_catch("after", "handler", &[], Some("handler"));
+        let (code, references) = b.build_references().unwrap();
+        assert_eq!(code.tries.iter().map(|t| (t.start_addr, t.insn_count)).collect::<Vec<_>>(),
+                   vec!((0, 4), (4, 1)));
+
+        let relaid = widen(&code, &references);
+        assert_eq!(relaid.tries.iter().map(|t| (t.start_addr, t.insn_count)).collect::<Vec<_>>(),
+                   vec!((0, 5), (5, 1)));
+        assert_eq!(relaid.tries[0].handler.handlers[0].type_, exception);
+        assert_eq!(relaid.tries[0].handler.handlers[0].addr, 6);
+        assert_eq!(relaid.tries[1].handler.catch_all_addr, Some(6));
+        assert_eq!(relaid.handlers.len(), 2);
+    }
+
+    #[test]
+    fn test_relayout_debug_info() {
+        let string = Rc::new("s".to_string());
+        // a position at each instruction, the line going up by one each time: const/4 at 0, const-string at 1,
+        // const/4 at 3 and return-void at 4
+        let special = |line: u8, address: u8| DebugItemBytecodes::SPECIAL_OPCODE(0x0A + 4 + line + 15 * address);
+        let mut b = CodeBuilder::new(1, 0);
+        b.debug_info(DebugInfo { line_start: 10, parameter_names: vec!(), bytecode: vec!(
+                special(0, 0), special(1, 1), special(1, 2), DebugItemBytecodes::DBG_ADVANCE_PC(1), special(1, 0)
+            ) })
+            .const_(0, 1)
+            .const_string(0, string.clone())
+            .const_(0, 2)
+            .op(Opcode::RETURN_VOID, &[]);
+        let (code, references) = b.build_references().unwrap();
+
+        let relaid = widen(&code, &references);
+        assert_eq!(relaid.debug_info.unwrap().bytecode, vec!(
+            special(0, 0), special(1, 1), special(1, 3), DebugItemBytecodes::DBG_ADVANCE_PC(1), special(1, 0)
+        ));
+
+        // a position far enough past the widened instruction that its address no longer fits a special opcode
+        let mut b = CodeBuilder::new(1, 0);
+        b.debug_info(DebugInfo { line_start: 1, parameter_names: vec!(), bytecode: vec!(special(1, 16)) })
+            .const_string(0, string);
+        for _ in 0 .. 14 {
+            b.op(Opcode::NOP, &[]);
+        }
+        b.op(Opcode::RETURN_VOID, &[]);
+        let (code, references) = b.build_references().unwrap();
+
+        let relaid = widen(&code, &references);
+        assert_eq!(relaid.debug_info.unwrap().bytecode,
+                   vec!(DebugItemBytecodes::DBG_ADVANCE_PC(17), special(1, 0)));
+    }
+}
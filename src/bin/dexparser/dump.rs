@@ -0,0 +1,374 @@
+// Text renderings of classes and their code, laid out after dexdump's
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use dexparser::instructions::{self, Format, IndexKind, Instruction, Payload};
+use dexparser::{access_flag_bits, AccessFlag, ClassDefinition, Code, DebugInfo, DebugItemBytecodes, DexFile,
+                EncodedField, EncodedMethod, EncodedValue, Field, Method, Prototype};
+
+// the hex column shows at most this many code units of an instruction
+const HEX_UNITS: usize = 6;
+
+// `(ILjava/lang/String;)V`
+pub fn prototype_descriptor(prototype: &Prototype) -> String {
+    let parameters = prototype.parameters.iter().map(|p| p.as_str()).collect::<String>();
+    format!("({}){}", parameters, prototype.return_type)
+}
+
+// `Lcom/foo/Bar;.baz:(I)V`
+pub fn method_descriptor(method: &Method) -> String {
+    format!("{}.{}:{}", method.definer, method.name, prototype_descriptor(&method.prototype))
+}
+
+// `Lcom/foo/Bar;.count:I`
+fn field_descriptor(field: &Field) -> String {
+    format!("{}.{}:{}", field.definer, field.name, field.type_)
+}
+
+// `0x0011 (PUBLIC FINAL)`
+fn access(flags: &[AccessFlag]) -> String {
+    let names = flags.iter()
+        .map(|flag| format!("{:?}", flag).trim_start_matches("ACC_").to_string())
+        .collect::<Vec<_>>();
+    format!("0x{:04x} ({})", access_flag_bits(flags), names.join(" "))
+}
+
+pub fn class(out: &mut dyn Write, file: &DexFile, index: usize, class: &ClassDefinition) -> io::Result<()> {
+    writeln!(out, "Class #{:<12} -", index)?;
+    writeln!(out, "  Class descriptor  : '{}'", class.class_type)?;
+    writeln!(out, "  Access flags      : {}", access(&class.access_flags))?;
+    writeln!(out, "  Superclass        : '{}'", class.superclass.as_ref().map_or("", |s| s.as_str()))?;
+    writeln!(out, "  Interfaces        -")?;
+    for (i, interface) in class.interfaces.iter().enumerate() {
+        writeln!(out, "    #{:<14} : '{}'", i, interface)?;
+    }
+
+    let no_fields = vec!();
+    let no_methods = vec!();
+    let data = class.class_data.as_ref();
+    writeln!(out, "  Static fields     -")?;
+    for (i, field) in data.map_or(&no_fields, |data| &data.static_fields).iter().enumerate() {
+        encoded_field(out, i, field, class.static_values.get(i))?;
+    }
+    writeln!(out, "  Instance fields   -")?;
+    for (i, field) in data.map_or(&no_fields, |data| &data.instance_fields).iter().enumerate() {
+        encoded_field(out, i, field, None)?;
+    }
+    writeln!(out, "  Direct methods    -")?;
+    for (i, method) in data.map_or(&no_methods, |data| &data.direct_methods).iter().enumerate() {
+        encoded_method(out, file, i, method)?;
+    }
+    writeln!(out, "  Virtual methods   -")?;
+    for (i, method) in data.map_or(&no_methods, |data| &data.virtual_methods).iter().enumerate() {
+        encoded_method(out, file, i, method)?;
+    }
+    writeln!(out, "  source_file       : {}", class.source_file_name.as_ref().map_or("(unknown)", |s| s.as_str()))?;
+    writeln!(out)
+}
+
+fn encoded_field(out: &mut dyn Write, index: usize, field: &EncodedField, value: Option<&EncodedValue>)
+                 -> io::Result<()> {
+    writeln!(out, "    #{:<14} : (in {})", index, field.field.definer)?;
+    writeln!(out, "      name          : '{}'", field.field.name)?;
+    writeln!(out, "      type          : '{}'", field.field.type_)?;
+    writeln!(out, "      access        : {}", access(&field.access_flags))?;
+    if let Some(value) = value {
+        writeln!(out, "      value         : {}", encoded_value(value))?;
+    }
+    Ok(())
+}
+
+fn encoded_method(out: &mut dyn Write, file: &DexFile, index: usize, method: &EncodedMethod) -> io::Result<()> {
+    writeln!(out, "    #{:<14} : (in {})", index, method.method.definer)?;
+    writeln!(out, "      name          : '{}'", method.method.name)?;
+    writeln!(out, "      type          : '{}'", prototype_descriptor(&method.method.prototype))?;
+    writeln!(out, "      access        : {}", access(&method.access_flags))?;
+    let code = match method.code {
+        Some(ref code) => code,
+        None => return writeln!(out, "      code          : (none)")
+    };
+    writeln!(out, "      code          -")?;
+    writeln!(out, "      registers     : {}", code.registers_size)?;
+    writeln!(out, "      ins           : {}", code.ins_size)?;
+    writeln!(out, "      outs          : {}", code.outs_size)?;
+    writeln!(out, "      insns size    : {} 16-bit code units", code.insns.len())?;
+    listing(out, file, code, "      ")?;
+    catches(out, code, "      ")?;
+    if let Some(ref debug_info) = code.debug_info {
+        debug(out, method, code, debug_info)?;
+    }
+    Ok(())
+}
+
+// Every instruction of the code, with its address and code units
+pub fn listing(out: &mut dyn Write, file: &DexFile, code: &Code, indent: &str) -> io::Result<()> {
+    let mut offset = 0;
+    while (offset as usize) < code.insns.len() {
+        let insn = match instructions::decode(&code.insns, offset) {
+            Ok(insn) => insn,
+            Err(e) => return writeln!(out, "{}{:04x}: <{}>", indent, offset, e)
+        };
+        let units = &code.insns[offset as usize .. (offset + insn.length.max(1)) as usize];
+        let mut hex = units.iter().take(HEX_UNITS).map(|unit| format!("{:04x}", unit)).collect::<Vec<_>>().join(" ");
+        if units.len() > HEX_UNITS {
+            hex.push_str(" ...");
+        }
+        writeln!(out, "{}{:04x}: {:<34}|{}", indent, offset, hex, instruction(file, &insn))?;
+        offset += insn.length.max(1);
+    }
+    Ok(())
+}
+
+pub fn catches(out: &mut dyn Write, code: &Code, indent: &str) -> io::Result<()> {
+    if code.tries.is_empty() {
+        return writeln!(out, "{}catches       : (none)", indent);
+    }
+    writeln!(out, "{}catches       : {}", indent, code.tries.len())?;
+    for try_item in &code.tries {
+        let end = try_item.start_addr + u32::from(try_item.insn_count);
+        writeln!(out, "{}  0x{:04x} - 0x{:04x}", indent, try_item.start_addr, end)?;
+        for handler in &try_item.handler.handlers {
+            writeln!(out, "{}    {} -> 0x{:04x}", indent, handler.type_, handler.addr)?;
+        }
+        if let Some(addr) = try_item.handler.catch_all_addr {
+            writeln!(out, "{}    <any> -> 0x{:04x}", indent, addr)?;
+        }
+    }
+    Ok(())
+}
+
+// A local variable's live range, from running the debug info state machine
+#[derive(Clone)]
+struct Local {
+    start: u32,
+    end: u32,
+    register: u32,
+    name: Option<Rc<String>>,
+    type_: Option<Rc<String>>,
+    signature: Option<Rc<String>>
+}
+
+// The line table and local variables the debug info describes
+fn debug(out: &mut dyn Write, method: &EncodedMethod, code: &Code, info: &DebugInfo) -> io::Result<()> {
+    let mut address = 0u32;
+    let mut line = i64::from(info.line_start);
+    let mut positions = vec!();
+    let mut live: HashMap<u32, Local> = HashMap::new();
+    let mut locals = vec!();
+
+    // the parameters are live from the start, in the last registers of the frame
+    let mut register = u32::from(code.registers_size.saturating_sub(code.ins_size));
+    if !method.access_flags.contains(&AccessFlag::ACC_STATIC) {
+        let type_ = Some(method.method.definer.clone());
+        live.insert(register, Local { start: 0, end: 0, register, name: Some(Rc::new("this".to_string())), type_,
+                                      signature: None });
+        register += 1;
+    }
+    for (i, parameter) in method.method.prototype.parameters.iter().enumerate() {
+        if let Some(Some(name)) = info.parameter_names.get(i) {
+            live.insert(register, Local { start: 0, end: 0, register, name: Some(name.clone()),
+                                          type_: Some(parameter.clone()), signature: None });
+        }
+        register += if **parameter == "J" || **parameter == "D" { 2 } else { 1 };
+    }
+
+    // the last variable each register held, for DBG_RESTART_LOCAL
+    let mut ended: HashMap<u32, Local> = HashMap::new();
+    for item in &info.bytecode {
+        let started = match item {
+            DebugItemBytecodes::DBG_ADVANCE_PC(n) => {
+                address += n;
+                None
+            },
+            DebugItemBytecodes::DBG_ADVANCE_LINE(n) => {
+                line += i64::from(*n);
+                None
+            },
+            DebugItemBytecodes::DBG_START_LOCAL { register_num, name, type_ } =>
+                Some(Local { start: address, end: 0, register: *register_num, name: name.clone(),
+                             type_: type_.clone(), signature: None }),
+            DebugItemBytecodes::DBG_START_LOCAL_EXTENDED { register_num, name, type_, sig } =>
+                Some(Local { start: address, end: 0, register: *register_num, name: name.clone(),
+                             type_: type_.clone(), signature: sig.clone() }),
+            DebugItemBytecodes::DBG_END_LOCAL(register) => {
+                if let Some(mut local) = live.remove(register) {
+                    local.end = address;
+                    ended.insert(*register, local.clone());
+                    locals.push(local);
+                }
+                None
+            },
+            DebugItemBytecodes::DBG_RESTART_LOCAL(register) =>
+                ended.get(register).map(|local| Local { start: address, ..local.clone() }),
+            DebugItemBytecodes::SPECIAL_OPCODE(opcode) => {
+                let adjusted = i64::from(*opcode) - 0x0A;
+                line += -4 + adjusted % 15;
+                address += (adjusted / 15) as u32;
+                positions.push((address, line));
+                None
+            },
+            _ => None
+        };
+        if let Some(started) = started {
+            if let Some(mut local) = live.insert(started.register, started) {
+                local.end = address;
+                locals.push(local);
+            }
+        }
+    }
+    let end = code.insns.len() as u32;
+    locals.extend(live.into_values().map(|local| Local { end, ..local }));
+    locals.sort_by_key(|local| (local.start, local.register));
+
+    writeln!(out, "      positions     :")?;
+    for (address, line) in positions {
+        writeln!(out, "        0x{:04x} line={}", address, line)?;
+    }
+    writeln!(out, "      locals        :")?;
+    let text = |s: &Option<Rc<String>>| s.as_ref().map_or(String::new(), |s| s.to_string());
+    for local in locals {
+        let line = format!("        0x{:04x} - 0x{:04x} reg={} {} {} {}", local.start, local.end, local.register,
+                           text(&local.name), text(&local.type_), text(&local.signature));
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+// An instruction as dexdump writes it, e.g. `invoke-virtual {v0, v1}, Lfoo;.bar:(I)V // method@0003`
+pub fn instruction(file: &DexFile, insn: &Instruction) -> String {
+    if let Some(ref payload) = insn.payload {
+        let size = match payload {
+            Payload::PackedSwitch { targets, .. } | Payload::SparseSwitch { targets, .. } =>
+                format!("{} cases", targets.len()),
+            Payload::FillArrayData { element_width, data } =>
+                format!("{} elements of {} bytes", data.len() / usize::from((*element_width).max(1)), element_width)
+        };
+        return format!("{} ({} units, {})", insn.opcode.name(), insn.length, size);
+    }
+
+    let registers = insn.registers.iter().map(|r| format!("v{}", r)).collect::<Vec<_>>();
+    let mut operands = match insn.opcode.format() {
+        Format::F35c | Format::F45cc => vec!(format!("{{{}}}", registers.join(", "))),
+        Format::F3rc | Format::F4rcc => match (insn.registers.first(), insn.registers.last()) {
+            (Some(first), Some(last)) => vec!(format!("{{v{} .. v{}}}", first, last)),
+            _ => vec!("{}".to_string())
+        },
+        _ => registers
+    };
+    let mut comments = vec!();
+    if let Some(literal) = insn.literal {
+        let wide = insn.opcode.name().starts_with("const-wide");
+        operands.push(format!("#{} {}", if wide { "long" } else { "int" }, literal));
+        comments.push(format!("#{:x}", literal));
+    }
+    if let (Some(target), Some(relative)) = (insn.target(), insn.branch_offset) {
+        operands.push(format!("{:04x}", target));
+        comments.push(format!("{}{:04x}", if relative < 0 { "-" } else { "+" }, relative.unsigned_abs()));
+    }
+    if let Some(index) = insn.index {
+        let (text, kind) = reference(file, insn.opcode.index_kind(), index);
+        operands.push(text);
+        comments.push(format!("{}@{:04x}", kind, index));
+    }
+    if let Some(index) = insn.proto_index {
+        let (text, kind) = reference(file, IndexKind::Prototype, index);
+        operands.push(text);
+        comments.push(format!("{}@{:04x}", kind, index));
+    }
+
+    let mut text = insn.opcode.name().to_string();
+    if !operands.is_empty() {
+        text = format!("{} {}", text, operands.join(", "));
+    }
+    if !comments.is_empty() {
+        text = format!("{} // {}", text, comments.join(", "));
+    }
+    text
+}
+
+// The item an index refers to, and the name of its pool. Indexes out of range are left for the comment to show
+fn reference(file: &DexFile, kind: IndexKind, index: u32) -> (String, &'static str) {
+    let data = &file.file_data;
+    let i = index as usize;
+    let (text, kind) = match kind {
+        IndexKind::String => (data.string_data.get(i).map(|s| format!("{:?}", s)), "string"),
+        IndexKind::Type => (data.type_identifiers.get(i).map(|t| t.to_string()), "type"),
+        IndexKind::Field => (data.fields.get(i).map(|f| field_descriptor(f)), "field"),
+        IndexKind::Method => (data.methods.get(i).map(|m| method_descriptor(m)), "method"),
+        IndexKind::Prototype => (data.prototypes.get(i).map(|p| prototype_descriptor(p)), "proto"),
+        IndexKind::CallSite => (data.call_sites.get(i).map(|c| format!("{}{}", c.method_name,
+                                                                        prototype_descriptor(&c.method_type))),
+                                "call_site"),
+        IndexKind::MethodHandle => (None, "method_handle"),
+        IndexKind::None => (None, "index")
+    };
+    (text.unwrap_or_else(|| format!("{}@{}", kind, index)), kind)
+}
+
+// A static field's initial value
+fn encoded_value(value: &EncodedValue) -> String {
+    match value {
+        EncodedValue::Byte(v) => v.to_string(),
+        EncodedValue::Short(v) => v.to_string(),
+        EncodedValue::Char(v) => v.to_string(),
+        EncodedValue::Int(v) => v.to_string(),
+        EncodedValue::Long(v) => v.to_string(),
+        EncodedValue::Float(v) => v.to_string(),
+        EncodedValue::Double(v) => v.to_string(),
+        EncodedValue::Boolean(v) => v.to_string(),
+        EncodedValue::Null => "null".to_string(),
+        EncodedValue::String(s) => format!("{:?}", s),
+        EncodedValue::Type(t) => t.to_string(),
+        EncodedValue::Field(f) | EncodedValue::Enum(f) => field_descriptor(f),
+        EncodedValue::Method(m) => method_descriptor(m),
+        EncodedValue::MethodType(p) => prototype_descriptor(p),
+        EncodedValue::MethodHandle(h) => format!("{:?}", h.type_),
+        EncodedValue::Array(values) =>
+            format!("[{}]", values.iter().map(encoded_value).collect::<Vec<_>>().join(", ")),
+        EncodedValue::Annotation(annotation) => {
+            let elements = annotation.values.iter()
+                .map(|element| format!("{}={}", element.name, encoded_value(&element.value)))
+                .collect::<Vec<_>>();
+            format!("@{}({})", annotation.type_, elements.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dexparser::DexBuilder;
+
+    #[test]
+    fn test_instruction() {
+        let mut b = DexBuilder::new(35);
+        b.string("hello");
+        b.method("LMain;", "run", "V", &["I", "Ljava/lang/String;"]);
+        let file = b.build().unwrap();
+        let string = file.file_data.string_data.iter().position(|s| **s == "hello").unwrap() as u16;
+        let method = file.file_data.methods.iter().position(|m| *m.name == "run").unwrap() as u16;
+
+        let insns = vec!(
+            0x5012,                 // const/4 v0, #5
+            0x011A, string,         // const-string v1, "hello"
+            0x306E, method, 0x0210, // invoke-virtual {v0, v1, v2}, LMain;.run
+            0x0038, 0xFFFA,         // if-eqz v0, -6
+            0x0118, 0, 0, 0, 0x100, // const-wide v1, #0x0100000000000000
+            0x000E                  // return-void
+        );
+        let text = instructions::decode_all(&insns).unwrap().iter()
+            .map(|insn| instruction(&file, insn))
+            .collect::<Vec<_>>();
+        assert_eq!(text, vec!(
+            "const/4 v0, #int 5 // #5",
+            format!("const-string v1, \"hello\" // string@{:04x}", string).as_str(),
+            format!("invoke-virtual {{v0, v1, v2}}, LMain;.run:(ILjava/lang/String;)V // method@{:04x}", method)
+                .as_str(),
+            "if-eqz v0, 0000 // -0006",
+            "const-wide v1, #long 72057594037927936 // #100000000000000",
+            "return-void"
+        ));
+    }
+}
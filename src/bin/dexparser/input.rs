@@ -0,0 +1,123 @@
+// Finding the .dex files behind the paths given on the command line
+
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+// A .dex file, named by its path or, for an entry of an archive, `app.apk!classes2.dex`
+pub struct Input {
+    pub name: String,
+    pub bytes: Vec<u8>
+}
+
+// The .dex files a path leads to: the file itself, the classes*.dex entries of an .apk, or every .dex and .apk
+// under a directory. A file that can't be read gives an error in its place, so the others still get looked at
+pub fn read(path: &Path) -> Vec<Result<Input, String>> {
+    if !path.is_dir() {
+        return read_file(path);
+    }
+    let mut files = vec!();
+    match walk(path, &mut files) {
+        Ok(()) => files.iter().flat_map(|file| read_file(file)).collect(),
+        Err(e) => vec!(Err(e))
+    }
+}
+
+// Archives are told apart by their contents rather than their extension, anything else is taken to be a .dex file
+fn read_file(path: &Path) -> Vec<Result<Input, String>> {
+    let name = path.display().to_string();
+    match fs::read(path) {
+        Ok(ref bytes) if bytes.starts_with(ZIP_MAGIC) => archive(&name, bytes),
+        Ok(bytes) => vec!(Ok(Input { name, bytes })),
+        Err(e) => vec!(Err(format!("{}: {}", name, e)))
+    }
+}
+
+// The .dex and .apk files under a directory, in name order. Symbolic links aren't followed
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let err = |e: std::io::Error| format!("{}: {}", dir.display(), e);
+    let mut entries = fs::read_dir(dir).map_err(err)?
+        .collect::<Result<Vec<_>, _>>().map_err(err)?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type().map_err(err)?;
+        let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        if file_type.is_dir() {
+            walk(&path, files)?;
+        } else if file_type.is_file() && matches!(extension.as_deref(), Some("dex") | Some("apk")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// The classes.dex, classes2.dex, ... entries at the root of an archive, in the order the runtime loads them
+fn archive(name: &str, bytes: &[u8]) -> Vec<Result<Input, String>> {
+    let mut archive = match zip::ZipArchive::new(Cursor::new(bytes)) {
+        Ok(archive) => archive,
+        Err(e) => return vec!(Err(format!("{}: {}", name, e)))
+    };
+    let mut entries = archive.file_names()
+        .filter_map(|entry| classes_number(entry).map(|number| (number, entry.to_string())))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return vec!(Err(format!("{}: no classes.dex in the archive", name)));
+    }
+    entries.sort();
+
+    entries.into_iter()
+        .map(|(_, entry)| {
+            let name = format!("{}!{}", name, entry);
+            let mut bytes = vec!();
+            archive.by_name(&entry)
+                .map_err(|e| e.to_string())
+                .and_then(|mut file| file.read_to_end(&mut bytes).map_err(|e| e.to_string()))
+                .map_err(|e| format!("{}: {}", name, e))?;
+            Ok(Input { name, bytes })
+        })
+        .collect()
+}
+
+// 1 for classes.dex, n for classesn.dex
+fn classes_number(entry: &str) -> Option<u32> {
+    let number = entry.strip_prefix("classes")?.strip_suffix(".dex")?;
+    if number.is_empty() {
+        Some(1)
+    } else if number.bytes().all(|b| b.is_ascii_digit()) && !number.starts_with('0') {
+        number.parse().ok().filter(|n| *n >= 2)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_archive() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec!()));
+        for (entry, contents) in &[("classes10.dex", "ten"), ("res/classes3.dex", "nested"), ("classes2.dex", "two"),
+                                   ("AndroidManifest.xml", "manifest"), ("classes.dex", "one"), ("classes1.dex", "?")] {
+            writer.start_file(*entry, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let inputs = archive("app.apk", &bytes).into_iter().map(Result::unwrap)
+            .map(|input| (input.name, String::from_utf8(input.bytes).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(inputs, vec!(
+            ("app.apk!classes.dex".to_string(), "one".to_string()),
+            ("app.apk!classes2.dex".to_string(), "two".to_string()),
+            ("app.apk!classes10.dex".to_string(), "ten".to_string())
+        ));
+
+        assert_eq!(archive("bad.apk", b"PK\x03\x04 not really").len(), 1);
+        assert!(archive("bad.apk", b"PK\x03\x04 not really")[0].is_err());
+    }
+}
@@ -0,0 +1,484 @@
+// The dexparser command line tool, for looking into .dex files and the ones inside .apk files without writing any
+// Rust. Built with the `cli` feature:
+//
+//     cargo install dexparser --features cli
+//     dexparser classes --package com.example app.apk
+
+mod dump;
+mod input;
+
+use std::cell::Cell;
+use std::env;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use dexparser::{java, sizes, verifier, AccessFlag, ClassDefinition, DexFile, ParseOptions};
+
+use crate::input::Input;
+
+const USAGE: &str = "\
+usage: dexparser <command> [options] <path>...
+
+Each path is a .dex file, an .apk (or any zip) whose classes*.dex entries are read, or a directory searched
+for both.
+
+commands:
+    info                   the header and the size of every pool
+    classes                list the classes defined, one per line
+        --package <name>       only those in the package or its subpackages
+        --match <text>         only those whose name contains the text
+        --access <flags>       only those with all of the flags, e.g. public,interface
+        --long                 the whole declaration rather than just the name
+    dump                   classes, fields and methods with their code, like dexdump -d
+        --class <name>         only the class, by Java name or descriptor
+    disasm                 the bytecode of every method
+        --class <name>         only the methods of the class, by Java name or descriptor
+        --method <name>        only the methods with the name
+    strings                the string pool, escaped one per line
+        --match <text>         only the strings containing the text
+    verify                 check the checksum and the bytecode of every method, failing on any problem
+    json                   an object from each file's name to the file, as described by
+                           schema/dex-file.schema.json
+        --pretty               indented
+    sizes                  the bytes of each file attributed to packages, classes, methods and fields
+        --depth <n>            only n levels below the root, packages being a level each
+        --json                 as JSON, an object from each file's name to its report
+        --pretty               indented JSON
+";
+
+struct Args {
+    command: String,
+    paths: Vec<PathBuf>,
+    package: Option<String>,
+    text: Option<String>,
+    access: Vec<AccessFlag>,
+    long: bool,
+    class: Option<String>,
+    method: Option<String>,
+    pretty: bool,
+    depth: Option<usize>,
+    json: bool
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", USAGE);
+        return;
+    }
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("dexparser: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let ok = match run(&args, &mut out).and_then(|ok| out.flush().map(|_| ok)) {
+        Ok(ok) => ok,
+        // the output was piped into something like head, which has seen all it wants
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => true,
+        Err(e) => {
+            eprintln!("dexparser: {}", e);
+            false
+        }
+    };
+    process::exit(if ok { 0 } else { 1 });
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let command = args[0].clone();
+    if !["info", "classes", "dump", "disasm", "strings", "verify", "json", "sizes"].contains(&command.as_str()) {
+        return Err(format!("unknown command '{}'", command));
+    }
+    let mut parsed = Args { command, paths: vec!(), package: None, text: None, access: vec!(), long: false,
+                            class: None, method: None, pretty: false, depth: None, json: false };
+
+    let mut rest = args[1 ..].iter();
+    let mut options_ended = false;
+    while let Some(arg) = rest.next() {
+        if options_ended || !arg.starts_with("--") {
+            parsed.paths.push(PathBuf::from(arg));
+            continue;
+        }
+        if arg == "--" {
+            options_ended = true;
+            continue;
+        }
+        // both `--name value` and `--name=value`
+        let (option, inline) = match arg.find('=') {
+            Some(i) => (&arg[.. i], Some(arg[i + 1 ..].to_string())),
+            None => (arg.as_str(), None)
+        };
+        if !accepts(&parsed.command, option) {
+            return Err(format!("{} doesn't take {}", parsed.command, option));
+        }
+        let mut value = || inline.clone().or_else(|| rest.next().cloned())
+            .ok_or_else(|| format!("{} needs a value", option));
+        match option {
+            "--package" => parsed.package = Some(value()?.replace('/', ".").trim_end_matches('.').to_string()),
+            "--match" => parsed.text = Some(value()?),
+            "--access" => for name in value()?.split(',').filter(|name| !name.is_empty()) {
+                parsed.access.push(access_flag(name).ok_or_else(|| format!("unknown access flag '{}'", name))?);
+            },
+            "--class" => parsed.class = Some(value()?),
+            "--method" => parsed.method = Some(value()?),
+            "--long" => parsed.long = true,
+            "--pretty" => parsed.pretty = true,
+            "--depth" => {
+                let depth = value()?;
+                parsed.depth = Some(depth.parse().map_err(|_| format!("--depth needs a number, not '{}'", depth))?);
+            },
+            "--json" => parsed.json = true,
+            _ => unreachable!()
+        }
+    }
+
+    if parsed.paths.is_empty() {
+        return Err("no paths given".to_string());
+    }
+    Ok(parsed)
+}
+
+fn accepts(command: &str, option: &str) -> bool {
+    matches!((command, option),
+        ("classes", "--package") | ("classes", "--match") | ("classes", "--access") | ("classes", "--long")
+        | ("dump", "--class")
+        | ("disasm", "--class") | ("disasm", "--method")
+        | ("strings", "--match")
+        | ("json", "--pretty")
+        | ("sizes", "--depth") | ("sizes", "--json") | ("sizes", "--pretty"))
+}
+
+fn access_flag(name: &str) -> Option<AccessFlag> {
+    Some(match name.to_ascii_lowercase().as_str() {
+        "public" => AccessFlag::ACC_PUBLIC,
+        "private" => AccessFlag::ACC_PRIVATE,
+        "protected" => AccessFlag::ACC_PROTECTED,
+        "static" => AccessFlag::ACC_STATIC,
+        "final" => AccessFlag::ACC_FINAL,
+        "interface" => AccessFlag::ACC_INTERFACE,
+        "abstract" => AccessFlag::ACC_ABSTRACT,
+        "synthetic" => AccessFlag::ACC_SYNTHETIC,
+        "annotation" => AccessFlag::ACC_ANNOTATION,
+        "enum" => AccessFlag::ACC_ENUM,
+        _ => return None
+    })
+}
+
+// Runs the command over every file the paths lead to, reporting those that can't be read or parsed on stderr.
+// Returns whether they all could, and for verify whether they all passed
+fn run(args: &Args, out: &mut dyn Write) -> io::Result<bool> {
+    let mut ok = true;
+    let mut inputs = vec!();
+    for path in &args.paths {
+        for input in input::read(path) {
+            match input {
+                Ok(input) => inputs.push(input),
+                Err(e) => {
+                    eprintln!("dexparser: {}", e);
+                    ok = false;
+                }
+            }
+        }
+    }
+
+    let options = ParseOptions::new().verify_checksum(args.command == "verify");
+    let several = inputs.len() > 1;
+    // each file is parsed as it comes up rather than all of them up front
+    let parsed = Cell::new(true);
+    let mut files = inputs.iter().filter_map(|input| {
+        dexparser::parse_with_options(&input.bytes, options.clone())
+            .map_err(|e| {
+                eprintln!("dexparser: {}: {}", input.name, e);
+                parsed.set(false);
+            })
+            .ok()
+            .map(|file| (input, file))
+    });
+
+    if args.command == "json" {
+        if args.pretty {
+            json(&mut serde_json::Serializer::pretty(out), &mut files)?;
+        } else {
+            json(&mut serde_json::Serializer::new(out), &mut files)?;
+        }
+        return Ok(ok && parsed.get());
+    }
+
+    if args.command == "sizes" {
+        let mut reports = inputs.iter().filter_map(|input| {
+            sizes::attribute(&input.bytes)
+                .map_err(|e| {
+                    eprintln!("dexparser: {}: {}", input.name, e);
+                    parsed.set(false);
+                })
+                .ok()
+                .map(|report| (input, report))
+        });
+        if args.json && args.pretty {
+            json(&mut serde_json::Serializer::pretty(out), &mut reports)?;
+        } else if args.json {
+            json(&mut serde_json::Serializer::new(out), &mut reports)?;
+        } else {
+            for (input, report) in reports {
+                if several {
+                    writeln!(out, "{}", input.name)?;
+                }
+                write!(out, "{}", report.tree(args.depth.unwrap_or(usize::MAX)))?;
+            }
+        }
+        return Ok(ok && parsed.get());
+    }
+
+    for (input, file) in files {
+        // the name goes before each line where those are short, and above everything else
+        let prefix = if several { format!("{}: ", input.name) } else { String::new() };
+        if several && !matches!(args.command.as_str(), "classes" | "strings") {
+            writeln!(out, "{}", input.name)?;
+        }
+        ok &= match args.command.as_str() {
+            "info" => info(out, &file),
+            "classes" => classes(out, args, &file, &prefix),
+            "dump" => dump(out, args, &file),
+            "disasm" => disasm(out, args, &file),
+            "strings" => strings(out, args, &file, &prefix),
+            "verify" => verify(out, &file),
+            _ => unreachable!()
+        }?;
+    }
+    Ok(ok && parsed.get())
+}
+
+fn json<S: Serializer<Ok = ()>, T: Serialize>(serializer: S, files: &mut dyn Iterator<Item = (&Input, T)>)
+    -> io::Result<()>
+    where S::Error: Into<io::Error> {
+    let mut map = serializer.serialize_map(None).map_err(Into::into)?;
+    for (input, file) in files {
+        map.serialize_entry(&input.name, &file).map_err(Into::into)?;
+    }
+    map.end().map_err(Into::into)
+}
+
+fn info(out: &mut dyn Write, file: &DexFile) -> io::Result<bool> {
+    let header = &file.header;
+    let data = &file.file_data;
+    let checksum = header.checksum.parse::<u32>().map_or(header.checksum.clone(), |c| format!("0x{:08x}", c));
+    let signature = header.signature.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let endianness = match header.endianness {
+        dexparser::Endianness::Little => "little",
+        dexparser::Endianness::Big => "big"
+    };
+    let with_code = file.classes.iter()
+        .filter_map(|class| class.class_data.as_ref())
+        .flat_map(|data| data.direct_methods.iter().chain(data.virtual_methods.iter()))
+        .filter(|method| method.code.is_some())
+        .count();
+
+    writeln!(out, "version           {:03}", header.version)?;
+    writeln!(out, "checksum          {}", checksum)?;
+    writeln!(out, "signature         {}", signature)?;
+    writeln!(out, "file size         {}", header.file_size)?;
+    writeln!(out, "endianness        {}", endianness)?;
+    writeln!(out, "strings           {}", data.string_data.len())?;
+    writeln!(out, "types             {}", data.type_identifiers.len())?;
+    writeln!(out, "prototypes        {}", data.prototypes.len())?;
+    writeln!(out, "fields            {}", data.fields.len())?;
+    writeln!(out, "methods           {}", data.methods.len())?;
+    writeln!(out, "method handles    {}", data.method_handles.len())?;
+    writeln!(out, "call sites        {}", data.call_sites.len())?;
+    writeln!(out, "classes           {}", file.classes.len())?;
+    writeln!(out, "methods with code {}", with_code)?;
+    Ok(true)
+}
+
+// A class given on the command line, either as `com.foo.Bar` or as `Lcom/foo/Bar;`
+fn is_class(class: &ClassDefinition, name: &Option<String>) -> bool {
+    name.as_ref().is_none_or(|name| *class.class_type == *name || java::type_name(&class.class_type) == *name)
+}
+
+fn classes(out: &mut dyn Write, args: &Args, file: &DexFile, prefix: &str) -> io::Result<bool> {
+    for class in &file.classes {
+        let name = java::type_name(&class.class_type);
+        let in_package = args.package.as_ref().is_none_or(|package| {
+            package.is_empty() || name.strip_prefix(package.as_str()).is_some_and(|rest| rest.starts_with('.'))
+        });
+        let matches = args.text.as_ref().is_none_or(|text| name.contains(text.as_str()));
+        let has_access = args.access.iter().all(|flag| class.access_flags.contains(flag));
+        if in_package && matches && has_access {
+            writeln!(out, "{}{}", prefix, if args.long { class.to_string() } else { name })?;
+        }
+    }
+    Ok(true)
+}
+
+fn dump(out: &mut dyn Write, args: &Args, file: &DexFile) -> io::Result<bool> {
+    for (i, class) in file.classes.iter().enumerate() {
+        if is_class(class, &args.class) {
+            dump::class(out, file, i, class)?;
+        }
+    }
+    Ok(true)
+}
+
+fn disasm(out: &mut dyn Write, args: &Args, file: &DexFile) -> io::Result<bool> {
+    let methods = file.classes.iter()
+        .filter(|class| is_class(class, &args.class))
+        .filter_map(|class| class.class_data.as_ref())
+        .flat_map(|data| data.direct_methods.iter().chain(data.virtual_methods.iter()))
+        .filter(|method| args.method.as_ref().is_none_or(|name| *method.method.name == *name));
+    for method in methods {
+        let code = match method.code {
+            Some(ref code) => code,
+            None => continue
+        };
+        writeln!(out, "{}", dump::method_descriptor(&method.method))?;
+        writeln!(out, "  registers {}, ins {}, outs {}", code.registers_size, code.ins_size, code.outs_size)?;
+        dump::listing(out, file, code, "  ")?;
+        dump::catches(out, code, "  ")?;
+        writeln!(out)?;
+    }
+    Ok(true)
+}
+
+fn strings(out: &mut dyn Write, args: &Args, file: &DexFile, prefix: &str) -> io::Result<bool> {
+    for string in &file.file_data.string_data {
+        if args.text.as_ref().is_none_or(|text| string.contains(text.as_str())) {
+            writeln!(out, "{}{}", prefix, string.escape_debug())?;
+        }
+    }
+    Ok(true)
+}
+
+fn verify(out: &mut dyn Write, file: &DexFile) -> io::Result<bool> {
+    let reports = verifier::verify(file);
+    for report in &reports {
+        for finding in &report.findings {
+            let offset = finding.offset.map_or(String::new(), |offset| format!("{:04x}: ", offset));
+            writeln!(out, "{}: {}{:?}: {}", dump::method_descriptor(&report.method), offset, finding.kind,
+                     finding.message)?;
+        }
+    }
+    Ok(reports.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dexparser::{ClassData, Code, DexBuilder, EncodedMethod};
+    use std::fs;
+    use std::path::Path;
+
+    fn args(args: &str) -> Result<Args, String> {
+        parse_args(&args.split(' ').map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_args() {
+        let parsed = args("classes --package com/foo/ --access=public,interface a.dex --long -- --b.dex").unwrap();
+        assert_eq!(parsed.package, Some("com.foo".to_string()));
+        assert_eq!(parsed.access, vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_INTERFACE));
+        assert!(parsed.long);
+        assert_eq!(parsed.paths, vec!(PathBuf::from("a.dex"), PathBuf::from("--b.dex")));
+
+        assert_eq!(args("decompile a.dex").err(), Some("unknown command 'decompile'".to_string()));
+        assert_eq!(args("info --pretty a.dex").err(), Some("info doesn't take --pretty".to_string()));
+        assert_eq!(args("disasm a.dex --method").err(), Some("--method needs a value".to_string()));
+        assert_eq!(args("classes --access=open a.dex").err(), Some("unknown access flag 'open'".to_string()));
+        assert_eq!(args("strings --match x").err(), Some("no paths given".to_string()));
+        assert_eq!(args("sizes --depth=deep a.dex").err(), Some("--depth needs a number, not 'deep'".to_string()));
+    }
+
+    // A file with the one class, whose static run() returns without doing anything
+    fn build(class: &str) -> Vec<u8> {
+        let mut b = DexBuilder::new(35);
+        let method = b.method(class, "run", "V", &[]);
+        let class = ClassDefinition {
+            class_type: b.type_(class),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod {
+                    method,
+                    access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_STATIC),
+                    code: Some(Code { registers_size: 0, ins_size: 0, outs_size: 0, debug_info: None,
+                                      insns: vec!(0x000E), tries: vec!(), handlers: vec!() }),
+                    system_annotations: Default::default()
+                }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        b.class(class);
+        dexparser::write(&b.build().unwrap()).unwrap()
+    }
+
+    // Runs the command on the path, which stands in for the placeholder `x` at the end of the command line
+    fn output(command: &str, path: &Path) -> (bool, String) {
+        let mut args = args(command).unwrap();
+        args.paths = vec!(path.to_path_buf());
+        let mut out = vec!();
+        let ok = run(&args, &mut out).unwrap();
+        (ok, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = env::temp_dir().join(format!("dexparser-cli-{}", process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("classes.dex"), build("Lcom/foo/Main;")).unwrap();
+        let mut apk = zip::ZipWriter::new(io::Cursor::new(vec!()));
+        apk.start_file("classes.dex", zip::write::FileOptions::default()).unwrap();
+        apk.write_all(&build("Lcom/foo/bar/Other;")).unwrap();
+        fs::write(dir.join("lib/app.apk"), apk.finish().unwrap().into_inner()).unwrap();
+        fs::write(dir.join("notes.txt"), "not a dex file").unwrap();
+
+        let dex = dir.join("classes.dex");
+        assert_eq!(output("classes x", &dex), (true, "com.foo.Main\n".to_string()));
+        assert_eq!(output("classes --long x", &dex), (true, "public class com.foo.Main\n".to_string()));
+        let (ok, text) = output("classes --package com.foo.bar x", &dir);
+        assert!(ok);
+        assert_eq!(text, format!("{}!classes.dex: com.foo.bar.Other\n", dir.join("lib/app.apk").display()));
+
+        let (ok, text) = output("disasm --class com.foo.Main x", &dex);
+        assert!(ok);
+        assert_eq!(text, concat!(
+            "Lcom/foo/Main;.run:()V\n",
+            "  registers 0, ins 0, outs 0\n",
+            "  0000: 000e                              |return-void\n",
+            "  catches       : (none)\n",
+            "\n"
+        ));
+        assert_eq!(output("verify x", &dex), (true, String::new()));
+
+        let (ok, text) = output("json x", &dex);
+        assert!(ok);
+        let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+        assert!(json[dex.display().to_string()]["classes"].is_array());
+
+        let (ok, text) = output("sizes --depth 2 x", &dex);
+        assert!(ok);
+        assert_eq!(text.lines().map(|line| line.split_whitespace().next().unwrap()).collect::<Vec<_>>(),
+                   vec!("bytes", "<root>", "com", "com.foo"));
+        let (ok, text) = output("sizes --json x", &dex);
+        assert!(ok);
+        let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+        assert_eq!(json[dex.display().to_string()]["root"]["children"][0]["name"], "com");
+
+        // the text file isn't looked at when the directory is searched, but is an error when named
+        let (ok, _) = output("info x", &dir.join("notes.txt"));
+        assert!(!ok);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,559 @@
+// Assembles a DexFile from classes described in terms of the result types
+//
+// Any string, type, prototype, field, method or method handle the classes refer to is gathered into
+// the identifier pools, which are then sorted into the order the format requires. Items interned through
+// the builder are always included, so the pools can hold entries only referenced from bytecode.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::method_counts::REFERENCE_LIMIT;
+use crate::parser;
+use crate::result_types::*;
+use crate::rewrite::Reference;
+use crate::writer::{self, Indexes};
+
+pub struct DexBuilder {
+    version: i32,
+    strings: HashMap<String, Rc<String>>,
+    types: HashSet<Rc<String>>,
+    prototypes: HashSet<Rc<Prototype>>,
+    fields: HashSet<Rc<Field>>,
+    methods: HashSet<Rc<Method>>,
+    method_handles: HashSet<Rc<MethodHandle>>,
+    call_sites: Vec<Rc<CallSiteItem>>,
+    classes: Vec<ClassDefinition>
+}
+
+impl DexBuilder {
+    // Version 038 is needed for method handles and call sites to be used
+    pub fn new(version: i32) -> Self {
+        DexBuilder {
+            version,
+            strings: HashMap::new(),
+            types: HashSet::new(),
+            prototypes: HashSet::new(),
+            fields: HashSet::new(),
+            methods: HashSet::new(),
+            method_handles: HashSet::new(),
+            call_sites: vec!(),
+            classes: vec!()
+        }
+    }
+
+    pub fn string(&mut self, s: &str) -> Rc<String> {
+        self.strings.entry(s.to_string()).or_insert_with(|| Rc::new(s.to_string())).clone()
+    }
+
+    // Types are identified by their descriptor, e.g. "I" or "Ljava/lang/Object;"
+    pub fn type_(&mut self, descriptor: &str) -> Rc<String> {
+        let type_ = self.string(descriptor);
+        self.types.insert(type_.clone());
+        type_
+    }
+
+    pub fn prototype(&mut self, return_type: &str, parameters: &[&str]) -> Rc<Prototype> {
+        let shorty = std::iter::once(return_type).chain(parameters.iter().cloned())
+            .map(shorty_descriptor)
+            .collect::<String>();
+
+        let prototype = Rc::new(Prototype {
+            shorty: self.string(&shorty),
+            return_type: self.type_(return_type),
+            parameters: parameters.iter().map(|p| self.type_(p)).collect()
+        });
+        intern(&mut self.prototypes, prototype)
+    }
+
+    pub fn field(&mut self, definer: &str, name: &str, type_: &str) -> Rc<Field> {
+        let field = Rc::new(Field {
+            definer: self.type_(definer),
+            type_: self.type_(type_),
+            name: self.string(name)
+        });
+        intern(&mut self.fields, field)
+    }
+
+    pub fn method(&mut self, definer: &str, name: &str, return_type: &str, parameters: &[&str]) -> Rc<Method> {
+        let method = Rc::new(Method {
+            definer: self.type_(definer),
+            prototype: self.prototype(return_type, parameters),
+            name: self.string(name)
+        });
+        intern(&mut self.methods, method)
+    }
+
+    pub fn method_handle(&mut self, type_: MethodHandleType, member: MethodHandleMember) -> Rc<MethodHandle> {
+        intern(&mut self.method_handles, Rc::new(MethodHandle { type_, member }))
+    }
+
+    // Call sites are numbered in the order they are added
+    pub fn call_site(&mut self, method_handle: Rc<MethodHandle>, method_name: &str, method_type: Rc<Prototype>,
+                     constant_values: Vec<EncodedValue>) -> Rc<CallSiteItem> {
+        let call_site = Rc::new(CallSiteItem {
+            method_handle,
+            method_name: self.string(method_name),
+            method_type,
+            constant_values
+        });
+        self.call_sites.push(call_site.clone());
+        call_site
+    }
+
+    // Classes are kept in the order they are added, so superclasses and interfaces have to come first
+    pub fn class(&mut self, class: ClassDefinition) -> &mut Self {
+        self.classes.push(class);
+        self
+    }
+
+    // Builds the file as it would come back from parsing it: the pools in sorted order, members and
+    // annotations sorted by index, and a header describing the written file
+    pub fn build(self) -> Result<DexFile, DexParserError> {
+        let mut pools = Pools::default();
+        for s in self.strings.values() {
+            pools.strings.insert(s.clone());
+        }
+        for type_ in &self.types {
+            pools.type_(type_);
+        }
+        for proto in &self.prototypes {
+            pools.prototype(proto);
+        }
+        for field in &self.fields {
+            pools.field(field);
+        }
+        for method in &self.methods {
+            pools.method(method);
+        }
+        for handle in &self.method_handles {
+            pools.method_handle(handle)?;
+        }
+        for call_site in &self.call_sites {
+            pools.call_site(call_site)?;
+        }
+        for class in &self.classes {
+            pools.class(class)?;
+        }
+
+        let file_data = pools.sort(self.call_sites);
+        check_limits(&file_data)?;
+
+        let mut classes = self.classes;
+        {
+            let idx = Indexes::new(&file_data);
+            for class in &mut classes {
+                sort_class(class, &idx)?;
+                parser::system_annotations::decode(class);
+            }
+        }
+
+        let mut file = DexFile {
+            header: Header {
+                version: self.version,
+                checksum: String::new(),
+                signature: [0; 20],
+                file_size: 0,
+                endianness: nom::Endianness::Little
+            },
+            file_data,
+            classes,
+            regions: vec!()
+        };
+
+        update_header(&mut file)?;
+        Ok(file)
+    }
+}
+
+// Indexes into these pools are 16 bits wide in bytecode and elsewhere, so a file can't hold more entries
+fn check_limits(fd: &DexFileData) -> Result<(), DexParserError> {
+    let pools = [("method_ids", fd.methods.len()), ("field_ids", fd.fields.len()),
+                 ("type_ids", fd.type_identifiers.len()), ("proto_ids", fd.prototypes.len())];
+    for (limit, requested) in pools.iter().cloned() {
+        if requested > REFERENCE_LIMIT {
+            return Err(DexParserError::LimitExceeded {
+                limit,
+                requested: requested as u64,
+                allowed: REFERENCE_LIMIT as u64
+            });
+        }
+    }
+    Ok(())
+}
+
+// The rest of the header can only be known once the file has been written out
+pub(crate) fn update_header(file: &mut DexFile) -> Result<(), DexParserError> {
+    let bytes = writer::write(file)?;
+    let mut signature = [0; 20];
+    signature.copy_from_slice(&bytes[12 .. 32]);
+    file.header.checksum = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]).to_string();
+    file.header.signature = signature;
+    file.header.file_size = bytes.len() as u32;
+    Ok(())
+}
+
+fn intern<T: Eq + std::hash::Hash>(set: &mut HashSet<Rc<T>>, item: Rc<T>) -> Rc<T> {
+    match set.get(&item) {
+        Some(existing) => existing.clone(),
+        None => {
+            set.insert(item.clone());
+            item
+        }
+    }
+}
+
+// Docs: ShortyDescriptor
+fn shorty_descriptor(descriptor: &str) -> char {
+    match descriptor.chars().next() {
+        Some('L') | Some('[') | None => 'L',
+        Some(c) => c
+    }
+}
+
+// Everything referred to from the file, before being put in order
+#[derive(Default)]
+pub(crate) struct Pools {
+    pub(crate) strings: HashSet<Rc<String>>,
+    pub(crate) types: HashSet<Rc<String>>,
+    pub(crate) prototypes: HashSet<Rc<Prototype>>,
+    pub(crate) fields: HashSet<Rc<Field>>,
+    pub(crate) methods: HashSet<Rc<Method>>,
+    pub(crate) method_handles: HashSet<Rc<MethodHandle>>
+}
+
+impl Pools {
+    fn type_(&mut self, type_: &Rc<String>) {
+        self.strings.insert(type_.clone());
+        self.types.insert(type_.clone());
+    }
+
+    fn prototype(&mut self, proto: &Rc<Prototype>) {
+        self.strings.insert(proto.shorty.clone());
+        self.type_(&proto.return_type);
+        for param in &proto.parameters {
+            self.type_(param);
+        }
+        self.prototypes.insert(proto.clone());
+    }
+
+    fn field(&mut self, field: &Rc<Field>) {
+        self.type_(&field.definer);
+        self.type_(&field.type_);
+        self.strings.insert(field.name.clone());
+        self.fields.insert(field.clone());
+    }
+
+    fn method(&mut self, method: &Rc<Method>) {
+        self.type_(&method.definer);
+        self.prototype(&method.prototype);
+        self.strings.insert(method.name.clone());
+        self.methods.insert(method.clone());
+    }
+
+    // the handle type decides whether the member is read back as a field or a method
+    fn method_handle(&mut self, handle: &Rc<MethodHandle>) -> Result<(), DexParserError> {
+        let accessor = matches!(handle.type_, MethodHandleType::STATIC_PUT | MethodHandleType::STATIC_GET
+            | MethodHandleType::INSTANCE_PUT | MethodHandleType::INSTANCE_GET);
+        match handle.member {
+            MethodHandleMember::Field(ref field) if accessor => self.field(field),
+            MethodHandleMember::Method(ref method) if !accessor => self.method(method),
+            _ => return Err(DexParserError::WritingFailed {
+                reason: format!("method handle type {:?} does not match its member", handle.type_)
+            })
+        }
+        self.method_handles.insert(handle.clone());
+        Ok(())
+    }
+
+    fn call_site(&mut self, call_site: &CallSiteItem) -> Result<(), DexParserError> {
+        self.method_handle(&call_site.method_handle)?;
+        self.strings.insert(call_site.method_name.clone());
+        self.prototype(&call_site.method_type);
+        call_site.constant_values.iter().try_for_each(|value| self.value(value))
+    }
+
+    // what an index in bytecode refers to
+    pub(crate) fn reference(&mut self, target: &Reference) -> Result<(), DexParserError> {
+        match target {
+            Reference::String(s) => { self.strings.insert(s.clone()); },
+            Reference::Type(type_) => self.type_(type_),
+            Reference::Field(field) => self.field(field),
+            Reference::Method(method) => self.method(method),
+            Reference::Prototype(proto) => self.prototype(proto),
+            Reference::CallSite(call_site) => self.call_site(call_site)?,
+            Reference::MethodHandle(handle) => self.method_handle(handle)?
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: &EncodedValue) -> Result<(), DexParserError> {
+        match value {
+            EncodedValue::MethodType(proto) => self.prototype(proto),
+            EncodedValue::MethodHandle(handle) => self.method_handle(handle)?,
+            EncodedValue::String(s) => { self.strings.insert(s.clone()); },
+            EncodedValue::Type(type_) => self.type_(type_),
+            EncodedValue::Field(field) | EncodedValue::Enum(field) => self.field(field),
+            EncodedValue::Method(method) => self.method(method),
+            EncodedValue::Array(values) => values.iter().try_for_each(|value| self.value(value))?,
+            EncodedValue::Annotation(annotation) => self.annotation(&annotation.type_, &annotation.values)?,
+            _ => ()
+        }
+        Ok(())
+    }
+
+    fn annotation(&mut self, type_: &Rc<String>, elements: &[AnnotationElement]) -> Result<(), DexParserError> {
+        self.type_(type_);
+        for element in elements {
+            self.strings.insert(element.name.clone());
+            self.value(&element.value)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn class(&mut self, class: &ClassDefinition) -> Result<(), DexParserError> {
+        self.type_(&class.class_type);
+        if let Some(ref superclass) = class.superclass {
+            self.type_(superclass);
+        }
+        for interface in &class.interfaces {
+            self.type_(interface);
+        }
+        if let Some(ref name) = class.source_file_name {
+            self.strings.insert(name.clone());
+        }
+
+        if let Some(ref annotations) = class.annotations {
+            for annotation in &annotations.class_annotations {
+                self.annotation(&annotation.type_, &annotation.elements)?;
+            }
+            for fa in &annotations.field_annotations {
+                self.field(&fa.field_data);
+                for annotation in &fa.annotations {
+                    self.annotation(&annotation.type_, &annotation.annotations)?;
+                }
+            }
+            for ma in &annotations.method_annotations {
+                self.method(&ma.method);
+                for annotation in &ma.annotations {
+                    self.annotation(&annotation.type_, &annotation.annotations)?;
+                }
+            }
+            for pa in &annotations.parameter_annotations {
+                self.method(&pa.method);
+                for annotation in pa.annotations.iter().flatten() {
+                    self.annotation(&annotation.type_, &annotation.annotations)?;
+                }
+            }
+        }
+
+        if let Some(ref class_data) = class.class_data {
+            for field in class_data.static_fields.iter().chain(class_data.instance_fields.iter()) {
+                self.field(&field.field);
+            }
+            for method in class_data.direct_methods.iter().chain(class_data.virtual_methods.iter()) {
+                self.method(&method.method);
+                if let Some(ref code) = method.code {
+                    self.code(code);
+                }
+            }
+        }
+
+        class.static_values.iter().try_for_each(|value| self.value(value))
+    }
+
+    fn code(&mut self, code: &Code) {
+        let handlers = code.handlers.iter().chain(code.tries.iter().map(|try_| &try_.handler));
+        for pair in handlers.flat_map(|handler| handler.handlers.iter()) {
+            self.type_(&pair.type_);
+        }
+
+        if let Some(ref debug_info) = code.debug_info {
+            let mut strings = debug_info.parameter_names.iter().flatten().collect::<Vec<_>>();
+            for item in &debug_info.bytecode {
+                match item {
+                    DebugItemBytecodes::DBG_START_LOCAL { name, type_, .. } => {
+                        strings.extend(name);
+                        if let Some(type_) = type_ {
+                            self.type_(type_);
+                        }
+                    },
+                    DebugItemBytecodes::DBG_START_LOCAL_EXTENDED { name, type_, sig, .. } => {
+                        strings.extend(name);
+                        strings.extend(sig);
+                        if let Some(type_) = type_ {
+                            self.type_(type_);
+                        }
+                    },
+                    DebugItemBytecodes::DBG_SET_FILE(name) => strings.extend(name),
+                    _ => ()
+                }
+            }
+            for s in strings {
+                self.strings.insert(s.clone());
+            }
+        }
+    }
+
+    // Docs: the sort order notes on each of the *_ids sections
+    fn sort(self, call_sites: Vec<Rc<CallSiteItem>>) -> DexFileData {
+        // strings are ordered by UTF-16 code point values
+        let mut string_data = self.strings.into_iter().collect::<Vec<_>>();
+        string_data.sort_by(|a, b| a.encode_utf16().cmp(b.encode_utf16()));
+
+        // types are ordered by string index, which the string order already gives
+        let mut type_identifiers = self.types.into_iter().collect::<Vec<_>>();
+        type_identifiers.sort_by(|a, b| a.encode_utf16().cmp(b.encode_utf16()));
+
+        let type_key = |type_: &Rc<String>| type_identifiers.binary_search_by(|t| t.encode_utf16().cmp(type_.encode_utf16()));
+
+        let mut prototypes = self.prototypes.into_iter().collect::<Vec<_>>();
+        prototypes.sort_by(|a, b| type_key(&a.return_type).cmp(&type_key(&b.return_type))
+            .then_with(|| a.parameters.iter().map(type_key).cmp(b.parameters.iter().map(type_key))));
+
+        let proto_indexes = positions(&prototypes);
+        let proto_key = |proto: &Rc<Prototype>| proto_indexes.get(proto).cloned();
+
+        let mut fields = self.fields.into_iter().collect::<Vec<_>>();
+        fields.sort_by(|a, b| type_key(&a.definer).cmp(&type_key(&b.definer))
+            .then_with(|| utf16_cmp(&a.name, &b.name))
+            .then_with(|| type_key(&a.type_).cmp(&type_key(&b.type_))));
+
+        let mut methods = self.methods.into_iter().collect::<Vec<_>>();
+        methods.sort_by(|a, b| type_key(&a.definer).cmp(&type_key(&b.definer))
+            .then_with(|| utf16_cmp(&a.name, &b.name))
+            .then_with(|| proto_key(&a.prototype).cmp(&proto_key(&b.prototype))));
+
+        // the format leaves these unordered, so they are put in the order of their members
+        let field_indexes = positions(&fields);
+        let method_indexes = positions(&methods);
+        let member_key = |handle: &Rc<MethodHandle>| match handle.member {
+            MethodHandleMember::Field(ref field) => (handle.type_ as u8, field_indexes.get(field).cloned()),
+            MethodHandleMember::Method(ref method) => (handle.type_ as u8, method_indexes.get(method).cloned())
+        };
+        let mut method_handles = self.method_handles.into_iter().collect::<Vec<_>>();
+        method_handles.sort_by_key(member_key);
+
+        DexFileData { string_data, type_identifiers, prototypes, fields, methods, method_handles, call_sites }
+    }
+}
+
+fn positions<T: Eq + std::hash::Hash>(pool: &[Rc<T>]) -> HashMap<Rc<T>, usize> {
+    pool.iter().enumerate().map(|(i, item)| (item.clone(), i)).collect()
+}
+
+pub(crate) fn utf16_cmp(a: &str, b: &str) -> Ordering {
+    a.encode_utf16().cmp(b.encode_utf16())
+}
+
+// Put everything the writer stores in index order into that order, so the class matches what parsing gives
+fn sort_class(class: &mut ClassDefinition, idx: &Indexes) -> Result<(), DexParserError> {
+    if let Some(ref mut annotations) = class.annotations {
+        sort_annotation_set(&mut annotations.class_annotations, |a| &a.type_, idx)?;
+        for annotation in &mut annotations.class_annotations {
+            sort_elements(&mut annotation.elements, idx)?;
+        }
+
+        sort_by_index(&mut annotations.field_annotations, |fa| idx.field(&fa.field_data))?;
+        sort_by_index(&mut annotations.method_annotations, |ma| idx.method(&ma.method))?;
+        sort_by_index(&mut annotations.parameter_annotations, |pa| idx.method(&pa.method))?;
+
+        let sets = annotations.field_annotations.iter_mut().map(|fa| &mut fa.annotations)
+            .chain(annotations.method_annotations.iter_mut().map(|ma| &mut ma.annotations))
+            .chain(annotations.parameter_annotations.iter_mut().flat_map(|pa| pa.annotations.iter_mut()));
+        for set in sets {
+            sort_annotation_set(set, |a| &a.type_, idx)?;
+            for annotation in set.iter_mut() {
+                sort_elements(&mut annotation.annotations, idx)?;
+            }
+        }
+    }
+
+    if let Some(ref mut class_data) = class.class_data {
+        sort_by_index(&mut class_data.static_fields, |field| idx.field(&field.field))?;
+        sort_by_index(&mut class_data.instance_fields, |field| idx.field(&field.field))?;
+        sort_by_index(&mut class_data.direct_methods, |method| idx.method(&method.method))?;
+        sort_by_index(&mut class_data.virtual_methods, |method| idx.method(&method.method))?;
+
+        for field in class_data.static_fields.iter_mut().chain(class_data.instance_fields.iter_mut()) {
+            sort_access_flags(&mut field.access_flags);
+        }
+        for method in class_data.direct_methods.iter_mut().chain(class_data.virtual_methods.iter_mut()) {
+            sort_access_flags(&mut method.access_flags);
+        }
+
+        for method in class_data.direct_methods.iter_mut().chain(class_data.virtual_methods.iter_mut()) {
+            if let Some(ref mut code) = method.code {
+                // every try's handler ends up in the handler list
+                for try_ in &code.tries {
+                    if !code.handlers.contains(&try_.handler) {
+                        code.handlers.push(try_.handler.clone());
+                    }
+                }
+            }
+        }
+    }
+    sort_access_flags(&mut class.access_flags);
+
+    for value in &mut class.static_values {
+        sort_value(value, idx)?;
+    }
+    Ok(())
+}
+
+// flags come back from parsing in the order of their bits
+fn sort_access_flags(flags: &mut Vec<AccessFlag>) {
+    flags.sort_by_key(writer::access_flag_bit);
+    flags.dedup();
+}
+
+fn sort_annotation_set<T>(set: &mut Vec<T>, type_: impl Fn(&T) -> &Rc<String>, idx: &Indexes) -> Result<(), DexParserError> {
+    sort_by_index(set, |annotation| idx.type_(type_(annotation)))
+}
+
+fn sort_elements(elements: &mut Vec<AnnotationElement>, idx: &Indexes) -> Result<(), DexParserError> {
+    sort_by_index(elements, |element| idx.string(&element.name))?;
+    for element in elements {
+        sort_value(&mut element.value, idx)?;
+    }
+    Ok(())
+}
+
+fn sort_value(value: &mut EncodedValue, idx: &Indexes) -> Result<(), DexParserError> {
+    match value {
+        EncodedValue::Array(values) => values.iter_mut().try_for_each(|value| sort_value(value, idx)),
+        EncodedValue::Annotation(annotation) => sort_elements(&mut annotation.values, idx),
+        _ => Ok(())
+    }
+}
+
+fn sort_by_index<T>(items: &mut Vec<T>, key: impl Fn(&T) -> Result<u32, DexParserError>) -> Result<(), DexParserError> {
+    let mut keyed = items.drain(..)
+        .map(|item| Ok((key(&item)?, item)))
+        .collect::<Result<Vec<_>, DexParserError>>()?;
+    keyed.sort_by_key(|item| item.0);
+    items.extend(keyed.into_iter().map(|item| item.1));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_limits() {
+        let mut fd = DexFileData {
+            string_data: vec!(), type_identifiers: vec!(), prototypes: vec!(), fields: vec!(), methods: vec!(),
+            method_handles: vec!(), call_sites: vec!()
+        };
+        fd.type_identifiers = (0 .. REFERENCE_LIMIT).map(|i| Rc::new(format!("LT{};", i))).collect();
+        assert!(check_limits(&fd).is_ok());
+        fd.type_identifiers.push(Rc::new("LOne;".to_string()));
+        match check_limits(&fd) {
+            Err(DexParserError::LimitExceeded { limit, requested, allowed }) =>
+                assert_eq!((limit, requested, allowed), ("type_ids", 65537, 65536)),
+            other => panic!("{:?}", other)
+        }
+    }
+}
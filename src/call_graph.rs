@@ -0,0 +1,399 @@
+// A call graph over one or more DEX files, built from the invoke-* instructions in every method body
+//
+// Virtual and interface calls are resolved with class hierarchy analysis: besides the method the call
+// resolves to from the declared receiver type, there is an edge to every override of it in a subtype
+// defined in the files. Methods that aren't defined in the files (framework and library methods) are
+// nodes like any other, but have no outgoing edges.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::hierarchy::ClassHierarchy;
+use crate::instructions::{self, Opcode};
+use crate::result_types::*;
+
+#[derive(Debug)]
+pub struct CallGraph {
+    pub nodes: Vec<Rc<Method>>,
+    pub edges: Vec<CallEdge>,
+    index: HashMap<Rc<Method>, usize>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CallEdge {
+    // indexes into CallGraph.nodes
+    pub caller: usize,
+    pub callee: usize,
+    pub kind: InvokeKind,
+    // address of the invoke instruction in the caller, in 16-bit code units
+    pub offset: u32,
+    // the callee is an override found in a subtype of the receiver type,
+    // rather than the method the call resolves to
+    pub by_hierarchy: bool
+}
+
+// The range forms of each invoke are folded into the same kind
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum InvokeKind {
+    Virtual,
+    Super,
+    Direct,
+    Static,
+    Interface,
+    Polymorphic,
+    // an invoke-custom, with an edge to its bootstrap method
+    Custom
+}
+
+impl InvokeKind {
+    pub fn from_opcode(opcode: Opcode) -> Option<InvokeKind> {
+        match opcode {
+            Opcode::INVOKE_VIRTUAL | Opcode::INVOKE_VIRTUAL_RANGE => Some(InvokeKind::Virtual),
+            Opcode::INVOKE_SUPER | Opcode::INVOKE_SUPER_RANGE => Some(InvokeKind::Super),
+            Opcode::INVOKE_DIRECT | Opcode::INVOKE_DIRECT_RANGE => Some(InvokeKind::Direct),
+            Opcode::INVOKE_STATIC | Opcode::INVOKE_STATIC_RANGE => Some(InvokeKind::Static),
+            Opcode::INVOKE_INTERFACE | Opcode::INVOKE_INTERFACE_RANGE => Some(InvokeKind::Interface),
+            Opcode::INVOKE_POLYMORPHIC | Opcode::INVOKE_POLYMORPHIC_RANGE => Some(InvokeKind::Polymorphic),
+            Opcode::INVOKE_CUSTOM | Opcode::INVOKE_CUSTOM_RANGE => Some(InvokeKind::Custom),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InvokeKind::Virtual => "invoke-virtual",
+            InvokeKind::Super => "invoke-super",
+            InvokeKind::Direct => "invoke-direct",
+            InvokeKind::Static => "invoke-static",
+            InvokeKind::Interface => "invoke-interface",
+            InvokeKind::Polymorphic => "invoke-polymorphic",
+            InvokeKind::Custom => "invoke-custom"
+        }
+    }
+}
+
+impl CallGraph {
+    pub fn new(file: &DexFile) -> Result<Self, DexParserError> {
+        CallGraph::from_files(&[file])
+    }
+
+    // Build a single graph over several files, such as the classes*.dex of a multidex app.
+    // Where a class is defined in more than one file, the first definition is used
+    pub fn from_files(files: &[&DexFile]) -> Result<Self, DexParserError> {
+        let hierarchy = ClassHierarchy::from_files(files);
+        let mut graph = CallGraph { nodes: vec!(), edges: vec!(), index: HashMap::new() };
+
+        for file in files {
+            let fd = &file.file_data;
+            let methods = file.classes.iter()
+                .filter_map(|class| class.class_data.as_ref())
+                .flat_map(|class_data| class_data.direct_methods.iter().chain(class_data.virtual_methods.iter()));
+
+            for method in methods {
+                let caller = graph.node(&method.method);
+                let code = match method.code {
+                    Some(ref code) => code,
+                    None => continue
+                };
+
+                for insn in instructions::decode_all(&code.insns)? {
+                    let kind = match InvokeKind::from_opcode(insn.opcode) {
+                        Some(kind) => kind,
+                        None => continue
+                    };
+                    let idx = insn.index.unwrap_or(0) as usize;
+                    let err = |pool: &str| DexParserError::InvalidBytecode {
+                        offset: insn.offset,
+                        reason: format!("{} index {} is out of range", pool, idx)
+                    };
+
+                    let add = |graph: &mut CallGraph, callee: &Rc<Method>, by_hierarchy: bool| {
+                        let callee = graph.node(callee);
+                        graph.edges.push(CallEdge { caller, callee, kind, offset: insn.offset, by_hierarchy });
+                    };
+
+                    if kind == InvokeKind::Custom {
+                        let call_site = fd.call_sites.get(idx).ok_or_else(|| err("call site"))?;
+                        if let MethodHandleMember::Method(ref bootstrap) = call_site.method_handle.member {
+                            add(&mut graph, bootstrap, false);
+                        }
+                        continue;
+                    }
+
+                    let target = fd.methods.get(idx).ok_or_else(|| err("method"))?;
+                    match kind {
+                        InvokeKind::Virtual | InvokeKind::Interface => {
+                            add(&mut graph, &resolve(&hierarchy, target), false);
+                            for override_ in hierarchy.overrides(target) {
+                                add(&mut graph, &override_.method, true);
+                            }
+                        },
+                        InvokeKind::Polymorphic => add(&mut graph, target, false),
+                        _ => add(&mut graph, &resolve(&hierarchy, target), false)
+                    }
+                }
+            }
+        }
+
+        Ok(graph)
+    }
+
+    fn node(&mut self, method: &Rc<Method>) -> usize {
+        if let Some(i) = self.index.get(method) {
+            return *i;
+        }
+        self.nodes.push(method.clone());
+        self.index.insert(method.clone(), self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    // The node for a method, if anything calls it or it is defined in the files
+    pub fn find(&self, method: &Method) -> Option<usize> {
+        self.index.get(method).cloned()
+    }
+
+    pub fn callees(&self, node: usize) -> impl Iterator<Item = &CallEdge> {
+        self.edges.iter().filter(move |edge| edge.caller == node)
+    }
+
+    pub fn callers(&self, node: usize) -> impl Iterator<Item = &CallEdge> {
+        self.edges.iter().filter(move |edge| edge.callee == node)
+    }
+
+    // Every node that can be reached from the given entry points, the entry points included
+    pub fn reachable_from(&self, entries: &[usize]) -> Vec<usize> {
+        let successors = self.successors();
+        let mut seen = vec!(false; self.nodes.len());
+        let mut queue = entries.iter().cloned().filter(|node| *node < self.nodes.len()).collect::<VecDeque<_>>();
+        for node in &queue {
+            seen[*node] = true;
+        }
+        while let Some(node) = queue.pop_front() {
+            for &next in &successors[node] {
+                if !seen[next] {
+                    seen[next] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+        (0 .. self.nodes.len()).filter(|node| seen[*node]).collect()
+    }
+
+    // A shortest chain of calls from one node to another, both ends included
+    pub fn path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        if from >= self.nodes.len() || to >= self.nodes.len() {
+            return None;
+        }
+        let successors = self.successors();
+        let mut parent = vec!(None; self.nodes.len());
+        let mut queue = VecDeque::new();
+        parent[from] = Some(from);
+        queue.push_back(from);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = vec!(to);
+                let mut current = to;
+                while current != from {
+                    current = parent[current]?;
+                    path.push(current);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &next in &successors[node] {
+                if parent[next].is_none() {
+                    parent[next] = Some(node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    fn successors(&self) -> Vec<Vec<usize>> {
+        let mut successors = vec!(vec!(); self.nodes.len());
+        for edge in &self.edges {
+            successors[edge.caller].push(edge.callee);
+        }
+        successors
+    }
+
+    // Graphviz, with edges found by class hierarchy analysis dashed
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph calls {\n");
+        for (i, method) in self.nodes.iter().enumerate() {
+            let _ = writeln!(out, "    n{} [label=\"{}\"];", i, escape_dot(&method_label(method)));
+        }
+        for edge in &self.edges {
+            let style = if edge.by_hierarchy { ", style=dashed" } else { "" };
+            let _ = writeln!(out, "    n{} -> n{} [label=\"{}\"{}];", edge.caller, edge.callee, edge.kind.name(), style);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    pub fn to_graphml(&self) -> String {
+        let mut out = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"method\" for=\"node\" attr.name=\"method\" attr.type=\"string\"/>\n",
+            "  <key id=\"kind\" for=\"edge\" attr.name=\"kind\" attr.type=\"string\"/>\n",
+            "  <key id=\"offset\" for=\"edge\" attr.name=\"offset\" attr.type=\"int\"/>\n",
+            "  <key id=\"by_hierarchy\" for=\"edge\" attr.name=\"by_hierarchy\" attr.type=\"boolean\"/>\n",
+            "  <graph id=\"calls\" edgedefault=\"directed\">\n"
+        ));
+        for (i, method) in self.nodes.iter().enumerate() {
+            let _ = writeln!(out, "    <node id=\"n{}\"><data key=\"method\">{}</data></node>", i,
+                             escape_xml(&method_label(method)));
+        }
+        for edge in &self.edges {
+            let _ = writeln!(out, concat!("    <edge source=\"n{}\" target=\"n{}\"><data key=\"kind\">{}</data>",
+                                          "<data key=\"offset\">{}</data><data key=\"by_hierarchy\">{}</data></edge>"),
+                             edge.caller, edge.callee, edge.kind.name(), edge.offset, edge.by_hierarchy);
+        }
+        out.push_str("  </graph>\n</graphml>\n");
+        out
+    }
+}
+
+// e.g. Lcom/example/Foo;->bar(ILjava/lang/String;)V
+fn method_label(method: &Method) -> String {
+    let proto = &method.prototype;
+    let parameters = proto.parameters.iter().map(|p| p.as_str()).collect::<String>();
+    format!("{}->{}({}){}", method.definer, method.name, parameters, proto.return_type)
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// The method a call resolves to, or the reference itself if it isn't defined in the files
+fn resolve(hierarchy: &ClassHierarchy, target: &Rc<Method>) -> Rc<Method> {
+    hierarchy.resolve(target).defined().map_or_else(|| target.clone(), |method| method.method.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn class(b: &mut DexBuilder, name: &str, superclass: &str, interfaces: &[&str],
+             direct: Vec<EncodedMethod>, virtual_: Vec<EncodedMethod>) -> ClassDefinition {
+        ClassDefinition {
+            class_type: b.type_(name),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(b.type_(superclass)),
+            interfaces: interfaces.iter().map(|i| b.type_(i)).collect(),
+            source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: direct,
+                virtual_methods: virtual_
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        }
+    }
+
+    fn method(method: Rc<Method>, insns: Option<Vec<u16>>) -> EncodedMethod {
+        EncodedMethod {
+            method,
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            code: insns.map(|insns| Code {
+                registers_size: 1, ins_size: 1, outs_size: 1, debug_info: None, insns, tries: vec!(), handlers: vec!()
+            }),
+            system_annotations: Default::default()
+        }
+    }
+
+    // Main.main calls Shape.draw virtually, Circle overrides it; Square implements Drawable.draw
+    fn build_file() -> DexFile {
+        let mut b = DexBuilder::new(35);
+        let main = b.method("LMain;", "main", "V", &[]);
+        let shape_draw = b.method("LShape;", "draw", "V", &[]);
+        let circle_draw = b.method("LCircle;", "draw", "V", &[]);
+        let drawable_draw = b.method("LDrawable;", "draw", "V", &[]);
+        let square_draw = b.method("LSquare;", "draw", "V", &[]);
+        let log = b.method("Landroid/util/Log;", "d", "I", &["Ljava/lang/String;", "Ljava/lang/String;"]);
+
+        // method ids are sorted by definer first, and every definer here is different
+        let mut sorted = [&main, &shape_draw, &circle_draw, &drawable_draw, &square_draw, &log];
+        sorted.sort_by(|a, b| a.definer.cmp(&b.definer));
+        let idx = |method: &Rc<Method>| sorted.iter().position(|m| *m == method).unwrap() as u16;
+        let main_code = vec!(
+            // invoke-virtual {v0}, Shape.draw
+            0x106E, idx(&shape_draw), 0x0000,
+            // invoke-interface {v0}, Drawable.draw
+            0x1072, idx(&drawable_draw), 0x0000,
+            0x000E
+        );
+        let circle_code = vec!(
+            // invoke-static/range {}, Log.d
+            0x0077, idx(&log), 0x0000,
+            0x000E
+        );
+
+        let classes = vec!(
+            class(&mut b, "LShape;", "Ljava/lang/Object;", &[], vec!(), vec!(method(shape_draw, Some(vec!(0x000E))))),
+            class(&mut b, "LCircle;", "LShape;", &[], vec!(), vec!(method(circle_draw, Some(circle_code)))),
+            class(&mut b, "LDrawable;", "Ljava/lang/Object;", &[], vec!(), vec!(method(drawable_draw, None))),
+            class(&mut b, "LSquare;", "Ljava/lang/Object;", &["LDrawable;"], vec!(), vec!(method(square_draw, Some(vec!(0x000E))))),
+            class(&mut b, "LMain;", "Ljava/lang/Object;", &[], vec!(method(main, Some(main_code))), vec!())
+        );
+        for class in classes {
+            b.class(class);
+        }
+        b.build().unwrap()
+    }
+
+    fn node(graph: &CallGraph, definer: &str, name: &str) -> usize {
+        graph.nodes.iter().position(|m| *m.definer == definer && *m.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_class_hierarchy_edges() {
+        let file = build_file();
+        let graph = CallGraph::new(&file).unwrap();
+
+        let main = node(&graph, "LMain;", "main");
+        let callees = graph.callees(main)
+            .map(|edge| (graph.nodes[edge.callee].definer.as_str(), edge.kind, edge.by_hierarchy))
+            .collect::<Vec<_>>();
+        assert_eq!(callees, vec!(
+            ("LShape;", InvokeKind::Virtual, false),
+            ("LCircle;", InvokeKind::Virtual, true),
+            ("LDrawable;", InvokeKind::Interface, false),
+            ("LSquare;", InvokeKind::Interface, true)
+        ));
+
+        // framework methods are nodes without any callees of their own
+        let log = node(&graph, "Landroid/util/Log;", "d");
+        assert_eq!(graph.callees(log).count(), 0);
+        assert_eq!(graph.path(main, log), Some(vec!(main, node(&graph, "LCircle;", "draw"), log)));
+        assert!(graph.reachable_from(&[main]).contains(&log));
+        assert!(!graph.reachable_from(&[log]).contains(&main));
+    }
+
+    #[test]
+    fn test_export() {
+        let file = build_file();
+        let graph = CallGraph::new(&file).unwrap();
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph calls {"));
+        assert!(dot.contains("[label=\"LMain;->main()V\"]"));
+        assert!(dot.contains("[label=\"invoke-virtual\", style=dashed]"));
+
+        let graphml = graph.to_graphml();
+        assert_eq!(graphml.matches("<edge ").count(), graph.edges.len());
+        assert!(graphml.contains("LShape;-&gt;draw()V"));
+    }
+}
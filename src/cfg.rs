@@ -0,0 +1,467 @@
+// Control-flow graphs of method bodies, split into basic blocks
+//
+// Blocks start at the first instruction, at every branch, switch and handler target, at the edges of
+// each try range, and after any instruction that ends a block. Inside a try range, every instruction that
+// can throw also ends its block, so that the exceptional edges leave from the instruction that threw.
+
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::instructions::{self, Instruction, Opcode, Payload};
+use crate::result_types::{Code, TryItem};
+
+#[derive(Debug, PartialEq)]
+pub struct ControlFlowGraph {
+    // every instruction in the method, in address order, with the payloads left out
+    pub instructions: Vec<Instruction>,
+    // the entry block is always the first
+    pub blocks: Vec<BasicBlock>
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BasicBlock {
+    // address of the first instruction, in 16-bit code units
+    pub start: u32,
+    // address just past the last instruction
+    pub end: u32,
+    // indexes into ControlFlowGraph.instructions
+    pub instructions: Range<usize>,
+    pub successors: Vec<Edge>,
+    // indexes of the blocks with an edge to this one
+    pub predecessors: Vec<usize>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edge {
+    // index into ControlFlowGraph.blocks
+    pub target: usize,
+    pub kind: EdgeKind
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EdgeKind {
+    // on to the next instruction, including the untaken branch of an if-*
+    FallThrough,
+    // goto and the taken branch of an if-*
+    Branch,
+    // the case of a switch with the given key
+    Switch(i32),
+    // to a catch handler for the given type, or None for a catch-all
+    Exception(Option<Rc<String>>)
+}
+
+impl ControlFlowGraph {
+    pub fn new(code: &Code) -> Result<Self, DexParserError> {
+        let decoded = instructions::decode_all(&code.insns)?;
+
+        // where each instruction (payloads included) starts
+        let mut at = vec!(None; code.insns.len() + 1);
+        for (i, insn) in decoded.iter().enumerate() {
+            at[insn.offset as usize] = Some(i);
+        }
+        let instruction_at = |offset: u32, from: u32| -> Result<&Instruction, DexParserError> {
+            at.get(offset as usize).cloned().flatten()
+                .map(|i| &decoded[i])
+                .filter(|insn| insn.payload.is_none())
+                .ok_or_else(|| DexParserError::InvalidBytecode {
+                    offset: from,
+                    reason: format!("target {} is not the start of an instruction", offset)
+                })
+        };
+        let covering_try = |offset: u32| code.tries.iter()
+            .find(|try_| offset >= try_.start_addr && offset < try_.start_addr.saturating_add(u32::from(try_.insn_count)));
+
+        // Find where the blocks begin
+        let mut leaders = vec!(false; code.insns.len() + 1);
+        let mut mark = |offset: u32, from: u32| -> Result<(), DexParserError> {
+            instruction_at(offset, from)?;
+            leaders[offset as usize] = true;
+            Ok(())
+        };
+
+        let code_end = code.insns.len() as u32;
+        if !decoded.is_empty() {
+            mark(0, 0)?;
+        }
+        for try_ in &code.tries {
+            let handler = &try_.handler;
+            let addrs = handler.handlers.iter().map(|pair| pair.addr).chain(handler.catch_all_addr);
+            for addr in addrs {
+                mark(addr, try_.start_addr)?;
+            }
+            for offset in &[try_.start_addr, try_.start_addr.saturating_add(u32::from(try_.insn_count))] {
+                // ranges can end at the end of the code, or just before a payload
+                if instruction_at(*offset, try_.start_addr).is_ok() {
+                    mark(*offset, try_.start_addr)?;
+                }
+            }
+        }
+        for insn in decoded.iter().filter(|insn| insn.payload.is_none()) {
+            let opcode = insn.opcode;
+            if opcode.can_branch() {
+                mark(insn.target().unwrap_or(0), insn.offset)?;
+            }
+            if opcode.can_switch() {
+                for (_, target) in switch_cases(insn, &decoded, &at)? {
+                    mark(target, insn.offset)?;
+                }
+            }
+            let ends_block = opcode.can_branch() || opcode.can_switch() || !opcode.can_continue()
+                || (opcode.can_throw() && covering_try(insn.offset).is_some());
+            let next = insn.offset + insn.length;
+            if ends_block && instruction_at(next, insn.offset).is_ok() {
+                mark(next, insn.offset)?;
+            }
+        }
+
+        // Lay out the blocks, leaving the payloads out of them
+        let instructions = decoded.iter().filter(|insn| insn.payload.is_none()).cloned().collect::<Vec<_>>();
+        let mut blocks: Vec<BasicBlock> = vec!();
+        for (i, insn) in instructions.iter().enumerate() {
+            let contiguous = i > 0 && instructions[i - 1].offset + instructions[i - 1].length == insn.offset;
+            match blocks.last_mut() {
+                Some(block) if contiguous && !leaders[insn.offset as usize] => {
+                    block.end = insn.offset + insn.length;
+                    block.instructions.end = i + 1;
+                },
+                _ => blocks.push(BasicBlock {
+                    start: insn.offset,
+                    end: insn.offset + insn.length,
+                    instructions: i .. i + 1,
+                    successors: vec!(),
+                    predecessors: vec!()
+                })
+            }
+        }
+
+        let block_at = |offset: u32| blocks.binary_search_by_key(&offset, |block| block.start).ok();
+
+        // Then connect them
+        let mut successors = Vec::with_capacity(blocks.len());
+        for block in &blocks {
+            let last = &instructions[block.instructions.end - 1];
+            let opcode = last.opcode;
+            let mut edges = vec!();
+
+            if opcode.can_branch() {
+                let target = last.target().and_then(block_at).unwrap_or(0);
+                edges.push(Edge { target, kind: EdgeKind::Branch });
+            }
+            if opcode.can_switch() {
+                for (key, target) in switch_cases(last, &decoded, &at)? {
+                    edges.push(Edge { target: block_at(target).unwrap_or(0), kind: EdgeKind::Switch(key) });
+                }
+            }
+            if opcode.can_continue() && block.end < code_end {
+                if let Some(target) = block_at(block.end) {
+                    edges.push(Edge { target, kind: EdgeKind::FallThrough });
+                }
+            }
+
+            let throws = instructions[block.instructions.clone()].iter().any(|insn| insn.opcode.can_throw());
+            if let Some(try_) = covering_try(block.start).filter(|_| throws) {
+                edges.extend(exception_edges(try_, &block_at));
+            }
+
+            successors.push(edges);
+        }
+
+        for (i, edges) in successors.into_iter().enumerate() {
+            for edge in &edges {
+                if !blocks[edge.target].predecessors.contains(&i) {
+                    blocks[edge.target].predecessors.push(i);
+                }
+            }
+            blocks[i].successors = edges;
+        }
+
+        Ok(ControlFlowGraph { instructions, blocks })
+    }
+
+    // The block holding the instruction at the given address
+    pub fn block_at(&self, offset: u32) -> Option<usize> {
+        let i = self.blocks.partition_point(|block| block.end <= offset);
+        self.blocks.get(i).filter(|block| offset >= block.start).map(|_| i)
+    }
+
+    // Blocks that can't be reached from the entry, such as code following a goto that nothing branches to
+    pub fn unreachable_blocks(&self) -> Vec<usize> {
+        let tree = self.dominators();
+        (1 .. self.blocks.len()).filter(|block| tree.immediate_dominator(*block).is_none()).collect()
+    }
+
+    // Each block is dominated by every block that all paths from the entry to it pass through
+    pub fn dominators(&self) -> DominatorTree {
+        let successors = self.blocks.iter()
+            .map(|block| block.successors.iter().map(|edge| edge.target).collect())
+            .collect::<Vec<Vec<_>>>();
+
+        if successors.is_empty() {
+            return DominatorTree { idoms: vec!() };
+        }
+        DominatorTree { idoms: immediate_dominators(&successors, 0) }
+    }
+
+    // Each block is post-dominated by every block that all paths from it to an exit pass through.
+    // The exits are the blocks without successors: returns, and throws that aren't caught.
+    // Blocks whose only post-dominator is the exit itself, or which never reach one, have no parent
+    pub fn post_dominators(&self) -> DominatorTree {
+        let exit = self.blocks.len();
+        // the reversed graph, with a single node standing in for every exit
+        let mut reversed = vec!(vec!(); exit + 1);
+        for (i, block) in self.blocks.iter().enumerate() {
+            if block.successors.is_empty() {
+                reversed[exit].push(i);
+            }
+            for edge in &block.successors {
+                reversed[edge.target].push(i);
+            }
+        }
+
+        let mut idoms = immediate_dominators(&reversed, exit);
+        idoms.truncate(exit);
+        for idom in &mut idoms {
+            if *idom == Some(exit) {
+                *idom = None;
+            }
+        }
+        DominatorTree { idoms }
+    }
+}
+
+// The cases of a switch instruction as (key, absolute target), from its payload
+fn switch_cases(insn: &Instruction, decoded: &[Instruction], at: &[Option<usize>]) -> Result<Vec<(i32, u32)>, DexParserError> {
+    let err = |reason: &str| DexParserError::InvalidBytecode { offset: insn.offset, reason: reason.to_string() };
+    let payload = insn.target()
+        .and_then(|target| at.get(target as usize).cloned().flatten())
+        .and_then(|i| decoded[i].payload.as_ref())
+        .ok_or_else(|| err("switch does not point to a payload"))?;
+
+    match (insn.opcode, payload) {
+        (Opcode::PACKED_SWITCH, Payload::PackedSwitch { .. }) | (Opcode::SPARSE_SWITCH, Payload::SparseSwitch { .. }) => (),
+        _ => return Err(err("switch points to the wrong kind of payload"))
+    }
+
+    Ok(payload.switch_cases().into_iter()
+        .map(|(key, target)| (key, (i64::from(insn.offset) + i64::from(target)) as u32))
+        .collect())
+}
+
+fn exception_edges(try_: &TryItem, block_at: &dyn Fn(u32) -> Option<usize>) -> Vec<Edge> {
+    let handler = &try_.handler;
+    let typed = handler.handlers.iter()
+        .map(|pair| (Some(pair.type_.clone()), pair.addr));
+    typed.chain(handler.catch_all_addr.map(|addr| (None, addr)))
+        .filter_map(|(type_, addr)| block_at(addr).map(|target| Edge { target, kind: EdgeKind::Exception(type_) }))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DominatorTree {
+    // the parent of each block, None for the root and anything that can't be reached from it
+    idoms: Vec<Option<usize>>
+}
+
+impl DominatorTree {
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        self.idoms.get(block).cloned().flatten()
+    }
+
+    // Every block dominates itself
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let mut current = Some(b);
+        while let Some(block) = current {
+            if block == a {
+                return true;
+            }
+            current = self.immediate_dominator(block);
+        }
+        false
+    }
+
+    // The blocks immediately dominated by the given one
+    pub fn children(&self, block: usize) -> Vec<usize> {
+        (0 .. self.idoms.len()).filter(|b| self.idoms[*b] == Some(block)).collect()
+    }
+}
+
+// A Simple, Fast Dominance Algorithm (Cooper, Harvey and Kennedy)
+fn immediate_dominators(successors: &[Vec<usize>], root: usize) -> Vec<Option<usize>> {
+    let n = successors.len();
+
+    // number the nodes in postorder, iteratively so deep graphs don't overflow the stack
+    let mut postorder = Vec::with_capacity(n);
+    let mut visited = vec!(false; n);
+    let mut stack = vec!((root, 0));
+    visited[root] = true;
+    while let Some((node, next)) = stack.pop() {
+        if let Some(&succ) = successors[node].get(next) {
+            stack.push((node, next + 1));
+            if !visited[succ] {
+                visited[succ] = true;
+                stack.push((succ, 0));
+            }
+        } else {
+            postorder.push(node);
+        }
+    }
+    let mut number = vec!(usize::MAX; n);
+    for (i, node) in postorder.iter().enumerate() {
+        number[*node] = i;
+    }
+
+    let mut predecessors = vec!(vec!(); n);
+    for (node, succs) in successors.iter().enumerate() {
+        for succ in succs {
+            predecessors[*succ].push(node);
+        }
+    }
+
+    let mut idoms = vec!(None; n);
+    idoms[root] = Some(root);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for node in postorder.iter().rev().filter(|node| **node != root) {
+            let mut new_idom = None;
+            for &pred in &predecessors[*node] {
+                if idoms[pred].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => pred,
+                    Some(current) => intersect(&idoms, &number, pred, current)
+                });
+            }
+            if new_idom.is_some() && idoms[*node] != new_idom {
+                idoms[*node] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    idoms[root] = None;
+    idoms
+}
+
+fn intersect(idoms: &[Option<usize>], number: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while number[a] < number[b] {
+            a = idoms[a].unwrap_or(b);
+        }
+        while number[b] < number[a] {
+            b = idoms[b].unwrap_or(a);
+        }
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::result_types::{EncodedCatchHandler, EncodedTypeAddrPair};
+
+    fn code(insns: Vec<u16>, tries: Vec<TryItem>) -> Code {
+        Code { registers_size: 2, ins_size: 0, outs_size: 0, debug_info: None, insns, tries, handlers: vec!() }
+    }
+
+    #[test]
+    fn test_if_else() {
+        let cfg = ControlFlowGraph::new(&code(vec!(
+            // 0: if-eqz v0, +4
+            0x0038, 0x0004,
+            // 2: const/4 v1, #1
+            0x1112,
+            // 3: goto +2
+            0x0228,
+            // 4: const/4 v1, #0
+            0x0112,
+            // 5: return v1
+            0x010F
+        ), vec!())).unwrap();
+
+        let starts = cfg.blocks.iter().map(|block| block.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec!(0, 2, 4, 5));
+        assert_eq!(cfg.blocks[0].successors, vec!(
+            Edge { target: 2, kind: EdgeKind::Branch },
+            Edge { target: 1, kind: EdgeKind::FallThrough }
+        ));
+        assert_eq!(cfg.blocks[1].successors, vec!(Edge { target: 3, kind: EdgeKind::Branch }));
+        assert_eq!(cfg.blocks[3].predecessors, vec!(1, 2));
+
+        let dom = cfg.dominators();
+        assert_eq!(dom.immediate_dominator(3), Some(0));
+        assert!(dom.dominates(0, 2));
+        assert!(!dom.dominates(1, 3));
+        assert_eq!(dom.children(0), vec!(1, 2, 3));
+
+        let post = cfg.post_dominators();
+        assert_eq!(post.immediate_dominator(0), Some(3));
+        assert_eq!(post.immediate_dominator(3), None);
+    }
+
+    #[test]
+    fn test_switch() {
+        let cfg = ControlFlowGraph::new(&code(vec!(
+            // 0: packed-switch v0, +6
+            0x002B, 0x0006, 0x0000,
+            // 3: return-void
+            0x000E,
+            // 4: return-void
+            0x000E,
+            // 5: nop, so the payload is aligned
+            0x0000,
+            // 6: packed-switch-payload, cases 1 -> 4 and 2 -> 3
+            0x0100, 0x0002, 0x0001, 0x0000, 0x0004, 0x0000, 0x0003, 0x0000
+        ), vec!())).unwrap();
+
+        assert_eq!(cfg.instructions.len(), 4);
+        assert_eq!(cfg.blocks.len(), 4);
+        assert_eq!(cfg.blocks[0].successors, vec!(
+            Edge { target: 2, kind: EdgeKind::Switch(1) },
+            Edge { target: 1, kind: EdgeKind::Switch(2) },
+            Edge { target: 1, kind: EdgeKind::FallThrough }
+        ));
+        // the nop before the payload can't be reached
+        assert_eq!(cfg.unreachable_blocks(), vec!(3));
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let handler = EncodedCatchHandler {
+            handlers: vec!(EncodedTypeAddrPair { type_: Rc::new("Ljava/lang/Exception;".to_string()), addr: 3 }),
+            catch_all_addr: Some(4)
+        };
+        let cfg = ControlFlowGraph::new(&code(vec!(
+            // 0: const/4 v0, #0
+            0x0012,
+            // 1: monitor-enter v0
+            0x001D,
+            // 2: return-void
+            0x000E,
+            // 3: move-exception v0
+            0x000D,
+            // 4: return-void
+            0x000E
+        ), vec!(TryItem { start_addr: 1, insn_count: 1, handler }))).unwrap();
+
+        let starts = cfg.blocks.iter().map(|block| block.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec!(0, 1, 2, 3, 4));
+        assert_eq!(cfg.blocks[1].successors, vec!(
+            Edge { target: 2, kind: EdgeKind::FallThrough },
+            Edge { target: 3, kind: EdgeKind::Exception(Some(Rc::new("Ljava/lang/Exception;".to_string()))) },
+            Edge { target: 4, kind: EdgeKind::Exception(None) }
+        ));
+        // const/4 can't throw, so its block has no exceptional edges even though it is outside the try
+        assert_eq!(cfg.blocks[0].successors, vec!(Edge { target: 1, kind: EdgeKind::FallThrough }));
+        assert_eq!(cfg.post_dominators().immediate_dominator(1), None);
+    }
+
+    #[test]
+    fn test_invalid_target() {
+        // goto into the middle of the const/16
+        let res = ControlFlowGraph::new(&code(vec!(0x0228, 0x0013, 0x0000, 0x000E), vec!()));
+        assert!(res.is_err());
+    }
+}
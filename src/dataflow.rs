@@ -0,0 +1,292 @@
+// A worklist solver for dataflow analyses over a method's control-flow graph
+//
+// An analysis gives the state at the boundary (the entry for a forward analysis, the exits for a
+// backward one), how states join where paths meet and how each instruction transforms a state. The
+// solver iterates to a fixed point and keeps the state at the start and end of every block.
+//
+// Blocks inside a try range end at the first instruction that can throw, so a state following an
+// exceptional edge is the one from just before that instruction rather than after it.
+
+use std::collections::VecDeque;
+
+use crate::cfg::{ControlFlowGraph, Edge, EdgeKind};
+use crate::instructions::Instruction;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    Forward,
+    Backward
+}
+
+pub trait Analysis {
+    type State: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    // The state on entry to the method, or on leaving it for a backward analysis
+    fn boundary(&self, cfg: &ControlFlowGraph) -> Self::State;
+
+    // The state of a block nothing has reached yet, which joining with any other state leaves unchanged
+    fn bottom(&self, cfg: &ControlFlowGraph) -> Self::State;
+
+    // Joins other into state, returning whether state changed
+    fn join(&self, state: &mut Self::State, other: &Self::State) -> bool;
+
+    // Applies the effect of an instruction, in the direction of the analysis
+    fn transfer(&self, insn: &Instruction, state: &mut Self::State);
+
+    // Adjusts a state as it crosses an edge, e.g. to make the caught exception available to a handler
+    fn edge(&self, _edge: &Edge, _state: &mut Self::State) {}
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DataflowResult<S> {
+    // the state on entry to each block, in program order whatever the direction of the analysis
+    pub block_entry: Vec<S>,
+    // the state on leaving each block
+    pub block_exit: Vec<S>
+}
+
+pub fn solve<A: Analysis>(cfg: &ControlFlowGraph, analysis: &A) -> DataflowResult<A::State> {
+    match analysis.direction() {
+        Direction::Forward => solve_forward(cfg, analysis),
+        Direction::Backward => solve_backward(cfg, analysis)
+    }
+}
+
+fn solve_forward<A: Analysis>(cfg: &ControlFlowGraph, analysis: &A) -> DataflowResult<A::State> {
+    let n = cfg.blocks.len();
+    let bottom = analysis.bottom(cfg);
+    let mut block_entry = vec!(bottom.clone(); n);
+    let mut block_exit = vec!(bottom; n);
+    if n == 0 {
+        return DataflowResult { block_entry, block_exit };
+    }
+
+    block_entry[0] = analysis.boundary(cfg);
+    let mut queued = vec!(false; n);
+    let mut worklist = VecDeque::new();
+    worklist.push_back(0);
+    queued[0] = true;
+
+    while let Some(b) = worklist.pop_front() {
+        queued[b] = false;
+        let block = &cfg.blocks[b];
+        let instructions = &cfg.instructions[block.instructions.clone()];
+
+        let mut state = block_entry[b].clone();
+        let mut before_last = state.clone();
+        for (i, insn) in instructions.iter().enumerate() {
+            if i + 1 == instructions.len() {
+                before_last = state.clone();
+            }
+            analysis.transfer(insn, &mut state);
+        }
+
+        for edge in &block.successors {
+            let mut out = match edge.kind {
+                EdgeKind::Exception(_) => before_last.clone(),
+                _ => state.clone()
+            };
+            analysis.edge(edge, &mut out);
+            if analysis.join(&mut block_entry[edge.target], &out) && !queued[edge.target] {
+                queued[edge.target] = true;
+                worklist.push_back(edge.target);
+            }
+        }
+        block_exit[b] = state;
+    }
+
+    DataflowResult { block_entry, block_exit }
+}
+
+fn solve_backward<A: Analysis>(cfg: &ControlFlowGraph, analysis: &A) -> DataflowResult<A::State> {
+    let n = cfg.blocks.len();
+    let bottom = analysis.bottom(cfg);
+    let mut block_entry = vec!(bottom.clone(); n);
+    let mut block_exit = vec!(bottom; n);
+
+    // every block is visited at least once, the exits first
+    let mut queued = vec!(true; n);
+    let mut worklist = (0 .. n).rev().collect::<VecDeque<_>>();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        if block.successors.is_empty() {
+            block_exit[b] = analysis.boundary(cfg);
+        }
+    }
+
+    while let Some(b) = worklist.pop_front() {
+        queued[b] = false;
+        let block = &cfg.blocks[b];
+        let instructions = &cfg.instructions[block.instructions.clone()];
+
+        let mut exit = if block.successors.is_empty() { analysis.boundary(cfg) } else { analysis.bottom(cfg) };
+        let mut exceptional = analysis.bottom(cfg);
+        for edge in &block.successors {
+            let mut state = block_entry[edge.target].clone();
+            analysis.edge(edge, &mut state);
+            match edge.kind {
+                EdgeKind::Exception(_) => analysis.join(&mut exceptional, &state),
+                _ => analysis.join(&mut exit, &state)
+            };
+        }
+
+        let mut state = exit.clone();
+        for (i, insn) in instructions.iter().enumerate().rev() {
+            analysis.transfer(insn, &mut state);
+            // a handler is entered from just before the instruction that throws
+            if i + 1 == instructions.len() {
+                analysis.join(&mut state, &exceptional);
+            }
+        }
+        block_exit[b] = exit;
+
+        if state != block_entry[b] {
+            block_entry[b] = state;
+            for &pred in &block.predecessors {
+                if !queued[pred] {
+                    queued[pred] = true;
+                    worklist.push_back(pred);
+                }
+            }
+        }
+    }
+
+    DataflowResult { block_entry, block_exit }
+}
+
+impl<S: Clone> DataflowResult<S> {
+    // The state at every instruction of the graph, in the order of cfg.instructions: the state before the
+    // instruction for a forward analysis, after it for a backward one
+    pub fn instruction_states<A: Analysis<State = S>>(&self, cfg: &ControlFlowGraph, analysis: &A) -> Vec<S> {
+        let mut states = vec!(analysis.bottom(cfg); cfg.instructions.len());
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            match analysis.direction() {
+                Direction::Forward => {
+                    let mut state = self.block_entry[b].clone();
+                    for i in block.instructions.clone() {
+                        states[i] = state.clone();
+                        analysis.transfer(&cfg.instructions[i], &mut state);
+                    }
+                },
+                Direction::Backward => {
+                    let mut state = self.block_exit[b].clone();
+                    for i in block.instructions.clone().rev() {
+                        states[i] = state.clone();
+                        analysis.transfer(&cfg.instructions[i], &mut state);
+                    }
+                }
+            }
+        }
+        states
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::result_types::Code;
+
+    fn code(insns: Vec<u16>) -> Code {
+        Code { registers_size: 4, ins_size: 0, outs_size: 0, debug_info: None, insns, tries: vec!(), handlers: vec!() }
+    }
+
+    // Registers that are read before being written again, as a bit set
+    struct Liveness;
+
+    impl Analysis for Liveness {
+        type State = u32;
+
+        fn direction(&self) -> Direction {
+            Direction::Backward
+        }
+
+        fn boundary(&self, _: &ControlFlowGraph) -> u32 {
+            0
+        }
+
+        fn bottom(&self, _: &ControlFlowGraph) -> u32 {
+            0
+        }
+
+        fn join(&self, state: &mut u32, other: &u32) -> bool {
+            let old = *state;
+            *state |= *other;
+            *state != old
+        }
+
+        fn transfer(&self, insn: &Instruction, state: &mut u32) {
+            use crate::instructions::Opcode;
+            match insn.opcode {
+                // const/4 vA writes vA
+                Opcode::CONST_4 => *state &= !(1 << insn.registers[0]),
+                // add-int/2addr vA, vB reads both and writes vA
+                Opcode::ADD_INT_2ADDR => *state |= (1 << insn.registers[0]) | (1 << insn.registers[1]),
+                Opcode::IF_EQZ | Opcode::RETURN => *state |= 1 << insn.registers[0],
+                _ => {}
+            }
+        }
+    }
+
+    // The number of paths leading to each block
+    struct Paths;
+
+    impl Analysis for Paths {
+        type State = u32;
+
+        fn direction(&self) -> Direction {
+            Direction::Forward
+        }
+
+        fn boundary(&self, _: &ControlFlowGraph) -> u32 {
+            1
+        }
+
+        fn bottom(&self, _: &ControlFlowGraph) -> u32 {
+            0
+        }
+
+        fn join(&self, state: &mut u32, other: &u32) -> bool {
+            let old = *state;
+            *state = (*state).max(*other);
+            *state != old
+        }
+
+        fn transfer(&self, _: &Instruction, _: &mut u32) {}
+
+        fn edge(&self, edge: &Edge, state: &mut u32) {
+            if edge.kind == EdgeKind::Branch {
+                *state += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_backward() {
+        let cfg = ControlFlowGraph::new(&code(vec!(
+            0x1012, // const/4 v0, #1
+            0x0038, 0x0003, // if-eqz v0, +3
+            0x10B0, // add-int/2addr v0, v1
+            0x000F // return v0
+        ))).unwrap();
+        let result = solve(&cfg, &Liveness);
+
+        assert_eq!(result.block_entry, vec!(0b10, 0b11, 0b01));
+        let states = result.instruction_states(&cfg, &Liveness);
+        // after each instruction
+        assert_eq!(states, vec!(0b11, 0b11, 0b01, 0));
+    }
+
+    #[test]
+    fn test_forward() {
+        let cfg = ControlFlowGraph::new(&code(vec!(
+            0x1012, // const/4 v0, #1
+            0x0038, 0x0003, // if-eqz v0, +3
+            0x10B0, // add-int/2addr v0, v1
+            0x000F // return v0
+        ))).unwrap();
+        let result = solve(&cfg, &Paths);
+        assert_eq!(result.block_entry, vec!(1, 1, 2));
+        assert_eq!(result.instruction_states(&cfg, &Paths), vec!(1, 1, 1, 2));
+    }
+}
@@ -0,0 +1,897 @@
+// Structural comparison of two files: which classes, fields and methods were added, removed or changed, and how
+//
+// Members are matched by name and type, so a renamed member shows up as one removed and another added. Bytecode is
+// compared instruction by instruction after renaming registers in the order they are first used and writing out the
+// strings, types and members instructions refer to, so renumbered registers and reordered pools aren't changes.
+// Nops that only align payloads are left out, and branches count instructions rather than code units.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::instructions::{self, IndexKind, Instruction, Opcode, Payload};
+use crate::result_types::*;
+
+// above this many cells in the table of common subsequence lengths, changed code is reported as replaced whole
+const MAX_LCS_CELLS: usize = 1 << 22;
+
+// The classes that differ between two files, in descriptor order
+#[derive(Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DexDiff {
+    pub classes: Vec<ClassDiff>
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClassDiff {
+    pub class_type: String,
+    pub change: Change,
+    // the members that differ, only for changed classes
+    pub fields: Vec<MemberDiff>,
+    pub methods: Vec<MemberDiff>
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemberDiff {
+    // `count:I` for fields, `run(I)V` for methods
+    pub name: String,
+    pub change: Change
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Change {
+    // only in the new file
+    Added,
+    // only in the old file
+    Removed,
+    // in both, with these differences. A changed class may have none of its own, only changed members
+    Changed(Vec<Difference>)
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Difference {
+    AccessFlags {
+        old: Vec<AccessFlag>,
+        new: Vec<AccessFlag>
+    },
+    Superclass {
+        old: Option<String>,
+        new: Option<String>
+    },
+    Interfaces {
+        old: Vec<String>,
+        new: Vec<String>
+    },
+    SourceFile {
+        old: Option<String>,
+        new: Option<String>
+    },
+    // annotations written out with their values, e.g. `runtime @Lcom/foo/Tag;(value=1)`; one whose values
+    // changed is both removed and added
+    Annotations {
+        removed: Vec<String>,
+        added: Vec<String>
+    },
+    // the initial value of a static field
+    StaticValue {
+        old: Option<String>,
+        new: Option<String>
+    },
+    // the instructions only in the old code and only in the new, in order
+    Instructions(Vec<InstructionEdit>),
+    // try blocks and their handlers, with addresses given as instruction numbers
+    Handlers {
+        removed: Vec<String>,
+        added: Vec<String>
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InstructionEdit {
+    // the address of a removed instruction in the old code, or of an added one in the new code
+    pub old_offset: Option<u32>,
+    pub new_offset: Option<u32>,
+    // with registers renamed r0, r1, ... in order of first use, and branch targets as a number of instructions
+    // forwards or backwards: `if-eqz r0, @+3`
+    pub instruction: String
+}
+
+pub fn diff(old: &DexFile, new: &DexFile) -> DexDiff {
+    let old_classes = old.classes.iter().map(|class| (class.class_type.as_str(), class)).collect::<BTreeMap<_, _>>();
+    let new_classes = new.classes.iter().map(|class| (class.class_type.as_str(), class)).collect::<BTreeMap<_, _>>();
+
+    let mut classes = vec!();
+    for (class_type, pair) in merge(&old_classes, &new_classes) {
+        let class_diff = match pair {
+            (Some(_), None) => ClassDiff { class_type: class_type.to_string(), change: Change::Removed, fields: vec!(),
+                                           methods: vec!() },
+            (None, Some(_)) => ClassDiff { class_type: class_type.to_string(), change: Change::Added, fields: vec!(),
+                                           methods: vec!() },
+            (Some(old_class), Some(new_class)) => match diff_class(old, old_class, new, new_class) {
+                Some(class_diff) => class_diff,
+                None => continue
+            },
+            (None, None) => unreachable!()
+        };
+        classes.push(class_diff);
+    }
+    DexDiff { classes }
+}
+
+// what the old and the new file have under a key
+type Pair<V> = (Option<V>, Option<V>);
+
+// The keys of both maps in order, with the value each map has for them
+fn merge<K: Ord + Copy, V: Copy>(old: &BTreeMap<K, V>, new: &BTreeMap<K, V>) -> Vec<(K, Pair<V>)> {
+    let mut keys = old.keys().chain(new.keys()).cloned().collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter().map(|key| (key, (old.get(&key).cloned(), new.get(&key).cloned()))).collect()
+}
+
+fn diff_class(old: &DexFile, old_class: &ClassDefinition, new: &DexFile, new_class: &ClassDefinition)
+              -> Option<ClassDiff> {
+    let mut differences = vec!();
+    flags(&mut differences, &old_class.access_flags, &new_class.access_flags);
+    if old_class.superclass != new_class.superclass {
+        differences.push(Difference::Superclass { old: text(&old_class.superclass), new: text(&new_class.superclass) });
+    }
+    if old_class.interfaces != new_class.interfaces {
+        let names = |interfaces: &[Rc<String>]| interfaces.iter().map(|i| i.to_string()).collect();
+        differences.push(Difference::Interfaces { old: names(&old_class.interfaces),
+                                                  new: names(&new_class.interfaces) });
+    }
+    if old_class.source_file_name != new_class.source_file_name {
+        differences.push(Difference::SourceFile { old: text(&old_class.source_file_name),
+                                                  new: text(&new_class.source_file_name) });
+    }
+    let class_annotations = |class: &ClassDefinition| class.annotations.iter()
+        .flat_map(|annotations| annotations.class_annotations.iter())
+        .map(|annotation| annotation_text(&annotation.visibility, &annotation.type_, &annotation.elements))
+        .collect::<Vec<_>>();
+    annotations(&mut differences, class_annotations(old_class), class_annotations(new_class));
+
+    let fields = diff_members(&fields(old_class), &fields(new_class),
+                              |old_field, new_field| diff_field(old_class, old_field, new_class, new_field));
+    let methods = diff_members(&methods(old_class), &methods(new_class), |old_method, new_method| {
+        diff_method(old, old_class, old_method, new, new_class, new_method)
+    });
+
+    if differences.is_empty() && fields.is_empty() && methods.is_empty() {
+        return None;
+    }
+    Some(ClassDiff { class_type: old_class.class_type.to_string(), change: Change::Changed(differences), fields,
+                     methods })
+}
+
+fn diff_members<T: Copy>(old: &BTreeMap<String, T>, new: &BTreeMap<String, T>,
+                         mut diff_member: impl FnMut(T, T) -> Vec<Difference>) -> Vec<MemberDiff> {
+    let old = old.iter().map(|(name, member)| (name.as_str(), *member)).collect::<BTreeMap<_, _>>();
+    let new = new.iter().map(|(name, member)| (name.as_str(), *member)).collect::<BTreeMap<_, _>>();
+    merge(&old, &new).into_iter()
+        .filter_map(|(name, pair)| {
+            let change = match pair {
+                (Some(_), None) => Change::Removed,
+                (None, Some(_)) => Change::Added,
+                (Some(old_member), Some(new_member)) => {
+                    let differences = diff_member(old_member, new_member);
+                    if differences.is_empty() {
+                        return None;
+                    }
+                    Change::Changed(differences)
+                },
+                (None, None) => unreachable!()
+            };
+            Some(MemberDiff { name: name.to_string(), change })
+        })
+        .collect()
+}
+
+// The fields of a class by name and type, with the index of the static ones for their initial value
+fn fields(class: &ClassDefinition) -> BTreeMap<String, (&EncodedField, Option<usize>)> {
+    class.class_data.iter()
+        .flat_map(|data| data.static_fields.iter().enumerate().map(|(i, field)| (field, Some(i)))
+            .chain(data.instance_fields.iter().map(|field| (field, None))))
+        .map(|(field, index)| (format!("{}:{}", field.field.name, field.field.type_), (field, index)))
+        .collect()
+}
+
+fn methods(class: &ClassDefinition) -> BTreeMap<String, &EncodedMethod> {
+    class.class_data.iter()
+        .flat_map(|data| data.direct_methods.iter().chain(data.virtual_methods.iter()))
+        .map(|method| (format!("{}{}", method.method.name, descriptor(&method.method.prototype)), method))
+        .collect()
+}
+
+fn diff_field(old_class: &ClassDefinition, (old_field, old_index): (&EncodedField, Option<usize>),
+              new_class: &ClassDefinition, (new_field, new_index): (&EncodedField, Option<usize>)) -> Vec<Difference> {
+    let mut differences = vec!();
+    flags(&mut differences, &old_field.access_flags, &new_field.access_flags);
+    let field_annotations = |class: &ClassDefinition, field: &EncodedField| class.annotations.iter()
+        .flat_map(|annotations| annotations.field_annotations.iter())
+        .filter(|annotations| annotations.field_data == field.field)
+        .flat_map(|annotations| annotations.annotations.iter())
+        .map(|annotation| annotation_text(&annotation.visibility, &annotation.type_, &annotation.annotations))
+        .collect::<Vec<_>>();
+    annotations(&mut differences, field_annotations(old_class, old_field), field_annotations(new_class, new_field));
+
+    // static fields past the end of static_values start out with their type's default value
+    let static_value = |class: &ClassDefinition, index: Option<usize>| index
+        .map(|i| class.static_values.get(i).map_or_else(|| "default".to_string(), value_text));
+    let (old_value, new_value) = (static_value(old_class, old_index), static_value(new_class, new_index));
+    if old_value != new_value {
+        differences.push(Difference::StaticValue { old: old_value, new: new_value });
+    }
+    differences
+}
+
+fn diff_method(old: &DexFile, old_class: &ClassDefinition, old_method: &EncodedMethod,
+               new: &DexFile, new_class: &ClassDefinition, new_method: &EncodedMethod) -> Vec<Difference> {
+    let mut differences = vec!();
+    flags(&mut differences, &old_method.access_flags, &new_method.access_flags);
+    annotations(&mut differences, method_annotations(old_class, old_method), method_annotations(new_class, new_method));
+
+    let old_code = Normalized::new(old, old_method.code.as_ref());
+    let new_code = Normalized::new(new, new_method.code.as_ref());
+    let edits = edits(&old_code.instructions, &new_code.instructions);
+    if !edits.is_empty() {
+        differences.push(Difference::Instructions(edits));
+    }
+    let removed = old_code.handlers.iter().filter(|h| !new_code.handlers.contains(h)).cloned().collect::<Vec<_>>();
+    let added = new_code.handlers.iter().filter(|h| !old_code.handlers.contains(h)).cloned().collect::<Vec<_>>();
+    if !removed.is_empty() || !added.is_empty() {
+        differences.push(Difference::Handlers { removed, added });
+    }
+    differences
+}
+
+// The annotations of a method and of each of its parameters
+fn method_annotations(class: &ClassDefinition, method: &EncodedMethod) -> Vec<String> {
+    let annotations = match class.annotations {
+        Some(ref annotations) => annotations,
+        None => return vec!()
+    };
+    let on_method = annotations.method_annotations.iter()
+        .filter(|annotations| annotations.method == method.method)
+        .flat_map(|annotations| annotations.annotations.iter())
+        .map(|annotation| annotation_text(&annotation.visibility, &annotation.type_, &annotation.annotations));
+    let on_parameters = annotations.parameter_annotations.iter()
+        .filter(|annotations| annotations.method == method.method)
+        .flat_map(|annotations| annotations.annotations.iter().enumerate())
+        .flat_map(|(i, annotations)| annotations.iter().map(move |annotation| format!("parameter {}: {}", i,
+            annotation_text(&annotation.visibility, &annotation.type_, &annotation.annotations))));
+    on_method.chain(on_parameters).collect()
+}
+
+fn flags(differences: &mut Vec<Difference>, old: &[AccessFlag], new: &[AccessFlag]) {
+    if old != new {
+        differences.push(Difference::AccessFlags { old: old.to_vec(), new: new.to_vec() });
+    }
+}
+
+// Annotations are compared as sets, each with all its values
+fn annotations(differences: &mut Vec<Difference>, old: Vec<String>, new: Vec<String>) {
+    let removed = old.iter().filter(|a| !new.contains(a)).cloned().collect::<Vec<_>>();
+    let added = new.iter().filter(|a| !old.contains(a)).cloned().collect::<Vec<_>>();
+    if !removed.is_empty() || !added.is_empty() {
+        differences.push(Difference::Annotations { removed, added });
+    }
+}
+
+fn text(s: &Option<Rc<String>>) -> Option<String> {
+    s.as_ref().map(|s| s.to_string())
+}
+
+// `(ILjava/lang/String;)V`
+fn descriptor(prototype: &Prototype) -> String {
+    format!("({}){}", prototype.parameters.iter().map(|p| p.as_str()).collect::<String>(), prototype.return_type)
+}
+
+fn field_text(field: &Field) -> String {
+    format!("{}.{}:{}", field.definer, field.name, field.type_)
+}
+
+fn method_text(method: &Method) -> String {
+    format!("{}.{}:{}", method.definer, method.name, descriptor(&method.prototype))
+}
+
+fn method_handle_text(handle: &MethodHandle) -> String {
+    let member = match handle.member {
+        MethodHandleMember::Field(ref field) => field_text(field),
+        MethodHandleMember::Method(ref method) => method_text(method)
+    };
+    format!("{:?} {}", handle.type_, member)
+}
+
+fn annotation_text(visibility: &Visibility, type_: &str, elements: &[AnnotationElement]) -> String {
+    let visibility = match visibility {
+        Visibility::BUILD => "build",
+        Visibility::RUNTIME => "runtime",
+        Visibility::SYSTEM => "system"
+    };
+    format!("{} @{}({})", visibility, type_, elements_text(elements))
+}
+
+fn elements_text(elements: &[AnnotationElement]) -> String {
+    elements.iter()
+        .map(|element| format!("{}={}", element.name, value_text(&element.value)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn value_text(value: &EncodedValue) -> String {
+    match value {
+        EncodedValue::Byte(v) => format!("(byte) {}", *v as i8),
+        EncodedValue::Short(v) => format!("(short) {}", v),
+        EncodedValue::Char(v) => format!("(char) {}", v),
+        EncodedValue::Int(v) => v.to_string(),
+        EncodedValue::Long(v) => format!("{}L", v),
+        EncodedValue::Float(v) => format!("{:?}f", v),
+        EncodedValue::Double(v) => format!("{:?}", v),
+        EncodedValue::Boolean(v) => v.to_string(),
+        EncodedValue::Null => "null".to_string(),
+        EncodedValue::String(s) => format!("{:?}", s),
+        EncodedValue::Type(t) => t.to_string(),
+        EncodedValue::Field(f) => field_text(f),
+        EncodedValue::Enum(f) => format!("enum {}", field_text(f)),
+        EncodedValue::Method(m) => method_text(m),
+        EncodedValue::MethodType(p) => descriptor(p),
+        EncodedValue::MethodHandle(h) => method_handle_text(h),
+        EncodedValue::Array(values) => format!("{{{}}}", values.iter().map(value_text).collect::<Vec<_>>().join(", ")),
+        EncodedValue::Annotation(annotation) => format!("@{}({})", annotation.type_, elements_text(&annotation.values))
+    }
+}
+
+// A method's code as text that doesn't depend on register numbers, pool indexes or the size of instructions
+struct Normalized {
+    // the address of each instruction, and the instruction
+    instructions: Vec<(u32, String)>,
+    handlers: Vec<String>
+}
+
+impl Normalized {
+    fn new(file: &DexFile, code: Option<&Code>) -> Self {
+        let code = match code {
+            Some(code) => code,
+            None => return Normalized { instructions: vec!(), handlers: vec!() }
+        };
+        let decoded = match instructions::decode_all(&code.insns) {
+            Ok(decoded) => decoded,
+            // compared unit by unit
+            Err(_) => return Normalized {
+                instructions: code.insns.iter().enumerate()
+                    .map(|(i, unit)| (i as u32, format!("{:04x}", unit)))
+                    .collect(),
+                handlers: vec!()
+            }
+        };
+        // a nop before a payload that only aligns it comes and goes with the size of the instructions before it
+        let padding = |i: usize| decoded[i].opcode == Opcode::NOP && decoded[i].payload.is_none()
+            && decoded[i].offset % 2 == 1 && decoded.get(i + 1).is_some_and(|next| next.payload.is_some());
+        let decoded = decoded.iter().enumerate()
+            .filter(|(i, _)| !padding(*i))
+            .map(|(_, insn)| insn)
+            .collect::<Vec<_>>();
+        let numbers = decoded.iter().enumerate().map(|(i, insn)| (insn.offset, i)).collect::<HashMap<_, _>>();
+        // payloads hold their targets relative to the switch instruction using them
+        let switches = decoded.iter()
+            .filter(|insn| insn.opcode.can_switch())
+            .filter_map(|insn| insn.target().map(|payload| (payload, insn.offset)))
+            .collect::<HashMap<_, _>>();
+
+        let mut registers = HashMap::new();
+        // a branch as the number of instructions it goes forwards or backwards
+        let relative = |from: u32, offset: i32| {
+            let target = (i64::from(from) + i64::from(offset)) as u32;
+            match (numbers.get(&from), numbers.get(&target)) {
+                (Some(from), Some(to)) => format!("@{:+}", *to as i64 - *from as i64),
+                _ => format!("@?{:+}", offset)
+            }
+        };
+        let instructions = decoded.iter()
+            .map(|insn| (insn.offset, instruction_text(file, insn, &mut registers, &switches, &relative)))
+            .collect();
+
+        // the instruction numbers a range of addresses starts and ends at
+        let number = |offset: u32| numbers.get(&offset).map_or_else(|| format!("?{}", offset), |n| n.to_string());
+        let handler = |handler: &EncodedCatchHandler| handler.handlers.iter()
+            .map(|pair| format!("{} -> {}", pair.type_, number(pair.addr)))
+            .chain(handler.catch_all_addr.map(|addr| format!("<any> -> {}", number(addr))))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let handlers = code.tries.iter()
+            .map(|try_item| format!("{} .. {}: {}", number(try_item.start_addr),
+                                    number(try_item.start_addr + u32::from(try_item.insn_count)),
+                                    handler(&try_item.handler)))
+            .collect();
+
+        Normalized { instructions, handlers }
+    }
+}
+
+fn instruction_text(file: &DexFile, insn: &Instruction, registers: &mut HashMap<u16, usize>,
+                    switches: &HashMap<u32, u32>, relative: &dyn Fn(u32, i32) -> String) -> String {
+    let mut operands = insn.registers.iter()
+        .map(|register| {
+            let next = registers.len();
+            format!("r{}", registers.entry(*register).or_insert(next))
+        })
+        .collect::<Vec<_>>();
+    if let Some(literal) = insn.literal {
+        operands.push(format!("#{}", literal));
+    }
+    if let Some(offset) = insn.branch_offset {
+        operands.push(relative(insn.offset, offset));
+    }
+    if let Some(index) = insn.index {
+        operands.push(reference(file, insn.opcode.index_kind(), index));
+    }
+    if let Some(index) = insn.proto_index {
+        operands.push(reference(file, IndexKind::Prototype, index));
+    }
+    match insn.payload {
+        Some(Payload::FillArrayData { element_width, ref data }) => {
+            operands.push(format!("width {}", element_width));
+            operands.push(data.iter().map(|b| format!("{:02x}", b)).collect());
+        },
+        Some(ref payload) => {
+            let switch = switches.get(&insn.offset).cloned();
+            operands.extend(payload.switch_cases().into_iter().map(|(key, target)| match switch {
+                Some(switch) => format!("{}: {}", key, relative(switch, target)),
+                None => format!("{}: @?{:+}", key, target)
+            }));
+        },
+        None => {}
+    }
+    if operands.is_empty() {
+        insn.opcode.name().to_string()
+    } else {
+        format!("{} {}", insn.opcode.name(), operands.join(", "))
+    }
+}
+
+// What an index refers to, written out so that it compares the same whatever its position in the pool
+fn reference(file: &DexFile, kind: IndexKind, index: u32) -> String {
+    let data = &file.file_data;
+    let i = index as usize;
+    let text = match kind {
+        IndexKind::String => data.string_data.get(i).map(|s| format!("{:?}", s)),
+        IndexKind::Type => data.type_identifiers.get(i).map(|t| t.to_string()),
+        IndexKind::Field => data.fields.get(i).map(|f| field_text(f)),
+        IndexKind::Method => data.methods.get(i).map(|m| method_text(m)),
+        IndexKind::Prototype => data.prototypes.get(i).map(|p| descriptor(p)),
+        IndexKind::CallSite => data.call_sites.get(i).map(|c| format!("call_site({}, {}, {})",
+            method_handle_text(&c.method_handle), c.method_name, descriptor(&c.method_type))),
+        IndexKind::MethodHandle => data.method_handles.get(i).map(|h| method_handle_text(h)),
+        IndexKind::None => None
+    };
+    text.unwrap_or_else(|| format!("{:?}@{}", kind, index))
+}
+
+// The lines only in `old` and only in `new`, from a longest common subsequence of the two
+fn edits(old: &[(u32, String)], new: &[(u32, String)]) -> Vec<InstructionEdit> {
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a.1 == b.1).count();
+    let suffix = old[prefix ..].iter().rev().zip(new[prefix ..].iter().rev()).take_while(|(a, b)| a.1 == b.1).count();
+    let old = &old[prefix .. old.len() - suffix];
+    let new = &new[prefix .. new.len() - suffix];
+    let removed = |line: &(u32, String)| InstructionEdit { old_offset: Some(line.0), new_offset: None,
+                                                          instruction: line.1.clone() };
+    let added = |line: &(u32, String)| InstructionEdit { old_offset: None, new_offset: Some(line.0),
+                                                        instruction: line.1.clone() };
+
+    if (old.len() + 1).saturating_mul(new.len() + 1) > MAX_LCS_CELLS {
+        return old.iter().map(removed).chain(new.iter().map(added)).collect();
+    }
+    // lengths[i][j] is the length of the longest common subsequence of old[i ..] and new[j ..]
+    let width = new.len() + 1;
+    let mut lengths = vec!(0u32; (old.len() + 1) * width);
+    for i in (0 .. old.len()).rev() {
+        for j in (0 .. new.len()).rev() {
+            lengths[i * width + j] = if old[i].1 == new[j].1 {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut edits = vec!();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i].1 == new[j].1 {
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1]) {
+            edits.push(removed(&old[i]));
+            i += 1;
+        } else {
+            edits.push(added(&new[j]));
+            j += 1;
+        }
+    }
+    edits
+}
+
+impl DexDiff {
+    pub fn is_empty(&self) -> bool {
+        self.classes.is_empty()
+    }
+
+    // `2 classes added, 1 removed, 3 changed`
+    pub fn summary(&self) -> String {
+        let count = |change: fn(&Change) -> bool| self.classes.iter().filter(|class| change(&class.change)).count();
+        format!("{} classes added, {} removed, {} changed", count(|c| *c == Change::Added),
+                count(|c| *c == Change::Removed), count(|c| matches!(c, Change::Changed(_))))
+    }
+}
+
+// The summary, then a line for each class that differs and under it a line for each difference:
+//
+//     ~ class Lcom/foo/Main;
+//         access flags: public -> public final
+//         ~ method run()V
+//             - 0003: const/4 r0, #1
+//             + 0003: const/4 r0, #2
+impl fmt::Display for DexDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.summary())?;
+        for class in &self.classes {
+            write_change(f, "", "class", &class.class_type, &class.change)?;
+            for field in &class.fields {
+                write_change(f, "    ", "field", &field.name, &field.change)?;
+            }
+            for method in &class.methods {
+                write_change(f, "    ", "method", &method.name, &method.change)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_change(f: &mut fmt::Formatter, indent: &str, kind: &str, name: &str, change: &Change) -> fmt::Result {
+    let differences = match change {
+        Change::Added => return writeln!(f, "{}+ {} {}", indent, kind, name),
+        Change::Removed => return writeln!(f, "{}- {} {}", indent, kind, name),
+        Change::Changed(differences) => differences
+    };
+    writeln!(f, "{}~ {} {}", indent, kind, name)?;
+    let option = |s: &Option<String>| s.clone().unwrap_or_else(|| "none".to_string());
+    for difference in differences {
+        match difference {
+            Difference::AccessFlags { old, new } =>
+                writeln!(f, "{}    access flags: {} -> {}", indent, flag_names(old), flag_names(new))?,
+            Difference::Superclass { old, new } =>
+                writeln!(f, "{}    superclass: {} -> {}", indent, option(old), option(new))?,
+            Difference::Interfaces { old, new } =>
+                writeln!(f, "{}    interfaces: [{}] -> [{}]", indent, old.join(", "), new.join(", "))?,
+            Difference::SourceFile { old, new } =>
+                writeln!(f, "{}    source file: {} -> {}", indent, option(old), option(new))?,
+            Difference::StaticValue { old, new } =>
+                writeln!(f, "{}    static value: {} -> {}", indent, option(old), option(new))?,
+            Difference::Annotations { removed, added } => {
+                for annotation in removed {
+                    writeln!(f, "{}    - annotation {}", indent, annotation)?;
+                }
+                for annotation in added {
+                    writeln!(f, "{}    + annotation {}", indent, annotation)?;
+                }
+            },
+            Difference::Instructions(edits) => for edit in edits {
+                match (edit.old_offset, edit.new_offset) {
+                    (Some(offset), _) => writeln!(f, "{}    - {:04x}: {}", indent, offset, edit.instruction)?,
+                    (_, Some(offset)) => writeln!(f, "{}    + {:04x}: {}", indent, offset, edit.instruction)?,
+                    (None, None) => {}
+                }
+            },
+            Difference::Handlers { removed, added } => {
+                for handler in removed {
+                    writeln!(f, "{}    - try {}", indent, handler)?;
+                }
+                for handler in added {
+                    writeln!(f, "{}    + try {}", indent, handler)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// `public static final`, or `none`
+fn flag_names(flags: &[AccessFlag]) -> String {
+    if flags.is_empty() {
+        return "none".to_string();
+    }
+    flags.iter()
+        .map(|flag| format!("{:?}", flag).trim_start_matches("ACC_").to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    // LMain; with a static field and a method run, built by `build` and given `insns` as the code of run
+    fn file(build: impl FnOnce(&mut DexBuilder, &mut ClassDefinition),
+            insns: impl Fn(&DexFileData) -> Vec<u16>) -> DexFile {
+        let mut b = DexBuilder::new(35);
+        let field = b.field("LMain;", "count", "I");
+        let run = b.method("LMain;", "run", "V", &["I"]);
+        let mut class = ClassDefinition {
+            class_type: b.type_("LMain;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(EncodedField { field, access_flags: vec!(AccessFlag::ACC_STATIC) }),
+                instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod {
+                    method: run,
+                    access_flags: vec!(AccessFlag::ACC_STATIC),
+                    code: Some(Code { registers_size: 4, ins_size: 1, outs_size: 0, debug_info: None, insns: vec!(),
+                                      tries: vec!(), handlers: vec!() }),
+                    system_annotations: Default::default()
+                }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(EncodedValue::Int(1)),
+            system_annotations: Default::default()
+        };
+        build(&mut b, &mut class);
+        b.class(class);
+        let mut file = b.build().unwrap();
+        let insns = insns(&file.file_data);
+        file.classes.iter_mut().find(|class| *class.class_type == "LMain;").unwrap()
+            .class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap().insns = insns;
+        file
+    }
+
+    fn string(data: &DexFileData, s: &str) -> u16 {
+        data.string_data.iter().position(|string| **string == s).unwrap() as u16
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = file(|b, _| { b.string("hello"); }, |fd| vec!(
+            0x0012,                     // const/4 v0, #0
+            0x0038, 0x0004,             // if-eqz v0, +4
+            0x011A, string(fd, "hello"), // const-string v1, "hello"
+            0x000E                      // return-void
+        ));
+        // the same code with the registers renumbered, an instruction added and a string added before "hello"
+        let same = file(|b, _| { b.string("hello"); b.string("aaa"); }, |fd| vec!(
+            0x0212,                     // const/4 v2, #0
+            0x0238, 0x0004,             // if-eqz v2, +4
+            0x001A, string(fd, "hello"), // const-string v0, "hello"
+            0x000E                      // return-void
+        ));
+        assert!(diff(&old, &same).is_empty());
+
+        let new = file(|b, class| {
+            b.string("hello");
+            class.access_flags.push(AccessFlag::ACC_FINAL);
+            class.static_values = vec!(EncodedValue::Int(2));
+            let other = b.type_("LOther;");
+            b.class(ClassDefinition { class_type: other, access_flags: vec!(), superclass: None, interfaces: vec!(),
+                                      source_file_name: None, annotations: None, class_data: None,
+                                      static_values: vec!(), system_annotations: Default::default() });
+        }, |fd| vec!(
+            0x0012,                     // const/4 v0, #0
+            0x0038, 0x0005,             // if-eqz v0, +5
+            0x0000,                     // nop
+            0x011A, string(fd, "hello"), // const-string v1, "hello"
+            0x000E                      // return-void
+        ));
+        let changes = diff(&old, &new);
+        assert_eq!(changes.classes, vec!(
+            ClassDiff {
+                class_type: "LMain;".to_string(),
+                change: Change::Changed(vec!(Difference::AccessFlags {
+                    old: vec!(AccessFlag::ACC_PUBLIC),
+                    new: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_FINAL)
+                })),
+                fields: vec!(MemberDiff {
+                    name: "count:I".to_string(),
+                    change: Change::Changed(vec!(Difference::StaticValue {
+                        old: Some("1".to_string()),
+                        new: Some("2".to_string())
+                    }))
+                }),
+                methods: vec!(MemberDiff {
+                    name: "run(I)V".to_string(),
+                    change: Change::Changed(vec!(Difference::Instructions(vec!(
+                        InstructionEdit { old_offset: Some(1), new_offset: None,
+                                          instruction: "if-eqz r0, @+2".to_string() },
+                        InstructionEdit { old_offset: None, new_offset: Some(1),
+                                          instruction: "if-eqz r0, @+3".to_string() },
+                        InstructionEdit { old_offset: None, new_offset: Some(3), instruction: "nop".to_string() }
+                    ))))
+                })
+            },
+            ClassDiff { class_type: "LOther;".to_string(), change: Change::Added, fields: vec!(), methods: vec!() }
+        ));
+
+        assert_eq!(changes.to_string(), "\
+1 classes added, 0 removed, 1 changed
+~ class LMain;
+    access flags: public -> public final
+    ~ field count:I
+        static value: 1 -> 2
+    ~ method run(I)V
+        - 0001: if-eqz r0, @+2
+        + 0001: if-eqz r0, @+3
+        + 0003: nop
++ class LOther;
+");
+    }
+
+    fn lines(lines: &[&str]) -> Vec<(u32, String)> {
+        lines.iter().enumerate().map(|(i, line)| (i as u32, line.to_string())).collect()
+    }
+
+    fn instructions(edits: &[InstructionEdit]) -> Vec<(Option<u32>, Option<u32>, &str)> {
+        edits.iter().map(|edit| (edit.old_offset, edit.new_offset, edit.instruction.as_str())).collect()
+    }
+
+    #[test]
+    fn test_edits() {
+        let script = edits(&lines(&["a", "b", "c", "d"]), &lines(&["a", "x", "c", "d", "e"]));
+        assert_eq!(instructions(&script), vec!((Some(1), None, "b"), (None, Some(1), "x"), (None, Some(4), "e")));
+        // a line moved from the front to the back is removed and added again
+        let script = edits(&lines(&["a", "b", "c"]), &lines(&["b", "c", "a"]));
+        assert_eq!(instructions(&script), vec!((Some(0), None, "a"), (None, Some(2), "a")));
+        assert!(edits(&lines(&["a", "b"]), &lines(&["a", "b"])).is_empty());
+
+        // too many lines to line up: everything between the common start and end is replaced, even lines in common
+        // 2101 * 2101 cells is over MAX_LCS_CELLS
+        let many = |prefix: &str| (0 .. 2100_u32)
+            .map(|i| (i, if i == 1000 { "same".to_string() } else { format!("{} {}", prefix, i) }))
+            .collect::<Vec<_>>();
+        let script = edits(&many("old"), &many("new"));
+        assert_eq!(script.len(), 4200);
+        assert_eq!(script.iter().filter(|edit| edit.instruction == "same").count(), 2);
+    }
+
+    #[test]
+    fn test_normalized() {
+        let text = |insns: Vec<u16>| {
+            let file = file(|_, _| {}, move |_| insns.clone());
+            let code = file.classes[0].class_data.as_ref().unwrap().direct_methods[0].code.as_ref();
+            Normalized::new(&file, code).instructions.into_iter().map(|(_, text)| text).collect::<Vec<_>>()
+        };
+        // registers are numbered by first use: const/4 v3, #1; add-int v1, v3, v3, and the same with v0 and v2
+        let doubled = vec!("const/4 r0, #1", "add-int r1, r0, r0", "return-void");
+        assert_eq!(text(vec!(0x1312, 0x0190, 0x0303, 0x000E)), doubled);
+        assert_eq!(text(vec!(0x1012, 0x0290, 0x0000, 0x000E)), doubled);
+        // add-int v2, v0, v2 reads the register it writes
+        assert_eq!(text(vec!(0x1012, 0x0290, 0x0200, 0x000E)),
+                   vec!("const/4 r0, #1", "add-int r1, r0, r1", "return-void"));
+        // code that doesn't decode is compared unit by unit
+        assert_eq!(text(vec!(0x003E, 0x000E)), vec!("003e", "000e"));
+    }
+
+    #[test]
+    fn test_targets() {
+        let old = file(|_, _| {}, |_| vec!(
+            0x0012,                                         // const/4 v0, #0
+            0x0038, 0x0005,                                 // if-eqz v0, +5
+            0x002B, 0x0005, 0x0000,                         // packed-switch v0, +5
+            0x000E,                                         // return-void
+            0x0000,                                         // nop, to align the payload
+            0x0100, 0x0001, 0x0000, 0x0000, 0x0003, 0x0000  // packed-switch-payload 0: +3
+        ));
+        // a nop at the start moves everything along, so that the payload is aligned without the other nop
+        let new = file(|_, _| {}, |_| vec!(
+            0x0000,                                         // nop
+            0x0012,                                         // const/4 v0, #0
+            0x0038, 0x0005,                                 // if-eqz v0, +5
+            0x002B, 0x0004, 0x0000,                         // packed-switch v0, +4
+            0x000E,                                         // return-void
+            0x0100, 0x0001, 0x0000, 0x0000, 0x0003, 0x0000  // packed-switch-payload 0: +3
+        ));
+        let changes = diff(&old, &new);
+        let edits = match changes.classes[0].methods[0].change {
+            Change::Changed(ref differences) => match differences[..] {
+                [Difference::Instructions(ref edits)] => edits,
+                ref other => panic!("{:?}", other)
+            },
+            ref other => panic!("{:?}", other)
+        };
+        assert_eq!(instructions(edits), vec!((None, Some(0), "nop")));
+
+        // the undecodable unit changed, the one after it didn't
+        let old = file(|_, _| {}, |_| vec!(0x003E, 0x000E));
+        let new = file(|_, _| {}, |_| vec!(0x003F, 0x000E));
+        assert_eq!(diff(&old, &new).to_string(), "\
+0 classes added, 0 removed, 1 changed
+~ class LMain;
+    ~ method run(I)V
+        - 0000: 003e
+        + 0000: 003f
+");
+    }
+
+    fn tag(b: &mut DexBuilder, value: i32) -> ClassAnnotation {
+        let value = AnnotationElement { name: b.string("value"), value: EncodedValue::Int(value) };
+        ClassAnnotation { visibility: Visibility::RUNTIME, type_: b.type_("LTag;"), elements: vec!(value) }
+    }
+
+    #[test]
+    fn test_member_differences() {
+        let old = file(|b, class| {
+            class.annotations = Some(Annotations { class_annotations: vec!(tag(b, 1)), field_annotations: vec!(),
+                                                   method_annotations: vec!(), parameter_annotations: vec!() });
+            let code = class.class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap();
+            code.tries = vec!(TryItem { start_addr: 0, insn_count: 1,
+                                        handler: EncodedCatchHandler { handlers: vec!(), catch_all_addr: Some(1) } });
+        }, |_| vec!(0x0012, 0x000E));
+        // the annotation's value changed, the method is annotated and has no try block, and the field is past the
+        // end of the static values
+        let new = file(|b, class| {
+            let run = class.class_data.as_ref().unwrap().direct_methods[0].method.clone();
+            let item = |annotation: ClassAnnotation| AnnotationItem { visibility: annotation.visibility,
+                                                                      type_: annotation.type_,
+                                                                      annotations: annotation.elements };
+            class.annotations = Some(Annotations {
+                class_annotations: vec!(tag(b, 2)),
+                field_annotations: vec!(),
+                method_annotations: vec!(MethodAnnotation { method: run.clone(), annotations: vec!(item(tag(b, 3))) }),
+                parameter_annotations: vec!(ParameterAnnotation {
+                    method: run,
+                    annotations: vec!(vec!(item(tag(b, 4))))
+                })
+            });
+            class.static_values = vec!();
+        }, |_| vec!(0x0012, 0x000E));
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.classes[0].change, Change::Changed(vec!(Difference::Annotations {
+            removed: vec!("runtime @LTag;(value=1)".to_string()),
+            added: vec!("runtime @LTag;(value=2)".to_string())
+        })));
+        assert_eq!(changes.classes[0].fields[0].change, Change::Changed(vec!(Difference::StaticValue {
+            old: Some("1".to_string()),
+            new: Some("default".to_string())
+        })));
+        assert_eq!(changes.classes[0].methods[0].change, Change::Changed(vec!(
+            Difference::Annotations {
+                removed: vec!(),
+                added: vec!("runtime @LTag;(value=3)".to_string(), "parameter 0: runtime @LTag;(value=4)".to_string())
+            },
+            Difference::Handlers { removed: vec!("0 .. 1: <any> -> 1".to_string()), added: vec!() }
+        )));
+        assert_eq!(changes.to_string(), "\
+0 classes added, 0 removed, 1 changed
+~ class LMain;
+    - annotation runtime @LTag;(value=1)
+    + annotation runtime @LTag;(value=2)
+    ~ field count:I
+        static value: 1 -> default
+    ~ method run(I)V
+        + annotation runtime @LTag;(value=3)
+        + annotation parameter 0: runtime @LTag;(value=4)
+        - try 0 .. 1: <any> -> 1
+");
+
+        // the other way round, with a class only in the old file
+        let removed = file(|b, _| {
+            let gone = b.type_("LGone;");
+            b.class(ClassDefinition { class_type: gone, access_flags: vec!(), superclass: None, interfaces: vec!(),
+                                      source_file_name: None, annotations: None, class_data: None,
+                                      static_values: vec!(), system_annotations: Default::default() });
+        }, |_| vec!(0x0012, 0x000E));
+        assert_eq!(diff(&removed, &new).summary(), "0 classes added, 1 removed, 1 changed");
+        assert!(diff(&removed, &new).to_string().contains("\n- class LGone;\n"));
+    }
+}
@@ -1,3 +1,6 @@
+// failure's derive expands to impls inside an anonymous const
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 
 #[derive(Debug, Fail, Clone)]
@@ -10,8 +13,29 @@ pub enum DexParserError {
     ParsingFailed {
         reason: String
     },
+    #[fail(display = "could not write file: {}", reason)]
+    WritingFailed {
+        reason: String
+    },
+    #[fail(display = "invalid bytecode at offset {}: {}", offset, reason)]
+    InvalidBytecode {
+        // in 16-bit code units from the start of the method's insns
+        offset: u32,
+        reason: String
+    },
     #[fail(display = "could not decode string to UTF8: may be malformed")]
-    EncodingError
+    EncodingError,
+    #[fail(display = "{} limit exceeded: requested {} but at most {} allowed", limit, requested, allowed)]
+    LimitExceeded {
+        limit: &'static str,
+        requested: u64,
+        allowed: u64
+    },
+    #[fail(display = "checksum mismatch: header has 0x{:08X} but file contents give 0x{:08X}", expected, actual)]
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32
+    }
 }
 
 impl<E: std::fmt::Debug + Clone> From<nom::Err<E>> for DexParserError {
@@ -76,7 +100,7 @@ impl From<::std::string::FromUtf8Error> for DexParserError {
 }
 
 impl From<DexParserError> for nom::Err<&[u8]> {
-    fn from(e: DexParserError) -> Self {
+    fn from(_e: DexParserError) -> Self {
         // TODO - work out how to build a proper error here, or avoid converting back and forth
         nom::Err::Failure(nom::Context::Code(b"Failed to parse DEX file", nom::ErrorKind::Custom(0)))
     }
@@ -0,0 +1,604 @@
+// Support for the fuzz targets under fuzz/: entry points into individual parsers, and a generator
+// for structurally valid DEX files so fuzzing gets past the header checks and into the deeper parsers
+
+use std::rc::Rc;
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use crate::error::DexParserError;
+use crate::result_types::*;
+use crate::builder::DexBuilder;
+
+// Parse a single encoded_value, resolving indexes against the pools from sample_file_data()
+pub fn parse_encoded_value(data: &[u8]) -> Result<EncodedValue, DexParserError> {
+    crate::parser::parse_encoded_value(data, &sample_file_data())
+}
+
+// Parse a code_item (and any debug info or handlers it points to) starting at the beginning of the data,
+// with offsets in it treated as relative to the start of the data
+pub fn parse_code_item(data: &[u8]) -> Result<Code, DexParserError> {
+    crate::parser::parse_code_item(data, &sample_file_data())
+}
+
+// A handful of entries in each pool, so that small indexes in fuzzed input resolve
+pub fn sample_file_data() -> DexFileData {
+    let string_data = ["I", "LFuzz;", "Ljava/lang/Object;", "V", "VI", "field", "method"].iter()
+        .map(|s| Rc::new(s.to_string()))
+        .collect::<Vec<_>>();
+    let type_identifiers = vec!(string_data[0].clone(), string_data[1].clone(),
+                                string_data[2].clone(), string_data[3].clone());
+    let prototypes = vec!(Rc::new(Prototype {
+        shorty: string_data[4].clone(),
+        return_type: type_identifiers[3].clone(),
+        parameters: vec!(type_identifiers[0].clone())
+    }));
+    let fields = vec!(Rc::new(Field {
+        definer: type_identifiers[1].clone(),
+        type_: type_identifiers[0].clone(),
+        name: string_data[5].clone()
+    }));
+    let methods = vec!(Rc::new(Method {
+        definer: type_identifiers[1].clone(),
+        prototype: prototypes[0].clone(),
+        name: string_data[6].clone()
+    }));
+
+    DexFileData { string_data, type_identifiers, prototypes, fields, methods, method_handles: vec!(), call_sites: vec!() }
+}
+
+// Keep generated files small: a fuzzer gets more out of many small files than a few huge ones
+const MAX_CLASSES: usize = 8;
+const MAX_MEMBERS: usize = 6;
+const MAX_INSNS: usize = 64;
+const MAX_TRIES: usize = 4;
+const MAX_ELEMENTS: usize = 4;
+const MAX_VALUE_DEPTH: usize = 2;
+
+const PRIMITIVES: [&str; 9] = ["Z", "B", "S", "C", "I", "J", "F", "D", "Ljava/lang/Object;"];
+
+// A DEX file described in terms of names rather than indexes, so that any value generated from
+// arbitrary input can be turned into a valid file
+#[derive(Debug, Clone)]
+pub struct GeneratedDex {
+    pub classes: Vec<GeneratedClass>
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedClass {
+    pub name: String,
+    pub superclass: String,
+    pub access_flags: u32,
+    pub interfaces: Vec<String>,
+    pub source_file: Option<String>,
+    pub static_fields: Vec<GeneratedField>,
+    pub instance_fields: Vec<GeneratedField>,
+    pub direct_methods: Vec<GeneratedMethod>,
+    pub virtual_methods: Vec<GeneratedMethod>,
+    pub static_values: Vec<GeneratedValue>,
+    pub annotations: Vec<GeneratedAnnotation>
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedField {
+    pub name: String,
+    pub type_: String,
+    pub access_flags: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedMethod {
+    pub name: String,
+    pub return_type: String,
+    pub parameters: Vec<String>,
+    pub access_flags: u32,
+    pub code: Option<GeneratedCode>
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedCode {
+    pub registers_size: u16,
+    pub ins_size: u16,
+    pub outs_size: u16,
+    pub insns: Vec<u16>,
+    pub tries: Vec<GeneratedTry>,
+    pub debug_info: Option<GeneratedDebugInfo>
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedTry {
+    pub start_addr: u32,
+    pub insn_count: u16,
+    pub handlers: Vec<(String, u32)>,
+    pub catch_all_addr: Option<u32>
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedDebugInfo {
+    pub line_start: u32,
+    pub ops: Vec<GeneratedDebugOp>
+}
+
+#[derive(Debug, Clone)]
+pub enum GeneratedDebugOp {
+    AdvancePc(u32),
+    AdvanceLine(i32),
+    StartLocal { register: u32, name: String, type_: String },
+    EndLocal(u32),
+    RestartLocal(u32),
+    PrologueEnd,
+    EpilogueBegin,
+    Special(u8)
+}
+
+#[derive(Debug, Clone)]
+pub enum GeneratedValue {
+    Byte(u8),
+    Short(i16),
+    Char(u16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Type(String),
+    Array(Vec<GeneratedValue>),
+    Null,
+    Boolean(bool)
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedAnnotation {
+    pub visibility: u8,
+    pub type_: String,
+    pub elements: Vec<(String, GeneratedValue)>
+}
+
+impl<'a> Arbitrary<'a> for GeneratedDex {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let count = u.int_in_range(0 ..= MAX_CLASSES)?;
+        let names = (0 .. count).map(|i| format!("Lfuzz/C{};", i)).collect::<Vec<_>>();
+
+        let mut classes = Vec::with_capacity(count);
+        for (i, name) in names.iter().enumerate() {
+            // superclasses always come earlier in the list, as the format requires
+            let superclass = if i > 0 && u.arbitrary()? {
+                names[u.int_in_range(0 ..= i - 1)?].clone()
+            } else {
+                "Ljava/lang/Object;".to_string()
+            };
+
+            let mut interfaces = vec!();
+            for other in &names {
+                if other != name && u.ratio(1, 4)? {
+                    interfaces.push(other.clone());
+                }
+            }
+
+            let source_file = if u.arbitrary()? { Some(format!("C{}.java", i)) } else { None };
+
+            let static_fields = arbitrary_fields(u, "s", &names)?;
+            let instance_fields = arbitrary_fields(u, "f", &names)?;
+            let direct_methods = arbitrary_methods(u, "d", &names)?;
+            let virtual_methods = arbitrary_methods(u, "v", &names)?;
+
+            let mut static_values = vec!();
+            for _ in 0 .. u.int_in_range(0 ..= static_fields.len())? {
+                static_values.push(arbitrary_value(u, &names, 0)?);
+            }
+
+            let mut annotations = vec!();
+            for _ in 0 .. u.int_in_range(0 ..= 2)? {
+                annotations.push(arbitrary_annotation(u, &names)?);
+            }
+            // only one annotation of each type is allowed per set
+            annotations.sort_by(|a, b| a.type_.cmp(&b.type_));
+            annotations.dedup_by(|a, b| a.type_ == b.type_);
+
+            classes.push(GeneratedClass {
+                name: name.clone(),
+                superclass,
+                access_flags: u.arbitrary::<u32>()? & 0x7611,
+                interfaces,
+                source_file,
+                static_fields,
+                instance_fields,
+                direct_methods,
+                virtual_methods,
+                static_values,
+                annotations
+            });
+        }
+
+        Ok(GeneratedDex { classes })
+    }
+}
+
+fn arbitrary_type(u: &mut Unstructured, names: &[String]) -> arbitrary::Result<String> {
+    let type_ = if !names.is_empty() && u.arbitrary()? {
+        u.choose(names)?.clone()
+    } else {
+        u.choose(&PRIMITIVES)?.to_string()
+    };
+
+    Ok(if u.ratio(1, 8)? { format!("[{}", type_) } else { type_ })
+}
+
+fn arbitrary_fields(u: &mut Unstructured, prefix: &str, names: &[String]) -> arbitrary::Result<Vec<GeneratedField>> {
+    let mut fields = vec!();
+    for i in 0 .. u.int_in_range(0 ..= MAX_MEMBERS)? {
+        fields.push(GeneratedField {
+            name: format!("{}{}", prefix, i),
+            type_: arbitrary_type(u, names)?,
+            access_flags: u.arbitrary::<u32>()? & 0x50DF
+        });
+    }
+    Ok(fields)
+}
+
+fn arbitrary_methods(u: &mut Unstructured, prefix: &str, names: &[String]) -> arbitrary::Result<Vec<GeneratedMethod>> {
+    let mut methods = vec!();
+    for i in 0 .. u.int_in_range(0 ..= MAX_MEMBERS)? {
+        let return_type = if u.arbitrary()? { "V".to_string() } else { arbitrary_type(u, names)? };
+
+        let mut parameters = vec!();
+        for _ in 0 .. u.int_in_range(0 ..= 3)? {
+            parameters.push(arbitrary_type(u, names)?);
+        }
+
+        let code = if u.ratio(3, 4)? { Some(arbitrary_code(u, names)?) } else { None };
+
+        methods.push(GeneratedMethod {
+            name: format!("{}{}", prefix, i),
+            return_type,
+            parameters,
+            access_flags: u.arbitrary::<u32>()? & 0x11DFF,
+            code
+        });
+    }
+    Ok(methods)
+}
+
+fn arbitrary_code(u: &mut Unstructured, names: &[String]) -> arbitrary::Result<GeneratedCode> {
+    let mut insns = vec!();
+    for _ in 0 .. u.int_in_range(1 ..= MAX_INSNS)? {
+        insns.push(u.arbitrary()?);
+    }
+    let len = insns.len() as u32;
+
+    // try items can't overlap and have to be in increasing address order
+    let mut tries = vec!();
+    let mut addr = 0;
+    for _ in 0 .. u.int_in_range(0 ..= MAX_TRIES)? {
+        if addr >= len {
+            break;
+        }
+        let start_addr = u.int_in_range(addr ..= len - 1)?;
+        let insn_count = u.int_in_range(1 ..= len - start_addr)?;
+        addr = start_addr + insn_count;
+
+        let mut handlers = vec!();
+        for _ in 0 .. u.int_in_range(0 ..= 2)? {
+            handlers.push((arbitrary_type(u, names)?, u.int_in_range(0 ..= len - 1)?));
+        }
+        let catch_all_addr = if handlers.is_empty() || u.arbitrary()? {
+            Some(u.int_in_range(0 ..= len - 1)?)
+        } else {
+            None
+        };
+
+        tries.push(GeneratedTry { start_addr, insn_count: insn_count as u16, handlers, catch_all_addr });
+    }
+
+    let debug_info = if u.arbitrary()? {
+        let mut ops = vec!();
+        for _ in 0 .. u.int_in_range(0 ..= 8)? {
+            ops.push(match u.int_in_range(0 ..= 7)? {
+                0 => GeneratedDebugOp::AdvancePc(u.int_in_range(0 ..= 16)?),
+                1 => GeneratedDebugOp::AdvanceLine(u.int_in_range(-8 ..= 8)?),
+                2 => GeneratedDebugOp::StartLocal {
+                    register: u.int_in_range(0 ..= 15)?,
+                    name: format!("l{}", u.int_in_range(0 ..= 3)?),
+                    type_: arbitrary_type(u, names)?
+                },
+                3 => GeneratedDebugOp::EndLocal(u.int_in_range(0 ..= 15)?),
+                4 => GeneratedDebugOp::RestartLocal(u.int_in_range(0 ..= 15)?),
+                5 => GeneratedDebugOp::PrologueEnd,
+                6 => GeneratedDebugOp::EpilogueBegin,
+                _ => GeneratedDebugOp::Special(u.int_in_range(0x0A ..= 0xFF)?)
+            });
+        }
+        Some(GeneratedDebugInfo { line_start: u.int_in_range(1 ..= 1000)?, ops })
+    } else {
+        None
+    };
+
+    Ok(GeneratedCode {
+        registers_size: u.int_in_range(0 ..= 16)?,
+        ins_size: u.int_in_range(0 ..= 4)?,
+        outs_size: u.int_in_range(0 ..= 4)?,
+        insns,
+        tries,
+        debug_info
+    })
+}
+
+fn arbitrary_value(u: &mut Unstructured, names: &[String], depth: usize) -> arbitrary::Result<GeneratedValue> {
+    let max = if depth < MAX_VALUE_DEPTH { 11 } else { 10 };
+    Ok(match u.int_in_range(0 ..= max)? {
+        0 => GeneratedValue::Byte(u.arbitrary()?),
+        1 => GeneratedValue::Short(u.arbitrary()?),
+        2 => GeneratedValue::Char(u.arbitrary()?),
+        3 => GeneratedValue::Int(u.arbitrary()?),
+        4 => GeneratedValue::Long(u.arbitrary()?),
+        5 => GeneratedValue::Float(u.arbitrary()?),
+        6 => GeneratedValue::Double(u.arbitrary()?),
+        7 => {
+            // printable ASCII only, which has the same encoding in MUTF-8 as UTF-8
+            let len = u.int_in_range(0 ..= 16)?;
+            let mut s = String::with_capacity(len);
+            for _ in 0 .. len {
+                s.push(char::from(u.int_in_range(0x20_u8 ..= 0x7E)?));
+            }
+            GeneratedValue::String(s)
+        },
+        8 => GeneratedValue::Type(arbitrary_type(u, names)?),
+        9 => GeneratedValue::Null,
+        10 => GeneratedValue::Boolean(u.arbitrary()?),
+        _ => {
+            let mut values = vec!();
+            for _ in 0 .. u.int_in_range(0 ..= MAX_ELEMENTS)? {
+                values.push(arbitrary_value(u, names, depth + 1)?);
+            }
+            GeneratedValue::Array(values)
+        }
+    })
+}
+
+fn arbitrary_annotation(u: &mut Unstructured, names: &[String]) -> arbitrary::Result<GeneratedAnnotation> {
+    let mut elements = vec!();
+    for i in 0 .. u.int_in_range(0 ..= MAX_ELEMENTS)? {
+        elements.push((format!("e{}", i), arbitrary_value(u, names, 0)?));
+    }
+
+    Ok(GeneratedAnnotation {
+        visibility: u.int_in_range(0 ..= 2)?,
+        type_: format!("Lfuzz/A{};", u.int_in_range(0 ..= 3)?),
+        elements
+    })
+}
+
+impl GeneratedDex {
+    // The file the generated description stands for, with its pools sorted and its header filled in
+    pub fn to_dex_file(&self) -> Result<DexFile, DexParserError> {
+        let mut builder = DexBuilder::new(35);
+
+        for class in &self.classes {
+            let name = &class.name;
+
+            let mut class_data = ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(),
+                virtual_methods: vec!()
+            };
+            for (fields, generated) in [(&mut class_data.static_fields, &class.static_fields),
+                                        (&mut class_data.instance_fields, &class.instance_fields)] {
+                for field in generated {
+                    fields.push(EncodedField {
+                        field: builder.field(name, &field.name, &field.type_),
+                        access_flags: access_flags(field.access_flags, false)
+                    });
+                }
+            }
+            for (methods, generated) in [(&mut class_data.direct_methods, &class.direct_methods),
+                                         (&mut class_data.virtual_methods, &class.virtual_methods)] {
+                for method in generated {
+                    let parameters = method.parameters.iter().map(String::as_str).collect::<Vec<_>>();
+                    methods.push(EncodedMethod {
+                        method: builder.method(name, &method.name, &method.return_type, &parameters),
+                        access_flags: access_flags(method.access_flags, true),
+                        code: method.code.as_ref().map(|code| code.to_code(&mut builder)),
+                        system_annotations: Default::default()
+                    });
+                }
+            }
+
+            let annotations = if class.annotations.is_empty() {
+                None
+            } else {
+                Some(Annotations {
+                    class_annotations: class.annotations.iter()
+                        .map(|annotation| ClassAnnotation {
+                            visibility: match annotation.visibility {
+                                0 => Visibility::BUILD,
+                                1 => Visibility::RUNTIME,
+                                _ => Visibility::SYSTEM
+                            },
+                            type_: builder.type_(&annotation.type_),
+                            elements: annotation.elements.iter()
+                                .map(|(name, value)| AnnotationElement {
+                                    name: builder.string(name),
+                                    value: value.to_value(&mut builder)
+                                }).collect()
+                        }).collect(),
+                    field_annotations: vec!(),
+                    method_annotations: vec!(),
+                    parameter_annotations: vec!()
+                })
+            };
+
+            let class = ClassDefinition {
+                class_type: builder.type_(name),
+                access_flags: access_flags(class.access_flags, false),
+                superclass: Some(builder.type_(&class.superclass)),
+                interfaces: class.interfaces.iter().map(|interface| builder.type_(interface)).collect(),
+                source_file_name: class.source_file.as_ref().map(|source_file| builder.string(source_file)),
+                annotations,
+                class_data: Some(class_data),
+                static_values: class.static_values.iter().map(|value| value.to_value(&mut builder)).collect(),
+                system_annotations: Default::default()
+            };
+            builder.class(class);
+        }
+
+        builder.build()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_dex_file()
+            .and_then(|file| crate::write(&file))
+            .expect("generated file could not be written")
+    }
+}
+
+impl GeneratedCode {
+    fn to_code(&self, builder: &mut DexBuilder) -> Code {
+        let tries = self.tries.iter()
+            .map(|try_| TryItem {
+                start_addr: try_.start_addr,
+                insn_count: try_.insn_count,
+                handler: EncodedCatchHandler {
+                    handlers: try_.handlers.iter()
+                        .map(|(type_, addr)| EncodedTypeAddrPair { type_: builder.type_(type_), addr: *addr })
+                        .collect(),
+                    catch_all_addr: try_.catch_all_addr
+                }
+            }).collect::<Vec<_>>();
+
+        let debug_info = self.debug_info.as_ref().map(|debug_info| DebugInfo {
+            line_start: debug_info.line_start,
+            parameter_names: vec!(),
+            bytecode: debug_info.ops.iter()
+                .map(|op| match op {
+                    GeneratedDebugOp::AdvancePc(diff) => DebugItemBytecodes::DBG_ADVANCE_PC(*diff),
+                    GeneratedDebugOp::AdvanceLine(diff) => DebugItemBytecodes::DBG_ADVANCE_LINE(*diff),
+                    GeneratedDebugOp::StartLocal { register, name, type_ } => DebugItemBytecodes::DBG_START_LOCAL {
+                        register_num: *register,
+                        name: Some(builder.string(name)),
+                        type_: Some(builder.type_(type_))
+                    },
+                    GeneratedDebugOp::EndLocal(register) => DebugItemBytecodes::DBG_END_LOCAL(*register),
+                    GeneratedDebugOp::RestartLocal(register) => DebugItemBytecodes::DBG_RESTART_LOCAL(*register),
+                    GeneratedDebugOp::PrologueEnd => DebugItemBytecodes::DBG_SET_PROLOGUE_END,
+                    GeneratedDebugOp::EpilogueBegin => DebugItemBytecodes::DBG_SET_EPILOGUE_BEGIN,
+                    GeneratedDebugOp::Special(opcode) => DebugItemBytecodes::SPECIAL_OPCODE(*opcode)
+                }).collect()
+        });
+
+        Code {
+            registers_size: self.registers_size,
+            ins_size: self.ins_size,
+            outs_size: self.outs_size,
+            debug_info,
+            insns: self.insns.clone(),
+            tries,
+            handlers: vec!()
+        }
+    }
+}
+
+impl GeneratedValue {
+    fn to_value(&self, builder: &mut DexBuilder) -> EncodedValue {
+        match self {
+            GeneratedValue::Byte(v) => EncodedValue::Byte(*v),
+            GeneratedValue::Short(v) => EncodedValue::Short(*v),
+            GeneratedValue::Char(v) => EncodedValue::Char(*v),
+            GeneratedValue::Int(v) => EncodedValue::Int(*v),
+            GeneratedValue::Long(v) => EncodedValue::Long(*v),
+            GeneratedValue::Float(v) => EncodedValue::Float(*v),
+            GeneratedValue::Double(v) => EncodedValue::Double(*v),
+            GeneratedValue::String(v) => EncodedValue::String(builder.string(v)),
+            GeneratedValue::Type(v) => EncodedValue::Type(builder.type_(v)),
+            GeneratedValue::Array(values) => EncodedValue::Array(values.iter().map(|v| v.to_value(builder)).collect()),
+            GeneratedValue::Null => EncodedValue::Null,
+            GeneratedValue::Boolean(v) => EncodedValue::Boolean(*v)
+        }
+    }
+}
+
+// The flags set in a raw access_flags value; 0x40 and 0x80 mean different things for methods
+fn access_flags(bits: u32, method: bool) -> Vec<AccessFlag> {
+    (0 .. 18).map(|i| 1 << i)
+        .filter(|bit| bits & bit != 0)
+        .map(|bit| match bit {
+            0x01 => AccessFlag::ACC_PUBLIC,
+            0x02 => AccessFlag::ACC_PRIVATE,
+            0x04 => AccessFlag::ACC_PROTECTED,
+            0x08 => AccessFlag::ACC_STATIC,
+            0x10 => AccessFlag::ACC_FINAL,
+            0x20 => AccessFlag::ACC_SYNCHRONIZED,
+            0x40 if method => AccessFlag::ACC_BRIDGE,
+            0x40 => AccessFlag::ACC_VOLATILE,
+            0x80 if method => AccessFlag::ACC_VARARGS,
+            0x80 => AccessFlag::ACC_TRANSIENT,
+            0x100 => AccessFlag::ACC_NATIVE,
+            0x200 => AccessFlag::ACC_INTERFACE,
+            0x400 => AccessFlag::ACC_ABSTRACT,
+            0x800 => AccessFlag::ACC_STRICT,
+            0x1000 => AccessFlag::ACC_SYNTHETIC,
+            0x2000 => AccessFlag::ACC_ANNOTATION,
+            0x4000 => AccessFlag::ACC_ENUM,
+            0x8000 => AccessFlag::UNUSED,
+            0x10000 => AccessFlag::ACC_CONSTRUCTOR,
+            _ => AccessFlag::ACC_DECLARED_SYNCHRONIZED
+        }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_with_options, ParseOptions, RegionKind};
+
+    // deterministic bytes to feed the generator with, standing in for fuzzer input
+    fn pseudo_random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0 .. len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_generated_files_parse() {
+        for seed in 0 .. 64 {
+            let bytes = pseudo_random_bytes(seed, 4096);
+            let dex = GeneratedDex::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+            let file = dex.to_bytes();
+
+            let res = parse_with_options(&file, ParseOptions::new().verify_checksum(true)).unwrap();
+
+            // everything in the file should be accounted for by the map list
+            assert!(res.regions.iter().all(|region| region.kind != RegionKind::Unmapped), "seed {}", seed);
+            // and writing what was parsed should give back the same file
+            assert_eq!(crate::write(&res).unwrap(), file, "seed {}", seed);
+            assert_eq!(res.classes.len(), dex.classes.len());
+
+            for (parsed, generated) in res.classes.iter().zip(&dex.classes) {
+                assert_eq!(*parsed.class_type, generated.name);
+                assert_eq!(parsed.interfaces.len(), generated.interfaces.len());
+                assert_eq!(parsed.static_values.len(), generated.static_values.len());
+
+                let class_data = parsed.class_data.as_ref().unwrap();
+                let mut field_names = class_data.instance_fields.iter()
+                    .map(|field| field.field.name.to_string())
+                    .collect::<Vec<_>>();
+                field_names.sort();
+                let mut generated_names = generated.instance_fields.iter()
+                    .map(|field| field.name.clone())
+                    .collect::<Vec<_>>();
+                generated_names.sort();
+                assert_eq!(field_names, generated_names);
+
+                assert_eq!(class_data.virtual_methods.len(), generated.virtual_methods.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_encoded_value() {
+        // an int of 1 byte
+        assert_eq!(parse_encoded_value(&[0x04, 0x7F]).unwrap(), EncodedValue::Int(0x7F));
+        // a string index outside of the sample pool
+        assert!(parse_encoded_value(&[0x17, 0xFF]).is_err());
+    }
+}
@@ -0,0 +1,494 @@
+// The class hierarchy of one or more DEX files, and field and method resolution over it
+//
+// Classes refer to their superclass and interfaces by descriptor only. This links each descriptor to
+// the definition in the files, if there is one; types that aren't defined (framework and library classes)
+// are external, and any lookup that reaches one of them can't be answered from the files alone.
+//
+// Resolution follows the rules of the JVM specification (5.4.3.2 to 5.4.3.4), which ART implements:
+// a class's own members and its superclasses come first, then its superinterfaces, where a method
+// resolves to the single maximally-specific default method if there is one. java.lang.Object is rarely
+// defined, so its members are taken from what the core library declares.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use crate::result_types::*;
+
+pub struct ClassHierarchy<'a> {
+    classes: HashMap<&'a str, &'a ClassDefinition>,
+    // direct subclasses of each superclass
+    subclasses: HashMap<&'a str, Vec<&'a str>>,
+    // classes and interfaces that directly implement or extend each interface
+    implementors: HashMap<&'a str, Vec<&'a str>>,
+    types: Vec<&'a Rc<String>>
+}
+
+// The outcome of looking a member up
+#[derive(Debug, PartialEq)]
+pub enum Resolution<'a, T> {
+    // the member is defined in the files
+    Defined(&'a T),
+    // the lookup got to a class that isn't defined in the files, which may well declare the member
+    External(Rc<String>),
+    // nothing in the hierarchy declares the member
+    NotFound
+}
+
+impl<'a, T> Resolution<'a, T> {
+    pub fn defined(&self) -> Option<&'a T> {
+        match self {
+            Resolution::Defined(member) => Some(*member),
+            _ => None
+        }
+    }
+}
+
+impl<'a> ClassHierarchy<'a> {
+    pub fn new(file: &'a DexFile) -> Self {
+        ClassHierarchy::from_files(&[file])
+    }
+
+    // Where a class is defined in more than one file, the first definition is used
+    pub fn from_files(files: &[&'a DexFile]) -> Self {
+        let mut classes = HashMap::new();
+        for class in files.iter().flat_map(|file| file.classes.iter()) {
+            classes.entry(class.class_type.as_str()).or_insert(class);
+        }
+
+        let mut subclasses: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut implementors: HashMap<&str, Vec<&str>> = HashMap::new();
+        for class in files.iter().flat_map(|file| file.classes.iter()) {
+            // skip the definitions that were shadowed
+            if !std::ptr::eq(classes[class.class_type.as_str()], class) {
+                continue;
+            }
+            if let Some(ref superclass) = class.superclass {
+                subclasses.entry(superclass.as_str()).or_default().push(class.class_type.as_str());
+            }
+            for interface in &class.interfaces {
+                implementors.entry(interface.as_str()).or_default().push(class.class_type.as_str());
+            }
+        }
+
+        let types = files.iter().flat_map(|file| file.file_data.type_identifiers.iter()).collect();
+        ClassHierarchy { classes, subclasses, implementors, types }
+    }
+
+    pub fn class(&self, descriptor: &str) -> Option<&'a ClassDefinition> {
+        self.classes.get(descriptor).cloned()
+    }
+
+    pub fn is_external(&self, descriptor: &str) -> bool {
+        !self.classes.contains_key(descriptor)
+    }
+
+    pub fn is_interface(&self, descriptor: &str) -> bool {
+        self.class(descriptor).is_some_and(|class| class.access_flags.contains(&AccessFlag::ACC_INTERFACE))
+    }
+
+    // Every class type the files refer to without defining, including the element types of arrays, sorted
+    pub fn external_classes(&self) -> Vec<&'a str> {
+        let mut external = self.types.iter()
+            .map(|type_| type_.trim_start_matches('['))
+            .filter(|type_| type_.starts_with('L') && self.is_external(type_))
+            .collect::<Vec<_>>();
+        external.sort();
+        external.dedup();
+        external
+    }
+
+    // The superclass chain, nearest first. It ends at java.lang.Object or at the first external class
+    pub fn superclasses(&self, descriptor: &str) -> Vec<&'a str> {
+        let mut chain = vec!();
+        let mut current = self.class(descriptor).and_then(|class| class.superclass.as_ref());
+        while let Some(superclass) = current {
+            // guard against cycles in malformed files
+            if chain.contains(&superclass.as_str()) {
+                break;
+            }
+            chain.push(superclass.as_str());
+            current = self.class(superclass).and_then(|class| class.superclass.as_ref());
+        }
+        chain
+    }
+
+    // All superclasses and superinterfaces, nearest first
+    pub fn supertypes(&self, descriptor: &str) -> Vec<&'a str> {
+        let mut seen = HashSet::new();
+        let mut found = vec!();
+        let mut queue = VecDeque::new();
+        queue.push_back(descriptor);
+        while let Some(type_) = queue.pop_front() {
+            let class = match self.class(type_) {
+                Some(class) => class,
+                None => continue
+            };
+            for supertype in class.superclass.iter().chain(class.interfaces.iter()) {
+                if seen.insert(supertype.as_str()) {
+                    found.push(supertype.as_str());
+                    queue.push_back(supertype.as_str());
+                }
+            }
+        }
+        found
+    }
+
+    // Every interface implemented by the class, its superclasses and those interfaces in turn, nearest first
+    pub fn superinterfaces(&self, descriptor: &str) -> Vec<&'a str> {
+        let mut seen = HashSet::new();
+        let mut found = vec!();
+        let mut queue = VecDeque::new();
+        queue.push_back(descriptor);
+        while let Some(type_) = queue.pop_front() {
+            let class = match self.class(type_) {
+                Some(class) => class,
+                None => continue
+            };
+            if let Some(ref superclass) = class.superclass {
+                if seen.insert(superclass.as_str()) {
+                    queue.push_back(superclass.as_str());
+                }
+            }
+            for interface in &class.interfaces {
+                if seen.insert(interface.as_str()) {
+                    found.push(interface.as_str());
+                    queue.push_back(interface.as_str());
+                }
+            }
+        }
+        found
+    }
+
+    pub fn is_subtype(&self, descriptor: &str, supertype: &str) -> bool {
+        descriptor == supertype || self.supertypes(descriptor).contains(&supertype)
+    }
+
+    pub fn direct_subclasses(&self, descriptor: &str) -> &[&'a str] {
+        self.subclasses.get(descriptor).map(|s| s.as_slice()).unwrap_or(&[])
+    }
+
+    // Every class that extends this one, directly or not
+    pub fn subclasses(&self, descriptor: &str) -> Vec<&'a str> {
+        self.closure(descriptor, |type_| self.direct_subclasses(type_).iter())
+    }
+
+    // Every type below this one, by extension or implementation
+    pub fn subtypes(&self, descriptor: &str) -> Vec<&'a str> {
+        self.closure(descriptor, |type_| {
+            self.direct_subclasses(type_).iter()
+                .chain(self.implementors.get(type_).into_iter().flatten())
+        })
+    }
+
+    // The classes that implement an interface, whether directly, through a subinterface or through a superclass
+    pub fn implementors(&self, interface: &str) -> Vec<&'a str> {
+        self.subtypes(interface).into_iter().filter(|type_| !self.is_interface(type_)).collect()
+    }
+
+    fn closure<'b, F, I>(&'b self, descriptor: &str, next: F) -> Vec<&'a str>
+        where F: Fn(&str) -> I, I: Iterator<Item = &'b &'a str>, 'a: 'b {
+        let mut seen = HashSet::new();
+        let mut found = vec!();
+        let mut queue = VecDeque::new();
+        queue.push_back(descriptor);
+        while let Some(type_) = queue.pop_front() {
+            for &subtype in next(type_) {
+                if seen.insert(subtype) {
+                    found.push(subtype);
+                    queue.push_back(subtype);
+                }
+            }
+        }
+        found
+    }
+
+    // JVMS 5.4.3.2: the class itself, then its superinterfaces, then its superclass
+    pub fn resolve_field(&self, descriptor: &str, name: &str, type_: &str) -> Resolution<'a, EncodedField> {
+        self.resolve_field_in(descriptor, name, type_, &mut HashSet::new())
+    }
+
+    fn resolve_field_in(&self, descriptor: &str, name: &str, type_: &str,
+                        seen: &mut HashSet<&'a str>) -> Resolution<'a, EncodedField> {
+        let class = match self.classes.get_key_value(descriptor) {
+            Some((key, class)) if seen.insert(key) => *class,
+            Some(_) => return Resolution::NotFound,
+            // java.lang.Object has no fields
+            None if descriptor == OBJECT => return Resolution::NotFound,
+            None => return Resolution::External(Rc::new(descriptor.to_string()))
+        };
+
+        if let Some(ref class_data) = class.class_data {
+            let field = class_data.static_fields.iter().chain(class_data.instance_fields.iter())
+                .find(|f| *f.field.name == name && *f.field.type_ == type_);
+            if let Some(field) = field {
+                return Resolution::Defined(field);
+            }
+        }
+
+        let mut external = None;
+        for supertype in class.interfaces.iter().chain(class.superclass.iter()) {
+            match self.resolve_field_in(supertype, name, type_, seen) {
+                Resolution::Defined(field) => return Resolution::Defined(field),
+                Resolution::External(type_) => external = external.or(Some(type_)),
+                Resolution::NotFound => {}
+            }
+        }
+        external.map_or(Resolution::NotFound, Resolution::External)
+    }
+
+    // Resolves a method reference by the rules for its kind of class
+    pub fn resolve(&self, method: &Method) -> Resolution<'a, EncodedMethod> {
+        if self.is_interface(&method.definer) {
+            self.resolve_interface_method(&method.definer, &method.name, &method.prototype)
+        } else {
+            self.resolve_method(&method.definer, &method.name, &method.prototype)
+        }
+    }
+
+    // JVMS 5.4.3.3: the class and its superclasses, then the maximally-specific superinterface methods
+    pub fn resolve_method(&self, descriptor: &str, name: &str, prototype: &Prototype) -> Resolution<'a, EncodedMethod> {
+        let mut chain = vec!(descriptor);
+        chain.extend(self.superclasses(descriptor));
+        for type_ in &chain {
+            let class = match self.class(type_) {
+                Some(class) => class,
+                // what java.lang.Object declares is known without its definition
+                None if *type_ == OBJECT && !is_object_method(name, prototype) => break,
+                None => return Resolution::External(Rc::new(type_.to_string()))
+            };
+            if let Some(method) = declared_method(class, name, prototype) {
+                return Resolution::Defined(method);
+            }
+        }
+        self.resolve_in_interfaces(descriptor, name, prototype)
+    }
+
+    // JVMS 5.4.3.4: the interface, then the public instance methods of java.lang.Object, then the
+    // maximally-specific superinterface methods
+    pub fn resolve_interface_method(&self, descriptor: &str, name: &str, prototype: &Prototype) -> Resolution<'a, EncodedMethod> {
+        let class = match self.class(descriptor) {
+            Some(class) => class,
+            None => return Resolution::External(Rc::new(descriptor.to_string()))
+        };
+        if let Some(method) = declared_method(class, name, prototype) {
+            return Resolution::Defined(method);
+        }
+
+        match self.class(OBJECT) {
+            Some(object) => {
+                let method = declared_method(object, name, prototype)
+                    .filter(|m| m.access_flags.contains(&AccessFlag::ACC_PUBLIC) && !is_static(m));
+                if let Some(method) = method {
+                    return Resolution::Defined(method);
+                }
+            },
+            // java.lang.Object is only defined in the core library itself
+            None => if is_public_object_method(name, prototype) {
+                return Resolution::External(Rc::new(OBJECT.to_string()));
+            }
+        }
+
+        self.resolve_in_interfaces(descriptor, name, prototype)
+    }
+
+    fn resolve_in_interfaces(&self, descriptor: &str, name: &str, prototype: &Prototype) -> Resolution<'a, EncodedMethod> {
+        // candidates are the non-private instance methods of the superinterfaces
+        let mut candidates = vec!();
+        let mut external = None;
+        for interface in self.superinterfaces(descriptor) {
+            match self.class(interface) {
+                Some(class) => {
+                    let method = declared_method(class, name, prototype)
+                        .filter(|m| !m.access_flags.contains(&AccessFlag::ACC_PRIVATE) && !is_static(m));
+                    if let Some(method) = method {
+                        candidates.push((interface, method));
+                    }
+                },
+                None => external = external.or(Some(interface))
+            }
+        }
+
+        // keep those no other candidate overrides
+        let maximally_specific = candidates.iter()
+            .filter(|(interface, _)| !candidates.iter().any(|(other, _)| other != interface && self.is_subtype(other, interface)))
+            .collect::<Vec<_>>();
+        let defaults = maximally_specific.iter()
+            .filter(|(_, m)| !m.access_flags.contains(&AccessFlag::ACC_ABSTRACT))
+            .collect::<Vec<_>>();
+
+        if defaults.len() == 1 {
+            Resolution::Defined(defaults[0].1)
+        } else if let Some((_, method)) = maximally_specific.first() {
+            Resolution::Defined(method)
+        } else if let Some(external) = external {
+            Resolution::External(Rc::new(external.to_string()))
+        } else {
+            Resolution::NotFound
+        }
+    }
+
+    // Virtual methods in the subtypes of the method's class that override it
+    pub fn overrides(&self, method: &Method) -> Vec<&'a EncodedMethod> {
+        self.subtypes(&method.definer).into_iter()
+            .filter_map(|type_| self.class(type_))
+            .filter_map(|class| class.class_data.as_ref())
+            .filter_map(|class_data| class_data.virtual_methods.iter()
+                .find(|m| m.method.name == method.name && m.method.prototype == method.prototype))
+            .collect()
+    }
+}
+
+const OBJECT: &str = "Ljava/lang/Object;";
+
+fn declared_method<'a>(class: &'a ClassDefinition, name: &str, prototype: &Prototype) -> Option<&'a EncodedMethod> {
+    let class_data = class.class_data.as_ref()?;
+    class_data.direct_methods.iter().chain(class_data.virtual_methods.iter())
+        .find(|m| *m.method.name == name && *m.method.prototype == *prototype)
+}
+
+fn is_static(method: &EncodedMethod) -> bool {
+    method.access_flags.contains(&AccessFlag::ACC_STATIC)
+}
+
+// The instance methods of java.lang.Object, the protected clone() and finalize() included
+fn is_object_method(name: &str, prototype: &Prototype) -> bool {
+    is_public_object_method(name, prototype)
+        || matches!((name, prototype.return_type.as_str(), prototype.parameters.is_empty()),
+                    ("clone", "Ljava/lang/Object;", true) | ("finalize", "V", true))
+}
+
+// The public instance methods of java.lang.Object
+fn is_public_object_method(name: &str, prototype: &Prototype) -> bool {
+    let parameters = prototype.parameters.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+    matches!((name, prototype.return_type.as_str(), parameters.as_slice()),
+        ("equals", "Z", ["Ljava/lang/Object;"]) | ("hashCode", "I", []) | ("toString", "Ljava/lang/String;", [])
+            | ("getClass", "Ljava/lang/Class;", []) | ("notify", "V", []) | ("notifyAll", "V", [])
+            | ("wait", "V", []) | ("wait", "V", ["J"]) | ("wait", "V", ["J", "I"]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn class(b: &mut DexBuilder, name: &str, superclass: &str, interfaces: &[&str], interface: bool,
+             fields: Vec<EncodedField>, methods: Vec<EncodedMethod>) -> ClassDefinition {
+        let mut access_flags = vec!(AccessFlag::ACC_PUBLIC);
+        if interface {
+            access_flags.push(AccessFlag::ACC_INTERFACE);
+            access_flags.push(AccessFlag::ACC_ABSTRACT);
+        }
+        ClassDefinition {
+            class_type: b.type_(name),
+            access_flags,
+            superclass: Some(b.type_(superclass)),
+            interfaces: interfaces.iter().map(|i| b.type_(i)).collect(),
+            source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: fields,
+                direct_methods: vec!(),
+                virtual_methods: methods
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        }
+    }
+
+    fn method(method: Rc<Method>) -> EncodedMethod {
+        EncodedMethod {
+            method, access_flags: vec!(AccessFlag::ACC_PUBLIC), code: None, system_annotations: Default::default()
+        }
+    }
+
+    // LB; extends LA;, both with a default m(). LBase; implements LA;, LImpl; extends it and implements LB;,
+    // LSub; extends LImpl;. LActivity; extends a framework class
+    fn build_file() -> DexFile {
+        let mut b = DexBuilder::new(35);
+        let a_m = b.method("LA;", "m", "V", &[]);
+        let b_m = b.method("LB;", "m", "V", &[]);
+        let x = b.field("LBase;", "x", "I");
+        let classes = vec!(
+            class(&mut b, "LA;", "Ljava/lang/Object;", &[], true, vec!(), vec!(method(a_m))),
+            class(&mut b, "LB;", "Ljava/lang/Object;", &["LA;"], true, vec!(), vec!(method(b_m))),
+            class(&mut b, "LBase;", "Ljava/lang/Object;", &["LA;"], false,
+                  vec!(EncodedField { field: x, access_flags: vec!(AccessFlag::ACC_PUBLIC) }), vec!()),
+            class(&mut b, "LImpl;", "LBase;", &["LB;"], false, vec!(), vec!()),
+            class(&mut b, "LSub;", "LImpl;", &[], false, vec!(), vec!()),
+            class(&mut b, "LActivity;", "Landroid/app/Activity;", &[], false, vec!(), vec!())
+        );
+        for class in classes {
+            b.class(class);
+        }
+        b.build().unwrap()
+    }
+
+    fn sorted(mut types: Vec<&str>) -> Vec<&str> {
+        types.sort();
+        types
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let file = build_file();
+        let hierarchy = ClassHierarchy::new(&file);
+
+        assert_eq!(hierarchy.superclasses("LSub;"), vec!("LImpl;", "LBase;", "Ljava/lang/Object;"));
+        assert_eq!(hierarchy.superinterfaces("LSub;"), vec!("LB;", "LA;"));
+        assert_eq!(hierarchy.subclasses("LBase;"), vec!("LImpl;", "LSub;"));
+        assert_eq!(sorted(hierarchy.implementors("LA;")), vec!("LBase;", "LImpl;", "LSub;"));
+        assert!(hierarchy.is_subtype("LSub;", "LA;"));
+        assert!(!hierarchy.is_subtype("LA;", "LB;"));
+        assert_eq!(hierarchy.external_classes(), vec!("Landroid/app/Activity;", "Ljava/lang/Object;"));
+    }
+
+    #[test]
+    fn test_resolution() {
+        let file = build_file();
+        let hierarchy = ClassHierarchy::new(&file);
+        let void = Prototype { shorty: Rc::new("V".to_string()), return_type: Rc::new("V".to_string()), parameters: vec!() };
+        let object = Rc::new("Ljava/lang/Object;".to_string());
+
+        // LB;'s default is more specific than LA;'s
+        let m = hierarchy.resolve_method("LSub;", "m", &void).defined().unwrap();
+        assert_eq!(*m.method.definer, "LB;");
+        let m = hierarchy.resolve_method("LBase;", "m", &void).defined().unwrap();
+        assert_eq!(*m.method.definer, "LA;");
+        assert_eq!(hierarchy.resolve_method("LActivity;", "onCreate", &void),
+                   Resolution::External(Rc::new("Landroid/app/Activity;".to_string())));
+
+        let hash_code = Prototype { shorty: Rc::new("I".to_string()), return_type: Rc::new("I".to_string()), parameters: vec!() };
+        assert_eq!(hierarchy.resolve_interface_method("LB;", "hashCode", &hash_code), Resolution::External(object));
+        assert_eq!(hierarchy.resolve_interface_method("LB;", "n", &void), Resolution::NotFound);
+
+        let x = hierarchy.resolve_field("LSub;", "x", "I").defined().unwrap();
+        assert_eq!(*x.field.definer, "LBase;");
+        assert_eq!(hierarchy.resolve_field("LSub;", "y", "I"), Resolution::NotFound);
+        assert_eq!(hierarchy.resolve_field("LActivity;", "y", "I"),
+                   Resolution::External(Rc::new("Landroid/app/Activity;".to_string())));
+    }
+
+    #[test]
+    fn test_object_methods() {
+        let file = build_file();
+        let hierarchy = ClassHierarchy::new(&file);
+        let prototype = |return_type: &str| Prototype {
+            shorty: Rc::new(if return_type == "V" { "V" } else { "L" }.to_string()),
+            return_type: Rc::new(return_type.to_string()),
+            parameters: vec!()
+        };
+        let object = Resolution::External(Rc::new("Ljava/lang/Object;".to_string()));
+
+        // super.clone() and super.finalize() reach java.lang.Object when it isn't in the file
+        assert_eq!(hierarchy.resolve_method("LSub;", "clone", &prototype("Ljava/lang/Object;")), object);
+        assert_eq!(hierarchy.resolve_method("LSub;", "finalize", &prototype("V")), object);
+        assert_eq!(hierarchy.resolve_method("LSub;", "toString", &prototype("Ljava/lang/String;")), object);
+        assert_eq!(hierarchy.resolve_method("LSub;", "clone", &prototype("V")), Resolution::NotFound);
+
+        // only the public ones are found through an interface
+        assert_eq!(hierarchy.resolve_interface_method("LB;", "clone", &prototype("Ljava/lang/Object;")),
+                   Resolution::NotFound);
+        assert_eq!(hierarchy.resolve_interface_method("LB;", "finalize", &prototype("V")), Resolution::NotFound);
+    }
+}
@@ -0,0 +1,921 @@
+// Decoding of the Dalvik bytecode held in Code.insns
+//
+// Docs: Dalvik bytecode, and Dalvik Executable instruction formats
+
+use crate::error::DexParserError;
+
+// What an instruction can do once it has executed
+const CONTINUE: u8 = 0x01;
+const BRANCH: u8 = 0x02;
+const SWITCH: u8 = 0x04;
+const THROW: u8 = 0x08;
+const RETURN: u8 = 0x10;
+const INVOKE: u8 = 0x20;
+
+// Docs: Dalvik Executable instruction formats
+//noinspection RsEnumVariantNaming
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Format {
+    F10x, F12x, F11n, F11x, F10t,
+    F20t, F22x, F21t, F21s, F21h, F21c, F23x, F22b, F22t, F22s, F22c,
+    F30t, F32x, F31i, F31t, F31c, F35c, F3rc,
+    F45cc, F4rcc,
+    F51l,
+    // the switch and array data tables, which are laid out in the instruction stream
+    Payload
+}
+
+// The pool an instruction's index refers to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IndexKind {
+    None,
+    String,
+    Type,
+    Field,
+    Method,
+    Prototype,
+    CallSite,
+    MethodHandle
+}
+
+macro_rules! opcodes {
+    ($($value:expr => $opcode:ident, $name:expr, $format:ident, $index:ident, $flags:expr;)*) => {
+        //noinspection RsEnumVariantNaming
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+        pub enum Opcode {
+            $($opcode,)*
+            PACKED_SWITCH_PAYLOAD,
+            SPARSE_SWITCH_PAYLOAD,
+            FILL_ARRAY_DATA_PAYLOAD
+        }
+
+        impl Opcode {
+            // None for the unused opcodes
+            pub fn from_u8(value: u8) -> Option<Opcode> {
+                match value {
+                    $($value => Some(Opcode::$opcode),)*
+                    _ => None
+                }
+            }
+
+            // the payloads are identified by the high byte of a nop
+            pub fn value(self) -> u8 {
+                match self {
+                    $(Opcode::$opcode => $value,)*
+                    Opcode::PACKED_SWITCH_PAYLOAD | Opcode::SPARSE_SWITCH_PAYLOAD
+                        | Opcode::FILL_ARRAY_DATA_PAYLOAD => 0x00
+                }
+            }
+
+            // the mnemonic used in the spec and by disassemblers, e.g. "move-result-object"
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$opcode => $name,)*
+                    Opcode::PACKED_SWITCH_PAYLOAD => "packed-switch-payload",
+                    Opcode::SPARSE_SWITCH_PAYLOAD => "sparse-switch-payload",
+                    Opcode::FILL_ARRAY_DATA_PAYLOAD => "fill-array-data-payload"
+                }
+            }
+
+            pub fn format(self) -> Format {
+                match self {
+                    $(Opcode::$opcode => Format::$format,)*
+                    Opcode::PACKED_SWITCH_PAYLOAD | Opcode::SPARSE_SWITCH_PAYLOAD
+                        | Opcode::FILL_ARRAY_DATA_PAYLOAD => Format::Payload
+                }
+            }
+
+            pub fn index_kind(self) -> IndexKind {
+                match self {
+                    $(Opcode::$opcode => IndexKind::$index,)*
+                    Opcode::PACKED_SWITCH_PAYLOAD | Opcode::SPARSE_SWITCH_PAYLOAD
+                        | Opcode::FILL_ARRAY_DATA_PAYLOAD => IndexKind::None
+                }
+            }
+
+            fn flags(self) -> u8 {
+                match self {
+                    $(Opcode::$opcode => $flags,)*
+                    Opcode::PACKED_SWITCH_PAYLOAD | Opcode::SPARSE_SWITCH_PAYLOAD
+                        | Opcode::FILL_ARRAY_DATA_PAYLOAD => 0
+                }
+            }
+        }
+    }
+}
+
+opcodes! {
+    0x00 => NOP, "nop", F10x, None, CONTINUE;
+    0x01 => MOVE, "move", F12x, None, CONTINUE;
+    0x02 => MOVE_FROM16, "move/from16", F22x, None, CONTINUE;
+    0x03 => MOVE_16, "move/16", F32x, None, CONTINUE;
+    0x04 => MOVE_WIDE, "move-wide", F12x, None, CONTINUE;
+    0x05 => MOVE_WIDE_FROM16, "move-wide/from16", F22x, None, CONTINUE;
+    0x06 => MOVE_WIDE_16, "move-wide/16", F32x, None, CONTINUE;
+    0x07 => MOVE_OBJECT, "move-object", F12x, None, CONTINUE;
+    0x08 => MOVE_OBJECT_FROM16, "move-object/from16", F22x, None, CONTINUE;
+    0x09 => MOVE_OBJECT_16, "move-object/16", F32x, None, CONTINUE;
+    0x0A => MOVE_RESULT, "move-result", F11x, None, CONTINUE;
+    0x0B => MOVE_RESULT_WIDE, "move-result-wide", F11x, None, CONTINUE;
+    0x0C => MOVE_RESULT_OBJECT, "move-result-object", F11x, None, CONTINUE;
+    0x0D => MOVE_EXCEPTION, "move-exception", F11x, None, CONTINUE;
+    0x0E => RETURN_VOID, "return-void", F10x, None, RETURN;
+    0x0F => RETURN, "return", F11x, None, RETURN;
+    0x10 => RETURN_WIDE, "return-wide", F11x, None, RETURN;
+    0x11 => RETURN_OBJECT, "return-object", F11x, None, RETURN;
+    0x12 => CONST_4, "const/4", F11n, None, CONTINUE;
+    0x13 => CONST_16, "const/16", F21s, None, CONTINUE;
+    0x14 => CONST, "const", F31i, None, CONTINUE;
+    0x15 => CONST_HIGH16, "const/high16", F21h, None, CONTINUE;
+    0x16 => CONST_WIDE_16, "const-wide/16", F21s, None, CONTINUE;
+    0x17 => CONST_WIDE_32, "const-wide/32", F31i, None, CONTINUE;
+    0x18 => CONST_WIDE, "const-wide", F51l, None, CONTINUE;
+    0x19 => CONST_WIDE_HIGH16, "const-wide/high16", F21h, None, CONTINUE;
+    0x1A => CONST_STRING, "const-string", F21c, String, CONTINUE | THROW;
+    0x1B => CONST_STRING_JUMBO, "const-string/jumbo", F31c, String, CONTINUE | THROW;
+    0x1C => CONST_CLASS, "const-class", F21c, Type, CONTINUE | THROW;
+    0x1D => MONITOR_ENTER, "monitor-enter", F11x, None, CONTINUE | THROW;
+    0x1E => MONITOR_EXIT, "monitor-exit", F11x, None, CONTINUE | THROW;
+    0x1F => CHECK_CAST, "check-cast", F21c, Type, CONTINUE | THROW;
+    0x20 => INSTANCE_OF, "instance-of", F22c, Type, CONTINUE | THROW;
+    0x21 => ARRAY_LENGTH, "array-length", F12x, None, CONTINUE | THROW;
+    0x22 => NEW_INSTANCE, "new-instance", F21c, Type, CONTINUE | THROW;
+    0x23 => NEW_ARRAY, "new-array", F22c, Type, CONTINUE | THROW;
+    0x24 => FILLED_NEW_ARRAY, "filled-new-array", F35c, Type, CONTINUE | THROW;
+    0x25 => FILLED_NEW_ARRAY_RANGE, "filled-new-array/range", F3rc, Type, CONTINUE | THROW;
+    0x26 => FILL_ARRAY_DATA, "fill-array-data", F31t, None, CONTINUE | THROW;
+    0x27 => THROW, "throw", F11x, None, THROW;
+    0x28 => GOTO, "goto", F10t, None, BRANCH;
+    0x29 => GOTO_16, "goto/16", F20t, None, BRANCH;
+    0x2A => GOTO_32, "goto/32", F30t, None, BRANCH;
+    0x2B => PACKED_SWITCH, "packed-switch", F31t, None, CONTINUE | SWITCH;
+    0x2C => SPARSE_SWITCH, "sparse-switch", F31t, None, CONTINUE | SWITCH;
+    0x2D => CMPL_FLOAT, "cmpl-float", F23x, None, CONTINUE;
+    0x2E => CMPG_FLOAT, "cmpg-float", F23x, None, CONTINUE;
+    0x2F => CMPL_DOUBLE, "cmpl-double", F23x, None, CONTINUE;
+    0x30 => CMPG_DOUBLE, "cmpg-double", F23x, None, CONTINUE;
+    0x31 => CMP_LONG, "cmp-long", F23x, None, CONTINUE;
+    0x32 => IF_EQ, "if-eq", F22t, None, CONTINUE | BRANCH;
+    0x33 => IF_NE, "if-ne", F22t, None, CONTINUE | BRANCH;
+    0x34 => IF_LT, "if-lt", F22t, None, CONTINUE | BRANCH;
+    0x35 => IF_GE, "if-ge", F22t, None, CONTINUE | BRANCH;
+    0x36 => IF_GT, "if-gt", F22t, None, CONTINUE | BRANCH;
+    0x37 => IF_LE, "if-le", F22t, None, CONTINUE | BRANCH;
+    0x38 => IF_EQZ, "if-eqz", F21t, None, CONTINUE | BRANCH;
+    0x39 => IF_NEZ, "if-nez", F21t, None, CONTINUE | BRANCH;
+    0x3A => IF_LTZ, "if-ltz", F21t, None, CONTINUE | BRANCH;
+    0x3B => IF_GEZ, "if-gez", F21t, None, CONTINUE | BRANCH;
+    0x3C => IF_GTZ, "if-gtz", F21t, None, CONTINUE | BRANCH;
+    0x3D => IF_LEZ, "if-lez", F21t, None, CONTINUE | BRANCH;
+    0x44 => AGET, "aget", F23x, None, CONTINUE | THROW;
+    0x45 => AGET_WIDE, "aget-wide", F23x, None, CONTINUE | THROW;
+    0x46 => AGET_OBJECT, "aget-object", F23x, None, CONTINUE | THROW;
+    0x47 => AGET_BOOLEAN, "aget-boolean", F23x, None, CONTINUE | THROW;
+    0x48 => AGET_BYTE, "aget-byte", F23x, None, CONTINUE | THROW;
+    0x49 => AGET_CHAR, "aget-char", F23x, None, CONTINUE | THROW;
+    0x4A => AGET_SHORT, "aget-short", F23x, None, CONTINUE | THROW;
+    0x4B => APUT, "aput", F23x, None, CONTINUE | THROW;
+    0x4C => APUT_WIDE, "aput-wide", F23x, None, CONTINUE | THROW;
+    0x4D => APUT_OBJECT, "aput-object", F23x, None, CONTINUE | THROW;
+    0x4E => APUT_BOOLEAN, "aput-boolean", F23x, None, CONTINUE | THROW;
+    0x4F => APUT_BYTE, "aput-byte", F23x, None, CONTINUE | THROW;
+    0x50 => APUT_CHAR, "aput-char", F23x, None, CONTINUE | THROW;
+    0x51 => APUT_SHORT, "aput-short", F23x, None, CONTINUE | THROW;
+    0x52 => IGET, "iget", F22c, Field, CONTINUE | THROW;
+    0x53 => IGET_WIDE, "iget-wide", F22c, Field, CONTINUE | THROW;
+    0x54 => IGET_OBJECT, "iget-object", F22c, Field, CONTINUE | THROW;
+    0x55 => IGET_BOOLEAN, "iget-boolean", F22c, Field, CONTINUE | THROW;
+    0x56 => IGET_BYTE, "iget-byte", F22c, Field, CONTINUE | THROW;
+    0x57 => IGET_CHAR, "iget-char", F22c, Field, CONTINUE | THROW;
+    0x58 => IGET_SHORT, "iget-short", F22c, Field, CONTINUE | THROW;
+    0x59 => IPUT, "iput", F22c, Field, CONTINUE | THROW;
+    0x5A => IPUT_WIDE, "iput-wide", F22c, Field, CONTINUE | THROW;
+    0x5B => IPUT_OBJECT, "iput-object", F22c, Field, CONTINUE | THROW;
+    0x5C => IPUT_BOOLEAN, "iput-boolean", F22c, Field, CONTINUE | THROW;
+    0x5D => IPUT_BYTE, "iput-byte", F22c, Field, CONTINUE | THROW;
+    0x5E => IPUT_CHAR, "iput-char", F22c, Field, CONTINUE | THROW;
+    0x5F => IPUT_SHORT, "iput-short", F22c, Field, CONTINUE | THROW;
+    0x60 => SGET, "sget", F21c, Field, CONTINUE | THROW;
+    0x61 => SGET_WIDE, "sget-wide", F21c, Field, CONTINUE | THROW;
+    0x62 => SGET_OBJECT, "sget-object", F21c, Field, CONTINUE | THROW;
+    0x63 => SGET_BOOLEAN, "sget-boolean", F21c, Field, CONTINUE | THROW;
+    0x64 => SGET_BYTE, "sget-byte", F21c, Field, CONTINUE | THROW;
+    0x65 => SGET_CHAR, "sget-char", F21c, Field, CONTINUE | THROW;
+    0x66 => SGET_SHORT, "sget-short", F21c, Field, CONTINUE | THROW;
+    0x67 => SPUT, "sput", F21c, Field, CONTINUE | THROW;
+    0x68 => SPUT_WIDE, "sput-wide", F21c, Field, CONTINUE | THROW;
+    0x69 => SPUT_OBJECT, "sput-object", F21c, Field, CONTINUE | THROW;
+    0x6A => SPUT_BOOLEAN, "sput-boolean", F21c, Field, CONTINUE | THROW;
+    0x6B => SPUT_BYTE, "sput-byte", F21c, Field, CONTINUE | THROW;
+    0x6C => SPUT_CHAR, "sput-char", F21c, Field, CONTINUE | THROW;
+    0x6D => SPUT_SHORT, "sput-short", F21c, Field, CONTINUE | THROW;
+    0x6E => INVOKE_VIRTUAL, "invoke-virtual", F35c, Method, CONTINUE | THROW | INVOKE;
+    0x6F => INVOKE_SUPER, "invoke-super", F35c, Method, CONTINUE | THROW | INVOKE;
+    0x70 => INVOKE_DIRECT, "invoke-direct", F35c, Method, CONTINUE | THROW | INVOKE;
+    0x71 => INVOKE_STATIC, "invoke-static", F35c, Method, CONTINUE | THROW | INVOKE;
+    0x72 => INVOKE_INTERFACE, "invoke-interface", F35c, Method, CONTINUE | THROW | INVOKE;
+    0x74 => INVOKE_VIRTUAL_RANGE, "invoke-virtual/range", F3rc, Method, CONTINUE | THROW | INVOKE;
+    0x75 => INVOKE_SUPER_RANGE, "invoke-super/range", F3rc, Method, CONTINUE | THROW | INVOKE;
+    0x76 => INVOKE_DIRECT_RANGE, "invoke-direct/range", F3rc, Method, CONTINUE | THROW | INVOKE;
+    0x77 => INVOKE_STATIC_RANGE, "invoke-static/range", F3rc, Method, CONTINUE | THROW | INVOKE;
+    0x78 => INVOKE_INTERFACE_RANGE, "invoke-interface/range", F3rc, Method, CONTINUE | THROW | INVOKE;
+    0x7B => NEG_INT, "neg-int", F12x, None, CONTINUE;
+    0x7C => NOT_INT, "not-int", F12x, None, CONTINUE;
+    0x7D => NEG_LONG, "neg-long", F12x, None, CONTINUE;
+    0x7E => NOT_LONG, "not-long", F12x, None, CONTINUE;
+    0x7F => NEG_FLOAT, "neg-float", F12x, None, CONTINUE;
+    0x80 => NEG_DOUBLE, "neg-double", F12x, None, CONTINUE;
+    0x81 => INT_TO_LONG, "int-to-long", F12x, None, CONTINUE;
+    0x82 => INT_TO_FLOAT, "int-to-float", F12x, None, CONTINUE;
+    0x83 => INT_TO_DOUBLE, "int-to-double", F12x, None, CONTINUE;
+    0x84 => LONG_TO_INT, "long-to-int", F12x, None, CONTINUE;
+    0x85 => LONG_TO_FLOAT, "long-to-float", F12x, None, CONTINUE;
+    0x86 => LONG_TO_DOUBLE, "long-to-double", F12x, None, CONTINUE;
+    0x87 => FLOAT_TO_INT, "float-to-int", F12x, None, CONTINUE;
+    0x88 => FLOAT_TO_LONG, "float-to-long", F12x, None, CONTINUE;
+    0x89 => FLOAT_TO_DOUBLE, "float-to-double", F12x, None, CONTINUE;
+    0x8A => DOUBLE_TO_INT, "double-to-int", F12x, None, CONTINUE;
+    0x8B => DOUBLE_TO_LONG, "double-to-long", F12x, None, CONTINUE;
+    0x8C => DOUBLE_TO_FLOAT, "double-to-float", F12x, None, CONTINUE;
+    0x8D => INT_TO_BYTE, "int-to-byte", F12x, None, CONTINUE;
+    0x8E => INT_TO_CHAR, "int-to-char", F12x, None, CONTINUE;
+    0x8F => INT_TO_SHORT, "int-to-short", F12x, None, CONTINUE;
+    0x90 => ADD_INT, "add-int", F23x, None, CONTINUE;
+    0x91 => SUB_INT, "sub-int", F23x, None, CONTINUE;
+    0x92 => MUL_INT, "mul-int", F23x, None, CONTINUE;
+    0x93 => DIV_INT, "div-int", F23x, None, CONTINUE | THROW;
+    0x94 => REM_INT, "rem-int", F23x, None, CONTINUE | THROW;
+    0x95 => AND_INT, "and-int", F23x, None, CONTINUE;
+    0x96 => OR_INT, "or-int", F23x, None, CONTINUE;
+    0x97 => XOR_INT, "xor-int", F23x, None, CONTINUE;
+    0x98 => SHL_INT, "shl-int", F23x, None, CONTINUE;
+    0x99 => SHR_INT, "shr-int", F23x, None, CONTINUE;
+    0x9A => USHR_INT, "ushr-int", F23x, None, CONTINUE;
+    0x9B => ADD_LONG, "add-long", F23x, None, CONTINUE;
+    0x9C => SUB_LONG, "sub-long", F23x, None, CONTINUE;
+    0x9D => MUL_LONG, "mul-long", F23x, None, CONTINUE;
+    0x9E => DIV_LONG, "div-long", F23x, None, CONTINUE | THROW;
+    0x9F => REM_LONG, "rem-long", F23x, None, CONTINUE | THROW;
+    0xA0 => AND_LONG, "and-long", F23x, None, CONTINUE;
+    0xA1 => OR_LONG, "or-long", F23x, None, CONTINUE;
+    0xA2 => XOR_LONG, "xor-long", F23x, None, CONTINUE;
+    0xA3 => SHL_LONG, "shl-long", F23x, None, CONTINUE;
+    0xA4 => SHR_LONG, "shr-long", F23x, None, CONTINUE;
+    0xA5 => USHR_LONG, "ushr-long", F23x, None, CONTINUE;
+    0xA6 => ADD_FLOAT, "add-float", F23x, None, CONTINUE;
+    0xA7 => SUB_FLOAT, "sub-float", F23x, None, CONTINUE;
+    0xA8 => MUL_FLOAT, "mul-float", F23x, None, CONTINUE;
+    0xA9 => DIV_FLOAT, "div-float", F23x, None, CONTINUE;
+    0xAA => REM_FLOAT, "rem-float", F23x, None, CONTINUE;
+    0xAB => ADD_DOUBLE, "add-double", F23x, None, CONTINUE;
+    0xAC => SUB_DOUBLE, "sub-double", F23x, None, CONTINUE;
+    0xAD => MUL_DOUBLE, "mul-double", F23x, None, CONTINUE;
+    0xAE => DIV_DOUBLE, "div-double", F23x, None, CONTINUE;
+    0xAF => REM_DOUBLE, "rem-double", F23x, None, CONTINUE;
+    0xB0 => ADD_INT_2ADDR, "add-int/2addr", F12x, None, CONTINUE;
+    0xB1 => SUB_INT_2ADDR, "sub-int/2addr", F12x, None, CONTINUE;
+    0xB2 => MUL_INT_2ADDR, "mul-int/2addr", F12x, None, CONTINUE;
+    0xB3 => DIV_INT_2ADDR, "div-int/2addr", F12x, None, CONTINUE | THROW;
+    0xB4 => REM_INT_2ADDR, "rem-int/2addr", F12x, None, CONTINUE | THROW;
+    0xB5 => AND_INT_2ADDR, "and-int/2addr", F12x, None, CONTINUE;
+    0xB6 => OR_INT_2ADDR, "or-int/2addr", F12x, None, CONTINUE;
+    0xB7 => XOR_INT_2ADDR, "xor-int/2addr", F12x, None, CONTINUE;
+    0xB8 => SHL_INT_2ADDR, "shl-int/2addr", F12x, None, CONTINUE;
+    0xB9 => SHR_INT_2ADDR, "shr-int/2addr", F12x, None, CONTINUE;
+    0xBA => USHR_INT_2ADDR, "ushr-int/2addr", F12x, None, CONTINUE;
+    0xBB => ADD_LONG_2ADDR, "add-long/2addr", F12x, None, CONTINUE;
+    0xBC => SUB_LONG_2ADDR, "sub-long/2addr", F12x, None, CONTINUE;
+    0xBD => MUL_LONG_2ADDR, "mul-long/2addr", F12x, None, CONTINUE;
+    0xBE => DIV_LONG_2ADDR, "div-long/2addr", F12x, None, CONTINUE | THROW;
+    0xBF => REM_LONG_2ADDR, "rem-long/2addr", F12x, None, CONTINUE | THROW;
+    0xC0 => AND_LONG_2ADDR, "and-long/2addr", F12x, None, CONTINUE;
+    0xC1 => OR_LONG_2ADDR, "or-long/2addr", F12x, None, CONTINUE;
+    0xC2 => XOR_LONG_2ADDR, "xor-long/2addr", F12x, None, CONTINUE;
+    0xC3 => SHL_LONG_2ADDR, "shl-long/2addr", F12x, None, CONTINUE;
+    0xC4 => SHR_LONG_2ADDR, "shr-long/2addr", F12x, None, CONTINUE;
+    0xC5 => USHR_LONG_2ADDR, "ushr-long/2addr", F12x, None, CONTINUE;
+    0xC6 => ADD_FLOAT_2ADDR, "add-float/2addr", F12x, None, CONTINUE;
+    0xC7 => SUB_FLOAT_2ADDR, "sub-float/2addr", F12x, None, CONTINUE;
+    0xC8 => MUL_FLOAT_2ADDR, "mul-float/2addr", F12x, None, CONTINUE;
+    0xC9 => DIV_FLOAT_2ADDR, "div-float/2addr", F12x, None, CONTINUE;
+    0xCA => REM_FLOAT_2ADDR, "rem-float/2addr", F12x, None, CONTINUE;
+    0xCB => ADD_DOUBLE_2ADDR, "add-double/2addr", F12x, None, CONTINUE;
+    0xCC => SUB_DOUBLE_2ADDR, "sub-double/2addr", F12x, None, CONTINUE;
+    0xCD => MUL_DOUBLE_2ADDR, "mul-double/2addr", F12x, None, CONTINUE;
+    0xCE => DIV_DOUBLE_2ADDR, "div-double/2addr", F12x, None, CONTINUE;
+    0xCF => REM_DOUBLE_2ADDR, "rem-double/2addr", F12x, None, CONTINUE;
+    0xD0 => ADD_INT_LIT16, "add-int/lit16", F22s, None, CONTINUE;
+    0xD1 => RSUB_INT, "rsub-int", F22s, None, CONTINUE;
+    0xD2 => MUL_INT_LIT16, "mul-int/lit16", F22s, None, CONTINUE;
+    0xD3 => DIV_INT_LIT16, "div-int/lit16", F22s, None, CONTINUE | THROW;
+    0xD4 => REM_INT_LIT16, "rem-int/lit16", F22s, None, CONTINUE | THROW;
+    0xD5 => AND_INT_LIT16, "and-int/lit16", F22s, None, CONTINUE;
+    0xD6 => OR_INT_LIT16, "or-int/lit16", F22s, None, CONTINUE;
+    0xD7 => XOR_INT_LIT16, "xor-int/lit16", F22s, None, CONTINUE;
+    0xD8 => ADD_INT_LIT8, "add-int/lit8", F22b, None, CONTINUE;
+    0xD9 => RSUB_INT_LIT8, "rsub-int/lit8", F22b, None, CONTINUE;
+    0xDA => MUL_INT_LIT8, "mul-int/lit8", F22b, None, CONTINUE;
+    0xDB => DIV_INT_LIT8, "div-int/lit8", F22b, None, CONTINUE | THROW;
+    0xDC => REM_INT_LIT8, "rem-int/lit8", F22b, None, CONTINUE | THROW;
+    0xDD => AND_INT_LIT8, "and-int/lit8", F22b, None, CONTINUE;
+    0xDE => OR_INT_LIT8, "or-int/lit8", F22b, None, CONTINUE;
+    0xDF => XOR_INT_LIT8, "xor-int/lit8", F22b, None, CONTINUE;
+    0xE0 => SHL_INT_LIT8, "shl-int/lit8", F22b, None, CONTINUE;
+    0xE1 => SHR_INT_LIT8, "shr-int/lit8", F22b, None, CONTINUE;
+    0xE2 => USHR_INT_LIT8, "ushr-int/lit8", F22b, None, CONTINUE;
+    0xFA => INVOKE_POLYMORPHIC, "invoke-polymorphic", F45cc, Method, CONTINUE | THROW | INVOKE;
+    0xFB => INVOKE_POLYMORPHIC_RANGE, "invoke-polymorphic/range", F4rcc, Method, CONTINUE | THROW | INVOKE;
+    0xFC => INVOKE_CUSTOM, "invoke-custom", F35c, CallSite, CONTINUE | THROW | INVOKE;
+    0xFD => INVOKE_CUSTOM_RANGE, "invoke-custom/range", F3rc, CallSite, CONTINUE | THROW | INVOKE;
+    0xFE => CONST_METHOD_HANDLE, "const-method-handle", F21c, MethodHandle, CONTINUE | THROW;
+    0xFF => CONST_METHOD_TYPE, "const-method-type", F21c, Prototype, CONTINUE | THROW;
+}
+
+impl Opcode {
+    // execution can carry on to the next instruction
+    pub fn can_continue(self) -> bool {
+        self.flags() & CONTINUE != 0
+    }
+
+    // goto and if-* instructions
+    pub fn can_branch(self) -> bool {
+        self.flags() & BRANCH != 0
+    }
+
+    pub fn can_switch(self) -> bool {
+        self.flags() & SWITCH != 0
+    }
+
+    pub fn can_throw(self) -> bool {
+        self.flags() & THROW != 0
+    }
+
+    pub fn is_return(self) -> bool {
+        self.flags() & RETURN != 0
+    }
+
+    pub fn is_invoke(self) -> bool {
+        self.flags() & INVOKE != 0
+    }
+
+    // size in 16-bit code units, for everything but the payloads which vary in size
+    pub fn length(self) -> u32 {
+        match self.format() {
+            Format::F10x | Format::F12x | Format::F11n | Format::F11x | Format::F10t => 1,
+            Format::F20t | Format::F22x | Format::F21t | Format::F21s | Format::F21h | Format::F21c
+                | Format::F23x | Format::F22b | Format::F22t | Format::F22s | Format::F22c => 2,
+            Format::F30t | Format::F32x | Format::F31i | Format::F31t | Format::F31c | Format::F35c
+                | Format::F3rc => 3,
+            Format::F45cc | Format::F4rcc => 4,
+            Format::F51l => 5,
+            Format::Payload => 0
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    // in 16-bit code units from the start of insns
+    pub offset: u32,
+    pub opcode: Opcode,
+    // in 16-bit code units
+    pub length: u32,
+    // the registers the instruction names, in the order they appear in its syntax.
+    // Range instructions have every register in the range listed
+    pub registers: Vec<u16>,
+    // the constant operand, already shifted for the */high16 instructions
+    pub literal: Option<i64>,
+    // relative to this instruction: the branch target for goto and if-*, and the payload for
+    // packed-switch, sparse-switch and fill-array-data
+    pub branch_offset: Option<i32>,
+    // an index into the pool given by opcode.index_kind()
+    pub index: Option<u32>,
+    // the prototype index of invoke-polymorphic
+    pub proto_index: Option<u32>,
+    // the contents of a payload pseudo-instruction
+    pub payload: Option<Payload>
+}
+
+// Docs: packed-switch-payload, sparse-switch-payload and fill-array-data-payload formats
+#[derive(Debug, PartialEq, Clone)]
+pub enum Payload {
+    // targets are relative to the switch instruction, not the payload
+    PackedSwitch {
+        first_key: i32,
+        targets: Vec<i32>
+    },
+    SparseSwitch {
+        keys: Vec<i32>,
+        targets: Vec<i32>
+    },
+    FillArrayData {
+        element_width: u16,
+        data: Vec<u8>
+    }
+}
+
+impl Payload {
+    // Each case of a switch as (key, target relative to the switch instruction)
+    pub fn switch_cases(&self) -> Vec<(i32, i32)> {
+        match self {
+            Payload::PackedSwitch { first_key, targets } => targets.iter().enumerate()
+                .map(|(i, target)| (first_key.wrapping_add(i as i32), *target))
+                .collect(),
+            Payload::SparseSwitch { keys, targets } => keys.iter().cloned().zip(targets.iter().cloned()).collect(),
+            Payload::FillArrayData { .. } => vec!()
+        }
+    }
+}
+
+impl Instruction {
+    // the absolute address of the branch target or payload
+    pub fn target(&self) -> Option<u32> {
+        self.branch_offset.map(|off| (i64::from(self.offset) + i64::from(off)) as u32)
+    }
+}
+
+// Decode every instruction and payload in a method's code
+pub fn decode_all(insns: &[u16]) -> Result<Vec<Instruction>, DexParserError> {
+    let mut instructions = vec!();
+    let mut offset = 0;
+    while (offset as usize) < insns.len() {
+        let instruction = decode(insns, offset)?;
+        offset += instruction.length;
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+// Decode the single instruction starting at the given offset
+pub fn decode(insns: &[u16], offset: u32) -> Result<Instruction, DexParserError> {
+    let err = |reason: String| DexParserError::InvalidBytecode { offset, reason };
+    let units = insns.get(offset as usize ..).unwrap_or(&[]);
+    let unit = |i: usize| units.get(i).cloned()
+        .ok_or_else(|| err(format!("instruction runs past the end of the code at unit {}", i)));
+
+    let first = unit(0)?;
+    let op = (first & 0xFF) as u8;
+    // the byte following the opcode, shared out between operands differently by each format
+    let aa = first >> 8;
+
+    if op == 0x00 && (1 ..= 3).contains(&aa) {
+        return decode_payload(units, offset, aa).ok_or_else(|| err("payload runs past the end of the code".to_string()));
+    }
+
+    let opcode = Opcode::from_u8(op).ok_or_else(|| err(format!("unused opcode 0x{:02X}", op)))?;
+    let mut instruction = Instruction {
+        offset,
+        opcode,
+        length: opcode.length(),
+        registers: vec!(),
+        literal: None,
+        branch_offset: None,
+        index: None,
+        proto_index: None,
+        payload: None
+    };
+    let a = aa & 0x0F;
+    let b = aa >> 4;
+    let u32_at = |i: usize| -> Result<u32, DexParserError> {
+        Ok(u32::from(unit(i)?) | (u32::from(unit(i + 1)?) << 16))
+    };
+
+    match opcode.format() {
+        Format::F10x => (),
+        Format::F12x => instruction.registers = vec!(a, b),
+        Format::F11n => {
+            instruction.registers = vec!(a);
+            // sign extend the top nibble
+            instruction.literal = Some(i64::from(((aa as u8) as i8) >> 4));
+        },
+        Format::F11x => instruction.registers = vec!(aa),
+        Format::F10t => instruction.branch_offset = Some(i32::from(aa as u8 as i8)),
+        Format::F20t => instruction.branch_offset = Some(i32::from(unit(1)? as i16)),
+        Format::F22x => instruction.registers = vec!(aa, unit(1)?),
+        Format::F21t => {
+            instruction.registers = vec!(aa);
+            instruction.branch_offset = Some(i32::from(unit(1)? as i16));
+        },
+        Format::F21s => {
+            instruction.registers = vec!(aa);
+            instruction.literal = Some(i64::from(unit(1)? as i16));
+        },
+        Format::F21h => {
+            instruction.registers = vec!(aa);
+            let shift = if opcode == Opcode::CONST_WIDE_HIGH16 { 48 } else { 16 };
+            instruction.literal = Some(i64::from(unit(1)? as i16) << shift);
+        },
+        Format::F21c => {
+            instruction.registers = vec!(aa);
+            instruction.index = Some(u32::from(unit(1)?));
+        },
+        Format::F23x => {
+            let cc_bb = unit(1)?;
+            instruction.registers = vec!(aa, cc_bb & 0xFF, cc_bb >> 8);
+        },
+        Format::F22b => {
+            let cc_bb = unit(1)?;
+            instruction.registers = vec!(aa, cc_bb & 0xFF);
+            instruction.literal = Some(i64::from((cc_bb >> 8) as u8 as i8));
+        },
+        Format::F22t => {
+            instruction.registers = vec!(a, b);
+            instruction.branch_offset = Some(i32::from(unit(1)? as i16));
+        },
+        Format::F22s => {
+            instruction.registers = vec!(a, b);
+            instruction.literal = Some(i64::from(unit(1)? as i16));
+        },
+        Format::F22c => {
+            instruction.registers = vec!(a, b);
+            instruction.index = Some(u32::from(unit(1)?));
+        },
+        Format::F30t => instruction.branch_offset = Some(u32_at(1)? as i32),
+        Format::F32x => instruction.registers = vec!(unit(1)?, unit(2)?),
+        Format::F31i => {
+            instruction.registers = vec!(aa);
+            instruction.literal = Some(i64::from(u32_at(1)? as i32));
+        },
+        Format::F31t => {
+            instruction.registers = vec!(aa);
+            instruction.branch_offset = Some(u32_at(1)? as i32);
+        },
+        Format::F31c => {
+            instruction.registers = vec!(aa);
+            instruction.index = Some(u32_at(1)?);
+        },
+        Format::F35c | Format::F45cc => {
+            // A|G|op BBBB F|E|D|C, where A is the argument count
+            if b > 5 {
+                return Err(err(format!("{} arguments given, but at most 5 are allowed", b)));
+            }
+            let fedc = unit(2)?;
+            let args = [fedc & 0x0F, (fedc >> 4) & 0x0F, (fedc >> 8) & 0x0F, fedc >> 12, a];
+            instruction.registers = args[.. b as usize].to_vec();
+            instruction.index = Some(u32::from(unit(1)?));
+            if opcode.format() == Format::F45cc {
+                instruction.proto_index = Some(u32::from(unit(3)?));
+            }
+        },
+        Format::F3rc | Format::F4rcc => {
+            let first_register = u32::from(unit(2)?);
+            if first_register + u32::from(aa) > 0x10000 {
+                return Err(err("register range runs past v65535".to_string()));
+            }
+            instruction.registers = (first_register .. first_register + u32::from(aa)).map(|r| r as u16).collect();
+            instruction.index = Some(u32::from(unit(1)?));
+            if opcode.format() == Format::F4rcc {
+                instruction.proto_index = Some(u32::from(unit(3)?));
+            }
+        },
+        Format::F51l => {
+            instruction.registers = vec!(aa);
+            instruction.literal = Some((u64::from(u32_at(1)?) | (u64::from(u32_at(3)?) << 32)) as i64);
+        },
+        Format::Payload => ()
+    }
+
+    Ok(instruction)
+}
+
+fn decode_payload(units: &[u16], offset: u32, ident: u16) -> Option<Instruction> {
+    let u32_at = |i: usize| Some(u32::from(*units.get(i)?) | (u32::from(*units.get(i + 1)?) << 16));
+    let list = |start: usize, size: usize| (0 .. size).map(|i| u32_at(start + 2 * i).map(|v| v as i32)).collect::<Option<Vec<_>>>();
+    let size = *units.get(1)? as usize;
+
+    let (opcode, length, payload) = match ident {
+        // Docs: packed-switch-payload
+        1 => {
+            let first_key = u32_at(2)? as i32;
+            (Opcode::PACKED_SWITCH_PAYLOAD, 4 + 2 * size, Payload::PackedSwitch { first_key, targets: list(4, size)? })
+        },
+        // Docs: sparse-switch-payload
+        2 => (Opcode::SPARSE_SWITCH_PAYLOAD, 2 + 4 * size,
+              Payload::SparseSwitch { keys: list(2, size)?, targets: list(2 + 2 * size, size)? }),
+        // Docs: fill-array-data-payload
+        _ => {
+            let element_width = *units.get(1)?;
+            let count = u64::from(u32_at(2)?);
+            let bytes = count.checked_mul(u64::from(element_width))?;
+            if bytes > 2 * units.len() as u64 {
+                return None;
+            }
+            let bytes = bytes as usize;
+            let data = units.get(4 .. 4 + bytes.div_ceil(2))?.iter()
+                .flat_map(|unit| unit.to_le_bytes().to_vec())
+                .take(bytes)
+                .collect();
+            (Opcode::FILL_ARRAY_DATA_PAYLOAD, 4 + bytes.div_ceil(2), Payload::FillArrayData { element_width, data })
+        }
+    };
+
+    if length > units.len() {
+        return None;
+    }
+
+    Some(Instruction {
+        offset,
+        opcode,
+        length: length as u32,
+        registers: vec!(),
+        literal: None,
+        branch_offset: None,
+        index: None,
+        proto_index: None,
+        payload: Some(payload)
+    })
+}
+
+// Encode an instruction or payload, the reverse of decode. The offset is only used for errors, branch offsets and
+// indexes are taken as they are
+pub fn encode(instruction: &Instruction) -> Result<Vec<u16>, DexParserError> {
+    let err = |reason: String| DexParserError::InvalidBytecode { offset: instruction.offset, reason };
+    let opcode = instruction.opcode;
+    let registers = &instruction.registers;
+    let reg = |i: usize, max: u32| -> Result<u16, DexParserError> {
+        let register = *registers.get(i)
+            .ok_or_else(|| err(format!("{} takes more than {} registers", opcode.name(), i)))?;
+        if u32::from(register) > max {
+            return Err(err(format!("{} can't name v{}, only registers up to v{}", opcode.name(), register, max)));
+        }
+        Ok(register)
+    };
+    let literal = |min: i64, max: i64| -> Result<i64, DexParserError> {
+        let value = instruction.literal.ok_or_else(|| err(format!("{} needs a literal", opcode.name())))?;
+        if value < min || value > max {
+            return Err(err(format!("{} can't hold the literal {}", opcode.name(), value)));
+        }
+        Ok(value)
+    };
+    let branch = |min: i64, max: i64| -> Result<i32, DexParserError> {
+        let offset = instruction.branch_offset.ok_or_else(|| err(format!("{} needs a branch offset", opcode.name())))?;
+        if i64::from(offset) < min || i64::from(offset) > max {
+            return Err(err(format!("{} can't branch {} units", opcode.name(), offset)));
+        }
+        Ok(offset)
+    };
+    let index = |max: u32| -> Result<u32, DexParserError> {
+        let index = instruction.index.ok_or_else(|| err(format!("{} needs an index", opcode.name())))?;
+        if index > max {
+            return Err(err(format!("{} can't hold the index {}", opcode.name(), index)));
+        }
+        Ok(index)
+    };
+    let proto_index = || -> Result<u16, DexParserError> {
+        match instruction.proto_index {
+            Some(index) if index <= 0xFFFF => Ok(index as u16),
+            _ => Err(err(format!("{} needs a prototype index of 16 bits", opcode.name())))
+        }
+    };
+    let split = |value: u32| [value as u16, (value >> 16) as u16];
+
+    let op = u16::from(opcode.value());
+    // the registers after those the format names would otherwise be dropped silently
+    let named = match opcode.format() {
+        Format::F10x | Format::F10t | Format::F20t | Format::F30t | Format::Payload => 0,
+        Format::F11n | Format::F11x | Format::F21t | Format::F21s | Format::F21h | Format::F21c | Format::F31i
+            | Format::F31t | Format::F31c | Format::F51l => 1,
+        Format::F12x | Format::F22x | Format::F22b | Format::F22t | Format::F22s | Format::F22c | Format::F32x => 2,
+        Format::F23x => 3,
+        Format::F35c | Format::F45cc | Format::F3rc | Format::F4rcc => registers.len()
+    };
+    if registers.len() > named {
+        return Err(err(format!("{} takes {} registers, not {}", opcode.name(), named, registers.len())));
+    }
+
+    let units = match opcode.format() {
+        Format::F10x => vec!(op),
+        Format::F12x => vec!(op | reg(0, 0xF)? << 8 | reg(1, 0xF)? << 12),
+        Format::F11n => vec!(op | reg(0, 0xF)? << 8 | ((literal(-8, 7)? as u16) & 0xF) << 12),
+        Format::F11x => vec!(op | reg(0, 0xFF)? << 8),
+        Format::F10t => vec!(op | u16::from(branch(-128, 127)? as u8) << 8),
+        Format::F20t => vec!(op, branch(-32768, 32767)? as u16),
+        Format::F22x => vec!(op | reg(0, 0xFF)? << 8, reg(1, 0xFFFF)?),
+        Format::F21t => vec!(op | reg(0, 0xFF)? << 8, branch(-32768, 32767)? as u16),
+        Format::F21s => vec!(op | reg(0, 0xFF)? << 8, literal(-32768, 32767)? as u16),
+        Format::F21h => {
+            let shift = if opcode == Opcode::CONST_WIDE_HIGH16 { 48 } else { 16 };
+            let value = literal(i64::MIN, i64::MAX)?;
+            let high = value >> shift;
+            if high << shift != value || (shift == 16 && !(-32768 ..= 32767).contains(&high)) {
+                return Err(err(format!("{} can't hold the literal {}", opcode.name(), value)));
+            }
+            vec!(op | reg(0, 0xFF)? << 8, high as u16)
+        },
+        Format::F21c => vec!(op | reg(0, 0xFF)? << 8, index(0xFFFF)? as u16),
+        Format::F23x => vec!(op | reg(0, 0xFF)? << 8, reg(1, 0xFF)? | reg(2, 0xFF)? << 8),
+        Format::F22b => vec!(op | reg(0, 0xFF)? << 8, reg(1, 0xFF)? | u16::from(literal(-128, 127)? as u8) << 8),
+        Format::F22t => vec!(op | reg(0, 0xF)? << 8 | reg(1, 0xF)? << 12, branch(-32768, 32767)? as u16),
+        Format::F22s => vec!(op | reg(0, 0xF)? << 8 | reg(1, 0xF)? << 12, literal(-32768, 32767)? as u16),
+        Format::F22c => vec!(op | reg(0, 0xF)? << 8 | reg(1, 0xF)? << 12, index(0xFFFF)? as u16),
+        Format::F30t => {
+            let [low, high] = split(branch(i64::from(i32::MIN), i64::from(i32::MAX))? as u32);
+            vec!(op, low, high)
+        },
+        Format::F32x => vec!(op, reg(0, 0xFFFF)?, reg(1, 0xFFFF)?),
+        Format::F31i => {
+            let [low, high] = split(literal(i64::from(i32::MIN), i64::from(i32::MAX))? as u32);
+            vec!(op | reg(0, 0xFF)? << 8, low, high)
+        },
+        Format::F31t => {
+            let [low, high] = split(branch(i64::from(i32::MIN), i64::from(i32::MAX))? as u32);
+            vec!(op | reg(0, 0xFF)? << 8, low, high)
+        },
+        Format::F31c => {
+            let [low, high] = split(index(u32::MAX)?);
+            vec!(op | reg(0, 0xFF)? << 8, low, high)
+        },
+        Format::F35c | Format::F45cc => {
+            if registers.len() > 5 {
+                return Err(err(format!("{} takes at most 5 registers, not {}", opcode.name(), registers.len())));
+            }
+            let mut args = [0; 5];
+            for (i, arg) in args.iter_mut().enumerate().take(registers.len()) {
+                *arg = reg(i, 0xF)?;
+            }
+            let mut units = vec!(op | (registers.len() as u16) << 12 | args[4] << 8, index(0xFFFF)? as u16,
+                                 args[0] | args[1] << 4 | args[2] << 8 | args[3] << 12);
+            if opcode.format() == Format::F45cc {
+                units.push(proto_index()?);
+            }
+            units
+        },
+        Format::F3rc | Format::F4rcc => {
+            let first = registers.first().cloned().unwrap_or(0);
+            let contiguous = registers.iter().enumerate().all(|(i, r)| u32::from(*r) == u32::from(first) + i as u32);
+            if registers.len() > 255 || !contiguous {
+                return Err(err(format!("{} takes a range of at most 255 registers", opcode.name())));
+            }
+            let mut units = vec!(op | (registers.len() as u16) << 8, index(0xFFFF)? as u16, first);
+            if opcode.format() == Format::F4rcc {
+                units.push(proto_index()?);
+            }
+            units
+        },
+        Format::F51l => {
+            let value = literal(i64::MIN, i64::MAX)? as u64;
+            let [a, b] = split(value as u32);
+            let [c, d] = split((value >> 32) as u32);
+            vec!(op | reg(0, 0xFF)? << 8, a, b, c, d)
+        },
+        Format::Payload => encode_payload(instruction).map_err(err)?
+    };
+    Ok(units)
+}
+
+fn encode_payload(instruction: &Instruction) -> Result<Vec<u16>, String> {
+    let mut units = vec!();
+    let push_u32 = |units: &mut Vec<u16>, value: u32| units.extend(&[value as u16, (value >> 16) as u16]);
+    if let Some(Payload::PackedSwitch { ref targets, .. }) | Some(Payload::SparseSwitch { ref targets, .. })
+        = instruction.payload {
+        if targets.len() > 0xFFFF {
+            return Err(format!("a switch can have at most 65535 cases, not {}", targets.len()));
+        }
+    }
+    match (instruction.opcode, &instruction.payload) {
+        // Docs: packed-switch-payload
+        (Opcode::PACKED_SWITCH_PAYLOAD, Some(Payload::PackedSwitch { first_key, targets })) => {
+            units.extend(&[0x0100, targets.len() as u16]);
+            push_u32(&mut units, *first_key as u32);
+            targets.iter().for_each(|target| push_u32(&mut units, *target as u32));
+        },
+        // Docs: sparse-switch-payload
+        (Opcode::SPARSE_SWITCH_PAYLOAD, Some(Payload::SparseSwitch { keys, targets })) => {
+            if keys.len() != targets.len() {
+                return Err("a sparse switch needs as many targets as keys".to_string());
+            }
+            units.extend(&[0x0200, keys.len() as u16]);
+            keys.iter().chain(targets.iter()).for_each(|value| push_u32(&mut units, *value as u32));
+        },
+        // Docs: fill-array-data-payload
+        (Opcode::FILL_ARRAY_DATA_PAYLOAD, Some(Payload::FillArrayData { element_width, data })) => {
+            if *element_width == 0 || data.len() % usize::from(*element_width) != 0 {
+                return Err(format!("{} bytes of data don't make elements {} bytes wide", data.len(), element_width));
+            }
+            units.extend(&[0x0300, *element_width]);
+            push_u32(&mut units, (data.len() / usize::from(*element_width)) as u32);
+            units.extend(data.chunks(2).map(|pair| u16::from(pair[0]) | u16::from(*pair.get(1).unwrap_or(&0)) << 8));
+        },
+        (opcode, _) => return Err(format!("{} has no payload of its kind", opcode.name()))
+    }
+    Ok(units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_table() {
+        let used = (0 ..= 255).filter_map(Opcode::from_u8).collect::<Vec<_>>();
+        // 256 less the 32 unused opcodes
+        assert_eq!(used.len(), 224);
+        for opcode in used {
+            assert_eq!(Opcode::from_u8(opcode.value()), Some(opcode));
+            assert!(opcode.length() > 0);
+        }
+    }
+
+    #[test]
+    fn test_decode_formats() {
+        let insns = [
+            // const/4 v1, #-1
+            0xF112,
+            // const-wide/high16 v2, #0x4000000000000000
+            0x0219, 0x4000,
+            // invoke-virtual {v1, v2, v3}, method@0005
+            0x306E, 0x0005, 0x0321,
+            // invoke-static/range {v4 .. v6}, method@0100
+            0x0377, 0x0100, 0x0004,
+            // if-eqz v0, -3
+            0x0038, 0xFFFD,
+            // const-wide v0, #0x0123456789ABCDEF
+            0x0018, 0xCDEF, 0x89AB, 0x4567, 0x0123,
+            // add-int/lit8 v0, v1, #-2
+            0x00D8, 0xFE01,
+            // invoke-polymorphic {v1}, method@0002, proto@0003
+            0x10FA, 0x0002, 0x0001, 0x0003
+        ];
+        let instructions = decode_all(&insns).unwrap();
+
+        let offsets = instructions.iter().map(|insn| insn.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, vec!(0, 1, 3, 6, 9, 11, 16, 18));
+
+        assert_eq!(instructions[0].registers, vec!(1));
+        assert_eq!(instructions[0].literal, Some(-1));
+        assert_eq!(instructions[1].literal, Some(0x4000_0000_0000_0000));
+        assert_eq!(instructions[2].opcode, Opcode::INVOKE_VIRTUAL);
+        assert_eq!(instructions[2].registers, vec!(1, 2, 3));
+        assert_eq!(instructions[2].index, Some(5));
+        assert_eq!(instructions[3].registers, vec!(4, 5, 6));
+        assert_eq!(instructions[4].target(), Some(6));
+        assert_eq!(instructions[5].literal, Some(0x0123_4567_89AB_CDEF));
+        assert_eq!(instructions[6].registers, vec!(0, 1));
+        assert_eq!(instructions[6].literal, Some(-2));
+        assert_eq!(instructions[7].registers, vec!(1));
+        assert_eq!(instructions[7].proto_index, Some(3));
+    }
+
+    #[test]
+    fn test_decode_payloads() {
+        let insns = [
+            // packed-switch v0, +4
+            0x002B, 0x0004, 0x0000,
+            // nop, for alignment
+            0x0000,
+            // packed-switch-payload with keys 10 and 11
+            0x0100, 0x0002, 0x000A, 0x0000, 0x0010, 0x0000, 0x0012, 0x0000,
+            // fill-array-data-payload of three bytes
+            0x0300, 0x0001, 0x0003, 0x0000, 0x0201, 0x0003
+        ];
+        let instructions = decode_all(&insns).unwrap();
+        assert_eq!(instructions.len(), 4);
+
+        assert_eq!(instructions[0].target(), Some(4));
+        let payload = instructions[2].payload.as_ref().unwrap();
+        assert_eq!(payload.switch_cases(), vec!((10, 0x10), (11, 0x12)));
+        assert_eq!(instructions[3].payload, Some(Payload::FillArrayData { element_width: 1, data: vec!(1, 2, 3) }));
+    }
+
+    #[test]
+    fn test_encode() {
+        let insns = [
+            0xF112, 0x0219, 0x4000, 0x306E, 0x0005, 0x0321, 0x0377, 0x0100, 0x0004, 0x0038, 0xFFFD,
+            0x0018, 0xCDEF, 0x89AB, 0x4567, 0x0123, 0x00D8, 0xFE01, 0x10FA, 0x0002, 0x0001, 0x0003,
+            0x002B, 0x0004, 0x0000, 0x0000,
+            0x0100, 0x0002, 0x000A, 0x0000, 0x0010, 0x0000, 0x0012, 0x0000,
+            0x0300, 0x0001, 0x0003, 0x0000, 0x0201, 0x0003
+        ];
+        let encoded = decode_all(&insns).unwrap().iter()
+            .flat_map(|insn| encode(insn).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(encoded, insns.to_vec());
+
+        // const/4 v16
+        let mut insn = decode(&[0x0012], 0).unwrap();
+        insn.registers = vec!(16);
+        assert!(encode(&insn).is_err());
+        // const/high16 with low bits set
+        let mut insn = decode(&[0x0015, 0x0001], 0).unwrap();
+        insn.literal = Some(0x10001);
+        assert!(encode(&insn).is_err());
+        // invoke-static/range over registers that aren't contiguous
+        let mut insn = decode(&[0x0277, 0x0000, 0x0000], 0).unwrap();
+        insn.registers = vec!(0, 2);
+        assert!(encode(&insn).is_err());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        // unused opcode
+        assert!(decode(&[0x003E], 0).is_err());
+        // truncated const
+        assert!(decode(&[0x0014, 0x0000], 0).is_err());
+        // six arguments to an invoke
+        assert!(decode(&[0x606E, 0x0000, 0x0000], 0).is_err());
+        // a switch payload larger than the code
+        assert!(decode(&[0x0200, 0x0100, 0x0000], 0).is_err());
+    }
+}
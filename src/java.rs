@@ -0,0 +1,615 @@
+// Rendering of descriptors, members and classes the way Java source and reflection spell them
+//
+// `Display` on Field, Method and Prototype gives the erased form, e.g. `int com.foo.Bar.baz(java.lang.String[])`.
+// EncodedField and EncodedMethod add their modifiers, and ClassDefinition renders as a class declaration. The
+// generic signatures of fields live in `dalvik.annotation.Signature` annotations on the class that declares them,
+// so the declaration functions take the class alongside a member and render its generic form when it has one.
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::result_types::*;
+
+const SIGNATURE: &str = "Ldalvik/annotation/Signature;";
+
+// The Java name of a type descriptor: `I` is `int`, `[Ljava/lang/String;` is `java.lang.String[]`. Nested classes
+// keep their binary names, `com.foo.Outer$Inner`
+pub fn type_name(descriptor: &str) -> String {
+    let dimensions = descriptor.bytes().take_while(|&c| c == b'[').count();
+    let element = &descriptor[dimensions..];
+    let mut name = match element {
+        "V" => "void".to_string(),
+        "Z" => "boolean".to_string(),
+        "B" => "byte".to_string(),
+        "S" => "short".to_string(),
+        "C" => "char".to_string(),
+        "I" => "int".to_string(),
+        "J" => "long".to_string(),
+        "F" => "float".to_string(),
+        "D" => "double".to_string(),
+        _ if element.starts_with('L') && element.ends_with(';') => element[1 .. element.len() - 1].replace('/', "."),
+        // not a valid descriptor, shown as it is
+        _ => element.to_string()
+    };
+    for _ in 0 .. dimensions {
+        name.push_str("[]");
+    }
+    name
+}
+
+// The name of a class without its package, `Bar` for `Lcom/foo/Bar;`
+pub fn simple_name(descriptor: &str) -> String {
+    let name = type_name(descriptor);
+    match name.rfind('.') {
+        Some(i) => name[i + 1 ..].to_string(),
+        None => name
+    }
+}
+
+// The package of a type, `com.foo` for `Lcom/foo/Bar;` and for arrays of it. Primitives and classes in the default
+// package give the empty string
+pub fn package_name(descriptor: &str) -> String {
+    let element = descriptor.trim_start_matches('[');
+    if !element.starts_with('L') {
+        return String::new();
+    }
+    let name = type_name(element);
+    match name.rfind('.') {
+        Some(i) => name[.. i].to_string(),
+        None => String::new()
+    }
+}
+
+// The Java modifiers for a set of access flags, in the order java.lang.reflect.Modifier gives them. Flags that
+// have no keyword (bridge, varargs, synthetic, constructor, ...) are left out, and interface, annotation and enum
+// are part of a class declaration rather than its modifiers
+pub fn modifiers(flags: &[AccessFlag]) -> String {
+    modifier_words(flags).join(" ")
+}
+
+fn modifier_words(flags: &[AccessFlag]) -> Vec<&'static str> {
+    let has = |flag: AccessFlag| flags.contains(&flag);
+    let mut words = vec!();
+    if has(AccessFlag::ACC_PUBLIC) { words.push("public"); }
+    if has(AccessFlag::ACC_PROTECTED) { words.push("protected"); }
+    if has(AccessFlag::ACC_PRIVATE) { words.push("private"); }
+    if has(AccessFlag::ACC_ABSTRACT) { words.push("abstract"); }
+    if has(AccessFlag::ACC_STATIC) { words.push("static"); }
+    if has(AccessFlag::ACC_FINAL) { words.push("final"); }
+    if has(AccessFlag::ACC_TRANSIENT) { words.push("transient"); }
+    if has(AccessFlag::ACC_VOLATILE) { words.push("volatile"); }
+    // native methods keep ACC_SYNCHRONIZED, the others have it replaced with ACC_DECLARED_SYNCHRONIZED
+    if has(AccessFlag::ACC_SYNCHRONIZED) || has(AccessFlag::ACC_DECLARED_SYNCHRONIZED) { words.push("synchronized"); }
+    if has(AccessFlag::ACC_NATIVE) { words.push("native"); }
+    if has(AccessFlag::ACC_STRICT) { words.push("strictfp"); }
+    words
+}
+
+impl fmt::Display for Prototype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}){}", parameter_list(&self.parameters), type_name(&self.return_type))
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}.{}", type_name(&self.type_), type_name(&self.definer), self.name)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parameters = parameter_list(&self.prototype.parameters);
+        if is_constructor(self) {
+            write!(f, "{}({})", type_name(&self.definer), parameters)
+        } else {
+            write!(f, "{} {}.{}({})", type_name(&self.prototype.return_type), type_name(&self.definer), self.name,
+                   parameters)
+        }
+    }
+}
+
+impl fmt::Display for EncodedField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", prefix(&self.access_flags), self.field)
+    }
+}
+
+impl fmt::Display for EncodedMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", prefix(&self.access_flags), self.method)
+    }
+}
+
+impl fmt::Display for ClassDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&class_declaration(self))
+    }
+}
+
+// The declaration of a class, with its type parameters and generic supertypes when it has a signature:
+// `public final class com.foo.Bar<T> extends com.foo.Base<T> implements java.lang.Runnable`
+pub fn class_declaration(class: &ClassDefinition) -> String {
+    class_declaration_with(class, &class.access_flags, &type_name(&class.class_type), &mut |name| name.to_string())
+}
+
+// A class declaration with the given modifiers and name, the names of the other classes in it passed through `names`
+pub(crate) fn class_declaration_with(class: &ClassDefinition, flags: &[AccessFlag], name: &str,
+                                     names: &mut dyn FnMut(&str) -> String) -> String {
+    let is_interface = class.access_flags.contains(&AccessFlag::ACC_INTERFACE);
+    let is_annotation = class.access_flags.contains(&AccessFlag::ACC_ANNOTATION);
+    let is_enum = class.access_flags.contains(&AccessFlag::ACC_ENUM);
+
+    // interfaces are implicitly abstract and enums implicitly final, and ACC_SUPER shares its bit with synchronized
+    let mut declaration = modifier_words(flags).into_iter()
+        .filter(|&word| !((is_interface && word == "abstract") || (is_enum && word == "final") || word == "synchronized"))
+        .map(|word| format!("{} ", word))
+        .collect::<String>();
+    declaration.push_str(if is_annotation { "@interface" } else if is_interface { "interface" } else if is_enum { "enum" } else { "class" });
+    declaration.push(' ');
+    declaration.push_str(name);
+
+    // the superclasses every enum and interface has are left out, as is java.lang.Object
+    let implicit = match class.superclass.as_ref().map(|s| s.as_str()) {
+        None | Some("Ljava/lang/Object;") => true,
+        Some("Ljava/lang/Enum;") => is_enum,
+        _ => is_interface
+    };
+    let signature = class.system_annotations.signature.as_ref().and_then(|s| parse_class_signature_with(s, names));
+    let (superclass, interfaces) = match signature {
+        Some(signature) => {
+            declaration.push_str(&signature.type_parameters);
+            (signature.superclass, signature.interfaces)
+        },
+        None => {
+            let superclass = class.superclass.as_ref().map(|s| names(&type_name(s))).unwrap_or_default();
+            let interfaces = class.interfaces.iter()
+                .filter(|i| !(is_annotation && i.as_str() == "Ljava/lang/annotation/Annotation;"))
+                .map(|i| names(&type_name(i)))
+                .collect();
+            (superclass, interfaces)
+        }
+    };
+
+    if !implicit {
+        declaration.push_str(" extends ");
+        declaration.push_str(&superclass);
+    }
+    if !interfaces.is_empty() {
+        declaration.push_str(if is_interface { " extends " } else { " implements " });
+        declaration.push_str(&interfaces.join(", "));
+    }
+    declaration
+}
+
+// A field with its modifiers and, if it has one, its generic type: `private java.util.List<T> com.foo.Bar.items`
+pub fn field_declaration(class: &ClassDefinition, field: &EncodedField) -> String {
+    let type_ = field_signature(class, &field.field)
+        .and_then(|s| parse_field_signature(&s))
+        .unwrap_or_else(|| type_name(&field.field.type_));
+    format!("{}{} {}.{}", prefix(&field.access_flags), type_, type_name(&field.field.definer), field.field.name)
+}
+
+// A method with its modifiers, type parameters, generic parameter and return types and the exceptions it
+// declares: `public static <T> T com.foo.Bar.first(java.util.List<T>) throws java.io.IOException`
+pub fn method_declaration(class: &ClassDefinition, method: &EncodedMethod) -> String {
+    let m = &method.method;
+    let mut declaration = prefix(&method.access_flags);
+    if is_default_method(class, method) {
+        declaration.push_str("default ");
+    }
+
+    let signature = method.system_annotations.signature.as_ref().and_then(|s| parse_method_signature(s));
+    let (parameters, return_type, mut throws) = match signature {
+        Some(signature) => {
+            if !signature.type_parameters.is_empty() {
+                declaration.push_str(&signature.type_parameters);
+                declaration.push(' ');
+            }
+            (signature.parameters.join(","), signature.return_type, signature.throws)
+        },
+        None => (parameter_list(&m.prototype.parameters), type_name(&m.prototype.return_type), vec!())
+    };
+    // a signature only lists the exceptions when one of them is a type variable
+    if throws.is_empty() {
+        throws = method.system_annotations.throws.iter().map(|t| type_name(t)).collect();
+    }
+
+    if is_constructor(m) {
+        declaration.push_str(&format!("{}({})", type_name(&m.definer), parameters));
+    } else {
+        declaration.push_str(&format!("{} {}.{}({})", return_type, type_name(&m.definer), m.name, parameters));
+    }
+    if !throws.is_empty() {
+        declaration.push_str(" throws ");
+        declaration.push_str(&throws.join(","));
+    }
+    declaration
+}
+
+// The generic signature of a field declared by the class
+pub fn field_signature(class: &ClassDefinition, field: &Field) -> Option<String> {
+    class.annotations.as_ref()?.field_annotations.iter()
+        .find(|a| *a.field_data == *field)
+        .and_then(|a| signature_item(&a.annotations))
+}
+
+// A class signature (JVMS 4.7.9.1) in Java syntax
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClassSignature {
+    // e.g. `<K, V extends java.lang.Comparable<V>>`, empty if the class has none
+    pub type_parameters: String,
+    pub superclass: String,
+    pub interfaces: Vec<String>
+}
+
+// A method signature (JVMS 4.7.9.1) in Java syntax
+#[derive(Debug, PartialEq, Clone)]
+pub struct MethodSignature {
+    pub type_parameters: String,
+    pub parameters: Vec<String>,
+    pub return_type: String,
+    pub throws: Vec<String>
+}
+
+pub fn parse_class_signature(signature: &str) -> Option<ClassSignature> {
+    parse_class_signature_with(signature, &mut |name| name.to_string())
+}
+
+pub fn parse_method_signature(signature: &str) -> Option<MethodSignature> {
+    parse_method_signature_with(signature, &mut |name| name.to_string())
+}
+
+// A field signature is a single reference type
+pub fn parse_field_signature(signature: &str) -> Option<String> {
+    parse_field_signature_with(signature, &mut |name| name.to_string())
+}
+
+// The `_with` forms pass the name of every class the signature mentions, e.g. `java.util.Map` or
+// `com.foo.Outer$Inner`, through `names`, to render it some other way than fully qualified
+pub fn parse_class_signature_with(signature: &str, names: &mut dyn FnMut(&str) -> String) -> Option<ClassSignature> {
+    let mut reader = SignatureReader { s: signature.as_bytes(), pos: 0, names };
+    let type_parameters = reader.type_parameters()?;
+    let superclass = reader.class_type()?;
+    let mut interfaces = vec!();
+    while !reader.at_end() {
+        interfaces.push(reader.class_type()?);
+    }
+    Some(ClassSignature { type_parameters, superclass, interfaces })
+}
+
+pub fn parse_method_signature_with(signature: &str, names: &mut dyn FnMut(&str) -> String) -> Option<MethodSignature> {
+    let mut reader = SignatureReader { s: signature.as_bytes(), pos: 0, names };
+    let type_parameters = reader.type_parameters()?;
+    reader.expect(b'(')?;
+    let mut parameters = vec!();
+    while reader.peek()? != b')' {
+        parameters.push(reader.java_type()?);
+    }
+    reader.expect(b')')?;
+    let return_type = reader.java_type()?;
+    let mut throws = vec!();
+    while !reader.at_end() {
+        reader.expect(b'^')?;
+        throws.push(reader.reference_type()?);
+    }
+    Some(MethodSignature { type_parameters, parameters, return_type, throws })
+}
+
+pub fn parse_field_signature_with(signature: &str, names: &mut dyn FnMut(&str) -> String) -> Option<String> {
+    let mut reader = SignatureReader { s: signature.as_bytes(), pos: 0, names };
+    let type_ = reader.reference_type()?;
+    if reader.at_end() { Some(type_) } else { None }
+}
+
+struct SignatureReader<'a> {
+    s: &'a [u8],
+    pos: usize,
+    names: &'a mut dyn FnMut(&str) -> String
+}
+
+impl<'a> SignatureReader<'a> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.s.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        if self.next()? == c { Some(()) } else { None }
+    }
+
+    // An identifier runs up to the next character that has a meaning in signatures
+    fn identifier(&mut self, stop: &[u8]) -> Option<String> {
+        let start = self.pos;
+        while !self.at_end() && !stop.contains(&self.s[self.pos]) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        String::from_utf8(self.s[start .. self.pos].to_vec()).ok()
+    }
+
+    // `<T:Ljava/lang/Object;U::Ljava/lang/Comparable<TU;>;>`, rendered `<T, U extends java.lang.Comparable<U>>`
+    fn type_parameters(&mut self) -> Option<String> {
+        if self.peek() != Some(b'<') {
+            return Some(String::new());
+        }
+        self.pos += 1;
+        let mut parameters = vec!();
+        while self.peek()? != b'>' {
+            let name = self.identifier(b":")?;
+            let mut bounds = vec!();
+            // the class bound may be empty, the interface bounds may not
+            self.expect(b':')?;
+            if self.peek()? != b':' && self.peek()? != b'>' && !self.at_type_parameter_start() {
+                bounds.push(self.reference_type()?);
+            }
+            while self.peek()? == b':' {
+                self.pos += 1;
+                bounds.push(self.reference_type()?);
+            }
+            let object = (self.names)("java.lang.Object");
+            bounds.retain(|b| *b != object);
+            if bounds.is_empty() {
+                parameters.push(name);
+            } else {
+                parameters.push(format!("{} extends {}", name, bounds.join(" & ")));
+            }
+        }
+        self.pos += 1;
+        Some(format!("<{}>", parameters.join(", ")))
+    }
+
+    // Whether an empty class bound is followed straight away by the next type parameter, as in `<T:U::...>`.
+    // A type parameter name is the only thing that can be followed by a colon before any of `;<>`
+    fn at_type_parameter_start(&self) -> bool {
+        let rest = &self.s[self.pos ..];
+        match rest.iter().position(|&c| c == b':' || c == b';' || c == b'<' || c == b'>') {
+            Some(i) => rest[i] == b':',
+            None => false
+        }
+    }
+
+    fn java_type(&mut self) -> Option<String> {
+        let primitive = match self.peek()? {
+            b'V' => "void",
+            b'Z' => "boolean",
+            b'B' => "byte",
+            b'S' => "short",
+            b'C' => "char",
+            b'I' => "int",
+            b'J' => "long",
+            b'F' => "float",
+            b'D' => "double",
+            _ => return self.reference_type()
+        };
+        self.pos += 1;
+        Some(primitive.to_string())
+    }
+
+    fn reference_type(&mut self) -> Option<String> {
+        match self.peek()? {
+            b'L' => self.class_type(),
+            b'T' => {
+                self.pos += 1;
+                let name = self.identifier(b";")?;
+                self.expect(b';')?;
+                Some(name)
+            },
+            b'[' => {
+                self.pos += 1;
+                Some(format!("{}[]", self.java_type()?))
+            },
+            _ => None
+        }
+    }
+
+    // `Ljava/util/Map<TK;TV;>.Entry<TK;TV;>;`, rendered `java.util.Map<K,V>.Entry<K,V>`
+    fn class_type(&mut self) -> Option<String> {
+        self.expect(b'L')?;
+        let binary_name = self.identifier(b"<.;")?.replace('/', ".");
+        let mut name = (self.names)(&binary_name);
+        loop {
+            if self.peek()? == b'<' {
+                self.pos += 1;
+                let mut arguments = vec!();
+                while self.peek()? != b'>' {
+                    arguments.push(self.type_argument()?);
+                }
+                self.pos += 1;
+                name.push_str(&format!("<{}>", arguments.join(",")));
+            }
+            match self.next()? {
+                b';' => return Some(name),
+                b'.' => {
+                    name.push('.');
+                    name.push_str(&self.identifier(b"<.;")?);
+                },
+                _ => return None
+            }
+        }
+    }
+
+    fn type_argument(&mut self) -> Option<String> {
+        match self.peek()? {
+            b'*' => {
+                self.pos += 1;
+                Some("?".to_string())
+            },
+            b'+' => {
+                self.pos += 1;
+                Some(format!("? extends {}", self.reference_type()?))
+            },
+            b'-' => {
+                self.pos += 1;
+                Some(format!("? super {}", self.reference_type()?))
+            },
+            _ => self.reference_type()
+        }
+    }
+}
+
+fn prefix(flags: &[AccessFlag]) -> String {
+    let modifiers = modifiers(flags);
+    if modifiers.is_empty() { modifiers } else { modifiers + " " }
+}
+
+fn parameter_list(parameters: &[Rc<String>]) -> String {
+    parameters.iter().map(|p| type_name(p)).collect::<Vec<_>>().join(",")
+}
+
+pub(crate) fn is_constructor(method: &Method) -> bool {
+    *method.name == "<init>"
+}
+
+// A method with a body declared by an interface, other than a static or private one
+pub(crate) fn is_default_method(class: &ClassDefinition, method: &EncodedMethod) -> bool {
+    class.access_flags.contains(&AccessFlag::ACC_INTERFACE)
+        && !method.access_flags.iter().any(|f| matches!(f, AccessFlag::ACC_ABSTRACT | AccessFlag::ACC_STATIC | AccessFlag::ACC_PRIVATE))
+        && *method.method.name != "<clinit>"
+}
+
+// The value of a Signature annotation is the signature split into an array of strings
+fn signature_value(elements: &[AnnotationElement]) -> Option<String> {
+    let element = elements.iter().find(|e| *e.name == "value")?;
+    match element.value {
+        EncodedValue::Array(ref parts) => parts.iter().map(|p| match p {
+            EncodedValue::String(s) => Some(s.as_str()),
+            _ => None
+        }).collect::<Option<Vec<_>>>().map(|parts| parts.concat()),
+        _ => None
+    }
+}
+
+fn signature_item(annotations: &[AnnotationItem]) -> Option<String> {
+    annotations.iter()
+        .find(|a| *a.type_ == SIGNATURE)
+        .and_then(|a| signature_value(&a.annotations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn signature(b: &mut DexBuilder, parts: &[&str]) -> Vec<AnnotationElement> {
+        vec!(AnnotationElement {
+            name: b.string("value"),
+            value: EncodedValue::Array(parts.iter().map(|p| EncodedValue::String(b.string(p))).collect())
+        })
+    }
+
+    #[test]
+    fn test_erased() {
+        assert_eq!(type_name("[[J"), "long[][]");
+        assert_eq!(type_name("Lcom/foo/Outer$Inner;"), "com.foo.Outer$Inner");
+        assert_eq!(simple_name("Lcom/foo/Bar;"), "Bar");
+
+        let mut b = DexBuilder::new(35);
+        let method = EncodedMethod {
+            method: b.method("Lcom/foo/Bar;", "baz", "I", &["[Ljava/lang/String;"]),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_STATIC, AccessFlag::ACC_VARARGS,
+                               AccessFlag::ACC_DECLARED_SYNCHRONIZED),
+            code: None,
+            system_annotations: Default::default()
+        };
+        assert_eq!(method.to_string(), "public static synchronized int com.foo.Bar.baz(java.lang.String[])");
+        assert_eq!(method.method.prototype.to_string(), "(java.lang.String[])int");
+
+        let constructor = b.method("Lcom/foo/Bar;", "<init>", "V", &["I", "Z"]);
+        assert_eq!(constructor.to_string(), "com.foo.Bar(int,boolean)");
+
+        let field = EncodedField {
+            field: b.field("Lcom/foo/Bar;", "count", "J"),
+            access_flags: vec!(AccessFlag::ACC_PRIVATE, AccessFlag::ACC_VOLATILE)
+        };
+        assert_eq!(field.to_string(), "private volatile long com.foo.Bar.count");
+
+        let class = ClassDefinition {
+            class_type: b.type_("Lcom/foo/Bar;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_INTERFACE, AccessFlag::ACC_ABSTRACT),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(b.type_("Ljava/lang/Runnable;"), b.type_("Ljava/io/Closeable;")),
+            source_file_name: None,
+            annotations: None,
+            class_data: None,
+            static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        assert_eq!(class.to_string(), "public interface com.foo.Bar extends java.lang.Runnable, java.io.Closeable");
+    }
+
+    #[test]
+    fn test_generic() {
+        let mut b = DexBuilder::new(35);
+        let first = b.method("Lcom/foo/Box;", "first", "Ljava/lang/Object;", &["Ljava/util/List;"]);
+        let items = b.field("Lcom/foo/Box;", "items", "Ljava/util/Map;");
+        let signature_type = b.type_(SIGNATURE);
+
+        let class_signature = signature(&mut b, &["<T::", "Ljava/lang/Comparable<TT;>;", ">", "Ljava/lang/Object;",
+                                                  "Ljava/lang/Iterable<TT;>;"]);
+        let field_signature = signature(&mut b, &["Ljava/util/Map<", "Ljava/lang/String;", "+TT;>;"]);
+        let method_signature = signature(&mut b, &["<U:TT;>(", "Ljava/util/List<", "-TU;>;)TU;", "^TE;"]);
+        let mut class = ClassDefinition {
+            class_type: b.type_("Lcom/foo/Box;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_FINAL),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(b.type_("Ljava/lang/Iterable;")),
+            source_file_name: None,
+            annotations: Some(Annotations {
+                class_annotations: vec!(ClassAnnotation {
+                    visibility: Visibility::SYSTEM, type_: signature_type.clone(), elements: class_signature
+                }),
+                field_annotations: vec!(FieldAnnotation {
+                    field_data: items.clone(),
+                    annotations: vec!(AnnotationItem {
+                        visibility: Visibility::SYSTEM, type_: signature_type.clone(), annotations: field_signature
+                    })
+                }),
+                method_annotations: vec!(MethodAnnotation {
+                    method: first.clone(),
+                    annotations: vec!(AnnotationItem {
+                        visibility: Visibility::SYSTEM, type_: signature_type, annotations: method_signature
+                    })
+                }),
+                parameter_annotations: vec!()
+            }),
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(),
+                virtual_methods: vec!(EncodedMethod {
+                    method: first, access_flags: vec!(AccessFlag::ACC_PUBLIC), code: None, system_annotations: Default::default()
+                })
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        crate::parser::system_annotations::decode(&mut class);
+
+        assert_eq!(class.to_string(),
+                   "public final class com.foo.Box<T extends java.lang.Comparable<T>> implements java.lang.Iterable<T>");
+        let field = EncodedField { field: items, access_flags: vec!(AccessFlag::ACC_PRIVATE) };
+        assert_eq!(field_declaration(&class, &field),
+                   "private java.util.Map<java.lang.String,? extends T> com.foo.Box.items");
+        let method = &class.class_data.as_ref().unwrap().virtual_methods[0];
+        assert_eq!(method_declaration(&class, method),
+                   "public <U extends T> U com.foo.Box.first(java.util.List<? super U>) throws E");
+
+        assert_eq!(parse_field_signature("Ljava/util/Map<TK;TV;>.Entry<TK;[TV;>;"),
+                   Some("java.util.Map<K,V>.Entry<K,V[]>".to_string()));
+        assert_eq!(parse_class_signature("<K:V::Ljava/lang/Runnable;>Ljava/lang/Object;").unwrap().type_parameters,
+                   "<K, V extends java.lang.Runnable>");
+        assert_eq!(parse_method_signature("(Ljava/util/List<*>;"), None);
+    }
+}
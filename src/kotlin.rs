@@ -0,0 +1,1379 @@
+// Decoding of the kotlin.Metadata annotation the Kotlin compiler puts on every class it generates
+//
+// The annotation's d1 strings carry a protobuf message describing the Kotlin declarations behind the class, and
+// d2 the strings that message refers to. Decoding them recovers what the bytecode alone doesn't say: nullability,
+// suspend functions, data, sealed and value classes, properties with their accessors, extension receivers and the
+// top-level declarations of file facades. Every function and property keeps the JVM name and descriptor it was
+// compiled to, which is what links it back to the methods and fields of the ClassDefinition.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::error::DexParserError;
+use crate::result_types::*;
+
+const METADATA: &str = "Lkotlin/Metadata;";
+
+// Types are decoded recursively, and may refer to each other through the type table
+const MAX_TYPE_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinMetadata {
+    // the metadata version (mv), e.g. [1, 9, 0]
+    pub version: Vec<i32>,
+    // the extra flags (xi)
+    pub extra_flags: i32,
+    // the package name (pn), when it differs from the JVM package of the class
+    pub package_name: Option<String>,
+    pub declaration: KotlinDeclaration
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum KotlinDeclaration {
+    // k = 1, a class, interface, object or the like
+    Class(KotlinClass),
+    // k = 2, the top-level declarations of a source file, compiled to static members of a `FileNameKt` class
+    FileFacade(KotlinPackage),
+    // k = 3, a class the compiler generated. Lambdas carry the function they implement
+    SyntheticClass(Option<KotlinFunction>),
+    // k = 4, the facade of a @JvmMultifileClass, with the internal names of its parts
+    MultiFileClassFacade(Vec<String>),
+    // k = 5, one of the parts, with the internal name of its facade (xs)
+    MultiFileClassPart { package: KotlinPackage, facade: String },
+    // a kind this decoder doesn't know about
+    Unknown(i32)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KotlinVisibility {
+    Internal,
+    Private,
+    Protected,
+    Public,
+    PrivateToThis,
+    Local
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KotlinModality {
+    Final,
+    Open,
+    Abstract,
+    Sealed
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KotlinClassKind {
+    Class,
+    Interface,
+    EnumClass,
+    EnumEntry,
+    AnnotationClass,
+    Object,
+    CompanionObject
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KotlinVariance {
+    In,
+    Out,
+    Invariant
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinClass {
+    // the Kotlin name, packages separated by '/' and nested classes by '.': `com/foo/Outer.Inner`
+    pub name: String,
+    pub visibility: KotlinVisibility,
+    pub modality: KotlinModality,
+    pub kind: KotlinClassKind,
+    pub is_inner: bool,
+    pub is_data: bool,
+    pub is_external: bool,
+    pub is_expect: bool,
+    // a value (inline) class
+    pub is_value: bool,
+    pub is_fun_interface: bool,
+    pub type_parameters: Vec<KotlinTypeParameter>,
+    pub supertypes: Vec<KotlinType>,
+    pub constructors: Vec<KotlinConstructor>,
+    pub functions: Vec<KotlinFunction>,
+    pub properties: Vec<KotlinProperty>,
+    // simple names of the companion object and nested classes
+    pub companion_object: Option<String>,
+    pub nested_classes: Vec<String>,
+    pub enum_entries: Vec<String>,
+    // the Kotlin names of the direct subclasses of a sealed class
+    pub sealed_subclasses: Vec<String>
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct KotlinPackage {
+    pub functions: Vec<KotlinFunction>,
+    pub properties: Vec<KotlinProperty>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinConstructor {
+    pub visibility: KotlinVisibility,
+    pub is_secondary: bool,
+    pub parameters: Vec<KotlinParameter>,
+    pub jvm_signature: Option<JvmSignature>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinFunction {
+    pub name: String,
+    pub visibility: KotlinVisibility,
+    pub modality: KotlinModality,
+    pub is_operator: bool,
+    pub is_infix: bool,
+    pub is_inline: bool,
+    pub is_tailrec: bool,
+    pub is_external: bool,
+    pub is_suspend: bool,
+    pub type_parameters: Vec<KotlinTypeParameter>,
+    // the receiver of an extension function
+    pub receiver_type: Option<KotlinType>,
+    pub parameters: Vec<KotlinParameter>,
+    pub return_type: KotlinType,
+    // None if the function has no JVM method of its own, or its descriptor can't be worked out
+    pub jvm_signature: Option<JvmSignature>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinProperty {
+    pub name: String,
+    pub visibility: KotlinVisibility,
+    pub modality: KotlinModality,
+    pub is_var: bool,
+    pub is_const: bool,
+    pub is_lateinit: bool,
+    pub is_delegated: bool,
+    pub type_parameters: Vec<KotlinTypeParameter>,
+    pub receiver_type: Option<KotlinType>,
+    pub return_type: KotlinType,
+    // the backing field and accessors, for whichever the property has
+    pub field: Option<JvmSignature>,
+    pub getter: Option<JvmSignature>,
+    pub setter: Option<JvmSignature>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinParameter {
+    pub name: String,
+    pub type_: KotlinType,
+    // for a vararg parameter, the type of each element (type_ is then the array)
+    pub vararg_element_type: Option<KotlinType>,
+    pub declares_default_value: bool,
+    pub is_crossinline: bool,
+    pub is_noinline: bool
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinTypeParameter {
+    pub name: String,
+    pub is_reified: bool,
+    pub variance: KotlinVariance,
+    pub upper_bounds: Vec<KotlinType>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct KotlinType {
+    pub classifier: KotlinClassifier,
+    pub arguments: Vec<KotlinTypeArgument>,
+    pub is_nullable: bool,
+    // a suspend function type
+    pub is_suspend: bool
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum KotlinClassifier {
+    // a Kotlin class name, `kotlin/collections/List`
+    Class(String),
+    // the name of a type parameter in scope
+    TypeParameter(String),
+    TypeAlias(String)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum KotlinTypeArgument {
+    Star,
+    Type(KotlinVariance, KotlinType)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct JvmSignature {
+    pub name: String,
+    pub descriptor: String
+}
+
+// The Kotlin metadata of a class, or None if the class has no kotlin.Metadata annotation
+pub fn decode(class: &ClassDefinition) -> Result<Option<KotlinMetadata>, DexParserError> {
+    let annotation = class.annotations.as_ref()
+        .and_then(|annotations| annotations.class_annotations.iter().find(|a| *a.type_ == METADATA));
+    match annotation {
+        Some(annotation) => decode_annotation(&annotation.elements).map(Some),
+        None => Ok(None)
+    }
+}
+
+// Decodes the elements of a kotlin.Metadata annotation
+pub fn decode_annotation(elements: &[AnnotationElement]) -> Result<KotlinMetadata, DexParserError> {
+    let value = |name: &str| elements.iter().find(|e| *e.name == name).map(|e| &e.value);
+    let int = |name: &str, default: i32| match value(name) {
+        Some(EncodedValue::Int(i)) => *i,
+        _ => default
+    };
+    let string = |name: &str| match value(name) {
+        Some(EncodedValue::String(s)) if !s.is_empty() => Some(s.to_string()),
+        _ => None
+    };
+    let strings = |name: &str| match value(name) {
+        Some(EncodedValue::Array(values)) => values.iter().map(|v| match v {
+            EncodedValue::String(s) => Ok(s.to_string()),
+            _ => Err(DexParserError::from(format!("kotlin.Metadata {} holds a value that isn't a string", name)))
+        }).collect(),
+        _ => Ok(vec!())
+    };
+
+    let kind = int("k", 1);
+    let version = match value("mv") {
+        Some(EncodedValue::Array(values)) => values.iter().filter_map(|v| match v {
+            EncodedValue::Int(i) => Some(*i),
+            _ => None
+        }).collect(),
+        _ => vec!()
+    };
+    let d1 = strings("d1")?;
+    let d2 = strings("d2")?;
+
+    let declaration = match kind {
+        1 => {
+            let (strings, data) = read_data(&d1, d2)?;
+            let proto = Message::parse(&data)?;
+            KotlinDeclaration::Class(class(&Context::new(&strings, &proto)?, &proto)?)
+        },
+        2 => {
+            let (strings, data) = read_data(&d1, d2)?;
+            let proto = Message::parse(&data)?;
+            KotlinDeclaration::FileFacade(package(&Context::new(&strings, &proto)?, &proto)?)
+        },
+        3 if d1.is_empty() => KotlinDeclaration::SyntheticClass(None),
+        3 => {
+            let (strings, data) = read_data(&d1, d2)?;
+            let proto = Message::parse(&data)?;
+            let context = Context::new(&strings, &Message::default())?;
+            KotlinDeclaration::SyntheticClass(Some(function(&context, &proto)?))
+        },
+        4 => KotlinDeclaration::MultiFileClassFacade(d1),
+        5 => {
+            let (strings, data) = read_data(&d1, d2)?;
+            let proto = Message::parse(&data)?;
+            let package = package(&Context::new(&strings, &proto)?, &proto)?;
+            KotlinDeclaration::MultiFileClassPart { package, facade: string("xs").unwrap_or_default() }
+        },
+        _ => KotlinDeclaration::Unknown(kind)
+    };
+
+    Ok(KotlinMetadata { version, extra_flags: int("xi", 0), package_name: string("pn"), declaration })
+}
+
+impl KotlinClass {
+    // The type descriptor of the class on the JVM, `Lcom/foo/Outer$Inner;`
+    pub fn descriptor(&self) -> String {
+        format!("L{};", self.name.replace('.', "$"))
+    }
+}
+
+impl KotlinConstructor {
+    pub fn method<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedMethod> {
+        self.jvm_signature.as_ref()?.method(class)
+    }
+}
+
+impl KotlinFunction {
+    // The method the function was compiled to, in the class the metadata came from
+    pub fn method<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedMethod> {
+        self.jvm_signature.as_ref()?.method(class)
+    }
+}
+
+impl KotlinProperty {
+    pub fn backing_field<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedField> {
+        self.field.as_ref()?.field(class)
+    }
+
+    pub fn getter_method<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedMethod> {
+        self.getter.as_ref()?.method(class)
+    }
+
+    pub fn setter_method<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedMethod> {
+        self.setter.as_ref()?.method(class)
+    }
+}
+
+impl JvmSignature {
+    pub fn method<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedMethod> {
+        let class_data = class.class_data.as_ref()?;
+        class_data.direct_methods.iter().chain(class_data.virtual_methods.iter())
+            .find(|m| *m.method.name == self.name && method_descriptor(&m.method.prototype) == self.descriptor)
+    }
+
+    pub fn field<'a>(&self, class: &'a ClassDefinition) -> Option<&'a EncodedField> {
+        let class_data = class.class_data.as_ref()?;
+        class_data.static_fields.iter().chain(class_data.instance_fields.iter())
+            .find(|f| *f.field.name == self.name && *f.field.type_ == self.descriptor)
+    }
+}
+
+// `(ILjava/lang/String;)V`
+fn method_descriptor(prototype: &Prototype) -> String {
+    let mut descriptor = String::from("(");
+    for parameter in &prototype.parameters {
+        descriptor.push_str(parameter);
+    }
+    descriptor.push(')');
+    descriptor.push_str(&prototype.return_type);
+    descriptor
+}
+
+// Kotlin syntax with dotted names: `kotlin.collections.Map<kotlin.String, out T>?`
+impl fmt::Display for KotlinType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_suspend {
+            f.write_str("suspend ")?;
+        }
+        match self.classifier {
+            KotlinClassifier::Class(ref name) | KotlinClassifier::TypeAlias(ref name) => f.write_str(&name.replace('/', "."))?,
+            KotlinClassifier::TypeParameter(ref name) => f.write_str(name)?
+        }
+        if !self.arguments.is_empty() {
+            f.write_str("<")?;
+            for (i, argument) in self.arguments.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                match argument {
+                    KotlinTypeArgument::Star => f.write_str("*")?,
+                    KotlinTypeArgument::Type(KotlinVariance::In, type_) => write!(f, "in {}", type_)?,
+                    KotlinTypeArgument::Type(KotlinVariance::Out, type_) => write!(f, "out {}", type_)?,
+                    KotlinTypeArgument::Type(KotlinVariance::Invariant, type_) => write!(f, "{}", type_)?
+                }
+            }
+            f.write_str(">")?;
+        }
+        if self.is_nullable {
+            f.write_str("?")?;
+        }
+        Ok(())
+    }
+}
+
+//=============================
+// d1: string table and protobuf
+//=============================
+
+// d1 holds bytes spread over strings. Since Kotlin 1.4 every char is one byte after a leading NUL; before that,
+// the bytes were packed 7 bits at a time so they would survive as modified UTF-8
+fn decode_bytes(d1: &[String]) -> Vec<u8> {
+    let mut chars = d1.iter().flat_map(|s| s.chars()).peekable();
+    match chars.peek() {
+        Some('\u{0}') => return chars.skip(1).map(|c| c as u32 as u8).collect(),
+        // a marker some old compilers wrote ahead of 7-bit data
+        Some('\u{FFFF}') => { chars.next(); },
+        _ => {}
+    }
+
+    let packed = chars.map(|c| (c as u32 as u8).wrapping_add(0x7F) & 0x7F).collect::<Vec<_>>();
+    let mut bytes = Vec::with_capacity(packed.len() * 7 / 8);
+    let (mut index, mut bit) = (0, 0);
+    for _ in 0 .. packed.len() * 7 / 8 {
+        let low = packed[index] >> bit;
+        index += 1;
+        let high = (packed[index] & ((1 << (bit + 1)) - 1)) << (7 - bit);
+        bytes.push(low.wrapping_add(high));
+        if bit == 6 {
+            index += 1;
+            bit = 0;
+        } else {
+            bit += 1;
+        }
+    }
+    bytes
+}
+
+// The string table comes first as a length-delimited message, followed by the bytes of the declaration's message
+fn read_data(d1: &[String], d2: Vec<String>) -> Result<(Strings, Vec<u8>), DexParserError> {
+    let mut bytes = decode_bytes(d1);
+    let mut pos = 0;
+    let length = varint(&bytes, &mut pos)? as usize;
+    let table = bytes.get(pos .. pos.saturating_add(length)).ok_or("kotlin.Metadata string table overruns d1")?;
+    let strings = Strings::new(&Message::parse(table)?, d2)?;
+    Ok((strings, bytes.split_off(pos + length)))
+}
+
+fn varint(data: &[u8], pos: &mut usize) -> Result<u64, DexParserError> {
+    let mut value = 0_u64;
+    for shift in (0 .. 64).step_by(7) {
+        let byte = *data.get(*pos).ok_or("kotlin.Metadata protobuf ends inside a varint")?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DexParserError::from("kotlin.Metadata protobuf has a varint longer than 64 bits"))
+}
+
+#[derive(Debug, Clone)]
+enum Wire<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    // 32 and 64-bit values, which nothing here reads
+    Fixed
+}
+
+// The fields of a protobuf message in the order they appear. Nested messages are parsed when they are asked for
+#[derive(Debug, Clone, Default)]
+struct Message<'a> {
+    fields: Vec<(u32, Wire<'a>)>
+}
+
+impl<'a> Message<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, DexParserError> {
+        let mut fields = vec!();
+        let mut pos = 0;
+        while pos < data.len() {
+            let key = varint(data, &mut pos)?;
+            let wire = match key & 7 {
+                0 => Wire::Varint(varint(data, &mut pos)?),
+                1 | 5 => {
+                    pos += if key & 7 == 1 { 8 } else { 4 };
+                    Wire::Fixed
+                },
+                2 => {
+                    let length = varint(data, &mut pos)? as usize;
+                    let bytes = data.get(pos .. pos.saturating_add(length))
+                        .ok_or("kotlin.Metadata protobuf field overruns its message")?;
+                    pos += length;
+                    Wire::Bytes(bytes)
+                },
+                wire_type => {
+                    return Err(DexParserError::from(format!("kotlin.Metadata protobuf has unsupported wire type {}", wire_type)));
+                }
+            };
+            fields.push(((key >> 3) as u32, wire));
+        }
+        if pos > data.len() {
+            return Err(DexParserError::from("kotlin.Metadata protobuf field overruns its message"));
+        }
+        Ok(Message { fields })
+    }
+
+    fn values(&self, field: u32) -> impl Iterator<Item = &Wire<'a>> {
+        self.fields.iter().filter(move |(f, _)| *f == field).map(|(_, wire)| wire)
+    }
+
+    // The last value of a scalar field wins, as in protobuf
+    fn int(&self, field: u32) -> Option<i32> {
+        self.values(field).filter_map(|wire| match wire {
+            Wire::Varint(v) => Some(*v as i32),
+            _ => None
+        }).last()
+    }
+
+    fn bool(&self, field: u32) -> bool {
+        self.int(field).is_some_and(|v| v != 0)
+    }
+
+    // Repeated ints may be packed into one length-delimited value or sent one by one
+    fn ints(&self, field: u32) -> Result<Vec<i32>, DexParserError> {
+        let mut ints = vec!();
+        for wire in self.values(field) {
+            match wire {
+                Wire::Varint(v) => ints.push(*v as i32),
+                Wire::Bytes(bytes) => {
+                    let mut pos = 0;
+                    while pos < bytes.len() {
+                        ints.push(varint(bytes, &mut pos)? as i32);
+                    }
+                },
+                Wire::Fixed => {}
+            }
+        }
+        Ok(ints)
+    }
+
+    fn string(&self, field: u32) -> Result<Option<String>, DexParserError> {
+        match self.values(field).last() {
+            Some(Wire::Bytes(bytes)) => Ok(Some(String::from_utf8(bytes.to_vec())?)),
+            _ => Ok(None)
+        }
+    }
+
+    fn messages(&self, field: u32) -> Result<Vec<Message<'a>>, DexParserError> {
+        self.values(field).filter_map(|wire| match wire {
+            Wire::Bytes(bytes) => Some(Message::parse(bytes)),
+            _ => None
+        }).collect()
+    }
+
+    fn message(&self, field: u32) -> Result<Option<Message<'a>>, DexParserError> {
+        Ok(self.messages(field)?.pop())
+    }
+}
+
+// The strings kotlinc always has available, which string table records can refer to by index
+const PREDEFINED_STRINGS: [&str; 44] = [
+    "kotlin/Any", "kotlin/Nothing", "kotlin/Unit", "kotlin/Throwable", "kotlin/Number",
+    "kotlin/Byte", "kotlin/Double", "kotlin/Float", "kotlin/Int", "kotlin/Long", "kotlin/Short", "kotlin/Boolean",
+    "kotlin/Char", "kotlin/CharSequence", "kotlin/String", "kotlin/Comparable", "kotlin/Enum",
+    "kotlin/Array", "kotlin/ByteArray", "kotlin/DoubleArray", "kotlin/FloatArray", "kotlin/IntArray",
+    "kotlin/LongArray", "kotlin/ShortArray", "kotlin/BooleanArray", "kotlin/CharArray",
+    "kotlin/Cloneable", "kotlin/Annotation",
+    "kotlin/collections/Iterable", "kotlin/collections/MutableIterable",
+    "kotlin/collections/Collection", "kotlin/collections/MutableCollection",
+    "kotlin/collections/List", "kotlin/collections/MutableList",
+    "kotlin/collections/Set", "kotlin/collections/MutableSet",
+    "kotlin/collections/Map", "kotlin/collections/MutableMap",
+    "kotlin/collections/Map.Entry", "kotlin/collections/MutableMap.MutableEntry",
+    "kotlin/collections/Iterator", "kotlin/collections/MutableIterator",
+    "kotlin/collections/ListIterator", "kotlin/collections/MutableListIterator"
+];
+
+// A StringTableTypes record, saying how to get one string (or `range` consecutive ones) out of d2
+#[derive(Debug, Clone, Default)]
+struct Record {
+    string: Option<String>,
+    predefined_index: Option<i32>,
+    // 1 turns an internal name into a class id, 2 does the same to a descriptor
+    operation: i32,
+    substring_index: Vec<i32>,
+    replace_char: Vec<i32>
+}
+
+// Resolves the string indexes of the protobuf messages through the string table and d2
+struct Strings {
+    records: Vec<Record>,
+    d2: Vec<String>
+}
+
+impl Strings {
+    fn new(table: &Message, d2: Vec<String>) -> Result<Self, DexParserError> {
+        let messages = table.messages(1)?;
+        // a record only means something for an index of d2, or when it carries its own string
+        let limit = d2.len() + messages.len();
+        let mut records = vec!();
+        for message in messages {
+            let record = Record {
+                string: message.string(6)?,
+                predefined_index: message.int(2),
+                operation: message.int(3).unwrap_or(0),
+                substring_index: message.ints(4)?,
+                replace_char: message.ints(5)?
+            };
+            let range = message.int(1).unwrap_or(1).max(1) as usize;
+            if records.len() + range > limit {
+                return Err(DexParserError::from("kotlin.Metadata string table has more records than strings"));
+            }
+            records.extend(std::iter::repeat_n(record, range));
+        }
+        Ok(Strings { records, d2 })
+    }
+
+    fn get(&self, index: i32) -> Result<String, DexParserError> {
+        let missing = || DexParserError::from(format!("kotlin.Metadata refers to string {} that it doesn't have", index));
+        let index = usize::try_from(index).map_err(|_| missing())?;
+        let record = match self.records.get(index) {
+            Some(record) => record,
+            None => return self.d2.get(index).cloned().ok_or_else(missing)
+        };
+
+        let mut string = match (&record.string, record.predefined_index) {
+            (Some(s), _) => s.clone(),
+            (None, Some(i)) if (i as usize) < PREDEFINED_STRINGS.len() => PREDEFINED_STRINGS[i as usize].to_string(),
+            _ => self.d2.get(index).cloned().ok_or_else(missing)?
+        };
+        if let [begin, end] = record.substring_index[..] {
+            let (begin, end) = (begin.max(0) as usize, end.max(0) as usize);
+            if begin <= end {
+                string = string.chars().skip(begin).take(end - begin).collect();
+            }
+        }
+        if let [from, to] = record.replace_char[..] {
+            if let (Some(from), Some(to)) = (std::char::from_u32(from as u32), std::char::from_u32(to as u32)) {
+                string = string.replace(from, &to.to_string());
+            }
+        }
+        match record.operation {
+            1 => string = string.replace('$', "."),
+            2 => {
+                let length = string.chars().count();
+                if length >= 2 {
+                    string = string.chars().skip(1).take(length - 2).collect();
+                }
+                string = string.replace('$', ".");
+            },
+            _ => {}
+        }
+        Ok(string)
+    }
+}
+
+//=============================
+// declarations
+//=============================
+
+// What types are resolved against: the type table of the enclosing class or package, and the type parameters
+// in scope by id
+#[derive(Clone)]
+struct Context<'s, 'a> {
+    strings: &'s Strings,
+    types: Vec<Message<'a>>,
+    first_nullable: Option<usize>,
+    type_parameters: Vec<(i32, String)>
+}
+
+impl<'s, 'a> Context<'s, 'a> {
+    // The context of a class or package, or of a function with its own type table
+    fn new(strings: &'s Strings, proto: &Message<'a>) -> Result<Self, DexParserError> {
+        let mut context = Context { strings, types: vec!(), first_nullable: None, type_parameters: vec!() };
+        context.type_table(proto)?;
+        Ok(context)
+    }
+
+    fn type_table(&mut self, proto: &Message<'a>) -> Result<(), DexParserError> {
+        if let Some(table) = proto.message(30)? {
+            self.types = table.messages(1)?;
+            self.first_nullable = table.int(2).filter(|&i| i >= 0).map(|i| i as usize);
+        }
+        Ok(())
+    }
+
+    fn string(&self, index: Option<i32>) -> Result<String, DexParserError> {
+        self.strings.get(index.ok_or("kotlin.Metadata declaration has no name")?)
+    }
+
+    // A type given either inline or by its index in the type table
+    fn type_field(&self, proto: &Message<'a>, field: u32, id_field: u32, depth: usize)
+                  -> Result<Option<KotlinType>, DexParserError> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(DexParserError::from("kotlin.Metadata types nest too deeply"));
+        }
+        if let Some(message) = proto.message(field)? {
+            return self.type_(&message, depth + 1).map(Some);
+        }
+        match proto.int(id_field) {
+            Some(id) => self.table_type(id, depth + 1).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    // Types from index first_nullable on are nullable without saying so themselves
+    fn table_type(&self, id: i32, depth: usize) -> Result<KotlinType, DexParserError> {
+        let message = usize::try_from(id).ok().and_then(|id| self.types.get(id))
+            .ok_or_else(|| DexParserError::from(format!("kotlin.Metadata refers to type {} that it doesn't have", id)))?;
+        let mut type_ = self.type_(message, depth)?;
+        if self.first_nullable.is_some_and(|first| id as usize >= first) {
+            type_.is_nullable = true;
+        }
+        Ok(type_)
+    }
+
+    fn required_type(&self, proto: &Message<'a>, field: u32, id_field: u32) -> Result<KotlinType, DexParserError> {
+        self.type_field(proto, field, id_field, 0)?
+            .ok_or_else(|| DexParserError::from("kotlin.Metadata declaration has no type"))
+    }
+
+    fn type_(&self, proto: &Message<'a>, depth: usize) -> Result<KotlinType, DexParserError> {
+        let classifier = if let Some(name) = proto.int(6) {
+            KotlinClassifier::Class(self.strings.get(name)?)
+        } else if let Some(id) = proto.int(7) {
+            let name = self.type_parameters.iter().rev().find(|(i, _)| *i == id).map(|(_, name)| name.clone());
+            KotlinClassifier::TypeParameter(name.unwrap_or_else(|| format!("T{}", id)))
+        } else if let Some(name) = proto.int(9) {
+            KotlinClassifier::TypeParameter(self.strings.get(name)?)
+        } else if let Some(name) = proto.int(12) {
+            KotlinClassifier::TypeAlias(self.strings.get(name)?)
+        } else {
+            return Err(DexParserError::from("kotlin.Metadata type has no classifier"));
+        };
+
+        let mut arguments = vec!();
+        for argument in proto.messages(2)? {
+            arguments.push(match argument.int(1).unwrap_or(2) {
+                3 => KotlinTypeArgument::Star,
+                projection => {
+                    let type_ = self.type_field(&argument, 2, 3, depth)?
+                        .ok_or("kotlin.Metadata type argument has no type")?;
+                    KotlinTypeArgument::Type(variance(projection), type_)
+                }
+            });
+        }
+
+        Ok(KotlinType {
+            classifier,
+            arguments,
+            is_nullable: proto.bool(3),
+            is_suspend: proto.int(1).unwrap_or(0) & 1 != 0
+        })
+    }
+
+    // Adds the type parameters to the scope before decoding their bounds, which may refer to them
+    fn type_parameters(&mut self, proto: &Message<'a>, field: u32) -> Result<Vec<KotlinTypeParameter>, DexParserError> {
+        let messages = proto.messages(field)?;
+        let mut names = vec!();
+        for message in &messages {
+            let name = self.string(message.int(2))?;
+            self.type_parameters.push((message.int(1).unwrap_or(-1), name.clone()));
+            names.push(name);
+        }
+
+        let mut parameters = vec!();
+        for (message, name) in messages.iter().zip(names) {
+            let mut upper_bounds = vec!();
+            for bound in message.messages(5)? {
+                upper_bounds.push(self.type_(&bound, 1)?);
+            }
+            for id in message.ints(6)? {
+                upper_bounds.push(self.table_type(id, 1)?);
+            }
+            parameters.push(KotlinTypeParameter {
+                name,
+                is_reified: message.bool(3),
+                variance: variance(message.int(4).unwrap_or(2)),
+                upper_bounds
+            });
+        }
+        Ok(parameters)
+    }
+
+    fn parameters(&self, proto: &Message<'a>, field: u32) -> Result<Vec<KotlinParameter>, DexParserError> {
+        proto.messages(field)?.iter().map(|message| {
+            let flags = message.int(1).unwrap_or(0);
+            Ok(KotlinParameter {
+                name: self.string(message.int(2))?,
+                type_: self.required_type(message, 3, 5)?,
+                vararg_element_type: self.type_field(message, 4, 6, 0)?,
+                declares_default_value: flag(flags, 1),
+                is_crossinline: flag(flags, 2),
+                is_noinline: flag(flags, 3)
+            })
+        }).collect()
+    }
+
+    // A JvmMethodSignature, with whatever it leaves out taken from the defaults
+    fn method_signature(&self, signature: Option<&Message<'a>>, name: &str, default_descriptor: Option<String>)
+                        -> Result<Option<JvmSignature>, DexParserError> {
+        let name = match signature.and_then(|s| s.int(1)) {
+            Some(index) => self.strings.get(index)?,
+            None => name.to_string()
+        };
+        let descriptor = match signature.and_then(|s| s.int(2)) {
+            Some(index) => Some(self.strings.get(index)?),
+            None => default_descriptor
+        };
+        Ok(descriptor.map(|descriptor| JvmSignature { name, descriptor }))
+    }
+}
+
+fn flag(flags: i32, bit: u32) -> bool {
+    flags & (1 << bit) != 0
+}
+
+fn visibility(flags: i32) -> KotlinVisibility {
+    match (flags >> 1) & 7 {
+        0 => KotlinVisibility::Internal,
+        1 => KotlinVisibility::Private,
+        2 => KotlinVisibility::Protected,
+        4 => KotlinVisibility::PrivateToThis,
+        5 => KotlinVisibility::Local,
+        _ => KotlinVisibility::Public
+    }
+}
+
+fn modality(flags: i32) -> KotlinModality {
+    match (flags >> 4) & 3 {
+        0 => KotlinModality::Final,
+        1 => KotlinModality::Open,
+        2 => KotlinModality::Abstract,
+        _ => KotlinModality::Sealed
+    }
+}
+
+fn variance(value: i32) -> KotlinVariance {
+    match value {
+        0 => KotlinVariance::In,
+        1 => KotlinVariance::Out,
+        _ => KotlinVariance::Invariant
+    }
+}
+
+// The JVM descriptor kotlinc assumes for a type when it leaves a signature out, ignoring nullability and type
+// arguments. None for type parameters, whose signatures are always written out
+fn default_descriptor(type_: &KotlinType) -> Option<String> {
+    let name = match type_.classifier {
+        KotlinClassifier::Class(ref name) => name.as_str(),
+        _ => return None
+    };
+    let primitive = match name {
+        "kotlin/Unit" => Some("V"),
+        "kotlin/Boolean" => Some("Z"),
+        "kotlin/Char" => Some("C"),
+        "kotlin/Byte" => Some("B"),
+        "kotlin/Short" => Some("S"),
+        "kotlin/Int" => Some("I"),
+        "kotlin/Float" => Some("F"),
+        "kotlin/Long" => Some("J"),
+        "kotlin/Double" => Some("D"),
+        "kotlin/BooleanArray" => Some("[Z"),
+        "kotlin/CharArray" => Some("[C"),
+        "kotlin/ByteArray" => Some("[B"),
+        "kotlin/ShortArray" => Some("[S"),
+        "kotlin/IntArray" => Some("[I"),
+        "kotlin/FloatArray" => Some("[F"),
+        "kotlin/LongArray" => Some("[J"),
+        "kotlin/DoubleArray" => Some("[D"),
+        _ => None
+    };
+    if let Some(primitive) = primitive {
+        return Some(primitive.to_string());
+    }
+
+    let java = match name {
+        "kotlin/Any" => "java/lang/Object".to_string(),
+        "kotlin/Nothing" => "java/lang/Void".to_string(),
+        "kotlin/Annotation" => "java/lang/annotation/Annotation".to_string(),
+        "kotlin/String" | "kotlin/CharSequence" | "kotlin/Throwable" | "kotlin/Cloneable" | "kotlin/Number"
+            | "kotlin/Comparable" | "kotlin/Enum" => format!("java/lang/{}", &name[7..]),
+        "kotlin/collections/Iterable" | "kotlin/collections/MutableIterable" => "java/lang/Iterable".to_string(),
+        "kotlin/collections/Map.Entry" | "kotlin/collections/MutableMap.MutableEntry" => "java/util/Map$Entry".to_string(),
+        _ if name.starts_with("kotlin/collections/") => {
+            let simple = name["kotlin/collections/".len() ..].trim_start_matches("Mutable");
+            match simple {
+                "Iterator" | "Collection" | "List" | "Set" | "Map" | "ListIterator" => format!("java/util/{}", simple),
+                _ => name.replace('.', "$")
+            }
+        },
+        _ if name.starts_with("kotlin/Function") && name[15..].parse::<u8>().is_ok_and(|n| n <= 22) =>
+            format!("kotlin/jvm/functions/{}", &name[7..]),
+        _ if name.starts_with("kotlin/reflect/KFunction") && name[24..].parse::<u8>().is_ok_and(|n| n <= 22) =>
+            "kotlin/reflect/KFunction".to_string(),
+        "kotlin/Char.Companion" | "kotlin/Byte.Companion" | "kotlin/Short.Companion" | "kotlin/Int.Companion"
+            | "kotlin/Float.Companion" | "kotlin/Long.Companion" | "kotlin/Double.Companion"
+            | "kotlin/String.Companion" | "kotlin/Enum.Companion" =>
+            format!("kotlin/jvm/internal/{}CompanionObject", &name[7 .. name.len() - ".Companion".len()]),
+        _ => name.replace('.', "$")
+    };
+    Some(format!("L{};", java))
+}
+
+// `(` receiver and parameters `)` return type, if all of them have default descriptors
+fn default_method_descriptor(receiver: Option<&KotlinType>, parameters: &[KotlinParameter], return_type: &str)
+                             -> Option<String> {
+    let mut descriptor = String::from("(");
+    for type_ in receiver.into_iter().chain(parameters.iter().map(|p| &p.type_)) {
+        descriptor.push_str(&default_descriptor(type_)?);
+    }
+    descriptor.push(')');
+    descriptor.push_str(return_type);
+    Some(descriptor)
+}
+
+fn class(context: &Context, proto: &Message) -> Result<KotlinClass, DexParserError> {
+    let mut context = context.clone();
+    let flags = proto.int(1).unwrap_or(6);
+    let type_parameters = context.type_parameters(proto, 5)?;
+
+    let mut supertypes = vec!();
+    for supertype in proto.messages(6)? {
+        supertypes.push(context.type_(&supertype, 0)?);
+    }
+    if supertypes.is_empty() {
+        for id in proto.ints(2)? {
+            supertypes.push(context.table_type(id, 0)?);
+        }
+    }
+
+    let mut constructors = vec!();
+    for constructor in proto.messages(8)? {
+        let flags = constructor.int(1).unwrap_or(6);
+        let parameters = context.parameters(&constructor, 2)?;
+        let default = default_method_descriptor(None, &parameters, "V");
+        constructors.push(KotlinConstructor {
+            visibility: visibility(flags),
+            is_secondary: flag(flags, 4),
+            jvm_signature: context.method_signature(constructor.message(100)?.as_ref(), "<init>", default)?,
+            parameters
+        });
+    }
+
+    let (functions, properties) = members(&context, proto, 9, 10)?;
+    let names = |field| -> Result<Vec<String>, DexParserError> {
+        proto.ints(field)?.into_iter().map(|i| context.strings.get(i)).collect()
+    };
+    let mut enum_entries = vec!();
+    for entry in proto.messages(13)? {
+        enum_entries.push(context.string(entry.int(1))?);
+    }
+
+    Ok(KotlinClass {
+        name: context.string(proto.int(3))?,
+        visibility: visibility(flags),
+        modality: modality(flags),
+        kind: match (flags >> 6) & 7 {
+            1 => KotlinClassKind::Interface,
+            2 => KotlinClassKind::EnumClass,
+            3 => KotlinClassKind::EnumEntry,
+            4 => KotlinClassKind::AnnotationClass,
+            5 => KotlinClassKind::Object,
+            6 => KotlinClassKind::CompanionObject,
+            _ => KotlinClassKind::Class
+        },
+        is_inner: flag(flags, 9),
+        is_data: flag(flags, 10),
+        is_external: flag(flags, 11),
+        is_expect: flag(flags, 12),
+        is_value: flag(flags, 13),
+        is_fun_interface: flag(flags, 14),
+        type_parameters,
+        supertypes,
+        constructors,
+        functions,
+        properties,
+        companion_object: match proto.int(4) {
+            Some(name) => Some(context.strings.get(name)?),
+            None => None
+        },
+        nested_classes: names(7)?,
+        enum_entries,
+        sealed_subclasses: names(16)?
+    })
+}
+
+fn package(context: &Context, proto: &Message) -> Result<KotlinPackage, DexParserError> {
+    let (functions, properties) = members(context, proto, 3, 4)?;
+    Ok(KotlinPackage { functions, properties })
+}
+
+fn members(context: &Context, proto: &Message, function_field: u32, property_field: u32)
+           -> Result<(Vec<KotlinFunction>, Vec<KotlinProperty>), DexParserError> {
+    let functions = proto.messages(function_field)?.iter().map(|f| function(context, f)).collect::<Result<_, _>>()?;
+    let properties = proto.messages(property_field)?.iter().map(|p| property(context, p)).collect::<Result<_, _>>()?;
+    Ok((functions, properties))
+}
+
+fn function(context: &Context, proto: &Message) -> Result<KotlinFunction, DexParserError> {
+    let mut context = context.clone();
+    context.type_table(proto)?;
+    let flags = proto.int(9).unwrap_or(6);
+    let name = context.string(proto.int(2))?;
+    let type_parameters = context.type_parameters(proto, 4)?;
+    let receiver_type = context.type_field(proto, 5, 8, 0)?;
+    let parameters = context.parameters(proto, 6)?;
+    let return_type = context.required_type(proto, 3, 7)?;
+
+    let default = default_descriptor(&return_type)
+        .and_then(|r| default_method_descriptor(receiver_type.as_ref(), &parameters, &r));
+    let jvm_signature = context.method_signature(proto.message(100)?.as_ref(), &name, default)?;
+    Ok(KotlinFunction {
+        name,
+        visibility: visibility(flags),
+        modality: modality(flags),
+        is_operator: flag(flags, 8),
+        is_infix: flag(flags, 9),
+        is_inline: flag(flags, 10),
+        is_tailrec: flag(flags, 11),
+        is_external: flag(flags, 12),
+        is_suspend: flag(flags, 13),
+        type_parameters,
+        receiver_type,
+        parameters,
+        return_type,
+        jvm_signature
+    })
+}
+
+fn property(context: &Context, proto: &Message) -> Result<KotlinProperty, DexParserError> {
+    let mut context = context.clone();
+    let flags = proto.int(11).unwrap_or(518);
+    let name = context.string(proto.int(2))?;
+    let type_parameters = context.type_parameters(proto, 4)?;
+    let receiver_type = context.type_field(proto, 5, 10, 0)?;
+    let return_type = context.required_type(proto, 3, 9)?;
+
+    // the field and accessors are only there if the property signature says so
+    let signature = proto.message(100)?;
+    let part = |field| -> Result<Option<Message>, DexParserError> {
+        match signature {
+            Some(ref signature) => signature.message(field),
+            None => Ok(None)
+        }
+    };
+    let field = match part(1)? {
+        Some(field) => context.method_signature(Some(&field), &name, default_descriptor(&return_type))?,
+        None => None
+    };
+    // accessors always have their name and descriptor written out
+    let accessor = |message: Option<Message>| match message {
+        Some(message) if message.int(1).is_some() => context.method_signature(Some(&message), "", None),
+        _ => Ok(None)
+    };
+    let getter = accessor(part(3)?)?;
+    let setter = accessor(part(4)?)?;
+
+    Ok(KotlinProperty {
+        name,
+        visibility: visibility(flags),
+        modality: modality(flags),
+        is_var: flag(flags, 8),
+        is_const: flag(flags, 11),
+        is_lateinit: flag(flags, 12),
+        is_delegated: flag(flags, 15),
+        type_parameters,
+        receiver_type,
+        return_type,
+        field,
+        getter,
+        setter
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+    use crate::options::ParseOptions;
+
+    fn put_varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn int(field: u32, value: i32) -> Vec<u8> {
+        let mut out = vec!();
+        put_varint(u64::from(field << 3), &mut out);
+        put_varint(value as u64, &mut out);
+        out
+    }
+
+    fn message(field: u32, parts: &[Vec<u8>]) -> Vec<u8> {
+        let body = parts.concat();
+        let mut out = vec!();
+        put_varint(u64::from(field << 3 | 2), &mut out);
+        put_varint(body.len() as u64, &mut out);
+        out.extend(body);
+        out
+    }
+
+    // The string table, length-delimited, then the declaration
+    fn data(records: &[Vec<u8>], declaration: &[Vec<u8>]) -> Vec<u8> {
+        let table = records.iter().map(|r| message(1, std::slice::from_ref(r))).collect::<Vec<_>>().concat();
+        let mut out = vec!();
+        put_varint(table.len() as u64, &mut out);
+        out.extend(table);
+        out.extend(declaration.concat());
+        out
+    }
+
+    fn metadata(b: &mut DexBuilder, kind: i32, d1: Vec<String>, d2: &[&str]) -> ClassAnnotation {
+        let mut element = |name: &str, value| AnnotationElement { name: b.string(name), value };
+        let elements = vec!(
+            element("k", EncodedValue::Int(kind)),
+            element("mv", EncodedValue::Array(vec!(EncodedValue::Int(1), EncodedValue::Int(9), EncodedValue::Int(0))))
+        );
+        let d1 = d1.iter().map(|s| EncodedValue::String(b.string(s))).collect();
+        let d2 = d2.iter().map(|s| EncodedValue::String(b.string(s))).collect();
+        let mut elements = elements;
+        elements.push(AnnotationElement { name: b.string("d1"), value: EncodedValue::Array(d1) });
+        elements.push(AnnotationElement { name: b.string("d2"), value: EncodedValue::Array(d2) });
+        ClassAnnotation { visibility: Visibility::RUNTIME, type_: b.type_(METADATA), elements }
+    }
+
+    fn class(b: &mut DexBuilder, name: &str, annotation: ClassAnnotation, fields: Vec<EncodedField>,
+             methods: Vec<EncodedMethod>) -> ClassDefinition {
+        ClassDefinition {
+            class_type: b.type_(name),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_FINAL),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: Some(Annotations {
+                class_annotations: vec!(annotation),
+                field_annotations: vec!(),
+                method_annotations: vec!(),
+                parameter_annotations: vec!()
+            }),
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: fields,
+                direct_methods: vec!(),
+                virtual_methods: methods
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        }
+    }
+
+    fn method(method: std::rc::Rc<Method>) -> EncodedMethod {
+        EncodedMethod { method, access_flags: vec!(AccessFlag::ACC_PUBLIC), code: None, system_annotations: Default::default() }
+    }
+
+    // data class Point<out T>(val x: Int) : Any() { class Nested; suspend fun load(id: String?): List<out T> }
+    #[test]
+    fn test_class() {
+        let d2 = ["", "Lcom/example/Point;", "T", "x", "load", "id", "kotlin/String", "kotlin/collections/List",
+                  "(Ljava/lang/String;Lkotlin/coroutines/Continuation;)Ljava/lang/Object;", "getX", "()I", "Nested",
+                  "kotlin/Any"];
+        let records = [int(2, 8), int(3, 2)];
+        let declaration = [
+            int(1, (1 << 10) | 6),
+            int(3, 1),
+            message(5, &[int(1, 0), int(2, 2), int(4, 1)]),
+            message(6, &[int(6, 12)]),
+            message(7, &[vec!(11)]),
+            message(8, &[message(2, &[int(2, 3), message(3, &[int(6, 0)])])]),
+            message(10, &[int(2, 3), int(9, 0), message(100, &[message(1, &[]), message(3, &[int(1, 9), int(2, 10)])])]),
+            message(9, &[
+                int(9, (1 << 13) | 6),
+                int(2, 4),
+                message(6, &[int(2, 5), int(5, 1)]),
+                message(3, &[int(6, 7), message(2, &[int(1, 1), message(2, &[int(7, 0)])])]),
+                message(100, &[int(2, 8)])
+            ]),
+            message(30, &[message(1, &[int(6, 0)]), message(1, &[int(6, 6)]), int(2, 1)])
+        ];
+        let d1 = std::iter::once('\u{0}').chain(data(&records, &declaration).into_iter().map(char::from)).collect();
+
+        let mut b = DexBuilder::new(35);
+        let annotation = metadata(&mut b, 1, vec!(d1), &d2);
+        let x = b.field("Lcom/example/Point;", "x", "I");
+        let fields = vec!(EncodedField { field: x, access_flags: vec!(AccessFlag::ACC_PRIVATE) });
+        let methods = vec!(
+            method(b.method("Lcom/example/Point;", "<init>", "V", &["I"])),
+            method(b.method("Lcom/example/Point;", "getX", "I", &[])),
+            method(b.method("Lcom/example/Point;", "load", "Ljava/lang/Object;",
+                            &["Ljava/lang/String;", "Lkotlin/coroutines/Continuation;"]))
+        );
+        let class = class(&mut b, "Lcom/example/Point;", annotation, fields, methods);
+        b.class(class);
+        // d1 has to survive being written out as modified UTF-8
+        let bytes = crate::writer::write(&b.build().unwrap()).unwrap();
+        let file = crate::parser::parse(&bytes, ParseOptions::default()).unwrap();
+        let class = &file.classes[0];
+
+        let metadata = decode(class).unwrap().unwrap();
+        assert_eq!(metadata.version, vec!(1, 9, 0));
+        let kotlin = match metadata.declaration {
+            KotlinDeclaration::Class(kotlin) => kotlin,
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(kotlin.name, "com/example/Point");
+        assert_eq!(kotlin.descriptor(), "Lcom/example/Point;");
+        assert!(kotlin.is_data && !kotlin.is_inner);
+        assert_eq!((kotlin.visibility, kotlin.modality, kotlin.kind),
+                   (KotlinVisibility::Public, KotlinModality::Final, KotlinClassKind::Class));
+        assert_eq!(kotlin.type_parameters[0].name, "T");
+        assert_eq!(kotlin.type_parameters[0].variance, KotlinVariance::Out);
+        assert_eq!(kotlin.supertypes[0].to_string(), "kotlin.Any");
+        assert_eq!(kotlin.nested_classes, vec!("Nested"));
+
+        let constructor = &kotlin.constructors[0];
+        assert!(!constructor.is_secondary);
+        assert_eq!(constructor.parameters[0].type_.to_string(), "kotlin.Int");
+        assert_eq!(*constructor.method(class).unwrap().method.name, "<init>");
+
+        let x = &kotlin.properties[0];
+        assert!(!x.is_var);
+        assert_eq!(x.return_type.to_string(), "kotlin.Int");
+        assert_eq!(x.field, Some(JvmSignature { name: "x".to_string(), descriptor: "I".to_string() }));
+        assert!(x.backing_field(class).is_some());
+        assert_eq!(*x.getter_method(class).unwrap().method.name, "getX");
+        assert!(x.setter.is_none());
+
+        let load = &kotlin.functions[0];
+        assert!(load.is_suspend && !load.is_inline);
+        assert_eq!(load.parameters[0].name, "id");
+        assert_eq!(load.parameters[0].type_.to_string(), "kotlin.String?");
+        assert_eq!(load.return_type.to_string(), "kotlin.collections.List<out T>");
+        assert_eq!(*load.method(class).unwrap().method.name, "load");
+    }
+
+    // Packs bytes 7 bits at a time the way kotlinc did before 1.4
+    fn encode_7_bit(bytes: &[u8]) -> String {
+        let bits = bytes.iter().flat_map(|b| (0 .. 8).map(move |i| (b >> i) & 1)).collect::<Vec<_>>();
+        bits.chunks(7)
+            .map(|chunk| chunk.iter().enumerate().fold(0_u8, |acc, (i, bit)| acc | (bit << i)))
+            .map(|group| char::from((group + 1) & 0x7F))
+            .collect()
+    }
+
+    // fun String.shout(times: Int = 1): String, top-level in ShoutKt, with no JVM signature written out
+    #[test]
+    fn test_file_facade() {
+        let d2 = ["shout", "times"];
+        let declaration = [message(3, &[
+            int(2, 0),
+            message(5, &[int(6, 2)]),
+            message(6, &[int(1, 1 << 1), int(2, 1), message(3, &[int(6, 3)])]),
+            message(3, &[int(6, 2)])
+        ])];
+        // kotlin/String and kotlin/Int are predefined, at indexes 2 and 3 of the string table
+        let records = [int(1, 2), int(2, 14), int(2, 8)];
+        let bytes = data(&records, &declaration);
+        assert_eq!(decode_bytes(&[encode_7_bit(&bytes)]), bytes);
+
+        let mut b = DexBuilder::new(35);
+        let annotation = metadata(&mut b, 2, vec!(encode_7_bit(&bytes)), &d2);
+        let shout = b.method("LShoutKt;", "shout", "Ljava/lang/String;", &["Ljava/lang/String;", "I"]);
+        let class = class(&mut b, "LShoutKt;", annotation, vec!(), vec!(method(shout)));
+
+        let package = match decode(&class).unwrap().unwrap().declaration {
+            KotlinDeclaration::FileFacade(package) => package,
+            other => panic!("{:?}", other)
+        };
+        let shout = &package.functions[0];
+        assert_eq!(shout.receiver_type.as_ref().unwrap().to_string(), "kotlin.String");
+        assert!(shout.parameters[0].declares_default_value);
+        assert_eq!(shout.jvm_signature, Some(JvmSignature {
+            name: "shout".to_string(),
+            descriptor: "(Ljava/lang/String;I)Ljava/lang/String;".to_string()
+        }));
+        assert!(shout.method(&class).is_some());
+
+        // a reference past the end of the string table and d2
+        let truncated = data(&records, &[message(3, &[int(2, 9), message(3, &[int(6, 2)])])]);
+        let annotation = metadata(&mut b, 2, vec!(encode_7_bit(&truncated)), &d2);
+        assert!(decode_annotation(&annotation.elements).is_err());
+    }
+
+    #[test]
+    fn test_decode_bytes() {
+        let bytes = (0 ..= 255).collect::<Vec<u8>>();
+
+        // since 1.4: a NUL, then one char per byte, over as many strings as it takes
+        let chars = std::iter::once('\u{0}').chain(bytes.iter().cloned().map(char::from)).collect::<String>();
+        let (first, second) = chars.split_at(chars.char_indices().nth(100).unwrap().0);
+        assert_eq!(decode_bytes(&[first.to_string(), second.to_string()]), bytes);
+
+        // before 1.4: 7 bits per char, with or without the 0xFFFF marker
+        let packed = encode_7_bit(&bytes);
+        assert!(packed.chars().all(|c| (c as u32) < 0x80));
+        let (first, second) = packed.split_at(37);
+        assert_eq!(decode_bytes(&[first.to_string(), second.to_string()]), bytes);
+        assert_eq!(decode_bytes(&[format!("\u{FFFF}{}", packed)]), bytes);
+        assert_eq!(decode_bytes(&[]), vec!());
+    }
+
+    fn strings(records: &[Vec<u8>], d2: &[&str]) -> Result<Strings, DexParserError> {
+        let table = records.iter().map(|r| message(1, std::slice::from_ref(r))).collect::<Vec<_>>().concat();
+        Strings::new(&Message::parse(&table)?, d2.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn test_string_table() {
+        let d2 = ["Lcom/example/Outer$Inner;", "com/example/Outer$Inner", "abcdef", "a$b", "", "", "one", "two",
+                  "tail"];
+        let records = [
+            int(3, 2),
+            int(3, 1),
+            message(4, &[vec!(1, 4)]),
+            message(5, &[vec!(b'$', b'.')]),
+            int(2, 14),
+            message(6, &[b"own".to_vec()]),
+            // one record for the two strings after it
+            int(1, 2)
+        ];
+        let strings = strings(&records, &d2).unwrap();
+        let all = (0 .. d2.len() as i32).map(|i| strings.get(i).unwrap()).collect::<Vec<_>>();
+        assert_eq!(all, vec!("com/example/Outer.Inner", "com/example/Outer.Inner", "bcd", "a.b", "kotlin/String", "own",
+                             "one", "two", "tail"));
+        assert!(strings.get(-1).is_err());
+        assert!(strings.get(d2.len() as i32).is_err());
+
+        // more records than there are strings for
+        assert!(self::strings(&[int(1, 3)], &["a"]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_protobuf() {
+        // a key that stops mid-varint, a value missing, bytes that run over, a short fixed32, a wire type of 3
+        for data in [&[0x80][..], &[0x08], &[0x0A, 0x05, 0x01], &[0x0D, 0x00, 0x00], &[0x0B]] {
+            assert!(Message::parse(data).is_err(), "{:?}", data);
+        }
+        let mut pos = 0;
+        assert!(varint(&[0xFF; 11], &mut pos).is_err());
+
+        // no prefix of a valid declaration panics, and cutting into the string table is an error
+        let records = [int(2, 14), int(2, 8)];
+        let declaration = [message(3, &[int(2, 0), message(3, &[int(6, 1)])])];
+        let bytes = data(&records, &declaration);
+        // the string table's length fits in the first byte
+        let table_end = 1 + bytes[0] as usize;
+        let mut b = DexBuilder::new(35);
+        for end in 0 .. bytes.len() {
+            let d1 = std::iter::once('\u{0}').chain(bytes[.. end].iter().cloned().map(char::from)).collect();
+            let result = decode_annotation(&metadata(&mut b, 2, vec!(d1), &["run"]).elements);
+            if end < table_end {
+                assert!(result.is_err(), "{}", end);
+            }
+        }
+    }
+
+    fn decode_class(records: &[Vec<u8>], declaration: &[Vec<u8>], d2: &[&str]) -> KotlinClass {
+        let d1 = std::iter::once('\u{0}').chain(data(records, declaration).into_iter().map(char::from)).collect();
+        let mut b = DexBuilder::new(35);
+        match decode_annotation(&metadata(&mut b, 1, vec!(d1), d2).elements).unwrap().declaration {
+            KotlinDeclaration::Class(class) => class,
+            other => panic!("{:?}", other)
+        }
+    }
+
+    // private sealed class Shape { abstract suspend fun area(block: suspend () -> Unit): Double?; val size: Int? }
+    #[test]
+    fn test_flags() {
+        let d2 = ["com/example/Shape", "com/example/Circle", "com/example/Square", "area", "kotlin/Double", "size",
+                  "kotlin/Int", "block", "kotlin/Unit"];
+        let declaration = [
+            int(1, (3 << 4) | (1 << 1)),
+            int(3, 0),
+            int(16, 1),
+            int(16, 2),
+            message(9, &[
+                int(9, (1 << 13) | (2 << 4) | 6),
+                int(2, 3),
+                message(6, &[int(2, 7), message(3, &[int(1, 1), int(6, 8)])]),
+                message(3, &[int(6, 4), int(3, 1)])
+            ]),
+            message(10, &[int(2, 5), int(9, 0)]),
+            // every type in the table from index 0 on is nullable
+            message(30, &[message(1, &[int(6, 6)]), int(2, 0)])
+        ];
+        let shape = decode_class(&[], &declaration, &d2);
+        assert_eq!((shape.visibility, shape.modality, shape.kind),
+                   (KotlinVisibility::Private, KotlinModality::Sealed, KotlinClassKind::Class));
+        assert!(!shape.is_data);
+        assert_eq!(shape.sealed_subclasses, vec!("com/example/Circle", "com/example/Square"));
+
+        let area = &shape.functions[0];
+        assert!(area.is_suspend && !area.is_inline);
+        assert_eq!(area.modality, KotlinModality::Abstract);
+        assert!(area.return_type.is_nullable);
+        assert_eq!(area.return_type.to_string(), "kotlin.Double?");
+        let block = &area.parameters[0].type_;
+        assert!(block.is_suspend && !block.is_nullable);
+
+        let size = &shape.properties[0];
+        assert!(size.return_type.is_nullable && !size.is_var);
+        assert_eq!(size.return_type.to_string(), "kotlin.Int?");
+
+        // data class Point
+        let point = decode_class(&[], &[int(1, (1 << 10) | 6), int(3, 0)], &["com/example/Point"]);
+        assert!(point.is_data);
+        assert_eq!((point.visibility, point.modality), (KotlinVisibility::Public, KotlinModality::Final));
+    }
+}
@@ -6,13 +6,57 @@ extern crate failure;
 mod parser;
 mod error;
 mod result_types;
+mod limits;
+mod options;
+mod writer;
+mod builder;
+
+pub mod instructions;
+pub mod cfg;
+pub mod hierarchy;
+pub mod call_graph;
+pub mod dataflow;
+pub mod type_inference;
+pub mod verifier;
+pub mod xref;
+pub mod java;
+pub mod kotlin;
+pub mod stub;
+pub mod diff;
+pub mod mapping;
+pub mod method_counts;
+pub mod sizes;
+pub mod rewrite;
+pub mod assembler;
+pub mod merge;
+pub mod multidex;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+#[cfg(feature = "serde")]
+pub mod serialization;
 
 pub use error::DexParserError;
 pub use result_types::*;
+pub use limits::ParseLimits;
+pub use options::ParseOptions;
+pub use writer::{write, access_flag_bits};
+pub use builder::DexBuilder;
 pub use nom::Endianness;
 
 pub fn parse(buf: &[u8]) -> Result<DexFile, DexParserError> {
-    parser::parse(buf)
+    parser::parse(buf, ParseOptions::default())
+}
+
+// Parse with custom bounds on resource usage, failing with DexParserError::LimitExceeded
+// if the file needs more than they allow
+pub fn parse_with_limits(buf: &[u8], limits: ParseLimits) -> Result<DexFile, DexParserError> {
+    parser::parse(buf, ParseOptions::default().limits(limits))
+}
+
+// Parse with control over which sections are decoded and how strictly (see ParseOptions)
+pub fn parse_with_options(buf: &[u8], options: ParseOptions) -> Result<DexFile, DexParserError> {
+    parser::parse(buf, options)
 }
 
-// TODO: validate checksum/signature
\ No newline at end of file
+// TODO: validate signature
\ No newline at end of file
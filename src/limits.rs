@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+// Bounds enforced while parsing, so that a small crafted file can't claim enormous tables,
+// nest encoded values arbitrarily deep or keep the parser busy indefinitely
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseLimits {
+    // largest number of bytes any single table or list in the file may claim to need
+    pub max_allocation: usize,
+    // deepest nesting allowed for encoded arrays and annotations
+    pub max_nesting_depth: usize,
+    // most class definitions a file may declare
+    pub max_classes: usize,
+    // total number of items (strings, classes, methods, values...) decoded across the whole file
+    pub max_work: u64,
+    // wall-clock time allowed for parsing the whole file
+    pub max_duration: Option<Duration>
+}
+
+impl ParseLimits {
+    // No limits at all: only suitable for trusted input
+    pub fn unlimited() -> Self {
+        ParseLimits {
+            max_allocation: usize::MAX,
+            max_nesting_depth: usize::MAX,
+            max_classes: usize::MAX,
+            max_work: u64::MAX,
+            max_duration: None
+        }
+    }
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_allocation: 64 * 1024 * 1024,
+            max_nesting_depth: 64,
+            // type_ids are indexed with 16 bits in most places, so no more classes can be referenced
+            max_classes: 65_536,
+            max_work: 100_000_000,
+            max_duration: None
+        }
+    }
+}
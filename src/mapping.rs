@@ -0,0 +1,677 @@
+// ProGuard and R8 mapping files, for putting back the names an obfuscated file was built from
+//
+// Docs: R8 retrace, and the mapping file format at r8/doc/retrace.md
+//
+// A mapping lists each renamed class with its fields and methods:
+//
+//     com.example.Main -> a.a:
+//     # {"id":"sourceFile","fileName":"Main.java"}
+//         int count -> a
+//         1:3:void run(int):10:12 -> b
+//         4:4:void helper():20:20 -> c
+//         4:4:void run():14 -> c
+//         # {"id":"com.android.tools.r8.residualsignature","signature":"()V"}
+//
+// Methods carry the range of line numbers they occupy in the obfuscated file, and the range those map back to.
+// Consecutive lines with the same obfuscated name and range are one call site with methods inlined into it: the
+// first line is the innermost of them and the last the method the code is actually in. The `# {...}` comments are
+// R8's metadata about the line before them.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::java;
+use crate::result_types::*;
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Mapping {
+    // the map version of R8's com.android.tools.r8.mapping header, e.g. "2.2"
+    pub version: Option<String>,
+    pub classes: Vec<ClassMapping>,
+    // indexes into classes, by their names as Java writes them
+    obfuscated: HashMap<String, usize>,
+    original: HashMap<String, usize>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClassMapping {
+    // names as Java writes them, `com.example.Main$Inner`
+    pub original: String,
+    pub obfuscated: String,
+    pub source_file: Option<String>,
+    // the class was made up by the compiler, and has no original
+    pub synthesized: bool,
+    pub fields: Vec<FieldMapping>,
+    pub methods: Vec<MethodMapping>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FieldMapping {
+    // in Java syntax, `java.lang.String[]`
+    pub original_type: String,
+    pub original_name: String,
+    pub obfuscated_name: String,
+    // the descriptor of the field's type in the obfuscated file, when it isn't just the original one renamed
+    pub residual_type: Option<String>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MethodMapping {
+    pub obfuscated_name: String,
+    // the line numbers in the obfuscated file this covers, None when it covers the whole method
+    pub obfuscated_lines: Option<(u32, u32)>,
+    // the method and the ones inlined into it at these lines, innermost first
+    pub frames: Vec<Frame>,
+    // the descriptor of the method in the obfuscated file, when it isn't just the original one renamed
+    pub residual_signature: Option<String>,
+    pub synthesized: bool
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    // the class the method was declared in, when it isn't the class this mapping is for
+    pub class: Option<String>,
+    pub name: String,
+    // in Java syntax
+    pub return_type: String,
+    pub parameters: Vec<String>,
+    // the line numbers in the original source. A single line when every obfuscated line maps to it, None when the
+    // lines weren't changed
+    pub original_lines: Option<(u32, u32)>
+}
+
+pub fn parse(text: &str) -> Result<Mapping, DexParserError> {
+    let mut mapping = Mapping::default();
+    // what a metadata comment is about
+    enum Last { Nothing, Class, Field, Method }
+    let mut last = Last::Nothing;
+
+    for (number, line) in text.lines().enumerate() {
+        let err = |reason: &str| DexParserError::from(format!("mapping line {}: {}", number + 1, reason));
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if let Some(comment) = trimmed.strip_prefix('#') {
+            let metadata = metadata(comment.trim());
+            let value = |key: &str| metadata.get(key).cloned();
+            let class = mapping.classes.last_mut();
+            match (metadata.get("id").map(|id| id.as_str()), &last, class) {
+                (Some("com.android.tools.r8.mapping"), _, _) => mapping.version = value("version"),
+                (Some("sourceFile"), Last::Class, Some(class)) => class.source_file = value("fileName"),
+                (Some("com.android.tools.r8.synthesized"), Last::Class, Some(class)) => class.synthesized = true,
+                (Some("com.android.tools.r8.synthesized"), Last::Method, Some(class)) =>
+                    class.methods.last_mut().unwrap().synthesized = true,
+                (Some("com.android.tools.r8.residualsignature"), Last::Method, Some(class)) =>
+                    class.methods.last_mut().unwrap().residual_signature = value("signature"),
+                (Some("com.android.tools.r8.residualsignature"), Last::Field, Some(class)) =>
+                    class.fields.last_mut().unwrap().residual_type = value("signature"),
+                // other metadata, and comments that aren't any
+                _ => {}
+            }
+            continue;
+        }
+
+        let (left, obfuscated) = match trimmed.rfind(" -> ") {
+            Some(arrow) => (trimmed[.. arrow].trim(), trimmed[arrow + 4 ..].trim()),
+            None => return Err(err("expected ` -> `"))
+        };
+        if !line.starts_with(char::is_whitespace) {
+            let obfuscated = obfuscated.strip_suffix(':').ok_or_else(|| err("expected `:` after the class"))?;
+            mapping.classes.push(ClassMapping { original: left.to_string(), obfuscated: obfuscated.to_string(),
+                                                source_file: None, synthesized: false, fields: vec!(),
+                                                methods: vec!() });
+            last = Last::Class;
+            continue;
+        }
+
+        let class = mapping.classes.last_mut().ok_or_else(|| err("member outside of a class"))?;
+        if !left.contains('(') {
+            let (type_, name) = left.rsplit_once(' ').ok_or_else(|| err("expected a field type and name"))?;
+            class.fields.push(FieldMapping { original_type: type_.trim().to_string(), original_name: name.to_string(),
+                                             obfuscated_name: obfuscated.to_string(), residual_type: None });
+            last = Last::Field;
+            continue;
+        }
+
+        let (obfuscated_lines, signature) = split_lines(left).map_err(|e| err(&e))?;
+        let open = signature.find('(').unwrap();
+        let close = signature.rfind(')').ok_or_else(|| err("expected `)`"))?;
+        let (return_type, qualified) = signature[.. open].trim().rsplit_once(' ')
+            .ok_or_else(|| err("expected a return type and method name"))?;
+        let (frame_class, name) = match qualified.rsplit_once('.') {
+            Some((class, name)) => (Some(class.to_string()), name),
+            None => (None, qualified)
+        };
+        let parameters = signature[open + 1 .. close].split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        let original_lines = match signature[close + 1 ..].strip_prefix(':') {
+            Some(lines) => Some(line_range(lines).map_err(|e| err(&e))?),
+            None => None
+        };
+        let frame = Frame { class: frame_class, name: name.to_string(), return_type: return_type.to_string(),
+                            parameters, original_lines };
+
+        // a call site gets a line for each method inlined into it, the last for the method it is in
+        let inlined = match class.methods.last_mut() {
+            Some(previous) if matches!(last, Last::Method) && obfuscated_lines.is_some()
+                && previous.obfuscated_lines == obfuscated_lines && previous.obfuscated_name == obfuscated => {
+                previous.frames.push(frame.clone());
+                true
+            },
+            _ => false
+        };
+        if !inlined {
+            class.methods.push(MethodMapping { obfuscated_name: obfuscated.to_string(), obfuscated_lines,
+                                               frames: vec!(frame), residual_signature: None, synthesized: false });
+        }
+        last = Last::Method;
+    }
+
+    for (i, class) in mapping.classes.iter().enumerate() {
+        mapping.obfuscated.insert(class.obfuscated.clone(), i);
+        mapping.original.insert(class.original.clone(), i);
+    }
+    Ok(mapping)
+}
+
+// the first and last of a range of line numbers
+type Lines = (u32, u32);
+
+// The `1:3:` a method line starts with, if it has one, and the rest of the line
+fn split_lines(line: &str) -> Result<(Option<Lines>, &str), String> {
+    if !line.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok((None, line));
+    }
+    // the second colon ends the range, leaving the return type
+    let end = line.match_indices(':').nth(1).map(|(i, _)| i).ok_or("expected a range of lines")?;
+    Ok((Some(line_range(&line[.. end])?), &line[end + 1 ..]))
+}
+
+// `10:12`, or `10` for a single line
+fn line_range(range: &str) -> Result<(u32, u32), String> {
+    let number = |s: &str| s.trim().parse::<u32>().map_err(|_| format!("'{}' isn't a line number", s));
+    match range.split_once(':') {
+        Some((start, end)) => match (number(start)?, number(end)?) {
+            (start, end) if start <= end => Ok((start, end)),
+            (start, end) => Err(format!("line range {}:{} ends before it starts", start, end))
+        },
+        None => number(range).map(|line| (line, line))
+    }
+}
+
+// The members of a metadata object whose values are strings. Anything else in it is skipped over
+fn metadata(json: &str) -> HashMap<String, String> {
+    let mut members = HashMap::new();
+    let mut chars = json.chars().peekable();
+    if chars.next() != Some('{') {
+        return members;
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        let key = match chars.peek() {
+            Some('"') => json_string(&mut chars),
+            _ => return members
+        };
+        skip_whitespace(&mut chars);
+        if chars.next() != Some(':') {
+            return members;
+        }
+        skip_whitespace(&mut chars);
+        if chars.peek() == Some(&'"') {
+            if let (Some(key), Some(value)) = (key, json_string(&mut chars)) {
+                members.insert(key, value);
+            }
+        } else {
+            skip_value(&mut chars);
+        }
+        skip_whitespace(&mut chars);
+        if chars.next() != Some(',') {
+            return members;
+        }
+    }
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+fn skip_whitespace(chars: &mut Chars) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn json_string(chars: &mut Chars) -> Option<String> {
+    chars.next();
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                't' => s.push('\t'),
+                'r' => s.push('\r'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let hex = chars.by_ref().take(4).collect::<String>();
+                    s.push(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).unwrap_or('\u{FFFD}'));
+                },
+                c => s.push(c)
+            },
+            c => s.push(c)
+        }
+    }
+}
+
+// A number, literal, array or object, up to the comma or bracket after it
+fn skip_value(chars: &mut Chars) {
+    let mut depth = 0;
+    while let Some(&c) = chars.peek() {
+        match c {
+            '"' => {
+                json_string(chars);
+                continue;
+            },
+            '[' | '{' => depth += 1,
+            ']' | '}' if depth == 0 => return,
+            ']' | '}' => depth -= 1,
+            ',' if depth == 0 => return,
+            _ => {}
+        }
+        chars.next();
+    }
+}
+
+// `com.foo.Bar[]` to `[Lcom/foo/Bar;`
+fn descriptor(java_type: &str) -> String {
+    let element = java_type.trim_end_matches("[]");
+    let dimensions = (java_type.len() - element.len()) / 2;
+    let element = match element {
+        "void" => "V".to_string(),
+        "boolean" => "Z".to_string(),
+        "byte" => "B".to_string(),
+        "short" => "S".to_string(),
+        "char" => "C".to_string(),
+        "int" => "I".to_string(),
+        "long" => "J".to_string(),
+        "float" => "F".to_string(),
+        "double" => "D".to_string(),
+        class => format!("L{};", class.replace('.', "/"))
+    };
+    "[".repeat(dimensions) + &element
+}
+
+// `4:4:void run():14` maps line 4 to line 14, and `1:3:void run(int):10:12` line 2 to line 11
+fn original_line(obfuscated_lines: Option<(u32, u32)>, original_lines: Option<(u32, u32)>, line: u32) -> u32 {
+    match (obfuscated_lines, original_lines) {
+        (Some((start, end)), Some((original_start, original_end)))
+            if line >= start && end.checked_sub(start).is_some()
+                && end.checked_sub(start) == original_end.checked_sub(original_start) => {
+            original_start + (line - start)
+        },
+        (_, Some((original_start, _))) => original_start,
+        (_, None) => line
+    }
+}
+
+impl Mapping {
+    // The mapping for a class, by its descriptor in the obfuscated file
+    pub fn class(&self, descriptor: &str) -> Option<&ClassMapping> {
+        self.obfuscated.get(&java::type_name(descriptor)).map(|i| &self.classes[*i])
+    }
+
+    // The original of a type descriptor, arrays included. Types the mapping doesn't rename are left as they are
+    pub fn original_type(&self, descriptor: &str) -> String {
+        let element = descriptor.trim_start_matches('[');
+        match self.obfuscated.get(&java::type_name(element)) {
+            Some(i) if element.starts_with('L') =>
+                "[".repeat(descriptor.len() - element.len()) + &self::descriptor(&self.classes[*i].original),
+            _ => descriptor.to_string()
+        }
+    }
+
+    // The descriptor an original type from the mapping has in the obfuscated file
+    fn obfuscated_type(&self, java_type: &str) -> String {
+        let element = java_type.trim_end_matches("[]");
+        match self.original.get(element) {
+            Some(i) => descriptor(&(self.classes[*i].obfuscated.clone() + &java_type[element.len() ..])),
+            None => descriptor(java_type)
+        }
+    }
+
+    // The descriptor a method from the mapping has in the obfuscated file
+    fn obfuscated_signature(&self, method: &MethodMapping) -> String {
+        if let Some(ref signature) = method.residual_signature {
+            return signature.clone();
+        }
+        let frame = method.frames.last().unwrap();
+        let parameters = frame.parameters.iter().map(|p| self.obfuscated_type(p)).collect::<String>();
+        format!("({}){}", parameters, self.obfuscated_type(&frame.return_type))
+    }
+
+    pub fn field_mapping(&self, field: &Field) -> Option<&FieldMapping> {
+        let class = self.class(&field.definer)?;
+        let mut named = class.fields.iter().filter(|f| f.obfuscated_name == *field.name).peekable();
+        let first = named.peek().cloned();
+        let type_ = |f: &FieldMapping| f.residual_type.clone()
+            .unwrap_or_else(|| self.obfuscated_type(&f.original_type));
+        named.find(|f| type_(f) == *field.type_).or(first)
+    }
+
+    // The mappings for a method that cover its lines, or the whole method. Those with inlined methods list them too
+    pub fn method_mappings(&self, method: &Method) -> Vec<&MethodMapping> {
+        let signature = format!("({}){}", method.prototype.parameters.iter().map(|p| p.as_str()).collect::<String>(),
+                                method.prototype.return_type);
+        self.class(&method.definer).into_iter()
+            .flat_map(|class| class.methods.iter())
+            .filter(|m| m.obfuscated_name == *method.name && self.obfuscated_signature(m) == signature)
+            .collect()
+    }
+
+    // A field with its original name, type and class
+    pub fn field(&self, field: &Field) -> Field {
+        let (name, type_) = match self.field_mapping(field) {
+            Some(mapping) => (Rc::new(mapping.original_name.clone()), Rc::new(descriptor(&mapping.original_type))),
+            None => (field.name.clone(), Rc::new(self.original_type(&field.type_)))
+        };
+        Field { definer: Rc::new(self.original_type(&field.definer)), type_, name }
+    }
+
+    // A method with its original name, prototype and class
+    pub fn method(&self, method: &Method) -> Method {
+        let mappings = self.method_mappings(method);
+        let frame = match mappings.first() {
+            Some(mapping) => mapping.frames.last().unwrap(),
+            None => return Method { definer: Rc::new(self.original_type(&method.definer)),
+                                    prototype: Rc::new(self.prototype(&method.prototype)), name: method.name.clone() }
+        };
+        let definer = match frame.class {
+            Some(ref class) => descriptor(class),
+            None => self.original_type(&method.definer)
+        };
+        let return_type = Rc::new(descriptor(&frame.return_type));
+        let parameters = frame.parameters.iter().map(|p| Rc::new(descriptor(p))).collect::<Vec<_>>();
+        let shorty = shorty(&return_type, &parameters);
+        Method { definer: Rc::new(definer), prototype: Rc::new(Prototype { shorty, return_type, parameters }),
+                 name: Rc::new(frame.name.clone()) }
+    }
+
+    // A prototype with its types renamed; the shorty stays the same
+    pub fn prototype(&self, prototype: &Prototype) -> Prototype {
+        Prototype {
+            shorty: prototype.shorty.clone(),
+            return_type: Rc::new(self.original_type(&prototype.return_type)),
+            parameters: prototype.parameters.iter().map(|p| Rc::new(self.original_type(p))).collect()
+        }
+    }
+
+    // Deobfuscates a stack trace line by line. Frames of methods with others inlined into them become a frame for
+    // each, and frames that could be any of several methods are followed by the other candidates as `<OR> at ...`.
+    //
+    // The files the trace came from, when given, settle which of several methods sharing an obfuscated name a
+    // frame is in by the line numbers in their debug info.
+    pub fn retrace(&self, files: &[&DexFile], trace: &str) -> String {
+        let mut retraced = String::new();
+        for line in trace.lines() {
+            match self.retrace_frame(files, line) {
+                Some(frames) => for frame in frames {
+                    retraced.push_str(&frame);
+                    retraced.push('\n');
+                },
+                None => {
+                    retraced.push_str(&self.retrace_names(line));
+                    retraced.push('\n');
+                }
+            }
+        }
+        retraced
+    }
+
+    // The class names in a line that isn't a frame, like `Caused by: a.b: message`
+    fn retrace_names(&self, line: &str) -> String {
+        line.split(' ')
+            .map(|word| {
+                let name = word.trim_end_matches(':');
+                match self.obfuscated.get(name) {
+                    Some(i) => self.classes[*i].original.clone() + &word[name.len() ..],
+                    None => word.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // `    at a.a.b(SourceFile:4)`
+    fn retrace_frame(&self, files: &[&DexFile], line: &str) -> Option<Vec<String>> {
+        let indent = &line[.. line.len() - line.trim_start().len()];
+        let frame = line.trim_start().strip_prefix("at ")?;
+        let open = frame.find('(')?;
+        let location = frame[open + 1 ..].strip_suffix(')')?;
+        let (class_name, method_name) = frame[.. open].rsplit_once('.')?;
+        let number = location.rsplit_once(':').and_then(|(_, n)| n.parse::<u32>().ok());
+        let class = &self.classes[*self.obfuscated.get(class_name)?];
+
+        let named = class.methods.iter().filter(|m| m.obfuscated_name == method_name).collect::<Vec<_>>();
+        let covering = named.iter()
+            .filter(|m| matches!((m.obfuscated_lines, number), (Some((start, end)), Some(n)) if start <= n && n <= end))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut candidates = if covering.is_empty() {
+            named.into_iter().filter(|m| m.obfuscated_lines.is_none()).collect()
+        } else {
+            covering
+        };
+        if let Some(number) = number {
+            self.narrow_by_lines(files, class, method_name, number, &mut candidates);
+        }
+
+        let source = |frame: &Frame| {
+            let class = frame.class.as_ref().and_then(|c| self.original.get(c)).map_or(class, |i| &self.classes[*i]);
+            class.source_file.clone().unwrap_or_else(|| {
+                let name = frame.class.as_ref().unwrap_or(&class.original);
+                let top_level = name.rsplit('.').next().unwrap_or(name).split('$').next().unwrap_or(name);
+                format!("{}.java", top_level)
+            })
+        };
+        let mut frames = vec!();
+        for (i, candidate) in candidates.iter().enumerate() {
+            for (j, frame) in candidate.frames.iter().enumerate() {
+                let location = match number {
+                    Some(number) => format!("{}:{}", source(frame),
+                                            original_line(candidate.obfuscated_lines, frame.original_lines, number)),
+                    None => source(frame)
+                };
+                let prefix = if i > 0 && j == 0 { "<OR> " } else { "" };
+                let text = format!("{}{}at {}.{}({})", indent, prefix,
+                                   frame.class.as_ref().unwrap_or(&class.original), frame.name, location);
+                if !frames.contains(&text) {
+                    frames.push(text);
+                }
+            }
+        }
+        if frames.is_empty() {
+            // a method the mapping doesn't know about, in a class it does
+            frames.push(format!("{}at {}.{}({})", indent, class.original, method_name, location));
+        }
+        Some(frames)
+    }
+
+    // Keeps the candidates for methods whose debug info has the line, when that leaves any
+    fn narrow_by_lines(&self, files: &[&DexFile], class: &ClassMapping, name: &str, number: u32,
+                       candidates: &mut Vec<&MethodMapping>) {
+        let definer = descriptor(&class.obfuscated);
+        let signatures = files.iter()
+            .flat_map(|file| file.classes.iter())
+            .filter(|c| *c.class_type == definer)
+            .filter_map(|c| c.class_data.as_ref())
+            .flat_map(|data| data.direct_methods.iter().chain(data.virtual_methods.iter()))
+            .filter(|m| *m.method.name == name)
+            .filter(|m| m.code.as_ref().and_then(|code| code.debug_info.as_ref())
+                .is_some_and(|info| lines(info).contains(&number)))
+            .map(|m| format!("({}){}", m.method.prototype.parameters.iter().map(|p| p.as_str()).collect::<String>(),
+                             m.method.prototype.return_type))
+            .collect::<Vec<_>>();
+        let narrowed = candidates.iter()
+            .filter(|m| signatures.contains(&self.obfuscated_signature(m)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !narrowed.is_empty() {
+            *candidates = narrowed;
+        }
+    }
+}
+
+// The line numbers in a method's position table
+fn lines(info: &DebugInfo) -> Vec<u32> {
+    let mut line = i64::from(info.line_start);
+    let mut lines = vec!();
+    for item in &info.bytecode {
+        match item {
+            DebugItemBytecodes::DBG_ADVANCE_LINE(n) => line += i64::from(*n),
+            DebugItemBytecodes::SPECIAL_OPCODE(opcode) => {
+                line += -4 + (i64::from(*opcode) - 0x0A) % 15;
+                lines.push(line as u32);
+            },
+            _ => {}
+        }
+    }
+    lines
+}
+
+fn shorty(return_type: &str, parameters: &[Rc<String>]) -> Rc<String> {
+    let short = |t: &str| if t.starts_with('L') || t.starts_with('[') { 'L' } else { t.chars().next().unwrap_or('V') };
+    Rc::new(std::iter::once(short(return_type)).chain(parameters.iter().map(|p| short(p))).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    const MAPPING: &str = "\
+# compiler: R8
+# {\"id\":\"com.android.tools.r8.mapping\",\"version\":\"2.2\"}
+com.example.Main -> a.a:
+# {\"id\":\"sourceFile\",\"fileName\":\"Main.kt\"}
+    int count -> a
+    com.example.Util helper -> b
+    1:3:void run(int):10:12 -> b
+    4:4:void com.example.Util.log(java.lang.String):40:40 -> b
+    4:4:void run(int):13 -> b
+    void start() -> b
+    5:6:com.example.Util create(com.example.Util[]):50:51 -> c
+    # {\"id\":\"com.android.tools.r8.residualsignature\",\"signature\":\"()La/b;\"}
+    # {\"id\":\"com.android.tools.r8.synthesized\"}
+com.example.Util -> a.b:
+";
+
+    #[test]
+    fn test_parse() {
+        let mapping = parse(MAPPING).unwrap();
+        assert_eq!(mapping.version, Some("2.2".to_string()));
+        let main = &mapping.classes[0];
+        assert_eq!((main.original.as_str(), main.obfuscated.as_str()), ("com.example.Main", "a.a"));
+        assert_eq!(main.source_file, Some("Main.kt".to_string()));
+        assert_eq!(main.fields[1], FieldMapping { original_type: "com.example.Util".to_string(),
+                                                 original_name: "helper".to_string(),
+                                                 obfuscated_name: "b".to_string(), residual_type: None });
+        assert_eq!(main.methods.len(), 4);
+        assert_eq!(main.methods[1].frames, vec!(
+            Frame { class: Some("com.example.Util".to_string()), name: "log".to_string(),
+                    return_type: "void".to_string(), parameters: vec!("java.lang.String".to_string()),
+                    original_lines: Some((40, 40)) },
+            Frame { class: None, name: "run".to_string(), return_type: "void".to_string(),
+                    parameters: vec!("int".to_string()), original_lines: Some((13, 13)) }
+        ));
+        assert_eq!(main.methods[3].residual_signature, Some("()La/b;".to_string()));
+        assert!(main.methods[3].synthesized);
+
+        assert!(parse("a -> b").is_err());
+        assert!(parse("    int a -> b").is_err());
+        assert!(parse("a -> b:\n    1:x:void a() -> b").is_err());
+        // ranges that end before they start
+        assert!(parse("com.Foo -> a.a:\n    1:3:void run():12:10 -> a\n").is_err());
+        assert!(parse("com.Foo -> a.a:\n    3:1:void run():10:12 -> a\n").is_err());
+        assert_eq!(original_line(Some((1, 3)), Some((12, 10)), 2), 12);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mapping = parse(MAPPING).unwrap();
+        let mut b = DexBuilder::new(35);
+        let field = b.field("La/a;", "b", "La/b;");
+        let run = b.method("La/a;", "b", "V", &["I"]);
+        let create = b.method("La/a;", "c", "La/b;", &[]);
+        let other = b.method("La/c;", "d", "[[La/b;", &["J"]);
+
+        assert_eq!(mapping.field(&field).to_string(), "com.example.Util com.example.Main.helper");
+        assert_eq!(mapping.method(&run).to_string(), "void com.example.Main.run(int)");
+        assert_eq!(mapping.method(&create).to_string(), "com.example.Util com.example.Main.create(com.example.Util[])");
+        assert_eq!(mapping.method(&other).to_string(), "com.example.Util[][] a.c.d(long)");
+        assert_eq!(*mapping.method(&create).prototype.shorty, "LL");
+    }
+
+    #[test]
+    fn test_retrace() {
+        let mapping = parse(MAPPING).unwrap();
+        let trace = "\
+java.lang.IllegalStateException: a.b
+\tat a.a.b(SourceFile:2)
+\tat a.a.b(SourceFile:4)
+\tat a.a.c(SourceFile:6)
+\tat a.a.z(SourceFile:1)
+\tat a.a.b(SourceFile:9)
+\tat java.lang.Thread.run(Thread.java:1012)
+";
+        assert_eq!(mapping.retrace(&[], trace), "\
+java.lang.IllegalStateException: com.example.Util
+\tat com.example.Main.run(Main.kt:11)
+\tat com.example.Util.log(Util.java:40)
+\tat com.example.Main.run(Main.kt:13)
+\tat com.example.Main.create(Main.kt:51)
+\tat com.example.Main.z(SourceFile:1)
+\tat com.example.Main.start(Main.kt:9)
+\tat java.lang.Thread.run(Thread.java:1012)
+");
+    }
+
+    #[test]
+    fn test_retrace_with_positions() {
+        // two methods renamed to a, told apart only by the lines in their debug info
+        let mapping = parse("Main -> a:\n    void first(int) -> a\n    void second() -> a\n").unwrap();
+        let mut b = DexBuilder::new(35);
+        let method = |b: &mut DexBuilder, parameters: &[&str], line_start| EncodedMethod {
+            method: b.method("La;", "a", "V", parameters),
+            access_flags: vec!(AccessFlag::ACC_STATIC),
+            code: Some(Code {
+                registers_size: 1, ins_size: parameters.len() as u16, outs_size: 0,
+                // a position at the first line, then one line and one address on
+                debug_info: Some(DebugInfo { line_start, parameter_names: vec!(),
+                                             bytecode: vec!(DebugItemBytecodes::SPECIAL_OPCODE(0x0E),
+                                                            DebugItemBytecodes::SPECIAL_OPCODE(0x1E)) }),
+                insns: vec!(0x0000, 0x000E), tries: vec!(), handlers: vec!()
+            }),
+            system_annotations: Default::default()
+        };
+        let first = method(&mut b, &["I"], 10);
+        let second = method(&mut b, &[], 20);
+        let class_type = b.type_("La;");
+        b.class(ClassDefinition {
+            class_type, access_flags: vec!(), superclass: None, interfaces: vec!(), source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData { static_fields: vec!(), instance_fields: vec!(),
+                                         direct_methods: vec!(first, second), virtual_methods: vec!() }),
+            static_values: vec!(), system_annotations: Default::default()
+        });
+        let file = b.build().unwrap();
+
+        assert_eq!(mapping.retrace(&[], "  at a.a(SourceFile:21)"),
+                   "  at Main.first(Main.java:21)\n  <OR> at Main.second(Main.java:21)\n");
+        assert_eq!(mapping.retrace(&[&file], "  at a.a(SourceFile:21)"), "  at Main.second(Main.java:21)\n");
+        assert_eq!(mapping.retrace(&[&file], "  at a.a(SourceFile:11)"), "  at Main.first(Main.java:11)\n");
+    }
+}
@@ -0,0 +1,107 @@
+// Combining several files into one, like d8 does when merging the outputs of separate compilations
+//
+// The classes of every file are taken over with the references in their code read out of that file's pools, then
+// the pools of the merged file are gathered and sorted anew and every index in bytecode pointed at its new position.
+// Encoded values and annotations hold their references directly, so they carry over as they are. A class defined in
+// more than one file is an error, as is a merged file needing more method, field, type or prototype references than
+// one file can hold, which fails with DexParserError::LimitExceeded.
+
+use std::collections::HashMap;
+
+use crate::error::DexParserError;
+use crate::result_types::*;
+use crate::rewrite::Rewriter;
+
+// The merged file has the highest version of those given, and their classes in order, except that supertypes are
+// moved ahead of the classes extending them
+pub fn merge(files: Vec<DexFile>) -> Result<DexFile, DexParserError> {
+    let mut defined = HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        for class in &file.classes {
+            if let Some(first) = defined.insert(class.class_type.clone(), i) {
+                return Err(DexParserError::WritingFailed {
+                    reason: format!("class {} is defined in file {} and again in file {}", class.class_type, first, i)
+                });
+            }
+        }
+    }
+
+    let mut files = files.into_iter();
+    let first = files.next()
+        .ok_or_else(|| DexParserError::WritingFailed { reason: "no files to merge".to_string() })?;
+    let mut merged = Rewriter::new(first)?;
+    for file in files {
+        merged.merge(Rewriter::new(file)?)?;
+    }
+    merged.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+    use crate::rewrite::{lift, Reference};
+    use std::rc::Rc;
+
+    fn file(class_type: &str, superclass: Option<&str>, calls: (&str, &str)) -> DexFile {
+        let mut b = DexBuilder::new(35);
+        let target = b.method(calls.0, calls.1, "V", &[]);
+        let run = b.method(class_type, "run", "V", &[]);
+        let class_type = b.type_(class_type);
+        let superclass = superclass.map(|s| b.type_(s));
+        let constant = b.field(&class_type, "CONSTANT", "Ljava/lang/String;");
+        let value = b.string(&format!("{} constant", class_type));
+        b.class(ClassDefinition {
+            class_type, access_flags: vec!(AccessFlag::ACC_PUBLIC), superclass, interfaces: vec!(),
+            source_file_name: None, annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(EncodedField { field: constant, access_flags: vec!(AccessFlag::ACC_STATIC) }),
+                instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod {
+                    method: run,
+                    access_flags: vec!(AccessFlag::ACC_STATIC),
+                    code: Some(Code { registers_size: 0, ins_size: 0, outs_size: 0, debug_info: None,
+                                      insns: vec!(), tries: vec!(), handlers: vec!() }),
+                    system_annotations: Default::default()
+                }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(EncodedValue::String(value)), system_annotations: Default::default()
+        });
+        let mut file = b.build().unwrap();
+
+        // invoke-static {}, target; return-void
+        let index = file.file_data.methods.iter().position(|m| *m == target).unwrap() as u16;
+        let code = file.classes[0].class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap();
+        code.insns = vec!(0x0071, index, 0x0000, 0x000E);
+        file
+    }
+
+    #[test]
+    fn test_merge() {
+        let first = file("Lb/Sub;", Some("La/Base;"), ("La/Base;", "run"));
+        let mut second = file("La/Base;", None, ("Lb/Sub;", "run"));
+        second.header.version = 38;
+        let merged = merge(vec!(first, second)).unwrap();
+
+        assert_eq!(merged.header.version, 38);
+        assert_eq!(merged.classes.iter().map(|c| c.class_type.as_str()).collect::<Vec<_>>(),
+                   vec!("La/Base;", "Lb/Sub;"));
+        assert_eq!(merged.file_data.methods.len(), 2);
+        for class in &merged.classes {
+            let code = class.class_data.as_ref().unwrap().direct_methods[0].code.as_ref().unwrap();
+            let callee = match lift(&merged, code).unwrap()[0].target {
+                Reference::Method(ref method) => method.definer.clone(),
+                ref other => panic!("{:?}", other)
+            };
+            assert_ne!(callee, class.class_type);
+            let constant = Rc::new(format!("{} constant", class.class_type));
+            assert_eq!(class.static_values, vec!(EncodedValue::String(constant)));
+        }
+        assert_eq!(crate::parse(&crate::write(&merged).unwrap()).unwrap(), merged);
+
+        let conflict = merge(vec!(file("La;", None, ("Lx;", "y")), file("La;", None, ("Lx;", "z"))));
+        assert!(conflict.is_err());
+        assert!(merge(vec!()).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// How many method, field and type references files use against the 65,536 a single file can hold, broken down by
+// package like the dex-method-counts tool
+//
+// A file can only refer to as many methods, fields and types as its 16-bit indexes reach, counting those it calls
+// or accesses in other files and the platform as well as its own. The breakdown puts each reference in the package
+// of the class it belongs to, and counts the methods and fields the classes of each package define alongside.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::java::package_name;
+use crate::result_types::*;
+
+// the number of method_ids, field_ids, type_ids or proto_ids a file can hold
+pub const REFERENCE_LIMIT: usize = 65536;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReferenceCounts {
+    // in the order the files were given
+    pub files: Vec<FileCounts>,
+    // in name order, the packages with no classes of their own but subpackages that do included
+    pub packages: Vec<PackageCounts>
+}
+
+// The sizes of a file's pools
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FileCounts {
+    pub methods: usize,
+    pub fields: usize,
+    pub types: usize,
+    pub prototypes: usize,
+    pub strings: usize
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PackageCounts {
+    // `com.example`, or the empty string for the default package
+    pub name: String,
+    // the package's own classes
+    pub own: Counts,
+    // the package's own classes and those of its subpackages
+    pub total: Counts
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Counts {
+    // method_ids and field_ids, summed over the files
+    pub referenced_methods: usize,
+    pub referenced_fields: usize,
+    // methods and fields in the class_data of classes
+    pub defined_methods: usize,
+    pub defined_fields: usize
+}
+
+pub fn count(files: &[&DexFile]) -> ReferenceCounts {
+    let mut own: BTreeMap<String, Counts> = BTreeMap::new();
+    for file in files {
+        for method in &file.file_data.methods {
+            own.entry(package_name(&method.definer)).or_default().referenced_methods += 1;
+        }
+        for field in &file.file_data.fields {
+            own.entry(package_name(&field.definer)).or_default().referenced_fields += 1;
+        }
+        for class in &file.classes {
+            let counts = own.entry(package_name(&class.class_type)).or_default();
+            if let Some(ref data) = class.class_data {
+                counts.defined_methods += data.direct_methods.len() + data.virtual_methods.len();
+                counts.defined_fields += data.static_fields.len() + data.instance_fields.len();
+            }
+        }
+    }
+
+    // every package with counts adds to its own total and to those of each package above it
+    let mut packages: BTreeMap<String, PackageCounts> = BTreeMap::new();
+    for (name, counts) in &own {
+        let mut ancestor = Some(name.as_str());
+        while let Some(current) = ancestor {
+            let package = packages.entry(current.to_string())
+                .or_insert_with(|| PackageCounts { name: current.to_string(), own: Counts::default(),
+                                                   total: Counts::default() });
+            package.total.add(counts);
+            ancestor = if current.is_empty() { None } else { Some(current.rsplit_once('.').map_or("", |(p, _)| p)) };
+        }
+        packages.get_mut(name).unwrap().own = *counts;
+    }
+
+    ReferenceCounts {
+        files: files.iter().map(|file| FileCounts {
+            methods: file.file_data.methods.len(),
+            fields: file.file_data.fields.len(),
+            types: file.file_data.type_identifiers.len(),
+            prototypes: file.file_data.prototypes.len(),
+            strings: file.file_data.string_data.len()
+        }).collect(),
+        packages: packages.into_values().collect()
+    }
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.referenced_methods += other.referenced_methods;
+        self.referenced_fields += other.referenced_fields;
+        self.defined_methods += other.defined_methods;
+        self.defined_fields += other.defined_fields;
+    }
+}
+
+impl FileCounts {
+    // whether any of the pools with 16-bit indexes is past the limit
+    pub fn over_limit(&self) -> bool {
+        self.methods > REFERENCE_LIMIT || self.fields > REFERENCE_LIMIT || self.types > REFERENCE_LIMIT
+            || self.prototypes > REFERENCE_LIMIT
+    }
+}
+
+impl ReferenceCounts {
+    // The packages whose own classes take up the most method references, the most first
+    pub fn largest(&self, n: usize) -> Vec<&PackageCounts> {
+        let mut packages = self.packages.iter().filter(|p| p.own.referenced_methods > 0).collect::<Vec<_>>();
+        packages.sort_by(|a, b| b.own.referenced_methods.cmp(&a.own.referenced_methods).then(a.name.cmp(&b.name)));
+        packages.truncate(n);
+        packages
+    }
+}
+
+// The pools of each file with how much of the limit they use, then the package tree with the totals of each package
+// and its subpackages, then the ten packages with the most method references of their own
+impl fmt::Display for ReferenceCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let used = |n: usize| format!("{} ({:.1}%)", n, n as f64 * 100.0 / REFERENCE_LIMIT as f64);
+        writeln!(f, "{:<6}{:<18}{:<18}{:<18}{:<18}strings", "file", "methods", "fields", "types", "prototypes")?;
+        for (i, file) in self.files.iter().enumerate() {
+            writeln!(f, "{:<6}{:<18}{:<18}{:<18}{:<18}{}{}", i, used(file.methods), used(file.fields), used(file.types),
+                     used(file.prototypes), file.strings, if file.over_limit() { "  over the limit" } else { "" })?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<48}{:>10}{:>10}{:>10}{:>10}", "package", "methods", "defined", "fields", "defined")?;
+        for package in &self.packages {
+            let (depth, name) = match package.name.as_str() {
+                "" => (0, "<root>"),
+                name => (name.matches('.').count() + 1, name.rsplit('.').next().unwrap())
+            };
+            let total = &package.total;
+            writeln!(f, "{:<48}{:>10}{:>10}{:>10}{:>10}", format!("{}{}", "  ".repeat(depth), name),
+                     total.referenced_methods, total.defined_methods, total.referenced_fields, total.defined_fields)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "most method references")?;
+        for package in self.largest(10) {
+            let name = if package.name.is_empty() { "<default>" } else { &package.name };
+            writeln!(f, "{:<48}{:>10}", name, package.own.referenced_methods)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn class(b: &mut DexBuilder, class_type: &str, methods: &[&str]) -> ClassDefinition {
+        let direct_methods = methods.iter()
+            .map(|name| EncodedMethod { method: b.method(class_type, name, "V", &[]), access_flags: vec!(),
+                                        code: None, system_annotations: Default::default() })
+            .collect();
+        ClassDefinition {
+            class_type: b.type_(class_type), access_flags: vec!(), superclass: None, interfaces: vec!(),
+            source_file_name: None, annotations: None,
+            class_data: Some(ClassData { static_fields: vec!(), instance_fields: vec!(), direct_methods,
+                                         virtual_methods: vec!() }),
+            static_values: vec!(), system_annotations: Default::default()
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        let mut b = DexBuilder::new(35);
+        let main = class(&mut b, "Lcom/example/Main;", &["a", "b"]);
+        let util = class(&mut b, "Lcom/example/util/Util;", &["c"]);
+        let default = class(&mut b, "LTop;", &[]);
+        b.method("Ljava/lang/Object;", "<init>", "V", &[]);
+        b.method("[I", "clone", "Ljava/lang/Object;", &[]);
+        b.field("Lcom/example/Main;", "count", "I");
+        b.class(main).class(util).class(default);
+        let first = b.build().unwrap();
+
+        let mut b = DexBuilder::new(35);
+        let other = class(&mut b, "Lcom/other/Other;", &["d"]);
+        b.class(other);
+        let second = b.build().unwrap();
+
+        let counts = count(&[&first, &second]);
+        assert_eq!(counts.files[0], FileCounts { methods: 5, fields: 1, types: 7, prototypes: 2, strings: 14 });
+        assert_eq!(counts.files[1].methods, 1);
+
+        let package = |name: &str| counts.packages.iter().find(|p| p.name == name).unwrap();
+        assert_eq!(counts.packages.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+                   vec!("", "com", "com.example", "com.example.util", "com.other", "java", "java.lang"));
+        assert_eq!(package("").own, Counts { referenced_methods: 1, referenced_fields: 0, defined_methods: 0,
+                                             defined_fields: 0 });
+        assert_eq!(package("").total.referenced_methods, 6);
+        assert_eq!(package("com.example").own, Counts { referenced_methods: 2, referenced_fields: 1,
+                                                        defined_methods: 2, defined_fields: 0 });
+        assert_eq!(package("com").total, Counts { referenced_methods: 4, referenced_fields: 1, defined_methods: 4,
+                                                  defined_fields: 0 });
+        assert_eq!(package("com").own, Counts::default());
+
+        assert_eq!(counts.largest(2).iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!("com.example", ""));
+        let report = counts.to_string();
+        assert!(report.contains(&format!("\n{:<48}{:>10}{:>10}{:>10}{:>10}\n", "    example", 3, 3, 1, 0)));
+        assert!(report.contains("\ncom.example                                              2\n"));
+    }
+}
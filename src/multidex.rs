@@ -0,0 +1,291 @@
+// Splitting classes over classes.dex, classes2.dex and so on, each within the references a file can hold
+//
+// Classes are packed in order, each going into the current file while the methods, fields, types and prototypes it
+// refers to still fit, otherwise starting the next file. The main dex classes, which an app needs before the runtime
+// loads its secondary files, all go in classes.dex ahead of the rest. They are given outright, or traced through
+// every class they refer to from roots the caller names. The manifest isn't read, so entry points such as the
+// Application and the components it declares have to be passed as roots.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::builder::Pools;
+use crate::error::DexParserError;
+use crate::method_counts::REFERENCE_LIMIT;
+use crate::result_types::*;
+use crate::rewrite::{lift, Rewriter};
+
+// Classes are named by their descriptors, e.g. `Lcom/example/App;`. Nothing is put in the main dex by default
+//
+//     let options = SplitOptions::new()
+//         .main_dex_roots(vec!("Lcom/example/App;".to_string(), "Lcom/example/MainActivity;".to_string()));
+//     let files = dexparser::multidex::split(vec!(file), &options)?;
+#[derive(Debug, Clone, PartialEq)]
+pub struct SplitOptions {
+    pub(crate) max_methods: usize,
+    pub(crate) max_fields: usize,
+    pub(crate) main_dex_classes: Vec<String>,
+    pub(crate) main_dex_roots: Vec<String>
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        SplitOptions {
+            max_methods: REFERENCE_LIMIT,
+            max_fields: REFERENCE_LIMIT,
+            main_dex_classes: vec!(),
+            main_dex_roots: vec!()
+        }
+    }
+}
+
+impl SplitOptions {
+    pub fn new() -> Self {
+        SplitOptions::default()
+    }
+
+    // The most method references to put in one file, at most the 65,536 a file can hold
+    pub fn max_methods(mut self, max: usize) -> Self {
+        self.max_methods = max.min(REFERENCE_LIMIT);
+        self
+    }
+
+    // The most field references to put in one file, at most the 65,536 a file can hold
+    pub fn max_fields(mut self, max: usize) -> Self {
+        self.max_fields = max.min(REFERENCE_LIMIT);
+        self
+    }
+
+    // Classes to put in the main dex as they are, such as those of a main dex list (see parse_main_dex_list)
+    pub fn main_dex_classes(mut self, classes: Vec<String>) -> Self {
+        self.main_dex_classes = classes;
+        self
+    }
+
+    // Classes to put in the main dex along with every class they refer to, directly or not. These are usually the
+    // Application and the activities, services, receivers and providers of the manifest, listed by hand
+    pub fn main_dex_roots(mut self, classes: Vec<String>) -> Self {
+        self.main_dex_roots = classes;
+        self
+    }
+}
+
+// The files in the order they are to be named, the main dex first. Names that aren't classes of the files are
+// ignored, and a class defined more than once is an error
+pub fn split(files: Vec<DexFile>, options: &SplitOptions) -> Result<Vec<DexFile>, DexParserError> {
+    let groups = {
+        let mut order = vec!();
+        let mut uses = HashMap::new();
+        for file in &files {
+            for class in &file.classes {
+                if uses.insert(class.class_type.as_str(), class_pools(file, class)?).is_some() {
+                    return Err(failed(format!("class {} is defined more than once", class.class_type)));
+                }
+                order.push(class.class_type.as_str());
+            }
+        }
+        let main = main_dex(&uses, options);
+        pack(&order, &main, &uses, options)?.into_iter()
+            .map(|group| group.classes.into_iter().map(str::to_string).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+
+    let mut files = files.into_iter();
+    let mut all = match files.next() {
+        Some(file) => Rewriter::new(file)?,
+        None => return Ok(vec!())
+    };
+    for file in files {
+        all.merge(Rewriter::new(file)?)?;
+    }
+    groups.iter()
+        .map(|group| all.split_off(&group.iter().map(String::as_str).collect::<Vec<_>>()).build())
+        .collect()
+}
+
+// classes.dex for the first file, then classes2.dex, classes3.dex and so on
+pub fn file_name(index: usize) -> String {
+    match index {
+        0 => "classes.dex".to_string(),
+        _ => format!("classes{}.dex", index + 1)
+    }
+}
+
+// Each file under its name, ready to go into an APK
+pub fn write_all(files: &[DexFile]) -> Result<Vec<(String, Vec<u8>)>, DexParserError> {
+    files.iter().enumerate()
+        .map(|(i, file)| Ok((file_name(i), crate::write(file)?)))
+        .collect()
+}
+
+// The descriptors of the classes in a main dex list as build tools write it, one class file per line:
+// `com/example/App.class`
+pub fn parse_main_dex_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| format!("L{};", line.trim_end_matches(".class")))
+        .collect()
+}
+
+fn failed(reason: String) -> DexParserError {
+    DexParserError::WritingFailed { reason }
+}
+
+// What a class adds to the pools of the file it goes in, code included
+fn class_pools(file: &DexFile, class: &ClassDefinition) -> Result<Pools, DexParserError> {
+    let mut pools = Pools::default();
+    pools.class(class)?;
+    let methods = class.class_data.iter()
+        .flat_map(|data| data.direct_methods.iter().chain(data.virtual_methods.iter()));
+    for code in methods.filter_map(|method| method.code.as_ref()) {
+        for reference in lift(file, code)? {
+            pools.reference(&reference.target)?;
+        }
+    }
+    Ok(pools)
+}
+
+fn main_dex<'a>(uses: &HashMap<&'a str, Pools>, options: &SplitOptions) -> HashSet<&'a str> {
+    let defined = |name: &str| uses.get_key_value(name).map(|(class, _)| *class);
+    let mut main = options.main_dex_classes.iter().filter_map(|name| defined(name)).collect::<HashSet<_>>();
+
+    let mut traced = HashSet::new();
+    let mut queue = options.main_dex_roots.iter().filter_map(|name| defined(name)).collect::<Vec<_>>();
+    while let Some(class) = queue.pop() {
+        if !traced.insert(class) {
+            continue;
+        }
+        // arrays of a class need the class itself
+        queue.extend(uses[class].types.iter().filter_map(|type_| defined(type_.trim_start_matches('['))));
+    }
+    main.extend(traced);
+    main
+}
+
+#[derive(Default)]
+struct Group<'a> {
+    classes: Vec<&'a str>,
+    pools: Pools
+}
+
+impl<'a> Group<'a> {
+    fn fits(&self, pools: &Pools, options: &SplitOptions) -> bool {
+        fn with<T: Eq + std::hash::Hash>(total: &HashSet<T>, added: &HashSet<T>) -> usize {
+            total.len() + added.iter().filter(|item| !total.contains(*item)).count()
+        }
+        with(&self.pools.methods, &pools.methods) <= options.max_methods
+            && with(&self.pools.fields, &pools.fields) <= options.max_fields
+            && with(&self.pools.types, &pools.types) <= REFERENCE_LIMIT
+            && with(&self.pools.prototypes, &pools.prototypes) <= REFERENCE_LIMIT
+    }
+
+    fn add(&mut self, class: &'a str, pools: &Pools) {
+        self.classes.push(class);
+        self.pools.methods.extend(pools.methods.iter().cloned());
+        self.pools.fields.extend(pools.fields.iter().cloned());
+        self.pools.types.extend(pools.types.iter().cloned());
+        self.pools.prototypes.extend(pools.prototypes.iter().cloned());
+    }
+}
+
+fn pack<'a>(order: &[&'a str], main: &HashSet<&str>, uses: &HashMap<&str, Pools>, options: &SplitOptions)
+            -> Result<Vec<Group<'a>>, DexParserError> {
+    let mut groups = vec!(Group::default());
+    for class in order.iter().filter(|class| main.contains(*class)) {
+        if !groups[0].fits(&uses[class], options) {
+            return Err(failed(format!("the main dex classes need more references than one file can hold, \
+                                       from {} on", class)));
+        }
+        groups[0].add(class, &uses[class]);
+    }
+
+    for class in order.iter().filter(|class| !main.contains(*class)) {
+        let pools = &uses[class];
+        if !groups.last().unwrap().fits(pools, options) {
+            groups.push(Group::default());
+            if !groups.last().unwrap().fits(pools, options) {
+                return Err(failed(format!("class {} needs more references than one file can hold", class)));
+            }
+        }
+        groups.last_mut().unwrap().add(class, pools);
+    }
+    groups.retain(|group| !group.classes.is_empty());
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn class(b: &mut DexBuilder, class_type: &str, superclass: Option<&str>, methods: &[&str]) -> ClassDefinition {
+        let direct_methods = methods.iter()
+            .map(|name| EncodedMethod { method: b.method(class_type, name, "V", &[]),
+                                        access_flags: vec!(AccessFlag::ACC_STATIC), code: None,
+                                        system_annotations: Default::default() })
+            .collect();
+        ClassDefinition {
+            class_type: b.type_(class_type), access_flags: vec!(), superclass: superclass.map(|s| b.type_(s)),
+            interfaces: vec!(), source_file_name: None, annotations: None,
+            class_data: Some(ClassData { static_fields: vec!(), instance_fields: vec!(), direct_methods,
+                                         virtual_methods: vec!() }),
+            static_values: vec!(), system_annotations: Default::default()
+        }
+    }
+
+    fn app() -> DexFile {
+        let mut b = DexBuilder::new(35);
+        let classes = vec!(
+            class(&mut b, "LOther1;", None, &["a", "b"]),
+            class(&mut b, "LApp;", None, &["run"]),
+            class(&mut b, "LBase;", None, &["base"]),
+            class(&mut b, "LHelper;", Some("LBase;"), &["help"]),
+            class(&mut b, "LOther2;", None, &["c", "d"])
+        );
+        for class in classes {
+            b.class(class);
+        }
+        let mut file = b.build().unwrap();
+
+        // App.run calls Helper.help, which is all that ties them together
+        let help = file.file_data.methods.iter().position(|m| *m.name == "help").unwrap() as u16;
+        let app = file.classes.iter_mut().find(|c| *c.class_type == "LApp;").unwrap();
+        app.class_data.as_mut().unwrap().direct_methods[0].code = Some(Code {
+            registers_size: 0, ins_size: 0, outs_size: 0, debug_info: None,
+            insns: vec!(0x0071, help, 0x0000, 0x000E), tries: vec!(), handlers: vec!()
+        });
+        file
+    }
+
+    fn class_names(file: &DexFile) -> Vec<&str> {
+        file.classes.iter().map(|class| class.class_type.as_str()).collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let options = SplitOptions::new().max_methods(4).main_dex_roots(vec!("LApp;".to_string()));
+        let files = split(vec!(app()), &options).unwrap();
+        assert_eq!(files.iter().map(class_names).collect::<Vec<_>>(),
+                   vec!(vec!("LApp;", "LBase;", "LHelper;"), vec!("LOther1;", "LOther2;")));
+        assert_eq!(files[0].file_data.methods.len(), 3);
+
+        let written = write_all(&files).unwrap();
+        assert_eq!(written.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(),
+                   vec!("classes.dex", "classes2.dex"));
+        for (file, (_, bytes)) in files.iter().zip(&written) {
+            assert_eq!(crate::parse(bytes).unwrap(), *file);
+        }
+
+        // an explicit list is taken as it is, without tracing, and the rest fill up the main dex after it
+        let options = SplitOptions::new().max_methods(4)
+            .main_dex_classes(parse_main_dex_list("# main dex\nOther2.class\n\nMissing.class\n"));
+        let files = split(vec!(app()), &options).unwrap();
+        assert_eq!(files.iter().map(class_names).collect::<Vec<_>>(),
+                   vec!(vec!("LOther1;", "LOther2;"), vec!("LApp;", "LBase;", "LHelper;")));
+
+        let options = SplitOptions::new().max_methods(2).main_dex_roots(vec!("LApp;".to_string()));
+        assert!(split(vec!(app()), &options).is_err());
+        assert!(split(vec!(app(), app()), &SplitOptions::new()).is_err());
+        assert_eq!(split(vec!(app()), &SplitOptions::new()).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::limits::ParseLimits;
+
+// Controls how much of a file is decoded and how strictly. Everything is decoded by default:
+//
+//     let options = ParseOptions::new()
+//         .skip_code(true)
+//         .skip_annotations(true)
+//         .verify_checksum(true);
+//     let file = dexparser::parse_with_options(&bytes, options)?;
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParseOptions {
+    pub(crate) limits: ParseLimits,
+    pub(crate) skip_code: bool,
+    pub(crate) skip_debug_info: bool,
+    pub(crate) skip_annotations: bool,
+    pub(crate) skip_static_values: bool,
+    pub(crate) verify_checksum: bool,
+    pub(crate) lenient: bool
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        ParseOptions::default()
+    }
+
+    // Bounds on the resources a single file may use (see ParseLimits)
+    pub fn limits(mut self, limits: ParseLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Don't decode method bodies: every EncodedMethod will have no code. This implies skipping debug info
+    pub fn skip_code(mut self, skip: bool) -> Self {
+        self.skip_code = skip;
+        self
+    }
+
+    // Don't decode debug_info_items: every Code will have no debug info
+    pub fn skip_debug_info(mut self, skip: bool) -> Self {
+        self.skip_debug_info = skip;
+        self
+    }
+
+    // Don't decode annotations directories: every class will have no annotations
+    pub fn skip_annotations(mut self, skip: bool) -> Self {
+        self.skip_annotations = skip;
+        self
+    }
+
+    // Don't decode the initial values of static fields: every class will have empty static values
+    pub fn skip_static_values(mut self, skip: bool) -> Self {
+        self.skip_static_values = skip;
+        self
+    }
+
+    // Check the adler32 checksum in the header against the file contents before parsing,
+    // failing with DexParserError::ChecksumMismatch if they differ
+    pub fn verify_checksum(mut self, verify: bool) -> Self {
+        self.verify_checksum = verify;
+        self
+    }
+
+    // Rather than failing the whole file, drop the parts of a class that can't be decoded (class data,
+    // code, debug info, annotations or static values) and carry on. Exceeded limits are still errors
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+}
@@ -0,0 +1,497 @@
+// Works out which class, member or package every byte of the file is there for (see sizes.rs)
+//
+// Items are followed from the classes that use them: a class's own data, annotations and static values, the code
+// and debug info of its methods, and from those the entries of the identifier pools and the strings, type lists
+// and encoded arrays they lead to. What one member uses belongs to it, what several use to the innermost class or
+// package holding all of them, and what nothing uses (the header, the map list, padding) to the root package.
+
+use std::collections::HashMap;
+
+use super::*;
+use super::raw_types::*;
+use super::encoded_value::EncodedValueType;
+use super::parse_data::{parse_annotation_set_item, parse_annotation_set_ref_list, parse_annotations_directory_item,
+                        parse_class_data_item, parse_code_item, parse_debug_info_item,
+                        parse_encoded_catch_handler_list, parse_string_data_item};
+use crate::instructions::{self, IndexKind};
+use crate::java;
+use crate::sizes::Category;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Owner {
+    // the type_ids index of the class, and the method_ids index of the method
+    Method(u32, u32),
+    // the type_ids index of the class, and the field_ids index of the field
+    Field(u32, u32),
+    Class(u32),
+    // dotted, the root package being the empty string
+    Package(String)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Attribution {
+    pub owner: Owner,
+    pub category: Category,
+    pub size: u32
+}
+
+impl Owner {
+    fn class(&self) -> Option<u32> {
+        match *self {
+            Owner::Method(class, _) | Owner::Field(class, _) | Owner::Class(class) => Some(class),
+            Owner::Package(_) => None
+        }
+    }
+}
+
+struct Attributor<'a> {
+    input: &'a [u8],
+    raw: &'a RawDexFile,
+    e: nom::Endianness,
+    ctx: &'a ParseContext,
+    // the package of every class the file defines, by type index
+    packages: HashMap<u32, String>,
+    strings: Vec<Option<Owner>>,
+    types: Vec<Option<Owner>>,
+    prototypes: Vec<Option<Owner>>,
+    fields: Vec<Option<Owner>>,
+    methods: Vec<Option<Owner>>,
+    call_sites: Vec<Option<Owner>>,
+    method_handles: Vec<Option<Owner>>,
+    // data items, by offset
+    items: HashMap<usize, Owner>
+}
+
+pub fn attribute(input: &[u8], raw: &RawDexFile, e: nom::Endianness,
+                 ctx: &ParseContext) -> Result<Vec<Attribution>, DexParserError> {
+    let mut packages = HashMap::new();
+    for class in &raw.class_def_items {
+        let descriptor = string(input, raw, *raw.type_id_items.get(class.class_idx as usize).unwrap_or(&NO_INDEX))?;
+        packages.insert(class.class_idx, java::package_name(&descriptor));
+    }
+
+    let mut attributor = Attributor {
+        input, raw, e, ctx, packages,
+        strings: vec!(None; raw.string_id_items.len()),
+        types: vec!(None; raw.type_id_items.len()),
+        prototypes: vec!(None; raw.proto_id_items.len()),
+        fields: vec!(None; raw.field_id_items.len()),
+        methods: vec!(None; raw.method_id_items.len()),
+        call_sites: vec!(None; raw.call_site_idxs.as_ref().map_or(0, |c| c.len())),
+        method_handles: vec!(None; raw.method_handle_idxs.as_ref().map_or(0, |m| m.len())),
+        items: HashMap::new()
+    };
+
+    for class in &raw.class_def_items {
+        attributor.class(class)?;
+    }
+    attributor.pools()?;
+    attributor.attributions()
+}
+
+// The string an index in string_ids refers to
+fn string(input: &[u8], raw: &RawDexFile, idx: u32) -> Result<String, DexParserError> {
+    let off = lookup(&raw.string_id_items, idx, "string")?;
+    Ok(parse_string_data_item(slice_from(input, off)?)?.1.data)
+}
+
+impl<'a> Attributor<'a> {
+    fn class(&mut self, class: &RawClassDefinition) -> Result<(), DexParserError> {
+        let owner = Owner::Class(class.class_idx);
+        self.use_index(IndexKind::Type, class.class_idx, &owner);
+        self.use_index(IndexKind::Type, class.superclass_idx, &owner);
+        self.use_index(IndexKind::String, class.source_file_idx, &owner);
+        if class.interfaces_off != 0 {
+            self.type_list(class.interfaces_off, &owner)?;
+        }
+
+        if class.annotations_off != 0 {
+            self.use_item(class.annotations_off, &owner);
+            let directory = parse_annotations_directory_item(slice_from(self.input, class.annotations_off)?,
+                                                             self.e, self.ctx)?.1;
+            self.annotation_set(directory.class_annotations_off, &owner)?;
+            for annotation in directory.fld_annot.unwrap_or_default() {
+                self.annotation_set(annotation.annotations_offset,
+                                    &Owner::Field(class.class_idx, annotation.field_idx))?;
+            }
+            for annotation in directory.mtd_annot.unwrap_or_default() {
+                self.annotation_set(annotation.annotations_offset,
+                                    &Owner::Method(class.class_idx, annotation.method_idx))?;
+            }
+            for annotation in directory.prm_annot.unwrap_or_default() {
+                let owner = Owner::Method(class.class_idx, annotation.method_idx);
+                self.use_item(annotation.annotations_offset, &owner);
+                let list = parse_annotation_set_ref_list(slice_from(self.input, annotation.annotations_offset)?,
+                                                         self.e, self.ctx)?.1;
+                for set in list.entries {
+                    self.annotation_set(set, &owner)?;
+                }
+            }
+        }
+
+        if class.class_data_off != 0 {
+            self.use_item(class.class_data_off, &owner);
+            let data = parse_class_data_item(slice_from(self.input, class.class_data_off)?, self.ctx)?.1;
+            for fields in &[data.static_fields, data.instance_fields] {
+                let mut idx = 0_u32;
+                for field in fields {
+                    idx = idx.wrapping_add(field.field_idx_diff);
+                    self.use_index(IndexKind::Field, idx, &Owner::Field(class.class_idx, idx));
+                }
+            }
+            for methods in &[data.direct_methods, data.virtual_methods] {
+                let mut idx = 0_u32;
+                for method in methods {
+                    idx = idx.wrapping_add(method.method_idx_diff);
+                    let owner = Owner::Method(class.class_idx, idx);
+                    self.use_index(IndexKind::Method, idx, &owner);
+                    if method.code_off != 0 {
+                        self.code(method.code_off, &owner)?;
+                    }
+                }
+            }
+        }
+
+        if class.static_values_off != 0 {
+            self.encoded_array(class.static_values_off, &owner)?;
+        }
+        Ok(())
+    }
+
+    fn code(&mut self, off: u32, owner: &Owner) -> Result<(), DexParserError> {
+        self.use_item(off, owner);
+        let data = slice_from(self.input, off)?;
+        let (rest, code) = parse_code_item(data, self.e, self.ctx)?;
+
+        // bytecode that doesn't decode still takes up its space, it just can't lead anywhere else
+        for insn in instructions::decode_all(&code.insns).unwrap_or_default() {
+            self.ctx.work(1)?;
+            if let Some(index) = insn.index {
+                self.use_index(insn.opcode.index_kind(), index, owner);
+            }
+            if let Some(proto) = insn.proto_index {
+                self.use_index(IndexKind::Prototype, proto, owner);
+            }
+        }
+
+        if code.tries_size != 0 {
+            for handler in parse_encoded_catch_handler_list(rest, self.ctx)?.1.list {
+                for pair in handler.handlers {
+                    self.use_index(IndexKind::Type, pair.type_idx, owner);
+                }
+            }
+        }
+
+        if code.debug_info_off != 0 {
+            self.debug_info(code.debug_info_off, owner)?;
+        }
+        Ok(())
+    }
+
+    // Docs: debug_info_item, the strings and types the state machine names
+    fn debug_info(&mut self, off: u32, owner: &Owner) -> Result<(), DexParserError> {
+        self.use_item(off, owner);
+        let (mut input, debug_info) = parse_debug_info_item(slice_from(self.input, off)?, self.ctx)?;
+        for name in debug_info.parameter_names {
+            self.use_index(IndexKind::String, name as u32, owner);
+        }
+
+        // uleb128p1 indexes are NO_INDEX once one is taken off
+        loop {
+            self.ctx.work(1)?;
+            let (rest, opcode) = take_one(input)?;
+            input = match opcode {
+                // DBG_END_SEQUENCE
+                0x00 => return Ok(()),
+                // DBG_ADVANCE_PC, DBG_END_LOCAL, DBG_RESTART_LOCAL
+                0x01 | 0x05 | 0x06 => parse_uleb128(rest)?.0,
+                // DBG_ADVANCE_LINE
+                0x02 => parse_sleb128(rest)?.0,
+                // DBG_START_LOCAL, DBG_START_LOCAL_EXTENDED
+                0x03 | 0x04 => {
+                    let (rest, (_, name, type_)) = tuple!(rest, parse_uleb128, parse_uleb128p1, parse_uleb128p1)?;
+                    self.use_index(IndexKind::String, name as u32, owner);
+                    self.use_index(IndexKind::Type, type_ as u32, owner);
+                    if opcode == 0x04 {
+                        let (rest, signature) = parse_uleb128p1(rest)?;
+                        self.use_index(IndexKind::String, signature as u32, owner);
+                        rest
+                    } else {
+                        rest
+                    }
+                },
+                // DBG_SET_FILE
+                0x09 => {
+                    let (rest, name) = parse_uleb128p1(rest)?;
+                    self.use_index(IndexKind::String, name as u32, owner);
+                    rest
+                },
+                // DBG_SET_PROLOGUE_END, DBG_SET_EPILOGUE_BEGIN and special opcodes have no arguments
+                _ => rest
+            };
+        }
+    }
+
+    fn type_list(&mut self, off: u32, owner: &Owner) -> Result<(), DexParserError> {
+        self.use_item(off, owner);
+        for type_ in parse_type_list(slice_from(self.input, off)?, self.e, self.ctx)?.1.list {
+            self.use_index(IndexKind::Type, u32::from(type_), owner);
+        }
+        Ok(())
+    }
+
+    fn annotation_set(&mut self, off: u32, owner: &Owner) -> Result<(), DexParserError> {
+        if off == 0 {
+            return Ok(());
+        }
+        self.use_item(off, owner);
+        for annotation in parse_annotation_set_item(slice_from(self.input, off)?, self.e, self.ctx)?.1.entries {
+            self.use_item(annotation, owner);
+            // past the visibility
+            let data = slice_from(self.input, annotation.saturating_add(1))?;
+            self.encoded_annotation(data, owner)?;
+        }
+        Ok(())
+    }
+
+    fn encoded_array(&mut self, off: u32, owner: &Owner) -> Result<(), DexParserError> {
+        self.use_item(off, owner);
+        self.encoded_array_values(slice_from(self.input, off)?, owner)?;
+        Ok(())
+    }
+
+    // Docs: encoded_array
+    fn encoded_array_values<'b>(&mut self, data: &'b [u8], owner: &Owner) -> nom::IResult<&'b [u8], ()> {
+        let _depth = self.ctx.nest()?;
+        let (mut data, size) = parse_uleb128(data)?;
+        for _ in 0..size {
+            data = self.encoded_value(data, owner)?.0;
+        }
+        Ok((data, ()))
+    }
+
+    // Docs: encoded_annotation
+    fn encoded_annotation<'b>(&mut self, data: &'b [u8], owner: &Owner) -> nom::IResult<&'b [u8], ()> {
+        let _depth = self.ctx.nest()?;
+        let (mut data, (type_, size)) = pair!(data, parse_uleb128, parse_uleb128)?;
+        self.use_index(IndexKind::Type, type_, owner);
+        for _ in 0..size {
+            let (rest, name) = parse_uleb128(data)?;
+            self.use_index(IndexKind::String, name, owner);
+            data = self.encoded_value(rest, owner)?.0;
+        }
+        Ok((data, ()))
+    }
+
+    // Docs: encoded_value
+    fn encoded_value<'b>(&mut self, data: &'b [u8], owner: &Owner) -> nom::IResult<&'b [u8], ()> {
+        self.ctx.work(1)?;
+        let (value, value_type) = take_one(data)?;
+        let value_arg = ((value_type & 0xE0) >> 5) as usize;
+
+        let kind = match EncodedValueType::parse(value_type & 0x1F)? {
+            EncodedValueType::Array => return self.encoded_array_values(value, owner),
+            EncodedValueType::Annotation => return self.encoded_annotation(value, owner),
+            EncodedValueType::Null | EncodedValueType::Boolean => return Ok((value, ())),
+            EncodedValueType::MethodType => IndexKind::Prototype,
+            EncodedValueType::MethodHandle => IndexKind::MethodHandle,
+            EncodedValueType::String => IndexKind::String,
+            EncodedValueType::Type => IndexKind::Type,
+            EncodedValueType::Field | EncodedValueType::Enum => IndexKind::Field,
+            EncodedValueType::Method => IndexKind::Method,
+            _ => IndexKind::None
+        };
+
+        let (rest, bytes) = take!(value, value_arg + 1)?;
+        if kind != IndexKind::None {
+            let index = bytes.iter().rev().fold(0_u64, |acc, b| (acc << 8) | u64::from(*b));
+            self.use_index(kind, index as u32, owner);
+        }
+        Ok((rest, ()))
+    }
+
+    // Pool entries belong to whatever uses them, so they are gone through from those that can only be used from
+    // code and annotations, to the strings everything else leads to
+    fn pools(&mut self) -> Result<(), DexParserError> {
+        let raw = self.raw;
+        for (i, off) in raw.call_site_idxs.iter().flatten().enumerate() {
+            if let Some(owner) = self.call_sites[i].clone() {
+                self.encoded_array(*off, &owner)?;
+            }
+        }
+
+        for (i, handle) in raw.method_handle_idxs.iter().flatten().enumerate() {
+            if let Some(owner) = self.method_handles[i].clone() {
+                // Docs: method handle type codes, the first four access fields and the rest invoke methods
+                let kind = if handle.type_ <= 0x03 { IndexKind::Field } else { IndexKind::Method };
+                self.use_index(kind, u32::from(handle.field_or_method_id), &owner);
+            }
+        }
+
+        for (i, field) in raw.field_id_items.iter().enumerate() {
+            if let Some(owner) = self.fields[i].clone() {
+                self.use_index(IndexKind::Type, u32::from(field.class_idx), &owner);
+                self.use_index(IndexKind::Type, u32::from(field.type_idx), &owner);
+                self.use_index(IndexKind::String, field.name_idx, &owner);
+            }
+        }
+
+        for (i, method) in raw.method_id_items.iter().enumerate() {
+            if let Some(owner) = self.methods[i].clone() {
+                self.use_index(IndexKind::Type, u32::from(method.class_idx), &owner);
+                self.use_index(IndexKind::Prototype, u32::from(method.proto_idx), &owner);
+                self.use_index(IndexKind::String, method.name_idx, &owner);
+            }
+        }
+
+        for (i, prototype) in raw.proto_id_items.iter().enumerate() {
+            if let Some(owner) = self.prototypes[i].clone() {
+                self.use_index(IndexKind::String, prototype.shorty_idx, &owner);
+                self.use_index(IndexKind::Type, prototype.return_type_idx, &owner);
+                if prototype.parameters_off != 0 {
+                    self.type_list(prototype.parameters_off, &owner)?;
+                }
+            }
+        }
+
+        for (i, descriptor) in raw.type_id_items.iter().enumerate() {
+            if let Some(owner) = self.types[i].clone() {
+                self.use_index(IndexKind::String, *descriptor, &owner);
+            }
+        }
+
+        for (i, off) in raw.string_id_items.iter().enumerate() {
+            if let Some(owner) = self.strings[i].clone() {
+                self.use_item(*off, &owner);
+            }
+        }
+        Ok(())
+    }
+
+    // The bytes of every item and pool entry, with what they belong to. Whatever isn't in any item goes to the
+    // root package
+    fn attributions(&self) -> Result<Vec<Attribution>, DexParserError> {
+        let root = Owner::Package(String::new());
+        let mut attributions = vec!();
+        let mut attribute = |owner: Option<&Owner>, category: Category, size: usize| {
+            attributions.push(Attribution { owner: owner.unwrap_or(&root).clone(), category, size: size as u32 });
+        };
+
+        let raw = self.raw;
+        let pools = [(&self.strings, 4), (&self.types, 4), (&self.prototypes, 12), (&self.fields, 8),
+                     (&self.methods, 8), (&self.call_sites, 4), (&self.method_handles, 8)];
+        for (pool, size) in pools.iter() {
+            for owner in pool.iter() {
+                attribute(owner.as_ref(), Category::Ids, *size);
+            }
+        }
+        for class in &raw.class_def_items {
+            attribute(Some(&Owner::Class(class.class_idx)), Category::Ids, 32);
+        }
+        let mut accounted = (raw.string_id_items.len() + raw.type_id_items.len() + self.call_sites.len()) * 4
+            + raw.proto_id_items.len() * 12
+            + (raw.field_id_items.len() + raw.method_id_items.len() + self.method_handles.len()) * 8
+            + raw.class_def_items.len() * 32;
+
+        let map_list = parse_map_list(slice_from(self.input, raw.header.map_off)?, self.e, self.ctx)?.1;
+        for section in &map_list.list {
+            let category = match section.type_ {
+                MapListItemType::CODE_ITEM => Category::Code,
+                MapListItemType::DEBUG_INFO_ITEM => Category::DebugInfo,
+                MapListItemType::STRING_DATA_ITEM => Category::Strings,
+                MapListItemType::ANNOTATION_ITEM | MapListItemType::ANNOTATION_SET_ITEM
+                    | MapListItemType::ANNOTATION_SET_REF_LIST
+                    | MapListItemType::ANNOTATIONS_DIRECTORY_ITEM => Category::Annotations,
+                MapListItemType::CLASS_DATA_ITEM => Category::ClassData,
+                MapListItemType::ENCODED_ARRAY_ITEM => Category::EncodedArrays,
+                MapListItemType::TYPE_LIST => Category::TypeLists,
+                // the identifier sections were counted entry by entry, and the rest is left to the root
+                _ => continue
+            };
+            for (start, end) in layout::item_extents(self.input, section, self.e, self.ctx).unwrap_or_default() {
+                attribute(self.items.get(&start), category, end - start);
+                accounted += end - start;
+            }
+        }
+
+        attribute(None, Category::Other, self.input.len().saturating_sub(accounted));
+        Ok(attributions)
+    }
+
+    fn use_index(&mut self, kind: IndexKind, idx: u32, owner: &Owner) {
+        let pool = match kind {
+            IndexKind::String => &mut self.strings,
+            IndexKind::Type => &mut self.types,
+            IndexKind::Prototype => &mut self.prototypes,
+            IndexKind::Field => &mut self.fields,
+            IndexKind::Method => &mut self.methods,
+            IndexKind::CallSite => &mut self.call_sites,
+            IndexKind::MethodHandle => &mut self.method_handles,
+            IndexKind::None => return
+        };
+        // NO_INDEX and anything else out of range leads nowhere
+        if let Some(slot) = pool.get_mut(idx as usize) {
+            let joined = match slot.take() {
+                Some(current) => join(&self.packages, &current, owner),
+                None => owner.clone()
+            };
+            *slot = Some(joined);
+        }
+    }
+
+    fn use_item(&mut self, off: u32, owner: &Owner) {
+        let joined = match self.items.get(&(off as usize)) {
+            Some(current) => join(&self.packages, current, owner),
+            None => owner.clone()
+        };
+        self.items.insert(off as usize, joined);
+    }
+}
+
+// The innermost owner holding both: the same member, the class both are in, or the package both are under
+fn join(packages: &HashMap<u32, String>, a: &Owner, b: &Owner) -> Owner {
+    if a == b {
+        return a.clone();
+    }
+    if let (Some(class), Some(other)) = (a.class(), b.class()) {
+        if class == other {
+            return Owner::Class(class);
+        }
+    }
+
+    let package = |owner: &Owner| match *owner {
+        Owner::Package(ref package) => package.clone(),
+        _ => owner.class().and_then(|class| packages.get(&class)).cloned().unwrap_or_default()
+    };
+    let (a, b) = (package(a), package(b));
+    let common = a.split('.').zip(b.split('.'))
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x)
+        .collect::<Vec<_>>()
+        .join(".");
+    Owner::Package(common)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join() {
+        let mut packages = HashMap::new();
+        packages.insert(1, "com.example".to_string());
+        packages.insert(2, "com.example.util".to_string());
+        packages.insert(3, "com.other".to_string());
+        packages.insert(4, "org".to_string());
+
+        assert_eq!(join(&packages, &Owner::Method(1, 0), &Owner::Method(1, 0)), Owner::Method(1, 0));
+        assert_eq!(join(&packages, &Owner::Method(1, 0), &Owner::Field(1, 3)), Owner::Class(1));
+        assert_eq!(join(&packages, &Owner::Method(1, 0), &Owner::Class(2)),
+                   Owner::Package("com.example".to_string()));
+        assert_eq!(join(&packages, &Owner::Class(2), &Owner::Class(3)), Owner::Package("com".to_string()));
+        assert_eq!(join(&packages, &Owner::Class(3), &Owner::Package("org".to_string())),
+                   Owner::Package(String::new()));
+        assert_eq!(join(&packages, &Owner::Class(4), &Owner::Package("org".to_string())),
+                   Owner::Package("org".to_string()));
+    }
+}
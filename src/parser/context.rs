@@ -0,0 +1,180 @@
+use std::cell::{Cell, RefCell};
+use std::time::Instant;
+
+use crate::error::DexParserError;
+use crate::limits::ParseLimits;
+use crate::options::ParseOptions;
+
+// How often (in units of work) the clock is checked against the time budget
+const CLOCK_INTERVAL: u64 = 1024;
+
+// State shared by every parser for a single file: the options and limits in force and how much of them is used
+pub struct ParseContext {
+    options: ParseOptions,
+    started: Instant,
+    work: Cell<u64>,
+    depth: Cell<usize>,
+    // nom errors can't carry our own error types, so the first exceeded limit is kept here
+    // and picked up once parsing has unwound
+    exceeded: RefCell<Option<DexParserError>>
+}
+
+impl ParseContext {
+    pub fn new(options: ParseOptions) -> Self {
+        ParseContext {
+            options,
+            started: Instant::now(),
+            work: Cell::new(0),
+            depth: Cell::new(0),
+            exceeded: RefCell::new(None)
+        }
+    }
+
+    pub fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+
+    fn limits(&self) -> &ParseLimits {
+        &self.options.limits
+    }
+
+    // In lenient mode, turn a failure to decode an optional part of the file into None so parsing can
+    // carry on without it. Exceeded limits are never recovered from
+    pub fn recover<T, E>(&self, res: Result<T, E>) -> Result<Option<T>, DexParserError>
+        where DexParserError: From<E> {
+        match res {
+            Ok(value) => Ok(Some(value)),
+            Err(_) if self.options.lenient && self.exceeded.borrow().is_none() => Ok(None),
+            Err(err) => Err(DexParserError::from(err))
+        }
+    }
+
+    // Check a list of `count` items, each taking up `item_size` bytes once parsed
+    pub fn count(&self, count: u32, item_size: usize) -> Result<usize, DexParserError> {
+        let bytes = (count as usize).saturating_mul(item_size);
+        if bytes > self.limits().max_allocation {
+            return Err(self.exceed("allocation", bytes as u64, self.limits().max_allocation as u64));
+        }
+        Ok(count as usize)
+    }
+
+    pub fn classes(&self, count: u32) -> Result<usize, DexParserError> {
+        if count as usize > self.limits().max_classes {
+            return Err(self.exceed("class", u64::from(count), self.limits().max_classes as u64));
+        }
+        self.count(count, std::mem::size_of::<crate::ClassDefinition>())
+    }
+
+    // Record some units of work, checking both the work and time budgets
+    pub fn work(&self, units: u64) -> Result<(), DexParserError> {
+        let before = self.work.get();
+        let after = before.saturating_add(units);
+        self.work.set(after);
+
+        if after > self.limits().max_work {
+            return Err(self.exceed("work", after, self.limits().max_work));
+        }
+
+        if let Some(max_duration) = self.limits().max_duration {
+            if before / CLOCK_INTERVAL != after / CLOCK_INTERVAL {
+                let elapsed = self.started.elapsed();
+                if elapsed > max_duration {
+                    return Err(self.exceed("time (ms)", elapsed.as_millis() as u64, max_duration.as_millis() as u64));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Descend one level into a nested value. Depth is restored when the guard is dropped
+    pub fn nest(&self) -> Result<DepthGuard<'_>, DexParserError> {
+        let depth = self.depth.get() + 1;
+        if depth > self.limits().max_nesting_depth {
+            return Err(self.exceed("nesting depth", depth as u64, self.limits().max_nesting_depth as u64));
+        }
+        self.depth.set(depth);
+        Ok(DepthGuard { ctx: self })
+    }
+
+    // The limit that caused parsing to fail, if any
+    pub fn take_exceeded(&self) -> Option<DexParserError> {
+        self.exceeded.borrow_mut().take()
+    }
+
+    fn exceed(&self, limit: &'static str, requested: u64, allowed: u64) -> DexParserError {
+        let err = DexParserError::LimitExceeded { limit, requested, allowed };
+        let mut exceeded = self.exceeded.borrow_mut();
+        if exceeded.is_none() {
+            *exceeded = Some(err.clone());
+        }
+        err
+    }
+}
+
+impl Default for ParseContext {
+    fn default() -> Self {
+        ParseContext::new(ParseOptions::default())
+    }
+}
+
+pub struct DepthGuard<'a> {
+    ctx: &'a ParseContext
+}
+
+impl<'a> Drop for DepthGuard<'a> {
+    fn drop(&mut self) {
+        self.ctx.depth.set(self.ctx.depth.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_limit() {
+        let ctx = ParseContext::new(ParseOptions::new().limits(ParseLimits { max_allocation: 16, ..ParseLimits::default() }));
+
+        assert_eq!(ctx.count(4, 4).unwrap(), 4);
+        assert!(ctx.count(5, 4).is_err());
+        assert_eq!(ctx.take_exceeded().unwrap().to_string(),
+                   "allocation limit exceeded: requested 20 but at most 16 allowed");
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let ctx = ParseContext::new(ParseOptions::new().limits(ParseLimits { max_nesting_depth: 1, ..ParseLimits::default() }));
+
+        {
+            let _outer = ctx.nest().unwrap();
+            assert!(ctx.nest().is_err());
+        }
+        // the guard dropping should free the level back up
+        assert!(ctx.nest().is_ok());
+    }
+
+    #[test]
+    fn test_work_limit() {
+        let ctx = ParseContext::new(ParseOptions::new().limits(ParseLimits { max_work: 10, ..ParseLimits::default() }));
+
+        assert!(ctx.work(10).is_ok());
+        assert!(ctx.work(1).is_err());
+    }
+
+    #[test]
+    fn test_recover() {
+        let strict = ParseContext::default();
+        assert!(strict.recover::<(), _>(Err(DexParserError::EncodingError)).is_err());
+
+        let lenient = ParseContext::new(ParseOptions::new().lenient(true));
+        assert_eq!(lenient.recover(Ok::<_, DexParserError>(1)).unwrap(), Some(1));
+        assert_eq!(lenient.recover::<(), _>(Err(DexParserError::EncodingError)).unwrap(), None);
+
+        // limits still apply when lenient
+        let limited = ParseContext::new(ParseOptions::new()
+            .lenient(true)
+            .limits(ParseLimits { max_work: 0, ..ParseLimits::default() }));
+        assert!(limited.recover(limited.work(1)).is_err());
+    }
+}
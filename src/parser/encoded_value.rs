@@ -1,31 +1,34 @@
-use super::{parse_uleb128, take_one, Uleb128};
+use super::{parse_uleb128, take_one, lookup, Uleb128};
+use super::context::ParseContext;
 use crate::error::*;
 use crate::result_types::*;
 use byteorder::ByteOrder;
 
 // note that this does NOT peek! that's the responsibility of the calling parser
-pub fn parse_encoded_value_item<'a>(data: &'a[u8], fd: &DexFileData) -> nom::IResult<&'a[u8], EncodedValue> {
+pub fn parse_encoded_value_item<'a>(data: &'a[u8], fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], EncodedValue> {
+    ctx.work(1)?;
     do_parse!(data,
         value_type: call!(take_one) >>
-        value: call!(parse_value, value_type, fd) >>
+        value: call!(parse_value, value_type, fd, ctx) >>
         (value)
     )
 }
 
-pub fn parse_encoded_annotation_item<'a>(data: &'a[u8], fd: &DexFileData) -> nom::IResult<&'a[u8], EncodedAnnotationItem> {
+pub fn parse_encoded_annotation_item<'a>(data: &'a[u8], fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], EncodedAnnotationItem> {
+    let _depth = ctx.nest()?;
     let res = do_parse!(data,
         type_idx: call!(parse_uleb128) >>
         size: call!(parse_uleb128) >>
-        elements: count!(call!(parse_annotation_element_item, fd), size as usize) >>
+        elements: count!(call!(parse_annotation_element_item, fd, ctx), ctx.count(size, std::mem::size_of::<AnnotationElement>())?) >>
         (RawEncodedAnnotationItem { type_idx, size, elements })
     )?;
 
     Ok((res.0, EncodedAnnotationItem {
-        type_: fd.type_identifiers[res.1.type_idx as usize].clone(),
-        values: res.1.elements.into_iter().map(|item| AnnotationElement {
-            name: fd.string_data[item.name_idx as usize].clone(),
+        type_: lookup(&fd.type_identifiers, res.1.type_idx, "type")?,
+        values: res.1.elements.into_iter().map(|item| Ok(AnnotationElement {
+            name: lookup(&fd.string_data, item.name_idx, "string")?,
             value: item.value
-        }).collect()
+        })).collect::<Result<Vec<_>, DexParserError>>()?
     }))
 }
 
@@ -43,19 +46,25 @@ pub struct RawEncodedAnnotationItem {
     pub elements: Vec<RawAnnotationElementItem>
 }
 
-pub fn parse_encoded_array_item<'a>(data: &'a[u8], fd: &DexFileData) -> nom::IResult<&'a[u8], Vec<EncodedValue>> {
+pub fn parse_encoded_array_item<'a>(data: &'a[u8], fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<EncodedValue>> {
+    let _depth = ctx.nest()?;
     do_parse!(data,
         size: call!(parse_uleb128)   >>
-        values: count!(call!(parse_encoded_value_item, fd), size as usize)  >>
+        values: count!(call!(parse_encoded_value_item, fd, ctx), ctx.count(size, std::mem::size_of::<EncodedValue>())?)  >>
         (values)
     )
 }
 
-fn parse_value<'a>(value: &'a[u8], value_type: u8, fd: &DexFileData) -> nom::IResult<&'a[u8], EncodedValue> {
+fn parse_value<'a>(value: &'a[u8], value_type: u8, fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], EncodedValue> {
     // The high order 3 bits of the value type may contain useful size information or data
     let value_arg = ((value_type & 0xE0) >> 5) as i8;
 
-    Ok(match EncodedValueType::parse(value_type & 0x1F)? {
+    let type_ = EncodedValueType::parse(value_type & 0x1F)?;
+    if value_arg > type_.max_value_arg() {
+        return Err(nom::Err::from(DexParserError::from(format!("encoded value of type {:?} can't be {} bytes long", type_, value_arg + 1))));
+    }
+
+    Ok(match type_ {
         EncodedValueType::Byte => {
             map!(value, take!(1), |x| { EncodedValue::Byte(x[0]) })?
         },
@@ -69,52 +78,53 @@ fn parse_value<'a>(value: &'a[u8], value_type: u8, fd: &DexFileData) -> nom::IRe
             map!(value, map!(take!(value_arg + 1), |x| { byteorder::LittleEndian::read_int(x, x.len()) as i32 }), EncodedValue::Int)?
         },
         EncodedValueType::Long => {
-            map!(value, map!(take!(value_arg + 1), |x| { byteorder::LittleEndian::read_int(x, x.len()) as i64 }), EncodedValue::Long)?
+            map!(value, map!(take!(value_arg + 1), |x| { byteorder::LittleEndian::read_int(x, x.len()) }), EncodedValue::Long)?
         },
-        // Floats and Doubles below the maximum byte width should be 0-extended to the right
+        // Floats and Doubles below the maximum byte width should be 0-extended to the right:
+        // the bytes given are the high-order ones, and the missing low-order bytes are zero
         EncodedValueType::Float => map!(value, map!(take!(value_arg + 1), |x| {
-            let mut v = x.to_vec();
-            v.extend(vec![0; 4 - x.len()]);
+            let mut v = vec![0; 4 - x.len()];
+            v.extend(x);
             byteorder::LittleEndian::read_f32(&v)
         }), EncodedValue::Float)?,
         EncodedValueType::Double => map!(value, map!(take!(value_arg + 1), |x| {
-            let mut v = x.to_vec();
-            v.extend(vec![0; 8 - x.len()]);
+            let mut v = vec![0; 8 - x.len()];
+            v.extend(x);
             byteorder::LittleEndian::read_f64(&v)
         }), EncodedValue::Double)?,
         EncodedValueType::MethodType => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::MethodType(fd.prototypes[res.1 as usize].clone()))
+            (res.0, EncodedValue::MethodType(lookup(&fd.prototypes, res.1, "prototype")?))
         },
         EncodedValueType::MethodHandle => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::MethodHandle(fd.methods[res.1 as usize].clone()))
+            (res.0, EncodedValue::MethodHandle(lookup(&fd.method_handles, res.1, "method handle")?))
         },
         EncodedValueType::String => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::String(fd.string_data[res.1 as usize].clone()))
+            (res.0, EncodedValue::String(lookup(&fd.string_data, res.1, "string")?))
         },
         EncodedValueType::Type => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::Type(fd.type_identifiers[res.1 as usize].clone()))
+            (res.0, EncodedValue::Type(lookup(&fd.type_identifiers, res.1, "type")?))
         },
         EncodedValueType::Field => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::Field(fd.fields[res.1 as usize].clone()))
+            (res.0, EncodedValue::Field(lookup(&fd.fields, res.1, "field")?))
         }
         EncodedValueType::Method => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::Method(fd.methods[res.1 as usize].clone()))
+            (res.0, EncodedValue::Method(lookup(&fd.methods, res.1, "method")?))
         },
         EncodedValueType::Enum => {
             let res = call!(value, convert_variable_u32, value_arg + 1)?;
-            (res.0, EncodedValue::Enum(fd.fields[res.1 as usize].clone()))
+            (res.0, EncodedValue::Enum(lookup(&fd.fields, res.1, "field")?))
         },
         EncodedValueType::Array => {
-            map!(value, call!(parse_encoded_array_item, fd), EncodedValue::Array)?
+            map!(value, call!(parse_encoded_array_item, fd, ctx), EncodedValue::Array)?
         },
         EncodedValueType::Annotation => {
-            map!(value, call!(parse_encoded_annotation_item, fd), EncodedValue::Annotation)?
+            map!(value, call!(parse_encoded_annotation_item, fd, ctx), EncodedValue::Annotation)?
         },
         EncodedValueType::Null => (value, EncodedValue::Null),
         // The value for boolean types is the last bit of the value arg
@@ -123,20 +133,54 @@ fn parse_value<'a>(value: &'a[u8], value_type: u8, fd: &DexFileData) -> nom::IRe
 }
 
 // Docs: annotation_element_item
-fn parse_annotation_element_item<'a>(data: &'a[u8], fd: &DexFileData) -> nom::IResult<&'a[u8], RawAnnotationElementItem> {
+fn parse_annotation_element_item<'a>(data: &'a[u8], fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], RawAnnotationElementItem> {
     do_parse!(data,
         name_idx: call!(parse_uleb128)   >>
-        value: call!(parse_encoded_value_item, fd)   >>
+        value: call!(parse_encoded_value_item, fd, ctx)   >>
         (RawAnnotationElementItem { name_idx, value })
     )
 }
 
+// Walks over an encoded_value without resolving any indexes, for when only its size is needed
+pub fn skip_encoded_value_item<'a>(data: &'a[u8], ctx: &ParseContext) -> nom::IResult<&'a[u8], ()> {
+    ctx.work(1)?;
+    let (value, value_type) = take_one(data)?;
+    let value_arg = ((value_type & 0xE0) >> 5) as usize;
+
+    match EncodedValueType::parse(value_type & 0x1F)? {
+        EncodedValueType::Byte => map!(value, take!(1), |_| ()),
+        EncodedValueType::Array => skip_encoded_array_item(value, ctx),
+        EncodedValueType::Annotation => skip_encoded_annotation_item(value, ctx),
+        EncodedValueType::Null | EncodedValueType::Boolean => Ok((value, ())),
+        _ => map!(value, take!(value_arg + 1), |_| ())
+    }
+}
+
+pub fn skip_encoded_array_item<'a>(data: &'a[u8], ctx: &ParseContext) -> nom::IResult<&'a[u8], ()> {
+    let _depth = ctx.nest()?;
+    do_parse!(data,
+        size: call!(parse_uleb128)  >>
+        count!(call!(skip_encoded_value_item, ctx), size as usize)  >>
+        (())
+    )
+}
+
+pub fn skip_encoded_annotation_item<'a>(data: &'a[u8], ctx: &ParseContext) -> nom::IResult<&'a[u8], ()> {
+    let _depth = ctx.nest()?;
+    do_parse!(data,
+        call!(parse_uleb128)    >>
+        size: call!(parse_uleb128)  >>
+        count!(pair!(parse_uleb128, call!(skip_encoded_value_item, ctx)), size as usize)    >>
+        (())
+    )
+}
+
 named_args!(convert_variable_u32(size: i8)<&[u8], u32>,
     map!(take!(size), |x| { byteorder::LittleEndian::read_uint(x, x.len()) as u32 })
 );
 
 #[derive(Debug)]
-enum EncodedValueType {
+pub enum EncodedValueType {
     Byte,
     Short,
     Char,
@@ -158,7 +202,20 @@ enum EncodedValueType {
 }
 
 impl EncodedValueType {
-    fn parse(value: u8) -> Result<Self, DexParserError> {
+    // Docs: encoded_value, the largest value_arg (byte count - 1) each type allows for its payload
+    fn max_value_arg(&self) -> i8 {
+        match self {
+            EncodedValueType::Byte => 0,
+            EncodedValueType::Short | EncodedValueType::Char => 1,
+            EncodedValueType::Long | EncodedValueType::Double => 7,
+            // these don't use the value arg to size a payload
+            EncodedValueType::Array | EncodedValueType::Annotation
+                | EncodedValueType::Null | EncodedValueType::Boolean => 7,
+            _ => 3
+        }
+    }
+
+    pub fn parse(value: u8) -> Result<Self, DexParserError> {
         match value {
             0x00 => Ok(EncodedValueType::Byte),
             0x02 => Ok(EncodedValueType::Short),
@@ -189,13 +246,16 @@ mod tests {
     use super::*;
     use byteorder::*;
     use std::rc::Rc;
+    use crate::limits::ParseLimits;
+    use crate::options::ParseOptions;
 
     #[test]
     fn test_empty_encoded_value_item() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let writer = vec!();
 
-        let err = parse_encoded_value_item(&writer, &fd);
+        let err = parse_encoded_value_item(&writer, &fd, &ctx);
 
         assert!(err.is_err());
         assert_eq!(err.err().unwrap(), nom::Err::Incomplete(nom::Needed::Size(1)));
@@ -204,10 +264,11 @@ mod tests {
     #[test]
     fn test_invalid_encoded_value_item_type() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
 
         writer.write_u8(0x01).unwrap();
-        let err = parse_encoded_value_item(&writer, &fd);
+        let err = parse_encoded_value_item(&writer, &fd, &ctx);
 
         assert!(err.is_err());
     }
@@ -215,19 +276,20 @@ mod tests {
     #[test]
     fn test_parse_byte_value() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
 
         let mut writer = vec!();
         // value type
         writer.write_u8(0b00000000).unwrap();
 
         // with no following byte value
-        let err = parse_encoded_value_item(&writer, &fd);
+        let err = parse_encoded_value_item(&writer, &fd, &ctx);
         assert!(err.is_err());
 
         // add in a value
         writer.write_u8(0x01).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Byte(0x01));
     }
@@ -235,14 +297,15 @@ mod tests {
     #[test]
     fn test_parse_short_value_single_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
 
         let mut writer = vec!();
         // value type
         writer.write_u8(0b00000010).unwrap();
         // value
-        writer.write_u8(1 as u8).unwrap();
+        writer.write_u8(1_u8).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Short(1))
     }
@@ -250,27 +313,29 @@ mod tests {
     #[test]
     fn test_parse_short_value_multiple_bytes() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0b00100010).unwrap();
         // value
-        writer.write_i16::<LittleEndian>(::std::i16::MAX).unwrap();
+        writer.write_i16::<LittleEndian>(i16::MAX).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
-        assert_eq!(res.1, EncodedValue::Short(::std::i16::MAX))
+        assert_eq!(res.1, EncodedValue::Short(i16::MAX))
     }
 
     #[test]
     fn test_parse_char_value_single_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0b00000011).unwrap();
         // single byte char value
-        writer.write_u8('A' as u8).unwrap();
+        writer.write_u8(b'A').unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Char('A' as u16))
     }
@@ -278,13 +343,14 @@ mod tests {
     #[test]
     fn test_parse_char_value_multiple_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0b00100011).unwrap();
         // two byte unicode value
         writer.write_u16::<LittleEndian>('ß' as u16).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Char('ß' as u16))
     }
@@ -292,13 +358,14 @@ mod tests {
     #[test]
     fn test_parse_int_value_single_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (int, single byte)
         writer.write_u8(0b00000100).unwrap();
         // value
         writer.write_u8(1_i32 as u8).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Int(1_i32))
     }
@@ -306,27 +373,29 @@ mod tests {
     #[test]
     fn test_parse_int_value_multiple_bytes() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (int, 4 bytes)
         writer.write_u8(0b01100100).unwrap();
         // value
-        writer.write_i32::<LittleEndian>(::std::i32::MAX).unwrap();
+        writer.write_i32::<LittleEndian>(i32::MAX).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
-        assert_eq!(res.1, EncodedValue::Int(::std::i32::MAX))
+        assert_eq!(res.1, EncodedValue::Int(i32::MAX))
     }
 
     #[test]
     fn test_parse_long_value_single_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (long, 1 byte)
         writer.write_u8(0b00000110).unwrap();
         // value
         writer.write_u8(1_i64 as u8).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Long(1_i64))
     }
@@ -334,20 +403,22 @@ mod tests {
     #[test]
     fn test_parse_long_value_multiple_bytes() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (long, 8 bytes)
         writer.write_u8(0b11100110).unwrap();
         // value
-        writer.write_i64::<LittleEndian>(::std::i64::MAX).unwrap();
+        writer.write_i64::<LittleEndian>(i64::MAX).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
-        assert_eq!(res.1, EncodedValue::Long(::std::i64::MAX))
+        assert_eq!(res.1, EncodedValue::Long(i64::MAX))
     }
 
     #[test]
     fn test_parse_float_value_single_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         // spec says a float may be encoded as a single byte
         let mut writer = vec!();
         // value type (float, 1 byte)
@@ -355,7 +426,7 @@ mod tests {
         // value
         writer.write_u8(0b00000000).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         match res.1 {
             EncodedValue::Float(x) => assert_eq!(x, 0_f32),
@@ -365,9 +436,10 @@ mod tests {
 
     #[test]
     fn test_parse_float_value_two_bytes() {
-        // a two byte value (which will be tiny, as it has no exponent)
-        // tests that we are sign extending correctly
+        // a two byte value, which gives the sign, exponent and top of the fraction
+        // tests that we are zero extending to the right correctly
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (float, 2 byte)
         writer.write_u8(0b00110000).unwrap();
@@ -375,11 +447,11 @@ mod tests {
         writer.write_u8(0b00110011).unwrap();
         writer.write_u8(0b00110011).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         match res.1 {
             EncodedValue::Float(x) => {
-                assert_eq!(x, 0.000000000000000000000000000000000000000018367_f32)
+                assert_eq!(x, f32::from_bits(0x3333_0000))
             },
             _ => panic!()
         }
@@ -388,27 +460,45 @@ mod tests {
     #[test]
     fn test_parse_float_value_multiple_byte() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (float, 4 bytes)
         writer.write_u8(0b01110000).unwrap();
         // value
-        writer.write_f32::<LittleEndian>(::std::f32::MAX).unwrap();
+        writer.write_f32::<LittleEndian>(f32::MAX).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
-        assert_eq!(res.1, EncodedValue::Float(::std::f32::MAX))
+        assert_eq!(res.1, EncodedValue::Float(f32::MAX))
+    }
+
+    #[test]
+    fn test_parse_double_value_one_byte() {
+        // 2.0 is 0x4000000000000000, so only the top byte needs to be stored
+        let fd = generate_file_data();
+        let ctx = ParseContext::default();
+        let mut writer = vec!();
+        // value type (double, 1 byte)
+        writer.write_u8(0b00010001).unwrap();
+        // value
+        writer.write_u8(0x40).unwrap();
+
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
+
+        assert_eq!(res.1, EncodedValue::Double(2.0))
     }
 
     #[test]
     fn test_parse_double_value() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (8-byte length)
         writer.write_u8(0b11110001).unwrap();
         // value
         writer.write_f64::<LittleEndian>(123_f64).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Double(123_f64))
     }
@@ -420,13 +510,14 @@ mod tests {
     #[test]
     fn test_parse_method_type() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x15).unwrap();
         // value
         writer.write_u32::<LittleEndian>(1_u32).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::MethodType(fd.prototypes[1].clone()))
     }
@@ -434,27 +525,29 @@ mod tests {
     #[test]
     fn test_parse_method_handle() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x16).unwrap();
         // value
         writer.write_u32::<LittleEndian>(1_u32).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
-        assert_eq!(res.1, EncodedValue::MethodHandle(fd.methods[1].clone()))
+        assert_eq!(res.1, EncodedValue::MethodHandle(fd.method_handles[1].clone()))
     }
 
     #[test]
     fn test_parse_string() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x17).unwrap();
         // value
         writer.write_u32::<LittleEndian>(0).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::String(fd.string_data[0].clone()))
     }
@@ -462,13 +555,14 @@ mod tests {
     #[test]
     fn test_parse_type() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x18).unwrap();
         // value
         writer.write_u32::<LittleEndian>(1_u32).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Type(fd.string_data[1].clone()))
     }
@@ -476,13 +570,14 @@ mod tests {
     #[test]
     fn test_parse_field() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x19).unwrap();
         // value
         writer.write_u32::<LittleEndian>(1_u32).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Field(fd.fields[1].clone()))
     }
@@ -490,13 +585,14 @@ mod tests {
     #[test]
     fn test_parse_method() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x1A).unwrap();
         // value
         writer.write_u32::<LittleEndian>(0_u32).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Method(fd.methods[0].clone()))
     }
@@ -504,13 +600,14 @@ mod tests {
     #[test]
     fn test_parse_enum() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x1B).unwrap();
         // value
         writer.write_u32::<LittleEndian>(1_u32).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Enum(fd.fields[1].clone()))
     }
@@ -518,6 +615,7 @@ mod tests {
     #[test]
     fn test_parse_array_simple() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         // simple test of two byte values
         let mut writer = vec!();
 
@@ -532,7 +630,7 @@ mod tests {
         writer.write_u8(0x00).unwrap();
         writer.write_u8(0x06).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Array(vec!(
             EncodedValue::Byte(0x05), EncodedValue::Byte(0x06))
@@ -542,6 +640,7 @@ mod tests {
     #[test]
     fn test_parse_array_complex() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         // test some variable length ints, a boolean and a null
         let mut writer = vec!();
 
@@ -554,16 +653,16 @@ mod tests {
         writer.write_u8(0x01).unwrap();
         // indicates a 4 byte integer (size - 1 = 3)
         writer.write_u8(0b01100100).unwrap();
-        writer.write_i32::<LittleEndian>(::std::i32::MAX).unwrap();
+        writer.write_i32::<LittleEndian>(i32::MAX).unwrap();
         // throw in a boolean and a null value, to see if we handle 0-byte elements
         writer.write_u8(0b00111111).unwrap();
         writer.write_u8(0b00111110).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Array(vec!(
             EncodedValue::Int(1),
-            EncodedValue::Int(::std::i32::MAX),
+            EncodedValue::Int(i32::MAX),
             EncodedValue::Boolean(true),
             EncodedValue::Null)
         ));
@@ -572,6 +671,7 @@ mod tests {
     #[test]
     fn test_parse_array_recursive() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         // test nested recursive arrays
         let mut writer = vec!();
 
@@ -594,7 +694,7 @@ mod tests {
         writer.write_u8(0b00111111).unwrap();
         writer.write_u8(0b00111110).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Array(vec!(
                 EncodedValue::Int(1),
@@ -605,9 +705,28 @@ mod tests {
         ))
     }
 
+    #[test]
+    fn test_parse_array_nesting_limit() {
+        let fd = generate_file_data();
+        let ctx = ParseContext::new(ParseOptions::new().limits(ParseLimits { max_nesting_depth: 4, ..ParseLimits::default() }));
+        let mut writer = vec!();
+
+        // arrays nested inside each other, each holding just the next one
+        for _ in 0..5 {
+            writer.write_u8(0b00011100).unwrap();
+            leb128::write::unsigned(&mut writer, 1).unwrap();
+        }
+        writer.write_u8(0b00111110).unwrap();
+
+        assert!(parse_encoded_value_item(&writer, &fd, &ctx).is_err());
+        assert_eq!(ctx.take_exceeded().unwrap().to_string(),
+                   "nesting depth limit exceeded: requested 5 but at most 4 allowed");
+    }
+
     #[test]
     fn test_parse_annotation() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type
         writer.write_u8(0x1D).unwrap();
@@ -625,7 +744,7 @@ mod tests {
         writer.write_u8(0x00).unwrap();
         writer.write_u8(0x06).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Annotation(EncodedAnnotationItem {
             type_: fd.string_data[1].clone(),
@@ -645,13 +764,14 @@ mod tests {
     #[test]
     fn test_parse_null() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type (byte)
         writer.write_u8(0x1E).unwrap();
         // dud value
         writer.write_u8(0x01).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Null)
     }
@@ -659,11 +779,12 @@ mod tests {
     #[test]
     fn test_parse_boolean_true() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type plus an extra bit for the boolean value
         writer.write_u8(0b00111111).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Boolean(true))
     }
@@ -671,11 +792,12 @@ mod tests {
     #[test]
     fn test_parse_boolean_false() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
         // value type plus an extra bit for the boolean value
         writer.write_u8(0b00011111).unwrap();
 
-        let res = parse_encoded_value_item(&writer, &fd).unwrap();
+        let res = parse_encoded_value_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, EncodedValue::Boolean(false))
     }
@@ -683,6 +805,7 @@ mod tests {
     #[test]
     fn test_parse_annotation_element_item() {
         let fd = generate_file_data();
+        let ctx = ParseContext::default();
         let mut writer = vec!();
 
         // name_idx
@@ -692,7 +815,7 @@ mod tests {
         writer.write_u8(0x00).unwrap();
         writer.write_u8(0x01).unwrap();
 
-        let res = parse_annotation_element_item(&writer, &fd).unwrap();
+        let res = parse_annotation_element_item(&writer, &fd, &ctx).unwrap();
 
         assert_eq!(res.1, RawAnnotationElementItem {
             name_idx: 1,
@@ -732,12 +855,20 @@ mod tests {
             }))
         }
 
+        let method_handles = methods.iter()
+            .map(|method| Rc::new(MethodHandle {
+                type_: MethodHandleType::INVOKE_STATIC,
+                member: MethodHandleMember::Method(method.clone())
+            })).collect();
+
         DexFileData {
             string_data,
             type_identifiers,
             prototypes,
             fields,
-            methods
+            methods,
+            method_handles,
+            call_sites: vec!()
         }
     }
 }
\ No newline at end of file
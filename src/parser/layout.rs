@@ -0,0 +1,391 @@
+// Works out which bytes of the file are described by the map list, so that everything else (the
+// link section, gaps between sections, bytes past the declared end of the file) can be surfaced
+
+use super::*;
+use super::raw_types::*;
+use super::encoded_value::{skip_encoded_annotation_item, skip_encoded_array_item};
+
+// Gaps smaller than this made up only of zeroes are treated as alignment padding
+const MAX_PADDING: usize = 3;
+
+pub fn find_regions(input: &[u8], header: &RawHeader, map_list: &RawMapList, e: nom::Endianness,
+                    ctx: &ParseContext) -> Vec<Region> {
+    let file_end = input.len().min(header.file_size as usize);
+    let mut regions = vec!();
+
+    let mut covered = map_item_extents(input, map_list, file_end, e, ctx);
+
+    if header.link_size != 0 {
+        let start = header.link_off as usize;
+        let end = start.saturating_add(header.link_size as usize).min(file_end);
+        if start < end {
+            regions.push(Region {
+                kind: RegionKind::Link,
+                offset: start as u32,
+                data: input[start..end].to_vec()
+            });
+            covered.push((start, end));
+        }
+    }
+
+    covered.sort();
+
+    // anything not covered up to the declared end of the file is unmapped
+    let mut pos = 0;
+    for (start, end) in covered {
+        if start > pos {
+            push_unmapped(&mut regions, input, pos, start.min(file_end));
+        }
+        pos = pos.max(end);
+    }
+    push_unmapped(&mut regions, input, pos, file_end);
+
+    if input.len() > file_end {
+        regions.push(Region {
+            kind: RegionKind::Trailing,
+            offset: file_end as u32,
+            data: input[file_end..].to_vec()
+        });
+    }
+
+    regions.sort_by_key(|region| region.offset);
+    regions
+}
+
+fn push_unmapped(regions: &mut Vec<Region>, input: &[u8], start: usize, end: usize) {
+    if start >= end {
+        return;
+    }
+
+    let data = &input[start..end];
+    if data.len() <= MAX_PADDING && data.iter().all(|b| *b == 0) {
+        return;
+    }
+
+    regions.push(Region { kind: RegionKind::Unmapped, offset: start as u32, data: data.to_vec() });
+}
+
+// Determine the (start, end) byte range of every section in the map list
+// Sections that can't be walked are assumed to run until the next section starts
+pub fn map_item_extents(input: &[u8], map_list: &RawMapList, file_end: usize, e: nom::Endianness,
+                        ctx: &ParseContext) -> Vec<(usize, usize)> {
+    let mut starts = map_list.list.iter()
+        .map(|item| item.offset as usize)
+        .collect::<Vec<_>>();
+    starts.sort();
+
+    map_list.list.iter()
+        .map(|item| {
+            let start = item.offset as usize;
+            let end = section_end(input, item, e, ctx).unwrap_or_else(|| {
+                starts.iter()
+                    .find(|next| **next > start)
+                    .cloned()
+                    .unwrap_or(file_end)
+            });
+            (start, end)
+        })
+        .collect()
+}
+
+fn section_end(input: &[u8], item: &RawMapListItem, e: nom::Endianness, ctx: &ParseContext) -> Option<usize> {
+    let start = item.offset as usize;
+    if let Some(item_size) = fixed_size(&item.type_) {
+        return start.checked_add((item.size as usize).checked_mul(item_size)?);
+    }
+
+    let items = item_extents(input, item, e, ctx)?;
+    Some(items.last().map_or(start, |last| last.1))
+}
+
+// Determine the (start, end) byte range of every item in a section, or None if the section can't be walked
+pub fn item_extents(input: &[u8], item: &RawMapListItem, e: nom::Endianness,
+                    ctx: &ParseContext) -> Option<Vec<(usize, usize)>> {
+    let start = item.offset as usize;
+    let size = item.size as usize;
+
+    if let Some(item_size) = fixed_size(&item.type_) {
+        start.checked_add(size.checked_mul(item_size)?)?;
+        ctx.count(item.size, 16).ok()?;
+        return Some((0..size).map(|i| (start + i * item_size, start + (i + 1) * item_size)).collect());
+    }
+
+    // Variable-sized items have to be walked one at a time
+    let mut items = vec!();
+    let mut pos = start;
+    for _ in 0..size {
+        if is_aligned(&item.type_) {
+            pos = (pos + 3) & !3;
+        }
+
+        ctx.work(1).ok()?;
+        let data = input.get(pos..)?;
+        let rest = skip_item(data, &item.type_, e, ctx).ok()?.0;
+        items.push((pos, pos + data.len() - rest.len()));
+        pos += data.len() - rest.len();
+    }
+
+    Some(items)
+}
+
+// Docs: map_list, item sizes for the fixed-size sections
+fn fixed_size(type_: &MapListItemType) -> Option<usize> {
+    match type_ {
+        MapListItemType::HEADER_ITEM => Some(HEADER_SIZE),
+        MapListItemType::STRING_ID_ITEM | MapListItemType::TYPE_ID_ITEM
+            | MapListItemType::CALL_SITE_ID_ITEM => Some(4),
+        MapListItemType::PROTO_ID_ITEM => Some(12),
+        MapListItemType::FIELD_ID_ITEM | MapListItemType::METHOD_ID_ITEM
+            | MapListItemType::METHOD_HANDLE_ITEM => Some(8),
+        MapListItemType::CLASS_DEF_ITEM => Some(32),
+        _ => None
+    }
+}
+
+// Docs: map_list, items in these sections must be four-byte aligned
+fn is_aligned(type_: &MapListItemType) -> bool {
+    matches!(type_, MapListItemType::MAP_LIST | MapListItemType::TYPE_LIST | MapListItemType::ANNOTATION_SET_REF_LIST
+        | MapListItemType::ANNOTATION_SET_ITEM | MapListItemType::CODE_ITEM
+        | MapListItemType::ANNOTATIONS_DIRECTORY_ITEM)
+}
+
+fn skip_item<'a>(data: &'a[u8], type_: &MapListItemType, e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], ()> {
+    match type_ {
+        MapListItemType::TYPE_LIST => do_parse!(data,
+            size: u32!(e)   >>
+            take!(size as usize * 2)    >>
+            (())
+        ),
+        MapListItemType::MAP_LIST => do_parse!(data,
+            size: u32!(e)   >>
+            take!(size as usize * 12)   >>
+            (())
+        ),
+        MapListItemType::ANNOTATION_SET_REF_LIST | MapListItemType::ANNOTATION_SET_ITEM => do_parse!(data,
+            size: u32!(e)   >>
+            take!(size as usize * 4)    >>
+            (())
+        ),
+        MapListItemType::ANNOTATIONS_DIRECTORY_ITEM => do_parse!(data,
+            u32!(e)         >>
+            fld_size: u32!(e)   >>
+            mtd_size: u32!(e)   >>
+            prm_size: u32!(e)   >>
+            take!((fld_size as usize + mtd_size as usize + prm_size as usize) * 8)   >>
+            (())
+        ),
+        MapListItemType::HIDDENAPI_CLASS_DATA_ITEM => do_parse!(data,
+            // the size includes the size field itself
+            size: u32!(e)   >>
+            take!((size as usize).saturating_sub(4))    >>
+            (())
+        ),
+        MapListItemType::CLASS_DATA_ITEM => skip_class_data_item(data),
+        MapListItemType::CODE_ITEM => skip_code_item(data, e),
+        MapListItemType::STRING_DATA_ITEM => do_parse!(data,
+            call!(parse_uleb128)    >>
+            take_until_and_consume!("\0")   >>
+            (())
+        ),
+        MapListItemType::DEBUG_INFO_ITEM => skip_debug_info_item(data),
+        MapListItemType::ANNOTATION_ITEM => do_parse!(data,
+            take!(1)    >>
+            call!(skip_encoded_annotation_item, ctx) >>
+            (())
+        ),
+        MapListItemType::ENCODED_ARRAY_ITEM => skip_encoded_array_item(data, ctx),
+        _ => Err(nom::Err::Error(nom::Context::Code(data, nom::ErrorKind::Custom(0))))
+    }
+}
+
+// Docs: class_data_item
+named!(skip_class_data_item<&[u8], ()>,
+    do_parse!(
+        static_fields_size: call!(parse_uleb128)    >>
+        instance_fields_size: call!(parse_uleb128)  >>
+        direct_methods_size: call!(parse_uleb128)   >>
+        virtual_methods_size: call!(parse_uleb128)  >>
+        count!(parse_uleb128, 2 * (static_fields_size as usize + instance_fields_size as usize))   >>
+        count!(parse_uleb128, 3 * (direct_methods_size as usize + virtual_methods_size as usize))  >>
+        (())
+    )
+);
+
+// Docs: code_item
+named_args!(skip_code_item(e: nom::Endianness)<&[u8], ()>,
+    do_parse!(
+        take!(6)                >>
+        tries_size: u16!(e)     >>
+        u32!(e)                 >>
+        insns_size: u32!(e)     >>
+        take!(insns_size as usize * 2)  >>
+        cond!(tries_size != 0 && insns_size % 2 != 0, take!(2))    >>
+        cond!(tries_size != 0, take!(tries_size as usize * 8))    >>
+        cond!(tries_size != 0, call!(skip_encoded_catch_handler_list))   >>
+        (())
+    )
+);
+
+// Docs: encoded_catch_handler_list
+named!(skip_encoded_catch_handler_list<&[u8], ()>,
+    do_parse!(
+        size: call!(parse_uleb128)  >>
+        count!(skip_encoded_catch_handler, size as usize)   >>
+        (())
+    )
+);
+
+// Docs: encoded_catch_handler
+named!(skip_encoded_catch_handler<&[u8], ()>,
+    do_parse!(
+        size: call!(parse_sleb128)  >>
+        count!(pair!(parse_uleb128, parse_uleb128), size.unsigned_abs() as usize)   >>
+        cond!(size <= 0, call!(parse_uleb128))  >>
+        (())
+    )
+);
+
+// Docs: debug_info_item
+// The state machine bytecodes take arguments which may contain zeroes, so these have to be walked
+fn skip_debug_info_item(data: &[u8]) -> nom::IResult<&[u8], ()> {
+    let (rest, (_line_start, parameters_size)) = pair!(data, parse_uleb128, parse_uleb128)?;
+    let mut input = count!(rest, parse_uleb128, parameters_size as usize)?.0;
+
+    loop {
+        let (rest, opcode) = take_one(input)?;
+        input = match opcode {
+            // DBG_END_SEQUENCE
+            0x00 => return Ok((rest, ())),
+            // DBG_ADVANCE_PC, DBG_END_LOCAL, DBG_RESTART_LOCAL, DBG_SET_FILE
+            0x01 | 0x05 | 0x06 | 0x09 => parse_uleb128(rest)?.0,
+            // DBG_ADVANCE_LINE
+            0x02 => parse_sleb128(rest)?.0,
+            // DBG_START_LOCAL
+            0x03 => count!(rest, parse_uleb128, 3)?.0,
+            // DBG_START_LOCAL_EXTENDED
+            0x04 => count!(rest, parse_uleb128, 4)?.0,
+            // DBG_SET_PROLOGUE_END, DBG_SET_EPILOGUE_BEGIN and special opcodes have no arguments
+            _ => rest
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::*;
+
+    #[allow(non_upper_case_globals)]
+    const e: nom::Endianness = nom::Endianness::Little;
+
+    #[test]
+    fn test_skip_debug_info_item() {
+        let mut writer = vec!();
+        // line start and a single parameter
+        leb128::write::unsigned(&mut writer, 1).unwrap();
+        leb128::write::unsigned(&mut writer, 1).unwrap();
+        leb128::write::unsigned(&mut writer, 0).unwrap();
+        // DBG_START_LOCAL with arguments that contain zeroes
+        writer.write_u8(0x03).unwrap();
+        leb128::write::unsigned(&mut writer, 0).unwrap();
+        leb128::write::unsigned(&mut writer, 0).unwrap();
+        leb128::write::unsigned(&mut writer, 0).unwrap();
+        // special opcode, then DBG_END_SEQUENCE
+        writer.write_u8(0x0A).unwrap();
+        writer.write_u8(0x00).unwrap();
+        // the next item
+        writer.write_u8(0xFF).unwrap();
+
+        let res = skip_debug_info_item(&writer).unwrap();
+
+        assert_eq!(res.0, &[0xFF]);
+    }
+
+    #[test]
+    fn test_skip_code_item() {
+        let mut writer = vec!();
+        // registers, ins, outs
+        for d in &[1_u16, 1_u16, 0_u16] {
+            writer.write_u16::<LittleEndian>(*d).unwrap();
+        }
+        // a single try
+        writer.write_u16::<LittleEndian>(1).unwrap();
+        // debug info offset
+        writer.write_u32::<LittleEndian>(0).unwrap();
+        // a single instruction, which needs padding before the tries
+        writer.write_u32::<LittleEndian>(1).unwrap();
+        writer.write_u16::<LittleEndian>(0x000E).unwrap();
+        writer.write_u16::<LittleEndian>(0).unwrap();
+        // try item
+        writer.write_u32::<LittleEndian>(0).unwrap();
+        writer.write_u16::<LittleEndian>(1).unwrap();
+        writer.write_u16::<LittleEndian>(1).unwrap();
+        // handler list with a single catch-all handler
+        leb128::write::unsigned(&mut writer, 1).unwrap();
+        leb128::write::signed(&mut writer, 0).unwrap();
+        leb128::write::unsigned(&mut writer, 0).unwrap();
+
+        let res = skip_code_item(&writer, e).unwrap();
+
+        assert_eq!(res.0.len(), 0);
+    }
+
+    #[test]
+    fn test_find_regions() {
+        let mut writer = vec![0_u8; HEADER_SIZE];
+        // map list directly after the header, describing the header and itself
+        let map_off = writer.len() as u32;
+        writer.write_u32::<LittleEndian>(2).unwrap();
+        for (type_, offset) in &[(0x0000_u16, 0_u32), (0x1000_u16, map_off)] {
+            writer.write_u16::<LittleEndian>(*type_).unwrap();
+            writer.write_u16::<LittleEndian>(0).unwrap();
+            writer.write_u32::<LittleEndian>(1).unwrap();
+            writer.write_u32::<LittleEndian>(*offset).unwrap();
+        }
+        // a payload nothing refers to
+        let payload_off = writer.len() as u32;
+        writer.extend_from_slice(b"payload");
+        // link data
+        let link_off = writer.len() as u32;
+        writer.extend_from_slice(b"link");
+        let file_size = writer.len() as u32;
+        // bytes past the end of the file
+        writer.extend_from_slice(b"trailing");
+
+        let ctx = ParseContext::default();
+        let map_list = parse_map_list(&writer[map_off as usize..], e, &ctx).unwrap().1;
+        let header = RawHeader {
+            version: 35,
+            checksum: 0,
+            signature: [0; 20],
+            file_size,
+            header_size: HEADER_SIZE as u32,
+            endian_tag: 0x12345678,
+            link_size: 4,
+            link_off,
+            map_off,
+            string_ids_size: 0,
+            string_ids_off: 0,
+            type_ids_size: 0,
+            type_ids_off: 0,
+            proto_ids_size: 0,
+            proto_ids_off: 0,
+            field_ids_size: 0,
+            field_ids_off: 0,
+            method_ids_size: 0,
+            method_ids_off: 0,
+            class_defs_size: 0,
+            class_defs_off: 0,
+            data_size: 0,
+            data_off: 0
+        };
+
+        let regions = find_regions(&writer, &header, &map_list, e, &ctx);
+
+        assert_eq!(regions, vec!(
+            Region { kind: RegionKind::Unmapped, offset: payload_off, data: b"payload".to_vec() },
+            Region { kind: RegionKind::Link, offset: link_off, data: b"link".to_vec() },
+            Region { kind: RegionKind::Trailing, offset: file_size, data: b"trailing".to_vec() }
+        ));
+    }
+}
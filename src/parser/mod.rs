@@ -2,11 +2,17 @@
 pub mod encoded_value;
 mod raw_types;
 mod parse_data;
+mod layout;
+mod context;
+pub mod system_annotations;
+pub mod attribution;
 
 use crate::result_types::*;
 use crate::error::*;
+use crate::options::ParseOptions;
 
 use self::raw_types::*;
+use self::context::ParseContext;
 use nom::*;
 
 // The magic that starts a DEX file
@@ -15,87 +21,175 @@ const DEX_FILE_MAGIC: [u8; 4] = [0x64, 0x65, 0x78, 0x0A];
 const ENDIAN_CONSTANT: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
 // Indicates modified non-standard (big-endian) encoding
 const REVERSE_ENDIAN_CONSTANT: [u8; 4] = [0x78, 0x56, 0x34, 0x12];
-// Header size is a constant 70 bytes according to the spec
-const HEADER_SIZE: usize = 70;
+// Header size is a constant 0x70 bytes according to the spec
+const HEADER_SIZE: usize = 0x70;
 // Special value indicating there is no index value
 const NO_INDEX: u32 = 0xFFFFFFFF;
 
 type Uleb128 = u32;
 type Sleb128 = i32;
 
-pub fn parse(buffer: &[u8]) -> Result<DexFile, DexParserError> {
+pub fn parse(buffer: &[u8], options: ParseOptions) -> Result<DexFile, DexParserError> {
+    let endianness = endianness(buffer)?;
+
+    if options.verify_checksum {
+        verify_checksum(buffer, endianness)?;
+    }
+
+    let ctx = ParseContext::new(options);
+
+    // a limit being exceeded takes precedence over whatever error it caused further up
+    parse_dex_file(buffer, endianness, &ctx)
+        .map_err(DexParserError::from)
+        .and_then(|raw| parse_data::transform_dex_file(raw.1, endianness, &ctx))
+        .map_err(|err| ctx.take_exceeded().unwrap_or(err))
+}
+
+// Attribute every byte of the file to the class, member or package it is there for
+pub fn attribute(buffer: &[u8], options: ParseOptions) -> Result<Vec<attribution::Attribution>, DexParserError> {
+    let endianness = endianness(buffer)?;
+    let ctx = ParseContext::new(options);
+
+    parse_dex_file(buffer, endianness, &ctx)
+        .map_err(DexParserError::from)
+        .and_then(|raw| attribution::attribute(buffer, &raw.1, endianness, &ctx))
+        .map_err(|err| ctx.take_exceeded().unwrap_or(err))
+}
+
+// Peek ahead to determine endianness
+fn endianness(buffer: &[u8]) -> Result<nom::Endianness, DexParserError> {
     // any DEX file will need to be at least as big as the header
     if buffer.len() < HEADER_SIZE {
         return Err(DexParserError::from(format!("buffer length {} is too short", buffer.len())));
     }
 
-    // Peek ahead to determine endianness
-    let endianness = {
-        if buffer[40 .. 44] == ENDIAN_CONSTANT {
-            nom::Endianness::Big
-        } else if buffer[40 .. 44] == REVERSE_ENDIAN_CONSTANT {
-            nom::Endianness::Little
-        } else {
-            return Err(DexParserError::from("could not determine endianness"));
-        }
-    };
+    if buffer[40 .. 44] == ENDIAN_CONSTANT {
+        Ok(nom::Endianness::Big)
+    } else if buffer[40 .. 44] == REVERSE_ENDIAN_CONSTANT {
+        Ok(nom::Endianness::Little)
+    } else {
+        Err(DexParserError::from("could not determine endianness"))
+    }
+}
 
-    let raw = parse_dex_file(buffer, endianness)?.1;
-    parse_data::transform_dex_file(raw, endianness)
+// Entry points into individual parsers for fuzzing, resolving indexes against the given pools
+#[cfg(feature = "fuzzing")]
+pub fn parse_encoded_value(data: &[u8], fd: &DexFileData) -> Result<EncodedValue, DexParserError> {
+    let ctx = ParseContext::default();
+    encoded_value::parse_encoded_value_item(data, fd, &ctx)
+        .map(|res| res.1)
+        .map_err(|err| ctx.take_exceeded().unwrap_or_else(|| DexParserError::from(err)))
 }
 
-fn parse_dex_file(input: &[u8], e: nom::Endianness) -> nom::IResult<&[u8], RawDexFile> {
+#[cfg(feature = "fuzzing")]
+pub fn parse_code_item(data: &[u8], fd: &DexFileData) -> Result<Code, DexParserError> {
+    let ctx = ParseContext::default();
+    parse_data::transform_method_code(data, 0, 0, fd, nom::Endianness::Little, &ctx)
+        .map_err(|err| ctx.take_exceeded().unwrap_or(err))
+}
+
+fn parse_dex_file<'a>(input: &'a[u8], e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], RawDexFile> {
 
     let header = parse_header(input, e)?.1;
 
-    let string_id_items = parse_string_id_items(&input[header.string_ids_off as usize ..],
-                                                header.string_ids_size as usize, e)?.1;
+    let string_id_items = parse_string_id_items(slice_from(input, header.string_ids_off)?,
+                                                ctx.count(header.string_ids_size, 4)?, e)?.1;
 
-    let type_id_items = parse_u32_list(&input[header.type_ids_off as usize ..],
-                                       header.type_ids_size as usize, e)?.1;
+    let type_id_items = parse_u32_list(slice_from(input, header.type_ids_off)?,
+                                       ctx.count(header.type_ids_size, 4)?, e)?.1;
 
-    let proto_id_items = parse_proto_id_items(&input[header.proto_ids_off as usize ..],
-                                                header.proto_ids_size as usize, e)?.1;
+    let proto_id_items = parse_proto_id_items(slice_from(input, header.proto_ids_off)?,
+                                                ctx.count(header.proto_ids_size, 12)?, e)?.1;
 
-    let field_id_items = parse_field_id_items(&input[header.field_ids_off as usize ..],
-                                                header.field_ids_size as usize, e)?.1;
+    let field_id_items = parse_field_id_items(slice_from(input, header.field_ids_off)?,
+                                                ctx.count(header.field_ids_size, 8)?, e)?.1;
 
-    let method_id_items = parse_method_id_items(&input[header.method_ids_off as usize ..],
-                                                header.method_ids_size as usize, e)?.1;
+    let method_id_items = parse_method_id_items(slice_from(input, header.method_ids_off)?,
+                                                ctx.count(header.method_ids_size, 8)?, e)?.1;
 
-    // store the remainder left after parsing here - need to start from here for further parsing
-    let (mut remainder, class_def_items) = parse_class_def_items(&input[header.class_defs_off as usize ..], header.class_defs_size as usize, e)?;
+    let class_def_items = parse_class_def_items(slice_from(input, header.class_defs_off)?,
+                                                ctx.classes(header.class_defs_size)?, e)?.1;
 
-    // Version 038 adds some new index pools with sizes not indicated in the header
-    // For this version and higher, we'll need to peek at the map list to know their size for parsing
-    let (mut call_site_idxs, mut method_handle_idxs) = (None, None);
-    if header.version >= 38 {
-        let map_list = call!(&input[header.map_off as usize ..], parse_map_list, e)?.1.list;
+    // Before 038 the map list is only needed to find the regions it doesn't account for, so a file whose map list
+    // can't be read still parses, just without them
+    let map_list = match slice_from(input, header.map_off) {
+        Ok(map) => match parse_map_list(map, e, ctx) {
+            Ok((_, map_list)) => Some(map_list),
+            Err(_) if header.version < 38 => None,
+            Err(err) => return Err(err)
+        },
+        Err(_) if header.version < 38 => None,
+        Err(err) => return Err(err.into())
+    };
 
-        let csi_count = map_list.iter().filter(|item| item.type_ == MapListItemType::CALL_SITE_ID_ITEM).count();
-        if csi_count > 0 {
-            let res = call!(&remainder, parse_u32_list, csi_count, e)?;
-            remainder = res.0;
-            call_site_idxs = Some(res.1);
-        }
+    // Version 038 adds some new index pools with sizes and offsets not indicated in the header
+    // These are only found through the map list
+    let find_section = |type_: MapListItemType| map_list.iter().flat_map(|map| map.list.iter())
+        .find(|item| item.type_ == type_);
 
-        let mhi_count = map_list.iter().filter(|item| item.type_ == MapListItemType::METHOD_HANDLE_ITEM).count();
-        if mhi_count > 0 {
-            method_handle_idxs = Some(call!(&remainder, parse_method_handle_items, mhi_count, e)?.1);
-        }
-    }
+    let call_site_idxs = match find_section(MapListItemType::CALL_SITE_ID_ITEM) {
+        Some(item) => Some(parse_u32_list(slice_from(input, item.offset)?, ctx.count(item.size, 4)?, e)?.1),
+        None => None
+    };
 
-    // anything left after data is just link data
-    let (ld, data) = map!(&input[header.data_off as usize ..], take!(header.data_size), |d| { d.to_vec() })?;
+    let method_handle_idxs = match find_section(MapListItemType::METHOD_HANDLE_ITEM) {
+        Some(item) => Some(parse_method_handle_items(slice_from(input, item.offset)?, ctx.count(item.size, 8)?, e)?.1),
+        None => None
+    };
 
-    let link_data = if ld.is_empty() {
-        Some(ld.to_vec())
-    } else {
-        None
+    ctx.count(header.data_size, 1)?;
+    let data = map!(slice_from(input, header.data_off)?, take!(header.data_size), |d| { d.to_vec() })?.1;
+
+    // the link section and anything the map list doesn't account for
+    let regions = match map_list {
+        Some(ref map_list) => layout::find_regions(input, &header, map_list, e, ctx),
+        None => vec!()
     };
 
     Ok((&[], RawDexFile { header, string_id_items, type_id_items, proto_id_items, field_id_items,
-            method_id_items, class_def_items, call_site_idxs, method_handle_idxs, data, link_data }))
+            method_id_items, class_def_items, call_site_idxs, method_handle_idxs, data, regions }))
+}
+
+// The checksum covers everything in the file after the magic, version and the checksum itself
+fn verify_checksum(buffer: &[u8], e: nom::Endianness) -> Result<(), DexParserError> {
+    let header = parse_header(buffer, e)?.1;
+    let end = (header.file_size as usize).min(buffer.len());
+    let actual = adler32(buffer.get(12 .. end).unwrap_or(&[]));
+
+    if actual != header.checksum {
+        return Err(DexParserError::ChecksumMismatch { expected: header.checksum, actual });
+    }
+    Ok(())
+}
+
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    // the largest number of bytes that can be summed before b could overflow a u32
+    const CHUNK: usize = 5552;
+
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for chunk in data.chunks(CHUNK) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+// Slice the input from an offset, failing rather than panicking if the offset is out of range
+fn slice_from(input: &[u8], off: u32) -> Result<&[u8], DexParserError> {
+    input.get(off as usize ..)
+        .ok_or_else(|| DexParserError::from(format!("offset 0x{:X} is outside of the file", off)))
+}
+
+// Look up an index into one of the identifier pools, failing rather than panicking if it's out of range
+fn lookup<T: Clone>(pool: &[T], idx: u32, name: &str) -> Result<T, DexParserError> {
+    pool.get(idx as usize)
+        .cloned()
+        .ok_or_else(|| DexParserError::from(format!("{} index {} is out of range", name, idx)))
 }
 
 // simple wrapper around the take!() macro so it returns a u8 instead of &[u8]
@@ -132,11 +226,11 @@ named!(parse_uleb128p1<&[u8], Sleb128>,
 
 // LEB128 only ever encodes 32-bit values in a .dex file
 pub fn read_uleb128(input: &[u8]) -> Result<Uleb128, leb128::read::Error> {
-    leb128::read::unsigned(&mut (input.clone())).map(|i| i as u32)
+    leb128::read::unsigned(&mut { input }).map(|i| i as u32)
 }
 
 pub fn read_sleb128(input: &[u8]) -> Result<Sleb128, leb128::read::Error> {
-    leb128::read::signed(&mut (input.clone())).map(|i| i as i32)
+    leb128::read::signed(&mut { input }).map(|i| i as i32)
 }
 
 named_args!(parse_string_id_items(size: usize, e: nom::Endianness)<&[u8], Vec<u32>>,
@@ -146,26 +240,26 @@ named_args!(parse_string_id_items(size: usize, e: nom::Endianness)<&[u8], Vec<u3
 named_args!(parse_u32_list(size: usize, e: nom::Endianness)<&[u8], Vec<u32>>, count!(u32!(e), size));
 
 // Docs: map_list
-named_args!(parse_map_list(e: nom::Endianness)<&[u8], RawMapList>,
+named_args!(parse_map_list<'a>(e: nom::Endianness, ctx: &'a ParseContext)<RawMapList>,
     do_parse!(
         size: u32!(e)                                           >>
         list: count!(do_parse!(
-                type_: map_res!(u16!(e), MapListItemType::parse)    >>
+                type_: map!(u16!(e), MapListItemType::parse)        >>
                 unused: u16!(e)                                 >>
                 size: u32!(e)                                   >>
                 offset: u32!(e)                                 >>
                 (RawMapListItem { type_, unused, size, offset })
-            ), size as usize)                                   >>
+            ), ctx.count(size, 12)?)                            >>
         (RawMapList { size, list })
     )
 );
 
 // Docs: type_list
-named_args!(parse_type_list(e: nom::Endianness)<&[u8], RawTypeList>,
+named_args!(parse_type_list<'a>(e: nom::Endianness, ctx: &'a ParseContext)<RawTypeList>,
     peek!(
         do_parse!(
             size: u32!(e)                                       >>
-            list: count!(u16!(e), size as usize)                >>
+            list: count!(u16!(e), ctx.count(size, 2)?)          >>
             (RawTypeList { size, list })
     )
 ));
@@ -231,7 +325,7 @@ named_args!(parse_header(e: nom::Endianness)<&[u8], RawHeader>,
         // adler32 checksum of the rest of this DEX file
         checksum: u32!(e)                   >>
         // SHA1 signature of the rest of the file
-        signature: count_fixed!(u8, call!(take_one), 20)                >>
+        signature: map!(take!(20), to_signature)                      >>
         // size of the entire file
         file_size: u32!(e)                  >>
         // size of the header
@@ -273,6 +367,12 @@ named_args!(parse_header(e: nom::Endianness)<&[u8], RawHeader>,
     )
 );
 
+fn to_signature(value: &[u8]) -> [u8; 20] {
+    let mut signature = [0; 20];
+    signature.copy_from_slice(value);
+    signature
+}
+
 fn parse_version(value: &[u8]) -> Result<i32, DexParserError> {
     value[0..3].iter()
         .map(|x| *x as char)
@@ -312,30 +412,48 @@ impl Visibility {
     }
 }
 
-impl MapListItemType {
+impl MethodHandleType {
     fn parse(value: u16) -> Result<Self, DexParserError> {
         match value {
-            0x0000 => Ok(MapListItemType::HEADER_ITEM),
-            0x0001 => Ok(MapListItemType::STRING_ID_ITEM),
-            0x0002 => Ok(MapListItemType::TYPE_ID_ITEM),
-            0x0003 => Ok(MapListItemType::PROTO_ID_ITEM),
-            0x0004 => Ok(MapListItemType::FIELD_ID_ITEM),
-            0x0005 => Ok(MapListItemType::METHOD_ID_ITEM),
-            0x0006 => Ok(MapListItemType::CLASS_DEF_ITEM),
-            0x0007 => Ok(MapListItemType::CALL_SITE_ID_ITEM),
-            0x0008 => Ok(MapListItemType::METHOD_HANDLE_ITEM),
-            0x1000 => Ok(MapListItemType::MAP_LIST),
-            0x1001 => Ok(MapListItemType::TYPE_LIST),
-            0x1002 => Ok(MapListItemType::ANNOTATION_SET_REF_LIST),
-            0x1003 => Ok(MapListItemType::ANNOTATION_SET_ITEM),
-            0x2000 => Ok(MapListItemType::CLASS_DATA_ITEM),
-            0x2001 => Ok(MapListItemType::CODE_ITEM),
-            0x2002 => Ok(MapListItemType::STRING_DATA_ITEM),
-            0x2003 => Ok(MapListItemType::DEBUG_INFO_ITEM),
-            0x2004 => Ok(MapListItemType::ANNOTATION_ITEM),
-            0x2005 => Ok(MapListItemType::ENCODED_ARRAY_ITEM),
-            0x2006 => Ok(MapListItemType::ANNOTATIONS_DIRECTORY_ITEM),
-            _ => Err(DexParserError::from(format!("No type code found for map list item 0x{:0X}", value)))
+            0x00 => Ok(MethodHandleType::STATIC_PUT),
+            0x01 => Ok(MethodHandleType::STATIC_GET),
+            0x02 => Ok(MethodHandleType::INSTANCE_PUT),
+            0x03 => Ok(MethodHandleType::INSTANCE_GET),
+            0x04 => Ok(MethodHandleType::INVOKE_STATIC),
+            0x05 => Ok(MethodHandleType::INVOKE_INSTANCE),
+            0x06 => Ok(MethodHandleType::INVOKE_CONSTRUCTOR),
+            0x07 => Ok(MethodHandleType::INVOKE_DIRECT),
+            0x08 => Ok(MethodHandleType::INVOKE_INTERFACE),
+            _ => Err(DexParserError::from(format!("Could not find method handle type for value 0x{:0X}", value)))
+        }
+    }
+}
+
+impl MapListItemType {
+    fn parse(value: u16) -> Self {
+        match value {
+            0x0000 => MapListItemType::HEADER_ITEM,
+            0x0001 => MapListItemType::STRING_ID_ITEM,
+            0x0002 => MapListItemType::TYPE_ID_ITEM,
+            0x0003 => MapListItemType::PROTO_ID_ITEM,
+            0x0004 => MapListItemType::FIELD_ID_ITEM,
+            0x0005 => MapListItemType::METHOD_ID_ITEM,
+            0x0006 => MapListItemType::CLASS_DEF_ITEM,
+            0x0007 => MapListItemType::CALL_SITE_ID_ITEM,
+            0x0008 => MapListItemType::METHOD_HANDLE_ITEM,
+            0x1000 => MapListItemType::MAP_LIST,
+            0x1001 => MapListItemType::TYPE_LIST,
+            0x1002 => MapListItemType::ANNOTATION_SET_REF_LIST,
+            0x1003 => MapListItemType::ANNOTATION_SET_ITEM,
+            0x2000 => MapListItemType::CLASS_DATA_ITEM,
+            0x2001 => MapListItemType::CODE_ITEM,
+            0x2002 => MapListItemType::STRING_DATA_ITEM,
+            0x2003 => MapListItemType::DEBUG_INFO_ITEM,
+            0x2004 => MapListItemType::ANNOTATION_ITEM,
+            0x2005 => MapListItemType::ENCODED_ARRAY_ITEM,
+            0x2006 => MapListItemType::ANNOTATIONS_DIRECTORY_ITEM,
+            0xF000 => MapListItemType::HIDDENAPI_CLASS_DATA_ITEM,
+            _ => MapListItemType::Unknown(value)
         }
     }
 }
@@ -390,9 +508,9 @@ mod tests {
     #[test]
     fn test_access_flag_bitmasking() {
         // all flags
-        assert_eq!(AccessFlag::parse(std::u32::MAX, AnnotationType::Method).len(), 18);
+        assert_eq!(AccessFlag::parse(u32::MAX, AnnotationType::Method).len(), 18);
         // no flags
-        assert_eq!(AccessFlag::parse(std::u32::MIN, AnnotationType::Method).len(), 0);
+        assert_eq!(AccessFlag::parse(u32::MIN, AnnotationType::Method).len(), 0);
     }
 
     #[test]
@@ -542,7 +660,7 @@ mod tests {
         writer.write_u16::<LittleEndian>(1).unwrap();
         writer.write_u16::<LittleEndian>(1).unwrap();
 
-        let res = parse_type_list(&writer, e).unwrap();
+        let res = parse_type_list(&writer, e, &ParseContext::default()).unwrap();
 
         assert_eq!(res.0.len(), writer.len());
         assert_eq!(res.1, RawTypeList {
@@ -568,7 +686,7 @@ mod tests {
         writer.write_u32::<LittleEndian>(2).unwrap();
         writer.write_u32::<LittleEndian>(2).unwrap();
 
-        let res = parse_map_list(&writer, e).unwrap();
+        let res = parse_map_list(&writer, e, &ParseContext::default()).unwrap();
 
         assert_eq!(res.0.len(), 0);
         assert_eq!(res.1, RawMapList {
@@ -616,7 +734,7 @@ mod tests {
         assert_eq!(res.1, 128);
 
         res = parse_uleb128(&[0b11111111, 0b11111111, 0b11111111, 0b11111111, 0b01111111]).unwrap();
-        assert_eq!(res.1, std::u32::MAX);
+        assert_eq!(res.1, u32::MAX);
     }
 
     #[test]
@@ -628,7 +746,7 @@ mod tests {
         assert_eq!(res.1, 127);
 
         res = parse_uleb128p1(&[0b11111111, 0b11111111, 0b11111111, 0b11111111, 0b00000111]).unwrap();
-        assert_eq!(res.1, std::i32::MAX - 1);
+        assert_eq!(res.1, i32::MAX - 1);
     }
 
     #[test]
@@ -643,7 +761,7 @@ mod tests {
         assert_eq!(res.1, 128);
 
         res = parse_sleb128(&[0b11111111, 0b11111111, 0b11111111, 0b11111111, 0b00000111]).unwrap();
-        assert_eq!(res.1, std::i32::MAX);
+        assert_eq!(res.1, i32::MAX);
 
         res = parse_sleb128(&[0b11111111, 0b11111111, 0b11111111, 0b11111111, 0b01111111]).unwrap();
         assert_eq!(res.1, -1);
@@ -708,7 +826,7 @@ mod tests {
             signature,
             file_size: 12345,
             header_size: HEADER_SIZE as u32,
-            endian_tag: 0x78563412 as u32,
+            endian_tag: 0x78563412_u32,
             link_size: 1,
             link_off: 1,
             map_off: 2,
@@ -729,6 +847,77 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(&[]), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        // long enough to need reducing part way through
+        assert_eq!(adler32(&[0xFF; 10_000]), 0xB623_EB2B);
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        use std::io::Write;
+
+        let mut writer = vec!();
+        writer.write_all(&DEX_FILE_MAGIC).unwrap();
+        writer.write_all(b"038\0").unwrap();
+        // checksum is filled in below
+        writer.write_u32::<LittleEndian>(0).unwrap();
+        writer.write_all(&[0; 20]).unwrap();
+        // file size, then zeroes for the rest of the header
+        writer.write_u32::<LittleEndian>(HEADER_SIZE as u32).unwrap();
+        writer.resize(HEADER_SIZE, 0);
+
+        let checksum = adler32(&writer[12 ..]);
+        assert!(verify_checksum(&writer, e).is_err());
+
+        (&mut writer[8 .. 12]).write_u32::<LittleEndian>(checksum).unwrap();
+        assert!(verify_checksum(&writer, e).is_ok());
+    }
+
+    #[test]
+    fn test_map_list_tolerance() {
+        use std::io::Write;
+
+        // a file with nothing in it but the header and a map list describing the two
+        let file_size = HEADER_SIZE as u32 + 4 + 2 * 12;
+        let mut writer = vec!();
+        writer.write_all(&DEX_FILE_MAGIC).unwrap();
+        writer.write_all(b"035\0").unwrap();
+        // checksum and signature
+        writer.write_all(&[0; 24]).unwrap();
+        writer.write_u32::<LittleEndian>(file_size).unwrap();
+        writer.write_u32::<LittleEndian>(HEADER_SIZE as u32).unwrap();
+        writer.write_all(&ENDIAN_CONSTANT).unwrap();
+        // no link section, then the map offset and zeroes for the empty sections
+        writer.write_all(&[0; 8]).unwrap();
+        writer.write_u32::<LittleEndian>(HEADER_SIZE as u32).unwrap();
+        writer.resize(HEADER_SIZE, 0);
+        writer.write_u32::<LittleEndian>(2).unwrap();
+        for (type_, offset) in &[(0x0000_u16, 0_u32), (0x1000_u16, HEADER_SIZE as u32)] {
+            writer.write_u16::<LittleEndian>(*type_).unwrap();
+            writer.write_u16::<LittleEndian>(0).unwrap();
+            writer.write_u32::<LittleEndian>(1).unwrap();
+            writer.write_u32::<LittleEndian>(*offset).unwrap();
+        }
+        assert!(parse_dex_file(&writer, e, &ParseContext::default()).unwrap().1.regions.is_empty());
+
+        // a section of a type added after this parser was written is passed over
+        let mut unknown = writer.clone();
+        (&mut unknown[HEADER_SIZE + 4 ..]).write_u16::<LittleEndian>(0x7777).unwrap();
+        let map_list = parse_map_list(&unknown[HEADER_SIZE ..], e, &ParseContext::default()).unwrap().1;
+        assert_eq!(map_list.list[0].type_, MapListItemType::Unknown(0x7777));
+        assert!(parse_dex_file(&unknown, e, &ParseContext::default()).is_ok());
+
+        // before 038 the map list isn't needed to read the file, from 038 on it is
+        let mut missing = writer.clone();
+        (&mut missing[0x34 ..]).write_u32::<LittleEndian>(file_size + 1).unwrap();
+        assert!(parse_dex_file(&missing, e, &ParseContext::default()).unwrap().1.regions.is_empty());
+        (&mut missing[4 ..]).write_all(b"038").unwrap();
+        assert!(parse_dex_file(&missing, e, &ParseContext::default()).is_err());
+    }
+
     // TODO: test parse_dex_file
 
     // TODO: test NO_INDEX
@@ -5,10 +5,18 @@ use super::raw_types::*;
 use crate::result_types::*;
 use super::*;
 
-fn transform_string_id_items<'a>(data: &'a[u8], sdi: &[u32], off: usize) -> nom::IResult<&'a[u8], Vec<Rc<String>>> {
-    let mut v = vec!();
+// Offsets are from the start of the file, but we only have the data blob here, so we'll need to do some math
+fn data_at(data: &[u8], off: usize, data_off: usize) -> Result<&[u8], DexParserError> {
+    off.checked_sub(data_off)
+        .and_then(|off| data.get(off..))
+        .ok_or_else(|| DexParserError::from(format!("offset 0x{:X} is outside of the data section", off)))
+}
+
+fn transform_string_id_items<'a>(data: &'a[u8], sdi: &[u32], off: usize, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<Rc<String>>> {
+    let mut v = Vec::with_capacity(sdi.len());
     for offset in sdi {
-        let raw = parse_string_data_item(&data[*offset as usize - off..])?.1;
+        ctx.work(1)?;
+        let raw = parse_string_data_item(data_at(data, *offset as usize, off)?)?.1;
 
         v.push(Rc::new(raw.data));
     }
@@ -26,20 +34,18 @@ fn transform_header(raw: &RawHeader, e: nom::Endianness) -> Result<Header, DexPa
 }
 
 fn transform_prototype_id_items<'a>(data: &'a[u8], proto_ids: &[RawPrototype], sd: &[Rc<String>],
-                                    ti: &[Rc<String>], off: usize, e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<Rc<Prototype>>> {
-    let mut v = vec!();
+                                    ti: &[Rc<String>], off: usize, e: nom::Endianness,
+                                    ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<Rc<Prototype>>> {
+    let mut v = Vec::with_capacity(proto_ids.len());
     for item in proto_ids {
-        let shorty = sd[item.shorty_idx as usize].clone();
-        let return_type = ti[item.return_type_idx as usize].clone();
+        ctx.work(1)?;
+        let shorty = lookup(sd, item.shorty_idx, "string")?;
+        let return_type = lookup(ti, item.return_type_idx, "type")?;
 
         let parameters = if item.parameters_off == 0 {
             vec!()
         } else {
-            parse_type_list(&data[item.parameters_off as usize - off..], e)?.1
-                .list
-                .into_iter()
-                .map(|idx| ti[idx as usize].clone())
-                .collect()
+            transform_type_list(data, item.parameters_off as usize, off, ti, e, ctx)?
         };
 
         v.push(Rc::new(Prototype { shorty, return_type, parameters }));
@@ -48,7 +54,16 @@ fn transform_prototype_id_items<'a>(data: &'a[u8], proto_ids: &[RawPrototype], s
     Ok((data, v))
 }
 
-pub fn transform_dex_file(raw: RawDexFile, e: nom::Endianness) -> Result<DexFile, DexParserError> {
+fn transform_type_list(data: &[u8], off: usize, data_off: usize, ti: &[Rc<String>], e: nom::Endianness,
+                       ctx: &ParseContext) -> Result<Vec<Rc<String>>, DexParserError> {
+    parse_type_list(data_at(data, off, data_off)?, e, ctx)?.1
+        .list
+        .into_iter()
+        .map(|idx| lookup(ti, u32::from(idx), "type"))
+        .collect()
+}
+
+pub fn transform_dex_file(raw: RawDexFile, e: nom::Endianness, ctx: &ParseContext) -> Result<DexFile, DexParserError> {
 
     // Offsets are given, but we only have the data blob here, so we'll need to do some math
     let off = raw.header.data_off as usize;
@@ -56,64 +71,79 @@ pub fn transform_dex_file(raw: RawDexFile, e: nom::Endianness) -> Result<DexFile
     let header = transform_header(&raw.header, e)?;
 
     let file_data = {
-        let sd = transform_string_id_items(&raw.data, &raw.string_id_items, off)?.1;
-        let ti = raw.type_id_items.into_iter().map(|i| sd[i as usize].clone()).collect::<Vec<_>>();
-        let pro = transform_prototype_id_items(&raw.data, &raw.proto_id_items, &sd, &ti, off, header.endianness)?.1;
+        let sd = transform_string_id_items(&raw.data, &raw.string_id_items, off, ctx)?.1;
+        let ti = raw.type_id_items.into_iter()
+            .map(|i| lookup(&sd, i, "string"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let pro = transform_prototype_id_items(&raw.data, &raw.proto_id_items, &sd, &ti, off, header.endianness, ctx)?.1;
 
         let fields = raw.field_id_items.into_iter()
-            .map(|i| Rc::new(Field {
-                definer: ti[i.class_idx as usize].clone(),
-                type_: ti[i.type_idx as usize].clone(),
-                name: sd[i.name_idx as usize].clone()
-            })).collect::<Vec<_>>();
+            .map(|i| Ok(Rc::new(Field {
+                definer: lookup(&ti, u32::from(i.class_idx), "type")?,
+                type_: lookup(&ti, u32::from(i.type_idx), "type")?,
+                name: lookup(&sd, i.name_idx, "string")?
+            }))).collect::<Result<Vec<_>, DexParserError>>()?;
 
         let methods = raw.method_id_items.into_iter()
-            .map(|i| Rc::new(Method {
-                definer: ti[i.class_idx as usize].clone(),
-                prototype: pro[i.proto_idx as usize].clone(),
-                name: sd[i.name_idx as usize].clone()
-            })).collect::<Vec<_>>();
+            .map(|i| Ok(Rc::new(Method {
+                definer: lookup(&ti, u32::from(i.class_idx), "type")?,
+                prototype: lookup(&pro, u32::from(i.proto_idx), "prototype")?,
+                name: lookup(&sd, i.name_idx, "string")?
+            }))).collect::<Result<Vec<_>, DexParserError>>()?;
+
+        let method_handles = raw.method_handle_idxs.unwrap_or_default().into_iter()
+            .map(|i| {
+                let type_ = MethodHandleType::parse(i.type_)?;
+                let idx = u32::from(i.field_or_method_id);
+                let member = match type_ {
+                    MethodHandleType::STATIC_PUT | MethodHandleType::STATIC_GET
+                        | MethodHandleType::INSTANCE_PUT | MethodHandleType::INSTANCE_GET =>
+                        MethodHandleMember::Field(lookup(&fields, idx, "field")?),
+                    _ => MethodHandleMember::Method(lookup(&methods, idx, "method")?)
+                };
+                Ok(Rc::new(MethodHandle { type_, member }))
+            }).collect::<Result<Vec<_>, DexParserError>>()?;
 
         DexFileData {
             string_data: sd,
             type_identifiers: ti,
             prototypes: pro,
             fields,
-            methods
+            methods,
+            method_handles,
+            call_sites: vec!()
         }
     };
 
-    let classes = transform_class_defs(&raw.data, off, &raw.class_def_items, &file_data, header.endianness)?.1;
-
-    /**
-    TODO: find a DEX file with call site items and test parsing them
+    // call sites are encoded arrays which may refer to anything else in the pools, so come last
+    let mut file_data = file_data;
+    if let Some(csi) = raw.call_site_idxs {
+        file_data.call_sites = parse_call_site_items(&raw.data, off, &csi, &file_data, ctx)?;
+    }
 
-    let call_site_items = if let Some(csi) = raw.call_site_idxs {
-        parse_call_site_items(&raw.data, off, &csi, &file_data)?
-    } else {
-        vec!()
-    };
-    */
+    let classes = transform_class_defs(&raw.data, off, &raw.class_def_items, &file_data, header.endianness, ctx)?.1;
 
     Ok(DexFile {
         header,
         file_data,
-        classes
+        classes,
+        regions: raw.regions
     })
 }
 
 fn transform_annotations<'a>(data: &'a[u8], off: usize, data_off: usize, fd: &DexFileData,
-                             e: nom::Endianness) -> nom::IResult<&'a[u8], Annotations> {
+                             e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Annotations> {
 
-    let adi = parse_annotations_directory_item(&data[off - data_off..], e)?.1;
+    let adi = parse_annotations_directory_item(data_at(data, off, data_off)?, e, ctx)?.1;
 
     let mut class_annotations = vec!();
     if adi.class_annotations_off != 0 {
-        let set_item = parse_annotation_set_item(&data[adi.class_annotations_off as usize - data_off..], e)?.1;
+        let set_item = parse_annotation_set_item(data_at(data, adi.class_annotations_off as usize, data_off)?, e, ctx)?.1;
         // Each entry here is an offset to an annotation_item in the data pool
         for annotation_offset in set_item.entries {
+            ctx.work(1)?;
             // Every annotation item contains a visibility, a type and an annotation
-            let annotation_item = parse_annotation_item(&data[annotation_offset as usize - data_off..], fd)?.1;
+            let annotation_item = parse_annotation_item(data_at(data, annotation_offset as usize, data_off)?, fd, ctx)?.1;
 
             class_annotations.push(ClassAnnotation {
                 visibility: annotation_item.visibility,
@@ -124,19 +154,19 @@ fn transform_annotations<'a>(data: &'a[u8], off: usize, data_off: usize, fd: &De
     };
 
     let field_annotations = if let Some(rfas) = adi.fld_annot {
-        transform_field_annotations(data, rfas, &fd, data_off, e)?.1
+        transform_field_annotations(data, rfas, fd, data_off, e, ctx)?.1
     } else {
         vec!()
     };
 
     let method_annotations = if let Some(rmas) = adi.mtd_annot {
-        transform_method_annotations(data, rmas, &fd, data_off, e)?.1
+        transform_method_annotations(data, rmas, fd, data_off, e, ctx)?.1
     } else {
         vec!()
     };
 
     let parameter_annotations = if let Some(rpas) = adi.prm_annot {
-        transform_parameter_annotations(data, rpas, &fd, data_off, e)?.1
+        transform_parameter_annotations(data, rpas, fd, data_off, e, ctx)?.1
     } else {
         vec!()
     };
@@ -150,179 +180,192 @@ fn transform_annotations<'a>(data: &'a[u8], off: usize, data_off: usize, fd: &De
 }
 
 fn transform_field_annotations<'a>(data: &'a[u8], rfas: Vec<RawFieldAnnotation>, fd: &DexFileData,
-                                   data_off: usize, e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<FieldAnnotation>> {
+                                   data_off: usize, e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<FieldAnnotation>> {
     let mut fa = Vec::with_capacity(rfas.len());
     for rfa in rfas {
         fa.push(FieldAnnotation {
-            field_data: fd.fields[rfa.field_idx as usize].clone(),
-            annotations: parse_annotations(&data, &fd, rfa.annotations_offset as usize, data_off, e)?.1
+            field_data: lookup(&fd.fields, rfa.field_idx, "field")?,
+            annotations: parse_annotations(data, fd, rfa.annotations_offset as usize, data_off, e, ctx)?.1
         })
     }
     Ok((data, fa))
 }
 
 fn transform_method_annotations<'a>(data: &'a[u8], rmas: Vec<RawMethodAnnotation>, fd: &DexFileData,
-                                    data_off: usize, e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<MethodAnnotation>> {
+                                    data_off: usize, e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<MethodAnnotation>> {
     let mut ma = Vec::with_capacity(rmas.len());
     for rma in rmas {
         ma.push(MethodAnnotation {
-            method: fd.methods[rma.method_idx as usize].clone(),
-            annotations: parse_annotations(&data, &fd, rma.annotations_offset as usize, data_off, e)?.1
+            method: lookup(&fd.methods, rma.method_idx, "method")?,
+            annotations: parse_annotations(data, fd, rma.annotations_offset as usize, data_off, e, ctx)?.1
         })
     }
     Ok((data, ma))
 }
 
 fn transform_parameter_annotations<'a>(data: &'a[u8], rpas: Vec<RawParameterAnnotation>, fd: &DexFileData,
-                                       data_off: usize, e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<ParameterAnnotation>> {
+                                       data_off: usize, e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<ParameterAnnotation>> {
     let mut pa = Vec::with_capacity(rpas.len());
     for rpa in rpas {
-        let asrl = parse_annotation_set_ref_list(&data[rpa.annotations_offset as usize - data_off..], e)?.1;
+        let asrl = parse_annotation_set_ref_list(data_at(data, rpa.annotations_offset as usize, data_off)?, e, ctx)?.1;
 
+        // an offset of zero means there are no annotations for that parameter
+        let mut annotations = Vec::with_capacity(asrl.entries.len());
         for annot_set_offset in asrl.entries {
-            if annot_set_offset != 0 {
-                pa.push(ParameterAnnotation {
-                    method: fd.methods[rpa.method_idx as usize].clone(),
-                    annotations: parse_annotations(&data, &fd, annot_set_offset as usize, data_off, e)?.1
-                })
-            }
+            annotations.push(if annot_set_offset == 0 {
+                vec!()
+            } else {
+                parse_annotations(data, fd, annot_set_offset as usize, data_off, e, ctx)?.1
+            });
         }
+
+        pa.push(ParameterAnnotation {
+            method: lookup(&fd.methods, rpa.method_idx, "method")?,
+            annotations
+        });
     }
     Ok((data, pa))
 }
 
 fn parse_annotations<'a>(data: &'a[u8], fd: &DexFileData, off: usize, data_off: usize,
-                         e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<AnnotationItem>> {
+                         e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<AnnotationItem>> {
     let mut annotations = vec!();
-    let asi = parse_annotation_set_item(&data[off - data_off..], e)?.1;
+    let asi = parse_annotation_set_item(data_at(data, off, data_off)?, e, ctx)?.1;
     for annot_offset in asi.entries {
-        annotations.push(parse_annotation_item(&data[annot_offset as usize - data_off..], fd)?.1);
+        ctx.work(1)?;
+        annotations.push(parse_annotation_item(data_at(data, annot_offset as usize, data_off)?, fd, ctx)?.1);
     }
 
     Ok((data, annotations))
 }
 
 fn transform_class_defs<'a>(data: &'a[u8], data_off: usize, cdis: &[RawClassDefinition],
-                            fd: &DexFileData, e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<ClassDefinition>> {
+                            fd: &DexFileData, e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<ClassDefinition>> {
 
     let mut v = Vec::with_capacity(cdis.len());
 
     for cdi in cdis {
-        let class_type = fd.type_identifiers[cdi.class_idx as usize].clone();
+        ctx.work(1)?;
+
+        let class_type = lookup(&fd.type_identifiers, cdi.class_idx, "type")?;
 
         let access_flags = AccessFlag::parse(cdi.access_flags, AnnotationType::Class);
 
         let superclass = if cdi.superclass_idx == NO_INDEX {
             None
         } else {
-            Some(fd.type_identifiers[cdi.superclass_idx as usize].clone())
+            Some(lookup(&fd.type_identifiers, cdi.superclass_idx, "type")?)
         };
 
         let interfaces = if cdi.interfaces_off == 0 {
             vec!()
         } else {
-            parse_type_list(&data[cdi.interfaces_off as usize - data_off..], e)?.1
-                .list
-                .into_iter()
-                .map(|idx| fd.type_identifiers[idx as usize].clone())
-                .collect()
+            transform_type_list(data, cdi.interfaces_off as usize, data_off, &fd.type_identifiers, e, ctx)?
         };
 
-        let annotations = if cdi.annotations_off == 0 {
+        let annotations = if cdi.annotations_off == 0 || ctx.options().skip_annotations {
             None
         } else {
-            Some(transform_annotations(&data, cdi.annotations_off as usize, data_off, &fd, e)?.1)
+            ctx.recover(transform_annotations(data, cdi.annotations_off as usize, data_off, fd, e, ctx))?
+                .map(|res| res.1)
         };
 
         let source_file_name = if cdi.source_file_idx == NO_INDEX {
             None
         } else {
-            Some(fd.string_data[cdi.source_file_idx as usize].clone())
+            Some(lookup(&fd.string_data, cdi.source_file_idx, "string")?)
         };
 
         let class_data = if cdi.class_data_off == 0 {
             None
         } else {
-            let class_data = parse_class_data_item(&data[cdi.class_data_off as usize - data_off..])?.1;
-
-            let static_fields = transform_encoded_fields(&class_data.static_fields, &fd);
-            let instance_fields = transform_encoded_fields(&class_data.instance_fields, &fd);
-            let direct_methods = transform_encoded_methods(&data, data_off, &class_data.direct_methods, &fd, e)?.1;
-            let virtual_methods = transform_encoded_methods(&data, data_off, &class_data.virtual_methods, &fd, e)?.1;
-
-            Some(ClassData { static_fields, instance_fields, direct_methods, virtual_methods })
+            ctx.recover(transform_class_data(data, cdi.class_data_off as usize, data_off, fd, e, ctx))?
         };
 
-        let static_values = if cdi.static_values_off == 0 {
+        let static_values = if cdi.static_values_off == 0 || ctx.options().skip_static_values {
             vec!()
         } else {
-            peek!(&data[cdi.static_values_off as usize - data_off..], call!(encoded_value::parse_encoded_array_item, fd))?.1
+            ctx.recover(transform_static_values(data, cdi.static_values_off as usize, data_off, fd, ctx))?
+                .unwrap_or_default()
         };
 
-        v.push(ClassDefinition { class_type, access_flags, superclass, interfaces,
-            source_file_name, annotations, class_data, static_values });
+        let mut class = ClassDefinition { class_type, access_flags, superclass, interfaces,
+            source_file_name, annotations, class_data, static_values, system_annotations: Default::default() };
+        super::system_annotations::decode(&mut class);
+        v.push(class);
     }
 
     Ok((data, v))
 }
 
+fn transform_class_data(data: &[u8], off: usize, data_off: usize, fd: &DexFileData, e: nom::Endianness,
+                        ctx: &ParseContext) -> Result<ClassData, DexParserError> {
+    let class_data = parse_class_data_item(data_at(data, off, data_off)?, ctx)?.1;
+
+    let static_fields = transform_encoded_fields(&class_data.static_fields, fd)?;
+    let instance_fields = transform_encoded_fields(&class_data.instance_fields, fd)?;
+    let direct_methods = transform_encoded_methods(data, data_off, &class_data.direct_methods, fd, e, ctx)?.1;
+    let virtual_methods = transform_encoded_methods(data, data_off, &class_data.virtual_methods, fd, e, ctx)?.1;
+
+    Ok(ClassData { static_fields, instance_fields, direct_methods, virtual_methods })
+}
+
+fn transform_static_values(data: &[u8], off: usize, data_off: usize, fd: &DexFileData,
+                           ctx: &ParseContext) -> Result<Vec<EncodedValue>, DexParserError> {
+    Ok(encoded_value::parse_encoded_array_item(data_at(data, off, data_off)?, fd, ctx)?.1)
+}
+
 // Encoded fields are stored sequentially, with each index in the raw encoded field being the *diff*
 // of the index (not the total index) from the previous entry
-fn transform_encoded_fields(raw: &[RawEncodedField], fd: &DexFileData) -> Vec<EncodedField> {
+fn transform_encoded_fields(raw: &[RawEncodedField], fd: &DexFileData) -> Result<Vec<EncodedField>, DexParserError> {
     let mut fields = vec!();
     // The first entry effectively has an offset of 0
-    let mut prev_offset = 0;
+    let mut prev_offset: u32 = 0;
     // Subsequent entry indexes are offsets of the previous entry index
     for field in raw {
+        prev_offset = prev_offset.wrapping_add(field.field_idx_diff);
         fields.push(EncodedField {
-            field: fd.fields[(prev_offset + field.field_idx_diff) as usize].clone(),
-            access_flags: AccessFlag::parse(field.access_flags as u32, AnnotationType::Field)
+            field: lookup(&fd.fields, prev_offset, "field")?,
+            access_flags: AccessFlag::parse(field.access_flags, AnnotationType::Field)
         });
-        prev_offset = field.field_idx_diff;
     }
-    fields
+    Ok(fields)
 }
 
 fn transform_code_item<'a>(data: &'a[u8], data_off: usize, handler_off: usize, raw: RawCodeItem,
-                           fd: &DexFileData, e: nom::Endianness) -> nom::IResult<&'a[u8], Code> {
+                           fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], Code> {
 
-    let debug_info = if raw.debug_info_off == 0 {
+    let debug_info = if raw.debug_info_off == 0 || ctx.options().skip_debug_info {
         None
     } else {
-        let rdi = parse_debug_info_item(&data[raw.debug_info_off as usize - data_off ..])?.1;
-
-        Some(DebugInfo {
-            line_start: rdi.line_start,
-            parameter_names: rdi.parameter_names,
-            bytecode: rdi.bytecode.into_iter().map(DebugItemBytecodes::parse).collect()
-        })
+        ctx.recover(transform_debug_info(data, raw.debug_info_off as usize, data_off, fd, ctx))?
     };
 
     let mut tries = vec!();
     if let Some(raw_tries) = raw.tries {
         for raw_try in raw_tries {
-
-            let code_units = parse_code_units(&data[raw_try.start_addr as usize ..],
-                                              raw_try.insn_count as usize, e)?.1;
+            ctx.work(1)?;
 
             let handler = {
-                let rh = peek!(&data[handler_off + raw_try.handler_off as usize ..], parse_encoded_catch_handler)?.1;
-                transform_encoded_catch_handler(rh, &fd)
+                let rh = peek!(slice_from(data, (handler_off + raw_try.handler_off as usize) as u32)?,
+                               call!(parse_encoded_catch_handler, ctx))?.1;
+                transform_encoded_catch_handler(rh, fd)?
             };
 
             tries.push(TryItem {
-                code_units,
+                start_addr: raw_try.start_addr,
+                insn_count: raw_try.insn_count,
                 handler
             });
         }
     }
 
     let handlers = if handler_off != 0 {
-        peek!(&data[handler_off as usize ..], parse_encoded_catch_handler_list)?.1
+        peek!(slice_from(data, handler_off as u32)?, call!(parse_encoded_catch_handler_list, ctx))?.1
             .list
             .into_iter()
-            .map(|raw| transform_encoded_catch_handler(raw, &fd))
-            .collect()
+            .map(|raw| transform_encoded_catch_handler(raw, fd))
+            .collect::<Result<Vec<_>, _>>()?
     } else {
         vec!()
     };
@@ -338,107 +381,170 @@ fn transform_code_item<'a>(data: &'a[u8], data_off: usize, handler_off: usize, r
     }))
 }
 
+fn transform_debug_info(data: &[u8], off: usize, data_off: usize, fd: &DexFileData,
+                        ctx: &ParseContext) -> Result<DebugInfo, DexParserError> {
+    let (bytecode, rdi) = parse_debug_info_item(data_at(data, off, data_off)?, ctx)?;
+
+    Ok(DebugInfo {
+        line_start: rdi.line_start,
+        parameter_names: rdi.parameter_names.into_iter()
+            .map(|idx| optional_lookup(&fd.string_data, idx, "string"))
+            .collect::<Result<Vec<_>, _>>()?,
+        bytecode: parse_debug_bytecode(bytecode, fd, ctx)?.1
+    })
+}
+
+// The bytecodes for the debug state machine, up to and including the DBG_END_SEQUENCE
+fn parse_debug_bytecode<'a>(data: &'a[u8], fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<DebugItemBytecodes>> {
+    let mut bytecode = vec!();
+    let mut input = data;
+
+    loop {
+        ctx.work(1)?;
+        let (rest, opcode) = take_one(input)?;
+
+        let (rest, item) = match opcode {
+            0x00 => return Ok((rest, bytecode)),
+            0x01 => map!(rest, parse_uleb128, DebugItemBytecodes::DBG_ADVANCE_PC)?,
+            0x02 => map!(rest, parse_sleb128, DebugItemBytecodes::DBG_ADVANCE_LINE)?,
+            0x03 => {
+                let (rest, (register_num, name, type_)) = tuple!(rest, parse_uleb128, parse_uleb128p1, parse_uleb128p1)?;
+                (rest, DebugItemBytecodes::DBG_START_LOCAL {
+                    register_num,
+                    name: optional_lookup(&fd.string_data, name, "string")?,
+                    type_: optional_lookup(&fd.type_identifiers, type_, "type")?
+                })
+            },
+            0x04 => {
+                let (rest, (register_num, name, type_, sig)) =
+                    tuple!(rest, parse_uleb128, parse_uleb128p1, parse_uleb128p1, parse_uleb128p1)?;
+                (rest, DebugItemBytecodes::DBG_START_LOCAL_EXTENDED {
+                    register_num,
+                    name: optional_lookup(&fd.string_data, name, "string")?,
+                    type_: optional_lookup(&fd.type_identifiers, type_, "type")?,
+                    sig: optional_lookup(&fd.string_data, sig, "string")?
+                })
+            },
+            0x05 => map!(rest, parse_uleb128, DebugItemBytecodes::DBG_END_LOCAL)?,
+            0x06 => map!(rest, parse_uleb128, DebugItemBytecodes::DBG_RESTART_LOCAL)?,
+            0x07 => (rest, DebugItemBytecodes::DBG_SET_PROLOGUE_END),
+            0x08 => (rest, DebugItemBytecodes::DBG_SET_EPILOGUE_BEGIN),
+            0x09 => {
+                let (rest, name) = parse_uleb128p1(rest)?;
+                (rest, DebugItemBytecodes::DBG_SET_FILE(optional_lookup(&fd.string_data, name, "string")?))
+            },
+            _ => (rest, DebugItemBytecodes::SPECIAL_OPCODE(opcode))
+        };
+
+        bytecode.push(item);
+        input = rest;
+    }
+}
+
+// uleb128p1 indexes use -1 for NO_INDEX
+fn optional_lookup<T: Clone>(pool: &[T], idx: Sleb128, name: &str) -> Result<Option<T>, DexParserError> {
+    if idx == -1 {
+        Ok(None)
+    } else {
+        lookup(pool, idx as u32, name).map(Some)
+    }
+}
+
 fn transform_encoded_methods<'a>(data: &'a[u8], data_off: usize, raw: &[RawEncodedMethod],
-                                 fd: &DexFileData, e: nom::Endianness) -> nom::IResult<&'a[u8], Vec<EncodedMethod>> {
+                                 fd: &DexFileData, e: nom::Endianness, ctx: &ParseContext) -> nom::IResult<&'a[u8], Vec<EncodedMethod>> {
     let mut methods = vec!();
-    let mut prev_offset = 0;
+    let mut prev_offset: u32 = 0;
     for method in raw {
-        let code = if method.code_off == 0 {
+        ctx.work(1)?;
+
+        let code = if method.code_off == 0 || ctx.options().skip_code {
             None
         } else {
-            // Have to stop parsing just before the handler here so we can get the handler offset
-            let (leftover, rci) = parse_code_item(&data[method.code_off as usize - data_off ..], e)?;
-
-            let handler_off = if rci.tries_size > 0 {
-                data.len() - leftover.len()
-            } else {
-                0
-            } as usize;
-
-            Some(transform_code_item(data, data_off, handler_off, rci, &fd, e)?.1)
+            ctx.recover(transform_method_code(data, data_off, method.code_off as usize, fd, e, ctx))?
         };
 
+        prev_offset = prev_offset.wrapping_add(method.method_idx_diff);
+
         methods.push(EncodedMethod {
-            method: fd.methods[(prev_offset + method.method_idx_diff) as usize].clone(),
-            access_flags: AccessFlag::parse(method.access_flags as u32, AnnotationType::Method),
-            code
+            method: lookup(&fd.methods, prev_offset, "method")?,
+            access_flags: AccessFlag::parse(method.access_flags, AnnotationType::Method),
+            code,
+            system_annotations: Default::default()
         });
-
-        prev_offset += method.method_idx_diff;
     }
 
     Ok((data, methods))
 }
 
-fn transform_encoded_catch_handler(raw: RawEncodedCatchHandler, fd: &DexFileData) -> EncodedCatchHandler {
-    EncodedCatchHandler {
+pub fn transform_method_code(data: &[u8], data_off: usize, code_off: usize, fd: &DexFileData, e: nom::Endianness,
+                         ctx: &ParseContext) -> Result<Code, DexParserError> {
+    // Have to stop parsing just before the handler here so we can get the handler offset
+    let (leftover, rci) = parse_code_item(data_at(data, code_off, data_off)?, e, ctx)?;
+
+    let handler_off = if rci.tries_size > 0 {
+        data.len() - leftover.len()
+    } else {
+        0
+    };
+
+    Ok(transform_code_item(data, data_off, handler_off, rci, fd, ctx)?.1)
+}
+
+fn transform_encoded_catch_handler(raw: RawEncodedCatchHandler, fd: &DexFileData) -> Result<EncodedCatchHandler, DexParserError> {
+    Ok(EncodedCatchHandler {
         handlers: raw.handlers.into_iter()
             .map(|raw| {
-                EncodedTypeAddrPair {
-                    type_: fd.type_identifiers[raw.type_idx as usize].clone(),
+                Ok(EncodedTypeAddrPair {
+                    type_: lookup(&fd.type_identifiers, raw.type_idx, "type")?,
                     addr: raw.addr
-                }
-            }).collect(),
+                })
+            }).collect::<Result<Vec<_>, DexParserError>>()?,
         catch_all_addr: raw.catch_all_addr
-    }
+    })
 }
 
-/**
-TODO: implement parsing call site items
-fn parse_call_site_items(data: &[u8], data_off: usize, csi: &[u32], fd: &DexFileData) -> Result<Vec<CallSiteItem>, DexParserError> {
-    unimplemented!();
-
-    let mut v = Vec::new();
-    for idx in csi {
-
-        let array = encoded_value::parse_encoded_array_item(&data[*idx as usize - data_off ..], fd)?.1;
-
-        let method_handle = if let EncodedValue::MethodHandle(mtd) = array[0] {
-            mtd.clone()
-        } else {
-            return Err(DexParserError::from("call site item could not be parsed: bootstrap linker method handle malformed"));
-        };
-
-        let method_name = if let EncodedValue::String(mtd_name) = array[1] {
-            mtd_name.clone()
-        } else {
-            return Err(DexParserError::from("call site item could not be parsed: bootstrap linker method name malformed"));
+// Docs: call_site_item
+// Each call site is an encoded array: the bootstrap method handle, the method name and type, then any extra arguments
+fn parse_call_site_items(data: &[u8], data_off: usize, csi: &[u32], fd: &DexFileData,
+                         ctx: &ParseContext) -> Result<Vec<Rc<CallSiteItem>>, DexParserError> {
+    let mut v = Vec::with_capacity(csi.len());
+    for off in csi {
+        ctx.work(1)?;
+        let mut array = encoded_value::parse_encoded_array_item(data_at(data, *off as usize, data_off)?, fd, ctx)?.1.into_iter();
+
+        let method_handle = match array.next() {
+            Some(EncodedValue::MethodHandle(handle)) => handle,
+            _ => return Err(DexParserError::from("call site item could not be parsed: bootstrap linker method handle malformed"))
         };
 
-        let method_type = if let EncodedValue::MethodType(mtd_type) = array[2] {
-            mtd_type.clone()
-        } else {
-            return Err(DexParserError::from("call site item could not be parsed: bootstrap linker method type malformed"));
+        let method_name = match array.next() {
+            Some(EncodedValue::String(name)) => name,
+            _ => return Err(DexParserError::from("call site item could not be parsed: bootstrap linker method name malformed"))
         };
 
-        // TODO: check indexing
-        let constant_values = if array.len() > 3 {
-            array[3 ..].to_vec()
-        } else {
-            vec!()
+        let method_type = match array.next() {
+            Some(EncodedValue::MethodType(proto)) => proto,
+            _ => return Err(DexParserError::from("call site item could not be parsed: bootstrap linker method type malformed"))
         };
 
-        v.push(CallSiteItem { method_handle, method_name, method_type, constant_values })
+        v.push(Rc::new(CallSiteItem { method_handle, method_name, method_type, constant_values: array.collect() }));
     }
     Ok(v)
 }
-*/
 
 // Docs: debug_info_item
-named!(parse_debug_info_item<&[u8], RawDebugInfoItem>,
-    peek!(
-        do_parse!(
-            line_start: call!(parse_uleb128)    >>
-            parameters_size: call!(parse_uleb128)   >>
-            parameter_names: count!(call!(parse_uleb128p1), parameters_size as usize)    >>
-            bytecode: map!(take_until_and_consume!("\0"), |i| { i.to_vec() })    >>
-            (RawDebugInfoItem { line_start, parameters_size, parameter_names, bytecode })
-        )
+// stops at the start of the state machine bytecode
+named_args!(pub parse_debug_info_item<'a>(ctx: &'a ParseContext)<RawDebugInfoItem>,
+    do_parse!(
+        line_start: call!(parse_uleb128)    >>
+        parameters_size: call!(parse_uleb128)   >>
+        parameter_names: count!(call!(parse_uleb128p1), ctx.count(parameters_size, 4)?)    >>
+        (RawDebugInfoItem { line_start, parameters_size, parameter_names })
     )
 );
 
 // Docs: code_item
-named_args!(parse_code_item(e: nom::Endianness)<&[u8], RawCodeItem>,
+named_args!(pub parse_code_item<'a>(e: nom::Endianness, ctx: &'a ParseContext)<RawCodeItem>,
     do_parse!(
         registers_size: u16!(e) >>
         ins_size: u16!(e)   >>
@@ -446,15 +552,14 @@ named_args!(parse_code_item(e: nom::Endianness)<&[u8], RawCodeItem>,
         tries_size: u16!(e) >>
         debug_info_off: u32!(e) >>
         insns_size: u32!(e) >>
-        insns: count!(u16!(e), insns_size as usize)  >>
+        insns: count!(u16!(e), ctx.count(insns_size, 2)?)  >>
         padding: cond!(tries_size != 0 && insns_size % 2 != 0, u16!(e))  >>
-        tries: cond!(tries_size != 0, count!(call!(parse_try_item, e), tries_size as usize)) >>
+        tries: cond!(tries_size != 0, count!(call!(parse_try_item, e), ctx.count(u32::from(tries_size), 8)?)) >>
         (RawCodeItem { registers_size, ins_size, outs_size, tries_size, debug_info_off,
          insns_size, insns, padding, tries })
     )
 );
 
-named_args!(parse_code_units(size: usize, e: nom::Endianness)<&[u8], Vec<u16>>, peek!(count!(u16!(e), size)));
 
 // Docs: try_item
 named_args!(parse_try_item(e: nom::Endianness)<&[u8], RawTryItem>,
@@ -467,19 +572,19 @@ named_args!(parse_try_item(e: nom::Endianness)<&[u8], RawTryItem>,
 );
 
 // Docs: encoded_catch_handler_list
-named!(parse_encoded_catch_handler_list<&[u8], RawEncodedCatchHandlerList>,
+named_args!(pub parse_encoded_catch_handler_list<'a>(ctx: &'a ParseContext)<RawEncodedCatchHandlerList>,
     do_parse!(
         size: call!(parse_uleb128) >>
-        list: count!(call!(parse_encoded_catch_handler), size as usize)  >>
+        list: count!(call!(parse_encoded_catch_handler, ctx), ctx.count(size, 32)?)  >>
         (RawEncodedCatchHandlerList { size, list })
     )
 );
 
 // Docs: encoded_catch_handler
-named!(parse_encoded_catch_handler<&[u8], RawEncodedCatchHandler>,
+named_args!(parse_encoded_catch_handler<'a>(ctx: &'a ParseContext)<RawEncodedCatchHandler>,
     do_parse!(
         size: call!(parse_sleb128) >>
-        handlers: count!(call!(parse_encoded_type_addr_pair), size.abs() as usize) >>
+        handlers: count!(call!(parse_encoded_type_addr_pair), ctx.count(size.unsigned_abs(), 8)?) >>
         catch_all_addr: cond!(size <= 0, call!(parse_uleb128)) >>
         (RawEncodedCatchHandler { size, handlers, catch_all_addr })
     )
@@ -495,7 +600,7 @@ named!(parse_encoded_type_addr_pair<&[u8], RawEncodedTypeAddrPair>,
 );
 
 // Docs: string_data
-named!(parse_string_data_item<&[u8], RawStringData>,
+named!(pub parse_string_data_item<&[u8], RawStringData>,
     peek!(
         do_parse!(
             utf16_size: call!(parse_uleb128)                    >>
@@ -506,26 +611,26 @@ named!(parse_string_data_item<&[u8], RawStringData>,
 );
 
 // Docs: annotation_item
-fn parse_annotation_item<'a>(data: &'a[u8], fd: &DexFileData) -> nom::IResult<&'a[u8], AnnotationItem> {
+fn parse_annotation_item<'a>(data: &'a[u8], fd: &DexFileData, ctx: &ParseContext) -> nom::IResult<&'a[u8], AnnotationItem> {
     peek!(data,
         do_parse!(
             visibility: map_res!(call!(take_one), Visibility::parse)    >>
-            encoded: call!(encoded_value::parse_encoded_annotation_item, fd)    >>
+            encoded: call!(encoded_value::parse_encoded_annotation_item, fd, ctx)    >>
             (AnnotationItem { visibility, type_: encoded.type_, annotations: encoded.values })
         )
     )
 }
 
 // Docs: annotation_directory_item
-named_args!(parse_annotations_directory_item(e:nom::Endianness)<&[u8], RawAnnotations>,
+named_args!(pub parse_annotations_directory_item<'a>(e: nom::Endianness, ctx: &'a ParseContext)<RawAnnotations>,
     peek!(do_parse!(
         class_annotations_off: u32!(e)                                                          >>
         fld_size: u32!(e)                                                                    >>
         mtd_size: u32!(e)                                                         >>
         prm_size: u32!(e)                                                      >>
-        fld_annot: cond!(fld_size > 0, count!(apply!(parse_field_annotation_item, e), fld_size as usize)) >>
-        mtd_annot: cond!(mtd_size > 0, count!(apply!(parse_method_annotation_item, e), mtd_size as usize)) >>
-        prm_annot: cond!(prm_size > 0, count!(apply!(parse_parameter_annotation_item, e), prm_size as usize)) >>
+        fld_annot: cond!(fld_size > 0, count!(apply!(parse_field_annotation_item, e), ctx.count(fld_size, 8)?)) >>
+        mtd_annot: cond!(mtd_size > 0, count!(apply!(parse_method_annotation_item, e), ctx.count(mtd_size, 8)?)) >>
+        prm_annot: cond!(prm_size > 0, count!(apply!(parse_parameter_annotation_item, e), ctx.count(prm_size, 8)?)) >>
         (RawAnnotations { class_annotations_off, fld_annot, mtd_annot, prm_annot })
     ))
 );
@@ -558,41 +663,41 @@ named_args!(parse_parameter_annotation_item(e: nom::Endianness)<&[u8], RawParame
 );
 
 // Docs: annotation_set_ref_list
-named_args!(parse_annotation_set_ref_list(e: nom::Endianness)<&[u8], RawAnnotationSetRefList>,
+named_args!(pub parse_annotation_set_ref_list<'a>(e: nom::Endianness, ctx: &'a ParseContext)<RawAnnotationSetRefList>,
     peek!(
         do_parse!(
             size: u32!(e)   >>
             // Docs: annotation_set_ref_item
-            entries: count!(u32!(e), size as usize)     >>
+            entries: count!(u32!(e), ctx.count(size, 4)?)     >>
             (RawAnnotationSetRefList { size, entries })
         )
     )
 );
 
 // Docs: annotation_set_item
-named_args!(parse_annotation_set_item(e: nom::Endianness)<&[u8], RawAnnotationSetItem>,
+named_args!(pub parse_annotation_set_item<'a>(e: nom::Endianness, ctx: &'a ParseContext)<RawAnnotationSetItem>,
     peek!(
         do_parse!(
             size: u32!(e)                               >>
             // Docs: annotation_offset_item
-            entries: count!(u32!(e), size as usize)     >>
+            entries: count!(u32!(e), ctx.count(size, 4)?)     >>
             (RawAnnotationSetItem { size, entries })
         )
     )
 );
 
 // Docs: class_data_item
-named!(parse_class_data_item<&[u8], RawClassDataItem>,
+named_args!(pub parse_class_data_item<'a>(ctx: &'a ParseContext)<RawClassDataItem>,
     peek!(
         do_parse!(
             static_fields_size: call!(parse_uleb128)    >>
             instance_fields_size: call!(parse_uleb128)    >>
             direct_methods_size: call!(parse_uleb128)    >>
             virtual_methods_size: call!(parse_uleb128)    >>
-            static_fields: count!(parse_encoded_field, static_fields_size as usize)    >>
-            instance_fields: count!(parse_encoded_field, instance_fields_size as usize)  >>
-            direct_methods: count!(parse_encoded_method, direct_methods_size as usize)    >>
-            virtual_methods: count!(parse_encoded_method, virtual_methods_size as usize) >>
+            static_fields: count!(parse_encoded_field, ctx.count(static_fields_size, 8)?)    >>
+            instance_fields: count!(parse_encoded_field, ctx.count(instance_fields_size, 8)?)  >>
+            direct_methods: count!(parse_encoded_method, ctx.count(direct_methods_size, 12)?)    >>
+            virtual_methods: count!(parse_encoded_method, ctx.count(virtual_methods_size, 12)?) >>
             (RawClassDataItem { static_fields_size, instance_fields_size, direct_methods_size,
             virtual_methods_size, direct_methods, instance_fields, static_fields, virtual_methods })
         )
@@ -618,24 +723,6 @@ named!(parse_encoded_method<&[u8], RawEncodedMethod>,
     )
 );
 
-impl DebugItemBytecodes {
-    pub fn parse(value: u8) -> Self {
-        match value {
-            0x00 => DebugItemBytecodes::DBG_END_SEQUENCE,
-            0x01 => DebugItemBytecodes::DBG_ADVANCE_PC,
-            0x02 => DebugItemBytecodes::DBG_ADVANCE_LINE,
-            0x03 => DebugItemBytecodes::DBG_START_LOCAL,
-            0x04 => DebugItemBytecodes::DBG_START_LOCAL_EXTENDED,
-            0x05 => DebugItemBytecodes::DBG_END_LOCAL,
-            0x06 => DebugItemBytecodes::DBG_RESTART_LOCAL,
-            0x07 => DebugItemBytecodes::DBG_SET_PROLOGUE_END,
-            0x08 => DebugItemBytecodes::DBG_SET_EPILOGUE_BEGIN,
-            0x09 => DebugItemBytecodes::DBG_SET_FILE,
-            _ => DebugItemBytecodes::SPECIAL_OPCODE(value)
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -685,7 +772,7 @@ mod tests {
         for d in &[0_u32, 1_u32, 1_u32, 1_u32, 1_u32, 2_u32, 1_u32, 2_u32, 1_u32, 2_u32] {
             writer.write_u32::<LittleEndian>(*d).unwrap();
         }
-        let res = parse_annotations_directory_item(&writer, e).unwrap();
+        let res = parse_annotations_directory_item(&writer, e, &ParseContext::default()).unwrap();
 
         // peek!() should not consume input
         assert_eq!(res.0.len(), writer.len());
@@ -725,7 +812,7 @@ mod tests {
         leb128::write::unsigned(&mut writer, 0b1011).unwrap();
         leb128::write::unsigned(&mut writer, 3).unwrap();
 
-        let res = parse_class_data_item(&writer).unwrap();
+        let res = parse_class_data_item(&writer, &ParseContext::default()).unwrap();
 
         assert_eq!(res.0.len(), writer.len());
 
@@ -765,7 +852,7 @@ mod tests {
         writer.write_u32::<LittleEndian>(3).unwrap();
         writer.write_u32::<LittleEndian>(4).unwrap();
 
-        let res = parse_annotation_set_ref_list(&writer, e).unwrap();
+        let res = parse_annotation_set_ref_list(&writer, e, &ParseContext::default()).unwrap();
 
         // ensure data was not consumed
         assert_eq!(res.0.len(), writer.len());
@@ -784,7 +871,7 @@ mod tests {
         writer.write_u32::<LittleEndian>(3).unwrap();
         writer.write_u32::<LittleEndian>(4).unwrap();
 
-        let res = parse_annotation_set_item(&writer, e).unwrap();
+        let res = parse_annotation_set_item(&writer, e, &ParseContext::default()).unwrap();
 
         // ensure data was not consumed
         assert_eq!(res.0.len(), writer.len());
@@ -801,7 +888,7 @@ mod tests {
         for d in &[0_u32, 0_u32, 0_u32, 0_u32] {
             writer.write_u32::<LittleEndian>(*d).unwrap();
         }
-        let res = parse_annotations_directory_item(&writer, e).unwrap();
+        let res = parse_annotations_directory_item(&writer, e, &ParseContext::default()).unwrap();
 
         // peek!() should not consume input
         assert_eq!(res.0.len(), writer.len());
@@ -855,7 +942,7 @@ mod tests {
 
         let fd = generate_file_data();
 
-        let res = parse_annotations(&data, &fd, 0, DATA_OFFSET, e).unwrap();
+        let res = parse_annotations(&data, &fd, 0, DATA_OFFSET, e, &ParseContext::default()).unwrap();
 
         let expect_annotation = AnnotationItem {
             visibility: Visibility::BUILD,
@@ -893,7 +980,7 @@ mod tests {
 
         let fd = generate_file_data();
 
-        let res = transform_field_annotations(&data, rfas, &fd, DATA_OFFSET, e).unwrap();
+        let res = transform_field_annotations(&data, rfas, &fd, DATA_OFFSET, e, &ParseContext::default()).unwrap();
 
         // ensure no data was consumed
         assert_eq!(res.0.len(), data.len());
@@ -956,12 +1043,20 @@ mod tests {
             }))
         }
 
+        let method_handles = methods.iter()
+            .map(|method| Rc::new(MethodHandle {
+                type_: MethodHandleType::INVOKE_STATIC,
+                member: MethodHandleMember::Method(method.clone())
+            })).collect();
+
         DexFileData {
             string_data,
             type_identifiers,
             prototypes,
             fields,
-            methods
+            methods,
+            method_handles,
+            call_sites: vec!()
         }
     }
 
@@ -975,7 +1070,7 @@ mod tests {
         let mut annot_2 = generate_annotation_item_data();
 
         // write the first offset (data length), including the extra space for these offset values
-        let first_offset = data.len() as u32 + (2 as usize * ::std::mem::size_of::<u32>()) as u32;
+        let first_offset = data.len() as u32 + (2_usize * ::std::mem::size_of::<u32>()) as u32;
 
         data.write_u32::<LittleEndian>(first_offset).unwrap();
         // write the second offset (data length + item_1 length)
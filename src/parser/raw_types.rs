@@ -1,4 +1,5 @@
 use super::{Uleb128, Sleb128};
+use crate::result_types::Region;
 
 // raw type for the first pass over the dex file
 #[derive(Debug, PartialEq)]
@@ -25,7 +26,8 @@ pub struct RawDexFile {
     // Docs: data
     pub data: Vec<u8>,
     // Docs: link_data
-    pub link_data: Option<Vec<u8>>
+    // along with any other bytes not covered by the map list
+    pub regions: Vec<Region>
 }
 
 #[derive(Debug, PartialEq)]
@@ -301,8 +303,7 @@ pub struct RawEncodedTypeAddrPair {
 pub struct RawDebugInfoItem {
     pub line_start: Uleb128,
     pub parameters_size: Uleb128,
-    pub parameter_names: Vec<Sleb128>,
-    pub bytecode: Vec<u8>
+    pub parameter_names: Vec<Sleb128>
 }
 
 //noinspection RsEnumVariantNaming
@@ -328,5 +329,8 @@ pub enum MapListItemType {
     DEBUG_INFO_ITEM,
     ANNOTATION_ITEM,
     ENCODED_ARRAY_ITEM,
-    ANNOTATIONS_DIRECTORY_ITEM
+    ANNOTATIONS_DIRECTORY_ITEM,
+    HIDDENAPI_CLASS_DATA_ITEM,
+    // a type code this parser doesn't know, whose section is left alone
+    Unknown(u16)
 }
\ No newline at end of file
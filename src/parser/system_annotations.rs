@@ -0,0 +1,246 @@
+// Decodes the dalvik.annotation system annotations of a class into its ClassSystemAnnotations and the
+// MethodSystemAnnotations of its methods
+//
+// Docs: dalvik-annotations. An annotation whose elements don't have the expected types is left undecoded rather
+// than failing the parse; it is still there among the class's annotations.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::result_types::*;
+use super::AnnotationType;
+
+pub fn decode(class: &mut ClassDefinition) {
+    let ClassDefinition { ref annotations, ref mut class_data, ref mut system_annotations, .. } = *class;
+    let annotations = match annotations {
+        Some(annotations) => annotations,
+        None => return
+    };
+
+    let mut system = ClassSystemAnnotations::default();
+    let mut defaults: &[AnnotationElement] = &[];
+    for annotation in &annotations.class_annotations {
+        let elements = &annotation.elements;
+        match annotation.type_.as_str() {
+            "Ldalvik/annotation/Signature;" => system.signature = signature(elements),
+            "Ldalvik/annotation/InnerClass;" => system.inner_class = inner_class(elements),
+            "Ldalvik/annotation/EnclosingClass;" => if let Some(EncodedValue::Type(t)) = value(elements, "value") {
+                system.enclosing_class = Some(t.clone());
+            },
+            "Ldalvik/annotation/EnclosingMethod;" => if let Some(EncodedValue::Method(m)) = value(elements, "value") {
+                system.enclosing_method = Some(m.clone());
+            },
+            "Ldalvik/annotation/MemberClasses;" => system.member_classes = types(elements),
+            "Ldalvik/annotation/SourceDebugExtension;" => if let Some(EncodedValue::String(s)) = value(elements, "value") {
+                system.source_debug_extension = Some(s.clone());
+            },
+            // the defaults of an annotation type's elements, as an instance of the annotation type itself
+            "Ldalvik/annotation/AnnotationDefault;" => if let Some(EncodedValue::Annotation(a)) = value(elements, "value") {
+                defaults = &a.values;
+            },
+            _ => {}
+        }
+    }
+    *system_annotations = system;
+
+    let class_data = match class_data {
+        Some(class_data) => class_data,
+        None => return
+    };
+    let method_annotations = annotations.method_annotations.iter()
+        .map(|ma| (&*ma.method, &ma.annotations))
+        .collect::<HashMap<_, _>>();
+    for method in class_data.direct_methods.iter_mut().chain(class_data.virtual_methods.iter_mut()) {
+        let mut system = MethodSystemAnnotations::default();
+        for annotation in method_annotations.get(&*method.method).map(|a| a.as_slice()).unwrap_or(&[]) {
+            let elements = &annotation.annotations;
+            match annotation.type_.as_str() {
+                "Ldalvik/annotation/Signature;" => system.signature = signature(elements),
+                "Ldalvik/annotation/Throws;" => system.throws = types(elements),
+                "Ldalvik/annotation/MethodParameters;" => system.parameters = parameters(elements),
+                _ => {}
+            }
+        }
+        system.annotation_default = defaults.iter()
+            .find(|d| d.name == method.method.name)
+            .map(|d| d.value.clone());
+        method.system_annotations = system;
+    }
+}
+
+fn value<'a>(elements: &'a [AnnotationElement], name: &str) -> Option<&'a EncodedValue> {
+    elements.iter().find(|e| *e.name == name).map(|e| &e.value)
+}
+
+fn array<'a>(elements: &'a [AnnotationElement], name: &str) -> &'a [EncodedValue] {
+    match value(elements, name) {
+        Some(EncodedValue::Array(values)) => values,
+        _ => &[]
+    }
+}
+
+// The signature is split into an array of strings, to share common parts in the string pool
+fn signature(elements: &[AnnotationElement]) -> Option<String> {
+    match value(elements, "value")? {
+        EncodedValue::Array(parts) => parts.iter().map(|part| match part {
+            EncodedValue::String(s) => Some(s.as_str()),
+            _ => None
+        }).collect::<Option<Vec<_>>>().map(|parts| parts.concat()),
+        _ => None
+    }
+}
+
+fn types(elements: &[AnnotationElement]) -> Vec<Rc<String>> {
+    array(elements, "value").iter().filter_map(|v| match v {
+        EncodedValue::Type(t) => Some(t.clone()),
+        _ => None
+    }).collect()
+}
+
+fn inner_class(elements: &[AnnotationElement]) -> Option<InnerClass> {
+    let name = match value(elements, "name")? {
+        EncodedValue::String(s) => Some(s.clone()),
+        EncodedValue::Null => None,
+        _ => return None
+    };
+    match value(elements, "accessFlags")? {
+        EncodedValue::Int(flags) => Some(InnerClass {
+            name,
+            access_flags: AccessFlag::parse(*flags as u32, AnnotationType::Class)
+        }),
+        _ => None
+    }
+}
+
+// Names and flags are in parallel arrays, one entry per parameter
+fn parameters(elements: &[AnnotationElement]) -> Vec<MethodParameter> {
+    let names = array(elements, "names");
+    let flags = array(elements, "accessFlags");
+    if names.len() != flags.len() {
+        return vec!();
+    }
+    names.iter().zip(flags).map(|(name, flags)| MethodParameter {
+        name: match name {
+            EncodedValue::String(s) => Some(s.clone()),
+            _ => None
+        },
+        access_flags: match flags {
+            EncodedValue::Int(flags) => AccessFlag::parse(*flags as u32, AnnotationType::Class),
+            _ => vec!()
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+    use crate::options::ParseOptions;
+
+    fn annotation(b: &mut DexBuilder, type_: &str, elements: Vec<(&str, EncodedValue)>) -> AnnotationItem {
+        AnnotationItem {
+            visibility: Visibility::SYSTEM,
+            type_: b.type_(type_),
+            annotations: elements.into_iter().map(|(name, value)| AnnotationElement { name: b.string(name), value }).collect()
+        }
+    }
+
+    fn class_annotation(b: &mut DexBuilder, type_: &str, elements: Vec<(&str, EncodedValue)>) -> ClassAnnotation {
+        let item = annotation(b, type_, elements);
+        ClassAnnotation { visibility: item.visibility, type_: item.type_, elements: item.annotations }
+    }
+
+    fn string(b: &mut DexBuilder, s: &str) -> EncodedValue {
+        EncodedValue::String(b.string(s))
+    }
+
+    // an annotation type LOuter$Inner; declared in LOuter;->run(), with an element m that throws and has named parameters
+    #[test]
+    fn test_decode() {
+        let mut b = DexBuilder::new(35);
+        let m = b.method("LOuter$Inner;", "m", "I", &["I", "Ljava/lang/String;"]);
+        let run = b.method("LOuter;", "run", "V", &[]);
+
+        let signature = EncodedValue::Array(vec!(string(&mut b, "<T:Ljava/lang/Object;>"), string(&mut b, "Ljava/lang/Object;")));
+        let name = string(&mut b, "Inner");
+        let member = EncodedValue::Array(vec!(EncodedValue::Type(b.type_("LOuter$Inner$1;"))));
+        let smap = string(&mut b, "SMAP");
+        let default = EncodedValue::Annotation(EncodedAnnotationItem {
+            type_: b.type_("LOuter$Inner;"),
+            values: vec!(AnnotationElement { name: b.string("m"), value: EncodedValue::Int(3) })
+        });
+        let class_annotations = vec!(
+            class_annotation(&mut b, "Ldalvik/annotation/Signature;", vec!(("value", signature))),
+            class_annotation(&mut b, "Ldalvik/annotation/InnerClass;", vec!(("accessFlags", EncodedValue::Int(0x19)), ("name", name))),
+            class_annotation(&mut b, "Ldalvik/annotation/EnclosingMethod;", vec!(("value", EncodedValue::Method(run.clone())))),
+            class_annotation(&mut b, "Ldalvik/annotation/MemberClasses;", vec!(("value", member))),
+            class_annotation(&mut b, "Ldalvik/annotation/SourceDebugExtension;", vec!(("value", smap))),
+            class_annotation(&mut b, "Ldalvik/annotation/AnnotationDefault;", vec!(("value", default)))
+        );
+
+        let throws = EncodedValue::Array(vec!(EncodedValue::Type(b.type_("Ljava/io/IOException;"))));
+        let names = EncodedValue::Array(vec!(string(&mut b, "count"), EncodedValue::Null));
+        let flags = EncodedValue::Array(vec!(EncodedValue::Int(0x10), EncodedValue::Int(0x1000)));
+        let method_annotations = vec!(MethodAnnotation {
+            method: m.clone(),
+            annotations: vec!(
+                annotation(&mut b, "Ldalvik/annotation/Throws;", vec!(("value", throws))),
+                annotation(&mut b, "Ldalvik/annotation/MethodParameters;", vec!(("names", names), ("accessFlags", flags)))
+            )
+        });
+
+        let class = ClassDefinition {
+            class_type: b.type_("LOuter$Inner;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_INTERFACE, AccessFlag::ACC_ABSTRACT, AccessFlag::ACC_ANNOTATION),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: Some(Annotations {
+                class_annotations,
+                field_annotations: vec!(),
+                method_annotations,
+                parameter_annotations: vec!()
+            }),
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(),
+                virtual_methods: vec!(EncodedMethod {
+                    method: m,
+                    access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_ABSTRACT),
+                    code: None,
+                    system_annotations: Default::default()
+                })
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        b.class(class);
+        let built = b.build().unwrap();
+
+        let class = &built.classes[0];
+        let system = &class.system_annotations;
+        assert_eq!(system.signature.as_ref().unwrap(), "<T:Ljava/lang/Object;>Ljava/lang/Object;");
+        let inner_class = system.inner_class.as_ref().unwrap();
+        assert_eq!(**inner_class.name.as_ref().unwrap(), "Inner");
+        assert_eq!(inner_class.access_flags, vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_STATIC, AccessFlag::ACC_FINAL));
+        assert_eq!(system.enclosing_class, None);
+        assert_eq!(system.enclosing_method, Some(run));
+        assert_eq!(*system.member_classes[0], "LOuter$Inner$1;");
+        assert_eq!(**system.source_debug_extension.as_ref().unwrap(), "SMAP");
+
+        let method = &class.class_data.as_ref().unwrap().virtual_methods[0].system_annotations;
+        assert_eq!(*method.throws[0], "Ljava/io/IOException;");
+        assert_eq!(method.parameters.len(), 2);
+        assert_eq!(**method.parameters[0].name.as_ref().unwrap(), "count");
+        assert_eq!(method.parameters[0].access_flags, vec!(AccessFlag::ACC_FINAL));
+        assert_eq!(method.parameters[1].name, None);
+        assert_eq!(method.parameters[1].access_flags, vec!(AccessFlag::ACC_SYNTHETIC));
+        assert_eq!(method.annotation_default, Some(EncodedValue::Int(3)));
+
+        // parsing the written file decodes the same
+        let bytes = crate::writer::write(&built).unwrap();
+        let parsed = crate::parser::parse(&bytes, ParseOptions::default()).unwrap();
+        assert_eq!(parsed.classes, built.classes);
+    }
+}
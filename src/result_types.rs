@@ -1,13 +1,17 @@
 use std::rc::Rc;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use crate::serialization::{endianness, float, pooled};
+
 #[derive(Debug, PartialEq)]
 pub struct DexFile {
     pub header: super::Header,
     pub file_data: DexFileData,
-    pub classes: Vec<ClassDefinition>
-    /* TODO: parse call site items
-        pub call_site_items: Vec<CallSiteItem>
-    */
+    pub classes: Vec<ClassDefinition>,
+    // the link section, plus any bytes not covered by the map list
+    pub regions: Vec<Region>
 }
 
 #[derive(Debug, PartialEq)]
@@ -16,73 +20,197 @@ pub struct DexFileData {
     pub type_identifiers: Vec<Rc<String>>,
     pub prototypes: Vec<Rc<Prototype>>,
     pub fields: Vec<Rc<Field>>,
-    pub methods: Vec<Rc<Method>>
+    pub methods: Vec<Rc<Method>>,
+    // new in version 038
+    pub method_handles: Vec<Rc<MethodHandle>>,
+    pub call_sites: Vec<Rc<CallSiteItem>>
+}
+
+// A named region of the file lying outside of the sections described by the map list
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Region {
+    pub kind: RegionKind,
+    // offset from the start of the file
+    pub offset: u32,
+    pub data: Vec<u8>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RegionKind {
+    // Docs: link_data
+    Link,
+    // bytes before the end of the file that no map_list item accounts for
+    Unmapped,
+    // bytes following the file size declared in the header
+    Trailing
 }
 
+// Docs: call_site_item
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CallSiteItem {
-    pub method_handle: Rc<Method>,
+    // the bootstrap linker method
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub method_handle: Rc<MethodHandle>,
+    // the name of the method the linker will resolve
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub method_name: Rc<String>,
+    // the type of the method the linker will resolve
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub method_type: Rc<Prototype>,
+    // any extra arguments passed to the linker
     pub constant_values: Vec<EncodedValue>
 }
 
+// Docs: method_handle_item
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MethodHandle {
+    pub type_: MethodHandleType,
+    pub member: MethodHandleMember
+}
+
+//noinspection RsEnumVariantNaming
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MethodHandleType {
+    STATIC_PUT,
+    STATIC_GET,
+    INSTANCE_PUT,
+    INSTANCE_GET,
+    INVOKE_STATIC,
+    INVOKE_INSTANCE,
+    INVOKE_CONSTRUCTOR,
+    INVOKE_DIRECT,
+    INVOKE_INTERFACE
+}
+
+// The accessor types refer to a field, the invoke types to a method
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MethodHandleMember {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    Field(Rc<Field>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    Method(Rc<Method>)
+}
+
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Header {
     pub version: i32,
     pub checksum: String,
     pub signature: [u8; 20],
     pub file_size: u32,
+    #[cfg_attr(feature = "serde", serde(with = "endianness"))]
     pub endianness: nom::Endianness
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Prototype {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub shorty: Rc<String>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub return_type: Rc<String>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub parameters: Vec<Rc<String>>
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Field {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub definer: Rc<String>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub type_: Rc<String>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub name: Rc<String>
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Method {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub definer: Rc<String>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub prototype: Rc<Prototype>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub name: Rc<String>
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ClassAnnotation {
     pub visibility: Visibility,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub type_: Rc<String>,
     pub elements: Vec<AnnotationElement>
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AnnotationElement {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub name: Rc<String>,
     pub value: EncodedValue
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ClassDefinition {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub class_type: Rc<String>,
     pub access_flags: Vec<AccessFlag>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub superclass: Option<Rc<String>>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub interfaces: Vec<Rc<String>>,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub source_file_name: Option<Rc<String>>,
     pub annotations: Option<Annotations>,
     pub class_data: Option<ClassData>,
-    pub static_values: Vec<EncodedValue>
+    pub static_values: Vec<EncodedValue>,
+    // decoded from the dalvik.annotation system annotations in `annotations`, which are what gets written out
+    pub system_annotations: ClassSystemAnnotations
+}
+
+#[derive(Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClassSystemAnnotations {
+    // the generic signature (dalvik.annotation.Signature), e.g. `<T:Ljava/lang/Object;>Ljava/lang/Object;`
+    pub signature: Option<String>,
+    // present for member, local and anonymous classes (dalvik.annotation.InnerClass)
+    pub inner_class: Option<InnerClass>,
+    // the class a member class is declared in (dalvik.annotation.EnclosingClass)
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub enclosing_class: Option<Rc<String>>,
+    // the method a local or anonymous class is declared in (dalvik.annotation.EnclosingMethod)
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub enclosing_method: Option<Rc<Method>>,
+    // the member classes declared in this one (dalvik.annotation.MemberClasses)
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub member_classes: Vec<Rc<String>>,
+    // the JSR-45 source map (dalvik.annotation.SourceDebugExtension)
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub source_debug_extension: Option<Rc<String>>
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InnerClass {
+    // the simple name, None for an anonymous class
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub name: Option<Rc<String>>,
+    // the flags the class was declared with in source, which can differ from those of the class definition
+    pub access_flags: Vec<AccessFlag>
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Annotations {
     pub class_annotations: Vec<ClassAnnotation>,
     pub field_annotations: Vec<FieldAnnotation>,
@@ -91,6 +219,7 @@ pub struct Annotations {
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ClassData {
     pub static_fields: Vec<EncodedField>,
     pub instance_fields: Vec<EncodedField>,
@@ -99,44 +228,84 @@ pub struct ClassData {
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EncodedField {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub field: Rc<Field>,
     pub access_flags: Vec<AccessFlag>
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EncodedMethod {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub method: Rc<Method>,
     pub access_flags: Vec<AccessFlag>,
-    pub code: Option<Code>
+    pub code: Option<Code>,
+    // decoded from the system annotations of the method and the class declaring it
+    pub system_annotations: MethodSystemAnnotations
+}
+
+#[derive(Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MethodSystemAnnotations {
+    // the generic signature (dalvik.annotation.Signature), e.g. `<T:Ljava/lang/Object;>(TT;)V`
+    pub signature: Option<String>,
+    // the checked exceptions the method declares (dalvik.annotation.Throws)
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub throws: Vec<Rc<String>>,
+    // one per parameter if the method has a dalvik.annotation.MethodParameters annotation, otherwise empty
+    pub parameters: Vec<MethodParameter>,
+    // the default value of an annotation type's element (dalvik.annotation.AnnotationDefault on the class)
+    pub annotation_default: Option<EncodedValue>
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MethodParameter {
+    // None if the parameter is unnamed in the class file
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub name: Option<Rc<String>>,
+    // ACC_FINAL, ACC_SYNTHETIC and ACC_MANDATED, which is reported as UNUSED
+    pub access_flags: Vec<AccessFlag>
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MethodAnnotation {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub method: Rc<Method>,
     pub annotations: Vec<AnnotationItem>
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ParameterAnnotation {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub method: Rc<Method>,
-    pub annotations: Vec<AnnotationItem>
+    // one set of annotations per parameter, empty if that parameter has none
+    pub annotations: Vec<Vec<AnnotationItem>>
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FieldAnnotation {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub field_data: Rc<Field>,
     pub annotations: Vec<AnnotationItem>
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AnnotationItem {
     pub visibility: Visibility,
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub type_: Rc<String>,
     pub annotations: Vec<AnnotationElement>
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Visibility {
     BUILD,
     RUNTIME,
@@ -145,6 +314,7 @@ pub enum Visibility {
 
 // Docs: code_item
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Code {
     // number of registers used by this code
     pub registers_size: u16,
@@ -160,13 +330,18 @@ pub struct Code {
 
 // Docs: try_item
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TryItem {
-    pub code_units: Vec<u16>,
+    // start address of the block of code covered by this entry, in 16-bit code units from the start of insns
+    pub start_addr: u32,
+    // number of 16-bit code units covered by this entry
+    pub insn_count: u16,
     pub handler: EncodedCatchHandler
 }
 
 // Docs: encoded_catch_handler
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EncodedCatchHandler {
     pub handlers: Vec<EncodedTypeAddrPair>,
     // bytecode
@@ -175,9 +350,11 @@ pub struct EncodedCatchHandler {
 }
 
 // Docs: encoded_type_addr_pair
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EncodedTypeAddrPair {
     // index into type_ids list for the type of exception to catch
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub type_: Rc<String>,
     // bytecode address of associated exception handler
     pub addr: u32
@@ -185,27 +362,41 @@ pub struct EncodedTypeAddrPair {
 
 // Docs: debug_info_item
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DebugInfo {
     pub line_start: u32,
-    pub parameter_names: Vec<i32>,
+    // None for parameters without a name
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    pub parameter_names: Vec<Option<Rc<String>>>,
+    // the state machine bytecode, not including the DBG_END_SEQUENCE that ends it
     pub bytecode: Vec<DebugItemBytecodes>
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum EncodedValue {
     Byte(u8),
     Short(i16),
     Char(u16),
     Int(i32),
     Long(i64),
+    #[cfg_attr(feature = "serde", serde(with = "float"))]
     Float(f32),
+    #[cfg_attr(feature = "serde", serde(with = "float"))]
     Double(f64),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     MethodType(Rc<Prototype>),
-    MethodHandle(Rc<Method>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    MethodHandle(Rc<MethodHandle>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     String(Rc<String>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     Type(Rc<String>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     Field(Rc<Field>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     Method(Rc<Method>),
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     Enum(Rc<Field>),
     Array(Vec<EncodedValue>),
     Annotation(EncodedAnnotationItem),
@@ -214,31 +405,54 @@ pub enum EncodedValue {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EncodedAnnotationItem {
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
     pub type_: Rc<String>,
     pub values: Vec<AnnotationElement>
 }
 
 //noinspection RsEnumVariantNaming
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DebugItemBytecodes {
     DBG_END_SEQUENCE,
-    DBG_ADVANCE_PC,
-    DBG_ADVANCE_LINE,
-    DBG_START_LOCAL,
-    DBG_START_LOCAL_EXTENDED,
-    DBG_END_LOCAL,
-    DBG_RESTART_LOCAL,
+    // address register increment
+    DBG_ADVANCE_PC(u32),
+    // line register increment
+    DBG_ADVANCE_LINE(i32),
+    DBG_START_LOCAL {
+        register_num: u32,
+        #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+        name: Option<Rc<String>>,
+        #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+        type_: Option<Rc<String>>
+    },
+    DBG_START_LOCAL_EXTENDED {
+        register_num: u32,
+        #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+        name: Option<Rc<String>>,
+        #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+        type_: Option<Rc<String>>,
+        #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+        sig: Option<Rc<String>>
+    },
+    DBG_END_LOCAL(u32),
+    DBG_RESTART_LOCAL(u32),
     DBG_SET_PROLOGUE_END,
     DBG_SET_EPILOGUE_BEGIN,
-    DBG_SET_FILE,
+    // None if there is no source file name
+    #[cfg_attr(feature = "serde", serde(with = "pooled"))]
+    DBG_SET_FILE(Option<Rc<String>>),
+    // advances both the line and address registers, then emits a position entry
     SPECIAL_OPCODE(u8)
 }
 
 //noinspection RsEnumVariantNaming
 #[allow(non_camel_case_types)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AccessFlag {
     ACC_PUBLIC,
     ACC_PRIVATE,
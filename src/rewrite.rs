@@ -0,0 +1,932 @@
+// Changing a file and writing it back out
+//
+// A Rewriter holds the classes of a file for classes, fields and methods to be added, removed and renamed, and for
+// the rest of each class to be edited in place. Bytecode refers to the pools by index, so on the way in what each
+// instruction refers to is read out of the pools, and on the way out, once the pools have been gathered and sorted
+// again, the new indexes are written back into the instructions. A const-string whose string moves past index 0xFFFF
+// becomes const-string/jumbo, and the method is laid out again around it. Renames rewrite every reference to what
+// was renamed: descriptors, members, annotations, encoded values, catch handlers, debug info and bytecode. Strings
+// are left alone, so reflection and generic signatures still name the old types and members.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::assembler;
+use crate::builder::{self, utf16_cmp, DexBuilder};
+use crate::error::DexParserError;
+use crate::instructions::{self, Format, IndexKind, Opcode};
+use crate::parser;
+use crate::result_types::*;
+use crate::writer::{self, Indexes};
+
+pub struct Rewriter {
+    version: i32,
+    classes: Vec<ClassDefinition>,
+    // held by field while editing, since static_values goes by the position of each field once they are sorted
+    static_values: HashMap<Rc<Field>, EncodedValue>,
+    // what the indexes in the code of each method refer to
+    references: HashMap<Rc<Method>, Vec<CodeReference>>
+}
+
+// What an index operand in bytecode refers to
+#[derive(Debug, PartialEq, Clone)]
+pub struct CodeReference {
+    // of the instruction, in 16-bit code units from the start of insns
+    pub offset: u32,
+    // a prototype at invoke-polymorphic is its second index, anything else the instruction's only index
+    pub target: Reference
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Reference {
+    String(Rc<String>),
+    Type(Rc<String>),
+    Field(Rc<Field>),
+    Method(Rc<Method>),
+    Prototype(Rc<Prototype>),
+    CallSite(Rc<CallSiteItem>),
+    MethodHandle(Rc<MethodHandle>)
+}
+
+impl Reference {
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            Reference::String(_) => IndexKind::String,
+            Reference::Type(_) => IndexKind::Type,
+            Reference::Field(_) => IndexKind::Field,
+            Reference::Method(_) => IndexKind::Method,
+            Reference::Prototype(_) => IndexKind::Prototype,
+            Reference::CallSite(_) => IndexKind::CallSite,
+            Reference::MethodHandle(_) => IndexKind::MethodHandle
+        }
+    }
+}
+
+// What the indexes in some code refer to in the pools of the file it is from
+pub fn lift(file: &DexFile, code: &Code) -> Result<Vec<CodeReference>, DexParserError> {
+    let mut references = vec!();
+    for insn in instructions::decode_all(&code.insns)? {
+        if let Some(index) = insn.index {
+            let target = pool_entry(&file.file_data, insn.opcode.index_kind(), index, insn.offset)?;
+            references.push(CodeReference { offset: insn.offset, target });
+        }
+        if let Some(index) = insn.proto_index {
+            let target = pool_entry(&file.file_data, IndexKind::Prototype, index, insn.offset)?;
+            references.push(CodeReference { offset: insn.offset, target });
+        }
+    }
+    Ok(references)
+}
+
+fn pool_entry(fd: &DexFileData, kind: IndexKind, index: u32, offset: u32) -> Result<Reference, DexParserError> {
+    let i = index as usize;
+    let target = match kind {
+        IndexKind::String => fd.string_data.get(i).cloned().map(Reference::String),
+        IndexKind::Type => fd.type_identifiers.get(i).cloned().map(Reference::Type),
+        IndexKind::Field => fd.fields.get(i).cloned().map(Reference::Field),
+        IndexKind::Method => fd.methods.get(i).cloned().map(Reference::Method),
+        IndexKind::Prototype => fd.prototypes.get(i).cloned().map(Reference::Prototype),
+        IndexKind::CallSite => fd.call_sites.get(i).cloned().map(Reference::CallSite),
+        IndexKind::MethodHandle => fd.method_handles.get(i).cloned().map(Reference::MethodHandle),
+        IndexKind::None => None
+    };
+    target.ok_or_else(|| DexParserError::InvalidBytecode {
+        offset,
+        reason: format!("{:?} index {} is out of range", kind, index)
+    })
+}
+
+impl Rewriter {
+    pub fn new(file: DexFile) -> Result<Self, DexParserError> {
+        let mut static_values = HashMap::new();
+        let mut references = HashMap::new();
+        for class in &file.classes {
+            if let Some(ref data) = class.class_data {
+                for (field, value) in data.static_fields.iter().zip(&class.static_values) {
+                    static_values.insert(field.field.clone(), value.clone());
+                }
+                for method in data.direct_methods.iter().chain(data.virtual_methods.iter()) {
+                    if let Some(ref code) = method.code {
+                        references.insert(method.method.clone(), lift(&file, code)?);
+                    }
+                }
+            }
+        }
+
+        let version = file.header.version;
+        let mut classes = file.classes;
+        for class in &mut classes {
+            class.static_values.clear();
+        }
+        Ok(Rewriter { version, classes, static_values, references })
+    }
+
+    // Their static_values are empty while they are here, see static_value
+    pub fn classes(&self) -> &[ClassDefinition] {
+        &self.classes
+    }
+
+    pub fn class(&self, descriptor: &str) -> Option<&ClassDefinition> {
+        self.classes.iter().find(|class| *class.class_type == descriptor)
+    }
+
+    // For the access flags, supertypes, source file and annotations. Static values and code go through
+    // set_static_value and set_code, and the rename_* methods take care of everything referring to what they rename
+    pub fn class_mut(&mut self, descriptor: &str) -> Option<&mut ClassDefinition> {
+        self.classes.iter_mut().find(|class| *class.class_type == descriptor)
+    }
+
+    pub fn field_mut(&mut self, field: &Field) -> Option<&mut EncodedField> {
+        let data = self.class_mut(&field.definer)?.class_data.as_mut()?;
+        data.static_fields.iter_mut().chain(data.instance_fields.iter_mut()).find(|f| *f.field == *field)
+    }
+
+    pub fn method_mut(&mut self, method: &Method) -> Option<&mut EncodedMethod> {
+        let data = self.class_mut(&method.definer)?.class_data.as_mut()?;
+        data.direct_methods.iter_mut().chain(data.virtual_methods.iter_mut()).find(|m| *m.method == *method)
+    }
+
+    // The static values of the class go with its static fields in the order they are listed. Code in its methods
+    // can't refer to the pools, bodies that do are given with set_code afterwards
+    pub fn add_class(&mut self, mut class: ClassDefinition) -> Result<(), DexParserError> {
+        if self.class(&class.class_type).is_some() {
+            return Err(failed(format!("class {} already exists", class.class_type)));
+        }
+        if let Some(ref data) = class.class_data {
+            for (field, value) in data.static_fields.iter().zip(class.static_values.drain(..)) {
+                self.static_values.insert(field.field.clone(), value);
+            }
+        }
+        self.classes.push(class);
+        Ok(())
+    }
+
+    pub fn remove_class(&mut self, descriptor: &str) -> Option<ClassDefinition> {
+        let position = self.classes.iter().position(|class| *class.class_type == descriptor)?;
+        let class = self.classes.remove(position);
+        if let Some(ref data) = class.class_data {
+            for field in &data.static_fields {
+                self.static_values.remove(&field.field);
+            }
+            for method in data.direct_methods.iter().chain(data.virtual_methods.iter()) {
+                self.references.remove(&method.method);
+            }
+        }
+        Some(class)
+    }
+
+    // Goes in the class the field names as its definer, among the static fields if it is ACC_STATIC
+    pub fn add_field(&mut self, field: EncodedField, value: Option<EncodedValue>) -> Result<(), DexParserError> {
+        if self.field_mut(&field.field).is_some() {
+            return Err(failed(format!("field {:?} already exists", field.field)));
+        }
+        let is_static = field.access_flags.contains(&AccessFlag::ACC_STATIC);
+        if value.is_some() && !is_static {
+            return Err(failed(format!("field {:?} isn't static, so can't have a static value", field.field)));
+        }
+        let key = field.field.clone();
+        let data = self.class_data_mut(&field.field.definer)?;
+        if is_static {
+            data.static_fields.push(field);
+        } else {
+            data.instance_fields.push(field);
+        }
+        if let Some(value) = value {
+            self.static_values.insert(key, value);
+        }
+        Ok(())
+    }
+
+    pub fn remove_field(&mut self, field: &Field) -> Option<EncodedField> {
+        let class = self.class_mut(&field.definer)?;
+        if let Some(ref mut annotations) = class.annotations {
+            annotations.field_annotations.retain(|fa| *fa.field_data != *field);
+        }
+        let data = class.class_data.as_mut()?;
+        let removed = take(&mut data.static_fields, |f| *f.field == *field)
+            .or_else(|| take(&mut data.instance_fields, |f| *f.field == *field));
+        self.static_values.remove(field);
+        removed
+    }
+
+    // Goes in the class the method names as its definer, among the direct methods if it is static, private or a
+    // constructor. The references are those of its code
+    pub fn add_method(&mut self, method: EncodedMethod, references: Vec<CodeReference>) -> Result<(), DexParserError> {
+        if self.method_mut(&method.method).is_some() {
+            return Err(failed(format!("method {:?} already exists", method.method)));
+        }
+        let key = method.method.clone();
+        let data = self.class_data_mut(&method.method.definer)?;
+        let direct = method.access_flags.iter()
+            .any(|flag| matches!(flag, AccessFlag::ACC_STATIC | AccessFlag::ACC_PRIVATE | AccessFlag::ACC_CONSTRUCTOR));
+        if direct {
+            data.direct_methods.push(method);
+        } else {
+            data.virtual_methods.push(method);
+        }
+        self.references.insert(key, references);
+        Ok(())
+    }
+
+    pub fn remove_method(&mut self, method: &Method) -> Option<EncodedMethod> {
+        let class = self.class_mut(&method.definer)?;
+        if let Some(ref mut annotations) = class.annotations {
+            annotations.method_annotations.retain(|ma| *ma.method != *method);
+            annotations.parameter_annotations.retain(|pa| *pa.method != *method);
+        }
+        let data = class.class_data.as_mut()?;
+        let removed = take(&mut data.direct_methods, |m| *m.method == *method)
+            .or_else(|| take(&mut data.virtual_methods, |m| *m.method == *method));
+        self.references.remove(method);
+        removed
+    }
+
+    pub fn static_value(&self, field: &Field) -> Option<&EncodedValue> {
+        self.static_values.get(field)
+    }
+
+    // None leaves the field to start out as zero, false or null
+    pub fn set_static_value(&mut self, field: &Field, value: Option<EncodedValue>) -> Result<(), DexParserError> {
+        let encoded = self.field_mut(field).ok_or_else(|| failed(format!("no field {:?}", field)))?;
+        if !encoded.access_flags.contains(&AccessFlag::ACC_STATIC) {
+            return Err(failed(format!("field {:?} isn't static, so can't have a static value", field)));
+        }
+        let key = encoded.field.clone();
+        match value {
+            Some(value) => self.static_values.insert(key, value),
+            None => self.static_values.remove(&key)
+        };
+        Ok(())
+    }
+
+    pub fn code_references(&self, method: &Method) -> Option<&[CodeReference]> {
+        self.references.get(method).map(Vec::as_slice)
+    }
+
+    // Replaces the body of a method, None making it abstract or native
+    pub fn set_code(&mut self, method: &Method, code: Option<Code>, references: Vec<CodeReference>)
+                    -> Result<(), DexParserError> {
+        let encoded = self.method_mut(method).ok_or_else(|| failed(format!("no method {:?}", method)))?;
+        encoded.code = code;
+        let key = encoded.method.clone();
+        self.references.insert(key, references);
+        Ok(())
+    }
+
+    // Renames a type wherever it is used, arrays of it included
+    pub fn rename_class(&mut self, descriptor: &str, new_descriptor: &str) -> Result<(), DexParserError> {
+        if self.class(new_descriptor).is_some() {
+            return Err(failed(format!("class {} already exists", new_descriptor)));
+        }
+        let mut renames = Renames::default();
+        renames.types.insert(descriptor.to_string(), new_descriptor.to_string());
+        self.apply(&renames);
+        Ok(())
+    }
+
+    pub fn rename_field(&mut self, field: &Field, name: &str) -> Result<(), DexParserError> {
+        let renamed = Field {
+            definer: field.definer.clone(),
+            type_: field.type_.clone(),
+            name: Rc::new(name.to_string())
+        };
+        if self.field_mut(&renamed).is_some() {
+            return Err(failed(format!("field {:?} already exists", renamed)));
+        }
+        let original = Field { definer: field.definer.clone(), type_: field.type_.clone(), name: field.name.clone() };
+        let mut renames = Renames::default();
+        renames.fields.insert(Rc::new(original), renamed.name);
+        self.apply(&renames);
+        Ok(())
+    }
+
+    // Only this method is renamed, not those it overrides or that override it
+    pub fn rename_method(&mut self, method: &Method, name: &str) -> Result<(), DexParserError> {
+        let renamed = Method {
+            definer: method.definer.clone(),
+            prototype: method.prototype.clone(),
+            name: Rc::new(name.to_string())
+        };
+        if self.method_mut(&renamed).is_some() {
+            return Err(failed(format!("method {:?} already exists", renamed)));
+        }
+        let original = Method { definer: method.definer.clone(), prototype: method.prototype.clone(),
+                                name: method.name.clone() };
+        let mut renames = Renames::default();
+        renames.methods.insert(Rc::new(original), renamed.name);
+        self.apply(&renames);
+        Ok(())
+    }
+
+    // Takes over the classes of another file, failing without changing anything if both define the same class
+    pub fn merge(&mut self, other: Rewriter) -> Result<(), DexParserError> {
+        if let Some(class) = other.classes.iter().find(|class| self.class(&class.class_type).is_some()) {
+            return Err(failed(format!("class {} is defined in both files", class.class_type)));
+        }
+        self.version = self.version.max(other.version);
+        self.classes.extend(other.classes);
+        self.static_values.extend(other.static_values);
+        self.references.extend(other.references);
+        Ok(())
+    }
+
+    // Moves the named classes, with their static values and code, into a new Rewriter of the same version
+    pub fn split_off(&mut self, descriptors: &[&str]) -> Rewriter {
+        let descriptors = descriptors.iter().cloned().collect::<HashSet<_>>();
+        let (taken, kept) = self.classes.drain(..)
+            .partition::<Vec<_>, _>(|class| descriptors.contains(class.class_type.as_str()));
+        self.classes = kept;
+
+        let mut other = Rewriter {
+            version: self.version,
+            classes: vec!(),
+            static_values: HashMap::new(),
+            references: HashMap::new()
+        };
+        for class in taken {
+            if let Some(ref data) = class.class_data {
+                for field in &data.static_fields {
+                    if let Some((field, value)) = self.static_values.remove_entry(&field.field) {
+                        other.static_values.insert(field, value);
+                    }
+                }
+                for method in data.direct_methods.iter().chain(data.virtual_methods.iter()) {
+                    if let Some((method, references)) = self.references.remove_entry(&method.method) {
+                        other.references.insert(method, references);
+                    }
+                }
+            }
+            other.classes.push(class);
+        }
+        other
+    }
+
+    // The file as parsing the written result gives it: only what is used in the pools, everything in sorted
+    // order, the indexes in bytecode pointing at their new positions and a header with the new checksum
+    pub fn build(self) -> Result<DexFile, DexParserError> {
+        let Rewriter { version, mut classes, static_values, references } = self;
+        let mut builder = DexBuilder::new(version);
+
+        // call sites are numbered in the order they are added, so go through the code in order
+        let mut call_sites: Vec<Rc<CallSiteItem>> = vec!();
+        for class in &mut classes {
+            class.static_values = ordered_static_values(class, &static_values);
+            for method in methods(class) {
+                for reference in references.get(&method.method).into_iter().flatten() {
+                    match reference.target {
+                        Reference::CallSite(ref call_site) if !call_sites.contains(call_site) => {
+                            call_sites.push(call_site.clone());
+                            builder.call_site(call_site.method_handle.clone(), &call_site.method_name,
+                                              call_site.method_type.clone(), call_site.constant_values.clone());
+                        },
+                        ref target => intern(&mut builder, target)
+                    }
+                }
+            }
+        }
+        for class in supertypes_first(classes) {
+            builder.class(class);
+        }
+
+        let mut file = builder.build()?;
+        {
+            let idx = Indexes::new(&file.file_data);
+            for class in &mut file.classes {
+                if let Some(ref mut data) = class.class_data {
+                    for method in data.direct_methods.iter_mut().chain(data.virtual_methods.iter_mut()) {
+                        if let Some(ref mut code) = method.code {
+                            let references = references.get(&method.method).map_or(&[][..], Vec::as_slice);
+                            relink(code, references, &idx, &file.file_data.call_sites)?;
+                        }
+                    }
+                }
+            }
+        }
+        builder::update_header(&mut file)?;
+        Ok(file)
+    }
+
+    pub fn write(self) -> Result<Vec<u8>, DexParserError> {
+        writer::write(&self.build()?)
+    }
+
+    fn class_data_mut(&mut self, definer: &str) -> Result<&mut ClassData, DexParserError> {
+        let class = self.class_mut(definer).ok_or_else(|| failed(format!("no class {}", definer)))?;
+        Ok(class.class_data.get_or_insert_with(|| ClassData {
+            static_fields: vec!(),
+            instance_fields: vec!(),
+            direct_methods: vec!(),
+            virtual_methods: vec!()
+        }))
+    }
+
+    fn apply(&mut self, renames: &Renames) {
+        for class in &mut self.classes {
+            renames.class(class);
+            parser::system_annotations::decode(class);
+        }
+        self.static_values = self.static_values.drain()
+            .map(|(field, value)| (renames.field(&field), renames.value(&value)))
+            .collect();
+        self.references = self.references.drain()
+            .map(|(method, references)| {
+                let references = references.iter()
+                    .map(|reference| CodeReference {
+                        offset: reference.offset,
+                        target: renames.reference(&reference.target)
+                    })
+                    .collect();
+                (renames.method(&method), references)
+            })
+            .collect();
+    }
+}
+
+fn failed(reason: String) -> DexParserError {
+    DexParserError::WritingFailed { reason }
+}
+
+fn take<T>(items: &mut Vec<T>, matches: impl Fn(&T) -> bool) -> Option<T> {
+    items.iter().position(matches).map(|i| items.remove(i))
+}
+
+fn methods(class: &ClassDefinition) -> impl Iterator<Item = &EncodedMethod> {
+    class.class_data.iter().flat_map(|data| data.direct_methods.iter().chain(data.virtual_methods.iter()))
+}
+
+fn intern(builder: &mut DexBuilder, target: &Reference) {
+    match target {
+        Reference::String(s) => { builder.string(s); },
+        Reference::Type(type_) => { builder.type_(type_); },
+        Reference::Field(field) => { builder.field(&field.definer, &field.name, &field.type_); },
+        Reference::Method(method) => {
+            let prototype = &method.prototype;
+            builder.method(&method.definer, &method.name, &prototype.return_type, &parameters(prototype));
+        },
+        Reference::Prototype(proto) => { builder.prototype(&proto.return_type, &parameters(proto)); },
+        Reference::MethodHandle(handle) => { builder.method_handle(handle.type_, handle.member.clone()); },
+        // added in order by build
+        Reference::CallSite(_) => ()
+    }
+}
+
+// Docs: class_defs, where a class has to come after its superclass and interfaces if the file defines them.
+// Otherwise classes keep their order
+fn supertypes_first(classes: Vec<ClassDefinition>) -> Vec<ClassDefinition> {
+    fn visit(i: usize, classes: &[ClassDefinition], positions: &HashMap<&str, usize>, visited: &mut [bool],
+             order: &mut Vec<usize>) {
+        if visited[i] {
+            return;
+        }
+        visited[i] = true;
+        let class = &classes[i];
+        for supertype in class.superclass.iter().chain(class.interfaces.iter()) {
+            if let Some(&j) = positions.get(supertype.as_str()) {
+                visit(j, classes, positions, visited, order);
+            }
+        }
+        order.push(i);
+    }
+
+    let mut order = vec!();
+    {
+        let positions = classes.iter().enumerate().map(|(i, class)| (class.class_type.as_str(), i)).collect();
+        let mut visited = vec!(false; classes.len());
+        for i in 0 .. classes.len() {
+            visit(i, &classes, &positions, &mut visited, &mut order);
+        }
+    }
+    let mut classes = classes.into_iter().map(Some).collect::<Vec<_>>();
+    order.into_iter().filter_map(|i| classes[i].take()).collect()
+}
+
+fn parameters(proto: &Prototype) -> Vec<&str> {
+    proto.parameters.iter().map(|p| p.as_str()).collect()
+}
+
+// The values of a class's static fields in the order the builder will put the fields in, up to the last one with
+// a value. Fields of one class are ordered by name, then type
+fn ordered_static_values(class: &ClassDefinition, values: &HashMap<Rc<Field>, EncodedValue>) -> Vec<EncodedValue> {
+    let mut fields = class.class_data.iter().flat_map(|data| data.static_fields.iter()).map(|f| &f.field)
+        .collect::<Vec<_>>();
+    fields.sort_by(|a, b| utf16_cmp(&a.name, &b.name).then_with(|| utf16_cmp(&a.type_, &b.type_)));
+    let len = fields.iter().rposition(|field| values.contains_key(*field)).map_or(0, |i| i + 1);
+    fields[.. len].iter()
+        .map(|field| values.get(*field).cloned().unwrap_or_else(|| default_value(&field.type_)))
+        .collect()
+}
+
+// what a static field without a value starts out as
+fn default_value(type_: &str) -> EncodedValue {
+    match type_ {
+        "Z" => EncodedValue::Boolean(false),
+        "B" => EncodedValue::Byte(0),
+        "S" => EncodedValue::Short(0),
+        "C" => EncodedValue::Char(0),
+        "I" => EncodedValue::Int(0),
+        "J" => EncodedValue::Long(0),
+        "F" => EncodedValue::Float(0.0),
+        "D" => EncodedValue::Double(0.0),
+        _ => EncodedValue::Null
+    }
+}
+
+// Writes the new index of what each reference refers to into its instruction, and checks every index has one.
+// A string index past 0xFFFF makes const-string into the longer const-string/jumbo, laying the code out again
+fn relink(code: &mut Code, references: &[CodeReference], idx: &Indexes, call_sites: &[Rc<CallSiteItem>])
+          -> Result<(), DexParserError> {
+    let resolve = |target: &Reference| assembler::index_of(target, idx, call_sites);
+    for reference in references {
+        if let Reference::String(ref s) = reference.target {
+            if idx.string(s)? > u32::from(u16::MAX)
+                && instructions::decode(&code.insns, reference.offset)?.opcode == Opcode::CONST_STRING {
+                *code = assembler::relayout(code, references, resolve)?;
+                return Ok(());
+            }
+        }
+    }
+
+    let mut linked = HashSet::new();
+    for reference in references {
+        let insn = instructions::decode(&code.insns, reference.offset)?;
+        let err = |reason: String| DexParserError::InvalidBytecode { offset: reference.offset, reason };
+        let kind = reference.target.kind();
+        let format = insn.opcode.format();
+        let second = kind == IndexKind::Prototype && matches!(format, Format::F45cc | Format::F4rcc);
+        if !second && kind != insn.opcode.index_kind() {
+            return Err(err(format!("{} takes a {:?} index, not a {:?}", insn.opcode.name(), insn.opcode.index_kind(),
+                                   kind)));
+        }
+
+        let index = resolve(&reference.target)?;
+        let unit = reference.offset as usize + if second { 3 } else { 1 };
+        if format == Format::F31c {
+            code.insns[unit] = index as u16;
+            code.insns[unit + 1] = (index >> 16) as u16;
+        } else if index > u32::from(u16::MAX) {
+            return Err(err(format!("{:?} index {} does not fit in {}", kind, index, insn.opcode.name())));
+        } else {
+            code.insns[unit] = index as u16;
+        }
+        linked.insert((reference.offset, second));
+    }
+
+    for insn in instructions::decode_all(&code.insns)? {
+        if insn.index.is_some() && !linked.contains(&(insn.offset, false))
+            || insn.proto_index.is_some() && !linked.contains(&(insn.offset, true)) {
+            return Err(DexParserError::InvalidBytecode {
+                offset: insn.offset,
+                reason: format!("no reference given for the index of {}", insn.opcode.name())
+            });
+        }
+    }
+    Ok(())
+}
+
+// New names by what they replace. Fields and methods keep their other parts, which type renames apply to
+#[derive(Default)]
+struct Renames {
+    types: HashMap<String, String>,
+    fields: HashMap<Rc<Field>, Rc<String>>,
+    methods: HashMap<Rc<Method>, Rc<String>>
+}
+
+impl Renames {
+    fn type_(&self, type_: &Rc<String>) -> Rc<String> {
+        let element = type_.trim_start_matches('[');
+        match self.types.get(element) {
+            Some(renamed) => Rc::new(format!("{}{}", &type_[.. type_.len() - element.len()], renamed)),
+            None => type_.clone()
+        }
+    }
+
+    fn prototype(&self, proto: &Rc<Prototype>) -> Rc<Prototype> {
+        Rc::new(Prototype {
+            shorty: proto.shorty.clone(),
+            return_type: self.type_(&proto.return_type),
+            parameters: proto.parameters.iter().map(|p| self.type_(p)).collect()
+        })
+    }
+
+    fn field(&self, field: &Rc<Field>) -> Rc<Field> {
+        Rc::new(Field {
+            definer: self.type_(&field.definer),
+            type_: self.type_(&field.type_),
+            name: self.fields.get(field).unwrap_or(&field.name).clone()
+        })
+    }
+
+    fn method(&self, method: &Rc<Method>) -> Rc<Method> {
+        Rc::new(Method {
+            definer: self.type_(&method.definer),
+            prototype: self.prototype(&method.prototype),
+            name: self.methods.get(method).unwrap_or(&method.name).clone()
+        })
+    }
+
+    fn method_handle(&self, handle: &Rc<MethodHandle>) -> Rc<MethodHandle> {
+        let member = match handle.member {
+            MethodHandleMember::Field(ref field) => MethodHandleMember::Field(self.field(field)),
+            MethodHandleMember::Method(ref method) => MethodHandleMember::Method(self.method(method))
+        };
+        Rc::new(MethodHandle { type_: handle.type_, member })
+    }
+
+    fn call_site(&self, call_site: &Rc<CallSiteItem>) -> Rc<CallSiteItem> {
+        Rc::new(CallSiteItem {
+            method_handle: self.method_handle(&call_site.method_handle),
+            method_name: call_site.method_name.clone(),
+            method_type: self.prototype(&call_site.method_type),
+            constant_values: call_site.constant_values.iter().map(|value| self.value(value)).collect()
+        })
+    }
+
+    fn reference(&self, target: &Reference) -> Reference {
+        match target {
+            Reference::String(s) => Reference::String(s.clone()),
+            Reference::Type(type_) => Reference::Type(self.type_(type_)),
+            Reference::Field(field) => Reference::Field(self.field(field)),
+            Reference::Method(method) => Reference::Method(self.method(method)),
+            Reference::Prototype(proto) => Reference::Prototype(self.prototype(proto)),
+            Reference::CallSite(call_site) => Reference::CallSite(self.call_site(call_site)),
+            Reference::MethodHandle(handle) => Reference::MethodHandle(self.method_handle(handle))
+        }
+    }
+
+    fn value(&self, value: &EncodedValue) -> EncodedValue {
+        match value {
+            EncodedValue::MethodType(proto) => EncodedValue::MethodType(self.prototype(proto)),
+            EncodedValue::MethodHandle(handle) => EncodedValue::MethodHandle(self.method_handle(handle)),
+            EncodedValue::Type(type_) => EncodedValue::Type(self.type_(type_)),
+            EncodedValue::Field(field) => EncodedValue::Field(self.field(field)),
+            EncodedValue::Method(method) => EncodedValue::Method(self.method(method)),
+            EncodedValue::Enum(field) => EncodedValue::Enum(self.field(field)),
+            EncodedValue::Array(values) => EncodedValue::Array(values.iter().map(|value| self.value(value)).collect()),
+            EncodedValue::Annotation(annotation) => EncodedValue::Annotation(EncodedAnnotationItem {
+                type_: self.type_(&annotation.type_),
+                values: self.elements(&annotation.values)
+            }),
+            value => value.clone()
+        }
+    }
+
+    fn elements(&self, elements: &[AnnotationElement]) -> Vec<AnnotationElement> {
+        elements.iter()
+            .map(|element| AnnotationElement { name: element.name.clone(), value: self.value(&element.value) })
+            .collect()
+    }
+
+    fn annotation_items(&self, items: &mut [AnnotationItem]) {
+        for item in items {
+            item.type_ = self.type_(&item.type_);
+            item.annotations = self.elements(&item.annotations);
+        }
+    }
+
+    fn class(&self, class: &mut ClassDefinition) {
+        class.class_type = self.type_(&class.class_type);
+        class.superclass = class.superclass.as_ref().map(|superclass| self.type_(superclass));
+        class.interfaces = class.interfaces.iter().map(|interface| self.type_(interface)).collect();
+
+        if let Some(ref mut annotations) = class.annotations {
+            for annotation in &mut annotations.class_annotations {
+                annotation.type_ = self.type_(&annotation.type_);
+                annotation.elements = self.elements(&annotation.elements);
+            }
+            for fa in &mut annotations.field_annotations {
+                fa.field_data = self.field(&fa.field_data);
+                self.annotation_items(&mut fa.annotations);
+            }
+            for ma in &mut annotations.method_annotations {
+                ma.method = self.method(&ma.method);
+                self.annotation_items(&mut ma.annotations);
+            }
+            for pa in &mut annotations.parameter_annotations {
+                pa.method = self.method(&pa.method);
+                for items in &mut pa.annotations {
+                    self.annotation_items(items);
+                }
+            }
+        }
+
+        if let Some(ref mut data) = class.class_data {
+            for field in data.static_fields.iter_mut().chain(data.instance_fields.iter_mut()) {
+                field.field = self.field(&field.field);
+            }
+            for method in data.direct_methods.iter_mut().chain(data.virtual_methods.iter_mut()) {
+                method.method = self.method(&method.method);
+                if let Some(ref mut code) = method.code {
+                    self.code(code);
+                }
+            }
+        }
+    }
+
+    fn code(&self, code: &mut Code) {
+        let handlers = code.handlers.iter_mut().chain(code.tries.iter_mut().map(|try_| &mut try_.handler));
+        for pair in handlers.flat_map(|handler| handler.handlers.iter_mut()) {
+            pair.type_ = self.type_(&pair.type_);
+        }
+        if let Some(ref mut debug_info) = code.debug_info {
+            for item in &mut debug_info.bytecode {
+                match item {
+                    DebugItemBytecodes::DBG_START_LOCAL { type_, .. }
+                        | DebugItemBytecodes::DBG_START_LOCAL_EXTENDED { type_, .. } => {
+                        *type_ = type_.as_ref().map(|t| self.type_(t));
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn method(m: Rc<Method>, access_flags: Vec<AccessFlag>, insns: Option<Vec<u16>>) -> EncodedMethod {
+        EncodedMethod {
+            method: m,
+            access_flags,
+            code: insns.map(|insns| Code { registers_size: 1, ins_size: 0, outs_size: 0, debug_info: None, insns,
+                                           tries: vec!(), handlers: vec!() }),
+            system_annotations: Default::default()
+        }
+    }
+
+    fn class(class_type: Rc<String>, static_fields: Vec<EncodedField>, direct_methods: Vec<EncodedMethod>,
+             static_values: Vec<EncodedValue>) -> ClassDefinition {
+        ClassDefinition {
+            class_type, access_flags: vec!(AccessFlag::ACC_PUBLIC), superclass: None, interfaces: vec!(),
+            source_file_name: None, annotations: None,
+            class_data: Some(ClassData { static_fields, instance_fields: vec!(), direct_methods,
+                                         virtual_methods: vec!() }),
+            static_values, system_annotations: Default::default()
+        }
+    }
+
+    fn build() -> DexFile {
+        let mut b = DexBuilder::new(35);
+        let hello = b.string("hello");
+        let count = b.field("LUtil;", "count", "I");
+        let help = b.method("LUtil;", "help", "V", &[]);
+        let run = b.method("LMain;", "run", "V", &[]);
+        let main = class(b.type_("LMain;"), vec!(), vec!(method(run, vec!(AccessFlag::ACC_STATIC), Some(vec!()))),
+                         vec!());
+        let util = class(b.type_("LUtil;"),
+                         vec!(EncodedField { field: count.clone(), access_flags: vec!(AccessFlag::ACC_STATIC) }),
+                         vec!(method(help, vec!(AccessFlag::ACC_STATIC), Some(vec!(0x000E)))),
+                         vec!(EncodedValue::Int(5)));
+        b.class(main).class(util);
+        let mut file = b.build().unwrap();
+
+        // const-string v0, "hello"; sget v0, Util.count; invoke-static {}, Util.help()V; return-void
+        let fd = &file.file_data;
+        let string = fd.string_data.iter().position(|s| *s == hello).unwrap() as u16;
+        let field = fd.fields.iter().position(|f| *f == count).unwrap() as u16;
+        let method = fd.methods.iter().position(|m| *m.name == "help").unwrap() as u16;
+        let run = &mut file.classes[0].class_data.as_mut().unwrap().direct_methods[0];
+        run.code.as_mut().unwrap().insns = vec!(0x001A, string, 0x0060, field, 0x0071, method, 0, 0x000E);
+        file
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut rewriter = Rewriter::new(build()).unwrap();
+        let count = rewriter.class("LUtil;").unwrap().class_data.as_ref().unwrap().static_fields[0].field.clone();
+        rewriter.rename_class("LUtil;", "Lcom/example/Helper;").unwrap();
+        let count = Field { definer: Rc::new("Lcom/example/Helper;".to_string()), type_: count.type_.clone(),
+                            name: count.name.clone() };
+        rewriter.rename_field(&count, "total").unwrap();
+        assert!(rewriter.rename_class("LMain;", "Lcom/example/Helper;").is_err());
+
+        // a new string and field that sort before the old ones, so every index moves
+        let a = Rc::new(Field { definer: Rc::new("Lcom/example/Helper;".to_string()), type_: Rc::new("I".to_string()),
+                                name: Rc::new("a".to_string()) });
+        rewriter.add_field(EncodedField { field: a.clone(), access_flags: vec!(AccessFlag::ACC_STATIC) },
+                           Some(EncodedValue::Int(7))).unwrap();
+        let main = rewriter.class("LMain;").unwrap().class_data.as_ref().unwrap().direct_methods[0].method.clone();
+        let mut references = rewriter.code_references(&main).unwrap().to_vec();
+        references[0].target = Reference::String(Rc::new("abc".to_string()));
+        let code = Code { registers_size: 1, ins_size: 0, outs_size: 0, debug_info: None,
+                          insns: vec!(0x001A, 0, 0x0060, 0, 0x0071, 0, 0, 0x000E), tries: vec!(), handlers: vec!() };
+        rewriter.set_code(&main, Some(code), references).unwrap();
+        rewriter.method_mut(&main).unwrap().access_flags.push(AccessFlag::ACC_PUBLIC);
+
+        let bytes = rewriter.write().unwrap();
+        let file = crate::parse(&bytes).unwrap();
+        assert_eq!(file.classes.iter().map(|c| c.class_type.as_str()).collect::<Vec<_>>(),
+                   vec!("LMain;", "Lcom/example/Helper;"));
+        assert!(!file.file_data.string_data.iter().any(|s| s.as_str() == "hello" || s.as_str() == "LUtil;"));
+        assert_eq!(file.classes[1].static_values, vec!(EncodedValue::Int(7), EncodedValue::Int(5)));
+
+        let run = &file.classes[0].class_data.as_ref().unwrap().direct_methods[0];
+        assert_eq!(run.access_flags, vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_STATIC));
+        let references = lift(&file, run.code.as_ref().unwrap()).unwrap();
+        let targets = references.iter().map(|r| match r.target {
+            Reference::String(ref s) => s.to_string(),
+            Reference::Field(ref f) => format!("{}->{}", f.definer, f.name),
+            Reference::Method(ref m) => format!("{}->{}", m.definer, m.name),
+            ref other => format!("{:?}", other)
+        }).collect::<Vec<_>>();
+        assert_eq!(targets, vec!("abc", "Lcom/example/Helper;->total", "Lcom/example/Helper;->help"));
+
+        // writing again without changes gives the same bytes
+        assert_eq!(Rewriter::new(file).unwrap().write().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_widen() {
+        let mut rewriter = Rewriter::new(build()).unwrap();
+        let main = rewriter.class("LMain;").unwrap().class_data.as_ref().unwrap().direct_methods[0].method.clone();
+
+        // enough strings sorting before "~" to push it past index 0xFFFF
+        let strings = Rc::new(Field { definer: main.definer.clone(), type_: Rc::new("[Ljava/lang/String;".to_string()),
+                                      name: Rc::new("strings".to_string()) });
+        let padding = (0 .. 0x10000).map(|i| EncodedValue::String(Rc::new(format!("s{:05}", i)))).collect();
+        rewriter.add_field(EncodedField { field: strings, access_flags: vec!(AccessFlag::ACC_STATIC) },
+                           Some(EncodedValue::Array(padding))).unwrap();
+
+        // const-string v0, "~"; if-eqz v0, end; packed-switch v0, payload; goto 0; end: return-void; the payload,
+        // with a try block over the first two instructions and a position at each end of it
+        let code = Code {
+            registers_size: 1, ins_size: 0, outs_size: 0,
+            debug_info: Some(DebugInfo { line_start: 1, parameter_names: vec!(), bytecode: vec!(
+                DebugItemBytecodes::SPECIAL_OPCODE(0x0A + 5 + 15 * 2),
+                DebugItemBytecodes::DBG_ADVANCE_PC(6),
+                DebugItemBytecodes::SPECIAL_OPCODE(0x0A + 5)
+            ) }),
+            insns: vec!(0x001A, 0, 0x0038, 6, 0x002B, 6, 0, 0xF928, 0x000E, 0x0000, 0x0100, 1, 0, 0, 4, 0),
+            tries: vec!(TryItem { start_addr: 0, insn_count: 4,
+                                  handler: EncodedCatchHandler { handlers: vec!(), catch_all_addr: Some(8) } }),
+            handlers: vec!(EncodedCatchHandler { handlers: vec!(), catch_all_addr: Some(8) })
+        };
+        let references = vec!(CodeReference { offset: 0, target: Reference::String(Rc::new("~".to_string())) });
+        rewriter.set_code(&main, Some(code), references).unwrap();
+
+        // the same string from the assembler, in a loop back over it with a switch and a try block around it all
+        let help = rewriter.class("LUtil;").unwrap().class_data.as_ref().unwrap().direct_methods[0].method.clone();
+        let mut b = assembler::CodeBuilder::new(1, 0);
+        b.label("loop")
+            .const_string(0, Rc::new("~".to_string()))
+            .sparse_switch(0, &[(1, "loop"), (2, "end")])
+            .goto("loop")
+            .label("end")
+            .op(Opcode::RETURN_VOID, &[])
+            .try_catch("loop", "end", &[], Some("end"));
+        let (code, references) = b.build_references().unwrap();
+        rewriter.set_code(&help, Some(code), references).unwrap();
+
+        let file = crate::parse(&rewriter.write().unwrap()).unwrap();
+        let code = file.classes[0].class_data.as_ref().unwrap().direct_methods[0].code.as_ref().unwrap();
+        let insns = instructions::decode_all(&code.insns).unwrap();
+        assert_eq!(insns.iter().map(|insn| (insn.offset, insn.opcode)).take(5).collect::<Vec<_>>(),
+                   vec!((0, Opcode::CONST_STRING_JUMBO), (3, Opcode::IF_EQZ), (5, Opcode::PACKED_SWITCH),
+                        (8, Opcode::GOTO), (9, Opcode::RETURN_VOID)));
+        assert_eq!(*file.file_data.string_data[insns[0].index.unwrap() as usize], "~");
+        assert!(insns[0].index.unwrap() > 0xFFFF);
+        assert_eq!((insns[1].target(), insns[3].target()), (Some(9), Some(0)));
+        let payload = insns.iter().find(|insn| Some(insn.offset) == insns[2].target()).unwrap();
+        assert_eq!(payload.payload.as_ref().unwrap().switch_cases(), vec!((0, 4)));
+        assert_eq!((code.tries[0].start_addr, code.tries[0].insn_count), (0, 5));
+        assert_eq!(code.tries[0].handler.catch_all_addr, Some(9));
+        assert_eq!(code.debug_info.as_ref().unwrap().bytecode, vec!(
+            DebugItemBytecodes::SPECIAL_OPCODE(0x0A + 5 + 15 * 3),
+            DebugItemBytecodes::DBG_ADVANCE_PC(6),
+            DebugItemBytecodes::SPECIAL_OPCODE(0x0A + 5)
+        ));
+
+        let util = file.classes.iter().find(|class| *class.class_type == "LUtil;").unwrap();
+        let code = util.class_data.as_ref().unwrap().direct_methods[0].code.as_ref().unwrap();
+        let insns = instructions::decode_all(&code.insns).unwrap();
+        assert_eq!(insns.iter().map(|insn| (insn.offset, insn.opcode)).take(4).collect::<Vec<_>>(),
+                   vec!((0, Opcode::CONST_STRING_JUMBO), (3, Opcode::SPARSE_SWITCH), (6, Opcode::GOTO),
+                        (7, Opcode::RETURN_VOID)));
+        assert_eq!(insns[2].target(), Some(0));
+        let payload = insns.iter().find(|insn| Some(insn.offset) == insns[1].target()).unwrap();
+        assert_eq!(payload.payload.as_ref().unwrap().switch_cases(), vec!((1, -3), (2, 4)));
+        assert_eq!((code.tries[0].start_addr, code.tries[0].insn_count), (0, 7));
+        assert_eq!(code.tries[0].handler.catch_all_addr, Some(7));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut rewriter = Rewriter::new(build()).unwrap();
+        let help = rewriter.class("LUtil;").unwrap().class_data.as_ref().unwrap().direct_methods[0].method.clone();
+        assert!(rewriter.remove_method(&help).is_some());
+        assert!(rewriter.remove_class("LMain;").is_some());
+        let file = rewriter.build().unwrap();
+        assert_eq!(file.classes.len(), 1);
+        assert!(file.file_data.methods.is_empty());
+        assert_eq!(file.classes[0].static_values, vec!(EncodedValue::Int(5)));
+
+        // code that refers to the pools without saying what to
+        let mut rewriter = Rewriter::new(build()).unwrap();
+        let run = rewriter.class("LMain;").unwrap().class_data.as_ref().unwrap().direct_methods[0].method.clone();
+        let code = Code { registers_size: 1, ins_size: 0, outs_size: 0, debug_info: None,
+                          insns: vec!(0x001A, 0, 0x000E), tries: vec!(), handlers: vec!() };
+        rewriter.set_code(&run, Some(code), vec!()).unwrap();
+        assert!(rewriter.build().is_err());
+    }
+}
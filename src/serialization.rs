@@ -0,0 +1,443 @@
+// Serde support for DexFile, behind the `serde` feature
+//
+// Strings, prototypes, fields, methods and method handles are shared through Rc across a file. They are written once,
+// in the pools of `file_data`, and everywhere else as their index in those pools, so a field reference is
+// `{"definer": 12, "type_": 3, "name": 40}` with each number an index into `string_data`. Deserializing gives back
+// the same sharing. The pools are only known while a whole DexFile is being serialized or deserialized, so the types
+// it is made of can't be serialized on their own, and `file_data` has to come before `classes`.
+//
+// Written as JSON, a file follows JSON_SCHEMA. Floats that JSON has no number for are written as "NaN", "Infinity"
+// and "-Infinity".
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use serde::de::{self, DeserializeOwned, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::result_types::*;
+
+pub const JSON_SCHEMA: &str = include_str!("../schema/dex-file.schema.json");
+
+thread_local! {
+    // the pools of the DexFile being serialized or deserialized on this thread
+    static POOLS: RefCell<Option<Pools>> = const { RefCell::new(None) };
+}
+
+struct Pool<T> {
+    items: Vec<Rc<T>>,
+    indexes: HashMap<Rc<T>, u32>
+}
+
+impl<T: Hash + Eq> Pool<T> {
+    fn new(items: &[Rc<T>]) -> Self {
+        let indexes = items.iter().enumerate().map(|(i, item)| (item.clone(), i as u32)).collect();
+        Pool { items: items.to_vec(), indexes }
+    }
+}
+
+struct Pools {
+    strings: Pool<String>,
+    prototypes: Pool<Prototype>,
+    fields: Pool<Field>,
+    methods: Pool<Method>,
+    method_handles: Pool<MethodHandle>
+}
+
+impl Pools {
+    fn new(data: &DexFileData) -> Self {
+        Pools {
+            strings: Pool::new(&data.string_data),
+            prototypes: Pool::new(&data.prototypes),
+            fields: Pool::new(&data.fields),
+            methods: Pool::new(&data.methods),
+            method_handles: Pool::new(&data.method_handles)
+        }
+    }
+}
+
+// Installs the pools of a file for as long as it is alive, putting back whatever was there before
+struct PoolsGuard(Option<Pools>);
+
+impl PoolsGuard {
+    fn install(pools: Pools) -> Self {
+        PoolsGuard(POOLS.with(|p| p.replace(Some(pools))))
+    }
+}
+
+impl Drop for PoolsGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        POOLS.with(|p| *p.borrow_mut() = previous);
+    }
+}
+
+fn with_pools<R>(f: impl FnOnce(&mut Pools) -> Result<R, String>) -> Result<R, String> {
+    POOLS.with(|p| match p.borrow_mut().as_mut() {
+        Some(pools) => f(pools),
+        None => Err("pool references can only be serialized and deserialized as part of a DexFile".to_string())
+    })
+}
+
+// A type whose values are shared through one of the pools
+trait PoolItem: Hash + Eq + Sized {
+    const NAME: &'static str;
+    fn pool(pools: &mut Pools) -> &mut Pool<Self>;
+}
+
+impl PoolItem for String {
+    const NAME: &'static str = "string";
+    fn pool(pools: &mut Pools) -> &mut Pool<Self> { &mut pools.strings }
+}
+
+impl PoolItem for Prototype {
+    const NAME: &'static str = "prototype";
+    fn pool(pools: &mut Pools) -> &mut Pool<Self> { &mut pools.prototypes }
+}
+
+impl PoolItem for Field {
+    const NAME: &'static str = "field";
+    fn pool(pools: &mut Pools) -> &mut Pool<Self> { &mut pools.fields }
+}
+
+impl PoolItem for Method {
+    const NAME: &'static str = "method";
+    fn pool(pools: &mut Pools) -> &mut Pool<Self> { &mut pools.methods }
+}
+
+impl PoolItem for MethodHandle {
+    const NAME: &'static str = "method handle";
+    fn pool(pools: &mut Pools) -> &mut Pool<Self> { &mut pools.method_handles }
+}
+
+// A value made of pool references: an Rc to a pooled item, or an Option or Vec of them
+pub(crate) trait Pooled: Sized {
+    fn serialize_pooled<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+    fn deserialize_pooled<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+}
+
+impl<T: PoolItem> Pooled for Rc<T> {
+    fn serialize_pooled<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let index = with_pools(|pools| T::pool(pools).indexes.get(self).cloned()
+            .ok_or_else(|| format!("{} is not in the file's pool", T::NAME)))
+            .map_err(ser::Error::custom)?;
+        serializer.serialize_u32(index)
+    }
+
+    fn deserialize_pooled<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let index = u32::deserialize(deserializer)?;
+        with_pools(|pools| T::pool(pools).items.get(index as usize).cloned()
+            .ok_or_else(|| format!("{} index {} is out of range", T::NAME, index)))
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<T: Pooled> Pooled for Option<T> {
+    fn serialize_pooled<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Some(value) => serializer.serialize_some(&Ref(value)),
+            None => serializer.serialize_none()
+        }
+    }
+
+    fn deserialize_pooled<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Option::<Owned<T>>::deserialize(deserializer)?.map(|value| value.0))
+    }
+}
+
+impl<T: Pooled> Pooled for Vec<T> {
+    fn serialize_pooled<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(Ref))
+    }
+
+    fn deserialize_pooled<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Owned<T>>::deserialize(deserializer)?.into_iter().map(|value| value.0).collect())
+    }
+}
+
+struct Ref<'a, T>(&'a T);
+
+impl<'a, T: Pooled> Serialize for Ref<'a, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize_pooled(serializer)
+    }
+}
+
+struct Owned<T>(T);
+
+impl<'de, T: Pooled> Deserialize<'de> for Owned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize_pooled(deserializer).map(Owned)
+    }
+}
+
+// `#[serde(with = "pooled")]` on the fields of the result types that refer into the pools
+pub(crate) mod pooled {
+    use super::*;
+
+    pub(crate) fn serialize<T: Pooled, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        value.serialize_pooled(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, T: Pooled, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        T::deserialize_pooled(deserializer)
+    }
+}
+
+pub(crate) mod endianness {
+    use super::*;
+    use nom::Endianness;
+
+    pub(crate) fn serialize<S: Serializer>(endianness: &Endianness, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match endianness {
+            Endianness::Big => "Big",
+            Endianness::Little => "Little"
+        })
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Endianness, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "Big" => Ok(Endianness::Big),
+            "Little" => Ok(Endianness::Little),
+            other => Err(de::Error::unknown_variant(other, &["Big", "Little"]))
+        }
+    }
+}
+
+// Floats are numbers, except for those JSON can't represent. Formats that aren't human-readable get them as they are
+pub(crate) mod float {
+    use super::*;
+
+    pub(crate) trait Float: Copy + Serialize + DeserializeOwned {
+        fn to_f64(self) -> f64;
+        fn from_f64(value: f64) -> Self;
+    }
+
+    impl Float for f32 {
+        fn to_f64(self) -> f64 { self as f64 }
+        fn from_f64(value: f64) -> Self { value as f32 }
+    }
+
+    impl Float for f64 {
+        fn to_f64(self) -> f64 { self }
+        fn from_f64(value: f64) -> Self { value }
+    }
+
+    pub(crate) fn serialize<T: Float, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let v = value.to_f64();
+        if !serializer.is_human_readable() || v.is_finite() {
+            value.serialize(serializer)
+        } else if v.is_nan() {
+            serializer.serialize_str("NaN")
+        } else {
+            serializer.serialize_str(if v > 0.0 { "Infinity" } else { "-Infinity" })
+        }
+    }
+
+    pub(crate) fn deserialize<'de, T: Float, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        if !deserializer.is_human_readable() {
+            return T::deserialize(deserializer);
+        }
+        deserializer.deserialize_any(FloatVisitor(PhantomData))
+    }
+
+    struct FloatVisitor<T>(PhantomData<T>);
+
+    impl<'de, T: Float> Visitor<'de> for FloatVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number, \"NaN\", \"Infinity\" or \"-Infinity\"")
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<T, E> {
+            Ok(T::from_f64(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> {
+            Ok(T::from_f64(v as f64))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+            Ok(T::from_f64(v as f64))
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+            match v {
+                "NaN" => Ok(T::from_f64(f64::NAN)),
+                "Infinity" => Ok(T::from_f64(f64::INFINITY)),
+                "-Infinity" => Ok(T::from_f64(f64::NEG_INFINITY)),
+                _ => Err(de::Error::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+    }
+}
+
+impl Serialize for DexFile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let _pools = PoolsGuard::install(Pools::new(&self.file_data));
+        let mut file = serializer.serialize_struct("DexFile", 4)?;
+        file.serialize_field("header", &self.header)?;
+        file.serialize_field("file_data", &self.file_data)?;
+        file.serialize_field("classes", &self.classes)?;
+        file.serialize_field("regions", &self.regions)?;
+        file.end()
+    }
+}
+
+// The pools hold the items themselves, rather than references to them
+impl Serialize for DexFileData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut data = serializer.serialize_struct("DexFileData", 7)?;
+        data.serialize_field("string_data", &Items(&self.string_data))?;
+        data.serialize_field("type_identifiers", &Ref(&self.type_identifiers))?;
+        data.serialize_field("prototypes", &Items(&self.prototypes))?;
+        data.serialize_field("fields", &Items(&self.fields))?;
+        data.serialize_field("methods", &Items(&self.methods))?;
+        data.serialize_field("method_handles", &Items(&self.method_handles))?;
+        data.serialize_field("call_sites", &Items(&self.call_sites))?;
+        data.end()
+    }
+}
+
+struct Items<'a, T>(&'a [Rc<T>]);
+
+impl<'a, T: Serialize> Serialize for Items<'a, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|item| &**item))
+    }
+}
+
+const FILE_FIELDS: &[&str] = &["header", "file_data", "classes", "regions"];
+const DATA_FIELDS: &[&str] = &[
+    "string_data", "type_identifiers", "prototypes", "fields", "methods", "method_handles", "call_sites"
+];
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum FileField { Header, FileData, Classes, Regions }
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum DataField { StringData, TypeIdentifiers, Prototypes, Fields, Methods, MethodHandles, CallSites }
+
+impl<'de> Deserialize<'de> for DexFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let _pools = PoolsGuard::install(Pools::new(&empty_data()));
+        deserializer.deserialize_struct("DexFile", FILE_FIELDS, FileVisitor)
+    }
+}
+
+struct FileVisitor;
+
+impl<'de> Visitor<'de> for FileVisitor {
+    type Value = DexFile;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a DexFile")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<DexFile, A::Error> {
+        let missing = |i| de::Error::invalid_length(i, &self);
+        Ok(DexFile {
+            header: seq.next_element()?.ok_or_else(|| missing(0))?,
+            file_data: seq.next_element()?.ok_or_else(|| missing(1))?,
+            classes: seq.next_element()?.ok_or_else(|| missing(2))?,
+            regions: seq.next_element()?.ok_or_else(|| missing(3))?
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DexFile, A::Error> {
+        let (mut header, mut file_data, mut classes, mut regions) = (None, None, None, None);
+        while let Some(key) = map.next_key()? {
+            match key {
+                FileField::Header => header = Some(map.next_value()?),
+                FileField::FileData => file_data = Some(map.next_value()?),
+                FileField::Classes if file_data.is_none() => {
+                    return Err(de::Error::custom("file_data must come before classes"));
+                },
+                FileField::Classes => classes = Some(map.next_value()?),
+                FileField::Regions => regions = Some(map.next_value()?)
+            }
+        }
+        Ok(DexFile {
+            header: header.ok_or_else(|| de::Error::missing_field("header"))?,
+            file_data: file_data.ok_or_else(|| de::Error::missing_field("file_data"))?,
+            classes: classes.ok_or_else(|| de::Error::missing_field("classes"))?,
+            regions: regions.ok_or_else(|| de::Error::missing_field("regions"))?
+        })
+    }
+}
+
+// Each pool is made available to the items read after it, so that a field can refer to strings, say
+impl<'de> Deserialize<'de> for DexFileData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("DexFileData", DATA_FIELDS, DataVisitor)
+    }
+}
+
+struct DataVisitor;
+
+fn empty_data() -> DexFileData {
+    DexFileData {
+        string_data: vec!(),
+        type_identifiers: vec!(),
+        prototypes: vec!(),
+        fields: vec!(),
+        methods: vec!(),
+        method_handles: vec!(),
+        call_sites: vec!()
+    }
+}
+
+// Puts the items of a pool behind Rcs and makes them available to what is read next
+fn pool<T: PoolItem, E: de::Error>(items: Vec<T>) -> Result<Vec<Rc<T>>, E> {
+    let items = items.into_iter().map(Rc::new).collect::<Vec<_>>();
+    with_pools(|pools| {
+        *T::pool(pools) = Pool::new(&items);
+        Ok(())
+    }).map_err(de::Error::custom)?;
+    Ok(items)
+}
+
+impl<'de> Visitor<'de> for DataVisitor {
+    type Value = DexFileData;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the pools of a DexFile")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<DexFileData, A::Error> {
+        let mut data = empty_data();
+        let missing = |i| de::Error::invalid_length(i, &self);
+        data.string_data = pool(seq.next_element()?.ok_or_else(|| missing(0))?)?;
+        data.type_identifiers = seq.next_element::<Owned<_>>()?.ok_or_else(|| missing(1))?.0;
+        data.prototypes = pool(seq.next_element()?.ok_or_else(|| missing(2))?)?;
+        data.fields = pool(seq.next_element()?.ok_or_else(|| missing(3))?)?;
+        data.methods = pool(seq.next_element()?.ok_or_else(|| missing(4))?)?;
+        data.method_handles = pool(seq.next_element()?.ok_or_else(|| missing(5))?)?;
+        data.call_sites = seq.next_element::<Vec<_>>()?.ok_or_else(|| missing(6))?.into_iter().map(Rc::new).collect();
+        Ok(data)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DexFileData, A::Error> {
+        let mut data = empty_data();
+        while let Some(key) = map.next_key()? {
+            match key {
+                DataField::StringData => data.string_data = pool(map.next_value()?)?,
+                DataField::TypeIdentifiers => data.type_identifiers = map.next_value::<Owned<_>>()?.0,
+                DataField::Prototypes => data.prototypes = pool(map.next_value()?)?,
+                DataField::Fields => data.fields = pool(map.next_value()?)?,
+                DataField::Methods => data.methods = pool(map.next_value()?)?,
+                DataField::MethodHandles => data.method_handles = pool(map.next_value()?)?,
+                DataField::CallSites => data.call_sites = map.next_value::<Vec<_>>()?.into_iter().map(Rc::new).collect()
+            }
+        }
+        Ok(data)
+    }
+}
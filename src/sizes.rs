@@ -0,0 +1,464 @@
+// Where the bytes of a file go: every item and pool entry attributed to the method, field, class or package it is
+// there for, and added up through the package tree
+//
+// Something only one member uses belongs to that member, so a method has its code, debug info and annotations, and
+// the strings, types and references only it uses. Anything shared belongs to the innermost class or package holding
+// everything that uses it: a string two methods of a class use is the class's, one used all over the file the root
+// package's. The header, the map list, padding and anything else no class leads to also go to the root package.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::DexParserError;
+use crate::java;
+use crate::options::ParseOptions;
+use crate::parser::attribution::Owner;
+use crate::result_types::*;
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SizeReport {
+    pub file_size: u32,
+    // the root package, its total adding up to the whole file
+    pub root: SizeNode
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SizeNode {
+    // `com.example` for packages, the empty string for the root, `com.example.Main` for classes, and `run(I)V` and
+    // `count:I` for methods and fields
+    pub name: String,
+    pub kind: NodeKind,
+    // what belongs to this node and not to any one of its children
+    pub own: Sizes,
+    // what belongs to this node and its children together
+    pub total: Sizes,
+    // the largest first: subpackages and classes under packages, and fields and methods under classes
+    pub children: Vec<SizeNode>
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NodeKind {
+    Package,
+    Class,
+    Field,
+    Method
+}
+
+// What an item of the file is, which says which part of `Sizes` it counts towards
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Category {
+    Code,
+    DebugInfo,
+    Strings,
+    Annotations,
+    ClassData,
+    EncodedArrays,
+    TypeLists,
+    // entries in the string, type, prototype, field, method, call site, method handle and class def sections
+    Ids,
+    // the header, the map list, hidden API flags, padding and anything outside of the map list
+    Other
+}
+
+// Bytes by the kind of item they are in
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Sizes {
+    pub code: u32,
+    pub debug_info: u32,
+    pub strings: u32,
+    pub annotations: u32,
+    pub class_data: u32,
+    pub encoded_arrays: u32,
+    pub type_lists: u32,
+    pub ids: u32,
+    pub other: u32
+}
+
+pub fn attribute(buffer: &[u8]) -> Result<SizeReport, DexParserError> {
+    // the pools give the names the attributions only have indexes for
+    let file = crate::parse(buffer)?;
+    let mut root = Builder::default();
+    for attribution in crate::parser::attribute(buffer, ParseOptions::default())? {
+        root.node(&file, &attribution.owner).own.add(attribution.category, attribution.size);
+    }
+
+    Ok(SizeReport {
+        file_size: buffer.len() as u32,
+        root: root.finish(String::new(), NodeKind::Package)
+    })
+}
+
+#[derive(Default)]
+struct Builder {
+    own: Sizes,
+    children: BTreeMap<String, (NodeKind, Builder)>
+}
+
+impl Builder {
+    fn node(&mut self, file: &DexFile, owner: &Owner) -> &mut Builder {
+        let data = &file.file_data;
+        let (class, member) = match *owner {
+            Owner::Package(ref package) => return self.package(package),
+            Owner::Class(class) => (class, None),
+            Owner::Field(class, field) => (class, data.fields.get(field as usize)
+                .map(|field| (format!("{}:{}", field.name, field.type_), NodeKind::Field))),
+            Owner::Method(class, method) => (class, data.methods.get(method as usize)
+                .map(|method| (format!("{}{}", method.name, descriptor(&method.prototype)), NodeKind::Method)))
+        };
+
+        let descriptor = data.type_identifiers.get(class as usize).map_or("", |t| t.as_str());
+        let class = self.package(&java::package_name(descriptor)).child(java::type_name(descriptor), NodeKind::Class);
+        match member {
+            Some((name, kind)) => class.child(name, kind),
+            None => class
+        }
+    }
+
+    fn package(&mut self, package: &str) -> &mut Builder {
+        if package.is_empty() {
+            return self;
+        }
+        let mut node = self;
+        for (i, _) in package.match_indices('.').chain(std::iter::once((package.len(), ""))) {
+            node = node.child(package[.. i].to_string(), NodeKind::Package);
+        }
+        node
+    }
+
+    fn child(&mut self, name: String, kind: NodeKind) -> &mut Builder {
+        &mut self.children.entry(name).or_insert_with(|| (kind, Builder::default())).1
+    }
+
+    fn finish(self, name: String, kind: NodeKind) -> SizeNode {
+        let mut children = self.children.into_iter()
+            .map(|(name, (kind, builder))| builder.finish(name, kind))
+            .collect::<Vec<_>>();
+        children.sort_by_key(|child| Reverse(child.total.total()));
+
+        let mut total = self.own;
+        for child in &children {
+            total.add_all(&child.total);
+        }
+        SizeNode { name, kind, own: self.own, total, children }
+    }
+}
+
+// `(ILjava/lang/String;)V`
+fn descriptor(prototype: &Prototype) -> String {
+    format!("({}){}", prototype.parameters.iter().map(|p| p.as_str()).collect::<String>(), prototype.return_type)
+}
+
+impl Sizes {
+    pub fn total(&self) -> u32 {
+        self.code + self.debug_info + self.strings + self.annotations + self.class_data + self.encoded_arrays
+            + self.type_lists + self.ids + self.other
+    }
+
+    pub fn get(&self, category: Category) -> u32 {
+        match category {
+            Category::Code => self.code,
+            Category::DebugInfo => self.debug_info,
+            Category::Strings => self.strings,
+            Category::Annotations => self.annotations,
+            Category::ClassData => self.class_data,
+            Category::EncodedArrays => self.encoded_arrays,
+            Category::TypeLists => self.type_lists,
+            Category::Ids => self.ids,
+            Category::Other => self.other
+        }
+    }
+
+    fn add(&mut self, category: Category, size: u32) {
+        let field = match category {
+            Category::Code => &mut self.code,
+            Category::DebugInfo => &mut self.debug_info,
+            Category::Strings => &mut self.strings,
+            Category::Annotations => &mut self.annotations,
+            Category::ClassData => &mut self.class_data,
+            Category::EncodedArrays => &mut self.encoded_arrays,
+            Category::TypeLists => &mut self.type_lists,
+            Category::Ids => &mut self.ids,
+            Category::Other => &mut self.other
+        };
+        *field += size;
+    }
+
+    fn add_all(&mut self, other: &Sizes) {
+        for category in CATEGORIES.iter() {
+            self.add(*category, other.get(*category));
+        }
+    }
+}
+
+const CATEGORIES: [Category; 9] = [Category::Code, Category::DebugInfo, Category::Strings, Category::Annotations,
+    Category::ClassData, Category::EncodedArrays, Category::TypeLists, Category::Ids, Category::Other];
+
+impl SizeNode {
+    // The node at a path of names from this one, e.g. `["com", "com.example", "com.example.Main", "run()V"]`
+    pub fn find(&self, path: &[&str]) -> Option<&SizeNode> {
+        match path.split_first() {
+            Some((name, rest)) => self.children.iter().find(|child| child.name == *name)?.find(rest),
+            None => Some(self)
+        }
+    }
+}
+
+impl SizeReport {
+    // The tree down to the given depth, packages being one level each and the root none, with each node's total,
+    // its share of the file and the kinds of item that make it up
+    pub fn tree(&self, depth: usize) -> String {
+        let mut text = format!("{:<60}{:>10}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}\n", "", "bytes", "%", "code",
+                               "debug", "strings", "annots", "other");
+        write_node(&mut text, &self.root, self.file_size, 0, depth);
+        text
+    }
+}
+
+fn write_node(text: &mut String, node: &SizeNode, file_size: u32, level: usize, depth: usize) {
+    let total = &node.total;
+    let name = if level == 0 { "<root>" } else { &node.name };
+    let rest = total.total() - total.code - total.debug_info - total.strings - total.annotations;
+    text.push_str(&format!("{:<60}{:>10}{:>7.1}%{:>10}{:>10}{:>10}{:>10}{:>10}\n",
+                           format!("{}{}", "  ".repeat(level), name), total.total(),
+                           f64::from(total.total()) * 100.0 / f64::from(file_size.max(1)),
+                           total.code, total.debug_info, total.strings, total.annotations, rest));
+    if level < depth {
+        for child in &node.children {
+            write_node(text, child, file_size, level + 1, depth);
+        }
+    }
+}
+
+// The whole tree, down to fields and methods
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.tree(usize::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::builder::DexBuilder;
+
+    #[test]
+    fn test_attribute() {
+        let mut b = DexBuilder::new(35);
+        let main_type = b.type_("Lcom/example/Main;");
+        let util_type = b.type_("Lcom/example/util/Util;");
+        let run = b.method("Lcom/example/Main;", "run", "V", &[]);
+        let helper = b.method("Lcom/example/util/Util;", "help", "V", &[]);
+        let count = b.field("Lcom/example/Main;", "count", "I");
+        b.string("only run uses this");
+        let code = |insns: Vec<u16>| Some(Code { registers_size: 1, ins_size: 0, outs_size: 0, debug_info: None,
+                                                 insns, tries: vec!(), handlers: vec!() });
+        let method = |method: Rc<Method>, insns: Vec<u16>| EncodedMethod {
+            method, access_flags: vec!(AccessFlag::ACC_STATIC), code: code(insns),
+            system_annotations: Default::default()
+        };
+        let class = |class_type, data| ClassDefinition {
+            class_type, access_flags: vec!(AccessFlag::ACC_PUBLIC), superclass: None, interfaces: vec!(),
+            source_file_name: None, annotations: None, class_data: Some(data), static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        b.class(class(main_type, ClassData {
+            static_fields: vec!(EncodedField { field: count, access_flags: vec!(AccessFlag::ACC_STATIC) }),
+            instance_fields: vec!(), direct_methods: vec!(method(run, vec!(0x000E))), virtual_methods: vec!()
+        }));
+        b.class(class(util_type, ClassData {
+            static_fields: vec!(), instance_fields: vec!(), direct_methods: vec!(method(helper, vec!(0x000E))),
+            virtual_methods: vec!()
+        }));
+        let mut file = b.build().unwrap();
+
+        // run loads the string and calls the helper
+        let string = file.file_data.string_data.iter().position(|s| s.as_str() == "only run uses this").unwrap();
+        let help = file.file_data.methods.iter().position(|m| m.name.as_str() == "help").unwrap();
+        let run = &mut file.classes.iter_mut().find(|c| c.class_type.as_str() == "Lcom/example/Main;").unwrap()
+            .class_data.as_mut().unwrap().direct_methods[0];
+        run.code = code(vec!(0x001A, string as u16, 0x0071, help as u16, 0x0000, 0x000E));
+        let bytes = crate::write(&file).unwrap();
+
+        let report = attribute(&bytes).unwrap();
+        assert_eq!(report.file_size, bytes.len() as u32);
+        assert_eq!(report.root.total.total(), bytes.len() as u32);
+        assert!(report.root.own.other >= 0x70);
+
+        let main = report.root.find(&["com", "com.example", "com.example.Main"]).unwrap();
+        assert_eq!(main.kind, NodeKind::Class);
+        let run = main.find(&["run()V"]).unwrap();
+        assert_eq!(run.kind, NodeKind::Method);
+        // the code item: 16 bytes of header and six code units
+        assert_eq!(run.own.code, 28);
+        // the method_id, and the string_ids of its name and the string only it loads
+        assert_eq!(run.own.ids, 8 + 4 + 4);
+        assert_eq!(run.own.strings, (1 + 3 + 1) + (1 + "only run uses this".len() as u32 + 1));
+        // the field_id, the string_id of its name, and the type_id and string_id of `I`
+        assert_eq!(main.find(&["count:I"]).unwrap().own.ids, 8 + 4 + 4 + 4);
+        // the class def and its class data
+        assert_eq!(main.own.ids, 32 + 4 + 4);
+        assert!(main.own.class_data > 0);
+
+        // the helper's method_id is used by both classes, so it belongs to the package holding both along with its
+        // name and the type of its class. So does the prototype of both methods, with the type and shorty `V`
+        let helper = report.root.find(&["com", "com.example", "com.example.util", "com.example.util.Util", "help()V"])
+            .unwrap();
+        assert_eq!(helper.own.ids, 0);
+        assert_eq!(report.root.find(&["com", "com.example"]).unwrap().own.ids, (8 + 4) + (4 + 4) + (12 + 4 + 4));
+
+        let tree = report.tree(2);
+        assert!(tree.lines().nth(1).unwrap().starts_with("<root>"));
+        assert!(tree.contains("\n    com.example "));
+        assert!(!tree.contains("com.example.Main"));
+    }
+
+    // Two classes of com.x, each with a method loading the same string and with the same debug info and class
+    // annotation
+    fn two_classes() -> Vec<u8> {
+        let mut b = DexBuilder::new(35);
+        b.string("shared");
+        let tag = b.type_("Lcom/x/Tag;");
+        let value = b.string("value");
+        for (class_type, name) in [("Lcom/x/A;", "f"), ("Lcom/x/B;", "g")] {
+            let method = b.method(class_type, name, "V", &[]);
+            let code = Code {
+                registers_size: 1, ins_size: 0, outs_size: 0,
+                debug_info: Some(DebugInfo { line_start: 1, parameter_names: vec!(), bytecode: vec!() }),
+                insns: vec!(), tries: vec!(), handlers: vec!()
+            };
+            let annotation = ClassAnnotation {
+                visibility: Visibility::RUNTIME, type_: tag.clone(),
+                elements: vec!(AnnotationElement { name: value.clone(), value: EncodedValue::Int(1) })
+            };
+            let class_type = b.type_(class_type);
+            b.class(ClassDefinition {
+                class_type, access_flags: vec!(AccessFlag::ACC_PUBLIC), superclass: None, interfaces: vec!(),
+                source_file_name: None,
+                annotations: Some(Annotations { class_annotations: vec!(annotation), field_annotations: vec!(),
+                                                method_annotations: vec!(), parameter_annotations: vec!() }),
+                class_data: Some(ClassData {
+                    static_fields: vec!(), instance_fields: vec!(), virtual_methods: vec!(),
+                    direct_methods: vec!(EncodedMethod {
+                        method, access_flags: vec!(AccessFlag::ACC_STATIC), code: Some(code),
+                        system_annotations: Default::default()
+                    })
+                }),
+                static_values: vec!(), system_annotations: Default::default()
+            });
+        }
+        let mut file = b.build().unwrap();
+
+        // const-string v0, "shared"; return-void
+        let shared = file.file_data.string_data.iter().position(|s| s.as_str() == "shared").unwrap() as u16;
+        for class in &mut file.classes {
+            let code = class.class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap();
+            code.insns = vec!(0x001A, shared, 0x000E, 0x0000);
+        }
+        crate::write(&file).unwrap()
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    // The offset of each item of the map list section of the given type, for items of a fixed size
+    fn items(bytes: &[u8], type_: u16, size: usize) -> Vec<usize> {
+        let map = u32_at(bytes, 0x34) as usize;
+        let entry = (0 .. u32_at(bytes, map) as usize).map(|i| map + 4 + i * 12)
+            .find(|&entry| u16::from_le_bytes([bytes[entry], bytes[entry + 1]]) == type_)
+            .unwrap();
+        let start = u32_at(bytes, entry + 8) as usize;
+        (0 .. u32_at(bytes, entry + 4) as usize).map(|i| start + i * size).collect()
+    }
+
+    // Each node's total is its own bytes and its children's, in every category
+    fn check_totals(node: &SizeNode) {
+        let mut total = node.own;
+        for child in &node.children {
+            check_totals(child);
+            total.add_all(&child.total);
+        }
+        assert_eq!(node.total, total, "{}", node.name);
+    }
+
+    #[test]
+    fn test_totals() {
+        let bytes = two_classes();
+        let report = attribute(&bytes).unwrap();
+        check_totals(&report.root);
+        assert_eq!(report.root.total.total(), bytes.len() as u32);
+
+        // the classes and what the packages have of their own add up to the file
+        let package = report.root.find(&["com", "com.x"]).unwrap();
+        let classes = package.children.iter().map(|class| class.total.total()).sum::<u32>();
+        assert_eq!(classes + package.own.total() + report.root.find(&["com"]).unwrap().own.total()
+                       + report.root.own.total(), bytes.len() as u32);
+
+        // each section is attributed whole
+        let file = crate::parse(&bytes).unwrap();
+        let data = &file.file_data;
+        let ids = (data.string_data.len() + data.type_identifiers.len()) * 4 + data.prototypes.len() * 12
+            + (data.fields.len() + data.methods.len()) * 8 + file.classes.len() * 32;
+        assert_eq!(report.root.total.ids, ids as u32);
+        // all the strings are short and ASCII: a byte of length, the string and a NUL
+        let strings = data.string_data.iter().map(|s| s.len() as u32 + 2).sum::<u32>();
+        assert_eq!(report.root.total.strings, strings);
+        // two code items of 16 bytes of header and four code units
+        assert_eq!(report.root.total.code, 2 * (16 + 8));
+    }
+
+    #[test]
+    fn test_shared() {
+        let bytes = two_classes();
+        let report = attribute(&bytes).unwrap();
+        let (a, b) = (&["com", "com.x", "com.x.A"][..], &["com", "com.x", "com.x.B"][..]);
+        let package = report.root.find(&["com", "com.x"]).unwrap();
+        // the string both methods load is the package's, the debug info and annotations each class's own
+        let shared = "shared".len() as u32 + 2;
+        assert!(package.own.strings >= shared);
+        // the class has only its descriptor
+        assert_eq!(report.root.find(a).unwrap().own.strings, 1 + "Lcom/x/A;".len() as u32 + 1);
+        let f = report.root.find(&["com", "com.x", "com.x.A", "f()V"]).unwrap();
+        // only its name
+        assert_eq!(f.own.strings, 1 + 1 + 1);
+        let debug_info = f.own.debug_info;
+        assert!(debug_info > 0);
+        let annotations = report.root.find(a).unwrap().own.annotations;
+        assert_eq!(report.root.find(b).unwrap().own.annotations, annotations);
+        assert_eq!(package.own.debug_info + package.own.annotations, 0);
+
+        // B's method uses A's debug info and B's annotation set A's annotation, leaving B's own behind
+        let mut bytes = bytes;
+        let code = items(&bytes, 0x2001, 16 + 8);
+        let debug_info_off = u32_at(&bytes, code[0] + 8);
+        bytes[code[1] + 8 .. code[1] + 12].copy_from_slice(&debug_info_off.to_le_bytes());
+        let sets = items(&bytes, 0x1003, 8);
+        let annotation_off = u32_at(&bytes, sets[0] + 4);
+        let orphan = u32_at(&bytes, sets[1] + 4);
+        bytes[sets[1] + 4 .. sets[1] + 8].copy_from_slice(&annotation_off.to_le_bytes());
+        let annotation = orphan - annotation_off;
+
+        let report = attribute(&bytes).unwrap();
+        check_totals(&report.root);
+        assert_eq!(report.root.total.total(), bytes.len() as u32);
+        let package = report.root.find(&["com", "com.x"]).unwrap();
+        assert_eq!(package.own.debug_info, debug_info);
+        assert_eq!(package.own.annotations, annotation);
+        for class in [a, b] {
+            let class = report.root.find(class).unwrap();
+            assert_eq!(class.total.debug_info, 0);
+            assert_eq!(class.own.annotations, annotations - annotation);
+        }
+        // what nothing refers to any more goes to the root
+        assert_eq!((report.root.own.debug_info, report.root.own.annotations), (debug_info, annotation));
+    }
+}
@@ -0,0 +1,967 @@
+// Java source stubs: classes rendered as source that compiles against the classes it refers to, with every method
+// body replaced by `throw new RuntimeException("stub")`
+//
+// A stub has the package of its class, the imports it needs, annotations with their values, modifiers, generic
+// signatures, constant initializers from the static values and parameter names from the debug info. Member classes
+// are nested inside the class declaring them. Synthetic and bridge members are left out, as are the members javac
+// generates for enums, since declaring them again would not compile. Constructors call their superclass's first
+// when it has no constructor without parameters.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::java::{self, type_name};
+use crate::result_types::*;
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "assert", "boolean", "break", "byte", "case", "catch", "char", "class", "const", "continue",
+    "default", "do", "double", "else", "enum", "extends", "false", "final", "finally", "float", "for", "goto", "if",
+    "implements", "import", "instanceof", "int", "interface", "long", "native", "new", "null", "package", "private",
+    "protected", "public", "return", "short", "static", "strictfp", "super", "switch", "synchronized", "this",
+    "throw", "throws", "transient", "true", "try", "void", "volatile", "while", "_"
+];
+
+// The stubs of the top-level classes of a file, each with the path of its source file: `com/foo/Bar.java`
+pub fn stubs(file: &DexFile) -> Vec<(String, String)> {
+    file.classes.iter()
+        .filter(|class| is_top_level(class))
+        .map(|class| (format!("{}.java", type_name(&class.class_type).replace('.', "/")), stub(file, class)))
+        .collect()
+}
+
+// The stub of a class, with the member classes the file defines for it nested inside
+pub fn stub(file: &DexFile, class: &ClassDefinition) -> String {
+    let package_name = package(&class.class_type);
+    let package_classes = file.classes.iter()
+        .filter(|c| package(&c.class_type) == package_name)
+        .map(|c| java::simple_name(&c.class_type))
+        .collect();
+    let mut stub = Stub {
+        file,
+        imports: Imports { package: package_name.clone(), package_classes, names: HashMap::new(), imports: BTreeSet::new() },
+        claimed: vec!(class.class_type.clone()).into_iter().collect(),
+        rendered: vec!(class.class_type.clone()).into_iter().collect()
+    };
+    // the class's own name and those of its member classes hide any other class with the same simple name
+    stub.imports.name(&type_name(&class.class_type));
+    stub.claim_members(class);
+    let body = stub.class(class, false);
+
+    let mut source = String::new();
+    if !package_name.is_empty() {
+        source.push_str(&format!("package {};\n\n", package_name));
+    }
+    for import in &stub.imports.imports {
+        source.push_str(&format!("import {};\n", import));
+    }
+    if !stub.imports.imports.is_empty() {
+        source.push('\n');
+    }
+    for line in body {
+        source.push_str(&line);
+        source.push('\n');
+    }
+    source
+}
+
+// Anonymous and local classes have no source of their own, and member classes are part of their outer class
+fn is_top_level(class: &ClassDefinition) -> bool {
+    let system = &class.system_annotations;
+    system.inner_class.is_none() && system.enclosing_class.is_none() && system.enclosing_method.is_none()
+}
+
+fn package(descriptor: &str) -> String {
+    let name = type_name(descriptor);
+    match name.rfind('.') {
+        Some(i) => name[.. i].to_string(),
+        None => String::new()
+    }
+}
+
+// Decides how a stub refers to each class: by its simple name if it is in the same package, in java.lang or
+// imported, and by its qualified name when another class has taken the simple name
+struct Imports {
+    package: String,
+    // the simple names of the package's classes, which hide those of java.lang
+    package_classes: HashSet<String>,
+    // the simple names taken so far, with the qualified name of the class each stands for
+    names: HashMap<String, String>,
+    imports: BTreeSet<String>
+}
+
+impl Imports {
+    // The source name of a class given its binary name, `Map.Entry` for `java.util.Map$Entry`. The top-level class
+    // is what gets imported
+    fn name(&mut self, binary_name: &str) -> String {
+        let (package, name) = match binary_name.rfind('.') {
+            Some(i) => (&binary_name[.. i], &binary_name[i + 1 ..]),
+            None => ("", binary_name)
+        };
+        // a `$` at either end of a name, or two in a row, is part of the name rather than a nesting
+        let nested = !name.split('$').any(|part| part.is_empty());
+        let top = if nested { name.split('$').next().unwrap_or(name) } else { name };
+        let source_name = if nested { name.replace('$', ".") } else { name.to_string() };
+        let qualify = |name: &str| if package.is_empty() { name.to_string() } else { format!("{}.{}", package, name) };
+
+        let qualified_top = qualify(top);
+        match self.names.get(top) {
+            Some(owner) if *owner == qualified_top => return source_name,
+            Some(_) => return qualify(&source_name),
+            None => {}
+        }
+        if package == "java.lang" && self.package != "java.lang" && self.package_classes.contains(top) {
+            return qualify(&source_name);
+        }
+        if !package.is_empty() && package != self.package && package != "java.lang" {
+            self.imports.insert(qualified_top.clone());
+        }
+        self.names.insert(top.to_string(), qualified_top);
+        source_name
+    }
+
+    fn type_(&mut self, descriptor: &str) -> String {
+        let dimensions = descriptor.bytes().take_while(|&c| c == b'[').count();
+        let element = &descriptor[dimensions ..];
+        let name = if element.starts_with('L') { self.name(&type_name(element)) } else { type_name(element) };
+        name + &"[]".repeat(dimensions)
+    }
+}
+
+struct Stub<'a> {
+    file: &'a DexFile,
+    imports: Imports,
+    // the classes claimed and rendered so far, so that member classes listing each other don't nest forever
+    claimed: HashSet<Rc<String>>,
+    rendered: HashSet<Rc<String>>
+}
+
+impl<'a> Stub<'a> {
+    fn member_classes(&self, class: &ClassDefinition) -> Vec<&'a ClassDefinition> {
+        let file = self.file;
+        class.system_annotations.member_classes.iter()
+            .filter_map(|member| file.classes.iter().find(|c| c.class_type == *member))
+            .collect()
+    }
+
+    fn claim_members(&mut self, class: &ClassDefinition) {
+        for member in self.member_classes(class) {
+            if !self.claimed.insert(member.class_type.clone()) {
+                continue;
+            }
+            let simple_name = source_simple_name(member);
+            self.imports.names.entry(simple_name).or_insert_with(|| type_name(&member.class_type));
+            self.claim_members(member);
+        }
+    }
+
+    // The lines of a class declaration, without the indentation it is nested at
+    fn class(&mut self, class: &ClassDefinition, nested: bool) -> Vec<String> {
+        let mut lines = self.annotations(class_annotations(class));
+
+        // a member class has its source modifiers in its InnerClass annotation
+        let inner_class = class.system_annotations.inner_class.as_ref().filter(|_| nested);
+        let flags = inner_class.map(|i| &i.access_flags).unwrap_or(&class.access_flags);
+        let imports = &mut self.imports;
+        let header = java::class_declaration_with(class, flags, &source_simple_name(class), &mut |name| imports.name(name));
+        lines.push(format!("{} {{", header));
+
+        let is_enum = class.access_flags.contains(&AccessFlag::ACC_ENUM);
+        let outer_instance = nested && !is_static_member(class, flags);
+
+        let mut sections: Vec<Vec<String>> = vec!();
+        let empty = ClassData {
+            static_fields: vec!(), instance_fields: vec!(), direct_methods: vec!(), virtual_methods: vec!()
+        };
+        let class_data = class.class_data.as_ref().unwrap_or(&empty);
+
+        if is_enum {
+            let constants = class_data.static_fields.iter()
+                .filter(|f| f.access_flags.contains(&AccessFlag::ACC_ENUM))
+                .map(|f| f.field.name.to_string())
+                .collect::<Vec<_>>();
+            sections.push(vec!(format!("{};", constants.join(", "))));
+        }
+
+        let mut fields = vec!();
+        for (i, field) in class_data.static_fields.iter().enumerate() {
+            if !(is_synthetic(&field.access_flags) || (is_enum && field.access_flags.contains(&AccessFlag::ACC_ENUM))) {
+                fields.extend(self.field(class, field, class.static_values.get(i)));
+            }
+        }
+        for field in &class_data.instance_fields {
+            if !is_synthetic(&field.access_flags) {
+                fields.extend(self.field(class, field, None));
+            }
+        }
+        if !fields.is_empty() {
+            sections.push(fields);
+        }
+
+        for method in class_data.direct_methods.iter().chain(&class_data.virtual_methods) {
+            if !is_generated(method, is_enum) {
+                sections.push(self.method(class, method, outer_instance));
+            }
+        }
+
+        for member in self.member_classes(class) {
+            if !self.rendered.insert(member.class_type.clone()) {
+                continue;
+            }
+            sections.push(self.class(member, true));
+        }
+
+        for (i, section) in sections.iter().enumerate() {
+            if i > 0 {
+                lines.push(String::new());
+            }
+            lines.extend(section.iter().map(|line| if line.is_empty() { String::new() } else { format!("    {}", line) }));
+        }
+        lines.push("}".to_string());
+        lines
+    }
+
+    fn field(&mut self, class: &ClassDefinition, field: &EncodedField, value: Option<&EncodedValue>) -> Vec<String> {
+        let annotations = class.annotations.as_ref()
+            .and_then(|a| a.field_annotations.iter().find(|a| *a.field_data == *field.field))
+            .map(|a| a.annotations.as_slice())
+            .unwrap_or(&[]);
+        let mut lines = self.annotations(items(annotations));
+
+        let imports = &mut self.imports;
+        let type_ = java::field_signature(class, &field.field)
+            .and_then(|s| java::parse_field_signature_with(&s, &mut |name| imports.name(name)))
+            .unwrap_or_else(|| self.imports.type_(&field.field.type_));
+        let mut declaration = java::modifiers(&field.access_flags);
+        if !declaration.is_empty() {
+            declaration.push(' ');
+        }
+        declaration.push_str(&format!("{} {}", type_, field.field.name));
+
+        // a final field must be initialized, and the constructors that would have done it only throw
+        let initializer = value.and_then(|v| self.value(v));
+        match initializer {
+            Some(initializer) => declaration.push_str(&format!(" = {}", initializer)),
+            None if field.access_flags.contains(&AccessFlag::ACC_FINAL) => {
+                declaration.push_str(&format!(" = {}", default_value(&field.field.type_)));
+            },
+            None => {}
+        }
+        declaration.push(';');
+        lines.push(declaration);
+        lines
+    }
+
+    fn method(&mut self, class: &ClassDefinition, method: &EncodedMethod, outer_instance: bool) -> Vec<String> {
+        let m = &method.method;
+        let annotations = self.method_annotations(class, m);
+        let mut lines = self.annotations(items(annotations));
+
+        let is_constructor = java::is_constructor(m);
+        let is_interface = class.access_flags.contains(&AccessFlag::ACC_INTERFACE);
+        let mut declaration = java::modifiers(&method.access_flags).split(' ')
+            .filter(|&word| !(word.is_empty() || (is_interface && word == "abstract")))
+            .map(|word| format!("{} ", word))
+            .collect::<String>();
+        if java::is_default_method(class, method) {
+            declaration.push_str("default ");
+        }
+
+        // the constructor of an inner class takes the outer instance first, which the source leaves implicit
+        let skipped = if is_constructor && outer_instance && !m.prototype.parameters.is_empty() { 1 } else { 0 };
+        let descriptors = &m.prototype.parameters[skipped ..];
+
+        let imports = &mut self.imports;
+        let signature = method.system_annotations.signature.as_ref()
+            .and_then(|s| java::parse_method_signature_with(s, &mut |name| imports.name(name)))
+            .filter(|s| s.parameters.len() == descriptors.len());
+        let (mut types, return_type, mut throws) = match signature {
+            Some(signature) => {
+                if !signature.type_parameters.is_empty() {
+                    declaration.push_str(&signature.type_parameters);
+                    declaration.push(' ');
+                }
+                (signature.parameters, signature.return_type, signature.throws)
+            },
+            None => {
+                let types = descriptors.iter().map(|p| self.imports.type_(p)).collect();
+                (types, self.imports.type_(&m.prototype.return_type), vec!())
+            }
+        };
+        if throws.is_empty() {
+            throws = method.system_annotations.throws.iter().map(|t| self.imports.type_(t)).collect();
+        }
+        if method.access_flags.contains(&AccessFlag::ACC_VARARGS) {
+            if let Some(last) = types.last_mut().filter(|t| t.ends_with("[]")) {
+                last.truncate(last.len() - 2);
+                last.push_str("...");
+            }
+        }
+
+        let names = parameter_names(method);
+        let parameter_annotations = class.annotations.as_ref()
+            .and_then(|a| a.parameter_annotations.iter().find(|pa| *pa.method == **m))
+            .map(|pa| pa.annotations.as_slice())
+            .unwrap_or(&[]);
+        // javac leaves the implicit parameters out of the parameter annotations
+        let annotated_from = m.prototype.parameters.len().saturating_sub(parameter_annotations.len());
+        let mut parameters = vec!();
+        for (i, type_) in types.iter().enumerate() {
+            let index = i + skipped;
+            let annotations = index.checked_sub(annotated_from)
+                .and_then(|i| parameter_annotations.get(i))
+                .map(|a| a.as_slice())
+                .unwrap_or(&[]);
+            let mut parameter = self.annotations(items(annotations))
+                .into_iter()
+                .map(|a| a + " ")
+                .collect::<String>();
+            parameter.push_str(&format!("{} {}", type_, names[index]));
+            parameters.push(parameter);
+        }
+
+        if is_constructor {
+            declaration.push_str(&source_simple_name(class));
+        } else {
+            declaration.push_str(&format!("{} {}", return_type, m.name));
+        }
+        declaration.push_str(&format!("({})", parameters.join(", ")));
+        if !throws.is_empty() {
+            declaration.push_str(&format!(" throws {}", throws.join(", ")));
+        }
+
+        let has_body = !method.access_flags.iter().any(|f| matches!(f, AccessFlag::ACC_ABSTRACT | AccessFlag::ACC_NATIVE));
+        if has_body {
+            lines.push(format!("{} {{", declaration));
+            if let Some(call) = self.super_call(class).filter(|_| is_constructor) {
+                lines.push(format!("    {};", call));
+            }
+            let exception = self.imports.name("java.lang.RuntimeException");
+            lines.push(format!("    throw new {}(\"stub\");", exception));
+            lines.push("}".to_string());
+        } else {
+            if let Some(default) = method.system_annotations.annotation_default.as_ref().and_then(|d| self.value(d)) {
+                declaration.push_str(&format!(" default {}", default));
+            }
+            declaration.push(';');
+            lines.push(declaration);
+        }
+        lines
+    }
+
+    // A constructor has to call one of its superclass's, which it only does implicitly if there's one without
+    // parameters. Superclasses the file doesn't define are assumed to have one. The arguments are cast so that
+    // they pick out a single constructor
+    fn super_call(&mut self, class: &ClassDefinition) -> Option<String> {
+        let file = self.file;
+        let superclass = class.superclass.as_ref().and_then(|s| file.classes.iter().find(|c| c.class_type == *s))?;
+        let skipped = match superclass.system_annotations.inner_class {
+            Some(ref inner) if inner.name.is_some() && !is_static_member(superclass, &inner.access_flags) => 1,
+            _ => 0
+        };
+        let parameters = superclass.class_data.as_ref()?.direct_methods.iter()
+            .filter(|m| java::is_constructor(&m.method) && !is_synthetic(&m.access_flags)
+                && !m.access_flags.contains(&AccessFlag::ACC_PRIVATE))
+            .map(|m| m.method.prototype.parameters.get(skipped ..).unwrap_or(&[]))
+            .min_by_key(|parameters| parameters.len())?;
+        if parameters.is_empty() {
+            return None;
+        }
+        let arguments = parameters.iter()
+            .map(|p| format!("({}) {}", self.imports.type_(p), default_value(p)))
+            .collect::<Vec<_>>();
+        Some(format!("super({})", arguments.join(", ")))
+    }
+
+    fn method_annotations(&self, class: &'a ClassDefinition, method: &Method) -> &'a [AnnotationItem] {
+        class.annotations.as_ref()
+            .and_then(|a| a.method_annotations.iter().find(|ma| *ma.method == *method))
+            .map(|ma| ma.annotations.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // One line per annotation the class file would have had: the system annotations are not written in source
+    fn annotations<'b, I>(&mut self, annotations: I) -> Vec<String>
+        where I: IntoIterator<Item = (&'b Visibility, &'b Rc<String>, &'b [AnnotationElement])> {
+        annotations.into_iter()
+            .filter(|(visibility, _, _)| **visibility != Visibility::SYSTEM)
+            .map(|(_, type_, elements)| self.annotation(type_, elements))
+            .collect()
+    }
+
+    fn annotation(&mut self, type_: &str, elements: &[AnnotationElement]) -> String {
+        let name = self.imports.type_(type_);
+        match elements {
+            [] => format!("@{}", name),
+            [element] if *element.name == "value" => format!("@{}({})", name, self.element_value(&element.value)),
+            _ => {
+                let elements = elements.iter()
+                    .map(|e| format!("{} = {}", e.name, self.element_value(&e.value)))
+                    .collect::<Vec<_>>();
+                format!("@{}({})", name, elements.join(", "))
+            }
+        }
+    }
+
+    fn element_value(&mut self, value: &EncodedValue) -> String {
+        self.value(value).unwrap_or_else(|| "null".to_string())
+    }
+
+    // A value as a Java expression, or None for the values that have no source form
+    fn value(&mut self, value: &EncodedValue) -> Option<String> {
+        Some(match value {
+            EncodedValue::Byte(b) => (*b as i8).to_string(),
+            EncodedValue::Short(s) => s.to_string(),
+            EncodedValue::Char(c) => format!("'{}'", escape(&[*c], '\'')),
+            EncodedValue::Int(i) => i.to_string(),
+            EncodedValue::Long(l) => format!("{}L", l),
+            EncodedValue::Float(f) if f.is_nan() => "0.0f / 0.0f".to_string(),
+            EncodedValue::Float(f) if f.is_infinite() => format!("{}1.0f / 0.0f", if *f < 0.0 { "-" } else { "" }),
+            EncodedValue::Float(f) => format!("{:?}f", f),
+            EncodedValue::Double(d) if d.is_nan() => "0.0 / 0.0".to_string(),
+            EncodedValue::Double(d) if d.is_infinite() => format!("{}1.0 / 0.0", if *d < 0.0 { "-" } else { "" }),
+            EncodedValue::Double(d) => format!("{:?}", d),
+            EncodedValue::String(s) => format!("\"{}\"", escape(&s.encode_utf16().collect::<Vec<_>>(), '"')),
+            EncodedValue::Type(t) => format!("{}.class", self.imports.type_(t)),
+            EncodedValue::Enum(f) => format!("{}.{}", self.imports.type_(&f.definer), f.name),
+            EncodedValue::Array(values) => {
+                let values = values.iter().map(|v| self.element_value(v)).collect::<Vec<_>>();
+                format!("{{{}}}", values.join(", "))
+            },
+            EncodedValue::Annotation(a) => self.annotation(&a.type_, &a.values),
+            EncodedValue::Null => "null".to_string(),
+            EncodedValue::Boolean(b) => b.to_string(),
+            EncodedValue::MethodType(_) | EncodedValue::MethodHandle(_)
+                | EncodedValue::Field(_) | EncodedValue::Method(_) => return None
+        })
+    }
+}
+
+fn items(annotations: &[AnnotationItem]) -> Vec<(&Visibility, &Rc<String>, &[AnnotationElement])> {
+    annotations.iter().map(|a| (&a.visibility, &a.type_, a.annotations.as_slice())).collect()
+}
+
+fn class_annotations(class: &ClassDefinition) -> Vec<(&Visibility, &Rc<String>, &[AnnotationElement])> {
+    class.annotations.as_ref()
+        .map(|a| a.class_annotations.iter().map(|a| (&a.visibility, &a.type_, a.elements.as_slice())).collect())
+        .unwrap_or_default()
+}
+
+// The name a class is declared with in source: a member class's own name rather than its binary name
+fn source_simple_name(class: &ClassDefinition) -> String {
+    match class.system_annotations.inner_class.as_ref().and_then(|i| i.name.as_ref()) {
+        Some(name) => name.to_string(),
+        None => java::simple_name(&class.class_type)
+    }
+}
+
+// Interfaces, enums and static classes have no outer instance
+fn is_static_member(class: &ClassDefinition, flags: &[AccessFlag]) -> bool {
+    [AccessFlag::ACC_INTERFACE, AccessFlag::ACC_ENUM, AccessFlag::ACC_STATIC].iter()
+        .any(|flag| flags.contains(flag) || class.access_flags.contains(flag))
+}
+
+fn is_synthetic(flags: &[AccessFlag]) -> bool {
+    flags.iter().any(|f| matches!(f, AccessFlag::ACC_SYNTHETIC | AccessFlag::ACC_BRIDGE))
+}
+
+// Members the compiler adds that the source doesn't declare: the static initializer, and for an enum its
+// constructors and the values() and valueOf(String) methods
+fn is_generated(method: &EncodedMethod, is_enum: bool) -> bool {
+    let m = &method.method;
+    if is_synthetic(&method.access_flags) || *m.name == "<clinit>" {
+        return true;
+    }
+    is_enum && (java::is_constructor(m)
+        || (*m.name == "values" && m.prototype.parameters.is_empty())
+        || (*m.name == "valueOf" && m.prototype.parameters.len() == 1 && *m.prototype.parameters[0] == "Ljava/lang/String;"))
+}
+
+// The names of a method's parameters from its debug info, else from its MethodParameters annotation, else `argN`.
+// A name that isn't a usable identifier is replaced the same way
+fn parameter_names(method: &EncodedMethod) -> Vec<String> {
+    let count = method.method.prototype.parameters.len();
+    let debug_names = method.code.as_ref()
+        .and_then(|c| c.debug_info.as_ref())
+        .map(|d| d.parameter_names.as_slice())
+        .unwrap_or(&[]);
+    let mut names: Vec<String> = vec!();
+    for i in 0 .. count {
+        let name = debug_names.get(i).cloned().flatten()
+            .or_else(|| method.system_annotations.parameters.get(i).and_then(|p| p.name.clone()))
+            .map(|name| name.to_string())
+            .filter(|name| is_identifier(name) && !names.contains(name))
+            .unwrap_or_else(|| format!("arg{}", i));
+        names.push(name);
+    }
+    names
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == '$' => {},
+        _ => return false
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$') && !KEYWORDS.contains(&name)
+}
+
+fn default_value(descriptor: &str) -> &'static str {
+    match descriptor {
+        "Z" => "false",
+        "B" | "S" | "I" => "0",
+        "C" => "'\\0'",
+        "J" => "0L",
+        "F" => "0.0f",
+        "D" => "0.0",
+        _ => "null"
+    }
+}
+
+// Escapes for a char or string literal. Unicode escapes are translated before the source is even tokenized, so
+// control characters get octal escapes instead
+fn escape(units: &[u16], quote: char) -> String {
+    let mut escaped = String::new();
+    for &unit in units {
+        match unit {
+            0x08 => escaped.push_str("\\b"),
+            0x09 => escaped.push_str("\\t"),
+            0x0a => escaped.push_str("\\n"),
+            0x0c => escaped.push_str("\\f"),
+            0x0d => escaped.push_str("\\r"),
+            0x5c => escaped.push_str("\\\\"),
+            _ if unit == quote as u16 => {
+                escaped.push('\\');
+                escaped.push(quote);
+            },
+            0x00 ..= 0x1f | 0x7f => escaped.push_str(&format!("\\{:03o}", unit)),
+            0x20 ..= 0x7e => escaped.push(unit as u8 as char),
+            _ => escaped.push_str(&format!("\\u{:04x}", unit))
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn item(b: &mut DexBuilder, visibility: Visibility, type_: &str, elements: Vec<(&str, EncodedValue)>) -> AnnotationItem {
+        AnnotationItem {
+            visibility,
+            type_: b.type_(type_),
+            annotations: elements.into_iter().map(|(name, value)| AnnotationElement { name: b.string(name), value }).collect()
+        }
+    }
+
+    fn class_annotation(b: &mut DexBuilder, visibility: Visibility, type_: &str, elements: Vec<(&str, EncodedValue)>) -> ClassAnnotation {
+        let item = item(b, visibility, type_, elements);
+        ClassAnnotation { visibility: item.visibility, type_: item.type_, elements: item.annotations }
+    }
+
+    fn strings(b: &mut DexBuilder, parts: &[&str]) -> EncodedValue {
+        EncodedValue::Array(parts.iter().map(|p| EncodedValue::String(b.string(p))).collect())
+    }
+
+    // the InnerClass and EnclosingClass annotations of a member class of com.foo.Widget
+    fn member(b: &mut DexBuilder, name: &str, flags: i32) -> Vec<ClassAnnotation> {
+        let name = EncodedValue::String(b.string(name));
+        let enclosing = EncodedValue::Type(b.type_("Lcom/foo/Widget;"));
+        vec!(
+            class_annotation(b, Visibility::SYSTEM, "Ldalvik/annotation/InnerClass;", vec!(("accessFlags", EncodedValue::Int(flags)), ("name", name))),
+            class_annotation(b, Visibility::SYSTEM, "Ldalvik/annotation/EnclosingClass;", vec!(("value", enclosing)))
+        )
+    }
+
+    fn class(b: &mut DexBuilder, class_type: &str, access_flags: Vec<AccessFlag>, superclass: &str, interfaces: &[&str],
+             annotations: Annotations, class_data: ClassData) -> ClassDefinition {
+        ClassDefinition {
+            class_type: b.type_(class_type),
+            access_flags,
+            superclass: Some(b.type_(superclass)),
+            interfaces: interfaces.iter().map(|i| b.type_(i)).collect(),
+            source_file_name: None,
+            annotations: Some(annotations),
+            class_data: Some(class_data),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        }
+    }
+
+    fn annotations(class_annotations: Vec<ClassAnnotation>) -> Annotations {
+        Annotations { class_annotations, field_annotations: vec!(), method_annotations: vec!(), parameter_annotations: vec!() }
+    }
+
+    fn field(field: &Rc<Field>, access_flags: Vec<AccessFlag>) -> EncodedField {
+        EncodedField { field: field.clone(), access_flags }
+    }
+
+    fn method(method: &Rc<Method>, access_flags: Vec<AccessFlag>, names: Option<Vec<Option<Rc<String>>>>) -> EncodedMethod {
+        let code = names.map(|names| Code {
+            registers_size: 4,
+            ins_size: 4,
+            outs_size: 0,
+            debug_info: Some(DebugInfo { line_start: 1, parameter_names: names, bytecode: vec!() }),
+            insns: vec!(0x000e),
+            tries: vec!(),
+            handlers: vec!()
+        });
+        EncodedMethod { method: method.clone(), access_flags, code, system_annotations: Default::default() }
+    }
+
+    fn file() -> DexFile {
+        use AccessFlag::*;
+        let mut b = DexBuilder::new(35);
+        let color = "Lcom/foo/Widget$Color;";
+
+        // com.foo.Widget, with a generic signature, constants, annotations and two member classes
+        let empty = b.field("Lcom/foo/Widget;", "EMPTY", "J");
+        let max = b.field("Lcom/foo/Widget;", "MAX", "I");
+        let name = b.field("Lcom/foo/Widget;", "NAME", "Ljava/lang/String;");
+        let verbose = b.field("Lcom/foo/Widget;", "VERBOSE", "Z");
+        let done = b.field("Lcom/foo/Widget;", "done", "Z");
+        let items = b.field("Lcom/foo/Widget;", "items", "Ljava/util/List;");
+        let init = b.method("Lcom/foo/Widget;", "<init>", "V", &["I"]);
+        let clinit = b.method("Lcom/foo/Widget;", "<clinit>", "V", &[]);
+        let join = b.method("Lcom/foo/Widget;", "join", "Ljava/lang/String;", &["[Ljava/lang/String;"]);
+        let lambda = b.method("Lcom/foo/Widget;", "lambda$join$0", "V", &[]);
+        let first = b.method("Lcom/foo/Widget;", "first", "Ljava/lang/Object;", &["Ljava/util/List;"]);
+        let poke = b.method("Lcom/foo/Widget;", "poke", "V", &[]);
+
+        let class_signature = strings(&mut b, &["<T:", "Ljava/lang/Object;", ">", "Ljava/lang/Object;", "Ljava/util/RandomAccess;"]);
+        let members = EncodedValue::Array(vec!(EncodedValue::Type(b.type_(color)), EncodedValue::Type(b.type_("Lcom/foo/Widget$Inner;"))));
+        let tag_name = EncodedValue::String(b.string("w\"1"));
+        let red = EncodedValue::Enum(b.field(color, "RED", color));
+        let mut widget_annotations = annotations(vec!(
+            class_annotation(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/Signature;", vec!(("value", class_signature))),
+            class_annotation(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/MemberClasses;", vec!(("value", members))),
+            class_annotation(&mut b, Visibility::RUNTIME, "Ljava/lang/Deprecated;", vec!()),
+            class_annotation(&mut b, Visibility::RUNTIME, "Lcom/foo/Tag;", vec!(
+                ("name", tag_name),
+                ("sizes", EncodedValue::Array(vec!(EncodedValue::Int(1), EncodedValue::Int(-2)))),
+                ("color", red)
+            ))
+        ));
+        let items_signature = strings(&mut b, &["Ljava/util/List<", "TT;>;"]);
+        widget_annotations.field_annotations.push(FieldAnnotation {
+            field_data: items.clone(),
+            annotations: vec!(item(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/Signature;", vec!(("value", items_signature))))
+        });
+        let first_signature = strings(&mut b, &["<U:TT;>(", "Ljava/util/List<", "TU;>;)TU;"]);
+        let throws = EncodedValue::Array(vec!(EncodedValue::Type(b.type_("Ljava/io/IOException;"))));
+        widget_annotations.method_annotations.push(MethodAnnotation {
+            method: first.clone(),
+            annotations: vec!(
+                item(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/Signature;", vec!(("value", first_signature))),
+                item(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/Throws;", vec!(("value", throws))),
+                item(&mut b, Visibility::RUNTIME, "Ljava/lang/Deprecated;", vec!())
+            )
+        });
+        widget_annotations.parameter_annotations.push(ParameterAnnotation {
+            method: join.clone(),
+            annotations: vec!(vec!(item(&mut b, Visibility::RUNTIME, "Ljava/lang/Deprecated;", vec!())))
+        });
+        let count = b.string("count");
+        let list = b.string("list");
+        let name_value = EncodedValue::String(b.string("a\"b\n\u{e9}"));
+        let mut widget = class(&mut b, "Lcom/foo/Widget;", vec!(ACC_PUBLIC), "Ljava/lang/Object;", &["Ljava/util/RandomAccess;"],
+            widget_annotations,
+            ClassData {
+                static_fields: vec!(
+                    field(&empty, vec!(ACC_PUBLIC, ACC_STATIC, ACC_FINAL)),
+                    field(&max, vec!(ACC_PUBLIC, ACC_STATIC, ACC_FINAL)),
+                    field(&name, vec!(ACC_PUBLIC, ACC_STATIC, ACC_FINAL)),
+                    field(&verbose, vec!(ACC_STATIC, ACC_FINAL))
+                ),
+                instance_fields: vec!(field(&done, vec!(ACC_PRIVATE, ACC_FINAL)), field(&items, vec!(ACC_PRIVATE))),
+                direct_methods: vec!(
+                    method(&init, vec!(ACC_PUBLIC, ACC_CONSTRUCTOR), Some(vec!(Some(count)))),
+                    method(&clinit, vec!(ACC_STATIC, ACC_CONSTRUCTOR), Some(vec!())),
+                    method(&join, vec!(ACC_PUBLIC, ACC_STATIC, ACC_VARARGS), Some(vec!(None))),
+                    method(&lambda, vec!(ACC_PRIVATE, ACC_STATIC, ACC_SYNTHETIC), Some(vec!()))
+                ),
+                virtual_methods: vec!(
+                    method(&first, vec!(ACC_PUBLIC), Some(vec!(Some(list)))),
+                    method(&poke, vec!(ACC_PUBLIC, ACC_NATIVE), None)
+                )
+            }
+        );
+        widget.static_values = vec!(EncodedValue::Long(0), EncodedValue::Int(10), name_value);
+        b.class(widget);
+
+        // the enum com.foo.Widget.Color, with the members javac adds to every enum
+        let values = b.field(color, "$VALUES", "[Lcom/foo/Widget$Color;");
+        let green = b.field(color, "GREEN", color);
+        let red = b.field(color, "RED", color);
+        let color_init = b.method(color, "<init>", "V", &["Ljava/lang/String;", "I"]);
+        let values_method = b.method(color, "values", "[Lcom/foo/Widget$Color;", &[]);
+        let value_of = b.method(color, "valueOf", color, &["Ljava/lang/String;"]);
+        let color_signature = strings(&mut b, &["Ljava/lang/Enum<", color, ">;"]);
+        let mut color_annotations = member(&mut b, "Color", 0x4019);
+        color_annotations.push(class_annotation(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/Signature;", vec!(("value", color_signature))));
+        let color_class = class(&mut b, color, vec!(ACC_PUBLIC, ACC_FINAL, ACC_ENUM), "Ljava/lang/Enum;", &[],
+            annotations(color_annotations),
+            ClassData {
+                static_fields: vec!(
+                    field(&values, vec!(ACC_PRIVATE, ACC_STATIC, ACC_FINAL, ACC_SYNTHETIC)),
+                    field(&green, vec!(ACC_PUBLIC, ACC_STATIC, ACC_FINAL, ACC_ENUM)),
+                    field(&red, vec!(ACC_PUBLIC, ACC_STATIC, ACC_FINAL, ACC_ENUM))
+                ),
+                instance_fields: vec!(),
+                direct_methods: vec!(
+                    method(&color_init, vec!(ACC_PRIVATE, ACC_CONSTRUCTOR), Some(vec!(None, None))),
+                    method(&value_of, vec!(ACC_PUBLIC, ACC_STATIC), Some(vec!(None))),
+                    method(&values_method, vec!(ACC_PUBLIC, ACC_STATIC), Some(vec!()))
+                ),
+                virtual_methods: vec!()
+            }
+        );
+        b.class(color_class);
+
+        // the inner class com.foo.Widget.Inner, whose constructor takes the outer instance
+        let outer = b.field("Lcom/foo/Widget$Inner;", "this$0", "Lcom/foo/Widget;");
+        let inner_init = b.method("Lcom/foo/Widget$Inner;", "<init>", "V", &["Lcom/foo/Widget;", "I"]);
+        let size = b.method("Lcom/foo/Widget$Inner;", "size", "I", &[]);
+        let inner_annotations = annotations(member(&mut b, "Inner", 0x1));
+        let size_name = b.string("size");
+        let inner = class(&mut b, "Lcom/foo/Widget$Inner;", vec!(ACC_PUBLIC), "Ljava/lang/Object;", &[],
+            inner_annotations,
+            ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(field(&outer, vec!(ACC_FINAL, ACC_SYNTHETIC))),
+                direct_methods: vec!(method(&inner_init, vec!(ACC_PUBLIC, ACC_CONSTRUCTOR), Some(vec!(None, Some(size_name))))),
+                virtual_methods: vec!(method(&size, vec!(ACC_PUBLIC), Some(vec!())))
+            }
+        );
+        b.class(inner);
+
+        // the annotation type com.foo.Tag, with a default for one of its elements
+        let tag_name = b.method("Lcom/foo/Tag;", "name", "Ljava/lang/String;", &[]);
+        let tag_sizes = b.method("Lcom/foo/Tag;", "sizes", "[I", &[]);
+        let tag_color = b.method("Lcom/foo/Tag;", "color", color, &[]);
+        let runtime = EncodedValue::Enum(b.field("Ljava/lang/annotation/RetentionPolicy;", "RUNTIME", "Ljava/lang/annotation/RetentionPolicy;"));
+        let default = EncodedValue::Annotation(EncodedAnnotationItem {
+            type_: b.type_("Lcom/foo/Tag;"),
+            values: vec!(AnnotationElement { name: b.string("sizes"), value: EncodedValue::Array(vec!(EncodedValue::Int(1))) })
+        });
+        let tag_annotations = annotations(vec!(
+            class_annotation(&mut b, Visibility::RUNTIME, "Ljava/lang/annotation/Retention;", vec!(("value", runtime))),
+            class_annotation(&mut b, Visibility::SYSTEM, "Ldalvik/annotation/AnnotationDefault;", vec!(("value", default)))
+        ));
+        let abstract_ = || vec!(ACC_PUBLIC, ACC_ABSTRACT);
+        let tag = class(&mut b, "Lcom/foo/Tag;", vec!(ACC_PUBLIC, ACC_INTERFACE, ACC_ABSTRACT, ACC_ANNOTATION), "Ljava/lang/Object;",
+            &["Ljava/lang/annotation/Annotation;"],
+            tag_annotations,
+            ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(),
+                virtual_methods: vec!(method(&tag_name, abstract_(), None), method(&tag_sizes, abstract_(), None), method(&tag_color, abstract_(), None))
+            }
+        );
+        b.class(tag);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_stubs() {
+        let stubs = stubs(&file());
+        assert_eq!(stubs.len(), 2);
+        assert_eq!(stubs[0].0, "com/foo/Widget.java");
+        assert_eq!(stubs[0].1, r#"package com.foo;
+
+import java.io.IOException;
+import java.util.List;
+import java.util.RandomAccess;
+
+@Tag(color = Widget.Color.RED, name = "w\"1", sizes = {1, -2})
+@Deprecated
+public class Widget<T> implements RandomAccess {
+    public static final long EMPTY = 0L;
+    public static final int MAX = 10;
+    public static final String NAME = "a\"b\n\u00e9";
+    static final boolean VERBOSE = false;
+    private final boolean done = false;
+    private List<T> items;
+
+    public Widget(int count) {
+        throw new RuntimeException("stub");
+    }
+
+    public static String join(@Deprecated String... arg0) {
+        throw new RuntimeException("stub");
+    }
+
+    @Deprecated
+    public <U extends T> U first(List<U> list) throws IOException {
+        throw new RuntimeException("stub");
+    }
+
+    public native void poke();
+
+    public static enum Color {
+        GREEN, RED;
+    }
+
+    public class Inner {
+        public Inner(int size) {
+            throw new RuntimeException("stub");
+        }
+
+        public int size() {
+            throw new RuntimeException("stub");
+        }
+    }
+}
+"#);
+        assert_eq!(stubs[1].0, "com/foo/Tag.java");
+        assert_eq!(stubs[1].1, r#"package com.foo;
+
+import java.lang.annotation.Retention;
+import java.lang.annotation.RetentionPolicy;
+
+@Retention(RetentionPolicy.RUNTIME)
+public @interface Tag {
+    public Widget.Color color();
+
+    public String name();
+
+    public int[] sizes() default {1};
+}
+"#);
+    }
+
+    #[test]
+    fn test_imports() {
+        let mut imports = Imports {
+            package: "com.foo".to_string(),
+            package_classes: vec!("String".to_string()).into_iter().collect(),
+            names: HashMap::new(),
+            imports: BTreeSet::new()
+        };
+        assert_eq!(imports.type_("[Ljava/util/Map$Entry;"), "Map.Entry[]");
+        assert_eq!(imports.type_("Lcom/bar/Map;"), "com.bar.Map");
+        // a class of the package hides the java.lang class of the same name
+        assert_eq!(imports.type_("Ljava/lang/String;"), "java.lang.String");
+        assert_eq!(imports.type_("Ljava/lang/Object;"), "Object");
+        assert_eq!(imports.type_("Lcom/foo/Outer$$Lambda;"), "Outer$$Lambda");
+        assert_eq!(imports.imports.iter().collect::<Vec<_>>(), vec!("java.util.Map"));
+        assert_eq!(escape(&[0, 0x27, 0x7f, 0x2028], '\''), "\\000\\'\\177\\u2028");
+    }
+
+    // An abstract class whose only constructor takes parameters, a subclass of it and a subclass of a class the file
+    // doesn't define
+    fn hierarchy() -> DexFile {
+        use AccessFlag::*;
+        let mut b = DexBuilder::new(35);
+        let limit = b.field("Lcom/bar/Base;", "LIMIT", "I");
+        let count = b.field("Lcom/bar/Base;", "count", "I");
+        let base_init = b.method("Lcom/bar/Base;", "<init>", "V", &["Ljava/lang/String;", "C"]);
+        let clinit = b.method("Lcom/bar/Base;", "<clinit>", "V", &[]);
+        let shape = b.method("Lcom/bar/Base;", "shape", "Ljava/lang/Object;", &[]);
+        let now = b.method("Lcom/bar/Base;", "now", "J", &[]);
+        let mut base = class(&mut b, "Lcom/bar/Base;", vec!(ACC_PUBLIC, ACC_ABSTRACT), "Ljava/lang/Object;", &[],
+            annotations(vec!()),
+            ClassData {
+                static_fields: vec!(
+                    field(&limit, vec!(ACC_PUBLIC, ACC_STATIC, ACC_FINAL)),
+                    field(&count, vec!(ACC_STATIC))
+                ),
+                instance_fields: vec!(),
+                direct_methods: vec!(
+                    method(&base_init, vec!(ACC_PUBLIC, ACC_CONSTRUCTOR), None),
+                    method(&clinit, vec!(ACC_STATIC, ACC_CONSTRUCTOR), Some(vec!())),
+                    method(&now, vec!(ACC_PUBLIC, ACC_STATIC, ACC_NATIVE), None)
+                ),
+                virtual_methods: vec!(method(&shape, vec!(ACC_PUBLIC, ACC_ABSTRACT), None))
+            }
+        );
+        base.static_values = vec!(EncodedValue::Int(5), EncodedValue::Int(1));
+        b.class(base);
+
+        let derived_init = b.method("Lcom/bar/Derived;", "<init>", "V", &[]);
+        let returns = [("run", "V"), ("total", "J"), ("shape", "Ljava/lang/String;"), ("grid", "[[I")].iter()
+            .map(|(name, return_type)| b.method("Lcom/bar/Derived;", name, return_type, &[]))
+            .map(|m| method(&m, vec!(ACC_PUBLIC), None))
+            .collect();
+        let derived = class(&mut b, "Lcom/bar/Derived;", vec!(ACC_PUBLIC), "Lcom/bar/Base;", &[], annotations(vec!()),
+            ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(method(&derived_init, vec!(ACC_PUBLIC, ACC_CONSTRUCTOR), None)),
+                virtual_methods: returns
+            }
+        );
+        b.class(derived);
+
+        let worker_init = b.method("Lcom/bar/Worker;", "<init>", "V", &["I"]);
+        let worker = class(&mut b, "Lcom/bar/Worker;", vec!(ACC_PUBLIC), "Ljava/lang/Thread;", &[], annotations(vec!()),
+            ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(method(&worker_init, vec!(ACC_PUBLIC, ACC_CONSTRUCTOR), None)),
+                virtual_methods: vec!()
+            }
+        );
+        b.class(worker);
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_bodies() {
+        let stubs = stubs(&hierarchy());
+        let stub = |name: &str| stubs.iter().find(|(path, _)| path == name).map(|(_, source)| source.as_str()).unwrap();
+
+        // the static initializer is left out, and the abstract and native methods have no body
+        assert_eq!(stub("com/bar/Base.java"), r#"package com.bar;
+
+public abstract class Base {
+    public static final int LIMIT = 5;
+    static int count = 1;
+
+    public Base(String arg0, char arg1) {
+        throw new RuntimeException("stub");
+    }
+
+    public static native long now();
+
+    public abstract Object shape();
+}
+"#);
+        // every return type gets the same body, and the constructor has to call Base(String, char)
+        assert_eq!(stub("com/bar/Derived.java"), r#"package com.bar;
+
+public class Derived extends Base {
+    public Derived() {
+        super((String) null, (char) '\0');
+        throw new RuntimeException("stub");
+    }
+
+    public int[][] grid() {
+        throw new RuntimeException("stub");
+    }
+
+    public void run() {
+        throw new RuntimeException("stub");
+    }
+
+    public String shape() {
+        throw new RuntimeException("stub");
+    }
+
+    public long total() {
+        throw new RuntimeException("stub");
+    }
+}
+"#);
+        // Thread isn't in the file, so it is assumed to have a constructor without parameters
+        assert_eq!(stub("com/bar/Worker.java"), r#"package com.bar;
+
+public class Worker extends Thread {
+    public Worker(int arg0) {
+        throw new RuntimeException("stub");
+    }
+}
+"#);
+    }
+}
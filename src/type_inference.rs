@@ -0,0 +1,722 @@
+// Infers the type held by each register at each instruction of a method
+//
+// The registers start out with the method's arguments in the last ins_size registers, typed from its
+// prototype, and every other register undefined. From there each instruction's semantics give the type it
+// writes: constants, moves, the results of invokes and filled-new-array, field and array gets, check-cast,
+// new-instance and the arithmetic and conversion operations. Where paths meet, types join to the closest
+// type covering both, or to Conflict if there is none, much as ART's verifier does.
+
+use std::rc::Rc;
+
+use crate::cfg::{ControlFlowGraph, Edge, EdgeKind};
+use crate::dataflow::{self, Analysis, Direction};
+use crate::error::DexParserError;
+use crate::hierarchy::ClassHierarchy;
+use crate::instructions::{IndexKind, Instruction, Opcode};
+use crate::result_types::*;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RegisterType {
+    // nothing has been written to the register yet
+    Undefined,
+    // different paths leave incompatible types in the register
+    Conflict,
+    // the constant 0, which can be used as an int, a float, a boolean or null
+    Zero,
+    // any other 32-bit constant, used as either an int or a float
+    Constant,
+    Boolean,
+    Byte,
+    Short,
+    Char,
+    Integer,
+    Float,
+    // the halves of a 64-bit value: the low half is in the lower numbered register
+    LongLo,
+    LongHi,
+    DoubleLo,
+    DoubleHi,
+    ConstantLo,
+    ConstantHi,
+    // an instance of the class or array type with the given descriptor
+    Reference(Rc<String>),
+    // the result of a new-instance at the given offset, before its constructor has been called
+    Uninitialized(Rc<String>, u32),
+    // `this` in a constructor, before the superclass constructor has been called
+    UninitializedThis(Rc<String>)
+}
+
+impl RegisterType {
+    // The type of a value of the given descriptor, the low half for long and double
+    pub fn from_descriptor(descriptor: &Rc<String>) -> RegisterType {
+        match descriptor.as_bytes().first() {
+            Some(b'Z') => RegisterType::Boolean,
+            Some(b'B') => RegisterType::Byte,
+            Some(b'S') => RegisterType::Short,
+            Some(b'C') => RegisterType::Char,
+            Some(b'I') => RegisterType::Integer,
+            Some(b'F') => RegisterType::Float,
+            Some(b'J') => RegisterType::LongLo,
+            Some(b'D') => RegisterType::DoubleLo,
+            Some(b'L') | Some(b'[') => RegisterType::Reference(descriptor.clone()),
+            _ => RegisterType::Conflict
+        }
+    }
+
+    pub fn is_wide(&self) -> bool {
+        matches!(*self, RegisterType::LongLo | RegisterType::DoubleLo | RegisterType::ConstantLo)
+    }
+
+    // The type the second register of a wide value holds
+    pub fn high_half(&self) -> Option<RegisterType> {
+        match *self {
+            RegisterType::LongLo => Some(RegisterType::LongHi),
+            RegisterType::DoubleLo => Some(RegisterType::DoubleHi),
+            RegisterType::ConstantLo => Some(RegisterType::ConstantHi),
+            _ => None
+        }
+    }
+
+    pub fn is_high_half(&self) -> bool {
+        matches!(*self, RegisterType::LongHi | RegisterType::DoubleHi | RegisterType::ConstantHi)
+    }
+
+    // Types that fit in a single register and can be used as an int
+    pub fn is_integral(&self) -> bool {
+        matches!(*self, RegisterType::Zero | RegisterType::Constant | RegisterType::Boolean | RegisterType::Byte
+            | RegisterType::Short | RegisterType::Char | RegisterType::Integer)
+    }
+
+    // Types that can be used as an object reference, initialized or not; Zero counts as null
+    pub fn is_reference(&self) -> bool {
+        matches!(*self, RegisterType::Zero | RegisterType::Reference(_) | RegisterType::Uninitialized(..)
+            | RegisterType::UninitializedThis(_))
+    }
+}
+
+// The registers at some point in a method
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub registers: Vec<RegisterType>,
+    // the result of the invoke or filled-new-array just executed, for a move-result to pick up.
+    // A wide result is held as its low half
+    pub result: RegisterType,
+    // the exception just caught, for a move-exception to pick up
+    pub exception: RegisterType
+}
+
+impl Frame {
+    // The type of a register, or Undefined if there is no such register
+    pub fn get(&self, register: u16) -> RegisterType {
+        self.registers.get(register as usize).cloned().unwrap_or(RegisterType::Undefined)
+    }
+
+    // Writing to either half of a wide value destroys the other half
+    fn set(&mut self, register: u16, type_: RegisterType) {
+        let r = register as usize;
+        if r >= self.registers.len() {
+            return;
+        }
+        if self.registers[r].is_wide() {
+            if let Some(high) = self.registers.get_mut(r + 1) {
+                *high = RegisterType::Conflict;
+            }
+        }
+        if self.registers[r].is_high_half() && r > 0 {
+            self.registers[r - 1] = RegisterType::Conflict;
+        }
+        self.registers[r] = type_;
+    }
+
+    fn set_wide(&mut self, register: u16, low: RegisterType) {
+        let high = low.high_half().unwrap_or(RegisterType::Conflict);
+        self.set(register, low);
+        self.set(register.saturating_add(1), high);
+    }
+
+    fn set_any(&mut self, register: u16, type_: RegisterType) {
+        if type_.is_wide() {
+            self.set_wide(register, type_);
+        } else {
+            self.set(register, type_);
+        }
+    }
+}
+
+pub struct TypeInference<'a> {
+    file: &'a DexFileData,
+    method: &'a Method,
+    is_static: bool,
+    code: &'a Code,
+    hierarchy: Option<&'a ClassHierarchy<'a>>
+}
+
+// Register types before each instruction of a method
+#[derive(Debug)]
+pub struct InferredTypes {
+    pub cfg: ControlFlowGraph,
+    // in the order of cfg.instructions; the registers are all Undefined at unreachable instructions
+    pub frames: Vec<Frame>
+}
+
+impl InferredTypes {
+    // The registers before the instruction at the given address
+    pub fn frame_at(&self, offset: u32) -> Option<&Frame> {
+        self.cfg.instructions.binary_search_by_key(&offset, |insn| insn.offset).ok().map(|i| &self.frames[i])
+    }
+}
+
+impl<'a> TypeInference<'a> {
+    // None if the method has no code
+    pub fn new(file: &'a DexFile, method: &'a EncodedMethod) -> Option<Self> {
+        Some(TypeInference {
+            file: &file.file_data,
+            method: &method.method,
+            is_static: method.access_flags.contains(&AccessFlag::ACC_STATIC),
+            code: method.code.as_ref()?,
+            hierarchy: None
+        })
+    }
+
+    // Joins two different classes to their closest common superclass, rather than to java.lang.Object
+    pub fn with_hierarchy(mut self, hierarchy: &'a ClassHierarchy<'a>) -> Self {
+        self.hierarchy = Some(hierarchy);
+        self
+    }
+
+    pub fn run(&self) -> Result<InferredTypes, DexParserError> {
+        let cfg = ControlFlowGraph::new(self.code)?;
+        let result = dataflow::solve(&cfg, self);
+        let frames = result.instruction_states(&cfg, self);
+        Ok(InferredTypes { cfg, frames })
+    }
+
+    // The frame on entry: `this` and the parameters in the last ins_size registers
+    pub fn entry_frame(&self) -> Frame {
+        let mut frame = self.empty_frame();
+        let mut r = self.code.registers_size.saturating_sub(self.code.ins_size);
+        if !self.is_static {
+            let this = if *self.method.name == "<init>" && *self.method.definer != "Ljava/lang/Object;" {
+                RegisterType::UninitializedThis(self.method.definer.clone())
+            } else {
+                RegisterType::Reference(self.method.definer.clone())
+            };
+            frame.set(r, this);
+            r = r.saturating_add(1);
+        }
+        for parameter in &self.method.prototype.parameters {
+            let type_ = RegisterType::from_descriptor(parameter);
+            let width = if type_.is_wide() { 2 } else { 1 };
+            frame.set_any(r, type_);
+            r = r.saturating_add(width);
+        }
+        frame
+    }
+
+    fn empty_frame(&self) -> Frame {
+        Frame {
+            registers: vec!(RegisterType::Undefined; self.code.registers_size as usize),
+            result: RegisterType::Undefined,
+            exception: RegisterType::Undefined
+        }
+    }
+
+    fn pool_type(&self, insn: &Instruction) -> RegisterType {
+        insn.index.and_then(|idx| self.file.type_identifiers.get(idx as usize))
+            .map_or(RegisterType::Conflict, |type_| RegisterType::Reference(type_.clone()))
+    }
+
+    fn field_type(&self, insn: &Instruction) -> RegisterType {
+        insn.index.and_then(|idx| self.file.fields.get(idx as usize))
+            .map_or(RegisterType::Conflict, |field| RegisterType::from_descriptor(&field.type_))
+    }
+
+    // The type an invoke leaves for move-result, or Undefined for a void method
+    fn return_type(&self, insn: &Instruction) -> RegisterType {
+        let idx = insn.index.unwrap_or(0) as usize;
+        let prototype = match insn.opcode.index_kind() {
+            IndexKind::CallSite => self.file.call_sites.get(idx).map(|call_site| &call_site.method_type),
+            _ if insn.proto_index.is_some() => self.file.prototypes.get(insn.proto_index.unwrap_or(0) as usize),
+            _ => self.file.methods.get(idx).map(|method| &method.prototype)
+        };
+        match prototype {
+            Some(prototype) if *prototype.return_type == "V" => RegisterType::Undefined,
+            Some(prototype) => RegisterType::from_descriptor(&prototype.return_type),
+            None => RegisterType::Conflict
+        }
+    }
+
+    // The type of an element read from the array in the given register, if it is known
+    fn element_type(&self, frame: &Frame, register: u16) -> Option<RegisterType> {
+        match frame.get(register) {
+            RegisterType::Reference(ref array) if array.starts_with('[') => {
+                Some(RegisterType::from_descriptor(&Rc::new(array[1 ..].to_string())))
+            },
+            _ => None
+        }
+    }
+
+    fn join_types(&self, a: &RegisterType, b: &RegisterType) -> RegisterType {
+        use self::RegisterType::*;
+        match (a, b) {
+            _ if a == b => a.clone(),
+            (Undefined, other) | (other, Undefined) => other.clone(),
+            (Conflict, _) | (_, Conflict) => Conflict,
+            (Zero, other) | (other, Zero) if other.is_integral() || *other == Float || other.is_reference() => match other {
+                Constant => Constant,
+                Uninitialized(..) | UninitializedThis(_) => Conflict,
+                _ => other.clone()
+            },
+            (Constant, Float) | (Float, Constant) => Float,
+            (a, b) if a.is_integral() && b.is_integral() => match (a, b) {
+                (Constant, other) | (other, Constant) => other.clone(),
+                (Boolean, other) | (other, Boolean) if *other == Byte => Byte,
+                (Byte, Short) | (Short, Byte) => Short,
+                _ => Integer
+            },
+            (ConstantLo, other) | (other, ConstantLo) if *other == LongLo || *other == DoubleLo => other.clone(),
+            (ConstantHi, other) | (other, ConstantHi) if *other == LongHi || *other == DoubleHi => other.clone(),
+            (Reference(a), Reference(b)) => Reference(self.common_superclass(a, b)),
+            _ => Conflict
+        }
+    }
+
+    fn common_superclass(&self, a: &Rc<String>, b: &Rc<String>) -> Rc<String> {
+        if let Some(hierarchy) = self.hierarchy {
+            let mut ancestors = vec!(a.as_str());
+            ancestors.extend(hierarchy.superclasses(a));
+            let mut candidates = vec!(b.as_str());
+            candidates.extend(hierarchy.superclasses(b));
+            if let Some(common) = candidates.iter().find(|type_| ancestors.contains(type_)) {
+                return Rc::new(common.to_string());
+            }
+        }
+        Rc::new("Ljava/lang/Object;".to_string())
+    }
+
+    // Arithmetic and conversions, typed from the mnemonic: add-int, mul-double/2addr, int-to-long, ...
+    fn arithmetic_type(opcode: Opcode) -> RegisterType {
+        let name = opcode.name();
+        let operand = match name.find("-to-") {
+            Some(i) => &name[i + 4 ..],
+            None => name.split(['-', '/']).nth(1).unwrap_or("")
+        };
+        match operand {
+            "byte" => RegisterType::Byte,
+            "char" => RegisterType::Char,
+            "short" => RegisterType::Short,
+            "long" => RegisterType::LongLo,
+            "float" => RegisterType::Float,
+            "double" => RegisterType::DoubleLo,
+            _ => RegisterType::Integer
+        }
+    }
+}
+
+impl<'a> Analysis for TypeInference<'a> {
+    type State = Frame;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, _: &ControlFlowGraph) -> Frame {
+        self.entry_frame()
+    }
+
+    fn bottom(&self, _: &ControlFlowGraph) -> Frame {
+        self.empty_frame()
+    }
+
+    fn join(&self, state: &mut Frame, other: &Frame) -> bool {
+        let mut changed = false;
+        for (a, b) in state.registers.iter_mut().zip(other.registers.iter()) {
+            let joined = self.join_types(a, b);
+            if joined != *a {
+                *a = joined;
+                changed = true;
+            }
+        }
+        for (a, b) in [(&mut state.result, &other.result), (&mut state.exception, &other.exception)] {
+            let joined = self.join_types(a, b);
+            if joined != *a {
+                *a = joined;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn transfer(&self, insn: &Instruction, frame: &mut Frame) {
+        use self::RegisterType::*;
+        let result = std::mem::replace(&mut frame.result, Undefined);
+        let regs = &insn.registers;
+        let reg = |i: usize| regs.get(i).cloned().unwrap_or(0);
+
+        match insn.opcode {
+            Opcode::MOVE | Opcode::MOVE_FROM16 | Opcode::MOVE_16
+                | Opcode::MOVE_OBJECT | Opcode::MOVE_OBJECT_FROM16 | Opcode::MOVE_OBJECT_16 => {
+                let value = frame.get(reg(1));
+                frame.set(reg(0), value);
+            },
+            Opcode::MOVE_WIDE | Opcode::MOVE_WIDE_FROM16 | Opcode::MOVE_WIDE_16 => {
+                let (low, high) = (frame.get(reg(1)), frame.get(reg(1).saturating_add(1)));
+                frame.set(reg(0), low);
+                frame.set(reg(0).saturating_add(1), high);
+            },
+            Opcode::MOVE_RESULT | Opcode::MOVE_RESULT_OBJECT => frame.set(reg(0), result),
+            Opcode::MOVE_RESULT_WIDE => frame.set_wide(reg(0), result),
+            Opcode::MOVE_EXCEPTION => {
+                let exception = frame.exception.clone();
+                frame.set(reg(0), exception);
+            },
+            Opcode::CONST_4 | Opcode::CONST_16 | Opcode::CONST | Opcode::CONST_HIGH16 => {
+                frame.set(reg(0), if insn.literal == Some(0) { Zero } else { Constant });
+            },
+            Opcode::CONST_WIDE_16 | Opcode::CONST_WIDE_32 | Opcode::CONST_WIDE | Opcode::CONST_WIDE_HIGH16 => {
+                frame.set_wide(reg(0), ConstantLo);
+            },
+            Opcode::CONST_STRING | Opcode::CONST_STRING_JUMBO => frame.set(reg(0), reference("Ljava/lang/String;")),
+            Opcode::CONST_CLASS => frame.set(reg(0), reference("Ljava/lang/Class;")),
+            Opcode::CONST_METHOD_HANDLE => frame.set(reg(0), reference("Ljava/lang/invoke/MethodHandle;")),
+            Opcode::CONST_METHOD_TYPE => frame.set(reg(0), reference("Ljava/lang/invoke/MethodType;")),
+            Opcode::CHECK_CAST | Opcode::NEW_ARRAY => {
+                let type_ = self.pool_type(insn);
+                frame.set(reg(0), type_);
+            },
+            Opcode::NEW_INSTANCE => {
+                let type_ = match self.pool_type(insn) {
+                    Reference(type_) => Uninitialized(type_, insn.offset),
+                    other => other
+                };
+                frame.set(reg(0), type_);
+            },
+            Opcode::FILLED_NEW_ARRAY | Opcode::FILLED_NEW_ARRAY_RANGE => frame.result = self.pool_type(insn),
+            Opcode::INSTANCE_OF => frame.set(reg(0), Boolean),
+            Opcode::ARRAY_LENGTH => frame.set(reg(0), Integer),
+            Opcode::CMPL_FLOAT | Opcode::CMPG_FLOAT | Opcode::CMPL_DOUBLE | Opcode::CMPG_DOUBLE | Opcode::CMP_LONG => {
+                frame.set(reg(0), Byte);
+            },
+            Opcode::AGET | Opcode::AGET_WIDE | Opcode::AGET_OBJECT => {
+                let fallback = match insn.opcode {
+                    Opcode::AGET => Integer,
+                    Opcode::AGET_WIDE => LongLo,
+                    _ => reference("Ljava/lang/Object;")
+                };
+                let type_ = self.element_type(frame, reg(1)).unwrap_or(fallback);
+                frame.set_any(reg(0), type_);
+            },
+            Opcode::AGET_BOOLEAN => frame.set(reg(0), Boolean),
+            Opcode::AGET_BYTE => frame.set(reg(0), Byte),
+            Opcode::AGET_CHAR => frame.set(reg(0), Char),
+            Opcode::AGET_SHORT => frame.set(reg(0), Short),
+            Opcode::IGET | Opcode::IGET_WIDE | Opcode::IGET_OBJECT | Opcode::IGET_BOOLEAN | Opcode::IGET_BYTE
+                | Opcode::IGET_CHAR | Opcode::IGET_SHORT | Opcode::SGET | Opcode::SGET_WIDE | Opcode::SGET_OBJECT
+                | Opcode::SGET_BOOLEAN | Opcode::SGET_BYTE | Opcode::SGET_CHAR | Opcode::SGET_SHORT => {
+                let type_ = self.field_type(insn);
+                frame.set_any(reg(0), type_);
+            },
+            opcode if opcode.is_invoke() => {
+                // calling a constructor initializes every copy of the object
+                if matches!(opcode, Opcode::INVOKE_DIRECT | Opcode::INVOKE_DIRECT_RANGE) {
+                    let receiver = frame.get(reg(0));
+                    let constructor = insn.index.and_then(|idx| self.file.methods.get(idx as usize))
+                        .is_some_and(|method| *method.name == "<init>");
+                    let initialized = match receiver {
+                        Uninitialized(ref type_, _) | UninitializedThis(ref type_) if constructor => Some(Reference(type_.clone())),
+                        _ => None
+                    };
+                    if let Some(initialized) = initialized {
+                        for register in &mut frame.registers {
+                            if *register == receiver {
+                                *register = initialized.clone();
+                            }
+                        }
+                    }
+                }
+                frame.result = self.return_type(insn);
+            },
+            opcode if (Opcode::NEG_INT.value() ..= Opcode::USHR_INT_LIT8.value()).contains(&opcode.value()) => {
+                frame.set_any(reg(0), TypeInference::arithmetic_type(opcode));
+            },
+            _ => {}
+        }
+    }
+
+    fn edge(&self, edge: &Edge, frame: &mut Frame) {
+        frame.exception = match edge.kind {
+            EdgeKind::Exception(Some(ref type_)) => RegisterType::Reference(type_.clone()),
+            EdgeKind::Exception(None) => reference("Ljava/lang/Throwable;"),
+            _ => RegisterType::Undefined
+        };
+        if let EdgeKind::Exception(_) = edge.kind {
+            frame.result = RegisterType::Undefined;
+        }
+    }
+}
+
+fn reference(descriptor: &str) -> RegisterType {
+    RegisterType::Reference(Rc::new(descriptor.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn method(method: Rc<Method>, access_flags: Vec<AccessFlag>, registers_size: u16, ins_size: u16,
+              tries: Vec<TryItem>) -> EncodedMethod {
+        EncodedMethod {
+            method,
+            access_flags,
+            code: Some(Code { registers_size, ins_size, outs_size: 0, debug_info: None, insns: vec!(), tries, handlers: vec!() }),
+            system_annotations: Default::default()
+        }
+    }
+
+    fn build_file(method: EncodedMethod) -> DexFile {
+        let mut b = DexBuilder::new(35);
+        // referenced from the bytecode patched in below
+        b.type_("Ljava/io/IOException;");
+        b.method("LMain;", "<init>", "V", &[]);
+        b.method("Ljava/lang/Object;", "<init>", "V", &[]);
+        b.field("LMain;", "count", "J");
+        let class = ClassDefinition {
+            class_type: b.type_("LMain;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(method),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        b.class(class);
+        b.build().unwrap()
+    }
+
+    fn set_insns(file: &mut DexFile, insns: Vec<u16>) {
+        file.classes[0].class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap().insns = insns;
+    }
+
+    fn infer(file: &DexFile) -> InferredTypes {
+        let class_data = file.classes[0].class_data.as_ref().unwrap();
+        TypeInference::new(file, &class_data.direct_methods[0]).unwrap().run().unwrap()
+    }
+
+    fn type_index(file: &DexFile, descriptor: &str) -> u16 {
+        file.file_data.type_identifiers.iter().position(|t| **t == descriptor).unwrap() as u16
+    }
+
+    fn method_index(file: &DexFile, definer: &str, name: &str) -> u16 {
+        file.file_data.methods.iter().position(|m| *m.definer == definer && *m.name == name).unwrap() as u16
+    }
+
+    #[test]
+    fn test_parameters_and_wide_values() {
+        let mut b = DexBuilder::new(35);
+        let run = b.method("LMain;", "run", "V", &["J", "Ljava/lang/String;"]);
+        let mut file = build_file(method(run, vec!(AccessFlag::ACC_PUBLIC), 6, 4, vec!()));
+        let count = file.file_data.fields.iter().position(|f| *f.name == "count").unwrap() as u16;
+        set_insns(&mut file, vec!(
+            0x3004,                        // move-wide v0, v3
+            0x1012,                        // const/4 v0, #1
+            0x2153, count,                 // iget-wide v1, v2, count
+            0x000E                          // return-void
+        ));
+
+        let class_data = file.classes[0].class_data.as_ref().unwrap();
+        let types = TypeInference::new(&file, &class_data.direct_methods[0]).unwrap().run().unwrap();
+        let object = |s: &str| RegisterType::Reference(Rc::new(s.to_string()));
+        use self::RegisterType::*;
+
+        // this, then the long in v3/v4 and the string in v5
+        assert_eq!(types.frames[0].registers, vec!(Undefined, Undefined, object("LMain;"), LongLo, LongHi, object("Ljava/lang/String;")));
+        assert_eq!(types.frame_at(1).unwrap().registers[.. 2], [LongLo, LongHi]);
+        // overwriting the low half breaks the pair
+        assert_eq!(types.frame_at(2).unwrap().registers[.. 2], [Constant, Conflict]);
+        assert_eq!(types.frame_at(4).unwrap().registers[.. 3], [Constant, LongLo, LongHi]);
+    }
+
+    #[test]
+    fn test_constructor_and_handler() {
+        let mut b = DexBuilder::new(35);
+        let run = b.method("LMain;", "run", "V", &[]);
+        let try_ = TryItem {
+            start_addr: 0,
+            insn_count: 6,
+            handler: EncodedCatchHandler {
+                handlers: vec!(EncodedTypeAddrPair { type_: b.type_("Ljava/io/IOException;"), addr: 7 }),
+                catch_all_addr: None
+            }
+        };
+        let mut file = build_file(method(run, vec!(AccessFlag::ACC_STATIC), 2, 0, vec!(try_)));
+        let fd = &file.file_data;
+        let main = fd.type_identifiers.iter().position(|t| **t == "LMain;").unwrap() as u16;
+        let init = fd.methods.iter().position(|m| *m.name == "<init>").unwrap() as u16;
+        set_insns(&mut file, vec!(
+            0x0022, main,             // new-instance v0, LMain;
+            0x0107,                   // move-object v1, v0
+            0x1070, init, 0x0000,     // invoke-direct {v0}, <init>
+            0x000E,                   // return-void
+            0x000D                    // move-exception v0
+        ));
+
+        let class_data = file.classes[0].class_data.as_ref().unwrap();
+        let types = TypeInference::new(&file, &class_data.direct_methods[0]).unwrap().run().unwrap();
+        let main = Rc::new("LMain;".to_string());
+        use self::RegisterType::*;
+
+        let uninitialized = Uninitialized(main.clone(), 0);
+        assert_eq!(types.frame_at(3).unwrap().registers, vec!(uninitialized.clone(), uninitialized));
+        assert_eq!(types.frame_at(6).unwrap().registers, vec!(Reference(main.clone()), Reference(main)));
+        assert_eq!(types.frame_at(7).unwrap().exception, Reference(Rc::new("Ljava/io/IOException;".to_string())));
+    }
+
+    #[test]
+    fn test_merge_points() {
+        let mut b = DexBuilder::new(35);
+        let run = b.method("LMain;", "run", "V", &["I"]);
+        let mut file = build_file(method(run, vec!(AccessFlag::ACC_STATIC), 3, 1, vec!()));
+        let main = type_index(&file, "LMain;");
+        set_insns(&mut file, vec!(
+            0x0238, 0x0006,                // if-eqz v2, +6
+            0x1012,                        // const/4 v0, #1
+            0x011C, main,                  // const-class v1, LMain;
+            0x0528,                        // goto +5
+            0x001C, main,                  // const-class v0, LMain;
+            0x0112,                        // const/4 v1, #0
+            0x2282,                        // int-to-float v2, v2
+            0x000E                         // return-void
+        ));
+
+        let types = infer(&file);
+        use self::RegisterType::*;
+        let class = Reference(Rc::new("Ljava/lang/Class;".to_string()));
+        assert_eq!(types.frame_at(5).unwrap().registers, vec!(Constant, class.clone(), Integer));
+        // a number and a reference conflict, null joins a reference, and an int and a float conflict
+        assert_eq!(types.frame_at(10).unwrap().registers, vec!(Conflict, class, Conflict));
+    }
+
+    #[test]
+    fn test_wide_pairs() {
+        let mut b = DexBuilder::new(35);
+        let run = b.method("LMain;", "run", "V", &["I", "J"]);
+        let mut file = build_file(method(run, vec!(AccessFlag::ACC_STATIC), 7, 3, vec!()));
+        set_insns(&mut file, vec!(
+            0x0438, 0x0005,                // if-eqz v4, +5
+            0x5004,                        // move-wide v0, v5
+            0x5204,                        // move-wide v2, v5
+            0x0428,                        // goto +4
+            0x0016, 0x0000,                // const-wide/16 v0, #0
+            0x5286,                        // long-to-double v2, v5
+            0x0612,                        // const/4 v6, #0
+            0x000E                         // return-void
+        ));
+
+        let types = infer(&file);
+        use self::RegisterType::*;
+        assert_eq!(types.frame_at(4).unwrap().registers[.. 4], [LongLo, LongHi, LongLo, LongHi]);
+        assert_eq!(types.frame_at(7).unwrap().registers[.. 2], [ConstantLo, ConstantHi]);
+        // a wide constant takes the type of the pair it meets, but a long and a double conflict
+        assert_eq!(types.frame_at(8).unwrap().registers,
+                   vec!(LongLo, LongHi, Conflict, Conflict, Integer, LongLo, LongHi));
+        // overwriting the high half breaks the pair too
+        assert_eq!(types.frame_at(9).unwrap().registers[5 ..], [Conflict, Zero]);
+    }
+
+    #[test]
+    fn test_uninitialized() {
+        let mut b = DexBuilder::new(35);
+        let init = b.method("LMain;", "<init>", "V", &[]);
+        let mut file = build_file(method(init, vec!(AccessFlag::ACC_CONSTRUCTOR), 2, 1, vec!()));
+        let main = type_index(&file, "LMain;");
+        let object_init = method_index(&file, "Ljava/lang/Object;", "<init>");
+        let main_init = method_index(&file, "LMain;", "<init>");
+        set_insns(&mut file, vec!(
+            0x0022, main,                  // new-instance v0, LMain;
+            0x1070, object_init, 0x0001,   // invoke-direct {v1}, Object.<init>
+            0x1070, main_init, 0x0000,     // invoke-direct {v0}, LMain;.<init>
+            0x000E                         // return-void
+        ));
+
+        let types = infer(&file);
+        use self::RegisterType::*;
+        let main = Rc::new("LMain;".to_string());
+        // `this` is uninitialized until the superclass constructor runs
+        assert_eq!(types.frames[0].registers, vec!(Undefined, UninitializedThis(main.clone())));
+        assert_eq!(types.frame_at(2).unwrap().registers,
+                   vec!(Uninitialized(main.clone(), 0), UninitializedThis(main.clone())));
+        assert_eq!(types.frame_at(5).unwrap().registers,
+                   vec!(Uninitialized(main.clone(), 0), Reference(main.clone())));
+        assert_eq!(types.frame_at(8).unwrap().registers, vec!(Reference(main.clone()), Reference(main.clone())));
+
+        // an uninitialized object can't stand in for null or for another new-instance
+        let class_data = file.classes[0].class_data.as_ref().unwrap();
+        let inference = TypeInference::new(&file, &class_data.direct_methods[0]).unwrap();
+        assert_eq!(inference.join_types(&Uninitialized(main.clone(), 0), &Zero), Conflict);
+        assert_eq!(inference.join_types(&Uninitialized(main.clone(), 0), &Uninitialized(main, 4)), Conflict);
+    }
+
+    #[test]
+    fn test_check_cast() {
+        let mut b = DexBuilder::new(35);
+        let run = b.method("LMain;", "run", "V", &["Ljava/lang/Object;"]);
+        let mut file = build_file(method(run, vec!(AccessFlag::ACC_STATIC), 1, 1, vec!()));
+        let exception = type_index(&file, "Ljava/io/IOException;");
+        set_insns(&mut file, vec!(
+            0x001F, exception,             // check-cast v0, Ljava/io/IOException;
+            0x000E                         // return-void
+        ));
+
+        let types = infer(&file);
+        let reference = |s: &str| RegisterType::Reference(Rc::new(s.to_string()));
+        assert_eq!(types.frame_at(0).unwrap().registers, vec!(reference("Ljava/lang/Object;")));
+        assert_eq!(types.frame_at(2).unwrap().registers, vec!(reference("Ljava/io/IOException;")));
+    }
+
+    #[test]
+    fn test_handler_entry() {
+        let mut b = DexBuilder::new(35);
+        let run = b.method("LMain;", "run", "V", &[]);
+        let try_ = TryItem {
+            start_addr: 0,
+            insn_count: 4,
+            handler: EncodedCatchHandler {
+                handlers: vec!(EncodedTypeAddrPair { type_: b.type_("Ljava/io/IOException;"), addr: 5 }),
+                catch_all_addr: Some(7)
+            }
+        };
+        let mut file = build_file(method(run, vec!(AccessFlag::ACC_STATIC), 1, 0, vec!(try_)));
+        let init = method_index(&file, "LMain;", "<init>");
+        set_insns(&mut file, vec!(
+            0x1012,                        // const/4 v0, #1
+            0x0071, init, 0x0000,          // invoke-static {}, LMain;.<init>
+            0x000E,                        // return-void
+            0x000D,                        // move-exception v0
+            0x000E,                        // return-void
+            0x000D,                        // move-exception v0
+            0x000E                         // return-void
+        ));
+
+        let types = infer(&file);
+        use self::RegisterType::*;
+        let io_exception = Reference(Rc::new("Ljava/io/IOException;".to_string()));
+        let throwable = Reference(Rc::new("Ljava/lang/Throwable;".to_string()));
+        // a typed handler sees its type, a catch-all sees Throwable, and neither sees a pending result
+        let handler = types.frame_at(5).unwrap();
+        assert_eq!((&handler.exception, &handler.result), (&io_exception, &Undefined));
+        let catch_all = types.frame_at(7).unwrap();
+        assert_eq!((&catch_all.exception, &catch_all.result), (&throwable, &Undefined));
+        assert_eq!(types.frame_at(6).unwrap().registers, vec!(io_exception));
+        assert_eq!(types.frame_at(8).unwrap().registers, vec!(throwable));
+        // the rest of the method never sees an exception
+        assert_eq!(types.frame_at(4).unwrap().exception, Undefined);
+    }
+}
@@ -0,0 +1,952 @@
+// Checks method bodies against the rules ART's method verifier enforces
+//
+// The structural checks come first: the code decodes, registers are in range, branches land on
+// instructions, payloads are the right kind and aligned, and execution can't run off the end. If those
+// pass, the code is checked with the types inferred for each register: every operand holds a value of
+// the kind the instruction needs, wide values are used as pairs, field and invoke operands match their
+// declarations, and each monitor-exit matches a monitor-enter. Unreachable code is not type checked.
+//
+// The checks are best effort where the files don't define a class: references to external types are
+// assumed to be assignable.
+
+use std::rc::Rc;
+
+use crate::cfg::ControlFlowGraph;
+use crate::dataflow::{self, Analysis, Direction};
+use crate::error::DexParserError;
+use crate::hierarchy::ClassHierarchy;
+use crate::instructions::{self, Format, Instruction, Opcode, Payload};
+use crate::result_types::*;
+use crate::type_inference::{Frame, RegisterType, TypeInference};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Finding {
+    // the address of the offending instruction, in 16-bit code units; None for problems with the code item itself
+    pub offset: Option<u32>,
+    pub kind: FindingKind,
+    pub message: String
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FindingKind {
+    // the code item or the try ranges are inconsistent with the code, or an index is out of range
+    Structure,
+    // the instructions don't decode
+    Decode,
+    RegisterBounds,
+    // half of a wide value used on its own, or a pair that doesn't hold one value
+    WidePair,
+    BranchTarget,
+    Payload,
+    // an operand holds the wrong kind of value
+    Type,
+    Monitor
+}
+
+// The findings for a single method
+#[derive(Debug, PartialEq)]
+pub struct MethodReport {
+    pub method: Rc<Method>,
+    pub findings: Vec<Finding>
+}
+
+// Verifies every method with code, reporting those with findings
+pub fn verify(file: &DexFile) -> Vec<MethodReport> {
+    let hierarchy = ClassHierarchy::new(file);
+    file.classes.iter()
+        .filter_map(|class| class.class_data.as_ref())
+        .flat_map(|class_data| class_data.direct_methods.iter().chain(class_data.virtual_methods.iter()))
+        .map(|method| MethodReport { method: method.method.clone(), findings: verify_method(file, &hierarchy, method) })
+        .filter(|report| !report.findings.is_empty())
+        .collect()
+}
+
+pub fn verify_method(file: &DexFile, hierarchy: &ClassHierarchy, method: &EncodedMethod) -> Vec<Finding> {
+    let code = match method.code {
+        Some(ref code) => code,
+        None => return vec!()
+    };
+    let verifier = Verifier { file: &file.file_data, hierarchy, method, code, findings: vec!() };
+    verifier.run(file)
+}
+
+// What an operand must hold
+#[derive(Debug, PartialEq, Clone)]
+enum Expect {
+    // any single-register value
+    Narrow,
+    // a single-register value that is not a reference: an int, a float or a constant
+    Primitive,
+    // any wide value
+    Wide,
+    Int,
+    Float,
+    Long,
+    Double,
+    // an initialized reference or null
+    Reference,
+    // a reference or null that is assignable to the class
+    Class(Rc<String>),
+    // as Class, but the object may not have been constructed yet
+    Unconstructed(Rc<String>)
+}
+
+impl Expect {
+    fn from_descriptor(descriptor: &Rc<String>) -> Expect {
+        match descriptor.as_bytes().first() {
+            Some(b'Z') | Some(b'B') | Some(b'S') | Some(b'C') | Some(b'I') => Expect::Int,
+            Some(b'F') => Expect::Float,
+            Some(b'J') => Expect::Long,
+            Some(b'D') => Expect::Double,
+            _ => Expect::Class(descriptor.clone())
+        }
+    }
+
+    // The type named in mnemonics: add-int, long-to-double, ...
+    fn from_mnemonic(word: &str) -> Expect {
+        match word {
+            "long" => Expect::Long,
+            "float" => Expect::Float,
+            "double" => Expect::Double,
+            _ => Expect::Int
+        }
+    }
+
+    fn is_wide(&self) -> bool {
+        matches!(*self, Expect::Wide | Expect::Long | Expect::Double)
+    }
+}
+
+struct Operand {
+    register: u16,
+    expect: Expect,
+    write: bool
+}
+
+fn read(register: u16, expect: Expect) -> Operand {
+    Operand { register, expect, write: false }
+}
+
+fn write(register: u16, wide: bool) -> Operand {
+    Operand { register, expect: if wide { Expect::Wide } else { Expect::Narrow }, write: true }
+}
+
+struct Verifier<'a> {
+    file: &'a DexFileData,
+    hierarchy: &'a ClassHierarchy<'a>,
+    method: &'a EncodedMethod,
+    code: &'a Code,
+    findings: Vec<Finding>
+}
+
+impl<'a> Verifier<'a> {
+    fn report(&mut self, offset: Option<u32>, kind: FindingKind, message: String) {
+        self.findings.push(Finding { offset, kind, message });
+    }
+
+    fn run(mut self, file: &DexFile) -> Vec<Finding> {
+        let code = self.code;
+        if code.ins_size > code.registers_size {
+            self.report(None, FindingKind::Structure,
+                        format!("ins_size {} is larger than registers_size {}", code.ins_size, code.registers_size));
+        }
+
+        let decoded = match instructions::decode_all(&code.insns) {
+            Ok(decoded) => decoded,
+            Err(DexParserError::InvalidBytecode { offset, reason }) => {
+                self.report(Some(offset), FindingKind::Decode, reason);
+                return self.findings;
+            },
+            Err(e) => {
+                self.report(None, FindingKind::Decode, e.to_string());
+                return self.findings;
+            }
+        };
+
+        self.check_structure(&decoded);
+        if !self.findings.is_empty() {
+            // the rest relies on the control flow being sound
+            return self.findings;
+        }
+
+        let inference = match TypeInference::new(file, self.method) {
+            Some(inference) => inference.with_hierarchy(self.hierarchy),
+            None => return self.findings
+        };
+        let types = match inference.run() {
+            Ok(types) => types,
+            Err(e) => {
+                self.report(None, FindingKind::Structure, e.to_string());
+                return self.findings;
+            }
+        };
+
+        let unreachable = types.cfg.unreachable_blocks();
+        for (b, block) in types.cfg.blocks.iter().enumerate() {
+            if unreachable.contains(&b) {
+                continue;
+            }
+            for i in block.instructions.clone() {
+                self.check_types(&types.cfg.instructions[i], &types.frames[i]);
+            }
+        }
+        self.check_monitors(&types.cfg, &unreachable);
+
+        self.findings.sort_by_key(|finding| finding.offset);
+        self.findings
+    }
+
+    // Register bounds, branch and payload targets, try ranges and the end of the code
+    fn check_structure(&mut self, decoded: &[Instruction]) {
+        let code = self.code;
+        let end = code.insns.len() as u32;
+        let mut at = vec!(None; code.insns.len() + 1);
+        for (i, insn) in decoded.iter().enumerate() {
+            at[insn.offset as usize] = Some(i);
+        }
+        let instruction_at = |offset: u32| at.get(offset as usize).cloned().flatten().map(|i: usize| &decoded[i]);
+        let is_instruction = |offset: u32| instruction_at(offset).is_some_and(|insn| insn.payload.is_none());
+
+        for insn in decoded {
+            let offset = Some(insn.offset);
+            if insn.payload.is_some() {
+                if insn.offset % 2 != 0 {
+                    self.report(offset, FindingKind::Payload, "payload is not 4-byte aligned".to_string());
+                }
+                continue;
+            }
+
+            match self.operands(insn) {
+                Ok(operands) => for operand in operands {
+                    let width = if operand.expect.is_wide() { 2 } else { 1 };
+                    if u32::from(operand.register) + width > u32::from(code.registers_size) {
+                        self.report(offset, FindingKind::RegisterBounds,
+                                    format!("v{} is out of range for {} registers", u32::from(operand.register) + width - 1, code.registers_size));
+                    }
+                },
+                Err(message) => self.report(offset, FindingKind::Structure, message)
+            }
+
+            if insn.opcode.is_invoke() && insn.registers.len() > code.outs_size as usize {
+                self.report(offset, FindingKind::Structure,
+                            format!("{} argument registers exceed outs_size {}", insn.registers.len(), code.outs_size));
+            }
+
+            let opcode = insn.opcode;
+            if opcode.can_branch() {
+                let target = insn.target().unwrap_or(insn.offset);
+                if insn.branch_offset == Some(0) && opcode != Opcode::GOTO_32 {
+                    self.report(offset, FindingKind::BranchTarget, "branch to itself".to_string());
+                } else if !is_instruction(target) {
+                    self.report(offset, FindingKind::BranchTarget, format!("branch target {} is not an instruction", target));
+                }
+            }
+
+            if opcode.can_switch() || opcode == Opcode::FILL_ARRAY_DATA {
+                let target = insn.target().unwrap_or(insn.offset);
+                let payload = instruction_at(target).and_then(|payload| payload.payload.as_ref());
+                let matches = matches!((opcode, payload),
+                    (Opcode::PACKED_SWITCH, Some(Payload::PackedSwitch { .. }))
+                        | (Opcode::SPARSE_SWITCH, Some(Payload::SparseSwitch { .. }))
+                        | (Opcode::FILL_ARRAY_DATA, Some(Payload::FillArrayData { .. })));
+                if !matches {
+                    self.report(offset, FindingKind::Payload, format!("{} does not point to a matching payload", opcode.name()));
+                } else if let Some(payload) = payload {
+                    for (key, relative) in payload.switch_cases() {
+                        let target = (i64::from(insn.offset) + i64::from(relative)) as u32;
+                        if !is_instruction(target) {
+                            self.report(offset, FindingKind::BranchTarget,
+                                        format!("target {} of case {} is not an instruction", target, key));
+                        }
+                    }
+                }
+            }
+
+            let next = insn.offset + insn.length;
+            if opcode.can_continue() && (next == end || instruction_at(next).is_some_and(|next| next.payload.is_some())) {
+                self.report(offset, FindingKind::Structure, "execution can continue past the end of the code".to_string());
+            }
+        }
+
+        for try_ in &code.tries {
+            let try_end = try_.start_addr.saturating_add(u32::from(try_.insn_count));
+            if !is_instruction(try_.start_addr) || (try_end != end && instruction_at(try_end).is_none()) || try_end > end {
+                self.report(Some(try_.start_addr), FindingKind::Structure,
+                            format!("try range {}..{} does not cover whole instructions", try_.start_addr, try_end));
+            }
+            let handler = &try_.handler;
+            for addr in handler.handlers.iter().map(|pair| pair.addr).chain(handler.catch_all_addr) {
+                if !is_instruction(addr) {
+                    self.report(Some(try_.start_addr), FindingKind::BranchTarget,
+                                format!("handler address {} is not an instruction", addr));
+                }
+            }
+        }
+    }
+
+    // The registers an instruction reads and writes, with what they must hold
+    fn operands(&self, insn: &Instruction) -> Result<Vec<Operand>, String> {
+        let regs = &insn.registers;
+        let r = |i: usize| regs.get(i).cloned().unwrap_or(0);
+        let name = insn.opcode.name();
+
+        let operands = match insn.opcode {
+            Opcode::MOVE | Opcode::MOVE_FROM16 | Opcode::MOVE_16 => {
+                vec!(write(r(0), false), read(r(1), Expect::Primitive))
+            },
+            Opcode::MOVE_WIDE | Opcode::MOVE_WIDE_FROM16 | Opcode::MOVE_WIDE_16 => vec!(write(r(0), true), read(r(1), Expect::Wide)),
+            Opcode::MOVE_OBJECT | Opcode::MOVE_OBJECT_FROM16 | Opcode::MOVE_OBJECT_16 => {
+                vec!(write(r(0), false), read(r(1), Expect::Unconstructed(object())))
+            },
+            Opcode::MOVE_RESULT | Opcode::MOVE_RESULT_OBJECT | Opcode::MOVE_EXCEPTION => vec!(write(r(0), false)),
+            Opcode::MOVE_RESULT_WIDE => vec!(write(r(0), true)),
+            // whether the kind of return suits the method is checked separately
+            Opcode::RETURN => vec!(read(r(0), Expect::Narrow)),
+            Opcode::RETURN_WIDE => vec!(read(r(0), Expect::Wide)),
+            Opcode::RETURN_OBJECT => {
+                let return_type = &self.method.method.prototype.return_type;
+                vec!(read(r(0), if is_class_or_array(return_type) { Expect::Class(return_type.clone()) } else { Expect::Reference }))
+            },
+            Opcode::CONST_WIDE_16 | Opcode::CONST_WIDE_32 | Opcode::CONST_WIDE | Opcode::CONST_WIDE_HIGH16 => vec!(write(r(0), true)),
+            Opcode::CONST_4 | Opcode::CONST_16 | Opcode::CONST | Opcode::CONST_HIGH16 | Opcode::CONST_STRING
+                | Opcode::CONST_STRING_JUMBO | Opcode::CONST_CLASS | Opcode::CONST_METHOD_HANDLE
+                | Opcode::CONST_METHOD_TYPE | Opcode::NEW_INSTANCE => vec!(write(r(0), false)),
+            Opcode::MONITOR_ENTER | Opcode::MONITOR_EXIT | Opcode::FILL_ARRAY_DATA => vec!(read(r(0), Expect::Reference)),
+            Opcode::THROW => vec!(read(r(0), Expect::Class(Rc::new("Ljava/lang/Throwable;".to_string())))),
+            Opcode::CHECK_CAST => vec!(read(r(0), Expect::Reference), write(r(0), false)),
+            Opcode::INSTANCE_OF | Opcode::ARRAY_LENGTH => vec!(write(r(0), false), read(r(1), Expect::Reference)),
+            Opcode::NEW_ARRAY => vec!(write(r(0), false), read(r(1), Expect::Int)),
+            Opcode::FILLED_NEW_ARRAY | Opcode::FILLED_NEW_ARRAY_RANGE => {
+                let type_ = self.pool(&self.file.type_identifiers, insn, "type")?;
+                let element = Rc::new(type_.get(1 ..).unwrap_or("").to_string());
+                regs.iter().map(|reg| read(*reg, Expect::from_descriptor(&element))).collect()
+            },
+            Opcode::PACKED_SWITCH | Opcode::SPARSE_SWITCH => vec!(read(r(0), Expect::Int)),
+            Opcode::CMPL_FLOAT | Opcode::CMPG_FLOAT => vec!(write(r(0), false), read(r(1), Expect::Float), read(r(2), Expect::Float)),
+            Opcode::CMPL_DOUBLE | Opcode::CMPG_DOUBLE => vec!(write(r(0), false), read(r(1), Expect::Double), read(r(2), Expect::Double)),
+            Opcode::CMP_LONG => vec!(write(r(0), false), read(r(1), Expect::Long), read(r(2), Expect::Long)),
+            Opcode::IF_EQ | Opcode::IF_NE => vec!(read(r(0), Expect::Narrow), read(r(1), Expect::Narrow)),
+            Opcode::IF_LT | Opcode::IF_GE | Opcode::IF_GT | Opcode::IF_LE => vec!(read(r(0), Expect::Int), read(r(1), Expect::Int)),
+            Opcode::IF_EQZ | Opcode::IF_NEZ => vec!(read(r(0), Expect::Narrow)),
+            Opcode::IF_LTZ | Opcode::IF_GEZ | Opcode::IF_GTZ | Opcode::IF_LEZ => vec!(read(r(0), Expect::Int)),
+            Opcode::AGET | Opcode::AGET_WIDE | Opcode::AGET_OBJECT | Opcode::AGET_BOOLEAN | Opcode::AGET_BYTE
+                | Opcode::AGET_CHAR | Opcode::AGET_SHORT => {
+                vec!(write(r(0), insn.opcode == Opcode::AGET_WIDE), read(r(1), Expect::Reference), read(r(2), Expect::Int))
+            },
+            Opcode::APUT | Opcode::APUT_WIDE | Opcode::APUT_OBJECT | Opcode::APUT_BOOLEAN | Opcode::APUT_BYTE
+                | Opcode::APUT_CHAR | Opcode::APUT_SHORT => {
+                let value = match insn.opcode {
+                    Opcode::APUT => Expect::Narrow,
+                    Opcode::APUT_WIDE => Expect::Wide,
+                    Opcode::APUT_OBJECT => Expect::Reference,
+                    _ => Expect::Int
+                };
+                vec!(read(r(0), value), read(r(1), Expect::Reference), read(r(2), Expect::Int))
+            },
+            opcode if (Opcode::IGET.value() ..= Opcode::SPUT_SHORT.value()).contains(&opcode.value()) => {
+                let field = self.pool(&self.file.fields, insn, "field")?;
+                let value = Expect::from_descriptor(&field.type_);
+                let mut operands = if name.starts_with("iget") || name.starts_with("sget") {
+                    vec!(write(r(0), value.is_wide()))
+                } else {
+                    vec!(read(r(0), value))
+                };
+                if name.starts_with("iget") {
+                    operands.push(read(r(1), Expect::Class(field.definer.clone())));
+                } else if name.starts_with("iput") {
+                    // a constructor may set fields before calling the superclass constructor
+                    operands.push(read(r(1), Expect::Unconstructed(field.definer.clone())));
+                }
+                operands
+            },
+            opcode if opcode.is_invoke() => self.invoke_operands(insn)?,
+            opcode if (Opcode::NEG_INT.value() ..= Opcode::USHR_INT_LIT8.value()).contains(&opcode.value()) => {
+                arithmetic_operands(insn)
+            },
+            _ => vec!()
+        };
+        Ok(operands)
+    }
+
+    fn invoke_operands(&self, insn: &Instruction) -> Result<Vec<Operand>, String> {
+        let (receiver, prototype) = match insn.opcode {
+            Opcode::INVOKE_CUSTOM | Opcode::INVOKE_CUSTOM_RANGE => {
+                (None, self.pool(&self.file.call_sites, insn, "call site")?.method_type.clone())
+            },
+            Opcode::INVOKE_POLYMORPHIC | Opcode::INVOKE_POLYMORPHIC_RANGE => {
+                let proto_index = insn.proto_index.unwrap_or(0) as usize;
+                let prototype = self.file.prototypes.get(proto_index)
+                    .ok_or_else(|| format!("prototype index {} is out of range", proto_index))?;
+                self.pool(&self.file.methods, insn, "method")?;
+                (Some(Expect::Class(Rc::new("Ljava/lang/invoke/MethodHandle;".to_string()))), prototype.clone())
+            },
+            opcode => {
+                let method = self.pool(&self.file.methods, insn, "method")?;
+                let receiver = match opcode {
+                    Opcode::INVOKE_STATIC | Opcode::INVOKE_STATIC_RANGE => None,
+                    Opcode::INVOKE_DIRECT | Opcode::INVOKE_DIRECT_RANGE if *method.name == "<init>" => {
+                        Some(Expect::Unconstructed(method.definer.clone()))
+                    },
+                    _ => Some(Expect::Class(method.definer.clone()))
+                };
+                (receiver, method.prototype.clone())
+            }
+        };
+
+        let expected = receiver.iter().cloned()
+            .chain(prototype.parameters.iter().map(Expect::from_descriptor))
+            .collect::<Vec<_>>();
+        let words = expected.iter().map(|expect| if expect.is_wide() { 2 } else { 1 }).sum::<usize>();
+        if words != insn.registers.len() {
+            return Err(format!("{} argument registers given, but the prototype takes {}", insn.registers.len(), words));
+        }
+
+        let mut operands = vec!();
+        let mut i = 0;
+        for expect in expected {
+            let register = insn.registers[i];
+            if expect.is_wide() {
+                if insn.registers[i + 1] != register.wrapping_add(1) {
+                    return Err(format!("wide argument in v{} and v{}, which are not a pair", register, insn.registers[i + 1]));
+                }
+                i += 1;
+            }
+            operands.push(read(register, expect));
+            i += 1;
+        }
+        Ok(operands)
+    }
+
+    fn pool<'b, T>(&self, pool: &'b [Rc<T>], insn: &Instruction, kind: &str) -> Result<&'b Rc<T>, String> {
+        let idx = insn.index.unwrap_or(0);
+        pool.get(idx as usize).ok_or_else(|| format!("{} index {} is out of range", kind, idx))
+    }
+
+    fn check_types(&mut self, insn: &Instruction, frame: &Frame) {
+        let offset = Some(insn.offset);
+        // the structure checks passed, so the operands can be worked out
+        let operands = self.operands(insn).unwrap_or_default();
+        for operand in operands.iter().filter(|operand| !operand.write) {
+            if let Some((kind, message)) = self.check_operand(frame, operand.register, &operand.expect) {
+                self.report(offset, kind, format!("{}: {}", insn.opcode.name(), message));
+            }
+        }
+
+        let return_type = self.method.method.prototype.return_type.clone();
+        let problem = match insn.opcode {
+            Opcode::MOVE_RESULT | Opcode::MOVE_RESULT_WIDE | Opcode::MOVE_RESULT_OBJECT => {
+                let fits = match insn.opcode {
+                    Opcode::MOVE_RESULT => !frame.result.is_wide() && !is_object(&frame.result),
+                    Opcode::MOVE_RESULT_WIDE => frame.result.is_wide(),
+                    _ => is_object(&frame.result)
+                };
+                match frame.result {
+                    RegisterType::Undefined => Some("there is no result to move".to_string()),
+                    ref result if !fits => Some(format!("the result is {:?}", result)),
+                    _ => None
+                }
+            },
+            Opcode::MOVE_EXCEPTION if frame.exception == RegisterType::Undefined => {
+                Some("not at the start of an exception handler".to_string())
+            },
+            Opcode::RETURN_VOID | Opcode::RETURN | Opcode::RETURN_WIDE | Opcode::RETURN_OBJECT => {
+                let expected = match return_type.as_bytes().first() {
+                    Some(b'V') => Opcode::RETURN_VOID,
+                    Some(b'J') | Some(b'D') => Opcode::RETURN_WIDE,
+                    Some(b'L') | Some(b'[') => Opcode::RETURN_OBJECT,
+                    _ => Opcode::RETURN
+                };
+                if insn.opcode != expected {
+                    Some(format!("the method returns {}", return_type))
+                } else {
+                    None
+                }
+            },
+            Opcode::IF_EQ | Opcode::IF_NE => {
+                let (a, b) = (frame.get(insn.registers[0]), frame.get(insn.registers[1]));
+                let objects = (is_object(&a), is_object(&b));
+                if a != RegisterType::Zero && b != RegisterType::Zero && objects.0 != objects.1 {
+                    Some(format!("comparing {:?} with {:?}", a, b))
+                } else {
+                    None
+                }
+            },
+            Opcode::NEW_INSTANCE => self.file.type_identifiers.get(insn.index.unwrap_or(0) as usize)
+                .filter(|type_| !type_.starts_with('L'))
+                .map(|type_| format!("{} is not a class", type_)),
+            Opcode::NEW_ARRAY | Opcode::FILLED_NEW_ARRAY | Opcode::FILLED_NEW_ARRAY_RANGE => {
+                self.file.type_identifiers.get(insn.index.unwrap_or(0) as usize)
+                    .filter(|type_| !type_.starts_with('[')
+                        || (insn.opcode != Opcode::NEW_ARRAY && matches!(type_.as_bytes().get(1), Some(b'J') | Some(b'D'))))
+                    .map(|type_| format!("cannot create {}", type_))
+            },
+            opcode if (Opcode::IGET.value() ..= Opcode::SPUT_SHORT.value()).contains(&opcode.value()) => {
+                self.file.fields.get(insn.index.unwrap_or(0) as usize)
+                    .filter(|field| !field_matches_opcode(opcode, &field.type_))
+                    .map(|field| format!("field {} has type {}", field.name, field.type_))
+            },
+            _ => None
+        };
+        if let Some(problem) = problem {
+            self.report(offset, FindingKind::Type, format!("{}: {}", insn.opcode.name(), problem));
+        }
+    }
+
+    fn check_operand(&self, frame: &Frame, register: u16, expect: &Expect) -> Option<(FindingKind, String)> {
+        use self::RegisterType::*;
+        let value = frame.get(register);
+        if value == Undefined {
+            return Some((FindingKind::Type, format!("v{} is read before it is written", register)));
+        }
+        if value == Conflict {
+            return Some((FindingKind::Type, format!("v{} holds different types on different paths", register)));
+        }
+
+        if expect.is_wide() {
+            let high = frame.get(register.wrapping_add(1));
+            if !value.is_wide() || value.high_half() != Some(high.clone()) {
+                return Some((FindingKind::WidePair, format!("v{} and v{} do not hold a wide value", register, register.wrapping_add(1))));
+            }
+        } else if value.is_wide() || value.is_high_half() {
+            return Some((FindingKind::WidePair, format!("v{} holds half of a wide value", register)));
+        }
+
+        let fits = match *expect {
+            Expect::Narrow | Expect::Wide => true,
+            Expect::Primitive => value.is_integral() || value == Float,
+            Expect::Int => value.is_integral(),
+            Expect::Float => matches!(value, Float | Zero | Constant),
+            Expect::Long => matches!(value, LongLo | ConstantLo),
+            Expect::Double => matches!(value, DoubleLo | ConstantLo),
+            Expect::Reference => matches!(value, Zero | Reference(_)),
+            Expect::Class(ref class) => match value {
+                Zero => true,
+                Reference(ref type_) => self.is_assignable(type_, class),
+                _ => false
+            },
+            Expect::Unconstructed(ref class) => match value {
+                Zero | Uninitialized(..) | UninitializedThis(_) => true,
+                Reference(ref type_) => self.is_assignable(type_, class),
+                _ => false
+            }
+        };
+        if fits {
+            None
+        } else {
+            let wanted = match *expect {
+                Expect::Class(ref class) | Expect::Unconstructed(ref class) => class.to_string(),
+                ref other => format!("{:?}", other)
+            };
+            Some((FindingKind::Type, format!("v{} is {:?}, but {} is expected", register, value, wanted)))
+        }
+    }
+
+    // Whether a value of one type can be used as the other, assuming it can where the files don't say
+    fn is_assignable(&self, from: &str, to: &str) -> bool {
+        if from == to || to == "Ljava/lang/Object;" {
+            return true;
+        }
+        if let Some(to_element) = to.strip_prefix('[') {
+            return match from.strip_prefix('[') {
+                Some(from_element) if is_class_or_array(from_element) && is_class_or_array(to_element) => {
+                    self.is_assignable(from_element, to_element)
+                },
+                Some(from_element) => from_element == to_element,
+                None => false
+            };
+        }
+        if from.starts_with('[') {
+            return matches!(to, "Ljava/lang/Cloneable;" | "Ljava/io/Serializable;");
+        }
+        // anything may be assigned to an interface type; ART checks that when the method is called
+        if self.hierarchy.is_external(to) || self.hierarchy.is_interface(to) || self.hierarchy.is_external(from) {
+            return true;
+        }
+        let superclasses = self.hierarchy.superclasses(from);
+        if superclasses.contains(&to) {
+            return true;
+        }
+        // if the chain ends at a class the files don't define, that class could extend the one wanted
+        superclasses.last().is_some_and(|last| *last != "Ljava/lang/Object;" && self.hierarchy.is_external(last))
+    }
+
+    fn check_monitors(&mut self, cfg: &ControlFlowGraph, unreachable: &[usize]) {
+        let analysis = Monitors;
+        let states = dataflow::solve(cfg, &analysis).instruction_states(cfg, &analysis);
+
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            if unreachable.contains(&b) {
+                continue;
+            }
+            for i in block.instructions.clone() {
+                let insn = &cfg.instructions[i];
+                let offset = Some(insn.offset);
+                let held = match states[i] {
+                    MonitorState::Held(ref held) => held,
+                    MonitorState::Inconsistent => {
+                        if i == block.instructions.start {
+                            self.report(offset, FindingKind::Monitor, "monitors held differ between paths".to_string());
+                        }
+                        continue;
+                    },
+                    MonitorState::Unreached => continue
+                };
+                match insn.opcode {
+                    Opcode::MONITOR_EXIT => match held.last() {
+                        None => self.report(offset, FindingKind::Monitor, "monitor-exit without a monitor held".to_string()),
+                        Some(top) if *top != insn.registers[0] => self.report(offset, FindingKind::Monitor,
+                            format!("monitor-exit on v{}, but the last monitor-enter was on v{}", insn.registers[0], top)),
+                        _ => {}
+                    },
+                    opcode if opcode.is_return() && !held.is_empty() => {
+                        self.report(offset, FindingKind::Monitor, format!("returns with {} monitors held", held.len()));
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn arithmetic_operands(insn: &Instruction) -> Vec<Operand> {
+    let name = insn.opcode.name();
+    let r = |i: usize| insn.registers.get(i).cloned().unwrap_or(0);
+    let shift = name.starts_with("sh") || name.starts_with("ushr");
+
+    if let Some(i) = name.find("-to-") {
+        let (from, to) = (Expect::from_mnemonic(&name[.. i]), Expect::from_mnemonic(&name[i + 4 ..]));
+        return vec!(write(r(0), to.is_wide()), read(r(1), from));
+    }
+
+    let type_ = Expect::from_mnemonic(name.split(['-', '/']).nth(1).unwrap_or(""));
+    let amount = if shift { Expect::Int } else { type_.clone() };
+    match insn.opcode.format() {
+        Format::F23x => vec!(write(r(0), type_.is_wide()), read(r(1), type_), read(r(2), amount)),
+        Format::F12x if name.ends_with("/2addr") => {
+            vec!(read(r(0), type_.clone()), read(r(1), amount), write(r(0), type_.is_wide()))
+        },
+        // neg-* and not-*
+        Format::F12x => vec!(write(r(0), type_.is_wide()), read(r(1), type_)),
+        // the /lit8 and /lit16 forms only work on ints
+        _ => vec!(write(r(0), false), read(r(1), Expect::Int))
+    }
+}
+
+fn field_matches_opcode(opcode: Opcode, type_: &str) -> bool {
+    let suffix = opcode.name().split('-').nth(1);
+    matches!((suffix, type_.as_bytes().first()),
+        (None, Some(b'I')) | (None, Some(b'F'))
+            | (Some("wide"), Some(b'J')) | (Some("wide"), Some(b'D'))
+            | (Some("object"), Some(b'L')) | (Some("object"), Some(b'['))
+            | (Some("boolean"), Some(b'Z')) | (Some("byte"), Some(b'B')) | (Some("char"), Some(b'C'))
+            | (Some("short"), Some(b'S')))
+}
+
+fn is_object(type_: &RegisterType) -> bool {
+    type_.is_reference() && *type_ != RegisterType::Zero
+}
+
+fn is_class_or_array(descriptor: &str) -> bool {
+    descriptor.starts_with('L') || descriptor.starts_with('[')
+}
+
+fn object() -> Rc<String> {
+    Rc::new("Ljava/lang/Object;".to_string())
+}
+
+// The registers locked by monitor-enter, innermost last
+#[derive(Debug, PartialEq, Clone)]
+enum MonitorState {
+    Unreached,
+    Held(Vec<u16>),
+    // paths meeting with different monitors held
+    Inconsistent
+}
+
+struct Monitors;
+
+impl Analysis for Monitors {
+    type State = MonitorState;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn boundary(&self, _: &ControlFlowGraph) -> MonitorState {
+        MonitorState::Held(vec!())
+    }
+
+    fn bottom(&self, _: &ControlFlowGraph) -> MonitorState {
+        MonitorState::Unreached
+    }
+
+    fn join(&self, state: &mut MonitorState, other: &MonitorState) -> bool {
+        let joined = match (&*state, other) {
+            (_, MonitorState::Unreached) => return false,
+            (MonitorState::Unreached, other) => other.clone(),
+            (a, b) if a == b => return false,
+            _ => MonitorState::Inconsistent
+        };
+        let changed = *state != joined;
+        *state = joined;
+        changed
+    }
+
+    fn transfer(&self, insn: &Instruction, state: &mut MonitorState) {
+        if let MonitorState::Held(ref mut held) = *state {
+            match insn.opcode {
+                Opcode::MONITOR_ENTER => held.push(insn.registers[0]),
+                Opcode::MONITOR_EXIT if held.last() == Some(&insn.registers[0]) => {
+                    held.pop();
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    // A file with the static method LMain;->run, taking the given parameters, with its code set to insns
+    fn verify_code(parameters: &[&str], return_type: &str, registers_size: u16, outs_size: u16,
+                   insns: impl Fn(&DexFileData) -> Vec<u16>) -> Vec<(Option<u32>, FindingKind)> {
+        verify_tries(parameters, return_type, registers_size, outs_size, insns, vec!())
+    }
+
+    // As verify_code, with the given try ranges
+    fn verify_tries(parameters: &[&str], return_type: &str, registers_size: u16, outs_size: u16,
+                    insns: impl Fn(&DexFileData) -> Vec<u16>, tries: Vec<TryItem>) -> Vec<(Option<u32>, FindingKind)> {
+        let mut b = DexBuilder::new(35);
+        b.field("LMain;", "count", "I");
+        b.method("LMain;", "take", "V", &["Ljava/lang/String;"]);
+        let run = b.method("LMain;", "run", return_type, parameters);
+        let ins_size = parameters.iter().map(|p| if *p == "J" || *p == "D" { 2 } else { 1 }).sum();
+        let class = ClassDefinition {
+            class_type: b.type_("LMain;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(),
+                instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod {
+                    method: run,
+                    access_flags: vec!(AccessFlag::ACC_STATIC),
+                    code: Some(Code {
+                        registers_size, ins_size, outs_size, debug_info: None, insns: vec!(), tries, handlers: vec!()
+                    }),
+                    system_annotations: Default::default()
+                }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(),
+            system_annotations: Default::default()
+        };
+        b.class(class);
+        let mut file = b.build().unwrap();
+
+        let insns = insns(&file.file_data);
+        file.classes[0].class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap().insns = insns;
+        verify(&file).into_iter()
+            .flat_map(|report| report.findings)
+            .map(|finding| (finding.offset, finding.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_code() {
+        let findings = verify_code(&["LMain;"], "I", 3, 0, |fd| vec!(
+            0x021D,                                                            // monitor-enter v2
+            0x2052, fd.fields.iter().position(|f| *f.name == "count").unwrap() as u16, // iget v0, v2, count
+            0x021E,                                                            // monitor-exit v2
+            0x000F                                                             // return v0
+        ));
+        assert_eq!(findings, vec!());
+    }
+
+    #[test]
+    fn test_structure() {
+        let findings = verify_code(&[], "V", 3, 0, |_| vec!(
+            0x0512,         // const/4 v5, #0
+            0x0038, 0x0003, // if-eqz v0, +3
+            0x0013, 0x0001, // const/16 v0, #1
+            0x000E          // return-void
+        ));
+        assert_eq!(findings, vec!((Some(0), FindingKind::RegisterBounds), (Some(1), FindingKind::BranchTarget)));
+    }
+
+    #[test]
+    fn test_types_and_monitors() {
+        let findings = verify_code(&["J"], "V", 3, 0, |_| vec!(
+            0x1012, // const/4 v0, #1
+            0x10B0, // add-int/2addr v0, v1
+            0x001E, // monitor-exit v0
+            0x000E  // return-void
+        ));
+        assert_eq!(findings, vec!(
+            (Some(1), FindingKind::WidePair),
+            (Some(2), FindingKind::Type),
+            (Some(2), FindingKind::Monitor)
+        ));
+    }
+
+    #[test]
+    fn test_invoke_and_return() {
+        let findings = verify_code(&[], "V", 1, 1, |fd| {
+            let take = fd.methods.iter().position(|m| *m.name == "take").unwrap() as u16;
+            vec!(
+                0x0012,               // const/4 v0, #0
+                0x1071, take, 0x0000, // invoke-static {v0}, take
+                0x1012,               // const/4 v0, #1
+                0x1071, take, 0x0000, // invoke-static {v0}, take
+                0x000F                // return v0
+            )
+        });
+        // null can be passed for a String, but 1 can't; and a void method can't return a value
+        assert_eq!(findings, vec!((Some(5), FindingKind::Type), (Some(8), FindingKind::Type)));
+    }
+
+    fn catch_all(start_addr: u32, insn_count: u16, addr: u32) -> TryItem {
+        let handler = EncodedCatchHandler { handlers: vec!(), catch_all_addr: Some(addr) };
+        TryItem { start_addr, insn_count, handler }
+    }
+
+    #[test]
+    fn test_move() {
+        // move copies ints and floats; references need move-object
+        assert_eq!(verify_code(&["LMain;"], "V", 2, 0, |_| vec!(0x1001, 0x000E)), vec!((Some(0), FindingKind::Type)));
+        assert_eq!(verify_code(&["LMain;"], "V", 2, 0, |_| vec!(0x1007, 0x000E)), vec!());
+        assert_eq!(verify_code(&["F"], "V", 2, 0, |_| vec!(0x1001, 0x000E)), vec!());
+        assert_eq!(verify_code(&[], "V", 2, 0, |_| vec!(0x1112, 0x1001, 0x000E)), vec!());
+    }
+
+    #[test]
+    fn test_code_item() {
+        // const/4 v0, #0 runs off the end without a return-void
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x0012)), vec!((Some(0), FindingKind::Structure)));
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x0012, 0x000E)), vec!());
+
+        // invoke-static {v0}, take needs an out register
+        let invoke = |fd: &DexFileData| {
+            let take = fd.methods.iter().position(|m| *m.name == "take").unwrap() as u16;
+            vec!(0x0012, 0x1071, take, 0x0000, 0x000E)
+        };
+        assert_eq!(verify_code(&[], "V", 1, 0, invoke), vec!((Some(1), FindingKind::Structure)));
+        assert_eq!(verify_code(&[], "V", 1, 1, invoke), vec!());
+
+        // invoke-static {}, take is missing its argument
+        let missing = verify_code(&[], "V", 1, 1, |fd| {
+            vec!(0x0071, fd.methods.iter().position(|m| *m.name == "take").unwrap() as u16, 0x0000, 0x000E)
+        });
+        assert_eq!(missing, vec!((Some(0), FindingKind::Structure)));
+
+        // the try range ends inside const/16
+        let insns = |_: &DexFileData| vec!(0x0013, 0x0001, 0x000E);
+        assert_eq!(verify_tries(&[], "V", 1, 0, insns, vec!(catch_all(0, 1, 2))),
+                   vec!((Some(0), FindingKind::Structure)));
+        assert_eq!(verify_tries(&[], "V", 1, 0, insns, vec!(catch_all(0, 2, 2))), vec!());
+    }
+
+    #[test]
+    fn test_decode() {
+        // an unused opcode, and const/16 without its literal
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x003E, 0x000E)), vec!((Some(0), FindingKind::Decode)));
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x000E, 0x0013)), vec!((Some(1), FindingKind::Decode)));
+    }
+
+    #[test]
+    fn test_wide_pair() {
+        // the long parameter is in v3 and v4
+        let valid = verify_code(&["J"], "V", 5, 0, |_| vec!(
+            0x0016, 0x0001, // const-wide/16 v0, #1
+            0x30BB,         // add-long/2addr v0, v3
+            0x000E          // return-void
+        ));
+        assert_eq!(valid, vec!());
+        let narrow = verify_code(&["J"], "V", 5, 0, |_| vec!(
+            0x0016, 0x0001, // const-wide/16 v0, #1
+            0x1212,         // const/4 v2, #1
+            0x20BB,         // add-long/2addr v0, v2
+            0x000E          // return-void
+        ));
+        assert_eq!(narrow, vec!((Some(3), FindingKind::WidePair)));
+    }
+
+    #[test]
+    fn test_branch_target() {
+        // goto +0, then goto +1
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x0028, 0x000E)), vec!((Some(0), FindingKind::BranchTarget)));
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x0128, 0x000E)), vec!());
+
+        // a handler inside const/16, then one at return-void
+        let insns = |_: &DexFileData| vec!(0x0013, 0x0001, 0x000E);
+        assert_eq!(verify_tries(&[], "V", 1, 0, insns, vec!(catch_all(0, 2, 1))),
+                   vec!((Some(0), FindingKind::BranchTarget)));
+        assert_eq!(verify_tries(&[], "V", 1, 0, insns, vec!(catch_all(0, 2, 2))), vec!());
+    }
+
+    #[test]
+    fn test_payload() {
+        // packed-switch v0 at 0 with a one-case packed-switch-payload at the given offset, jumping to the case
+        let switch = |opcode: u16, payload: u16, case: u16| move |_: &DexFileData| {
+            let mut insns = vec!(opcode, payload, 0x0000);
+            insns.resize(payload as usize, 0x000E);
+            insns.extend(vec!(0x0100, 0x0001, 0x0000, 0x0000, case, 0x0000));
+            insns
+        };
+        assert_eq!(verify_code(&["I"], "V", 1, 0, switch(0x002B, 4, 3)), vec!());
+        // sparse-switch pointing to a packed payload
+        assert_eq!(verify_code(&["I"], "V", 1, 0, switch(0x002C, 4, 3)), vec!((Some(0), FindingKind::Payload)));
+        // a payload that isn't 4-byte aligned
+        assert_eq!(verify_code(&["I"], "V", 1, 0, switch(0x002B, 5, 3)), vec!((Some(5), FindingKind::Payload)));
+        // a case jumping into the switch itself
+        assert_eq!(verify_code(&["I"], "V", 1, 0, switch(0x002B, 4, 2)), vec!((Some(0), FindingKind::BranchTarget)));
+    }
+
+    #[test]
+    fn test_type_rules() {
+        let run = |fd: &DexFileData| fd.methods.iter().position(|m| *m.name == "run").unwrap() as u16;
+        // invoke-static {}, run, then move-result v0 or move-result-object v0, then return v0
+        assert_eq!(verify_code(&[], "I", 1, 0, |fd| vec!(0x0071, run(fd), 0x0000, 0x000A, 0x000F)), vec!());
+        assert_eq!(verify_code(&[], "I", 1, 0, |fd| vec!(0x0071, run(fd), 0x0000, 0x000C, 0x000F)),
+                   vec!((Some(3), FindingKind::Type)));
+        // move-result with no invoke before it
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x000A, 0x000E)), vec!((Some(0), FindingKind::Type)));
+
+        // sget v0 and sget-wide v0 on the int field count
+        let count = |fd: &DexFileData| fd.fields.iter().position(|f| *f.name == "count").unwrap() as u16;
+        assert_eq!(verify_code(&[], "V", 2, 0, |fd| vec!(0x0060, count(fd), 0x000E)), vec!());
+        assert_eq!(verify_code(&[], "V", 2, 0, |fd| vec!(0x0061, count(fd), 0x000E)),
+                   vec!((Some(0), FindingKind::Type)));
+
+        // move-exception is only allowed at the start of a handler
+        assert_eq!(verify_code(&[], "V", 1, 0, |_| vec!(0x000D, 0x000E)), vec!((Some(0), FindingKind::Type)));
+        let handled = verify_tries(&[], "V", 1, 0, |_| vec!(
+            0x0012, // const/4 v0, #0
+            0x0027, // throw v0
+            0x000D, // move-exception v0
+            0x000E  // return-void
+        ), vec!(catch_all(0, 2, 2)));
+        assert_eq!(handled, vec!());
+    }
+
+    #[test]
+    fn test_monitor() {
+        let monitors = |exits: [u16; 2]| move |_: &DexFileData| vec!(
+            0x001D,   // monitor-enter v0
+            0x011D,   // monitor-enter v1
+            exits[0], // monitor-exit
+            exits[1], // monitor-exit
+            0x000E    // return-void
+        );
+        assert_eq!(verify_code(&["LMain;", "LMain;"], "V", 2, 0, monitors([0x011E, 0x001E])), vec!());
+        // leaving v0 first doesn't release it, so it is still held at the return
+        assert_eq!(verify_code(&["LMain;", "LMain;"], "V", 2, 0, monitors([0x001E, 0x011E])),
+                   vec!((Some(2), FindingKind::Monitor), (Some(4), FindingKind::Monitor)));
+    }
+}
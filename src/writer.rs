@@ -0,0 +1,941 @@
+// Serializes a DexFile back into the DEX format
+//
+// The identifier pools are written out in the order they appear in file_data, so any indexes held in
+// Code.insns stay valid. Everything else in the file has to refer to entries present in those pools.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::result_types::*;
+
+// Header size is a constant 0x70 bytes according to the spec
+const HEADER_SIZE: u32 = 0x70;
+// Special value indicating there is no index value
+const NO_INDEX: u32 = 0xFFFFFFFF;
+
+pub fn write(file: &DexFile) -> Result<Vec<u8>, DexParserError> {
+    let fd = &file.file_data;
+    let idx = Indexes::new(fd);
+
+    // the identifier sections are all of a fixed size, so the data section's offset is known up front
+    let ids_size = 4 * fd.string_data.len() + 4 * fd.type_identifiers.len() + 12 * fd.prototypes.len()
+        + 8 * fd.fields.len() + 8 * fd.methods.len() + 32 * file.classes.len()
+        + 4 * fd.call_sites.len() + 8 * fd.method_handles.len();
+    let data_off = HEADER_SIZE + ids_size as u32;
+
+    let mut data = DataSection::new(data_off);
+
+    // Docs: string_data_item
+    let start = data.offset();
+    let string_offs = fd.string_data.iter()
+        .map(|s| {
+            let off = data.offset();
+            write_uleb128(&mut data.buf, s.encode_utf16().count() as u32);
+            data.buf.extend(to_mutf8(s));
+            data.buf.push(0);
+            off
+        }).collect::<Vec<_>>();
+    data.section(MapItem::STRING_DATA_ITEM, start, string_offs.len());
+
+    // Docs: type_list
+    // prototypes and classes with the same types share a single list
+    let mut type_lists = HashMap::new();
+    data.align();
+    let start = data.offset();
+    let lists = fd.prototypes.iter().map(|proto| &proto.parameters)
+        .chain(file.classes.iter().map(|class| &class.interfaces));
+    for list in lists {
+        if list.is_empty() || type_lists.contains_key(list) {
+            continue;
+        }
+        data.align();
+        type_lists.insert(list, data.offset());
+        write_u32(&mut data.buf, list.len() as u32);
+        for type_ in list {
+            write_u16(&mut data.buf, idx.type_16(type_)?);
+        }
+    }
+    data.section(MapItem::TYPE_LIST, start, type_lists.len());
+
+    let codes = file.classes.iter()
+        .filter_map(|class| class.class_data.as_ref())
+        .flat_map(|class_data| class_data.direct_methods.iter().chain(class_data.virtual_methods.iter()))
+        .filter_map(|method| method.code.as_ref())
+        .collect::<Vec<_>>();
+
+    // Docs: debug_info_item
+    let start = data.offset();
+    let mut debug_offs = Vec::with_capacity(codes.len());
+    for code in &codes {
+        debug_offs.push(match code.debug_info {
+            Some(ref debug_info) => {
+                let off = data.offset();
+                write_debug_info(&mut data.buf, debug_info, &idx)?;
+                off
+            },
+            None => 0
+        });
+    }
+    data.section(MapItem::DEBUG_INFO_ITEM, start, debug_offs.iter().filter(|off| **off != 0).count());
+
+    // Docs: code_item
+    data.align();
+    let start = data.offset();
+    let mut code_offs = Vec::with_capacity(codes.len());
+    for (code, debug_off) in codes.iter().zip(debug_offs) {
+        data.align();
+        code_offs.push(data.offset());
+        write_code_item(&mut data.buf, code, debug_off, &idx)?;
+    }
+    data.section(MapItem::CODE_ITEM, start, codes.len());
+
+    let annotations_offs = write_annotations(&mut data, file, &idx)?;
+
+    // Docs: class_data_item
+    let start = data.offset();
+    let mut code_offs = code_offs.into_iter();
+    let mut class_data_offs = Vec::with_capacity(file.classes.len());
+    for class in &file.classes {
+        class_data_offs.push(match class.class_data {
+            Some(ref class_data) => {
+                let off = data.offset();
+                write_class_data(&mut data.buf, class_data, &mut code_offs, &idx)?;
+                off
+            },
+            None => 0
+        });
+    }
+    data.section(MapItem::CLASS_DATA_ITEM, start, class_data_offs.iter().filter(|off| **off != 0).count());
+
+    // Docs: encoded_array_item
+    let start = data.offset();
+    let mut static_values_offs = Vec::with_capacity(file.classes.len());
+    for class in &file.classes {
+        static_values_offs.push(if class.static_values.is_empty() {
+            0
+        } else {
+            let off = data.offset();
+            write_encoded_array(&mut data.buf, &class.static_values, &idx)?;
+            off
+        });
+    }
+    let mut call_site_offs = Vec::with_capacity(fd.call_sites.len());
+    for call_site in &fd.call_sites {
+        call_site_offs.push(data.offset());
+        let mut values = vec!(
+            EncodedValue::MethodHandle(call_site.method_handle.clone()),
+            EncodedValue::String(call_site.method_name.clone()),
+            EncodedValue::MethodType(call_site.method_type.clone())
+        );
+        values.extend(call_site.constant_values.iter().cloned());
+        write_encoded_array(&mut data.buf, &values, &idx)?;
+    }
+    data.section(MapItem::ENCODED_ARRAY_ITEM, start,
+                 static_values_offs.iter().filter(|off| **off != 0).count() + call_site_offs.len());
+
+    // The identifier sections, which sit between the header and the data
+    let mut ids = IdSections::new();
+
+    ids.begin(MapItem::STRING_ID_ITEM, fd.string_data.len());
+    for off in string_offs {
+        write_u32(&mut ids.buf, off);
+    }
+
+    ids.begin(MapItem::TYPE_ID_ITEM, fd.type_identifiers.len());
+    for type_ in &fd.type_identifiers {
+        write_u32(&mut ids.buf, idx.string(type_)?);
+    }
+
+    ids.begin(MapItem::PROTO_ID_ITEM, fd.prototypes.len());
+    for proto in &fd.prototypes {
+        write_u32(&mut ids.buf, idx.string(&proto.shorty)?);
+        write_u32(&mut ids.buf, idx.type_(&proto.return_type)?);
+        write_u32(&mut ids.buf, type_list_off(&type_lists, &proto.parameters));
+    }
+
+    ids.begin(MapItem::FIELD_ID_ITEM, fd.fields.len());
+    for field in &fd.fields {
+        write_u16(&mut ids.buf, idx.type_16(&field.definer)?);
+        write_u16(&mut ids.buf, idx.type_16(&field.type_)?);
+        write_u32(&mut ids.buf, idx.string(&field.name)?);
+    }
+
+    ids.begin(MapItem::METHOD_ID_ITEM, fd.methods.len());
+    for method in &fd.methods {
+        write_u16(&mut ids.buf, idx.type_16(&method.definer)?);
+        write_u16(&mut ids.buf, to_u16(idx.prototype(&method.prototype)?, "prototype")?);
+        write_u32(&mut ids.buf, idx.string(&method.name)?);
+    }
+
+    ids.begin(MapItem::CLASS_DEF_ITEM, file.classes.len());
+    for (i, class) in file.classes.iter().enumerate() {
+        write_u32(&mut ids.buf, idx.type_(&class.class_type)?);
+        write_u32(&mut ids.buf, access_flag_bits(&class.access_flags));
+        write_u32(&mut ids.buf, match class.superclass {
+            Some(ref superclass) => idx.type_(superclass)?,
+            None => NO_INDEX
+        });
+        write_u32(&mut ids.buf, type_list_off(&type_lists, &class.interfaces));
+        write_u32(&mut ids.buf, match class.source_file_name {
+            Some(ref name) => idx.string(name)?,
+            None => NO_INDEX
+        });
+        write_u32(&mut ids.buf, annotations_offs[i]);
+        write_u32(&mut ids.buf, class_data_offs[i]);
+        write_u32(&mut ids.buf, static_values_offs[i]);
+    }
+
+    ids.begin(MapItem::CALL_SITE_ID_ITEM, call_site_offs.len());
+    for off in call_site_offs {
+        write_u32(&mut ids.buf, off);
+    }
+
+    ids.begin(MapItem::METHOD_HANDLE_ITEM, fd.method_handles.len());
+    for handle in &fd.method_handles {
+        write_u16(&mut ids.buf, method_handle_type(handle.type_));
+        write_u16(&mut ids.buf, 0);
+        write_u16(&mut ids.buf, match handle.member {
+            MethodHandleMember::Field(ref field) => to_u16(idx.field(field)?, "field")?,
+            MethodHandleMember::Method(ref method) => to_u16(idx.method(method)?, "method")?
+        });
+        write_u16(&mut ids.buf, 0);
+    }
+
+    // Docs: map_list
+    // the last thing in the data section, which lists itself
+    data.align();
+    let map_off = data.offset();
+    let mut map = vec!((MapItem::HEADER_ITEM, 0, 1));
+    map.extend(ids.sections.iter().cloned());
+    map.extend(data.sections.iter().cloned());
+    map.push((MapItem::MAP_LIST, map_off, 1));
+    write_u32(&mut data.buf, map.len() as u32);
+    for (type_, off, size) in &map {
+        write_u16(&mut data.buf, *type_ as u16);
+        write_u16(&mut data.buf, 0);
+        write_u32(&mut data.buf, *size as u32);
+        write_u32(&mut data.buf, *off);
+    }
+
+    let link = file.regions.iter().find(|region| region.kind == RegionKind::Link);
+    let link_off = data.offset();
+    let link_size = link.map_or(0, |link| link.data.len() as u32);
+    let file_size = link_off + link_size;
+
+    // Docs: header_item
+    let mut out = Vec::with_capacity(file_size as usize);
+    out.extend_from_slice(b"dex\n");
+    out.extend_from_slice(format!("{:03}\0", file.header.version).as_bytes());
+    // checksum and signature are filled in once everything else is written
+    out.extend_from_slice(&[0; 24]);
+    write_u32(&mut out, file_size);
+    write_u32(&mut out, HEADER_SIZE);
+    write_u32(&mut out, 0x12345678);
+    write_u32(&mut out, link_size);
+    write_u32(&mut out, if link_size == 0 { 0 } else { link_off });
+    write_u32(&mut out, map_off);
+    for type_ in &[MapItem::STRING_ID_ITEM, MapItem::TYPE_ID_ITEM, MapItem::PROTO_ID_ITEM,
+                   MapItem::FIELD_ID_ITEM, MapItem::METHOD_ID_ITEM, MapItem::CLASS_DEF_ITEM] {
+        let (off, size) = ids.sections.iter()
+            .find(|section| section.0 == *type_)
+            .map_or((0, 0), |section| (section.1, section.2));
+        write_u32(&mut out, size as u32);
+        write_u32(&mut out, off);
+    }
+    write_u32(&mut out, data.buf.len() as u32);
+    write_u32(&mut out, data_off);
+
+    out.extend(ids.buf);
+    out.extend(data.buf);
+    if let Some(link) = link {
+        out.extend_from_slice(&link.data);
+    }
+
+    // Docs: header_item, the signature covers everything after itself and the checksum everything after itself
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(&out[32 ..]);
+    out[12 .. 32].copy_from_slice(&sha1.digest().bytes());
+    let checksum = crate::parser::adler32(&out[12 ..]);
+    out[8 .. 12].copy_from_slice(&checksum.to_le_bytes());
+
+    Ok(out)
+}
+
+// Where each entry in the pools will end up, for resolving references to them
+pub(crate) struct Indexes<'a> {
+    strings: HashMap<&'a str, u32>,
+    types: HashMap<&'a str, u32>,
+    prototypes: HashMap<&'a Prototype, u32>,
+    fields: HashMap<&'a Field, u32>,
+    methods: HashMap<&'a Method, u32>,
+    method_handles: HashMap<&'a MethodHandle, u32>
+}
+
+impl<'a> Indexes<'a> {
+    pub fn new(fd: &'a DexFileData) -> Self {
+        fn index<'a, T: ?Sized + Eq + std::hash::Hash>(pool: impl Iterator<Item = &'a T>) -> HashMap<&'a T, u32> {
+            let mut indexes = HashMap::new();
+            for (i, item) in pool.enumerate() {
+                // if an entry is duplicated, the first one wins
+                indexes.entry(item).or_insert(i as u32);
+            }
+            indexes
+        }
+
+        Indexes {
+            strings: index(fd.string_data.iter().map(|s| s.as_str())),
+            types: index(fd.type_identifiers.iter().map(|s| s.as_str())),
+            prototypes: index(fd.prototypes.iter().map(Rc::as_ref)),
+            fields: index(fd.fields.iter().map(Rc::as_ref)),
+            methods: index(fd.methods.iter().map(Rc::as_ref)),
+            method_handles: index(fd.method_handles.iter().map(Rc::as_ref))
+        }
+    }
+
+    pub fn string(&self, s: &str) -> Result<u32, DexParserError> {
+        self.strings.get(s).cloned().ok_or_else(|| missing("string", &s))
+    }
+
+    pub fn type_(&self, t: &str) -> Result<u32, DexParserError> {
+        self.types.get(t).cloned().ok_or_else(|| missing("type", &t))
+    }
+
+    // type indexes are only 16 bits wide in most places
+    fn type_16(&self, t: &str) -> Result<u16, DexParserError> {
+        to_u16(self.type_(t)?, "type")
+    }
+
+    pub fn prototype(&self, proto: &Prototype) -> Result<u32, DexParserError> {
+        self.prototypes.get(proto).cloned().ok_or_else(|| missing("prototype", proto))
+    }
+
+    pub fn field(&self, field: &Field) -> Result<u32, DexParserError> {
+        self.fields.get(field).cloned().ok_or_else(|| missing("field", field))
+    }
+
+    pub fn method(&self, method: &Method) -> Result<u32, DexParserError> {
+        self.methods.get(method).cloned().ok_or_else(|| missing("method", method))
+    }
+
+    pub fn method_handle(&self, handle: &MethodHandle) -> Result<u32, DexParserError> {
+        self.method_handles.get(handle).cloned().ok_or_else(|| missing("method handle", handle))
+    }
+}
+
+fn missing(pool: &str, item: &dyn std::fmt::Debug) -> DexParserError {
+    DexParserError::WritingFailed { reason: format!("{:?} is not in the {} pool", item, pool) }
+}
+
+fn to_u16(idx: u32, pool: &str) -> Result<u16, DexParserError> {
+    if idx > u32::from(u16::MAX) {
+        return Err(DexParserError::WritingFailed {
+            reason: format!("{} index {} does not fit in 16 bits", pool, idx)
+        });
+    }
+    Ok(idx as u16)
+}
+
+fn type_list_off(type_lists: &HashMap<&Vec<Rc<String>>, u32>, list: &Vec<Rc<String>>) -> u32 {
+    type_lists.get(list).cloned().unwrap_or(0)
+}
+
+// Docs: map_list item type codes
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq)]
+enum MapItem {
+    HEADER_ITEM = 0x0000,
+    STRING_ID_ITEM = 0x0001,
+    TYPE_ID_ITEM = 0x0002,
+    PROTO_ID_ITEM = 0x0003,
+    FIELD_ID_ITEM = 0x0004,
+    METHOD_ID_ITEM = 0x0005,
+    CLASS_DEF_ITEM = 0x0006,
+    CALL_SITE_ID_ITEM = 0x0007,
+    METHOD_HANDLE_ITEM = 0x0008,
+    MAP_LIST = 0x1000,
+    TYPE_LIST = 0x1001,
+    ANNOTATION_SET_REF_LIST = 0x1002,
+    ANNOTATION_SET_ITEM = 0x1003,
+    CLASS_DATA_ITEM = 0x2000,
+    CODE_ITEM = 0x2001,
+    STRING_DATA_ITEM = 0x2002,
+    DEBUG_INFO_ITEM = 0x2003,
+    ANNOTATION_ITEM = 0x2004,
+    ENCODED_ARRAY_ITEM = 0x2005,
+    ANNOTATIONS_DIRECTORY_ITEM = 0x2006
+}
+
+// The identifier sections, laid out one after the other following the header
+struct IdSections {
+    buf: Vec<u8>,
+    sections: Vec<(MapItem, u32, usize)>
+}
+
+impl IdSections {
+    fn new() -> Self {
+        IdSections { buf: vec!(), sections: vec!() }
+    }
+
+    // empty sections are left out of the map list
+    fn begin(&mut self, type_: MapItem, size: usize) {
+        if size > 0 {
+            self.sections.push((type_, HEADER_SIZE + self.buf.len() as u32, size));
+        }
+    }
+}
+
+// The data section, along with a map list entry for each part of it
+struct DataSection {
+    base: u32,
+    buf: Vec<u8>,
+    sections: Vec<(MapItem, u32, usize)>
+}
+
+impl DataSection {
+    fn new(base: u32) -> Self {
+        DataSection { base, buf: vec!(), sections: vec!() }
+    }
+
+    fn offset(&self) -> u32 {
+        self.base + self.buf.len() as u32
+    }
+
+    fn align(&mut self) {
+        while !self.buf.len().is_multiple_of(4) {
+            self.buf.push(0);
+        }
+    }
+
+    fn section(&mut self, type_: MapItem, start: u32, size: usize) {
+        if size > 0 {
+            self.sections.push((type_, start, size));
+        }
+    }
+}
+
+// Annotation items, then the sets and set ref lists holding them, then a directory per class.
+// Returns the offset of each class's directory (0 for classes without annotations)
+fn write_annotations(data: &mut DataSection, file: &DexFile, idx: &Indexes) -> Result<Vec<u32>, DexParserError> {
+    // every set of annotations to be written, as (visibility, type, elements)
+    let mut sets = vec!();
+    // per class: the class set, then (field, set) pairs, (method, set) pairs and (method, sets per parameter)
+    let mut directories = vec!();
+
+    for class in &file.classes {
+        let annotations = match class.annotations {
+            Some(ref annotations) => annotations,
+            None => {
+                directories.push(None);
+                continue;
+            }
+        };
+
+        let class_set = if annotations.class_annotations.is_empty() {
+            None
+        } else {
+            sets.push(annotations.class_annotations.iter()
+                .map(|a| (&a.visibility, &a.type_, &a.elements))
+                .collect::<Vec<_>>());
+            Some(sets.len() - 1)
+        };
+
+        let mut fields = vec!();
+        for fa in &annotations.field_annotations {
+            sets.push(fa.annotations.iter().map(|a| (&a.visibility, &a.type_, &a.annotations)).collect());
+            fields.push((idx.field(&fa.field_data)?, sets.len() - 1));
+        }
+
+        let mut methods = vec!();
+        for ma in &annotations.method_annotations {
+            sets.push(ma.annotations.iter().map(|a| (&a.visibility, &a.type_, &a.annotations)).collect());
+            methods.push((idx.method(&ma.method)?, sets.len() - 1));
+        }
+
+        let mut parameters = vec!();
+        for pa in &annotations.parameter_annotations {
+            let mut param_sets = vec!();
+            for param in &pa.annotations {
+                param_sets.push(if param.is_empty() {
+                    None
+                } else {
+                    sets.push(param.iter().map(|a| (&a.visibility, &a.type_, &a.annotations)).collect());
+                    Some(sets.len() - 1)
+                });
+            }
+            parameters.push((idx.method(&pa.method)?, param_sets));
+        }
+
+        // Docs: annotations_directory_item, each list is sorted by index
+        fields.sort_by_key(|entry| entry.0);
+        methods.sort_by_key(|entry| entry.0);
+        parameters.sort_by_key(|entry| entry.0);
+        directories.push(Some((class_set, fields, methods, parameters)));
+    }
+
+    // Docs: annotation_item
+    let start = data.offset();
+    let mut item_offs = Vec::with_capacity(sets.len());
+    for set in &sets {
+        let mut offs = Vec::with_capacity(set.len());
+        for (visibility, type_, elements) in set {
+            offs.push((idx.type_(type_)?, data.offset()));
+            data.buf.push(match visibility {
+                Visibility::BUILD => 0x00,
+                Visibility::RUNTIME => 0x01,
+                Visibility::SYSTEM => 0x02
+            });
+            write_encoded_annotation(&mut data.buf, type_, elements, idx)?;
+        }
+        // Docs: annotation_set_item, sorted by type index
+        offs.sort_by_key(|off| off.0);
+        item_offs.push(offs);
+    }
+    data.section(MapItem::ANNOTATION_ITEM, start, item_offs.iter().map(Vec::len).sum());
+
+    // Docs: annotation_set_item
+    data.align();
+    let start = data.offset();
+    let mut set_offs = Vec::with_capacity(sets.len());
+    for offs in item_offs {
+        data.align();
+        set_offs.push(data.offset());
+        write_u32(&mut data.buf, offs.len() as u32);
+        for (_, off) in offs {
+            write_u32(&mut data.buf, off);
+        }
+    }
+    data.section(MapItem::ANNOTATION_SET_ITEM, start, set_offs.len());
+
+    // Docs: annotation_set_ref_list
+    data.align();
+    let start = data.offset();
+    let mut ref_list_offs = vec!();
+    for (_, _, _, parameters) in directories.iter().flatten() {
+        let mut offs = Vec::with_capacity(parameters.len());
+        for (_, param_sets) in parameters {
+            data.align();
+            offs.push(data.offset());
+            write_u32(&mut data.buf, param_sets.len() as u32);
+            for set in param_sets {
+                write_u32(&mut data.buf, set.map_or(0, |set| set_offs[set]));
+            }
+        }
+        ref_list_offs.push(offs);
+    }
+    data.section(MapItem::ANNOTATION_SET_REF_LIST, start, ref_list_offs.iter().map(Vec::len).sum());
+
+    // Docs: annotations_directory_item
+    data.align();
+    let start = data.offset();
+    let mut ref_list_offs = ref_list_offs.into_iter();
+    let mut directory_offs = Vec::with_capacity(directories.len());
+    for directory in directories {
+        let (class_set, fields, methods, parameters) = match directory {
+            Some(directory) => directory,
+            None => {
+                directory_offs.push(0);
+                continue;
+            }
+        };
+
+        data.align();
+        directory_offs.push(data.offset());
+        write_u32(&mut data.buf, class_set.map_or(0, |set| set_offs[set]));
+        write_u32(&mut data.buf, fields.len() as u32);
+        write_u32(&mut data.buf, methods.len() as u32);
+        write_u32(&mut data.buf, parameters.len() as u32);
+        for (field, set) in fields {
+            write_u32(&mut data.buf, field);
+            write_u32(&mut data.buf, set_offs[set]);
+        }
+        for (method, set) in methods {
+            write_u32(&mut data.buf, method);
+            write_u32(&mut data.buf, set_offs[set]);
+        }
+        for ((method, _), off) in parameters.into_iter().zip(ref_list_offs.next().unwrap_or_default()) {
+            write_u32(&mut data.buf, method);
+            write_u32(&mut data.buf, off);
+        }
+    }
+    data.section(MapItem::ANNOTATIONS_DIRECTORY_ITEM, start, directory_offs.iter().filter(|off| **off != 0).count());
+
+    Ok(directory_offs)
+}
+
+// Docs: class_data_item
+// Fields and methods are sorted by index, as only the difference from the previous index is stored
+fn write_class_data(buf: &mut Vec<u8>, class_data: &ClassData, code_offs: &mut dyn Iterator<Item = u32>,
+                    idx: &Indexes) -> Result<(), DexParserError> {
+    write_uleb128(buf, class_data.static_fields.len() as u32);
+    write_uleb128(buf, class_data.instance_fields.len() as u32);
+    write_uleb128(buf, class_data.direct_methods.len() as u32);
+    write_uleb128(buf, class_data.virtual_methods.len() as u32);
+
+    for fields in &[&class_data.static_fields, &class_data.instance_fields] {
+        let mut encoded = fields.iter()
+            .map(|field| Ok((idx.field(&field.field)?, access_flag_bits(&field.access_flags))))
+            .collect::<Result<Vec<_>, DexParserError>>()?;
+        encoded.sort_by_key(|field| field.0);
+
+        let mut prev = 0;
+        for (field_idx, flags) in encoded {
+            write_uleb128(buf, field_idx - prev);
+            write_uleb128(buf, flags);
+            prev = field_idx;
+        }
+    }
+
+    for methods in &[&class_data.direct_methods, &class_data.virtual_methods] {
+        // code offsets were handed out in declaration order, so pick them up before sorting
+        let mut encoded = methods.iter()
+            .map(|method| {
+                let code_off = if method.code.is_some() { code_offs.next().unwrap_or(0) } else { 0 };
+                Ok((idx.method(&method.method)?, access_flag_bits(&method.access_flags), code_off))
+            })
+            .collect::<Result<Vec<_>, DexParserError>>()?;
+        encoded.sort_by_key(|method| method.0);
+
+        let mut prev = 0;
+        for (method_idx, flags, code_off) in encoded {
+            write_uleb128(buf, method_idx - prev);
+            write_uleb128(buf, flags);
+            write_uleb128(buf, code_off);
+            prev = method_idx;
+        }
+    }
+
+    Ok(())
+}
+
+// Docs: code_item
+fn write_code_item(buf: &mut Vec<u8>, code: &Code, debug_info_off: u32, idx: &Indexes) -> Result<(), DexParserError> {
+    write_u16(buf, code.registers_size);
+    write_u16(buf, code.ins_size);
+    write_u16(buf, code.outs_size);
+    write_u16(buf, code.tries.len() as u16);
+    write_u32(buf, debug_info_off);
+    write_u32(buf, code.insns.len() as u32);
+    for insn in &code.insns {
+        write_u16(buf, *insn);
+    }
+
+    if code.tries.is_empty() {
+        return Ok(());
+    }
+
+    if !code.insns.len().is_multiple_of(2) {
+        write_u16(buf, 0);
+    }
+
+    // every try's handler has to be in the list, even if it was left out of Code.handlers
+    let mut handlers = code.handlers.iter().collect::<Vec<_>>();
+    for try_ in &code.tries {
+        if !handlers.contains(&&try_.handler) {
+            handlers.push(&try_.handler);
+        }
+    }
+
+    // Docs: encoded_catch_handler_list
+    // offsets to each handler are from the start of the list
+    let mut list = vec!();
+    write_uleb128(&mut list, handlers.len() as u32);
+    let mut handler_offs = Vec::with_capacity(handlers.len());
+    for handler in &handlers {
+        handler_offs.push(to_u16(list.len() as u32, "catch handler offset")?);
+        let size = handler.handlers.len() as i32;
+        write_sleb128(&mut list, if handler.catch_all_addr.is_some() { -size } else { size });
+        for pair in &handler.handlers {
+            write_uleb128(&mut list, idx.type_(&pair.type_)?);
+            write_uleb128(&mut list, pair.addr);
+        }
+        if let Some(addr) = handler.catch_all_addr {
+            write_uleb128(&mut list, addr);
+        }
+    }
+
+    // Docs: try_item
+    for try_ in &code.tries {
+        let handler = handlers.iter().position(|handler| **handler == try_.handler).unwrap_or(0);
+        write_u32(buf, try_.start_addr);
+        write_u16(buf, try_.insn_count);
+        write_u16(buf, handler_offs[handler]);
+    }
+    buf.extend(list);
+
+    Ok(())
+}
+
+// Docs: debug_info_item
+fn write_debug_info(buf: &mut Vec<u8>, debug_info: &DebugInfo, idx: &Indexes) -> Result<(), DexParserError> {
+    // uleb128p1 is the index plus one, so NO_INDEX is written as zero
+    fn write_optional(buf: &mut Vec<u8>, idx: Option<u32>) {
+        write_uleb128(buf, idx.map_or(0, |idx| idx + 1));
+    }
+    let string = |s: &Option<Rc<String>>| s.as_ref().map(|s| idx.string(s)).transpose();
+    let type_ = |t: &Option<Rc<String>>| t.as_ref().map(|t| idx.type_(t)).transpose();
+
+    write_uleb128(buf, debug_info.line_start);
+    write_uleb128(buf, debug_info.parameter_names.len() as u32);
+    for name in &debug_info.parameter_names {
+        write_optional(buf, string(name)?);
+    }
+
+    for item in &debug_info.bytecode {
+        match item {
+            DebugItemBytecodes::DBG_END_SEQUENCE => buf.push(0x00),
+            DebugItemBytecodes::DBG_ADVANCE_PC(addr_diff) => {
+                buf.push(0x01);
+                write_uleb128(buf, *addr_diff);
+            },
+            DebugItemBytecodes::DBG_ADVANCE_LINE(line_diff) => {
+                buf.push(0x02);
+                write_sleb128(buf, *line_diff);
+            },
+            DebugItemBytecodes::DBG_START_LOCAL { register_num, name, type_: t } => {
+                buf.push(0x03);
+                write_uleb128(buf, *register_num);
+                write_optional(buf, string(name)?);
+                write_optional(buf, type_(t)?);
+            },
+            DebugItemBytecodes::DBG_START_LOCAL_EXTENDED { register_num, name, type_: t, sig } => {
+                buf.push(0x04);
+                write_uleb128(buf, *register_num);
+                write_optional(buf, string(name)?);
+                write_optional(buf, type_(t)?);
+                write_optional(buf, string(sig)?);
+            },
+            DebugItemBytecodes::DBG_END_LOCAL(register_num) => {
+                buf.push(0x05);
+                write_uleb128(buf, *register_num);
+            },
+            DebugItemBytecodes::DBG_RESTART_LOCAL(register_num) => {
+                buf.push(0x06);
+                write_uleb128(buf, *register_num);
+            },
+            DebugItemBytecodes::DBG_SET_PROLOGUE_END => buf.push(0x07),
+            DebugItemBytecodes::DBG_SET_EPILOGUE_BEGIN => buf.push(0x08),
+            DebugItemBytecodes::DBG_SET_FILE(name) => {
+                buf.push(0x09);
+                write_optional(buf, string(name)?);
+            },
+            DebugItemBytecodes::SPECIAL_OPCODE(opcode) => buf.push(*opcode)
+        }
+    }
+    buf.push(0x00);
+
+    Ok(())
+}
+
+// Docs: encoded_array
+fn write_encoded_array(buf: &mut Vec<u8>, values: &[EncodedValue], idx: &Indexes) -> Result<(), DexParserError> {
+    write_uleb128(buf, values.len() as u32);
+    for value in values {
+        write_encoded_value(buf, value, idx)?;
+    }
+    Ok(())
+}
+
+// Docs: encoded_annotation
+fn write_encoded_annotation(buf: &mut Vec<u8>, type_: &str, elements: &[AnnotationElement],
+                            idx: &Indexes) -> Result<(), DexParserError> {
+    write_uleb128(buf, idx.type_(type_)?);
+    write_uleb128(buf, elements.len() as u32);
+
+    // Docs: annotation_element, sorted by name index
+    let mut sorted = elements.iter()
+        .map(|element| Ok((idx.string(&element.name)?, element)))
+        .collect::<Result<Vec<_>, DexParserError>>()?;
+    sorted.sort_by_key(|element| element.0);
+
+    for (name, element) in sorted {
+        write_uleb128(buf, name);
+        write_encoded_value(buf, &element.value, idx)?;
+    }
+    Ok(())
+}
+
+// Docs: encoded_value
+// Values are written in as few bytes as they can be
+fn write_encoded_value(buf: &mut Vec<u8>, value: &EncodedValue, idx: &Indexes) -> Result<(), DexParserError> {
+    match value {
+        EncodedValue::Byte(v) => write_value(buf, 0x00, &[*v]),
+        EncodedValue::Short(v) => write_signed(buf, 0x02, i64::from(*v)),
+        EncodedValue::Char(v) => write_unsigned(buf, 0x03, u64::from(*v)),
+        EncodedValue::Int(v) => write_signed(buf, 0x04, i64::from(*v)),
+        EncodedValue::Long(v) => write_signed(buf, 0x06, *v),
+        EncodedValue::Float(v) => write_right_zero_extended(buf, 0x10, u64::from(v.to_bits()) << 32, 4),
+        EncodedValue::Double(v) => write_right_zero_extended(buf, 0x11, v.to_bits(), 8),
+        EncodedValue::MethodType(v) => write_unsigned(buf, 0x15, u64::from(idx.prototype(v)?)),
+        EncodedValue::MethodHandle(v) => write_unsigned(buf, 0x16, u64::from(idx.method_handle(v)?)),
+        EncodedValue::String(v) => write_unsigned(buf, 0x17, u64::from(idx.string(v)?)),
+        EncodedValue::Type(v) => write_unsigned(buf, 0x18, u64::from(idx.type_(v)?)),
+        EncodedValue::Field(v) => write_unsigned(buf, 0x19, u64::from(idx.field(v)?)),
+        EncodedValue::Method(v) => write_unsigned(buf, 0x1A, u64::from(idx.method(v)?)),
+        EncodedValue::Enum(v) => write_unsigned(buf, 0x1B, u64::from(idx.field(v)?)),
+        EncodedValue::Array(values) => {
+            buf.push(0x1C);
+            write_encoded_array(buf, values, idx)?;
+        },
+        EncodedValue::Annotation(annotation) => {
+            buf.push(0x1D);
+            write_encoded_annotation(buf, &annotation.type_, &annotation.values, idx)?;
+        },
+        EncodedValue::Null => buf.push(0x1E),
+        // the value is held in the value arg
+        EncodedValue::Boolean(v) => buf.push((u8::from(*v) << 5) | 0x1F)
+    }
+    Ok(())
+}
+
+fn write_value(buf: &mut Vec<u8>, value_type: u8, bytes: &[u8]) {
+    buf.push(((bytes.len() as u8 - 1) << 5) | value_type);
+    buf.extend_from_slice(bytes);
+}
+
+// sign-extended on reading, so leading bytes which only repeat the sign can be dropped
+fn write_signed(buf: &mut Vec<u8>, value_type: u8, value: i64) {
+    let bytes = value.to_le_bytes();
+    let mut len = 8;
+    while len > 1 && i64::from(bytes[len - 2] as i8) >> 8 == i64::from(bytes[len - 1] as i8) {
+        len -= 1;
+    }
+    write_value(buf, value_type, &bytes[.. len]);
+}
+
+// zero-extended on reading, so leading zero bytes can be dropped
+fn write_unsigned(buf: &mut Vec<u8>, value_type: u8, value: u64) {
+    let bytes = value.to_le_bytes();
+    let len = 8 - bytes.iter().rev().take_while(|b| **b == 0).count().min(7);
+    write_value(buf, value_type, &bytes[.. len]);
+}
+
+// zero-extended to the right on reading, so trailing zero bytes (the low-order ones) can be dropped
+fn write_right_zero_extended(buf: &mut Vec<u8>, value_type: u8, value: u64, width: usize) {
+    let bytes = value.to_le_bytes();
+    let zeros = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    write_value(buf, value_type, &bytes[zeros.max(8 - width) ..]);
+}
+
+// The raw access_flags value the flags are written as
+pub fn access_flag_bits(flags: &[AccessFlag]) -> u32 {
+    flags.iter().fold(0, |bits, flag| bits | access_flag_bit(flag))
+}
+
+// some flags share a bit, meaning different things for fields and methods
+pub(crate) fn access_flag_bit(flag: &AccessFlag) -> u32 {
+    match flag {
+        AccessFlag::ACC_PUBLIC => 0x01,
+        AccessFlag::ACC_PRIVATE => 0x02,
+        AccessFlag::ACC_PROTECTED => 0x04,
+        AccessFlag::ACC_STATIC => 0x08,
+        AccessFlag::ACC_FINAL => 0x10,
+        AccessFlag::ACC_SYNCHRONIZED => 0x20,
+        AccessFlag::ACC_VOLATILE | AccessFlag::ACC_BRIDGE => 0x40,
+        AccessFlag::ACC_TRANSIENT | AccessFlag::ACC_VARARGS => 0x80,
+        AccessFlag::ACC_NATIVE => 0x100,
+        AccessFlag::ACC_INTERFACE => 0x200,
+        AccessFlag::ACC_ABSTRACT => 0x400,
+        AccessFlag::ACC_STRICT => 0x800,
+        AccessFlag::ACC_SYNTHETIC => 0x1000,
+        AccessFlag::ACC_ANNOTATION => 0x2000,
+        AccessFlag::ACC_ENUM => 0x4000,
+        AccessFlag::UNUSED => 0x8000,
+        AccessFlag::ACC_CONSTRUCTOR => 0x10000,
+        AccessFlag::ACC_DECLARED_SYNCHRONIZED => 0x20000
+    }
+}
+
+fn method_handle_type(type_: MethodHandleType) -> u16 {
+    match type_ {
+        MethodHandleType::STATIC_PUT => 0x00,
+        MethodHandleType::STATIC_GET => 0x01,
+        MethodHandleType::INSTANCE_PUT => 0x02,
+        MethodHandleType::INSTANCE_GET => 0x03,
+        MethodHandleType::INVOKE_STATIC => 0x04,
+        MethodHandleType::INVOKE_INSTANCE => 0x05,
+        MethodHandleType::INVOKE_CONSTRUCTOR => 0x06,
+        MethodHandleType::INVOKE_DIRECT => 0x07,
+        MethodHandleType::INVOKE_INTERFACE => 0x08
+    }
+}
+
+// Docs: MUTF-8 (Modified UTF-8) Encoding
+// nulls are written as two bytes, and characters outside the BMP as a surrogate pair of three bytes each
+pub(crate) fn to_mutf8(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    for unit in s.encode_utf16() {
+        match unit {
+            0x01 ..= 0x7F => out.push(unit as u8),
+            0x00 | 0x80 ..= 0x7FF => {
+                out.push(0xC0 | (unit >> 6) as u8);
+                out.push(0x80 | (unit & 0x3F) as u8);
+            },
+            _ => {
+                out.push(0xE0 | (unit >> 12) as u8);
+                out.push(0x80 | ((unit >> 6) & 0x3F) as u8);
+                out.push(0x80 | (unit & 0x3F) as u8);
+            }
+        }
+    }
+    out
+}
+
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_uleb128(buf: &mut Vec<u8>, value: u32) {
+    leb128::write::unsigned(buf, u64::from(value)).unwrap();
+}
+
+fn write_sleb128(buf: &mut Vec<u8>, value: i32) {
+    leb128::write::signed(buf, i64::from(value)).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_signed() {
+        let mut buf = vec!();
+        write_signed(&mut buf, 0x04, 0x7F);
+        write_signed(&mut buf, 0x04, 0x80);
+        write_signed(&mut buf, 0x04, -1);
+        write_signed(&mut buf, 0x06, i64::MIN);
+
+        assert_eq!(buf, vec!(0x04, 0x7F, 0x24, 0x80, 0x00, 0x04, 0xFF,
+                             0xE6, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80));
+    }
+
+    #[test]
+    fn test_write_unsigned() {
+        let mut buf = vec!();
+        write_unsigned(&mut buf, 0x17, 0);
+        write_unsigned(&mut buf, 0x17, 0x1234);
+
+        assert_eq!(buf, vec!(0x17, 0x00, 0x37, 0x34, 0x12));
+    }
+
+    #[test]
+    fn test_write_right_zero_extended() {
+        let mut buf = vec!();
+        // 1.0f is 0x3F800000, so only the top two bytes are needed
+        write_right_zero_extended(&mut buf, 0x10, u64::from(1.0_f32.to_bits()) << 32, 4);
+        write_right_zero_extended(&mut buf, 0x11, 0_f64.to_bits(), 8);
+
+        assert_eq!(buf, vec!(0x30, 0x80, 0x3F, 0x11, 0x00));
+    }
+
+    #[test]
+    fn test_to_mutf8() {
+        assert_eq!(to_mutf8("a\0"), vec!(0x61, 0xC0, 0x80));
+        // U+1F600 as a surrogate pair
+        assert_eq!(to_mutf8("\u{1F600}"), vec!(0xED, 0xA0, 0xBD, 0xED, 0xB8, 0x80));
+    }
+}
@@ -0,0 +1,362 @@
+// An index of where each string, type, field and method is referenced from
+//
+// References are gathered from the bytecode of every method, the catch handlers of its try blocks, the
+// values of every annotation and the initial values of static fields, so each lookup is a single hash.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::error::DexParserError;
+use crate::instructions::{self, IndexKind, Instruction, Opcode};
+use crate::result_types::*;
+
+#[derive(Debug, Default)]
+pub struct XrefIndex {
+    strings: HashMap<Rc<String>, Vec<Xref>>,
+    types: HashMap<Rc<String>, Vec<Xref>>,
+    fields: HashMap<Rc<Field>, Vec<Xref>>,
+    methods: HashMap<Rc<Method>, Vec<Xref>>
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Xref {
+    pub kind: XrefKind,
+    pub source: XrefSource
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum XrefKind {
+    // a field get
+    Read,
+    // a field put
+    Write,
+    // an invoke, or the bootstrap method of an invoke-custom
+    Call,
+    // new-instance, new-array and filled-new-array
+    Instantiate,
+    // anything else: const-string, const-class, check-cast, catch handlers, encoded values, ...
+    Use
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum XrefSource {
+    // an instruction of a method, the offset in 16-bit code units. For catch handlers it is the handler address
+    Code { method: Rc<Method>, offset: u32 },
+    Annotation { class: Rc<String>, target: AnnotationTarget },
+    // the initial value of a static field
+    StaticValue { field: Rc<Field> }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AnnotationTarget {
+    Class,
+    Field(Rc<Field>),
+    Method(Rc<Method>),
+    // the method and the index of the parameter
+    Parameter(Rc<Method>, usize)
+}
+
+impl Xref {
+    // The method the reference is made from, if it is made from code
+    pub fn method(&self) -> Option<&Rc<Method>> {
+        match self.source {
+            XrefSource::Code { ref method, .. } => Some(method),
+            _ => None
+        }
+    }
+
+    pub fn offset(&self) -> Option<u32> {
+        match self.source {
+            XrefSource::Code { offset, .. } => Some(offset),
+            _ => None
+        }
+    }
+}
+
+impl XrefIndex {
+    pub fn new(file: &DexFile) -> Result<Self, DexParserError> {
+        let mut index = XrefIndex::default();
+        for class in &file.classes {
+            index.add_class(file, class)?;
+        }
+        Ok(index)
+    }
+
+    pub fn string_refs(&self, s: &str) -> &[Xref] {
+        lookup(&self.strings, &s.to_string())
+    }
+
+    // Types are looked up by descriptor, e.g. "Ljava/lang/Object;"
+    pub fn type_refs(&self, descriptor: &str) -> &[Xref] {
+        lookup(&self.types, &descriptor.to_string())
+    }
+
+    pub fn field_refs(&self, field: &Field) -> &[Xref] {
+        lookup(&self.fields, field)
+    }
+
+    pub fn method_refs(&self, method: &Method) -> &[Xref] {
+        lookup(&self.methods, method)
+    }
+
+    pub fn readers<'a>(&'a self, field: &Field) -> impl Iterator<Item = &'a Xref> {
+        of_kind(self.field_refs(field), XrefKind::Read)
+    }
+
+    pub fn writers<'a>(&'a self, field: &Field) -> impl Iterator<Item = &'a Xref> {
+        of_kind(self.field_refs(field), XrefKind::Write)
+    }
+
+    pub fn callers<'a>(&'a self, method: &Method) -> impl Iterator<Item = &'a Xref> {
+        of_kind(self.method_refs(method), XrefKind::Call)
+    }
+
+    pub fn instantiations<'a>(&'a self, descriptor: &str) -> impl Iterator<Item = &'a Xref> {
+        of_kind(self.type_refs(descriptor), XrefKind::Instantiate)
+    }
+
+    fn add_class(&mut self, file: &DexFile, class: &ClassDefinition) -> Result<(), DexParserError> {
+        if let Some(ref class_data) = class.class_data {
+            // static_values holds the leading static fields' values, in the order the fields are declared
+            for (field, value) in class_data.static_fields.iter().zip(class.static_values.iter()) {
+                self.add_value(value, &XrefSource::StaticValue { field: field.field.clone() });
+            }
+
+            for method in class_data.direct_methods.iter().chain(class_data.virtual_methods.iter()) {
+                if let Some(ref code) = method.code {
+                    self.add_code(file, &method.method, code)?;
+                }
+            }
+        }
+
+        if let Some(ref annotations) = class.annotations {
+            let source = |target| XrefSource::Annotation { class: class.class_type.clone(), target };
+            for annotation in &annotations.class_annotations {
+                self.add_annotation(&annotation.type_, &annotation.elements, &source(AnnotationTarget::Class));
+            }
+            for annotation in &annotations.field_annotations {
+                let source = source(AnnotationTarget::Field(annotation.field_data.clone()));
+                for item in &annotation.annotations {
+                    self.add_annotation(&item.type_, &item.annotations, &source);
+                }
+            }
+            for annotation in &annotations.method_annotations {
+                let source = source(AnnotationTarget::Method(annotation.method.clone()));
+                for item in &annotation.annotations {
+                    self.add_annotation(&item.type_, &item.annotations, &source);
+                }
+            }
+            for annotation in &annotations.parameter_annotations {
+                for (i, parameter) in annotation.annotations.iter().enumerate() {
+                    let source = source(AnnotationTarget::Parameter(annotation.method.clone(), i));
+                    for item in parameter {
+                        self.add_annotation(&item.type_, &item.annotations, &source);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_code(&mut self, file: &DexFile, method: &Rc<Method>, code: &Code) -> Result<(), DexParserError> {
+        let fd = &file.file_data;
+        for insn in instructions::decode_all(&code.insns)? {
+            let source = XrefSource::Code { method: method.clone(), offset: insn.offset };
+            match insn.opcode.index_kind() {
+                IndexKind::String => {
+                    let string = pool_entry(&fd.string_data, &insn, "string")?;
+                    push(&mut self.strings, string, XrefKind::Use, source);
+                },
+                IndexKind::Type => {
+                    let type_ = pool_entry(&fd.type_identifiers, &insn, "type")?;
+                    let kind = match insn.opcode {
+                        Opcode::NEW_INSTANCE | Opcode::NEW_ARRAY | Opcode::FILLED_NEW_ARRAY
+                            | Opcode::FILLED_NEW_ARRAY_RANGE => XrefKind::Instantiate,
+                        _ => XrefKind::Use
+                    };
+                    push(&mut self.types, type_, kind, source);
+                },
+                IndexKind::Field => {
+                    let field = pool_entry(&fd.fields, &insn, "field")?;
+                    let name = insn.opcode.name();
+                    let kind = if name.starts_with("iget") || name.starts_with("sget") { XrefKind::Read } else { XrefKind::Write };
+                    push(&mut self.fields, field, kind, source);
+                },
+                IndexKind::Method => {
+                    let method = pool_entry(&fd.methods, &insn, "method")?;
+                    push(&mut self.methods, method, XrefKind::Call, source);
+                },
+                IndexKind::CallSite => {
+                    let call_site = pool_entry(&fd.call_sites, &insn, "call site")?;
+                    if let MethodHandleMember::Method(ref bootstrap) = call_site.method_handle.member {
+                        push(&mut self.methods, bootstrap, XrefKind::Call, source.clone());
+                    }
+                    for value in &call_site.constant_values {
+                        self.add_value(value, &source);
+                    }
+                },
+                IndexKind::MethodHandle => {
+                    let method_handle = pool_entry(&fd.method_handles, &insn, "method handle")?;
+                    self.add_method_handle(method_handle, &source);
+                },
+                IndexKind::Prototype | IndexKind::None => {}
+            }
+        }
+
+        for try_ in &code.tries {
+            for handler in &try_.handler.handlers {
+                let source = XrefSource::Code { method: method.clone(), offset: handler.addr };
+                push(&mut self.types, &handler.type_, XrefKind::Use, source);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_annotation(&mut self, type_: &Rc<String>, elements: &[AnnotationElement], source: &XrefSource) {
+        push(&mut self.types, type_, XrefKind::Use, source.clone());
+        for element in elements {
+            self.add_value(&element.value, source);
+        }
+    }
+
+    fn add_method_handle(&mut self, method_handle: &MethodHandle, source: &XrefSource) {
+        match method_handle.member {
+            MethodHandleMember::Field(ref field) => push(&mut self.fields, field, XrefKind::Use, source.clone()),
+            MethodHandleMember::Method(ref method) => push(&mut self.methods, method, XrefKind::Use, source.clone())
+        }
+    }
+
+    fn add_value(&mut self, value: &EncodedValue, source: &XrefSource) {
+        match *value {
+            EncodedValue::String(ref s) => push(&mut self.strings, s, XrefKind::Use, source.clone()),
+            EncodedValue::Type(ref t) => push(&mut self.types, t, XrefKind::Use, source.clone()),
+            EncodedValue::Field(ref f) | EncodedValue::Enum(ref f) => push(&mut self.fields, f, XrefKind::Use, source.clone()),
+            EncodedValue::Method(ref m) => push(&mut self.methods, m, XrefKind::Use, source.clone()),
+            EncodedValue::MethodHandle(ref mh) => self.add_method_handle(mh, source),
+            EncodedValue::Array(ref values) => {
+                for value in values {
+                    self.add_value(value, source);
+                }
+            },
+            EncodedValue::Annotation(ref annotation) => self.add_annotation(&annotation.type_, &annotation.values, source),
+            _ => {}
+        }
+    }
+}
+
+fn pool_entry<'a, T>(pool: &'a [Rc<T>], insn: &Instruction, name: &str) -> Result<&'a Rc<T>, DexParserError> {
+    let idx = insn.index.unwrap_or(0);
+    pool.get(idx as usize).ok_or_else(|| DexParserError::InvalidBytecode {
+        offset: insn.offset,
+        reason: format!("{} index {} is out of range", name, idx)
+    })
+}
+
+fn push<K: Eq + std::hash::Hash>(map: &mut HashMap<Rc<K>, Vec<Xref>>, key: &Rc<K>, kind: XrefKind, source: XrefSource) {
+    map.entry(key.clone()).or_default().push(Xref { kind, source });
+}
+
+fn lookup<'a, K: Eq + std::hash::Hash>(map: &'a HashMap<Rc<K>, Vec<Xref>>, key: &K) -> &'a [Xref] {
+    map.get(key).map(|xrefs| xrefs.as_slice()).unwrap_or(&[])
+}
+
+fn of_kind(xrefs: &[Xref], kind: XrefKind) -> impl Iterator<Item = &Xref> {
+    xrefs.iter().filter(move |xref| xref.kind == kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DexBuilder;
+
+    fn build_file() -> (DexFile, Rc<Field>, Rc<Method>, Rc<Method>) {
+        let mut b = DexBuilder::new(35);
+        let name = b.field("LMain;", "NAME", "Ljava/lang/String;");
+        let run = b.method("LMain;", "run", "V", &[]);
+        let go = b.method("LTarget;", "go", "V", &[]);
+        let hello = b.string("hello");
+        let class = ClassDefinition {
+            class_type: b.type_("LMain;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(b.type_("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: None,
+            annotations: Some(Annotations {
+                class_annotations: vec!(ClassAnnotation {
+                    visibility: Visibility::RUNTIME,
+                    type_: b.type_("LAnno;"),
+                    elements: vec!(AnnotationElement { name: b.string("value"), value: EncodedValue::Type(b.type_("LTarget;")) })
+                }),
+                field_annotations: vec!(),
+                method_annotations: vec!(),
+                parameter_annotations: vec!()
+            }),
+            class_data: Some(ClassData {
+                static_fields: vec!(EncodedField { field: name.clone(), access_flags: vec!(AccessFlag::ACC_STATIC) }),
+                instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod {
+                    method: run.clone(),
+                    access_flags: vec!(AccessFlag::ACC_STATIC),
+                    code: Some(Code {
+                        registers_size: 1, ins_size: 0, outs_size: 0, debug_info: None, insns: vec!(), tries: vec!(), handlers: vec!()
+                    }),
+                    system_annotations: Default::default()
+                }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(EncodedValue::String(hello)),
+            system_annotations: Default::default()
+        };
+        b.class(class);
+        let mut file = b.build().unwrap();
+
+        // the pools are only sorted once built, so the bytecode goes in afterwards
+        let fd = &file.file_data;
+        let string = fd.string_data.iter().position(|s| **s == "hello").unwrap() as u16;
+        let target = fd.type_identifiers.iter().position(|t| **t == "LTarget;").unwrap() as u16;
+        let field = fd.fields.iter().position(|f| *f == name).unwrap() as u16;
+        let method = fd.methods.iter().position(|m| *m == go).unwrap() as u16;
+        let insns = vec!(
+            0x001A, string,         // const-string v0, "hello"
+            0x0062, field,          // sget-object v0, NAME
+            0x0071, method, 0x0000, // invoke-static {}, go
+            0x0022, target,         // new-instance v0, LTarget;
+            0x0069, field,          // sput-object v0, NAME
+            0x000E                  // return-void
+        );
+        file.classes[0].class_data.as_mut().unwrap().direct_methods[0].code.as_mut().unwrap().insns = insns;
+
+        (file, name, run, go)
+    }
+
+    #[test]
+    fn test_code_references() {
+        let (file, name, run, go) = build_file();
+        let index = XrefIndex::new(&file).unwrap();
+
+        let code = |offset| XrefSource::Code { method: run.clone(), offset };
+        assert_eq!(index.readers(&name).map(|x| x.source.clone()).collect::<Vec<_>>(), vec!(code(2)));
+        assert_eq!(index.writers(&name).map(|x| x.source.clone()).collect::<Vec<_>>(), vec!(code(9)));
+        assert_eq!(index.callers(&go).map(|x| (x.method(), x.offset())).collect::<Vec<_>>(), vec!((Some(&run), Some(4))));
+        assert_eq!(index.instantiations("LTarget;").map(|x| x.offset()).collect::<Vec<_>>(), vec!(Some(7)));
+        assert!(index.method_refs(&run).is_empty());
+    }
+
+    #[test]
+    fn test_value_references() {
+        let (file, name, _, _) = build_file();
+        let index = XrefIndex::new(&file).unwrap();
+
+        assert_eq!(index.string_refs("hello").iter().map(|x| x.source.clone()).collect::<Vec<_>>(), vec!(
+            XrefSource::StaticValue { field: name },
+            XrefSource::Code { method: file.file_data.methods.iter().find(|m| *m.name == "run").unwrap().clone(), offset: 0 }
+        ));
+
+        let annotation = XrefSource::Annotation { class: Rc::new("LMain;".to_string()), target: AnnotationTarget::Class };
+        assert_eq!(index.type_refs("LAnno;"), &[Xref { kind: XrefKind::Use, source: annotation.clone() }]);
+        assert!(index.type_refs("LTarget;").contains(&Xref { kind: XrefKind::Use, source: annotation }));
+        assert!(index.string_refs("missing").is_empty());
+    }
+}
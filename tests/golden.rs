@@ -0,0 +1,522 @@
+// Complete files assembled with DexBuilder, checked against everything parse gives back for them
+
+use std::rc::Rc;
+
+use dexparser::*;
+
+fn s(value: &str) -> Rc<String> {
+    Rc::new(value.to_string())
+}
+
+fn round_trip(file: &DexFile) -> DexFile {
+    let bytes = write(file).unwrap();
+    assert_eq!(bytes.len() as u32, file.header.file_size);
+    parse_with_options(&bytes, ParseOptions::new().verify_checksum(true)).unwrap()
+}
+
+#[test]
+fn test_minimal_class() {
+    let mut builder = DexBuilder::new(35);
+    let count = builder.field("LHello;", "count", "I");
+    let main = builder.method("LHello;", "main", "V", &[]);
+    let class = ClassDefinition {
+        class_type: builder.type_("LHello;"),
+        access_flags: vec!(AccessFlag::ACC_PUBLIC),
+        superclass: Some(builder.type_("Ljava/lang/Object;")),
+        interfaces: vec!(),
+        source_file_name: Some(builder.string("Hello.java")),
+        annotations: None,
+        class_data: Some(ClassData {
+            static_fields: vec!(EncodedField { field: count, access_flags: vec!(AccessFlag::ACC_STATIC) }),
+            instance_fields: vec!(),
+            direct_methods: vec!(EncodedMethod {
+                method: main,
+                access_flags: vec!(AccessFlag::ACC_STATIC, AccessFlag::ACC_PUBLIC),
+                code: Some(Code {
+                    registers_size: 0,
+                    ins_size: 0,
+                    outs_size: 0,
+                    debug_info: None,
+                    // return-void
+                    insns: vec!(0x000E),
+                    tries: vec!(),
+                    handlers: vec!()
+                }),
+                system_annotations: Default::default()
+            }),
+            virtual_methods: vec!()
+        }),
+        static_values: vec!(EncodedValue::Int(42)),
+        system_annotations: Default::default()
+    };
+    builder.class(class);
+    let built = builder.build().unwrap();
+
+    let proto = Rc::new(Prototype { shorty: s("V"), return_type: s("V"), parameters: vec!() });
+    let field = Rc::new(Field { definer: s("LHello;"), type_: s("I"), name: s("count") });
+    let method = Rc::new(Method { definer: s("LHello;"), prototype: proto.clone(), name: s("main") });
+    let expected = DexFile {
+        header: Header {
+            version: 35,
+            checksum: built.header.checksum.clone(),
+            signature: built.header.signature,
+            file_size: built.header.file_size,
+            endianness: Endianness::Little
+        },
+        file_data: DexFileData {
+            // sorted by UTF-16 code units, so upper case comes first
+            string_data: vec!(s("Hello.java"), s("I"), s("LHello;"), s("Ljava/lang/Object;"), s("V"),
+                              s("count"), s("main")),
+            type_identifiers: vec!(s("I"), s("LHello;"), s("Ljava/lang/Object;"), s("V")),
+            prototypes: vec!(proto),
+            fields: vec!(field.clone()),
+            methods: vec!(method.clone()),
+            method_handles: vec!(),
+            call_sites: vec!()
+        },
+        classes: vec!(ClassDefinition {
+            class_type: s("LHello;"),
+            access_flags: vec!(AccessFlag::ACC_PUBLIC),
+            superclass: Some(s("Ljava/lang/Object;")),
+            interfaces: vec!(),
+            source_file_name: Some(s("Hello.java")),
+            annotations: None,
+            class_data: Some(ClassData {
+                static_fields: vec!(EncodedField { field, access_flags: vec!(AccessFlag::ACC_STATIC) }),
+                instance_fields: vec!(),
+                direct_methods: vec!(EncodedMethod {
+                    method,
+                    // in the order of their bits
+                    access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_STATIC),
+                    code: Some(Code {
+                        registers_size: 0,
+                        ins_size: 0,
+                        outs_size: 0,
+                        debug_info: None,
+                        insns: vec!(0x000E),
+                        tries: vec!(),
+                        handlers: vec!()
+                    }),
+                    system_annotations: Default::default()
+                }),
+                virtual_methods: vec!()
+            }),
+            static_values: vec!(EncodedValue::Int(42)),
+            system_annotations: Default::default()
+        }),
+        regions: vec!()
+    };
+
+    assert_eq!(built, expected);
+    assert_eq!(round_trip(&built), expected);
+}
+
+// One of everything: annotations of every kind, every type of encoded value, code with tries and
+// debug info, and the method handles and call sites added in version 038
+fn build_full_file() -> DexFile {
+    let mut b = DexBuilder::new(38);
+
+    let object = b.type_("Ljava/lang/Object;");
+    let runnable = b.type_("Ljava/lang/Runnable;");
+    let base = b.type_("Lgolden/Base;");
+    let main = b.type_("Lgolden/Main;");
+    let nullable = b.type_("Lgolden/Nullable;");
+    let info = b.type_("Lgolden/Info;");
+    let exception = b.type_("Ljava/lang/Exception;");
+
+    let name = b.field("Lgolden/Main;", "name", "Ljava/lang/String;");
+    let counter = b.field("Lgolden/Main;", "COUNTER", "J");
+    let values = b.field("Lgolden/Main;", "VALUES", "[I");
+    let level = b.field("Lgolden/Level;", "HIGH", "Lgolden/Level;");
+
+    let init = b.method("Lgolden/Main;", "<init>", "V", &[]);
+    let run = b.method("Lgolden/Main;", "run", "V", &[]);
+    let compute = b.method("Lgolden/Main;", "compute", "I", &["I", "Ljava/lang/String;"]);
+    let base_init = b.method("Lgolden/Base;", "<init>", "V", &[]);
+    let bootstrap = b.method("Ljava/lang/invoke/LambdaMetafactory;", "metafactory", "Ljava/lang/invoke/CallSite;",
+                             &["Ljava/lang/invoke/MethodHandles$Lookup;", "Ljava/lang/String;",
+                               "Ljava/lang/invoke/MethodType;"]);
+    // only referred to from bytecode
+    let _to_string = b.method("Ljava/lang/Object;", "toString", "Ljava/lang/String;", &[]);
+
+    let bootstrap_handle = b.method_handle(MethodHandleType::INVOKE_STATIC, MethodHandleMember::Method(bootstrap));
+    let getter_handle = b.method_handle(MethodHandleType::STATIC_GET, MethodHandleMember::Field(counter.clone()));
+    let runnable_type = b.prototype("Ljava/lang/Runnable;", &[]);
+    let void_type = b.prototype("V", &[]);
+    let lambda = b.method("Lgolden/Main;", "lambda$run$0", "V", &[]);
+    let lambda_handle = b.method_handle(MethodHandleType::INVOKE_STATIC, MethodHandleMember::Method(lambda.clone()));
+    b.call_site(bootstrap_handle, "run", runnable_type, vec!(
+        EncodedValue::MethodType(void_type.clone()),
+        EncodedValue::MethodHandle(lambda_handle),
+        EncodedValue::MethodType(void_type)
+    ));
+
+    let annotation = |visibility: Visibility, type_: &Rc<String>, elements: Vec<(Rc<String>, EncodedValue)>| {
+        AnnotationItem {
+            visibility,
+            type_: type_.clone(),
+            annotations: elements.into_iter()
+                .map(|(name, value)| AnnotationElement { name, value })
+                .collect()
+        }
+    };
+
+    b.class(ClassDefinition {
+        class_type: base.clone(),
+        access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_ABSTRACT),
+        superclass: Some(object.clone()),
+        interfaces: vec!(),
+        source_file_name: None,
+        annotations: None,
+        class_data: Some(ClassData {
+            static_fields: vec!(),
+            instance_fields: vec!(),
+            direct_methods: vec!(EncodedMethod {
+                method: base_init,
+                access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_CONSTRUCTOR),
+                code: Some(Code {
+                    registers_size: 1,
+                    ins_size: 1,
+                    outs_size: 1,
+                    debug_info: None,
+                    // invoke-direct {v0}, method@0000; return-void
+                    insns: vec!(0x1070, 0x0000, 0x0000, 0x000E),
+                    tries: vec!(),
+                    handlers: vec!()
+                }),
+                system_annotations: Default::default()
+            }),
+            virtual_methods: vec!()
+        }),
+        static_values: vec!(),
+        system_annotations: Default::default()
+    });
+
+    let catch_exception = EncodedCatchHandler {
+        handlers: vec!(EncodedTypeAddrPair { type_: exception, addr: 5 }),
+        catch_all_addr: None
+    };
+    let catch_all = EncodedCatchHandler {
+        handlers: vec!(),
+        catch_all_addr: Some(6)
+    };
+
+    let info_annotation = annotation(Visibility::RUNTIME, &info, vec!(
+        (b.string("value"), EncodedValue::String(b.string("main"))),
+        (b.string("level"), EncodedValue::Enum(level)),
+        (b.string("count"), EncodedValue::Int(3)),
+        (b.string("nested"), EncodedValue::Annotation(EncodedAnnotationItem {
+            type_: nullable.clone(),
+            values: vec!(AnnotationElement { name: b.string("reason"), value: EncodedValue::Null })
+        }))
+    ));
+    let build_annotation = annotation(Visibility::BUILD, &nullable, vec!());
+    let signature = b.type_("Ldalvik/annotation/Signature;");
+    let system_annotation = annotation(Visibility::SYSTEM, &signature, vec!(
+        (b.string("value"), EncodedValue::Array(vec!(EncodedValue::String(b.string("()V")))))
+    ));
+    let nullable_parameter = annotation(Visibility::RUNTIME, &nullable, vec!());
+
+    let class = ClassDefinition {
+        class_type: main.clone(),
+        access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_FINAL),
+        superclass: Some(base),
+        interfaces: vec!(runnable),
+        source_file_name: Some(b.string("Main.java")),
+        annotations: Some(Annotations {
+            class_annotations: vec!(ClassAnnotation {
+                visibility: info_annotation.visibility.clone(),
+                type_: info_annotation.type_.clone(),
+                elements: info_annotation.annotations.clone()
+            }),
+            field_annotations: vec!(FieldAnnotation { field_data: name.clone(), annotations: vec!(build_annotation) }),
+            method_annotations: vec!(MethodAnnotation { method: run.clone(), annotations: vec!(system_annotation) }),
+            parameter_annotations: vec!(ParameterAnnotation {
+                method: compute.clone(),
+                annotations: vec!(vec!(), vec!(nullable_parameter))
+            })
+        }),
+        class_data: Some(ClassData {
+            static_fields: vec!(
+                EncodedField { field: values.clone(), access_flags: vec!(AccessFlag::ACC_STATIC, AccessFlag::ACC_FINAL) },
+                EncodedField { field: counter, access_flags: vec!(AccessFlag::ACC_STATIC, AccessFlag::ACC_VOLATILE) }
+            ),
+            instance_fields: vec!(
+                EncodedField { field: name, access_flags: vec!(AccessFlag::ACC_PRIVATE, AccessFlag::ACC_TRANSIENT) }
+            ),
+            direct_methods: vec!(
+                EncodedMethod {
+                    method: init,
+                    access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_CONSTRUCTOR),
+                    code: Some(Code {
+                        registers_size: 1,
+                        ins_size: 1,
+                        outs_size: 1,
+                        debug_info: Some(DebugInfo {
+                            line_start: 3,
+                            parameter_names: vec!(),
+                            bytecode: vec!(DebugItemBytecodes::DBG_SET_PROLOGUE_END, DebugItemBytecodes::SPECIAL_OPCODE(0x0E))
+                        }),
+                        // invoke-direct {v0}, method@0003; return-void
+                        insns: vec!(0x1070, 0x0003, 0x0000, 0x000E),
+                        tries: vec!(),
+                        handlers: vec!()
+                    }),
+                    system_annotations: Default::default()
+                },
+                EncodedMethod {
+                    method: lambda,
+                    access_flags: vec!(AccessFlag::ACC_PRIVATE, AccessFlag::ACC_STATIC, AccessFlag::ACC_SYNTHETIC),
+                    code: Some(Code {
+                        registers_size: 0,
+                        ins_size: 0,
+                        outs_size: 0,
+                        debug_info: None,
+                        insns: vec!(0x000E),
+                        tries: vec!(),
+                        handlers: vec!()
+                    }),
+                    system_annotations: Default::default()
+                }
+            ),
+            virtual_methods: vec!(
+                EncodedMethod {
+                    method: run,
+                    access_flags: vec!(AccessFlag::ACC_PUBLIC),
+                    code: Some(Code {
+                        registers_size: 2,
+                        ins_size: 1,
+                        outs_size: 0,
+                        debug_info: Some(DebugInfo {
+                            line_start: 10,
+                            parameter_names: vec!(),
+                            bytecode: vec!(
+                                DebugItemBytecodes::DBG_SET_FILE(Some(b.string("Other.java"))),
+                                DebugItemBytecodes::DBG_START_LOCAL {
+                                    register_num: 0,
+                                    name: Some(b.string("r")),
+                                    type_: Some(b.type_("Ljava/lang/Runnable;"))
+                                },
+                                DebugItemBytecodes::DBG_START_LOCAL_EXTENDED {
+                                    register_num: 1,
+                                    name: Some(b.string("list")),
+                                    type_: Some(b.type_("Ljava/util/List;")),
+                                    sig: Some(b.string("Ljava/util/List<Ljava/lang/String;>;"))
+                                },
+                                DebugItemBytecodes::DBG_ADVANCE_PC(2),
+                                DebugItemBytecodes::DBG_ADVANCE_LINE(-1),
+                                DebugItemBytecodes::DBG_END_LOCAL(0),
+                                DebugItemBytecodes::DBG_RESTART_LOCAL(0),
+                                DebugItemBytecodes::DBG_SET_EPILOGUE_BEGIN,
+                                DebugItemBytecodes::DBG_SET_FILE(None),
+                                DebugItemBytecodes::SPECIAL_OPCODE(0xFF)
+                            )
+                        }),
+                        // invoke-custom {}, call_site@0; move-result-object v0; nop; nop; return-void; return-void
+                        insns: vec!(0x00FC, 0x0000, 0x0000, 0x000C, 0x0000, 0x000E, 0x000E),
+                        tries: vec!(
+                            TryItem { start_addr: 0, insn_count: 3, handler: catch_exception.clone() },
+                            TryItem { start_addr: 3, insn_count: 1, handler: catch_all.clone() }
+                        ),
+                        handlers: vec!(catch_exception, catch_all)
+                    }),
+                    system_annotations: Default::default()
+                },
+                EncodedMethod {
+                    method: compute,
+                    access_flags: vec!(AccessFlag::ACC_PUBLIC, AccessFlag::ACC_VARARGS, AccessFlag::ACC_BRIDGE),
+                    code: None,
+                    system_annotations: Default::default()
+                }
+            )
+        }),
+        static_values: vec!(
+            EncodedValue::Array(vec!(EncodedValue::Byte(0xFF), EncodedValue::Short(-2), EncodedValue::Char(0xFFFF))),
+            EncodedValue::Long(i64::MIN),
+            EncodedValue::Float(1.5),
+            EncodedValue::Double(-0.25),
+            EncodedValue::Type(object),
+            EncodedValue::Field(values),
+            EncodedValue::Method(b.method("Lgolden/Main;", "run", "V", &[])),
+            EncodedValue::MethodType(b.prototype("I", &["I", "Ljava/lang/String;"])),
+            EncodedValue::MethodHandle(getter_handle),
+            EncodedValue::String(b.string("caf\u{e9} \u{1F600} \0")),
+            EncodedValue::Boolean(true),
+            EncodedValue::Boolean(false)
+        ),
+        system_annotations: Default::default()
+    };
+    b.class(class);
+
+    b.build().unwrap()
+}
+
+#[test]
+fn test_full_file() {
+    let built = build_full_file();
+    let parsed = round_trip(&built);
+    assert_eq!(parsed, built);
+
+    // spot checks on what the builder put together
+    let fd = &parsed.file_data;
+    assert_eq!(parsed.header.version, 38);
+    assert_eq!(fd.call_sites.len(), 1);
+    assert_eq!(*fd.call_sites[0].method_name, "run");
+    assert_eq!(fd.call_sites[0].constant_values.len(), 3);
+    assert_eq!(fd.method_handles.len(), 3);
+    assert!(fd.methods.iter().any(|method| *method.name == "toString"));
+    assert!(fd.string_data.windows(2).all(|pair| pair[0].encode_utf16().lt(pair[1].encode_utf16())));
+
+    let main = &parsed.classes[1];
+    let annotations = main.annotations.as_ref().unwrap();
+    // elements are sorted by name
+    let names = annotations.class_annotations[0].elements.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!("count", "level", "nested", "value"));
+    assert_eq!(annotations.parameter_annotations[0].annotations[0], vec!());
+    assert_eq!(annotations.parameter_annotations[0].annotations[1][0].type_.as_str(), "Lgolden/Nullable;");
+
+    let run = &main.class_data.as_ref().unwrap().virtual_methods.iter()
+        .find(|method| *method.method.name == "run").unwrap();
+    let code = run.code.as_ref().unwrap();
+    assert_eq!(code.tries[1].handler.catch_all_addr, Some(6));
+    assert_eq!(code.debug_info.as_ref().unwrap().bytecode.len(), 10);
+}
+
+#[test]
+fn test_write_parsed_file() {
+    // writing a parsed file gives back exactly the bytes it was parsed from
+    let bytes = write(&build_full_file()).unwrap();
+    assert_eq!(write(&parse(&bytes).unwrap()).unwrap(), bytes);
+}
+
+#[test]
+fn test_write_missing_pool_entry() {
+    let mut file = build_full_file();
+    file.file_data.fields.clear();
+
+    match write(&file) {
+        Err(DexParserError::WritingFailed { .. }) => (),
+        res => panic!("expected writing to fail, got {:?}", res.map(|bytes| bytes.len()))
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    let mut built = build_full_file();
+    let json = serde_json::to_string(&built).unwrap();
+    let deserialized: DexFile = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, built);
+
+    // references into the pools come back shared
+    let main = &deserialized.classes[1];
+    let pooled = deserialized.file_data.string_data.iter().find(|s| ***s == *main.class_type).unwrap();
+    assert!(Rc::ptr_eq(pooled, &main.class_type));
+    let run = &main.class_data.as_ref().unwrap().virtual_methods[0].method;
+    assert!(deserialized.file_data.methods.iter().any(|method| Rc::ptr_eq(method, run)));
+
+    // floats JSON has no number for
+    built.classes[1].static_values[2] = EncodedValue::Float(f32::NAN);
+    built.classes[1].static_values[3] = EncodedValue::Double(f64::NEG_INFINITY);
+    let text = serde_json::to_string(&built).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(json["classes"][1]["static_values"][2], serde_json::json!({ "Float": "NaN" }));
+    assert_eq!(json["classes"][1]["static_values"][3], serde_json::json!({ "Double": "-Infinity" }));
+    let deserialized: DexFile = serde_json::from_str(&text).unwrap();
+    assert!(matches!(deserialized.classes[1].static_values[2], EncodedValue::Float(f) if f.is_nan()));
+    assert_eq!(deserialized.classes[1].static_values[3], EncodedValue::Double(f64::NEG_INFINITY));
+
+    let schema: serde_json::Value = serde_json::from_str(serialization::JSON_SCHEMA).unwrap();
+    validate(&schema, &schema, &json, "").unwrap();
+    let mut invalid = json.clone();
+    invalid["classes"][0]["access_flags"][0] = serde_json::json!("ACC_NONE");
+    assert!(validate(&schema, &schema, &invalid, "").is_err());
+
+    // classes can only be read once the pools they refer to are known, and a Value orders its keys alphabetically
+    assert!(serde_json::from_str::<DexFile>(&json.to_string()).is_err());
+    let out_of_range = text.replacen("\"class_type\":", "\"class_type\":100000,\"ignored\":", 1);
+    let error = serde_json::from_str::<DexFile>(&out_of_range).unwrap_err();
+    assert!(error.to_string().starts_with("string index 100000 is out of range"));
+}
+
+// Checks a value against the parts of JSON Schema that the schema uses
+#[cfg(feature = "serde")]
+fn validate(root: &serde_json::Value, schema: &serde_json::Value, value: &serde_json::Value, path: &str) -> Result<(), String> {
+    use serde_json::Value;
+    let fail = |message: &str| Err(format!("{}: {}", path, message));
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let name = reference.trim_start_matches("#/$defs/");
+        return validate(root, &root["$defs"][name], value, path);
+    }
+    if let Some(types) = schema.get("type") {
+        let types = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => vec!(types.as_str().unwrap())
+        };
+        let matches = types.iter().any(|t| match *t {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => false
+        });
+        if !matches {
+            return fail(&format!("expected {:?}, found {}", types, value));
+        }
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            return fail(&format!("{} is not one of {:?}", value, values));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return fail(&format!("expected {}", constant));
+        }
+    }
+    if let Some(number) = value.as_f64() {
+        if schema.get("minimum").and_then(Value::as_f64).is_some_and(|minimum| number < minimum)
+            || schema.get("maximum").and_then(Value::as_f64).is_some_and(|maximum| number > maximum) {
+            return fail(&format!("{} is out of range", number));
+        }
+    }
+    if let Some(alternatives) = schema.get("oneOf").and_then(Value::as_array) {
+        let matching = alternatives.iter().filter(|a| validate(root, a, value, path).is_ok()).count();
+        if matching != 1 {
+            return fail(&format!("{} matches {} of the alternatives", value, matching));
+        }
+    }
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+            if !object.contains_key(required.as_str().unwrap()) {
+                return fail(&format!("missing {}", required));
+            }
+        }
+        for (key, member) in object {
+            match properties.and_then(|p| p.get(key)) {
+                Some(property) => validate(root, property, member, &format!("{}/{}", path, key))?,
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return fail(&format!("unexpected {}", key));
+                },
+                None => {}
+            }
+        }
+    }
+    if let Some(array) = value.as_array() {
+        let length = array.len() as u64;
+        if schema.get("minItems").and_then(Value::as_u64).is_some_and(|minimum| length < minimum)
+            || schema.get("maxItems").and_then(Value::as_u64).is_some_and(|maximum| length > maximum) {
+            return fail("wrong number of items");
+        }
+        if let Some(items) = schema.get("items") {
+            for (i, item) in array.iter().enumerate() {
+                validate(root, items, item, &format!("{}/{}", path, i))?;
+            }
+        }
+    }
+    Ok(())
+}